@@ -0,0 +1,44 @@
+//! A bot that replaces `/now` typed in a document with the time in UTC:
+//!
+//!     cargo run --example now_bot -- 127.0.0.1:4000 team notes.txt
+
+use carnelia_collab::bot::{BotContext, BotRunner};
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+const COMMAND: &str = "/now";
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let mut args = std::env::args().skip(1);
+    let addr = args.next().unwrap_or_else(|| "127.0.0.1:4000".to_string());
+    let room = args.next().unwrap_or_else(|| "lobby".to_string());
+    let doc = args.next().unwrap_or_else(|| "notes.txt".to_string());
+    let bot = BotRunner::new("now-bot", &room, &doc)
+        .on_insert(|ctx, pos, text| async move { replace_command(ctx, pos + text.len()).await })
+        .connect(addr.as_str())
+        .await?;
+    println!("[bot] in {}/{}, waiting for {}", room, doc, COMMAND);
+    bot.run().await
+}
+
+/// Replaces `/now` if an insert ending at `end` just finished typing it.
+async fn replace_command(ctx: BotContext, end: usize) -> io::Result<()> {
+    let text = ctx.text().await;
+    if !text
+        .get(..end)
+        .is_some_and(|typed| typed.ends_with(COMMAND))
+    {
+        return Ok(());
+    }
+    let start = end - COMMAND.len();
+    ctx.delete(start, COMMAND.len()).await?;
+    ctx.insert(start, &utc_time(SystemTime::now())).await
+}
+
+/// `HH:MM UTC` of `now`.
+fn utc_time(now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let minutes = secs / 60 % (24 * 60);
+    format!("{:02}:{:02} UTC", minutes / 60, minutes % 60)
+}
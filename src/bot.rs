@@ -0,0 +1,340 @@
+//! Bots: programs that join a document like any other client and act on
+//! what happens in it, e.g. putting the time where someone typed `/now`.
+//! `BotRunner` takes async callbacks for edits and joins and runs them one
+//! at a time, in the order the server sent the events, each to the end
+//! before the next message is read. The `BotContext` they are given reads
+//! the text and edits it; its edits apply locally right away, so the next
+//! callback sees them, and positions in events always refer to the text
+//! the context has at that point.
+
+use crate::protocol::{
+    ClientMessage, Op, ServerMessage, Subscription, UserEventKind, WireUser, decode_sync_response,
+    decode_update, encode_sync_request, encode_update, make_scoped_user_id,
+};
+use crate::textpos::{apply_op_to_doc, build_doc, clamp_to_boundary};
+use mdcs_sdk::{Message, TextDoc};
+use serde::Serialize;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::Mutex;
+
+type CallbackFuture = Pin<Box<dyn Future<Output = io::Result<()>> + Send>>;
+type Callback<A> = Box<dyn FnMut(BotContext, A) -> CallbackFuture + Send>;
+
+/// Callbacks to run, and who runs them where.
+pub struct BotRunner {
+    user: String,
+    room: String,
+    doc: String,
+    on_insert: Vec<Callback<(usize, String)>>,
+    on_delete: Vec<Callback<(usize, String)>>,
+    on_user_joined: Vec<Callback<WireUser>>,
+}
+
+impl BotRunner {
+    /// A bot called `user` in `room`/`doc`, doing nothing yet.
+    pub fn new(user: &str, room: &str, doc: &str) -> Self {
+        Self {
+            user: user.to_string(),
+            room: room.to_string(),
+            doc: doc.to_string(),
+            on_insert: Vec::new(),
+            on_delete: Vec::new(),
+            on_user_joined: Vec::new(),
+        }
+    }
+
+    /// Runs `callback` with the byte position and text of each insert
+    /// someone else makes.
+    pub fn on_insert<F, Fut>(mut self, mut callback: F) -> Self
+    where
+        F: FnMut(BotContext, usize, String) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        self.on_insert.push(Box::new(move |ctx, (pos, text)| {
+            Box::pin(callback(ctx, pos, text))
+        }));
+        self
+    }
+
+    /// Runs `callback` with the byte position and the deleted text of each
+    /// delete someone else makes.
+    pub fn on_delete<F, Fut>(mut self, mut callback: F) -> Self
+    where
+        F: FnMut(BotContext, usize, String) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        self.on_delete.push(Box::new(move |ctx, (pos, text)| {
+            Box::pin(callback(ctx, pos, text))
+        }));
+        self
+    }
+
+    /// Runs `callback` for each user joining the document after the bot.
+    pub fn on_user_joined<F, Fut>(mut self, mut callback: F) -> Self
+    where
+        F: FnMut(BotContext, WireUser) -> Fut + Send + 'static,
+        Fut: Future<Output = io::Result<()>> + Send + 'static,
+    {
+        self.on_user_joined
+            .push(Box::new(move |ctx, user| Box::pin(callback(ctx, user))));
+        self
+    }
+
+    /// Joins the document on the server at `addr`; fails if the server
+    /// refuses the join. Events start once the bot is `run`.
+    pub async fn connect(mut self, addr: impl ToSocketAddrs) -> io::Result<Bot> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let doc_id = format!("{}/{}", self.room, self.doc);
+        let user_id = make_scoped_user_id(&doc_id, &self.user);
+        let mut session = Session {
+            doc: TextDoc::new(doc_id.clone(), user_id.clone()),
+            doc_id,
+            user_id,
+            version: 0,
+            users: Vec::new(),
+            presence_seq: 0,
+            writer,
+        };
+        let hello = Message::Hello {
+            replica_id: session.user_id.clone(),
+            user_name: self.user.clone(),
+        };
+        session.send(&hello).await?;
+        // Bots act on edits and joins; others' cursors would only be read
+        // to be thrown away.
+        let subscriptions = ClientMessage::SetSubscriptions {
+            subscriptions: vec![Subscription::Edits, Subscription::Presence],
+        };
+        session.send(&subscriptions).await?;
+        session
+            .send(&encode_sync_request(&session.doc_id, 0))
+            .await?;
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = lines.next_line().await?.ok_or_else(|| {
+                io::Error::new(io::ErrorKind::UnexpectedEof, "the server hung up")
+            })?;
+            let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                // The room may call the doc something else.
+                if let Ok(ServerMessage::Welcome {
+                    doc: Some(doc),
+                    redirected_from: Some(_),
+                    ..
+                }) = serde_json::from_str(&line)
+                {
+                    session.doc_id = format!("{}/{}", self.room, doc);
+                    session.user_id = make_scoped_user_id(&session.doc_id, &self.user);
+                    session.doc = TextDoc::new(session.doc_id.clone(), session.user_id.clone());
+                    self.doc = doc;
+                }
+                continue;
+            };
+            if let Some((doc_id, sync, _)) = decode_sync_response(&msg)
+                && doc_id == session.doc_id
+            {
+                if let Some(error) = sync.error {
+                    return Err(io::Error::other(format!("join refused: {}", error)));
+                }
+                session.apply(&msg);
+                break;
+            }
+        }
+        Ok(Bot {
+            runner: self,
+            ctx: BotContext {
+                session: Arc::new(Mutex::new(session)),
+            },
+            lines,
+        })
+    }
+}
+
+/// A bot joined to its document.
+pub struct Bot {
+    runner: BotRunner,
+    ctx: BotContext,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+}
+
+impl Bot {
+    /// A handle on the document, as the callbacks get it.
+    pub fn context(&self) -> BotContext {
+        self.ctx.clone()
+    }
+
+    /// Runs the callbacks until the server hangs up, or one of them fails.
+    pub async fn run(mut self) -> io::Result<()> {
+        let runner = &mut self.runner;
+        while let Some(line) = self.lines.next_line().await? {
+            if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+                let event = self.ctx.session.lock().await.apply(&msg);
+                match event {
+                    Some(Event::Inserted(pos, text)) => {
+                        for callback in &mut runner.on_insert {
+                            callback(self.ctx.clone(), (pos, text.clone())).await?;
+                        }
+                    }
+                    Some(Event::Deleted(pos, text)) => {
+                        for callback in &mut runner.on_delete {
+                            callback(self.ctx.clone(), (pos, text.clone())).await?;
+                        }
+                    }
+                    None => {}
+                }
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(ServerMessage::UserEvent {
+                    room,
+                    doc,
+                    kind: UserEventKind::Joined,
+                    user,
+                    ..
+                }) if room == runner.room && doc == runner.doc => {
+                    for callback in &mut runner.on_user_joined {
+                        callback(self.ctx.clone(), user.clone()).await?;
+                    }
+                }
+                Ok(ServerMessage::Members {
+                    users,
+                    presence_seq,
+                    ..
+                }) => {
+                    let mut session = self.ctx.session.lock().await;
+                    if presence_seq > session.presence_seq {
+                        session.presence_seq = presence_seq;
+                        session.users = users;
+                    }
+                }
+                // The edit never happened on the server; take its text.
+                Ok(ServerMessage::Rejected { error }) => {
+                    println!("[bot] server rejected an edit: {}", error);
+                    let mut session = self.ctx.session.lock().await;
+                    let request = encode_sync_request(&session.doc_id, session.version);
+                    session.send(&request).await?;
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The document as a bot sees it, for its callbacks to read and edit.
+/// Clones share it.
+#[derive(Clone)]
+pub struct BotContext {
+    session: Arc<Mutex<Session>>,
+}
+
+impl BotContext {
+    /// The text as the bot has it, its own edits included.
+    pub async fn text(&self) -> String {
+        self.session.lock().await.doc.get_text()
+    }
+
+    /// Everyone in the document, the bot included.
+    pub async fn users(&self) -> Vec<WireUser> {
+        self.session.lock().await.users.clone()
+    }
+
+    /// Inserts `text` at byte `pos`, locally and on the server.
+    pub async fn insert(&self, pos: usize, text: &str) -> io::Result<()> {
+        let op = Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        self.session.lock().await.edit(op).await
+    }
+
+    /// Deletes `len` bytes from `pos`, locally and on the server.
+    pub async fn delete(&self, pos: usize, len: usize) -> io::Result<()> {
+        self.session
+            .lock()
+            .await
+            .edit(Op::Delete {
+                pos,
+                len,
+                pos_chars: None,
+                len_chars: None,
+            })
+            .await
+    }
+}
+
+/// An edit by someone else, as passed to the callbacks.
+enum Event {
+    Inserted(usize, String),
+    Deleted(usize, String),
+}
+
+struct Session {
+    doc_id: String,
+    user_id: String,
+    doc: TextDoc,
+    version: u64,
+    users: Vec<WireUser>,
+    /// The `presence_seq` of `users`.
+    presence_seq: u64,
+    writer: OwnedWriteHalf,
+}
+
+impl Session {
+    /// Applies a message from the server, returning the edit it was if
+    /// someone else made one.
+    fn apply(&mut self, msg: &Message) -> Option<Event> {
+        if let Some((doc_id, sync, version)) = decode_sync_response(msg) {
+            if doc_id != self.doc_id || sync.error.is_some() {
+                return None;
+            }
+            self.doc = build_doc(&self.doc_id, &self.user_id, &sync.text);
+            self.version = version;
+            self.users = sync.users;
+            self.presence_seq = sync.presence_seq;
+            return None;
+        }
+        let (doc_id, update, version) = decode_update(msg)?;
+        if doc_id != self.doc_id {
+            return None;
+        }
+        self.version = version;
+        // Our own edits come back too; they are applied already.
+        if update.user_id == self.user_id {
+            return None;
+        }
+        let before = self.doc.get_text();
+        apply_op_to_doc(&mut self.doc, &update.op);
+        match update.op {
+            Op::Insert { pos, text, .. } => Some(Event::Inserted(pos, text)),
+            Op::Delete { pos, .. } => {
+                let removed = before.len() - self.doc.get_text().len();
+                let start = clamp_to_boundary(&before, pos);
+                Some(Event::Deleted(
+                    start,
+                    before[start..start + removed].to_string(),
+                ))
+            }
+            Op::Cursor { .. } | Op::Selection { .. } => None,
+        }
+    }
+
+    async fn edit(&mut self, op: Op) -> io::Result<()> {
+        apply_op_to_doc(&mut self.doc, &op);
+        let update = encode_update(&self.doc_id, &self.user_id, op, Vec::new(), self.version)
+            .map_err(io::Error::other)?;
+        self.send(&update).await
+    }
+
+    async fn send(&mut self, msg: &impl Serialize) -> io::Result<()> {
+        let mut line = serde_json::to_string(msg).map_err(io::Error::other)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await
+    }
+}
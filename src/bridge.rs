@@ -0,0 +1,244 @@
+//! `collab-cli bridge`: one document shared between two servers. The bridge
+//! joins the document on both as an ordinary client. Whenever someone else
+//! edits either side, it merges both texts against the text they last
+//! agreed on and sends each side the ops that bring it to the merged text,
+//! through the same path as any other client's edits. Its own ops come back
+//! as updates made by its user, so they count as confirmations and are
+//! never forwarded again.
+
+use crate::protocol::{
+    ClientMessage, Op, Subscription, decode_sync_response, decode_update, encode_sync_request,
+    encode_update, make_scoped_user_id,
+};
+use crate::snapshot;
+use crate::textpos::{apply_op_to_doc, build_doc};
+use mdcs_sdk::{Message, TextDoc};
+use serde::Serialize;
+use std::error::Error;
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+
+/// A room on one of the two servers.
+#[derive(Debug, Clone)]
+pub struct Endpoint {
+    pub addr: String,
+    pub room: String,
+}
+
+/// Keeps `doc` the same in `left.room` and `right.room`, joining both as
+/// `user`, until either server hangs up. Whichever side is longer when the
+/// bridge starts is copied to the other first.
+pub async fn run(
+    left: &Endpoint,
+    right: &Endpoint,
+    doc: &str,
+    user: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut left = Side::connect("left", left, doc, user).await?;
+    let mut right = Side::connect("right", right, doc, user).await?;
+
+    let (left_text, right_text) = (left.text.get_text(), right.text.get_text());
+    let mut base = if right_text.chars().count() > left_text.chars().count() {
+        println!(
+            "[bridge] copying right to left ({} bytes)",
+            right_text.len()
+        );
+        right_text
+    } else {
+        if left_text != right_text {
+            println!("[bridge] copying left to right ({} bytes)", left_text.len());
+        }
+        left_text
+    };
+    left.edit_to(&base).await?;
+    right.edit_to(&base).await?;
+
+    // Whether someone else edited either side since the last merge.
+    let mut changed = false;
+    loop {
+        let (side, line) = tokio::select! {
+            line = left.lines.next_line() => (&mut left, line?),
+            line = right.lines.next_line() => (&mut right, line?),
+        };
+        let Some(line) = line else {
+            return Err(format!("the {} server hung up", side.label).into());
+        };
+        if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+            changed |= side.receive(&msg);
+        }
+
+        // Merging while our own ops are in flight would count them twice.
+        if changed && left.unconfirmed == 0 && right.unconfirmed == 0 {
+            changed = false;
+            let (left_text, right_text) = (left.text.get_text(), right.text.get_text());
+            // Edits too large to merge: the left side wins.
+            let merged = snapshot::merge(&base, &left_text, &right_text)
+                .unwrap_or_else(|| left_text.clone());
+            left.edit_to(&merged).await?;
+            right.edit_to(&merged).await?;
+            base = merged;
+        }
+    }
+}
+
+/// The bridge's connection to one server.
+struct Side {
+    label: &'static str,
+    doc_id: String,
+    user_id: String,
+    /// The server's text: every update applied in the order it sent them.
+    text: TextDoc,
+    version: u64,
+    /// Ops sent that the server hasn't sent back yet.
+    unconfirmed: usize,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl Side {
+    async fn connect(
+        label: &'static str,
+        endpoint: &Endpoint,
+        doc: &str,
+        user: &str,
+    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let stream = TcpStream::connect(&endpoint.addr)
+            .await
+            .map_err(|err| format!("connecting to the {} server: {}", label, err))?;
+        let (reader, writer) = stream.into_split();
+        let doc_id = format!("{}/{}", endpoint.room, doc);
+        let user_id = make_scoped_user_id(&doc_id, user);
+        let mut side = Self {
+            label,
+            text: TextDoc::new(doc_id.clone(), user_id.clone()),
+            doc_id,
+            user_id,
+            version: 0,
+            unconfirmed: 0,
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+        let hello = Message::Hello {
+            replica_id: side.user_id.clone(),
+            user_name: user.to_string(),
+        };
+        side.send(&hello).await?;
+        // Only the text is bridged.
+        let subscriptions = ClientMessage::SetSubscriptions {
+            subscriptions: vec![Subscription::Edits],
+        };
+        side.send(&subscriptions).await?;
+        side.send(&encode_sync_request(&side.doc_id, 0)).await?;
+        loop {
+            let Some(line) = side.lines.next_line().await? else {
+                return Err(format!("the {} server hung up", label).into());
+            };
+            let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                continue;
+            };
+            if let Some((doc_id, sync, version)) = decode_sync_response(&msg)
+                && doc_id == side.doc_id
+            {
+                if let Some(error) = sync.error {
+                    return Err(format!("the {} server refused the join: {}", label, error).into());
+                }
+                side.text = build_doc(&side.doc_id, &side.user_id, &sync.text);
+                side.version = version;
+                println!("[bridge] joined {} on the {} server", side.doc_id, label);
+                return Ok(side);
+            }
+        }
+    }
+
+    /// Applies an update from the server; true if someone else made it.
+    fn receive(&mut self, msg: &Message) -> bool {
+        let Some((doc_id, update, version)) = decode_update(msg) else {
+            return false;
+        };
+        if doc_id != self.doc_id || !matches!(update.op, Op::Insert { .. } | Op::Delete { .. }) {
+            return false;
+        }
+        apply_op_to_doc(&mut self.text, &update.op);
+        self.version = version;
+        if update.user_id == self.user_id {
+            self.unconfirmed = self.unconfirmed.saturating_sub(1);
+            false
+        } else {
+            true
+        }
+    }
+
+    /// Sends the ops turning the server's text into `target`. They are
+    /// applied to `text` when the server sends them back.
+    async fn edit_to(&mut self, target: &str) -> io::Result<()> {
+        let text = self.text.get_text();
+        for op in avoid_front_inserts(&text, snapshot::diff(&text, target)) {
+            let update = encode_update(&self.doc_id, &self.user_id, op, Vec::new(), self.version)
+                .map_err(io::Error::other)?;
+            self.send(&update).await?;
+            self.unconfirmed += 1;
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &impl Serialize) -> io::Result<()> {
+        let mut line = serde_json::to_string(msg).map_err(io::Error::other)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await
+    }
+}
+
+/// `ops` for `text`, with inserts at the very start rewritten: `TextDoc`
+/// puts those after the first character of a non-empty document. Such an
+/// insert is sent as the new text plus that character, inserted after it,
+/// followed by deleting the original.
+fn avoid_front_inserts(text: &str, ops: Vec<Op>) -> Vec<Op> {
+    let mut text = text.to_string();
+    let mut rewritten = Vec::with_capacity(ops.len());
+    for op in ops {
+        match (&op, text.chars().next()) {
+            (
+                Op::Insert {
+                    pos: 0,
+                    text: insert,
+                    ..
+                },
+                Some(first),
+            ) => {
+                let len = first.len_utf8();
+                rewritten.push(Op::Insert {
+                    pos: len,
+                    text: format!("{}{}", insert, first),
+                    pos_chars: None,
+                });
+                rewritten.push(Op::Delete {
+                    pos: 0,
+                    len,
+                    pos_chars: None,
+                    len_chars: None,
+                });
+            }
+            _ => rewritten.push(op.clone()),
+        }
+        snapshot::apply_to_text(&mut text, &op);
+    }
+    rewritten
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn inserts_at_the_start_land_at_the_start() {
+        for (from, to) in [("bc", "abc"), ("é", "xyé"), ("", "new"), ("abc", "Xbc")] {
+            let mut doc = build_doc("room/doc", "bridge", from);
+            for op in avoid_front_inserts(from, snapshot::diff(from, to)) {
+                apply_op_to_doc(&mut doc, &op);
+            }
+            assert_eq!(doc.get_text(), to, "{:?} -> {:?}", from, to);
+        }
+    }
+}
@@ -0,0 +1,221 @@
+//! Long inserts sent as a sequence of smaller Inserts, for pastes and
+//! loaded files in the client and the TUI.
+
+use crate::protocol::{Op, ServerLimits};
+use crate::textpos::{clamp_to_boundary, next_char_boundary};
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Inserts longer than this go out in chunks unless configured otherwise.
+pub const DEFAULT_CHUNK_BYTES: usize = 64 * 1024;
+
+/// An insert going out as Inserts of at most a chunk each, at advancing
+/// positions, so no single message is huge and other traffic isn't stuck
+/// behind it. Chunks go out in order, each once the one before was sent;
+/// stopping early keeps the ones sent so far.
+#[derive(Debug)]
+pub struct ChunkedInsert {
+    /// Where the next chunk goes.
+    pos: usize,
+    chunks: VecDeque<String>,
+    total: usize,
+    bytes: usize,
+    sent_bytes: usize,
+    /// Time between chunks, to stay within the server's edit rate.
+    interval: Duration,
+    last_sent: Option<Instant>,
+}
+
+impl ChunkedInsert {
+    /// Cuts `text`, to be inserted at byte `pos`, into chunks of at most
+    /// `chunk_bytes`, smaller still where `fits` says a chunk's message
+    /// would be too long. A chunk holds at least one character.
+    pub fn new(pos: usize, text: &str, chunk_bytes: usize, fits: impl Fn(&str) -> bool) -> Self {
+        let mut chunks = VecDeque::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let first = next_char_boundary(rest, 0);
+            let mut split = clamp_to_boundary(rest, chunk_bytes).max(first);
+            while split > first && !fits(&rest[..split]) {
+                split = clamp_to_boundary(rest, split / 2).max(first);
+            }
+            let (chunk, tail) = rest.split_at(split);
+            chunks.push_back(chunk.to_string());
+            rest = tail;
+        }
+        Self {
+            pos,
+            total: chunks.len(),
+            chunks,
+            bytes: text.len(),
+            sent_bytes: 0,
+            interval: Duration::ZERO,
+            last_sent: None,
+        }
+    }
+
+    /// Spaces the chunks out to stay within `limits.max_ops_per_second`.
+    pub fn paced(mut self, limits: &ServerLimits) -> Self {
+        if let Some(rate) = limits.max_ops_per_second.filter(|rate| *rate > 0) {
+            self.interval = Duration::from_secs(1) / rate;
+        }
+        self
+    }
+
+    /// The Insert of the next chunk, or `None` once all were sent.
+    pub fn next_op(&self) -> Option<Op> {
+        let text = self.chunks.front()?;
+        Some(Op::Insert {
+            pos: self.pos,
+            text: text.clone(),
+            pos_chars: None,
+        })
+    }
+
+    /// Notes that the chunk `next_op` returned went out at `now`.
+    pub fn chunk_sent(&mut self, now: Instant) {
+        if let Some(text) = self.chunks.pop_front() {
+            self.pos += text.len();
+            self.sent_bytes += text.len();
+            self.last_sent = Some(now);
+        }
+    }
+
+    /// How long the next chunk has to wait after `now`.
+    pub fn wait(&self, now: Instant) -> Duration {
+        self.last_sent.map_or(Duration::ZERO, |at| {
+            (at + self.interval).saturating_duration_since(now)
+        })
+    }
+
+    /// Keeps the chunks still to come in place around someone else's edit.
+    pub fn adjust_for_remote(&mut self, op: &Op) {
+        match op {
+            Op::Insert { pos, text, .. } if *pos <= self.pos => self.pos += text.len(),
+            Op::Delete { pos, len, .. } if *pos < self.pos => {
+                self.pos -= (self.pos - pos).min(*len);
+            }
+            _ => {}
+        }
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.chunks.is_empty()
+    }
+
+    /// Length of the whole text.
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    /// How many chunks the text was cut into.
+    pub fn chunks(&self) -> usize {
+        self.total
+    }
+
+    /// E.g. "uploading 3/80 chunks".
+    pub fn progress(&self) -> String {
+        format!("uploading {}/{} chunks", self.sent(), self.total)
+    }
+
+    /// How much went out, e.g. "3/80 chunks (196608 of 5242880 bytes)".
+    pub fn summary(&self) -> String {
+        format!(
+            "{}/{} chunks ({} of {} bytes)",
+            self.sent(),
+            self.total,
+            self.sent_bytes,
+            self.bytes
+        )
+    }
+
+    fn sent(&self) -> usize {
+        self.total - self.chunks.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot;
+
+    fn send_all(upload: &mut ChunkedInsert, text: &mut String) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        while let Some(op) = upload.next_op() {
+            if let Op::Insert { text: chunk, .. } = &op {
+                sizes.push(chunk.len());
+            }
+            snapshot::apply_to_text(text, &op);
+            upload.chunk_sent(Instant::now());
+        }
+        sizes
+    }
+
+    #[test]
+    fn chunks_rebuild_the_text_in_place() {
+        let pasted: String = (0..40_000).map(|idx| format!("{}é中🎉\n", idx)).collect();
+        let mut text = "before|after".to_string();
+        let mut upload = ChunkedInsert::new(7, &pasted, 4096, |_| true);
+        assert!(upload.chunks() > 100);
+        let sizes = send_all(&mut upload, &mut text);
+        assert!(upload.is_done());
+        let (last, full) = sizes.split_last().unwrap();
+        assert!(full.iter().all(|size| *size <= 4096 && *size > 4092));
+        assert!(*last <= 4096);
+        assert_eq!(text, format!("before|{}after", pasted));
+        let summary = format!(
+            "{0}/{0} chunks ({1} of {1} bytes)",
+            sizes.len(),
+            pasted.len()
+        );
+        assert_eq!(upload.summary(), summary);
+    }
+
+    #[test]
+    fn chunks_shrink_to_fit_and_follow_remote_edits() {
+        let shrunk = ChunkedInsert::new(0, "ääääää", 100, |chunk| chunk.len() <= 6);
+        assert_eq!(shrunk.chunks(), 2);
+        let mut upload = ChunkedInsert::new(2, "ääääää", 4, |_| true);
+        assert_eq!(upload.chunks(), 3);
+        let mut text = "xxyy".to_string();
+        let first = upload.next_op().unwrap();
+        snapshot::apply_to_text(&mut text, &first);
+        upload.chunk_sent(Instant::now());
+        assert_eq!(upload.progress(), "uploading 1/3 chunks");
+
+        // Someone types in front of the upload, then deletes some of that.
+        let remote = Op::Insert {
+            pos: 0,
+            text: "ab".into(),
+            pos_chars: None,
+        };
+        snapshot::apply_to_text(&mut text, &remote);
+        upload.adjust_for_remote(&remote);
+        let remote = Op::Delete {
+            pos: 1,
+            len: 3,
+            pos_chars: None,
+            len_chars: None,
+        };
+        snapshot::apply_to_text(&mut text, &remote);
+        upload.adjust_for_remote(&remote);
+        send_all(&mut upload, &mut text);
+        assert_eq!(text, format!("a{}yy", "ä".repeat(6)));
+    }
+
+    #[test]
+    fn chunks_are_spaced_by_the_server_rate() {
+        let limits = ServerLimits {
+            max_ops_per_second: Some(4),
+            ..ServerLimits::default()
+        };
+        let mut upload = ChunkedInsert::new(0, "abcdef", 2, |_| true).paced(&limits);
+        let start = Instant::now();
+        assert_eq!(upload.wait(start), Duration::ZERO);
+        upload.chunk_sent(start);
+        assert_eq!(upload.wait(start), Duration::from_millis(250));
+        let later = start + Duration::from_millis(100);
+        assert_eq!(upload.wait(later), Duration::from_millis(150));
+        assert_eq!(upload.wait(start + Duration::from_secs(1)), Duration::ZERO);
+    }
+}
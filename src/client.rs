@@ -1,81 +1,710 @@
+use crate::chunked::ChunkedInsert;
+use crate::export::{Assembler, ExportedDoc};
+use crate::lines::{WriteStats, write_lines};
+use crate::position;
 use crate::protocol::{
-    Op, decode_sync_response, decode_update, doc_id_from_scoped_user_id, encode_sync_request,
+    ActivityEntry, ClientMessage, Op, ServerLimits, ServerMessage, WireRevision, WireTrashed,
+    decode_sync_response, decode_update, doc_id_from_scoped_user_id, encode_sync_request,
     encode_update, make_scoped_user_id,
 };
+use crate::record::Recorder;
+use crate::replace::{self, Pattern};
+use crate::snapshot::{self, PendingOps};
+use crate::storage::{Access, Acl, RoomMeta, UserStats, WhitespacePolicy};
+use crate::textpos::{apply_op_to_doc, build_doc};
+use crate::tui::cursor_line_col;
 use mdcs_sdk::{Awareness, Message, TextDoc};
+use serde::Serialize;
 use std::collections::HashMap;
 use std::error::Error;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
 use tokio::net::TcpStream;
 use tokio::sync::mpsc;
+use unicode_width::UnicodeWidthStr;
 
-pub async fn run(addr: &str, user: &str, room: &str, doc: &str) -> Result<(), Box<dyn Error>> {
-    println!("[client] connecting to {}", addr);
-    let stream = TcpStream::connect(addr).await?;
-    let (reader, writer) = stream.into_split();
+mod confirm;
+pub(crate) mod netsim;
+mod prompt;
+pub(crate) mod resync;
 
-    let (out_tx, mut out_rx) = mpsc::channel::<Message>(64);
+use confirm::Plan;
+pub use confirm::{ClientConfig, DeleteThreshold};
+pub use netsim::NetConditions;
+pub use prompt::{DriftLimits, PromptStyle};
+use resync::SyncSchedule;
+
+/// Activity feed entries `/activity` keeps.
+const FEED_LEN: usize = 100;
+/// Bytes of a malformed server line kept for the log.
+const MALFORMED_SHOWN: usize = 80;
+
+/// `println!` that keeps a `--prompt rich` prompt below what it prints.
+macro_rules! say {
+    ($($arg:tt)*) => {
+        prompt::print_line(&format!($($arg)*))
+    };
+}
+
+/// Settings for `run` beyond which document to join.
+pub struct Options {
+    /// `/load` sends files longer than this in chunks of this size.
+    pub chunk_bytes: usize,
+    pub prompt: PromptStyle,
+    /// When the rich prompt suggests a `/sync`.
+    pub drift: DriftLimits,
+    /// Deletes past this wait for a `y`; `None` sends them right away.
+    pub confirm_deletes: Option<DeleteThreshold>,
+    /// Delays and losses to put on the connection, for trying out a bad
+    /// network.
+    pub network: NetConditions,
+    /// Sync by itself this often, once the user stopped typing.
+    pub auto_sync: Option<Duration>,
+    /// Where `--record` appends what goes over the connection.
+    pub record: Option<Arc<Recorder>>,
+}
+
+/// Connects to the server at `addr` for a request about `room`/`doc`,
+/// saying hello as `user` first: the server goes by that name in deciding
+/// what the request may see or change.
+async fn connect_as(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> Result<TcpStream, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let hello = Message::Hello {
+        replica_id: make_scoped_user_id(&format!("{}/{}", room, doc), user),
+        user_name: user.to_string(),
+    };
+    let mut line = serde_json::to_string(&hello)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+    Ok(stream)
+}
+
+/// Fetches every document of `room` from the server at `addr`, for
+/// `export-room --addr`. Without a `user`, documents with readers listed
+/// are left out.
+pub async fn export_room(
+    addr: &str,
+    room: &str,
+    user: Option<&str>,
+) -> Result<Vec<ExportedDoc>, Box<dyn Error>> {
+    let mut stream = match user {
+        Some(user) => connect_as(addr, room, "", user).await?,
+        None => TcpStream::connect(addr).await?,
+    };
+    let request = ClientMessage::ExportRoom {
+        room: room.to_string(),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    let mut assembler = Assembler::default();
+    while let Some(line) = lines.next_line().await? {
+        let Ok(msg) = serde_json::from_str::<ServerMessage>(&line) else {
+            continue;
+        };
+        if let Some(done) = assembler.push(msg) {
+            return Ok(done?);
+        }
+    }
+    Err("the server closed the connection before the export was done".into())
+}
+
+/// Asks the server at `addr` to save `room`/`doc` with a revision now, on
+/// behalf of `user`. Returns the version saved and its size in bytes.
+pub async fn snapshot(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> Result<(u64, usize), Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = ClientMessage::Snapshot {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        user: Some(user.to_string()),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::SnapshotDone { version, bytes }) => return Ok((version, bytes)),
+            Ok(ServerMessage::SnapshotFailed { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection before the snapshot was done".into())
+}
+
+/// Asks the server at `addr` who wrote how much of `room`/`doc`, as
+/// `user`.
+pub async fn doc_stats(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> Result<Vec<UserStats>, Box<dyn Error>> {
+    let mut stream = connect_as(addr, room, doc, user).await?;
+    let request = ClientMessage::StatsRequest {
+        room: room.to_string(),
+        doc: doc.to_string(),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::DocStats { per_user }) => return Ok(per_user),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection without sending stats".into())
+}
+
+/// Asks the server at `addr` which rooms have documents.
+pub async fn list_rooms(addr: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let mut line = serde_json::to_string(&ClientMessage::ListRooms)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Ok(ServerMessage::Rooms { rooms }) = serde_json::from_str(&line) {
+            return Ok(rooms);
+        }
+    }
+    Err("the server closed the connection without listing rooms".into())
+}
+
+/// Asks the server at `addr` which documents `room` has.
+pub async fn list_docs(addr: &str, room: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = ClientMessage::ListDocs {
+        room: room.to_string(),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        if let Ok(ServerMessage::Docs { docs, .. }) = serde_json::from_str(&line) {
+            return Ok(docs);
+        }
+    }
+    Err("the server closed the connection without listing documents".into())
+}
+
+/// Asks the server at `addr` for the stored revisions of `room`/`doc`,
+/// oldest first, as `user`.
+pub async fn list_revisions(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> Result<Vec<WireRevision>, Box<dyn Error>> {
+    let mut stream = connect_as(addr, room, doc, user).await?;
+    let request = ClientMessage::ListRevisions {
+        room: room.to_string(),
+        doc: doc.to_string(),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::Revisions { revisions }) => return Ok(revisions),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection without listing revisions".into())
+}
+
+/// Asks the server at `addr` for the text of revision `version` of
+/// `room`/`doc`, as `user`.
+pub async fn revision_text(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    version: u64,
+    user: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut stream = connect_as(addr, room, doc, user).await?;
+    let request = ClientMessage::HistoryRequest {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        version,
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::RevisionText { text, .. }) => return Ok(text),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection without sending the revision".into())
+}
+
+/// Asks the server at `addr` to turn `room`/`doc` back into revision
+/// `version`, on behalf of `user`. Returns the document's version after.
+pub async fn restore_revision(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    version: u64,
+    user: &str,
+) -> Result<u64, Box<dyn Error>> {
+    let mut stream = connect_as(addr, room, doc, user).await?;
+    let request = ClientMessage::Restore {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        version,
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::RestoreDone { version, .. }) => return Ok(version),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection before the restore was done".into())
+}
+
+/// Asks the server at `addr` which documents deleted from `room` are still
+/// in its trash, last deleted first.
+pub async fn list_trash(addr: &str, room: &str) -> Result<Vec<WireTrashed>, Box<dyn Error>> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let request = ClientMessage::ListTrash {
+        room: room.to_string(),
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::Trash { docs, .. }) => return Ok(docs),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection without listing the trash".into())
+}
+
+/// Asks the server at `addr` to bring `room`/`doc` back from the trash,
+/// as `to` if given, on behalf of `user`. Returns the name it has now.
+pub async fn restore_doc(
+    addr: &str,
+    room: &str,
+    doc: &str,
+    to: Option<&str>,
+    force: bool,
+    user: &str,
+) -> Result<String, Box<dyn Error>> {
+    let mut stream = connect_as(addr, room, to.unwrap_or(doc), user).await?;
+    let request = ClientMessage::RestoreDoc {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        to: to.map(str::to_string),
+        force,
+    };
+    let mut line = serde_json::to_string(&request)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes()).await?;
+
+    let mut lines = BufReader::new(stream).lines();
+    while let Some(line) = lines.next_line().await? {
+        match serde_json::from_str::<ServerMessage>(&line) {
+            Ok(ServerMessage::DocRestored { doc, .. }) => return Ok(doc),
+            Ok(ServerMessage::Rejected { error }) => return Err(error.into()),
+            _ => {}
+        }
+    }
+    Err("the server closed the connection before the document was restored".into())
+}
+
+/// A server line that is no message, cut short for a log, after why it
+/// didn't parse.
+pub(crate) fn malformed_line(line: &str, err: &serde_json::Error) -> String {
+    // Where the JSON breaks says more than which messages there are.
+    let syntax = serde_json::from_str::<serde::de::IgnoredAny>(line).err();
+    let err = syntax.as_ref().unwrap_or(err);
+    let mut end = line.len().min(MALFORMED_SHOWN);
+    while !line.is_char_boundary(end) {
+        end -= 1;
+    }
+    let more = if end < line.len() { "…" } else { "" };
+    format!("{}: {}{}", err, &line[..end], more)
+}
+
+/// `stats` as the rows of a table with a header, for `/docstats` and the
+/// TUI's `:stats`.
+pub fn stats_table(stats: &[UserStats]) -> Vec<String> {
+    let width = stats
+        .iter()
+        .map(|stats| stats.user.width())
+        .chain([4])
+        .max()
+        .unwrap_or(0);
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs());
+    let header = format!(
+        "{:<width$}  {:>9}  {:>9}  {:>6}  last active",
+        "user", "inserted", "deleted", "edits"
+    );
+    let rows = stats.iter().map(|stats| {
+        let padding = " ".repeat(width - stats.user.width());
+        let active = stats.last_active.map_or("-".to_string(), |at| {
+            let minutes = now.saturating_sub(at) / 60;
+            match (minutes / 60, minutes % 60) {
+                (0, minutes) => format!("{}m ago", minutes),
+                (hours, _) if hours < 48 => format!("{}h ago", hours),
+                (hours, _) => format!("{}d ago", hours / 24),
+            }
+        });
+        format!(
+            "{}{}  {:>7} B  {:>7} B  {:>6}  {}",
+            stats.user, padding, stats.inserted_bytes, stats.deleted_bytes, stats.ops, active
+        )
+    });
+    std::iter::once(header).chain(rows).collect()
+}
+
+/// What the writer sends: sync traffic, or requests like `SetPolicy`.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outgoing {
+    Sync(Message),
+    Request(ClientMessage),
+}
+
+impl From<Message> for Outgoing {
+    fn from(msg: Message) -> Self {
+        Self::Sync(msg)
+    }
+}
+
+/// Runs the line client.
+pub async fn run(
+    addr: &str,
+    user: &str,
+    room: &str,
+    doc: &str,
+    options: Options,
+) -> Result<(), Box<dyn Error>> {
+    let Options {
+        chunk_bytes,
+        prompt: prompt_style,
+        drift: drift_limits,
+        confirm_deletes,
+        network,
+        auto_sync,
+        record,
+    } = options;
+    say!("[client] connecting to {}", addr);
+    if network.active() {
+        say!(
+            "[client] simulating {}ms latency, {}ms jitter, {}% loss{}, seed {}",
+            network.latency_ms,
+            network.jitter_ms,
+            network.loss_pct,
+            if network.unreliable { " (dropped)" } else { "" },
+            network.seed
+        );
+    }
+    let (reader, writer) = netsim::connect(addr, &network).await?;
+    let (reader, writer) = match &record {
+        Some(recorder) => recorder.tap(reader, writer),
+        None => (reader, writer),
+    };
+
+    let (out_tx, mut out_rx) = mpsc::channel::<Outgoing>(64);
 
     let writer_task = tokio::spawn(async move {
         let mut writer = writer;
-        while let Some(msg) = out_rx.recv().await {
-            let json = match serde_json::to_string(&msg) {
-                Ok(json) => json,
-                Err(_) => continue,
-            };
-            if writer.write_all(json.as_bytes()).await.is_err() {
-                break;
-            }
-            if writer.write_all(b"\n").await.is_err() {
-                break;
-            }
-        }
+        let _ = write_lines(&mut out_rx, &mut writer, &WriteStats::new()).await;
     });
 
-    let doc_id = format!("{}/{}", room, doc);
-    let raw_user_id = format!("{}-{}", user, unique_suffix());
-    let scoped_user_id = make_scoped_user_id(&doc_id, &raw_user_id);
-    let replica_id = scoped_user_id.clone();
+    // The server may say the room calls it something else.
+    let mut doc = doc.to_string();
+    let mut doc_id = format!("{}/{}", room, doc);
+    // Without a name the server makes one up, announced in its welcome.
+    let mut user = user.to_string();
+    let raw_user_id = format!("{}-{}", id_prefix(&user), unique_suffix());
+    let mut scoped_user_id = make_scoped_user_id(&doc_id, &raw_user_id);
+    let mut replica_id = scoped_user_id.clone();
     let mut doc_state = TextDoc::new(doc_id.clone(), replica_id.clone());
-    let awareness = Awareness::new(replica_id.clone(), user.to_string());
+    let mut awareness = Awareness::new(replica_id.clone(), user.clone());
     let mut local_user_id: Option<String> = Some(replica_id.clone());
 
-    out_tx
-        .send(Message::Hello {
-            replica_id: scoped_user_id.clone(),
-            user_name: user.to_string(),
-        })
-        .await?;
-    out_tx.send(encode_sync_request(&doc_id, 0)).await?;
+    let hello = Message::Hello {
+        replica_id: scoped_user_id.clone(),
+        user_name: user.clone(),
+    };
+    out_tx.send(hello.into()).await?;
+    out_tx.send(encode_sync_request(&doc_id, 0).into()).await?;
+    let mut pending = PendingOps::default();
+    pending.request_sent();
+    let mut schedule = SyncSchedule::new(auto_sync, Instant::now());
+    // An automatic sync is answered without printing the document again.
+    let mut quiet_sync = false;
 
-    println!("[client] joined room '{}' doc '{}'", room, doc);
-    println!("[client] type /help for commands");
+    say!("[client] joined room '{}' doc '{}'", room, doc);
+    say!("[client] type /help for commands");
 
     let mut server_lines = BufReader::new(reader).lines();
     let mut stdin_lines = BufReader::new(tokio::io::stdin()).lines();
 
     let mut version = 0u64;
     let mut users: HashMap<String, String> = HashMap::new();
+    // The `presence_seq` of `users`; older lists are stale.
+    let mut presence_seq = 0u64;
     let mut cursors: HashMap<String, usize> = HashMap::new();
+    let mut following: Option<String> = None;
+    // A `/snapshot` waiting for the edits typed before it to be confirmed.
+    let mut snapshot_wanted = false;
+    // What the server announced it refuses, checked before sending.
+    let mut limits = ServerLimits::default();
+    // The message of the day last shown, so a welcome repeating it (after
+    // `/nick`, say) doesn't show it again.
+    let mut motd: Option<String> = None;
+    // The document's activity feed, printed by `/activity`.
+    let mut feed: Vec<ActivityEntry> = Vec::new();
+    // Lines from the server that were no message, for `/docstats`.
+    let mut malformed = 0u64;
+    // A `/load` going out chunk by chunk.
+    let mut upload: Option<ChunkedInsert> = None;
+    let mut drift = prompt::Drift::default();
+    // A big delete or `/sed` waiting for a `y`, and the text it was shown
+    // against.
+    let mut confirming: Option<(Vec<Op>, String)> = None;
 
-    loop {
+    'session: loop {
+        if prompt_style == PromptStyle::Rich {
+            drift.seen(version);
+            let suspect = drift.suspect(Instant::now(), &drift_limits);
+            let bytes = doc_state.get_text().len();
+            prompt::update(prompt::render(
+                &doc_id,
+                version,
+                bytes,
+                users.len(),
+                suspect,
+            ));
+        }
+        let upload_wait = upload.as_ref().map(|upload| upload.wait(Instant::now()));
+        let auto_at = schedule.next_auto();
         tokio::select! {
+            _ = tokio::time::sleep_until(auto_at.unwrap_or_else(Instant::now).into()), if auto_at.is_some() => {
+                if !schedule.auto_due(Instant::now()) {
+                    continue;
+                }
+                if out_tx.send(encode_sync_request(&doc_id, version).into()).await.is_err() {
+                    say!("[client] failed to send sync request");
+                    break;
+                }
+                pending.request_sent();
+                quiet_sync = true;
+            }
+            _ = tokio::time::sleep(upload_wait.unwrap_or_default()), if upload_wait.is_some() => {
+                let Some(op) = upload.as_ref().and_then(ChunkedInsert::next_op) else {
+                    continue;
+                };
+                let msg = match encode_update(
+                    &doc_id,
+                    local_user_id.as_deref().unwrap_or(""),
+                    op.clone(),
+                    Vec::new(),
+                    version,
+                ) {
+                    Ok(msg) => msg,
+                    Err(err) => {
+                        say!("[client] failed to encode update: {}", err);
+                        break;
+                    }
+                };
+                apply_op_to_doc(&mut doc_state, &op);
+                if out_tx.send(msg.into()).await.is_err() {
+                    say!("[client] failed to send message");
+                    break;
+                }
+                pending.op_sent(&op);
+                schedule.edited(Instant::now());
+                if let Some(sending) = upload.as_mut() {
+                    sending.chunk_sent(Instant::now());
+                    if sending.is_done() {
+                        say!("[client] loaded {} bytes", sending.bytes());
+                        upload = None;
+                    } else {
+                        say!("[client] {}", sending.progress());
+                    }
+                }
+            }
             line = server_lines.next_line() => {
                 let line = match line {
                     Ok(Some(line)) => line,
                     Ok(None) => {
-                        println!("[client] server closed connection");
+                        say!("[client] server closed connection");
                         break;
                     }
                     Err(err) => {
-                        println!("[client] read error: {}", err);
+                        say!("[client] read error: {}", err);
                         break;
                     }
                 };
 
                 let msg: Message = match serde_json::from_str(&line) {
                     Ok(msg) => msg,
-                    Err(_) => continue,
+                    Err(_) => {
+                        match serde_json::from_str(&line) {
+                            Ok(ServerMessage::Welcome {
+                                limits: announced,
+                                name,
+                                doc: joined,
+                                redirected_from,
+                                access,
+                                motd: welcome_motd,
+                            }) => {
+                                limits = announced;
+                                if let Some(name) = name {
+                                    say!("[client] joined as guest {}", name);
+                                    user = name;
+                                }
+                                if let (Some(joined), Some(from)) = (joined, redirected_from) {
+                                    say!("[client] '{}' is '{}' in this room; joined that", from, joined);
+                                    doc_id = format!("{}/{}", room, joined);
+                                    scoped_user_id = make_scoped_user_id(&doc_id, &raw_user_id);
+                                    replica_id = scoped_user_id.clone();
+                                    doc_state = TextDoc::new(doc_id.clone(), replica_id.clone());
+                                    awareness = Awareness::new(replica_id.clone(), user.clone());
+                                    local_user_id = Some(replica_id.clone());
+                                    doc = joined;
+                                }
+                                if access == Some(Access::Read) {
+                                    say!("[client] you may only read this document; edits will be refused");
+                                }
+                                if let Some(text) = welcome_motd
+                                    && motd.as_ref() != Some(&text)
+                                {
+                                    say!("[client] message of the day:");
+                                    text.lines().for_each(|line| say!("  {}", line));
+                                    motd = Some(text);
+                                }
+                            }
+                            Ok(ServerMessage::Rejected { error }) => {
+                                say!("[client] server rejected an edit: {}", error);
+                                if let Some(upload) = upload.take() {
+                                    say!("[client] upload stopped after {}", upload.summary());
+                                }
+                                // The edit is gone on the server; take its text.
+                                pending.op_acked();
+                                if out_tx.send(encode_sync_request(&doc_id, version).into()).await.is_err() {
+                                    break;
+                                }
+                                pending.request_sent();
+                                schedule.sent(Instant::now());
+                            }
+                            Ok(ServerMessage::DocInfo { policy, acl, seeded_from_template, .. }) => {
+                                if !acl.is_open() {
+                                    say!("[client] access: {}", acl);
+                                }
+                                if seeded_from_template {
+                                    say!("[client] new document, started from the server's template");
+                                }
+                                if !seeded_from_template || !policy.is_off() {
+                                    say!("[client] whitespace policy: {}", policy);
+                                }
+                            }
+                            Ok(ServerMessage::RoomInfo { room, meta }) => match meta.default_doc {
+                                Some(default_doc) if meta.aliases.is_empty() => {
+                                    say!("[client] {} opens on '{}'", room, default_doc);
+                                }
+                                Some(default_doc) => {
+                                    let aliases: Vec<&str> = meta.aliases.keys().map(String::as_str).collect();
+                                    say!("[client] {} opens on '{}', also joined as {}", room, default_doc, aliases.join(", "));
+                                }
+                                None => say!("[client] {} has no default doc", room),
+                            },
+                            Ok(ServerMessage::Members { users: members, presence_seq: seq, .. })
+                                if seq > presence_seq =>
+                            {
+                                presence_seq = seq;
+                                let followed = following.as_ref().filter(|id| users.contains_key(*id));
+                                users = members.into_iter().map(|user| (user.id, user.name)).collect();
+                                cursors.retain(|id, _| users.contains_key(id));
+                                if followed.is_some_and(|id| !users.contains_key(id)) {
+                                    say!("[client] followed user left");
+                                }
+                            }
+                            Ok(ServerMessage::UserEvent { kind, user, detail, .. }) => {
+                                say!("[client] {}", kind.describe(&user.name, detail.as_deref()));
+                            }
+                            Ok(ServerMessage::Notice { text, .. }) => {
+                                say!("[client] notice: {}", text);
+                            }
+                            Ok(ServerMessage::DocRenamed { from, to, .. }) => {
+                                say!("[client] '{}' was moved to '{}'; following it", from, to);
+                                doc_id = format!("{}/{}", room, to);
+                                scoped_user_id = make_scoped_user_id(&doc_id, &raw_user_id);
+                                replica_id = scoped_user_id.clone();
+                                doc_state = build_doc(&doc_id, &replica_id, &doc_state.get_text());
+                                awareness = Awareness::new(replica_id.clone(), user.clone());
+                                local_user_id = Some(replica_id.clone());
+                                doc = to;
+                                // Edits in flight went to the old name.
+                                if out_tx.send(encode_sync_request(&doc_id, version).into()).await.is_err() {
+                                    break;
+                                }
+                                pending.request_sent();
+                                schedule.sent(Instant::now());
+                            }
+                            // Entries sent again on `/sync` are already there.
+                            Ok(ServerMessage::Activity { entry, .. }) if !feed.contains(&entry) => {
+                                if feed.len() >= FEED_LEN {
+                                    feed.remove(0);
+                                }
+                                feed.push(entry);
+                            }
+                            Ok(ServerMessage::Error { error, .. }) => {
+                                say!("[client] server error: {}", error);
+                            }
+                            Err(err) => {
+                                malformed += 1;
+                                say!("[client] malformed line from the server: {}", malformed_line(&line, &err));
+                            }
+                            _ => {}
+                        }
+                        continue;
+                    }
                 };
 
                 let mut ctx = ClientContext {
@@ -83,41 +712,330 @@ pub async fn run(addr: &str, user: &str, room: &str, doc: &str) -> Result<(), Bo
                     replica_id: &replica_id,
                     doc_state: &mut doc_state,
                     version: &mut version,
+                    pending: &mut pending,
                     local_user_id: &mut local_user_id,
                     users: &mut users,
+                    presence_seq: &mut presence_seq,
                     cursors: &mut cursors,
+                    following: following.as_deref(),
+                    upload: upload.as_mut(),
+                    quiet_sync: &mut quiet_sync,
                 };
                 apply_server_message(&msg, &mut ctx);
+                if matches!(msg, Message::SyncResponse { .. }) {
+                    drift.synced(version, Instant::now());
+                }
+                if snapshot_wanted && pending.unacked() == 0 {
+                    snapshot_wanted = false;
+                    spawn_snapshot(addr, room, &doc, &user);
+                }
             }
             input = stdin_lines.next_line() => {
                 let input = match input {
                     Ok(Some(line)) => line,
                     Ok(None) => break,
                     Err(err) => {
-                        println!("[client] stdin error: {}", err);
+                        say!("[client] stdin error: {}", err);
                         break;
                     }
                 };
+                prompt::input_read();
+
+                // Anything but a yes to a big delete or `/sed` drops it.
+                let approved = match confirming.take() {
+                    Some((ops, asked_on)) if confirm::confirmed(&input) => {
+                        if asked_on != doc_state.get_text() {
+                            say!("[client] the document changed meanwhile; nothing changed");
+                            continue;
+                        }
+                        Some(ops)
+                    }
+                    Some(_) => {
+                        say!("[client] nothing changed");
+                        continue;
+                    }
+                    None => None,
+                };
 
                 let current_text = doc_state.get_text();
                 if handle_local_command(&input, &current_text, &users, &cursors) {
                     continue;
                 }
 
+                if handle_follow_command(&input, &current_text, &users, &cursors, &mut following) {
+                    continue;
+                }
+
                 if input.trim().eq_ignore_ascii_case("/quit") {
                     break;
                 }
 
+                if let Some(name) = input.trim().strip_prefix("/nick ") {
+                    let hello = Message::Hello {
+                        replica_id: scoped_user_id.clone(),
+                        user_name: name.trim().to_string(),
+                    };
+                    if out_tx.send(hello.into()).await.is_err() {
+                        say!("[client] failed to send new name");
+                        break;
+                    }
+                    user = name.trim().to_string();
+                    continue;
+                }
+
+                if let Some(rules) = input.trim().strip_prefix("/policy") {
+                    let policy = match parse_policy(rules) {
+                        Ok(policy) => policy,
+                        Err(err) => {
+                            say!("[client] {}", err);
+                            continue;
+                        }
+                    };
+                    let request = ClientMessage::SetPolicy {
+                        room: room.to_string(),
+                        doc: doc.to_string(),
+                        policy,
+                    };
+                    if out_tx.send(Outgoing::Request(request)).await.is_err() {
+                        say!("[client] failed to send policy");
+                        break;
+                    }
+                    continue;
+                }
+
+                if let Some(args) = input.trim().strip_prefix("/acl") {
+                    let acl = match parse_acl(args) {
+                        Ok(acl) => acl,
+                        Err(err) => {
+                            say!("[client] {}", err);
+                            continue;
+                        }
+                    };
+                    let request = ClientMessage::SetAcl {
+                        room: room.to_string(),
+                        doc: doc.to_string(),
+                        acl,
+                    };
+                    if out_tx.send(Outgoing::Request(request)).await.is_err() {
+                        say!("[client] failed to send the ACL");
+                        break;
+                    }
+                    continue;
+                }
+
+                if let Some(args) = input.trim().strip_prefix("/roomdoc") {
+                    let meta = match parse_room_meta(args) {
+                        Ok(meta) => meta,
+                        Err(err) => {
+                            say!("[client] {}", err);
+                            continue;
+                        }
+                    };
+                    let request = ClientMessage::SetRoomMeta {
+                        room: room.to_string(),
+                        meta,
+                    };
+                    if out_tx.send(Outgoing::Request(request)).await.is_err() {
+                        say!("[client] failed to send room settings");
+                        break;
+                    }
+                    continue;
+                }
+
+                if input.trim().eq_ignore_ascii_case("/activity") {
+                    print_activity(&feed);
+                    continue;
+                }
+
+                if input.trim().eq_ignore_ascii_case("/docstats") {
+                    let (addr, room, doc, user) = (addr.to_string(), room.to_string(), doc.to_string(), user.clone());
+                    tokio::spawn(async move {
+                        match doc_stats(&addr, &room, &doc, &user).await {
+                            Ok(stats) if stats.is_empty() => say!("[client] no edits recorded yet"),
+                            Ok(stats) => stats_table(&stats).iter().for_each(|row| say!("  {}", row)),
+                            Err(err) => say!("[client] stats failed: {}", err),
+                        }
+                    });
+                    if malformed > 0 {
+                        say!("[client] malformed lines from the server so far: {}", malformed);
+                    }
+                    continue;
+                }
+
+                if input.trim().eq_ignore_ascii_case("/trash") {
+                    let (addr, room) = (addr.to_string(), room.to_string());
+                    tokio::spawn(async move {
+                        match list_trash(&addr, &room).await {
+                            Ok(docs) if docs.is_empty() => say!("[client] the trash of {} is empty", room),
+                            Ok(docs) => trash_lines(&docs).iter().for_each(|line| say!("  {}", line)),
+                            Err(err) => say!("[client] listing the trash failed: {}", err),
+                        }
+                    });
+                    continue;
+                }
+
+                if let Some(args) = input.trim().strip_prefix("/restore-doc") {
+                    let (trashed, to, force) = match parse_restore_doc(args) {
+                        Ok(parsed) => parsed,
+                        Err(err) => {
+                            say!("[client] {}", err);
+                            continue;
+                        }
+                    };
+                    let (addr, room, user) = (addr.to_string(), room.to_string(), user.clone());
+                    tokio::spawn(async move {
+                        match restore_doc(&addr, &room, &trashed, to.as_deref(), force, &user).await {
+                            Ok(name) => say!("[client] restored {} as {}/{}", trashed, room, name),
+                            Err(err) => say!("[client] restore failed: {}", err),
+                        }
+                    });
+                    continue;
+                }
+
+                if input.trim().eq_ignore_ascii_case("/snapshot") {
+                    if pending.unacked() == 0 {
+                        spawn_snapshot(addr, room, &doc, &user);
+                    } else {
+                        snapshot_wanted = true;
+                        say!(
+                            "[client] snapshot once {} sent edits are confirmed",
+                            pending.unacked()
+                        );
+                    }
+                    continue;
+                }
+
+                if let Some(path) = input.trim().strip_prefix("/load ") {
+                    if upload.is_some() {
+                        say!("[client] still loading; /cancel first");
+                        continue;
+                    }
+                    let contents = match std::fs::read_to_string(path.trim()) {
+                        Ok(contents) => contents,
+                        Err(err) => {
+                            say!("[client] {}: {}", path.trim(), err);
+                            continue;
+                        }
+                    };
+                    if contents.is_empty() {
+                        say!("[client] {} is empty", path.trim());
+                        continue;
+                    }
+                    if let Err(err) = limits.check_doc_size(current_text.len(), contents.len()) {
+                        say!("[client] not loaded: {}", err);
+                        continue;
+                    }
+                    let user_id = local_user_id.as_deref().unwrap_or("");
+                    let pos = current_text.len();
+                    let fits = |chunk: &str| {
+                        let op = Op::Insert {
+                            pos,
+                            text: chunk.to_string(),
+                            pos_chars: None,
+                        };
+                        encode_update(&doc_id, user_id, op, Vec::new(), version)
+                            .is_ok_and(|update| limits.check_update(0, &update).is_ok())
+                    };
+                    let loading =
+                        ChunkedInsert::new(pos, &contents, chunk_bytes, fits).paced(&limits);
+                    if loading.chunks() > 1 {
+                        say!(
+                            "[client] loading {} bytes in {} chunks; /cancel stops",
+                            contents.len(),
+                            loading.chunks()
+                        );
+                    }
+                    upload = Some(loading);
+                    continue;
+                }
+
+                if input.trim().eq_ignore_ascii_case("/cancel") {
+                    match upload.take() {
+                        Some(upload) => {
+                            say!("[client] upload cancelled after {}", upload.summary())
+                        }
+                        None => say!("[client] nothing is loading"),
+                    }
+                    continue;
+                }
+
                 if input.trim().eq_ignore_ascii_case("/sync") {
-                    if out_tx.send(encode_sync_request(&doc_id, version)).await.is_err() {
-                        println!("[client] failed to send sync request");
+                    if let Err(wait) = schedule.manual(Instant::now()) {
+                        say!(
+                            "[client] synced a moment ago; try again in {:.1}s",
+                            wait.as_secs_f64()
+                        );
+                        continue;
+                    }
+                    if out_tx.send(encode_sync_request(&doc_id, version).into()).await.is_err() {
+                        say!("[client] failed to send sync request");
                         break;
                     }
+                    pending.request_sent();
                     continue;
                 }
 
-                if let Some(op) = parse_command(&input) {
-                    if let Op::Cursor { pos } = op {
+                let (dry_run, command) = match input.trim().strip_prefix("/dry-run") {
+                    Some(rest) => (true, rest),
+                    None => (false, input.as_str()),
+                };
+                let sed = input.trim().strip_prefix("/sed").filter(|args| {
+                    args.is_empty() || args.starts_with(char::is_whitespace)
+                });
+                let ops = match (approved, sed) {
+                    (Some(ops), _) => ops,
+                    (None, Some(args)) => {
+                        let found = parse_sed(args).map(|(pattern, replacement)| {
+                            replace::find(&current_text, &pattern, &replacement, true)
+                        });
+                        let found = match found {
+                            Ok(found) if found.is_empty() => {
+                                say!("[client] no match");
+                                continue;
+                            }
+                            Ok(found) => found,
+                            Err(err) => {
+                                say!("[client] {}", err);
+                                continue;
+                            }
+                        };
+                        let lines = confirm::replace_lines(&current_text, &found);
+                        lines.iter().for_each(|line| say!("{}", line));
+                        let ops = replace::to_ops(&found);
+                        let many = |threshold: DeleteThreshold| found.len() > threshold.replacements;
+                        if confirm_deletes.is_some_and(many) {
+                            say!("[client] replace them? [y/N]");
+                            confirming = Some((ops, current_text.clone()));
+                            continue;
+                        }
+                        ops
+                    }
+                    (None, None) => {
+                        let Some(op) = parse_command(command) else {
+                            if !input.trim().is_empty() {
+                                say!("[client] unknown command, try /help");
+                            }
+                            continue;
+                        };
+                        match confirm::plan(op, &current_text, dry_run, confirm_deletes) {
+                            Plan::Send(op) => vec![op],
+                            Plan::Confirm(op, lines) => {
+                                lines.iter().for_each(|line| say!("{}", line));
+                                confirming = Some((vec![op], current_text.clone()));
+                                continue;
+                            }
+                            Plan::DryRun(lines) => {
+                                lines.iter().for_each(|line| say!("{}", line));
+                                continue;
+                            }
+                        }
+                    }
+                };
+                // There is no batch message: a `/sed` goes out as one update
+                // per op, last match first.
+                let mut doc_len = current_text.len();
+                for op in ops {
+                    if let Op::Cursor { pos, .. } = op {
                         awareness.set_cursor(&doc_id, pos);
                         if let Some(user_id) = local_user_id.as_deref() {
                             let msg = Message::Presence {
@@ -125,53 +1043,90 @@ pub async fn run(addr: &str, user: &str, room: &str, doc: &str) -> Result<(), Bo
                                 document_id: doc_id.clone(),
                                 cursor_pos: Some(pos),
                             };
-                            if out_tx.send(msg).await.is_err() {
-                                println!("[client] failed to send presence");
-                                break;
+                            if out_tx.send(msg.into()).await.is_err() {
+                                say!("[client] failed to send presence");
+                                break 'session;
                             }
                         }
                     } else {
-                        apply_local_op(&mut doc_state, &op);
                         let combined_delta = Vec::new();
                         let msg = encode_update(
                             &doc_id,
                             local_user_id.as_deref().unwrap_or(""),
-                            op,
+                            op.clone(),
                             combined_delta,
                             version,
                         );
                         match msg {
                             Ok(msg) => {
-                                if out_tx.send(msg).await.is_err() {
-                                    println!("[client] failed to send message");
-                                    break;
+                                if let Err(err) = limits.check_update(doc_len, &msg) {
+                                    say!("[client] not sent: {}", err);
+                                    continue 'session;
+                                }
+                                apply_op_to_doc(&mut doc_state, &op);
+                                doc_len = match &op {
+                                    Op::Insert { text, .. } => doc_len + text.len(),
+                                    Op::Delete { len, .. } => doc_len - (*len).min(doc_len),
+                                    _ => doc_len,
+                                };
+                                if out_tx.send(msg.into()).await.is_err() {
+                                    say!("[client] failed to send message");
+                                    break 'session;
+                                }
+                                pending.op_sent(&op);
+                                schedule.edited(Instant::now());
+                                if let Some(upload) = upload.as_mut() {
+                                    upload.adjust_for_remote(&op);
                                 }
                             }
                             Err(err) => {
-                                println!("[client] failed to encode update: {}", err);
-                                break;
+                                say!("[client] failed to encode update: {}", err);
+                                break 'session;
                             }
                         }
                     }
-                } else if !input.trim().is_empty() {
-                    println!("[client] unknown command, try /help");
                 }
+
             }
         }
     }
 
+    if let Some(upload) = upload {
+        say!("[client] upload stopped after {}", upload.summary());
+    }
     writer_task.abort();
     Ok(())
 }
 
+/// Takes a snapshot over a connection of its own, printing the result.
+fn spawn_snapshot(addr: &str, room: &str, doc: &str, user: &str) {
+    let (addr, room, doc) = (addr.to_string(), room.to_string(), doc.to_string());
+    let user = user.to_string();
+    tokio::spawn(async move {
+        match snapshot(&addr, &room, &doc, &user).await {
+            Ok((version, bytes)) => {
+                say!("[client] snapshot saved at v{} ({} bytes)", version, bytes)
+            }
+            Err(err) => say!("[client] snapshot failed: {}", err),
+        }
+    });
+}
+
 struct ClientContext<'a> {
     doc_id: &'a str,
     replica_id: &'a str,
     doc_state: &'a mut TextDoc,
     version: &'a mut u64,
+    pending: &'a mut PendingOps,
     local_user_id: &'a mut Option<String>,
     users: &'a mut HashMap<String, String>,
+    presence_seq: &'a mut u64,
     cursors: &'a mut HashMap<String, usize>,
+    following: Option<&'a str>,
+    /// Chunks of a `/load` still to go, kept in place around remote edits.
+    upload: Option<&'a mut ChunkedInsert>,
+    /// The next sync response answers an automatic sync.
+    quiet_sync: &'a mut bool,
 }
 
 fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
@@ -184,7 +1139,6 @@ fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
                 return;
             }
             ctx.users.insert(replica_id.clone(), user_name.clone());
-            println!("[client] user online: {}", user_name);
         }
         Message::Update { .. } => {
             if let Some((update_doc_id, payload, server_version)) = decode_update(msg) {
@@ -195,8 +1149,25 @@ fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
                     // Treat `op` as the single source of truth for remote edits.
                     // Ignore `payload.delta` to avoid double-applying changes.
                     apply_op_to_doc(ctx.doc_state, &payload.op);
+                    if let Some(upload) = ctx.upload.as_deref_mut() {
+                        upload.adjust_for_remote(&payload.op);
+                    }
+                } else if matches!(payload.op, Op::Insert { .. } | Op::Delete { .. }) {
+                    ctx.pending.op_acked();
                 }
                 *ctx.version = server_version;
+                if let Op::Cursor { pos, .. } = payload.op {
+                    ctx.cursors.insert(payload.user_id.clone(), pos);
+                }
+                if ctx.following == Some(payload.user_id.as_str()) {
+                    let pos = match &payload.op {
+                        Op::Insert { pos, text, .. } => pos.saturating_add(text.len()),
+                        Op::Delete { pos, .. } | Op::Cursor { pos, .. } => *pos,
+                        Op::Selection { head, .. } => *head,
+                    };
+                    ctx.cursors.insert(payload.user_id.clone(), pos);
+                    print_follow_line(&ctx.doc_state.get_text(), ctx.users, &payload.user_id, pos);
+                }
             }
         }
         Message::Presence {
@@ -210,10 +1181,16 @@ fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
             match cursor_pos {
                 Some(pos) => {
                     ctx.cursors.insert(user_id.clone(), *pos);
+                    if ctx.following == Some(user_id.as_str()) {
+                        print_follow_line(&ctx.doc_state.get_text(), ctx.users, user_id, *pos);
+                    }
                 }
                 None => {
                     ctx.cursors.remove(user_id);
                     ctx.users.remove(user_id);
+                    if ctx.following == Some(user_id.as_str()) {
+                        say!("[client] followed user left");
+                    }
                 }
             }
         }
@@ -222,15 +1199,41 @@ fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
                 if sync_doc_id != ctx.doc_id {
                     return;
                 }
-                *ctx.doc_state = build_doc(ctx.doc_id, ctx.replica_id, &payload.text);
+                if let Some(err) = payload.error {
+                    say!("[client] join refused: {}", err);
+                    return;
+                }
+                // Local ops sent after the request stay; only the
+                // difference to the server's text is applied.
+                let target = ctx.pending.rebase(&payload.text);
+                let ops = snapshot::diff(&ctx.doc_state.get_text(), &target);
+                for op in &ops {
+                    apply_op_to_doc(ctx.doc_state, op);
+                    if let Some(upload) = ctx.upload.as_deref_mut() {
+                        upload.adjust_for_remote(op);
+                    }
+                }
                 *ctx.version = server_version;
                 ctx.cursors.clear();
                 ctx.users.clear();
+                *ctx.presence_seq = payload.presence_seq;
                 for user in payload.users {
                     ctx.users.insert(user.id, user.name);
                 }
                 *ctx.local_user_id = Some(ctx.replica_id.to_string());
-                println!("[client] sync complete (v{})", *ctx.version);
+                // A background sync that found nothing to fix stays quiet.
+                if std::mem::take(ctx.quiet_sync) {
+                    if ops.is_empty() {
+                        return;
+                    }
+                    say!(
+                        "[client] background sync fixed {} differences (v{})",
+                        ops.len(),
+                        *ctx.version
+                    );
+                } else {
+                    say!("[client] sync complete (v{})", *ctx.version);
+                }
                 print_document(&ctx.doc_state.get_text());
             }
         }
@@ -238,6 +1241,141 @@ fn apply_server_message(msg: &Message, ctx: &mut ClientContext<'_>) {
     }
 }
 
+/// The policy of `/policy final-newline trim strict`, any of them, or
+/// `/policy off`.
+fn parse_policy(rules: &str) -> Result<WhitespacePolicy, String> {
+    let mut policy = WhitespacePolicy::default();
+    for rule in rules.split_whitespace() {
+        match rule {
+            "final-newline" => policy.final_newline = true,
+            "trim" => policy.trim_trailing = true,
+            "strict" => policy.strict = true,
+            "off" => policy = WhitespacePolicy::default(),
+            _ => {
+                return Err(format!(
+                    "unknown rule '{}': try final-newline, trim, strict or off",
+                    rule
+                ));
+            }
+        }
+    }
+    if rules.trim().is_empty() {
+        return Err("usage: /policy [final-newline] [trim] [strict] | off".to_string());
+    }
+    Ok(policy)
+}
+
+/// The pattern and replacement of `/sed [--regex] <pattern> <replacement>`.
+/// A pattern with spaces goes in double quotes; the replacement is the rest
+/// of the line, and left out deletes the matches.
+fn parse_sed(args: &str) -> Result<(Pattern, String), String> {
+    let usage = || "usage: /sed [--regex] <pattern> <replacement>".to_string();
+    let args = args.trim_start();
+    let (regex, args) = match args.strip_prefix("--regex") {
+        Some(rest) if rest.is_empty() || rest.starts_with(char::is_whitespace) => {
+            (true, rest.trim_start())
+        }
+        _ => (false, args),
+    };
+    let (pattern, rest) = match args.strip_prefix('"') {
+        Some(quoted) => quoted
+            .split_once('"')
+            .ok_or_else(|| "no closing quote after the pattern".to_string())?,
+        None => args.split_once(char::is_whitespace).unwrap_or((args, "")),
+    };
+    if pattern.is_empty() {
+        return Err(usage());
+    }
+    let replacement = rest.strip_prefix(char::is_whitespace).unwrap_or(rest);
+    Ok((Pattern::new(pattern, regex)?, replacement.to_string()))
+}
+
+/// The ACL of `/acl writers=ada,bob readers=cy`, either list left out
+/// letting everyone, or of `/acl open`.
+fn parse_acl(args: &str) -> Result<Acl, String> {
+    let usage = || "usage: /acl [writers=<names>] [readers=<names>] | open".to_string();
+    let mut acl = Acl::default();
+    for part in args.split_whitespace() {
+        let names = |list: &str| {
+            list.split(',')
+                .filter(|name| !name.is_empty())
+                .map(String::from)
+                .collect()
+        };
+        match part.split_once('=') {
+            Some(("writers", list)) => acl.writers = names(list),
+            Some(("readers", list)) => acl.readers = names(list),
+            None if part == "open" => acl = Acl::default(),
+            _ => return Err(usage()),
+        }
+    }
+    if args.trim().is_empty() {
+        return Err(usage());
+    }
+    Ok(acl)
+}
+
+/// The settings of `/roomdoc <doc> [alias...]`: `doc` is joined for an
+/// empty doc name and for each alias; `/roomdoc off` clears them.
+fn parse_room_meta(args: &str) -> Result<RoomMeta, String> {
+    let mut words = args.split_whitespace();
+    let Some(doc) = words.next() else {
+        return Err("usage: /roomdoc <doc> [alias...] | off".to_string());
+    };
+    if doc == "off" {
+        return Ok(RoomMeta::default());
+    }
+    Ok(RoomMeta {
+        default_doc: Some(doc.to_string()),
+        aliases: words
+            .map(|alias| (alias.to_string(), doc.to_string()))
+            .collect(),
+    })
+}
+
+/// The doc, new name and `--force` of `/restore-doc <doc> [<new name>]
+/// [--force]`.
+fn parse_restore_doc(args: &str) -> Result<(String, Option<String>, bool), String> {
+    let mut force = false;
+    let mut names = Vec::new();
+    for word in args.split_whitespace() {
+        match word {
+            "--force" => force = true,
+            name => names.push(name.to_string()),
+        }
+    }
+    match <[String; 1]>::try_from(names) {
+        Ok([doc]) => Ok((doc, None, force)),
+        Err(names) => match <[String; 2]>::try_from(names) {
+            Ok([doc, to]) => Ok((doc, Some(to), force)),
+            Err(_) => Err("usage: /restore-doc <doc> [<new name>] [--force]".to_string()),
+        },
+    }
+}
+
+/// The documents of a room's trash, one line each, for `/trash`.
+fn trash_lines(docs: &[WireTrashed]) -> Vec<String> {
+    let now_ms = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let width = docs.iter().map(|doc| doc.name.width()).max().unwrap_or(0);
+    docs.iter()
+        .map(|doc| {
+            let padding = " ".repeat(width - doc.name.width());
+            let hours = now_ms.saturating_sub(doc.deleted_at_ms) / (60 * 60 * 1000);
+            let ago = if hours < 48 {
+                format!("{}h ago", hours)
+            } else {
+                format!("{}d ago", hours / 24)
+            };
+            format!(
+                "{}{}  {:>7} B  deleted {}",
+                doc.name, padding, doc.bytes, ago
+            )
+        })
+        .collect()
+}
+
 fn parse_command(input: &str) -> Option<Op> {
     let trimmed = input.trim();
     if trimmed.is_empty() {
@@ -270,19 +1408,28 @@ fn parse_insert(rest: &str) -> Option<Op> {
     let mut parts = rest.splitn(2, ' ');
     let pos = parts.next()?.parse::<usize>().ok()?;
     let text = parts.next().unwrap_or("").to_string();
-    Some(Op::Insert { pos, text })
+    Some(Op::Insert {
+        pos,
+        text,
+        pos_chars: None,
+    })
 }
 
 fn parse_delete(rest: &str) -> Option<Op> {
     let mut parts = rest.split_whitespace();
     let pos = parts.next()?.parse::<usize>().ok()?;
     let len = parts.next()?.parse::<usize>().ok()?;
-    Some(Op::Delete { pos, len })
+    Some(Op::Delete {
+        pos,
+        len,
+        pos_chars: None,
+        len_chars: None,
+    })
 }
 
 fn parse_cursor(rest: &str) -> Option<Op> {
     let pos = rest.trim().parse::<usize>().ok()?;
-    Some(Op::Cursor { pos })
+    Some(Op::Cursor { pos, at: None })
 }
 
 fn handle_local_command(
@@ -301,115 +1448,176 @@ fn handle_local_command(
         return true;
     }
     if trimmed.eq_ignore_ascii_case("/users") {
-        println!("[client] users:");
+        say!("[client] users:");
         for (id, name) in users {
-            println!("  {}: {}", id, name);
+            say!("  {}: {}", id, name);
         }
         return true;
     }
     if trimmed.eq_ignore_ascii_case("/cursors") {
-        println!("[client] cursors:");
+        say!("[client] cursors:");
         for (id, pos) in cursors {
             let name = users.get(id).map(String::as_str).unwrap_or("unknown");
-            println!("  {} ({}): {}", id, name, pos);
+            let at = position::line_col(text, *pos);
+            say!("  {} {} ({}, byte {})", name, at, id, pos);
         }
         return true;
     }
     false
 }
 
-fn print_help() {
-    println!("Commands:");
-    println!("  /insert <pos> <text>   (or: i <pos> <text>)");
-    println!("  /delete <pos> <len>    (or: d <pos> <len>)");
-    println!("  /cursor <pos>          (or: c <pos>)");
-    println!("  /sync");
-    println!("  /show");
-    println!("  /users");
-    println!("  /cursors");
-    println!("  /quit");
-}
-
-fn print_document(text: &str) {
-    println!("[doc] {} bytes", text.len());
-    for (idx, line) in text.lines().enumerate() {
-        println!("{:>4} | {}", idx + 1, line);
+fn handle_follow_command(
+    input: &str,
+    text: &str,
+    users: &HashMap<String, String>,
+    cursors: &HashMap<String, usize>,
+    following: &mut Option<String>,
+) -> bool {
+    let trimmed = input.trim();
+    let Some(rest) = trimmed
+        .strip_prefix("/follow")
+        .filter(|rest| rest.is_empty() || rest.starts_with(char::is_whitespace))
+    else {
+        return false;
+    };
+    let query = rest.trim();
+    if query.is_empty() {
+        match following.as_deref() {
+            Some(id) => {
+                let name = users.get(id).map(String::as_str).unwrap_or("unknown");
+                say!("[client] following {} ({})", name, id);
+            }
+            None => say!("[client] not following anyone, try /follow <user>"),
+        }
+        return true;
+    }
+    if query.eq_ignore_ascii_case("off") {
+        if following.take().is_some() {
+            say!("[client] stopped following");
+        } else {
+            say!("[client] not following anyone");
+        }
+        return true;
     }
+    match resolve_user(users, query) {
+        Ok(id) => {
+            let name = users.get(&id).map(String::as_str).unwrap_or("unknown");
+            say!("[client] following {} ({})", name, id);
+            if let Some(pos) = cursors.get(&id) {
+                print_follow_line(text, users, &id, *pos);
+            }
+            *following = Some(id);
+        }
+        Err(err) => say!("[client] {}", err),
+    }
+    true
 }
 
-fn build_doc(doc_id: &str, replica_id: &str, text: &str) -> TextDoc {
-    let mut doc = TextDoc::new(doc_id, replica_id);
-    if !text.is_empty() {
-        doc.insert(0, text);
+/// Resolves a user by exact id or name first, then by unique id/name prefix.
+fn resolve_user(users: &HashMap<String, String>, query: &str) -> Result<String, String> {
+    if users.contains_key(query) {
+        return Ok(query.to_string());
+    }
+    let exact: Vec<&String> = users
+        .iter()
+        .filter(|(_, name)| name.as_str() == query)
+        .map(|(id, _)| id)
+        .collect();
+    if exact.len() == 1 {
+        return Ok(exact[0].clone());
     }
-    doc
-}
 
-fn apply_local_op(doc: &mut TextDoc, op: &Op) {
-    match op {
-        Op::Insert { pos, text } => {
-            let current = doc.get_text();
-            let char_pos = byte_to_char_index(&current, *pos);
-            doc.insert(char_pos, text);
-        }
-        Op::Delete { pos, len } => {
-            let current = doc.get_text();
-            if current.is_empty() {
-                return;
-            }
-            let start = clamp_to_boundary(&current, *pos);
-            let end = clamp_to_boundary(&current, start.saturating_add(*len));
-            if start >= end {
-                return;
-            }
-            let char_start = current[..start].chars().count();
-            let char_len = current[start..end].chars().count();
-            if char_len > 0 {
-                doc.delete(char_start, char_len);
-            }
+    let query_lower = query.to_lowercase();
+    let mut matches: Vec<(&String, &String)> = users
+        .iter()
+        .filter(|(id, name)| {
+            let raw_id = id.split_once('|').map(|(_, raw)| raw).unwrap_or(id);
+            id.starts_with(query)
+                || raw_id.starts_with(query)
+                || name.to_lowercase().starts_with(&query_lower)
+        })
+        .collect();
+    matches.sort();
+    match matches.len() {
+        0 => Err(format!("no user matches '{}'", query)),
+        1 => Ok(matches[0].0.clone()),
+        _ => {
+            let candidates: Vec<String> = matches
+                .iter()
+                .map(|(id, name)| format!("{} ({})", name, id))
+                .collect();
+            Err(format!(
+                "'{}' is ambiguous: {}",
+                query,
+                candidates.join(", ")
+            ))
         }
-        Op::Cursor { .. } => {}
     }
 }
 
-fn apply_op_to_doc(doc: &mut TextDoc, op: &Op) {
-    match op {
-        Op::Insert { pos, text } => {
-            let current = doc.get_text();
-            let char_pos = byte_to_char_index(&current, *pos);
-            doc.insert(char_pos, text);
-        }
-        Op::Delete { pos, len } => {
-            let current = doc.get_text();
-            if current.is_empty() {
-                return;
-            }
-            let start = clamp_to_boundary(&current, *pos);
-            let end = clamp_to_boundary(&current, start.saturating_add(*len));
-            if start >= end {
-                return;
-            }
-            let char_start = current[..start].chars().count();
-            let char_len = current[start..end].chars().count();
-            if char_len > 0 {
-                doc.delete(char_start, char_len);
-            }
-        }
-        Op::Cursor { .. } => {}
+fn print_follow_line(text: &str, users: &HashMap<String, String>, user_id: &str, pos: usize) {
+    let name = users.get(user_id).map(String::as_str).unwrap_or(user_id);
+    let (line_idx, col) = cursor_line_col(text, pos);
+    let line = text.split('\n').nth(line_idx).unwrap_or("");
+    let prefix = format!("{} @ {}:{} | ", name, line_idx + 1, col + 1);
+    say!("{}{}", prefix, line);
+    say!("{}^", " ".repeat(prefix.width() + col));
+}
+
+fn print_help() {
+    say!("Commands:");
+    say!("  /insert <pos> <text>   (or: i <pos> <text>)");
+    say!("  /delete <pos> <len>    (or: d <pos> <len>)");
+    say!("  /cursor <pos>          (or: c <pos>)");
+    say!("  /dry-run <edit>        show what an edit would change, sending nothing");
+    say!("  /sed [--regex] <pattern> <replacement>  replace every match, e.g. /sed teh the");
+    say!("  /load <path>           append a file, in chunks if it is long");
+    say!("  /cancel                stop a /load, keeping what was sent");
+    say!("  /sync                  fetch the doc again (at most every 2 s)");
+    say!("  /snapshot              save the doc with a revision now");
+    say!("  /docstats              who wrote how much of the doc, and malformed server lines");
+    say!("  /trash                 documents deleted from the room, still restorable");
+    say!("  /restore-doc <doc> [<new name>] [--force]  bring a deleted doc back");
+    say!("  /activity              snapshots, big deletions, joins and leaves");
+    say!("  /policy <rules>|off    tidy whitespace on save: final-newline, trim, strict");
+    say!("  /acl writers=<names> readers=<names>|open  who may edit and read the doc");
+    say!("  /roomdoc <doc> [alias...]|off  the doc joined for an empty name or an alias");
+    say!("  /show");
+    say!("  /users");
+    say!("  /nick <name>           change the name others see");
+    say!("  /cursors");
+    say!("  /follow <user>         (or: /follow off)");
+    say!("  /quit");
+}
+
+/// Prints the activity feed, oldest first, with UTC times.
+fn print_activity(feed: &[ActivityEntry]) {
+    if feed.is_empty() {
+        say!("[client] no activity yet");
+        return;
     }
+    say!("[client] activity:");
+    for entry in feed {
+        say!("  {}  {}", utc_clock(entry.at_ms), entry.describe());
+    }
+}
+
+/// `hh:mm:ss` of a Unix time in milliseconds, in UTC.
+fn utc_clock(at_ms: u64) -> String {
+    let secs = (at_ms / 1000) % 86_400;
+    format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
 }
 
-fn clamp_to_boundary(text: &str, pos: usize) -> usize {
-    let mut pos = pos.min(text.len());
-    while pos > 0 && !text.is_char_boundary(pos) {
-        pos -= 1;
+fn print_document(text: &str) {
+    say!("[doc] {} bytes", text.len());
+    for (idx, line) in text.lines().enumerate() {
+        say!("{:>4} | {}", idx + 1, line);
     }
-    pos
 }
 
-fn byte_to_char_index(text: &str, byte_pos: usize) -> usize {
-    let byte_pos = clamp_to_boundary(text, byte_pos);
-    text[..byte_pos].chars().count()
+/// Start of the user id for `user`, who is a guest if unnamed.
+fn id_prefix(user: &str) -> &str {
+    if user.is_empty() { "guest" } else { user }
 }
 
 fn unique_suffix() -> u128 {
@@ -419,3 +1627,71 @@ fn unique_suffix() -> u128 {
         .unwrap_or_default()
         .as_millis()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn users() -> HashMap<String, String> {
+        [
+            ("room/doc|ada-1", "Ada"),
+            ("room/doc|adam-2", "Adam"),
+            ("room/doc|bob-3", "bob"),
+        ]
+        .into_iter()
+        .map(|(id, name)| (id.to_string(), name.to_string()))
+        .collect()
+    }
+
+    #[test]
+    fn users_resolve_by_id_name_or_unique_prefix() {
+        let users = users();
+        assert_eq!(
+            resolve_user(&users, "room/doc|bob-3").unwrap(),
+            "room/doc|bob-3"
+        );
+        // An exact name wins over the longer names it starts.
+        assert_eq!(resolve_user(&users, "Ada").unwrap(), "room/doc|ada-1");
+        assert_eq!(resolve_user(&users, "BO").unwrap(), "room/doc|bob-3");
+        assert_eq!(resolve_user(&users, "adam-").unwrap(), "room/doc|adam-2");
+
+        let err = resolve_user(&users, "ad").unwrap_err();
+        assert_eq!(
+            err,
+            "'ad' is ambiguous: Ada (room/doc|ada-1), Adam (room/doc|adam-2)"
+        );
+        assert_eq!(
+            resolve_user(&users, "cy").unwrap_err(),
+            "no user matches 'cy'"
+        );
+    }
+
+    #[test]
+    fn follow_is_not_a_prefix_of_other_commands() {
+        let (users, cursors) = (users(), HashMap::new());
+        let mut following = None;
+        assert!(!handle_follow_command(
+            "/followers",
+            "",
+            &users,
+            &cursors,
+            &mut following
+        ));
+        assert!(handle_follow_command(
+            "/follow bob",
+            "",
+            &users,
+            &cursors,
+            &mut following
+        ));
+        assert_eq!(following.as_deref(), Some("room/doc|bob-3"));
+        assert!(handle_follow_command(
+            " /follow off",
+            "",
+            &users,
+            &cursors,
+            &mut following
+        ));
+        assert_eq!(following, None);
+    }
+}
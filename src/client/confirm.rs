@@ -0,0 +1,320 @@
+//! Second thoughts before edits: deletes past a threshold show what they
+//! remove and wait for a `y`, so do `/sed`s replacing many matches, and
+//! `/dry-run <edit>` shows what an edit would change without sending it.
+
+use crate::protocol::Op;
+use crate::replace::Replacement;
+use crate::snapshot::{self, Change};
+use crate::textpos::clamp_to_boundary;
+use serde::Deserialize;
+
+/// Lines of deleted text shown before asking.
+const PREVIEW_LINES: usize = 5;
+/// Characters shown of each of them.
+const PREVIEW_WIDTH: usize = 80;
+
+/// The config's `[client]` section.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    /// Ask before big deletes; unset, only when stdin is a terminal.
+    pub confirm_destructive: Option<bool>,
+    /// Deletes of more bytes than this ask first...
+    pub confirm_above_bytes: usize,
+    /// ... and so do those of more than this share of the document.
+    pub confirm_above_percent: usize,
+    /// `/sed`s replacing more matches than this ask first.
+    pub confirm_above_replacements: usize,
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        Self {
+            confirm_destructive: None,
+            confirm_above_bytes: 200,
+            confirm_above_percent: 20,
+            confirm_above_replacements: 20,
+        }
+    }
+}
+
+impl ClientConfig {
+    /// When deletes ask first, given `--confirm-destructive` and whether
+    /// stdin is a terminal; `None` if they never do.
+    pub fn threshold(&self, flag: Option<bool>, interactive: bool) -> Option<DeleteThreshold> {
+        let enabled = flag.or(self.confirm_destructive).unwrap_or(interactive);
+        enabled.then_some(DeleteThreshold {
+            bytes: self.confirm_above_bytes,
+            percent: self.confirm_above_percent,
+            replacements: self.confirm_above_replacements,
+        })
+    }
+}
+
+/// Deletes past either limit wait for a `y`, and so do `/sed`s replacing
+/// more than `replacements` matches.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeleteThreshold {
+    pub bytes: usize,
+    pub percent: usize,
+    pub replacements: usize,
+}
+
+/// What becomes of an edit typed in the client.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Plan {
+    Send(Op),
+    /// Print the lines and send the op once the user says `y`.
+    Confirm(Op, Vec<String>),
+    /// Print the lines of what it would change; nothing is sent.
+    DryRun(Vec<String>),
+}
+
+/// What to do with `op`, typed against `text`, after `/dry-run` if
+/// `dry_run`; deletes past `threshold` are confirmed first.
+pub(super) fn plan(op: Op, text: &str, dry_run: bool, threshold: Option<DeleteThreshold>) -> Plan {
+    let mut after = text.to_string();
+    snapshot::apply_to_text(&mut after, &op);
+    if dry_run {
+        return Plan::DryRun(dry_run_lines(text, &after));
+    }
+    let removed = text.len() - after.len().min(text.len());
+    let Op::Delete { pos, .. } = &op else {
+        return Plan::Send(op);
+    };
+    let past = threshold.is_some_and(|threshold| {
+        removed > threshold.bytes || removed * 100 > text.len() * threshold.percent
+    });
+    if !past {
+        return Plan::Send(op);
+    }
+    let start = clamp_to_boundary(text, *pos);
+    let lines = confirm_lines(&text[start..start + removed], text.len());
+    Plan::Confirm(op, lines)
+}
+
+/// Whether the answer to the question `Plan::Confirm` asked is yes.
+pub(super) fn confirmed(answer: &str) -> bool {
+    matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+fn confirm_lines(removed: &str, doc_len: usize) -> Vec<String> {
+    let mut lines = vec![format!(
+        "[client] this deletes {} bytes ({}% of the document):",
+        removed.len(),
+        removed.len() * 100 / doc_len.max(1)
+    )];
+    for line in removed.lines().take(PREVIEW_LINES) {
+        lines.push(format!("  | {}", clip(line)));
+    }
+    let more = removed.lines().count().saturating_sub(PREVIEW_LINES);
+    if more > 0 {
+        lines.push(format!("  | ... and {} more lines", more));
+    }
+    lines.push("[client] delete it? [y/N]".to_string());
+    lines
+}
+
+/// The lines `/sed` shows before replacing: how many matches, and the
+/// first lines they are on before and after.
+pub(super) fn replace_lines(text: &str, replacements: &[Replacement]) -> Vec<String> {
+    // Matches on the same line show together.
+    let mut spans: Vec<(usize, usize, Vec<&Replacement>)> = Vec::new();
+    for replacement in replacements {
+        match spans.last_mut() {
+            Some((_, end, on_line)) if replacement.start <= *end => {
+                *end = line_end(text, replacement.end.max(*end));
+                on_line.push(replacement);
+            }
+            _ => {
+                let start = text[..replacement.start].rfind('\n').map_or(0, |at| at + 1);
+                let end = line_end(text, replacement.end);
+                spans.push((start, end, vec![replacement]));
+            }
+        }
+    }
+    let mut lines = vec![format!(
+        "[client] {} {} on {} {}:",
+        replacements.len(),
+        if replacements.len() == 1 {
+            "match"
+        } else {
+            "matches"
+        },
+        spans.len(),
+        if spans.len() == 1 { "line" } else { "lines" }
+    )];
+    for (start, end, on_line) in spans.iter().take(PREVIEW_LINES) {
+        let mut after = String::new();
+        let mut copied = *start;
+        for replacement in on_line {
+            after.push_str(&text[copied..replacement.start]);
+            after.push_str(&replacement.with);
+            copied = replacement.end;
+        }
+        after.push_str(&text[copied..*end]);
+        for line in text[*start..*end].lines() {
+            lines.push(format!("- {}", clip(line)));
+        }
+        for line in after.lines() {
+            lines.push(format!("+ {}", clip(line)));
+        }
+    }
+    let more = spans.len().saturating_sub(PREVIEW_LINES);
+    if more > 0 {
+        lines.push(format!("  ... and {} more lines", more));
+    }
+    lines
+}
+
+fn line_end(text: &str, pos: usize) -> usize {
+    text[pos..].find('\n').map_or(text.len(), |at| pos + at)
+}
+
+fn dry_run_lines(before: &str, after: &str) -> Vec<String> {
+    let mut lines: Vec<String> = snapshot::diff_lines(before, after)
+        .into_iter()
+        .filter_map(|change| match change {
+            Change::Keep(_) => None,
+            Change::Delete(line) => Some(format!("- {}", clip(line))),
+            Change::Insert(line) => Some(format!("+ {}", clip(line))),
+        })
+        .collect();
+    if lines.is_empty() {
+        lines.push("[client] dry run: no change to the text".to_string());
+    } else {
+        lines.push(format!(
+            "[client] dry run: {} -> {} bytes, nothing sent",
+            before.len(),
+            after.len()
+        ));
+    }
+    lines
+}
+
+fn clip(line: &str) -> String {
+    match line.char_indices().nth(PREVIEW_WIDTH) {
+        Some((end, _)) => format!("{}…", &line[..end]),
+        None => line.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const THRESHOLD: DeleteThreshold = DeleteThreshold {
+        bytes: 200,
+        percent: 20,
+        replacements: 20,
+    };
+
+    fn delete(pos: usize, len: usize) -> Op {
+        Op::Delete {
+            pos,
+            len,
+            pos_chars: None,
+            len_chars: None,
+        }
+    }
+
+    #[test]
+    fn big_deletes_wait_for_a_yes() {
+        let text = "x".repeat(1000);
+        assert_eq!(
+            plan(delete(0, 150), &text, false, Some(THRESHOLD)),
+            Plan::Send(delete(0, 150))
+        );
+        // Past the end only what is there counts.
+        assert!(matches!(
+            plan(delete(900, 999_999), &text, false, Some(THRESHOLD)),
+            Plan::Send(_)
+        ));
+        let Plan::Confirm(op, lines) = plan(delete(0, 999_999), &text, false, Some(THRESHOLD))
+        else {
+            panic!("a delete of everything is confirmed");
+        };
+        assert_eq!(op, delete(0, 999_999));
+        assert_eq!(
+            lines[0],
+            "[client] this deletes 1000 bytes (100% of the document):"
+        );
+        assert!(lines[1].ends_with('…') && lines[1].len() < 100);
+        assert_eq!(lines.last().unwrap(), "[client] delete it? [y/N]");
+        assert!(matches!(
+            plan(delete(0, 999_999), &text, false, None),
+            Plan::Send(_)
+        ));
+
+        // A short document asks for a share of it.
+        let lines = ["one", "two", "three", "four", "five", "six", "seven"].join("\n");
+        let Plan::Confirm(_, preview) = plan(delete(0, 30), &lines, false, Some(THRESHOLD)) else {
+            panic!("most of the document is confirmed");
+        };
+        assert_eq!(preview[1], "  | one");
+        assert_eq!(preview[6], "  | ... and 2 more lines");
+        assert!(matches!(
+            plan(delete(4, 3), &lines, false, Some(THRESHOLD)),
+            Plan::Send(_)
+        ));
+        assert!(confirmed(" Y ") && confirmed("yes"));
+        // Scripts piping commands in aren't asked unless told to.
+        let config = ClientConfig::default();
+        assert_eq!(config.threshold(None, true), Some(THRESHOLD));
+        assert_eq!(config.threshold(None, false), None);
+        assert_eq!(config.threshold(Some(true), false), Some(THRESHOLD));
+        assert!(!confirmed("") && !confirmed("no") && !confirmed("/delete 0 9"));
+    }
+
+    #[test]
+    fn dry_runs_only_describe_the_edit() {
+        let text = "alpha\nbeta\ngamma\n";
+        let insert = Op::Insert {
+            pos: 6,
+            text: "BETA ".into(),
+            pos_chars: None,
+        };
+        assert_eq!(
+            plan(insert, text, true, Some(THRESHOLD)),
+            Plan::DryRun(vec![
+                "- beta".to_string(),
+                "+ BETA beta".to_string(),
+                "[client] dry run: 17 -> 22 bytes, nothing sent".to_string(),
+            ])
+        );
+        // Dry runs of big deletes don't ask either: there is nothing to send.
+        let Plan::DryRun(lines) = plan(delete(0, 999), text, true, Some(THRESHOLD)) else {
+            panic!("a dry run sends nothing");
+        };
+        assert_eq!(lines.len(), 4);
+        let cursor = Op::Cursor { pos: 3, at: None };
+        assert_eq!(
+            plan(cursor, text, true, None),
+            Plan::DryRun(vec!["[client] dry run: no change to the text".to_string()])
+        );
+    }
+
+    #[test]
+    fn replacements_preview_the_lines_they_change() {
+        let text = "cat\ndog cat cat\nbird\n";
+        let at = |start: usize| Replacement {
+            start,
+            end: start + 3,
+            with: "cow".to_string(),
+        };
+        assert_eq!(
+            replace_lines(text, &[at(0), at(8), at(12)]),
+            [
+                "[client] 3 matches on 2 lines:",
+                "- cat",
+                "+ cow",
+                "- dog cat cat",
+                "+ dog cow cow",
+            ]
+        );
+        let many: String = (0..8).map(|_| "cat\n").collect();
+        let lines = replace_lines(&many, &(0..8).map(|line| at(line * 4)).collect::<Vec<_>>());
+        assert_eq!(lines[0], "[client] 8 matches on 8 lines:");
+        assert_eq!(lines.last().unwrap(), "  ... and 3 more lines");
+    }
+}
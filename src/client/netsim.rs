@@ -0,0 +1,233 @@
+//! A bad network on demand: `--simulate-latency` and friends put a relay
+//! between the client and its socket that holds back each line by a
+//! seeded random delay, so a laggy or lossy link can be felt, and
+//! reproduced, on localhost. Lines of one direction stay in order, as
+//! over TCP. TCP doesn't lose data, so a "lost" line is resent a
+//! retransmission timeout later, holding up those behind it; only
+//! `--simulate-unreliable` really drops it. The sim harness draws its
+//! delays from the same `NetConditions`.
+
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::{TcpStream, ToSocketAddrs};
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// The shortest wait before a lost line is resent, as Linux's minimum RTO.
+const RETRANSMIT_MS: u64 = 200;
+/// Buffer of the in-memory pipes between the client and the relay.
+const PIPE_LEN: usize = 64 * 1024;
+
+pub(crate) type Reader = Box<dyn AsyncRead + Send + Unpin>;
+pub(crate) type Writer = Box<dyn AsyncWrite + Send + Unpin>;
+
+/// How bad the simulated network is; the default is a perfect one.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct NetConditions {
+    /// Delay of every line.
+    pub latency_ms: u64,
+    /// Up to this much more, uniformly.
+    pub jitter_ms: u64,
+    /// Percentage of lines lost.
+    pub loss_pct: u8,
+    /// Drop lost lines instead of resending them late.
+    pub unreliable: bool,
+    /// The same seed delays and loses the same lines.
+    pub seed: u64,
+}
+
+impl NetConditions {
+    /// Whether lines are delayed or lost at all.
+    pub fn active(&self) -> bool {
+        self.latency_ms > 0 || self.jitter_ms > 0 || self.loss_pct > 0
+    }
+
+    /// The delay of the next line in milliseconds, or `None` if it is
+    /// dropped. Draws nothing for loss when there is none, so a lossless
+    /// link uses one number per line.
+    pub(crate) fn delay_ms(&self, rng: &mut Rng) -> Option<u64> {
+        let mut delay = self.latency_ms + rng.below(self.jitter_ms as usize + 1) as u64;
+        if self.loss_pct > 0 && rng.below(100) < self.loss_pct as usize {
+            if self.unreliable {
+                return None;
+            }
+            delay += RETRANSMIT_MS.max(2 * self.latency_ms);
+        }
+        Some(delay)
+    }
+}
+
+/// SplitMix64, so a seed means the same delays on every platform.
+pub(crate) struct Rng(u64);
+
+impl Rng {
+    pub(crate) fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform in `0..n`; `n` must not be 0.
+    pub(crate) fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+}
+
+/// Connects to `addr`, through a relay under `conditions` if they are
+/// active. Dropping the writer shuts the connection down for writing, as
+/// with a socket.
+pub(crate) async fn connect(
+    addr: impl ToSocketAddrs,
+    conditions: &NetConditions,
+) -> io::Result<(Reader, Writer)> {
+    let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+    if !conditions.active() {
+        return Ok((Box::new(reader), Box::new(writer)));
+    }
+    let (incoming, relay_in) = tokio::io::duplex(PIPE_LEN);
+    let (outgoing, relay_out) = tokio::io::duplex(PIPE_LEN);
+    // Each direction has numbers of its own, so what one sends doesn't
+    // change the delays of the other.
+    let seed = conditions.seed;
+    tokio::spawn(relay(relay_out, writer, *conditions, Rng::new(seed)));
+    tokio::spawn(relay(reader, relay_in, *conditions, Rng::new(!seed)));
+    Ok((Box::new(incoming), Box::new(outgoing)))
+}
+
+/// Copies lines from `from` to `to`, each once its delay has passed but
+/// never before the one ahead of it, until either side is closed.
+async fn relay(
+    from: impl AsyncRead + Unpin,
+    mut to: impl AsyncWrite + Unpin,
+    conditions: NetConditions,
+    mut rng: Rng,
+) {
+    let (tx, mut rx) = mpsc::unbounded_channel::<(Instant, Vec<u8>)>();
+    let reading = async move {
+        let mut from = BufReader::new(from);
+        loop {
+            let mut line = Vec::new();
+            match from.read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            let Some(delay) = conditions.delay_ms(&mut rng) else {
+                continue;
+            };
+            let at = Instant::now() + Duration::from_millis(delay);
+            if tx.send((at, line)).is_err() {
+                break;
+            }
+        }
+    };
+    let delivering = async move {
+        while let Some((at, line)) = rx.recv().await {
+            tokio::time::sleep_until(at).await;
+            if to.write_all(&line).await.is_err() || to.flush().await.is_err() {
+                return;
+            }
+        }
+        let _ = to.shutdown().await;
+    };
+    tokio::join!(reading, delivering);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    fn delays(conditions: &NetConditions, count: usize) -> Vec<Option<u64>> {
+        let mut rng = Rng::new(conditions.seed);
+        (0..count).map(|_| conditions.delay_ms(&mut rng)).collect()
+    }
+
+    #[test]
+    fn a_seed_means_the_same_delays() {
+        let conditions = NetConditions {
+            latency_ms: 50,
+            jitter_ms: 30,
+            seed: 7,
+            ..NetConditions::default()
+        };
+        let first = delays(&conditions, 1000);
+        assert_eq!(first, delays(&conditions, 1000));
+        let other = NetConditions {
+            seed: 8,
+            ..conditions
+        };
+        assert_ne!(first, delays(&other, 1000));
+
+        let first: Vec<u64> = first.into_iter().map(Option::unwrap).collect();
+        assert!(first.iter().all(|delay| (50..=80).contains(delay)));
+        // Spread over the whole range, not stuck at one end.
+        assert!(first.contains(&50) && first.contains(&80));
+        let mean = first.iter().sum::<u64>() as f64 / first.len() as f64;
+        assert!((62.0..68.0).contains(&mean), "mean {}", mean);
+    }
+
+    #[test]
+    fn lost_lines_come_late_unless_the_link_is_unreliable() {
+        let lossy = NetConditions {
+            latency_ms: 10,
+            loss_pct: 20,
+            seed: 3,
+            ..NetConditions::default()
+        };
+        let late = delays(&lossy, 1000);
+        let resent = late.iter().filter(|delay| **delay == Some(210)).count();
+        assert_eq!(late.iter().flatten().count(), 1000, "nothing is dropped");
+        assert_eq!(
+            resent + late.iter().filter(|delay| **delay == Some(10)).count(),
+            1000
+        );
+        assert!((150..250).contains(&resent), "{} resent", resent);
+
+        let unreliable = NetConditions {
+            unreliable: true,
+            ..lossy
+        };
+        let dropped = delays(&unreliable, 1000);
+        // The same lines are lost, only now they never arrive.
+        for (late, dropped) in late.iter().zip(&dropped) {
+            assert_eq!(*late == Some(210), dropped.is_none());
+        }
+    }
+
+    #[tokio::test]
+    async fn the_relay_delays_lines_and_keeps_their_order() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let conditions = NetConditions {
+            latency_ms: 40,
+            jitter_ms: 40,
+            ..NetConditions::default()
+        };
+        let (_reader, mut writer) = connect(addr, &conditions).await.unwrap();
+        let (server, _) = listener.accept().await.unwrap();
+
+        let sent = Instant::now();
+        for idx in 0..20 {
+            writer
+                .write_all(format!("line {}\n", idx).as_bytes())
+                .await
+                .unwrap();
+        }
+        drop(writer);
+        let mut lines = BufReader::new(server).lines();
+        let mut got = Vec::new();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            got.push(line);
+        }
+        assert!(sent.elapsed() >= Duration::from_millis(40));
+        let expected: Vec<String> = (0..20).map(|idx| format!("line {}", idx)).collect();
+        assert_eq!(got, expected);
+    }
+}
@@ -0,0 +1,178 @@
+//! The `--prompt rich` line shown while the client waits for input, e.g.
+//! `demo/notes.txt v42 1.2KB 3users> `, and a `[drift?]` marker when the
+//! local text has gone long without being checked against the server's.
+
+use std::io::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// What the client shows while waiting for a line of input.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum PromptStyle {
+    /// Nothing, every line is output.
+    Plain,
+    /// Room, doc, version, size and users, redrawn below any output.
+    Rich,
+}
+
+/// When the local text counts as possibly out of step with the server.
+#[derive(Debug, Clone, Copy)]
+pub struct DriftLimits {
+    /// Longest time since a sync compared the whole text.
+    pub max_age: Duration,
+    /// Most versions applied one by one since that sync.
+    pub max_versions: u64,
+}
+
+/// When the local text was last compared with the server's, and how far
+/// the versions have moved since.
+#[derive(Debug, Default)]
+pub(super) struct Drift {
+    synced_at: Option<Instant>,
+    synced_version: u64,
+    seen_version: u64,
+}
+
+impl Drift {
+    /// A SyncResponse at `version` replaced the text at `now`.
+    pub(super) fn synced(&mut self, version: u64, now: Instant) {
+        self.synced_at = Some(now);
+        self.synced_version = version;
+        self.seen_version = version;
+    }
+
+    /// An edit took the document to `version`.
+    pub(super) fn seen(&mut self, version: u64) {
+        self.seen_version = self.seen_version.max(version);
+    }
+
+    /// Whether a `/sync` is worth it. Never before the first sync, which is
+    /// still on its way.
+    pub(super) fn suspect(&self, now: Instant, limits: &DriftLimits) -> bool {
+        let Some(synced_at) = self.synced_at else {
+            return false;
+        };
+        now.saturating_duration_since(synced_at) > limits.max_age
+            || self.seen_version.saturating_sub(self.synced_version) > limits.max_versions
+    }
+}
+
+/// E.g. `demo/notes.txt v42 1.2KB 3users [drift?]> `.
+pub(super) fn render(
+    doc_id: &str,
+    version: u64,
+    bytes: usize,
+    users: usize,
+    drift: bool,
+) -> String {
+    let marker = if drift { " [drift?]" } else { "" };
+    format!(
+        "{} v{} {} {}users{}> ",
+        doc_id,
+        version,
+        human_bytes(bytes),
+        users,
+        marker
+    )
+}
+
+fn human_bytes(bytes: usize) -> String {
+    match bytes {
+        0..1024 => format!("{}B", bytes),
+        1024..1_048_576 => format!("{:.1}KB", bytes as f64 / 1024.0),
+        _ => format!("{:.1}MB", bytes as f64 / 1_048_576.0),
+    }
+}
+
+/// The prompt last set and whether it is on screen, shared with tasks that
+/// print on their own.
+struct Shown {
+    text: String,
+    visible: bool,
+}
+
+static PROMPT: Mutex<Option<Shown>> = Mutex::new(None);
+
+/// Sets the prompt, drawing it unless one is already on screen; that one
+/// is left alone so input typed after it stays put.
+pub(super) fn update(text: String) {
+    let mut prompt = PROMPT.lock().unwrap_or_else(|err| err.into_inner());
+    let visible = prompt.as_ref().is_some_and(|shown| shown.visible);
+    if !visible {
+        print!("{}", text);
+        let _ = std::io::stdout().flush();
+    }
+    *prompt = Some(Shown {
+        text,
+        visible: true,
+    });
+}
+
+/// A line of input was read: Enter left the prompt behind.
+pub(super) fn input_read() {
+    let mut prompt = PROMPT.lock().unwrap_or_else(|err| err.into_inner());
+    if let Some(shown) = prompt.as_mut() {
+        shown.visible = false;
+    }
+}
+
+/// Prints `line`, over the prompt if one is shown, and draws the prompt
+/// again below it. Input typed after the prompt is still submitted by
+/// Enter, but the terminal doesn't show it again.
+pub(super) fn print_line(line: &str) {
+    let prompt = PROMPT.lock().unwrap_or_else(|err| err.into_inner());
+    match prompt.as_ref() {
+        Some(shown) if shown.visible => {
+            print!("\r\x1b[2K{}\n{}", line, shown.text);
+            let _ = std::io::stdout().flush();
+        }
+        _ => println!("{}", line),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LIMITS: DriftLimits = DriftLimits {
+        max_age: Duration::from_secs(600),
+        max_versions: 100,
+    };
+
+    #[test]
+    fn drift_is_suspected_after_long_or_many_versions_without_sync() {
+        let start = Instant::now();
+        let mut drift = Drift::default();
+        drift.seen(5000);
+        let much_later = start + Duration::from_secs(3600);
+        assert!(!drift.suspect(much_later, &LIMITS), "joining isn't drift");
+
+        drift.synced(40, start);
+        drift.seen(140);
+        assert!(!drift.suspect(start + Duration::from_secs(600), &LIMITS));
+        assert!(drift.suspect(start + Duration::from_secs(601), &LIMITS));
+        drift.seen(141);
+        assert!(drift.suspect(start, &LIMITS));
+        drift.seen(90);
+        assert!(drift.suspect(start, &LIMITS), "versions don't go back");
+
+        drift.synced(141, start + Duration::from_secs(700));
+        assert!(!drift.suspect(start + Duration::from_secs(700), &LIMITS));
+    }
+
+    #[test]
+    fn prompt_shows_size_users_and_drift() {
+        assert_eq!(
+            render("demo/a.txt", 0, 0, 1, false),
+            "demo/a.txt v0 0B 1users> "
+        );
+        assert_eq!(
+            render("demo/notes.txt", 42, 1229, 3, false),
+            "demo/notes.txt v42 1.2KB 3users> "
+        );
+        assert_eq!(
+            render("demo/big", 7, 5 * 1_048_576, 2, true),
+            "demo/big v7 5.0MB 2users [drift?]> "
+        );
+    }
+}
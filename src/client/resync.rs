@@ -0,0 +1,130 @@
+//! When to ask the server for the whole document again. A sync asked for
+//! by hand within `MIN_INTERVAL` of the last one is refused, so mashing
+//! Ctrl+R sends one; with `--auto-sync` one is sent by itself every so
+//! often, but only once the user has stopped typing for `QUIET`, so the
+//! snapshot doesn't land in the middle of a sentence.
+
+use std::time::{Duration, Instant};
+
+/// Syncs asked for by hand are at least this far apart.
+pub(crate) const MIN_INTERVAL: Duration = Duration::from_secs(2);
+/// An automatic sync waits for this long without local edits.
+pub(crate) const QUIET: Duration = Duration::from_secs(30);
+
+#[derive(Debug)]
+pub(crate) struct SyncSchedule {
+    /// How often to sync by itself, if at all.
+    every: Option<Duration>,
+    /// When the last sync request went out, for whatever reason.
+    last_sync: Instant,
+    last_edit: Option<Instant>,
+}
+
+impl SyncSchedule {
+    /// A schedule for a document whose join sent a sync request at `now`.
+    pub(crate) fn new(every: Option<Duration>, now: Instant) -> Self {
+        Self {
+            every,
+            last_sync: now,
+            last_edit: None,
+        }
+    }
+
+    /// A sync asked for by hand at `now`: noted as sent if it may go out,
+    /// else how much longer it has to wait.
+    pub(crate) fn manual(&mut self, now: Instant) -> Result<(), Duration> {
+        let since = now.saturating_duration_since(self.last_sync);
+        if since < MIN_INTERVAL {
+            return Err(MIN_INTERVAL - since);
+        }
+        self.last_sync = now;
+        Ok(())
+    }
+
+    /// A sync request went out for another reason, e.g. a rejected edit.
+    pub(crate) fn sent(&mut self, now: Instant) {
+        self.last_sync = now;
+    }
+
+    /// The user edited the document at `now`.
+    pub(crate) fn edited(&mut self, now: Instant) {
+        self.last_edit = Some(now);
+    }
+
+    /// When an automatic sync is next due, if ever.
+    pub(crate) fn next_auto(&self) -> Option<Instant> {
+        let due = self.last_sync + self.every?;
+        Some(match self.last_edit {
+            Some(edited) => due.max(edited + QUIET),
+            None => due,
+        })
+    }
+
+    /// Whether an automatic sync should go out at `now`; if so it is noted
+    /// as sent.
+    pub(crate) fn auto_due(&mut self, now: Instant) -> bool {
+        if self.next_auto().is_none_or(|due| now < due) {
+            return false;
+        }
+        self.last_sync = now;
+        true
+    }
+}
+
+impl Default for SyncSchedule {
+    fn default() -> Self {
+        Self::new(None, Instant::now())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn syncs_by_hand_are_throttled() {
+        let start = Instant::now();
+        let mut schedule = SyncSchedule::new(None, start);
+        let soon = start + Duration::from_millis(500);
+        assert_eq!(schedule.manual(soon), Err(Duration::from_millis(1500)));
+        assert_eq!(schedule.manual(start + MIN_INTERVAL), Ok(()));
+        // Mashing the key sends nothing more until the window is over.
+        let mashed = start + MIN_INTERVAL + Duration::from_millis(100);
+        assert!(schedule.manual(mashed).is_err());
+        assert!(schedule.manual(mashed + Duration::from_secs(1)).is_err());
+        let later = start + MIN_INTERVAL * 2;
+        assert_eq!(schedule.manual(later), Ok(()));
+
+        // A sync sent for another reason counts too.
+        schedule.sent(later + Duration::from_secs(5));
+        assert!(schedule.manual(later + Duration::from_secs(6)).is_err());
+        assert_eq!(schedule.next_auto(), None, "no --auto-sync");
+        assert!(!schedule.auto_due(later + Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn automatic_syncs_wait_while_the_user_types() {
+        let start = Instant::now();
+        let every = Duration::from_secs(300);
+        let mut schedule = SyncSchedule::new(Some(every), start);
+        assert!(!schedule.auto_due(start + Duration::from_secs(299)));
+        assert_eq!(schedule.next_auto(), Some(start + every));
+
+        // Typing right before it's due puts it off until 30 s of quiet.
+        let typed = start + Duration::from_secs(290);
+        schedule.edited(typed);
+        schedule.edited(typed + Duration::from_secs(15));
+        let quiet = typed + Duration::from_secs(15) + QUIET;
+        assert_eq!(schedule.next_auto(), Some(quiet));
+        assert!(!schedule.auto_due(start + every));
+        assert!(!schedule.auto_due(quiet - Duration::from_secs(1)));
+        assert!(schedule.auto_due(quiet));
+        assert!(!schedule.auto_due(quiet), "sent already");
+        assert_eq!(schedule.next_auto(), Some(quiet + every));
+
+        // A sync by hand restarts the wait.
+        let manual = quiet + Duration::from_secs(100);
+        assert_eq!(schedule.manual(manual), Ok(()));
+        assert_eq!(schedule.next_auto(), Some(manual + every));
+    }
+}
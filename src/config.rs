@@ -0,0 +1,141 @@
+use crate::client::ClientConfig;
+use crate::tui::{CursorConfig, EditingConfig, SpellConfig, ThemeConfig, TuiConfig, ViewConfig};
+use serde::Deserialize;
+use std::error::Error;
+use std::path::{Path, PathBuf};
+
+const APP_DIR: &str = "carnelia-collab";
+const FILE_NAME: &str = "config.toml";
+
+/// Settings read from `config.toml`. Every section is optional.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub theme: ThemeConfig,
+    pub cursors: CursorConfig,
+    pub view: ViewConfig,
+    pub editing: EditingConfig,
+    pub spellcheck: SpellConfig,
+    pub tui: TuiConfig,
+    pub client: ClientConfig,
+}
+
+/// `$XDG_CONFIG_HOME/carnelia-collab/config.toml`, falling back to
+/// `~/.config` (or `%APPDATA%` on Windows).
+pub fn default_path() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("APPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".config"))
+            }
+        })?;
+    Some(base.join(APP_DIR).join(FILE_NAME))
+}
+
+/// Loads the config from `path`, or from the default location when `None`.
+/// A missing file at the default location is not an error.
+pub fn load(path: Option<&Path>) -> Result<Config, Box<dyn Error>> {
+    let (path, required) = match path {
+        Some(path) => (path.to_path_buf(), true),
+        None => match default_path() {
+            Some(path) => (path, false),
+            None => return Ok(Config::default()),
+        },
+    };
+    let text = match std::fs::read_to_string(&path) {
+        Ok(text) => text,
+        Err(err) if !required && err.kind() == std::io::ErrorKind::NotFound => {
+            return Ok(Config::default());
+        }
+        Err(err) => return Err(format!("{}: {}", path.display(), err).into()),
+    };
+    parse(&text).map_err(|err| format!("{}: {}", path.display(), err).into())
+}
+
+fn parse(text: &str) -> Result<Config, toml::de::Error> {
+    toml::from_str(text)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tui::{Bindings, LineEndings, ThemeName};
+
+    #[test]
+    fn parses_theme_section_and_rejects_unknown_keys() {
+        let config = parse(
+            r##"
+            [theme]
+            name = "high-contrast"
+            cursor_bg = "#ffcc00"
+            palette = ["cyan", "#112233"]
+
+            [cursors]
+            edit_flash_ms = 250
+
+            [view]
+            scrollbar = false
+            gutter = false
+
+            [editing]
+            line_endings = "keep"
+            smart_end = true
+
+            [spellcheck]
+            dictionary = "/tmp/words"
+            "##,
+        )
+        .unwrap();
+        assert_eq!(config.theme.name, Some(ThemeName::HighContrast));
+        assert_eq!(config.theme.cursor_bg.as_deref(), Some("#ffcc00"));
+        assert_eq!(config.theme.palette.len(), 2);
+        assert_eq!(config.cursors.edit_flash_ms, 250);
+        assert_eq!(config.cursors.fade_after_ms, 10_000);
+        assert!(!config.view.scrollbar);
+        assert!(parse("").unwrap().view.scrollbar);
+        assert!(!config.view.gutter && parse("").unwrap().view.gutter);
+        assert_eq!(config.editing.line_endings, LineEndings::Keep);
+        assert!(config.editing.smart_end && !parse("").unwrap().editing.smart_end);
+        assert!(parse("[editing]\nline_endings = \"crlf\"").is_err());
+        assert_eq!(config.spellcheck.language, "en_US");
+        assert_eq!(
+            config.spellcheck.dictionary.as_deref(),
+            Some(Path::new("/tmp/words"))
+        );
+
+        let client = parse("[client]\nconfirm_above_bytes = 1000")
+            .unwrap()
+            .client;
+        assert_eq!(client.confirm_above_bytes, 1000);
+        assert_eq!(client.confirm_above_percent, 20);
+        assert_eq!(client.confirm_above_replacements, 20);
+        assert_eq!(client.confirm_destructive, None);
+        assert!(parse("[client]\nconfirm = true").is_err());
+
+        assert!(parse("").unwrap().theme.palette.is_empty());
+        assert!(parse("[theme]\ncursor = \"red\"").is_err());
+    }
+
+    #[test]
+    fn key_bindings_are_read_from_the_tui_keys_table() {
+        let config = parse(
+            r#"
+            [tui.keys]
+            quit = "ctrl+x"
+            cut = ["alt+x", "ctrl+shift+x"]
+            sidebar = []
+            "#,
+        )
+        .unwrap();
+        assert!(Bindings::resolve(&config.tui.keys).is_ok());
+        let config = parse("[tui.keys]\nquit = \"hyper+q\"\nundo = \"ctrl+r\"").unwrap();
+        let err = Bindings::resolve(&config.tui.keys).unwrap_err();
+        assert_eq!(err.lines().count(), 2, "{}", err);
+        assert!(parse("[tui.keys]\nquit = 1").is_err());
+        assert!(parse("[tui]\nbindings = {}").is_err());
+    }
+}
@@ -0,0 +1,741 @@
+//! `collab-cli dashboard`: the rooms and documents of a running server,
+//! from `GET /admin/overview` and `/metrics` on its health address, polled
+//! every 2 seconds and drawn full-screen. While the server can't be
+//! reached the last numbers stay up under a banner and polling goes on.
+
+use crate::storage::encode_component;
+use crate::tui::widgets::{
+    self, Prompt, PromptEvent, Screen, Style, TerminalGuard, clip_line, text_width, tty,
+};
+use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use crossterm::style::Color;
+use crossterm::terminal;
+use serde::Deserialize;
+use std::collections::{HashMap, VecDeque};
+use std::error::Error;
+use std::io;
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+
+const POLL_EVERY: Duration = Duration::from_secs(2);
+/// How long a request to the server may take.
+const TIMEOUT: Duration = Duration::from_secs(2);
+/// Polls of ops/min kept per document for its sparkline: a minute's.
+const SPARK_LEN: usize = 30;
+const SPARKS: [char; 8] = ['▁', '▂', '▃', '▄', '▅', '▆', '▇', '█'];
+/// Cells of the bar of a gauge.
+const GAUGE_CELLS: usize = 10;
+/// Changes waiting longer than this to be saved are marked; the server
+/// saves once a second.
+const SLOW_FLUSH_MS: u64 = 5_000;
+
+/// `/admin/overview`, as much of it as is shown. Fields older servers
+/// don't send are left empty.
+#[derive(Debug, Deserialize)]
+struct Overview {
+    rooms: Vec<RoomOverview>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RoomOverview {
+    room: String,
+    docs: Vec<DocOverview>,
+}
+
+#[derive(Debug, Deserialize)]
+struct DocOverview {
+    doc: String,
+    users: usize,
+    #[serde(default)]
+    user_names: Vec<String>,
+    version: u64,
+    #[serde(default)]
+    bytes: usize,
+    ops_last_minute: usize,
+    #[serde(default)]
+    unsaved_ms: Option<u64>,
+}
+
+/// A document as last polled, with its ops/min of the polls before.
+#[derive(Debug)]
+struct Row {
+    room: String,
+    doc: String,
+    users: usize,
+    user_names: Vec<String>,
+    version: u64,
+    bytes: usize,
+    /// Oldest first, the last one current.
+    ops: VecDeque<usize>,
+    unsaved_ms: Option<u64>,
+}
+
+impl Row {
+    fn ops_last_minute(&self) -> usize {
+        self.ops.back().copied().unwrap_or(0)
+    }
+}
+
+/// What the table is sorted by, picked with the keys 1 to 6.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Column {
+    Doc,
+    Users,
+    Version,
+    Ops,
+    Bytes,
+    Lag,
+}
+
+impl Column {
+    const ALL: [Column; 6] = [
+        Column::Doc,
+        Column::Users,
+        Column::Version,
+        Column::Ops,
+        Column::Bytes,
+        Column::Lag,
+    ];
+
+    fn title(self) -> &'static str {
+        match self {
+            Column::Doc => "room/doc",
+            Column::Users => "users",
+            Column::Version => "version",
+            Column::Ops => "ops/min",
+            Column::Bytes => "bytes",
+            Column::Lag => "flush lag",
+        }
+    }
+
+    /// Cells it takes in the table; the doc takes what the others leave.
+    fn width(self) -> usize {
+        match self {
+            Column::Doc => 0,
+            Column::Users => 7,
+            Column::Version => 9,
+            Column::Ops => SPARK_LEN + 7,
+            Column::Bytes => 11,
+            Column::Lag => 11,
+        }
+    }
+}
+
+/// A number shown against the highest it has been since the dashboard
+/// started, as the server has nothing to measure it against.
+#[derive(Debug, Default)]
+struct Gauge {
+    value: Option<u64>,
+    peak: u64,
+}
+
+impl Gauge {
+    fn set(&mut self, value: Option<u64>) {
+        self.value = value;
+        self.peak = self.peak.max(value.unwrap_or(0));
+    }
+
+    fn line(&self, label: &str, format: fn(u64) -> String) -> String {
+        let Some(value) = self.value else {
+            return format!("{} ?", label);
+        };
+        let filled = (value as usize * GAUGE_CELLS)
+            .checked_div(self.peak as usize)
+            .unwrap_or(0);
+        format!(
+            "{} {}{} {} (peak {})",
+            label,
+            "█".repeat(filled),
+            "░".repeat(GAUGE_CELLS - filled),
+            format(value),
+            format(self.peak)
+        )
+    }
+}
+
+/// What a key asked for beyond changing the view.
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    None,
+    Quit,
+    Kick {
+        room: String,
+        doc: String,
+        user: String,
+    },
+}
+
+struct Dashboard {
+    addr: String,
+    rows: Vec<Row>,
+    sort: Column,
+    descending: bool,
+    /// The room and doc of the selected row, kept across polls.
+    selected: Option<(String, String)>,
+    connections: Gauge,
+    memory: Gauge,
+    /// Why the last poll failed, until one succeeds.
+    unreachable: Option<String>,
+    /// The prompt for whom to kick, and from which room and doc.
+    kick: Option<(Prompt, String, String)>,
+    /// The outcome of the last kick.
+    status: Option<String>,
+}
+
+impl Dashboard {
+    fn new(addr: &str) -> Self {
+        Self {
+            addr: addr.to_string(),
+            rows: Vec::new(),
+            sort: Column::Ops,
+            descending: true,
+            selected: None,
+            connections: Gauge::default(),
+            memory: Gauge::default(),
+            unreachable: None,
+            kick: None,
+            status: None,
+        }
+    }
+
+    /// Takes the numbers of a poll, adding each document's ops/min to its
+    /// sparkline.
+    fn update(&mut self, overview: Overview, metrics: &str) {
+        let mut sparks: HashMap<(String, String), VecDeque<usize>> = self
+            .rows
+            .drain(..)
+            .map(|row| ((row.room, row.doc), row.ops))
+            .collect();
+        for room in overview.rooms {
+            for doc in room.docs {
+                let key = (room.room.clone(), doc.doc);
+                let mut ops = sparks.remove(&key).unwrap_or_default();
+                if ops.len() == SPARK_LEN {
+                    ops.pop_front();
+                }
+                ops.push_back(doc.ops_last_minute);
+                let (room, doc_name) = key;
+                self.rows.push(Row {
+                    room,
+                    doc: doc_name,
+                    users: doc.users,
+                    user_names: doc.user_names,
+                    version: doc.version,
+                    bytes: doc.bytes,
+                    ops,
+                    unsaved_ms: doc.unsaved_ms,
+                });
+            }
+        }
+        let gauges = parse_metrics(metrics);
+        self.connections
+            .set(gauges.get("connections_open").copied());
+        self.memory
+            .set(gauges.get("process_resident_memory_bytes").copied());
+        self.unreachable = None;
+    }
+
+    /// The rows in the order shown, ties by name.
+    fn sorted(&self) -> Vec<&Row> {
+        let mut rows: Vec<&Row> = self.rows.iter().collect();
+        rows.sort_by(|a, b| {
+            let order = match self.sort {
+                Column::Doc => (&a.room, &a.doc).cmp(&(&b.room, &b.doc)),
+                Column::Users => a.users.cmp(&b.users),
+                Column::Version => a.version.cmp(&b.version),
+                Column::Ops => a.ops_last_minute().cmp(&b.ops_last_minute()),
+                Column::Bytes => a.bytes.cmp(&b.bytes),
+                Column::Lag => a.unsaved_ms.cmp(&b.unsaved_ms),
+            };
+            let order = if self.descending {
+                order.reverse()
+            } else {
+                order
+            };
+            order.then_with(|| (&a.room, &a.doc).cmp(&(&b.room, &b.doc)))
+        });
+        rows
+    }
+
+    /// Index of the selected row in `rows`, the first if it's gone.
+    fn selected_index(&self, rows: &[&Row]) -> usize {
+        self.selected
+            .as_ref()
+            .and_then(|(room, doc)| {
+                rows.iter()
+                    .position(|row| row.room == *room && row.doc == *doc)
+            })
+            .unwrap_or(0)
+    }
+
+    fn handle_key(&mut self, key: KeyEvent) -> Action {
+        if let Some((prompt, room, doc)) = &mut self.kick {
+            match prompt.handle_key(key) {
+                PromptEvent::Submit => {
+                    let user = prompt.input().trim().to_string();
+                    let (room, doc) = (room.clone(), doc.clone());
+                    self.kick = None;
+                    if user.is_empty() {
+                        return Action::None;
+                    }
+                    return Action::Kick { room, doc, user };
+                }
+                PromptEvent::Cancel => self.kick = None,
+                _ => {}
+            }
+            return Action::None;
+        }
+        let keys: Vec<(String, String)> = self
+            .sorted()
+            .iter()
+            .map(|row| (row.room.clone(), row.doc.clone()))
+            .collect();
+        let at = self
+            .selected
+            .as_ref()
+            .and_then(|selected| keys.iter().position(|key| key == selected))
+            .unwrap_or(0);
+        let pick = |idx: usize| keys.get(idx).cloned();
+        match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Action::Quit,
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                return Action::Quit;
+            }
+            KeyCode::Up => self.selected = pick(at.saturating_sub(1)).or(pick(at)),
+            KeyCode::Down => self.selected = pick(at + 1).or(pick(at)),
+            KeyCode::Char('k') => {
+                if let Some((room, doc)) = pick(at) {
+                    let prompt = Prompt::new(&format!("kick from {}/{}", room, doc), "");
+                    self.kick = Some((prompt, room, doc));
+                    self.status = None;
+                }
+            }
+            KeyCode::Char(digit @ '1'..='6') => {
+                let column = Column::ALL[digit as usize - '1' as usize];
+                if column == self.sort {
+                    self.descending = !self.descending;
+                } else {
+                    // Names read best A to Z, numbers biggest first.
+                    self.sort = column;
+                    self.descending = column != Column::Doc;
+                }
+            }
+            _ => {}
+        }
+        Action::None
+    }
+
+    /// The frame for a terminal of `cols` by `rows`, and where the cursor
+    /// goes if a prompt is open.
+    fn render(&self, cols: usize, rows: usize) -> (Screen, Option<(u16, u16)>) {
+        let mut screen = Screen::new(cols, rows);
+        if rows < 5 {
+            return (screen, None);
+        }
+        let bar = Style::colored(Color::DarkGrey, Color::White);
+        let title = format!(" collab-cli dashboard | {}", self.addr);
+        screen.put(0, 0, &format!("{:<cols$}", clip_line(&title, cols)), bar);
+        let gauges = format!(
+            " {}   {}",
+            self.connections
+                .line("connections", |count| count.to_string()),
+            self.memory.line("memory", format_bytes)
+        );
+        screen.put(0, 1, &clip_line(&gauges, cols), Style::default());
+        if let Some(err) = &self.unreachable {
+            let banner = format!(
+                " can't reach {}: {}; retrying every {}s",
+                self.addr,
+                err,
+                POLL_EVERY.as_secs()
+            );
+            let warning = Style::colored(Color::Red, Color::White);
+            screen.put(
+                0,
+                2,
+                &format!("{:<cols$}", clip_line(&banner, cols)),
+                warning,
+            );
+        }
+
+        let sorted = self.sorted();
+        let name_width = Column::ALL
+            .iter()
+            .fold(cols, |left, column| left.saturating_sub(column.width()))
+            .max(12);
+        let header = Column::ALL
+            .iter()
+            .enumerate()
+            .map(|(idx, &column)| {
+                let arrow = match (column == self.sort, self.descending) {
+                    (false, _) => "",
+                    (true, true) => "↓",
+                    (true, false) => "↑",
+                };
+                let title = format!("{}{} {}", idx + 1, arrow, column.title());
+                match column {
+                    Column::Doc => pad(&title, name_width),
+                    _ => format!("{:>width$}", title, width = column.width()),
+                }
+            })
+            .collect::<String>();
+        let lines: Vec<String> = sorted
+            .iter()
+            .map(|row| table_line(row, name_width))
+            .collect();
+        let selected = Style::colored(Color::Blue, Color::White);
+        let at = self.selected_index(&sorted);
+        let area = (3, rows - 4, cols);
+        widgets::draw_list(&mut screen, area, &header, &lines, (at, selected));
+
+        let status_row = rows - 1;
+        if let Some((prompt, ..)) = &self.kick {
+            let line = format!("{} (Enter kicks, Esc cancels)", prompt.line());
+            screen.put(
+                0,
+                status_row,
+                &format!("{:<cols$}", clip_line(&line, cols)),
+                bar,
+            );
+            let col = prompt.cursor_col().min(cols.saturating_sub(1));
+            return (screen, Some((col as u16, status_row as u16)));
+        }
+        let mut line = match &self.status {
+            Some(status) => format!(" {}", status),
+            None => " Up/Down select | 1-6 sort | k kick | q quit".to_string(),
+        };
+        if let Some(row) = sorted.get(at)
+            && !row.user_names.is_empty()
+        {
+            line.push_str(&format!(" | in {}: {}", row.doc, row.user_names.join(", ")));
+        }
+        screen.put(
+            0,
+            status_row,
+            &format!("{:<cols$}", clip_line(&line, cols)),
+            bar,
+        );
+        (screen, None)
+    }
+}
+
+/// A row of the table, its doc in `name_width` cells.
+fn table_line(row: &Row, name_width: usize) -> String {
+    let lag = match row.unsaved_ms {
+        None => "-".to_string(),
+        Some(ms) if ms >= SLOW_FLUSH_MS => format!("! {}", format_millis(ms)),
+        Some(ms) => format_millis(ms),
+    };
+    format!(
+        "{}{:>7}{:>9}{:>width$} {:>5}{:>11}{:>11}",
+        pad(&format!("{}/{}", row.room, row.doc), name_width),
+        row.users,
+        row.version,
+        sparkline(&row.ops),
+        row.ops_last_minute(),
+        format_bytes(row.bytes as u64),
+        lag,
+        width = SPARK_LEN + 1
+    )
+}
+
+/// `text` cut or padded with spaces to `width` cells.
+fn pad(text: &str, width: usize) -> String {
+    let text = clip_line(text, width);
+    let padding = width - text_width(&text);
+    format!("{}{}", text, " ".repeat(padding))
+}
+
+/// One bar per sample, as high as it is against the highest of them.
+fn sparkline(samples: &VecDeque<usize>) -> String {
+    let max = samples.iter().copied().max().unwrap_or(0).max(1);
+    samples
+        .iter()
+        .map(|&sample| SPARKS[sample * (SPARKS.len() - 1) / max])
+        .collect()
+}
+
+fn format_bytes(bytes: u64) -> String {
+    const KIB: u64 = 1024;
+    const MIB: u64 = KIB * 1024;
+    const GIB: u64 = MIB * 1024;
+    match bytes {
+        bytes if bytes >= GIB => format!("{:.1} GiB", bytes as f64 / GIB as f64),
+        bytes if bytes >= MIB => format!("{:.1} MiB", bytes as f64 / MIB as f64),
+        bytes if bytes >= KIB => format!("{:.1} KiB", bytes as f64 / KIB as f64),
+        bytes => format!("{} B", bytes),
+    }
+}
+
+fn format_millis(ms: u64) -> String {
+    match ms {
+        ms if ms < 10_000 => format!("{:.1}s", ms as f64 / 1000.0),
+        ms if ms < 120_000 => format!("{}s", ms / 1000),
+        ms => format!("{}m", ms / 60_000),
+    }
+}
+
+/// The series of `metrics` without labels, by name.
+fn parse_metrics(metrics: &str) -> HashMap<&str, u64> {
+    metrics
+        .lines()
+        .filter_map(|line| {
+            let (name, value) = line.split_once(' ')?;
+            if name.starts_with('#') || name.contains('{') {
+                return None;
+            }
+            Some((name, value.trim().parse::<f64>().ok()? as u64))
+        })
+        .collect()
+}
+
+/// Sends `method target` to the HTTP server at `addr` and returns the
+/// status code and body of its answer.
+async fn request(addr: &str, method: &str, target: &str) -> Result<(u16, String), String> {
+    let exchange = async {
+        let mut stream = TcpStream::connect(addr).await?;
+        let request = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n",
+            method, target, addr
+        );
+        stream.write_all(request.as_bytes()).await?;
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        Ok::<_, io::Error>(response)
+    };
+    let response = match tokio::time::timeout(TIMEOUT, exchange).await {
+        Ok(response) => response.map_err(|err| err.to_string())?,
+        Err(_) => return Err(format!("no answer within {}s", TIMEOUT.as_secs())),
+    };
+    let (head, body) = response
+        .split_once("\r\n\r\n")
+        .ok_or("the answer is not HTTP")?;
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or("the answer is not HTTP")?;
+    Ok((status, body.to_string()))
+}
+
+async fn poll(addr: &str) -> Result<(Overview, String), String> {
+    let (status, body) = request(addr, "GET", "/admin/overview").await?;
+    if status != 200 {
+        return Err(format!("/admin/overview answered {}", status));
+    }
+    let overview =
+        serde_json::from_str(&body).map_err(|err| format!("unreadable overview: {}", err))?;
+    let (_, metrics) = request(addr, "GET", "/metrics").await?;
+    Ok((overview, metrics))
+}
+
+/// Asks the server to close `user`'s connections to `room`/`doc`; returns
+/// how many there were.
+async fn kick(addr: &str, room: &str, doc: &str, user: &str) -> Result<usize, String> {
+    let encode = |name: &str| encode_component(name).map_err(|err| err.to_string());
+    let doc = doc
+        .split('/')
+        .map(encode)
+        .collect::<Result<Vec<_>, _>>()?
+        .join("/");
+    let target = format!(
+        "/admin/kick/{}/{}?user={}",
+        encode(room)?,
+        doc,
+        encode(user)?
+    );
+    let (status, body) = request(addr, "POST", &target).await?;
+    if status != 200 {
+        return Err(body.trim().to_string());
+    }
+    #[derive(Deserialize)]
+    struct Kicked {
+        kicked: usize,
+    }
+    serde_json::from_str::<Kicked>(&body)
+        .map(|reply| reply.kicked)
+        .map_err(|err| format!("unreadable answer: {}", err))
+}
+
+enum UiEvent {
+    Key(KeyEvent),
+    Resize,
+    Polled(Result<(Overview, String), String>),
+    /// The answer to kicking the user named first from the room and doc.
+    Kicked(String, String, String, Result<usize, String>),
+}
+
+/// Forwards terminal events to the UI loop until the receiver is gone.
+fn read_input(ui_tx: &mpsc::UnboundedSender<UiEvent>) {
+    while let Ok(event) = event::read() {
+        let ui_event = match event {
+            Event::Key(key) => UiEvent::Key(key),
+            Event::Resize(_, _) => UiEvent::Resize,
+            _ => continue,
+        };
+        if ui_tx.send(ui_event).is_err() {
+            break;
+        }
+    }
+}
+
+/// Shows the dashboard for the server whose health address is `addr`
+/// until `q` is pressed.
+pub async fn run(addr: &str) -> Result<(), Box<dyn Error>> {
+    tty::install_panic_hook();
+    match tty::catch_unwind(run_dashboard(addr)).await {
+        Ok(result) => result,
+        // The hook has restored the terminal and printed the panic.
+        Err(_) => Err("the dashboard crashed unexpectedly".into()),
+    }
+}
+
+async fn run_dashboard(addr: &str) -> Result<(), Box<dyn Error>> {
+    let _term = TerminalGuard::new(false)?;
+    let (ui_tx, mut ui_rx) = mpsc::unbounded_channel::<UiEvent>();
+    let input_tx = ui_tx.clone();
+    tokio::task::spawn_blocking(move || read_input(&input_tx));
+    let (poll_tx, poll_addr) = (ui_tx.clone(), addr.to_string());
+    let poller = tokio::spawn(async move {
+        let mut every = tokio::time::interval(POLL_EVERY);
+        loop {
+            every.tick().await;
+            if poll_tx
+                .send(UiEvent::Polled(poll(&poll_addr).await))
+                .is_err()
+            {
+                break;
+            }
+        }
+    });
+
+    let mut dashboard = Dashboard::new(addr);
+    let mut last_frame = None;
+    loop {
+        let (cols, rows) = terminal::size()?;
+        let (frame, cursor) = dashboard.render(cols as usize, rows as usize);
+        widgets::show(frame, cursor, &mut last_frame)?;
+
+        let Some(event) = ui_rx.recv().await else {
+            break;
+        };
+        match event {
+            UiEvent::Key(key) if key.kind != KeyEventKind::Release => {
+                match dashboard.handle_key(key) {
+                    Action::None => {}
+                    Action::Quit => break,
+                    Action::Kick { room, doc, user } => {
+                        let (kick_tx, addr) = (ui_tx.clone(), addr.to_string());
+                        tokio::spawn(async move {
+                            let kicked = kick(&addr, &room, &doc, &user).await;
+                            let _ = kick_tx.send(UiEvent::Kicked(user, room, doc, kicked));
+                        });
+                    }
+                }
+            }
+            UiEvent::Key(_) => {}
+            UiEvent::Resize => last_frame = None,
+            UiEvent::Polled(Ok((overview, metrics))) => dashboard.update(overview, &metrics),
+            UiEvent::Polled(Err(err)) => dashboard.unreachable = Some(err),
+            UiEvent::Kicked(user, room, doc, result) => {
+                dashboard.status = Some(match result {
+                    Ok(count) => format!(
+                        "kicked {} from {}/{} ({} connections)",
+                        user, room, doc, count
+                    ),
+                    Err(err) => format!("kicking {} failed: {}", user, err),
+                });
+            }
+        }
+    }
+    poller.abort();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn overview(docs: &[(&str, usize, usize)]) -> Overview {
+        let json = serde_json::json!({
+            "rooms": [{
+                "room": "team",
+                "docs": docs.iter().map(|(doc, users, ops)| serde_json::json!({
+                    "doc": doc,
+                    "users": users,
+                    "user_names": ["ada"],
+                    "version": 1,
+                    "ops_last_minute": ops,
+                })).collect::<Vec<_>>(),
+            }]
+        });
+        serde_json::from_value(json).unwrap()
+    }
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    #[test]
+    fn polls_extend_sparklines_and_sorting_keeps_the_selection() {
+        let mut dashboard = Dashboard::new("127.0.0.1:8080");
+        let metrics = "connections_open 3\nroom_users{room=\"team\"} 2\n";
+        dashboard.update(overview(&[("a", 1, 0), ("b", 2, 4)]), metrics);
+        dashboard.update(overview(&[("a", 1, 8), ("b", 2, 2)]), metrics);
+        let names = |dashboard: &Dashboard| -> Vec<String> {
+            dashboard
+                .sorted()
+                .iter()
+                .map(|row| row.doc.clone())
+                .collect()
+        };
+        // Busiest first.
+        assert_eq!(names(&dashboard), ["a", "b"]);
+        assert_eq!(sparkline(&dashboard.sorted()[0].ops), "▁█");
+        assert_eq!(dashboard.connections.value, Some(3));
+        assert_eq!(dashboard.memory.value, None);
+
+        dashboard.handle_key(key(KeyCode::Down));
+        dashboard.handle_key(key(KeyCode::Char('2')));
+        assert_eq!(names(&dashboard), ["b", "a"]);
+        assert_eq!(dashboard.selected, Some(("team".into(), "b".into())));
+        dashboard.handle_key(key(KeyCode::Char('2')));
+        assert_eq!(names(&dashboard), ["a", "b"]);
+
+        // Documents no longer open drop out.
+        dashboard.update(overview(&[("b", 2, 2)]), "");
+        assert_eq!(dashboard.rows.len(), 1);
+        assert_eq!(dashboard.rows[0].ops.len(), 3);
+        assert_eq!(
+            dashboard
+                .connections
+                .line("connections", |count| count.to_string()),
+            "connections ?"
+        );
+    }
+
+    #[test]
+    fn k_asks_whom_to_kick_from_the_selected_document() {
+        let mut dashboard = Dashboard::new("127.0.0.1:8080");
+        dashboard.update(overview(&[("notes", 1, 0)]), "");
+        assert_eq!(dashboard.handle_key(key(KeyCode::Char('k'))), Action::None);
+        // Keys go to the prompt while it's open.
+        for ch in "bob".chars() {
+            assert_eq!(dashboard.handle_key(key(KeyCode::Char(ch))), Action::None);
+        }
+        let (screen, cursor) = dashboard.render(100, 10);
+        assert!(screen.row_text(9).starts_with("kick from team/notes: bob"));
+        assert_eq!(cursor, Some((25, 9)));
+        assert_eq!(
+            dashboard.handle_key(key(KeyCode::Enter)),
+            Action::Kick {
+                room: "team".into(),
+                doc: "notes".into(),
+                user: "bob".into(),
+            }
+        );
+        assert_eq!(dashboard.handle_key(key(KeyCode::Char('q'))), Action::Quit);
+    }
+}
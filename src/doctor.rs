@@ -0,0 +1,507 @@
+//! `collab-cli doctor`: independent checks of a server's reachability and
+//! of a data dir's health, each returning a `Check` so they can be run,
+//! tested and added to one at a time.
+
+use crate::protocol::{
+    ClientMessage, ServerMessage, decode_sync_response, encode_sync_request, make_scoped_user_id,
+};
+use crate::storage::{self, Cipher, Storage, StorageBackend, SyncPolicy, is_running};
+use mdcs_sdk::Message;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::TcpStream;
+
+/// How long a check waits for the server.
+const TIMEOUT: Duration = Duration::from_secs(5);
+/// The document joined to test the handshake; nothing is written to it.
+const PROBE_ROOM: &str = "doctor-probe";
+const PROBE_DOC: &str = "probe.txt";
+const PINGS: usize = 3;
+/// Round trips slower than this make typing feel laggy.
+const SLOW_RTT: Duration = Duration::from_millis(250);
+/// Free space below which saves are about to fail, and below which they
+/// may soon.
+const FAIL_FREE_BYTES: u64 = 64 << 20;
+const WARN_FREE_BYTES: u64 = 1 << 30;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Status::Pass => "pass",
+            Status::Warn => "warn",
+            Status::Fail => "FAIL",
+        })
+    }
+}
+
+/// The outcome of one check.
+#[derive(Debug, Clone)]
+pub struct Check {
+    pub name: &'static str,
+    pub status: Status,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: &'static str, status: Status, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status,
+            detail: detail.into(),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[{}] {:<12} {}", self.status, self.name, self.detail)
+    }
+}
+
+/// Checks the server at `addr` from DNS to its version, stopping at the
+/// first failure since the later checks would fail the same way.
+pub async fn check_server(addr: &str) -> Vec<Check> {
+    let mut checks = vec![check_dns(addr).await];
+    let passed = |checks: &[Check]| checks.last().is_some_and(|c| c.status != Status::Fail);
+    if passed(&checks) {
+        checks.push(check_connect(addr).await);
+    }
+    if passed(&checks) {
+        checks.push(check_handshake(addr).await);
+    }
+    if passed(&checks) {
+        checks.push(check_rtt(addr).await);
+    }
+    if passed(&checks) {
+        checks.push(check_version(addr).await);
+    }
+    checks
+}
+
+/// Checks the data dir of a server, which may be running.
+pub async fn check_data_dir(dir: &Path, cipher: Option<Cipher>) -> Vec<Check> {
+    let mut checks = vec![check_permissions(dir)];
+    if checks[0].status == Status::Fail {
+        return checks;
+    }
+    checks.push(check_free_space(dir));
+    let mut storage = Storage::new(dir, SyncPolicy::Never);
+    if let Some(cipher) = cipher {
+        storage = storage.encrypt_with(cipher);
+    }
+    checks.push(check_documents(&storage).await);
+    checks.push(check_stale_files(dir));
+    checks
+}
+
+pub async fn check_dns(addr: &str) -> Check {
+    match tokio::time::timeout(TIMEOUT, tokio::net::lookup_host(addr)).await {
+        Err(_) => Check::new("dns", Status::Fail, format!("resolving {} timed out", addr)),
+        Ok(Err(err)) => Check::new("dns", Status::Fail, format!("{}: {}", addr, err)),
+        Ok(Ok(found)) => {
+            let found: Vec<String> = found.map(|addr| addr.to_string()).collect();
+            Check::new(
+                "dns",
+                Status::Pass,
+                format!("resolved to {}", found.join(", ")),
+            )
+        }
+    }
+}
+
+pub async fn check_connect(addr: &str) -> Check {
+    let started = Instant::now();
+    match connect(addr).await {
+        Ok(stream) => {
+            let peer = stream
+                .peer_addr()
+                .map_or_else(|_| addr.to_string(), |peer| peer.to_string());
+            let took = started.elapsed();
+            Check::new("connect", Status::Pass, format!("{} in {:.1?}", peer, took))
+        }
+        Err(err) => Check::new("connect", Status::Fail, err.to_string()),
+    }
+}
+
+/// Joins a throwaway document the way clients do.
+pub async fn check_handshake(addr: &str) -> Check {
+    let doc_id = format!("{}/{}", PROBE_ROOM, PROBE_DOC);
+    let joined = async {
+        let stream = connect(addr).await?;
+        let (reader, mut writer) = stream.into_split();
+        let hello = Message::Hello {
+            replica_id: make_scoped_user_id(&doc_id, "doctor"),
+            user_name: "doctor".to_string(),
+        };
+        send(&mut writer, &hello).await?;
+        send(&mut writer, &encode_sync_request(&doc_id, 0)).await?;
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            let line = next_line(&mut lines).await?;
+            let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                continue;
+            };
+            if let Some((id, sync, _)) = decode_sync_response(&msg)
+                && id == doc_id
+            {
+                return io::Result::Ok(sync.error);
+            }
+        }
+    };
+    match joined.await {
+        Ok(None) => Check::new("handshake", Status::Pass, format!("joined {}", doc_id)),
+        Ok(Some(error)) => Check::new(
+            "handshake",
+            Status::Fail,
+            format!("join refused: {}", error),
+        ),
+        Err(err) => Check::new("handshake", Status::Fail, err.to_string()),
+    }
+}
+
+/// Times a few pings, which the server answers without touching any
+/// document.
+pub async fn check_rtt(addr: &str) -> Check {
+    let timed = async {
+        let (reader, mut writer) = connect(addr).await?.into_split();
+        let mut lines = BufReader::new(reader).lines();
+        let mut rtts = Vec::with_capacity(PINGS);
+        for _ in 0..PINGS {
+            let started = Instant::now();
+            send(&mut writer, &Message::Ping).await?;
+            while !matches!(
+                serde_json::from_str(&next_line(&mut lines).await?),
+                Ok(Message::Pong)
+            ) {}
+            rtts.push(started.elapsed());
+        }
+        io::Result::Ok(rtts)
+    };
+    match timed.await {
+        Ok(rtts) => {
+            let (min, max) = (rtts.iter().min(), rtts.iter().max());
+            let (min, max) = (
+                min.copied().unwrap_or_default(),
+                max.copied().unwrap_or_default(),
+            );
+            let detail = format!("{:.1?} to {:.1?} over {} pings", min, max, PINGS);
+            let status = if max > SLOW_RTT {
+                Status::Warn
+            } else {
+                Status::Pass
+            };
+            Check::new("rtt", status, detail)
+        }
+        Err(err) => Check::new("rtt", Status::Fail, err.to_string()),
+    }
+}
+
+/// Compares the server's version with this build's. Servers older than
+/// the version request don't answer it, which is only a warning.
+pub async fn check_version(addr: &str) -> Check {
+    let asked = async {
+        let (reader, mut writer) = connect(addr).await?.into_split();
+        send(&mut writer, &ClientMessage::Version).await?;
+        let mut lines = BufReader::new(reader).lines();
+        loop {
+            if let Ok(ServerMessage::Version { version }) =
+                serde_json::from_str(&next_line(&mut lines).await?)
+            {
+                return io::Result::Ok(version);
+            }
+        }
+    };
+    let ours = env!("CARGO_PKG_VERSION");
+    match asked.await {
+        Ok(version) if version == ours => Check::new("version", Status::Pass, version),
+        Ok(version) => Check::new(
+            "version",
+            Status::Warn,
+            format!("server {}, this client {}", version, ours),
+        ),
+        Err(err) if err.kind() == io::ErrorKind::TimedOut => Check::new(
+            "version",
+            Status::Warn,
+            "the server didn't say; it predates version requests",
+        ),
+        Err(err) => Check::new("version", Status::Fail, err.to_string()),
+    }
+}
+
+/// The data dir is a directory the server can create files in.
+pub fn check_permissions(dir: &Path) -> Check {
+    let name = "permissions";
+    match std::fs::metadata(dir) {
+        Err(err) => return Check::new(name, Status::Fail, format!("{}: {}", dir.display(), err)),
+        Ok(meta) if !meta.is_dir() => {
+            let detail = format!("{} is not a directory", dir.display());
+            return Check::new(name, Status::Fail, detail);
+        }
+        Ok(_) => {}
+    }
+    let probe = dir.join(format!(".doctor.{}.tmp", std::process::id()));
+    match std::fs::write(&probe, b"").and_then(|_| std::fs::remove_file(&probe)) {
+        Ok(()) => Check::new(name, Status::Pass, format!("{} is writable", dir.display())),
+        Err(err) => {
+            let detail = format!("can't write to {}: {}", dir.display(), err);
+            Check::new(name, Status::Fail, detail)
+        }
+    }
+}
+
+pub fn check_free_space(dir: &Path) -> Check {
+    match free_space(dir) {
+        Ok((available, total)) => free_space_status(available, total),
+        Err(err) => Check::new("disk space", Status::Warn, format!("not checked: {}", err)),
+    }
+}
+
+fn free_space_status(available: u64, total: u64) -> Check {
+    let status = match available {
+        _ if available < FAIL_FREE_BYTES => Status::Fail,
+        _ if available < WARN_FREE_BYTES => Status::Warn,
+        _ => Status::Pass,
+    };
+    let mib = |bytes: u64| bytes >> 20;
+    let detail = format!("{} MiB free of {} MiB", mib(available), mib(total));
+    Check::new("disk space", status, detail)
+}
+
+/// Bytes available to unprivileged users, and the size of the file system
+/// holding `dir`.
+#[cfg(unix)]
+fn free_space(dir: &Path) -> io::Result<(u64, u64)> {
+    use std::os::unix::ffi::OsStrExt;
+    let path = std::ffi::CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+    // SAFETY: statvfs is plain data, and all zeroes is a valid value of it.
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    // SAFETY: `path` is NUL-terminated and `stat` is ours to write to.
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let block = stat.f_frsize as u64;
+    Ok((stat.f_bavail as u64 * block, stat.f_blocks as u64 * block))
+}
+
+#[cfg(not(unix))]
+fn free_space(_dir: &Path) -> io::Result<(u64, u64)> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "only supported on Unix",
+    ))
+}
+
+/// Loads every stored document, which reads its text and metadata and
+/// verifies its checksum. Nothing is quarantined or repaired.
+pub async fn check_documents(storage: &Storage) -> Check {
+    let name = "documents";
+    let rooms = match storage.list_rooms().await {
+        Ok(rooms) => rooms,
+        Err(err) => return Check::new(name, Status::Fail, format!("listing rooms: {}", err)),
+    };
+    let (mut loaded, mut problems, mut status) = (0, Vec::new(), Status::Pass);
+    for room in &rooms {
+        let docs = match storage.list(room).await {
+            Ok(docs) => docs,
+            Err(err) => {
+                problems.push(format!("{}: {}", room, err));
+                status = Status::Fail;
+                continue;
+            }
+        };
+        for doc in docs {
+            match storage.load(room, &doc.name).await {
+                Ok(stored) => {
+                    loaded += 1;
+                    if let Some(warning) = stored.warning {
+                        problems.push(format!("{}/{}: {}", room, doc.name, warning));
+                        status = status.max(Status::Warn);
+                    }
+                }
+                Err(err) => {
+                    let kind = if storage::is_corrupt(&err) {
+                        "corrupt"
+                    } else {
+                        "unreadable"
+                    };
+                    problems.push(format!("{}/{} is {}: {}", room, doc.name, kind, err));
+                    status = Status::Fail;
+                }
+            }
+        }
+    }
+    let mut detail = format!("{} loaded from {} rooms", loaded, rooms.len());
+    for problem in problems {
+        detail.push_str("\n    ");
+        detail.push_str(&problem);
+    }
+    Check::new(name, status, detail)
+}
+
+/// Temporary files of saves that never finished, left by a server that
+/// is no longer running, and sockets, which nothing under the data dir
+/// should be.
+pub fn check_stale_files(dir: &Path) -> Check {
+    let name = "stale files";
+    let mut stale = Vec::new();
+    if let Err(err) = find_stale(dir, &mut stale) {
+        return Check::new(name, Status::Warn, format!("not checked: {}", err));
+    }
+    if stale.is_empty() {
+        return Check::new(name, Status::Pass, "none");
+    }
+    let mut detail = format!("{} to remove", stale.len());
+    for path in stale {
+        detail.push_str(&format!("\n    {}", path.display()));
+    }
+    Check::new(name, Status::Warn, detail)
+}
+
+fn find_stale(dir: &Path, stale: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let (path, file_type) = (entry.path(), entry.file_type()?);
+        if file_type.is_dir() {
+            find_stale(&path, stale)?;
+        } else if is_socket(&file_type) || is_abandoned_tmp(&path) {
+            stale.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn is_socket(file_type: &std::fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_socket()
+}
+
+#[cfg(not(unix))]
+fn is_socket(_file_type: &std::fs::FileType) -> bool {
+    false
+}
+
+/// A `.<file>.<pid>-<n>.tmp` (or, from older versions, `.<file>.<pid>.tmp`)
+/// of an atomic save whose process is gone.
+fn is_abandoned_tmp(path: &Path) -> bool {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return false;
+    };
+    let Some(stem) = name
+        .strip_prefix('.')
+        .and_then(|name| name.strip_suffix(".tmp"))
+    else {
+        return false;
+    };
+    let pid = stem.rsplit_once('.').map(|(_, suffix)| {
+        let pid = suffix.split_once('-').map_or(suffix, |(pid, _)| pid);
+        pid.parse::<u32>()
+    });
+    match pid {
+        Some(Ok(pid)) => !is_running(pid),
+        _ => false,
+    }
+}
+
+async fn connect(addr: &str) -> io::Result<TcpStream> {
+    tokio::time::timeout(TIMEOUT, TcpStream::connect(addr))
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connecting timed out"))?
+}
+
+async fn send(
+    writer: &mut (impl AsyncWriteExt + Unpin),
+    msg: &impl serde::Serialize,
+) -> io::Result<()> {
+    let mut line = serde_json::to_string(msg).map_err(io::Error::other)?;
+    line.push('\n');
+    writer.write_all(line.as_bytes()).await
+}
+
+async fn next_line(
+    lines: &mut Lines<BufReader<tokio::net::tcp::OwnedReadHalf>>,
+) -> io::Result<String> {
+    tokio::time::timeout(TIMEOUT, lines.next_line())
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no answer from the server"))??
+        .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "the server hung up"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::DocMeta;
+    use crate::testing::TestServer;
+
+    #[tokio::test]
+    async fn a_running_server_passes_and_a_closed_port_fails_at_connect() {
+        let server = TestServer::spawn().await.unwrap();
+        let addr = server.addr.to_string();
+        let checks = check_server(&addr).await;
+        let names: Vec<&str> = checks.iter().map(|check| check.name).collect();
+        assert_eq!(names, ["dns", "connect", "handshake", "rtt", "version"]);
+        for check in &checks {
+            assert_eq!(check.status, Status::Pass, "{}", check);
+        }
+        let data_dir = server.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(data_dir);
+
+        let checks = check_server(&addr).await;
+        assert_eq!(checks.len(), 2);
+        assert_eq!(checks[1].status, Status::Fail);
+        assert_eq!(check_dns("no port").await.status, Status::Fail);
+    }
+
+    #[tokio::test]
+    async fn corrupt_documents_and_abandoned_saves_are_reported() {
+        let dir = std::env::temp_dir().join(format!("carnelia-doctor-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        for doc in ["fine", "flipped"] {
+            storage
+                .save("team", doc, "hello".into(), DocMeta::default())
+                .await
+                .unwrap();
+        }
+        let checks = check_data_dir(&dir, None).await;
+        for check in &checks {
+            assert_ne!(check.status, Status::Fail, "{}", check);
+        }
+
+        std::fs::write(dir.join("team").join("flipped"), "jello").unwrap();
+        // Ours is running; no process has the largest pid.
+        let gone = format!(".fine.{}-3.tmp", i32::MAX);
+        std::fs::write(dir.join("team").join(&gone), "").unwrap();
+        let ours = format!(".fine.{}-4.tmp", std::process::id());
+        std::fs::write(dir.join("team").join(ours), "").unwrap();
+        let older = format!(".fine.{}.tmp", std::process::id());
+        std::fs::write(dir.join("team").join(older), "").unwrap();
+        let checks = check_data_dir(&dir, None).await;
+        let documents = &checks[2];
+        assert_eq!(documents.status, Status::Fail);
+        assert!(documents.detail.starts_with("1 loaded from 1 rooms"));
+        assert!(documents.detail.contains("team/flipped is corrupt"));
+        let stale = &checks[3];
+        assert_eq!(stale.status, Status::Warn);
+        assert!(stale.detail.starts_with("1 to remove") && stale.detail.contains(&gone));
+        // Checking moved nothing aside.
+        assert!(dir.join("team").join("flipped").exists());
+
+        let missing = check_data_dir(&dir.join("missing"), None).await;
+        assert_eq!((missing.len(), missing[0].status), (1, Status::Fail));
+        assert_eq!(free_space_status(10 << 20, 1 << 40).status, Status::Fail);
+        assert_eq!(free_space_status(100 << 20, 1 << 40).status, Status::Warn);
+        assert_eq!(free_space_status(5 << 30, 1 << 40).status, Status::Pass);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,275 @@
+//! Room exports (`export-room`): a zip with every document of a room under
+//! `docs/` and a `manifest.json` listing their metadata, or why they
+//! couldn't be loaded.
+
+use crate::protocol::{EXPORT_CHUNK_LEN, ServerMessage};
+use crate::storage::{DocMeta, StorageBackend};
+use crate::textpos::clamp_to_boundary;
+use serde::Serialize;
+use std::io::{self, Seek, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+use zip::write::SimpleFileOptions;
+
+/// Where documents go in the archive, so none clashes with the manifest.
+const DOCS_DIR: &str = "docs";
+const MANIFEST: &str = "manifest.json";
+
+/// A document of the exported room, or why it couldn't be loaded.
+pub struct ExportedDoc {
+    pub name: String,
+    pub content: Result<(String, DocMeta), String>,
+}
+
+impl ExportedDoc {
+    pub async fn load(storage: &dyn StorageBackend, room: &str, name: String) -> Self {
+        let content = storage
+            .load(room, &name)
+            .await
+            .map(|stored| (stored.text, stored.meta))
+            .map_err(|err| err.to_string());
+        Self { name, content }
+    }
+
+    /// What the server sends for the document: its metadata, then its text
+    /// in chunks.
+    pub fn into_messages(self) -> Vec<ServerMessage> {
+        let (text, meta) = match self.content {
+            Ok(content) => content,
+            Err(error) => {
+                return vec![ServerMessage::DocFailed {
+                    name: self.name,
+                    error,
+                }];
+            }
+        };
+        let mut messages = vec![ServerMessage::DocStart {
+            name: self.name.clone(),
+            meta,
+        }];
+        let mut rest = text.as_str();
+        while !rest.is_empty() {
+            let end = clamp_to_boundary(rest, EXPORT_CHUNK_LEN);
+            let (chunk, tail) = rest.split_at(end);
+            messages.push(ServerMessage::DocChunk {
+                name: self.name.clone(),
+                text: chunk.to_string(),
+            });
+            rest = tail;
+        }
+        messages
+    }
+}
+
+/// Every document of `room`, read straight from storage.
+pub async fn collect(storage: &dyn StorageBackend, room: &str) -> io::Result<Vec<ExportedDoc>> {
+    let mut docs = Vec::new();
+    for entry in storage.list(room).await? {
+        docs.push(ExportedDoc::load(storage, room, entry.name).await);
+    }
+    Ok(docs)
+}
+
+/// Puts an export received as `ServerMessage`s back together.
+#[derive(Default)]
+pub struct Assembler {
+    docs: Vec<ExportedDoc>,
+}
+
+impl Assembler {
+    /// Takes the next message; returns the documents once the export is
+    /// done.
+    pub fn push(&mut self, msg: ServerMessage) -> Option<Result<Vec<ExportedDoc>, String>> {
+        match msg {
+            ServerMessage::DocStart { name, meta } => self.docs.push(ExportedDoc {
+                name,
+                content: Ok((String::new(), meta)),
+            }),
+            ServerMessage::DocChunk { name, text } => {
+                if let Some(ExportedDoc {
+                    name: current,
+                    content: Ok((so_far, _)),
+                }) = self.docs.last_mut()
+                    && *current == name
+                {
+                    so_far.push_str(&text);
+                }
+            }
+            ServerMessage::DocFailed { name, error } => self.docs.push(ExportedDoc {
+                name,
+                content: Err(error),
+            }),
+            ServerMessage::ExportDone { error: Some(error) } => return Some(Err(error)),
+            ServerMessage::ExportDone { error: None } => {
+                return Some(Ok(std::mem::take(&mut self.docs)));
+            }
+            ServerMessage::Version { .. }
+            | ServerMessage::SnapshotDone { .. }
+            | ServerMessage::SnapshotFailed { .. }
+            | ServerMessage::DocStats { .. }
+            | ServerMessage::Welcome { .. }
+            | ServerMessage::Rejected { .. }
+            | ServerMessage::Error { .. }
+            | ServerMessage::UserEvent { .. }
+            | ServerMessage::Members { .. }
+            | ServerMessage::DocInfo { .. }
+            | ServerMessage::Activity { .. }
+            | ServerMessage::Notice { .. }
+            | ServerMessage::DocRenamed { .. }
+            | ServerMessage::RoomInfo { .. }
+            | ServerMessage::Rooms { .. }
+            | ServerMessage::Docs { .. }
+            | ServerMessage::Revisions { .. }
+            | ServerMessage::Trash { .. }
+            | ServerMessage::DocRestored { .. }
+            | ServerMessage::RevisionText { .. }
+            | ServerMessage::RestoreDone { .. } => {}
+        }
+        None
+    }
+}
+
+#[derive(Serialize)]
+struct Manifest<'a> {
+    room: &'a str,
+    /// Unix seconds.
+    exported_at: u64,
+    docs: Vec<ManifestEntry<'a>>,
+}
+
+#[derive(Serialize)]
+struct ManifestEntry<'a> {
+    name: &'a str,
+    /// Of the text in the archive; absent if the document failed to load.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    size: Option<usize>,
+    #[serde(flatten)]
+    meta: Option<&'a DocMeta>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+/// Writes the zip of `room`'s documents to `out`.
+pub fn write_zip<W: Write + Seek>(out: W, room: &str, docs: &[ExportedDoc]) -> io::Result<()> {
+    let mut zip = zip::ZipWriter::new(out);
+    let options = SimpleFileOptions::default().compression_method(zip::CompressionMethod::Deflated);
+    let mut entries = Vec::new();
+    for doc in docs {
+        let entry = match &doc.content {
+            Ok((text, meta)) => {
+                let path = format!("{}/{}", DOCS_DIR, doc.name);
+                zip.start_file(path.as_str(), options)
+                    .map_err(io::Error::other)?;
+                zip.write_all(text.as_bytes())?;
+                ManifestEntry {
+                    name: &doc.name,
+                    path: Some(path),
+                    size: Some(text.len()),
+                    meta: Some(meta),
+                    error: None,
+                }
+            }
+            Err(error) => ManifestEntry {
+                name: &doc.name,
+                path: None,
+                size: None,
+                meta: None,
+                error: Some(error),
+            },
+        };
+        entries.push(entry);
+    }
+    let manifest = Manifest {
+        room,
+        exported_at: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs()),
+        docs: entries,
+    };
+    zip.start_file(MANIFEST, options)
+        .map_err(io::Error::other)?;
+    serde_json::to_writer_pretty(&mut zip, &manifest)?;
+    zip.finish().map_err(io::Error::other)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn exports_survive_the_wire_and_zip_with_a_manifest() {
+        let meta = DocMeta {
+            version: 7,
+            last_modified: Some(1_700_000_000),
+            last_editor: Some("ada".into()),
+            ..DocMeta::default()
+        };
+        let long = "éa".repeat(EXPORT_CHUNK_LEN / 2);
+        let docs = vec![
+            ExportedDoc {
+                name: "notes/2024/standup.md".into(),
+                content: Ok((long.clone(), meta)),
+            },
+            ExportedDoc {
+                name: "empty".into(),
+                content: Ok((String::new(), DocMeta::default())),
+            },
+            ExportedDoc {
+                name: "lost".into(),
+                content: Err("checksum mismatch".into()),
+            },
+        ];
+
+        let mut assembler = Assembler::default();
+        let mut sent = 0;
+        for msg in docs.into_iter().flat_map(ExportedDoc::into_messages) {
+            sent += 1;
+            assert!(assembler.push(msg).is_none());
+        }
+        // The long text took two chunks, split between characters.
+        assert_eq!(sent, 5);
+        let done = ServerMessage::ExportDone { error: None };
+        let docs = assembler.push(done).unwrap().unwrap();
+        assert_eq!(docs[0].content.as_ref().unwrap().0, long);
+
+        let mut zip = Cursor::new(Vec::new());
+        write_zip(&mut zip, "team", &docs).unwrap();
+        let mut archive = zip::ZipArchive::new(zip).unwrap();
+        let mut names: Vec<&str> = archive.file_names().collect();
+        names.sort();
+        assert_eq!(
+            names,
+            ["docs/empty", "docs/notes/2024/standup.md", "manifest.json"]
+        );
+        let mut text = String::new();
+        archive
+            .by_name("docs/notes/2024/standup.md")
+            .unwrap()
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, long);
+
+        let manifest: serde_json::Value =
+            serde_json::from_reader(archive.by_name(MANIFEST).unwrap()).unwrap();
+        assert_eq!(manifest["room"], "team");
+        let entries = manifest["docs"].as_array().unwrap();
+        assert_eq!(entries.len(), 3);
+        assert_eq!(entries[0]["path"], "docs/notes/2024/standup.md");
+        assert_eq!(entries[0]["version"], 7);
+        assert_eq!(entries[0]["last_modified"], 1_700_000_000);
+        assert_eq!(entries[0]["last_editor"], "ada");
+        assert_eq!(entries[0]["size"], long.len());
+        assert_eq!(entries[2]["name"], "lost");
+        assert_eq!(entries[2]["error"], "checksum mismatch");
+        assert!(entries[2].get("path").is_none() && entries[2].get("version").is_none());
+
+        let mut failed = Assembler::default();
+        let stopped = ServerMessage::ExportDone {
+            error: Some("no such room".into()),
+        };
+        assert_eq!(failed.push(stopped).unwrap().err().unwrap(), "no such room");
+    }
+}
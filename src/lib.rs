@@ -0,0 +1,27 @@
+//! A collaborative plain-text editor over TCP: the server and its storage,
+//! the wire protocol, and the line and terminal clients. `collab-cli` is a
+//! thin command line over this crate, and `dashboard` a live view of a
+//! server over its admin endpoints; `testing` runs servers and clients
+//! in-process for end-to-end tests, and `bot` runs callbacks on a
+//! document's edits.
+
+pub mod bot;
+pub mod bridge;
+pub mod chunked;
+pub mod client;
+pub mod config;
+pub mod dashboard;
+pub mod doctor;
+pub mod export;
+mod lines;
+mod position;
+pub mod protocol;
+pub mod record;
+mod replace;
+pub mod server;
+pub mod sim;
+mod snapshot;
+pub mod storage;
+pub mod testing;
+mod textpos;
+pub mod tui;
@@ -0,0 +1,180 @@
+//! Writing JSON lines to a connection in batches: whatever is queued when
+//! the writer gets to it goes out in one write, newlines included, up to
+//! `BATCH_BYTES`. A message that finds the queue empty is written right
+//! away, so batching only kicks in under bursts and adds no latency.
+
+use serde::Serialize;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// A batch stops taking messages once it holds this many bytes.
+pub(crate) const BATCH_BYTES: usize = 64 * 1024;
+
+/// Messages and bytes written, for metrics.
+#[derive(Debug, Default)]
+pub(crate) struct WriteStats {
+    messages: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl WriteStats {
+    pub(crate) const fn new() -> Self {
+        Self {
+            messages: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn messages(&self) -> u64 {
+        self.messages.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+}
+
+/// Lines waiting to be written together. The buffer is kept between
+/// batches, so a busy connection stops allocating once it has grown.
+#[derive(Debug, Default)]
+pub(crate) struct LineBatch {
+    buf: Vec<u8>,
+    messages: u64,
+}
+
+impl LineBatch {
+    /// Adds `msg` as a line; one that can't be serialized is skipped.
+    pub(crate) fn push(&mut self, msg: &impl Serialize) {
+        let start = self.buf.len();
+        if serde_json::to_writer(&mut self.buf, msg).is_err() {
+            self.buf.truncate(start);
+            return;
+        }
+        self.buf.push(b'\n');
+        self.messages += 1;
+    }
+
+    /// Whether the batch should be written before taking more.
+    pub(crate) fn full(&self) -> bool {
+        self.buf.len() >= BATCH_BYTES
+    }
+
+    /// Writes the lines in one go, counting them in `stats`, and empties
+    /// the batch.
+    pub(crate) async fn flush(
+        &mut self,
+        writer: &mut (impl AsyncWrite + Unpin),
+        stats: &WriteStats,
+    ) -> io::Result<()> {
+        if self.buf.is_empty() {
+            return Ok(());
+        }
+        writer.write_all(&self.buf).await?;
+        writer.flush().await?;
+        stats.messages.fetch_add(self.messages, Ordering::Relaxed);
+        stats
+            .bytes
+            .fetch_add(self.buf.len() as u64, Ordering::Relaxed);
+        self.buf.clear();
+        self.messages = 0;
+        Ok(())
+    }
+}
+
+/// Writes what is sent on `rx` to `writer`, one JSON line each, until the
+/// channel is closed or writing fails: each message with those queued
+/// behind it, as far as they fit in a batch.
+pub(crate) async fn write_lines<T: Serialize>(
+    rx: &mut mpsc::Receiver<T>,
+    writer: &mut (impl AsyncWrite + Unpin),
+    stats: &WriteStats,
+) -> io::Result<()> {
+    let mut batch = LineBatch::default();
+    while let Some(msg) = rx.recv().await {
+        batch.push(&msg);
+        while !batch.full() {
+            let Ok(msg) = rx.try_recv() else {
+                break;
+            };
+            batch.push(&msg);
+        }
+        batch.flush(writer, stats).await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use std::time::Duration;
+    use tokio::io::{AsyncBufReadExt, BufReader};
+
+    /// Counts the writes it is given, each one a syscall on a socket.
+    #[derive(Default)]
+    struct CountingWriter {
+        writes: usize,
+        written: Vec<u8>,
+    }
+
+    impl AsyncWrite for CountingWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            self.writes += 1;
+            self.written.extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn bursts_are_written_in_a_few_big_writes() {
+        let (tx, mut rx) = mpsc::channel(1000);
+        let line = "x".repeat(200);
+        for _ in 0..1000 {
+            tx.send(line.clone()).await.unwrap();
+        }
+        drop(tx);
+        let (mut writer, stats) = (CountingWriter::default(), WriteStats::new());
+        write_lines(&mut rx, &mut writer, &stats).await.unwrap();
+
+        // 1000 lines of 203 bytes make four batches, not 2000 writes.
+        assert_eq!(writer.writes, 4);
+        assert_eq!((stats.messages(), stats.bytes()), (1000, 203_000));
+        let text = String::from_utf8(writer.written).unwrap();
+        assert_eq!(text.lines().count(), 1000);
+        assert!(
+            text.lines()
+                .all(|written| written == format!("\"{}\"", line))
+        );
+    }
+
+    #[tokio::test]
+    async fn a_lone_message_is_written_at_once() {
+        let (tx, mut rx) = mpsc::channel(8);
+        let (mut ours, theirs) = tokio::io::duplex(1024);
+        tokio::spawn(async move { write_lines(&mut rx, &mut ours, &WriteStats::new()).await });
+        tx.send("hi").await.unwrap();
+        // Nothing more is coming, and it goes out without waiting for it.
+        let mut line = String::new();
+        let mut reader = BufReader::new(theirs);
+        tokio::time::timeout(Duration::from_secs(5), reader.read_line(&mut line))
+            .await
+            .expect("written while the channel is open")
+            .unwrap();
+        assert_eq!(line, "\"hi\"\n");
+    }
+}
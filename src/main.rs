@@ -1,10 +1,10 @@
-mod client;
-mod protocol;
-mod server;
-mod storage;
-mod tui;
-
-use clap::{Parser, Subcommand};
+use carnelia_collab::storage::{self, StorageBackend};
+use carnelia_collab::{
+    bridge, client, config, dashboard, doctor, export, protocol, record, server, sim, tui,
+};
+use clap::parser::ValueSource;
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use std::io::IsTerminal;
 
 #[derive(Parser, Debug)]
 #[command(
@@ -22,44 +22,370 @@ enum Command {
     /// Run the collaboration server
     Server {
         /// Address to bind (e.g. 0.0.0.0:4000)
-        #[arg(long, default_value = "0.0.0.0:4000")]
+        #[arg(long, env = "COLLAB_ADDR", default_value = "0.0.0.0:4000")]
         addr: String,
         /// Directory to store document snapshots
-        #[arg(long, default_value = "data")]
+        #[arg(long, env = "COLLAB_DATA_DIR", default_value = "data")]
         data_dir: String,
         /// Address for HTTP health checks (GET /health)
-        #[arg(long, default_value = "0.0.0.0:8080")]
+        #[arg(long, env = "COLLAB_HEALTH_ADDR", default_value = "0.0.0.0:8080")]
         health_addr: String,
+        /// Where documents are kept; `memory` loses them on exit
+        #[arg(long, env = "COLLAB_STORAGE", value_enum, default_value = "fs")]
+        storage: storage::BackendKind,
+        /// When saved documents are fsynced: never, on-save, or
+        /// interval:<n>ms / interval:<n>s
+        #[arg(long, env = "COLLAB_FSYNC", default_value = "on-save")]
+        fsync: storage::SyncPolicy,
+        /// Revisions kept per document under <room>/.history
+        #[arg(long, env = "COLLAB_HISTORY_KEEP", default_value_t = 50)]
+        history_keep: usize,
+        /// Store a revision every this many versions...
+        #[arg(long, env = "COLLAB_HISTORY_EVERY_VERSIONS", default_value_t = 100)]
+        history_every_versions: u64,
+        /// ...or this many minutes, whichever comes first
+        #[arg(long, env = "COLLAB_HISTORY_EVERY_MINUTES", default_value_t = 10)]
+        history_every_minutes: u64,
+        /// Encrypt stored documents and revisions with a key derived from
+        /// this file
+        #[arg(long, env = "COLLAB_ENCRYPTION_KEY_FILE")]
+        encryption_key_file: Option<std::path::PathBuf>,
+        /// Encrypt plaintext documents found at startup instead of refusing
+        /// to start
+        #[arg(long, env = "COLLAB_MIGRATE_ENCRYPT", requires = "encryption_key_file")]
+        migrate_encrypt: bool,
+        /// Gzip documents and revisions larger than this many bytes (0: never)
+        #[arg(long, env = "COLLAB_COMPRESS_ABOVE", default_value_t = storage::DEFAULT_COMPRESS_ABOVE)]
+        compress_above: usize,
+        /// Reload documents edited on disk by other programs and send the
+        /// changes to connected clients
+        #[arg(long, env = "COLLAB_WATCH_DATA_DIR")]
+        watch_data_dir: bool,
+        /// Documents created by joining them start from template.<ext> (or
+        /// template) in this directory, with {{date}}, {{room}} and {{doc}}
+        /// filled in
+        #[arg(long, env = "COLLAB_TEMPLATE_DIR")]
+        template_dir: Option<std::path::PathBuf>,
+        /// Joining a doc name that differs only in case from an existing
+        /// doc: warn, merge (join that one) or distinct
+        #[arg(long, env = "COLLAB_DOC_NAMES", value_enum, default_value = "warn")]
+        doc_names: storage::DocNames,
+        /// Purge deleted documents from the trash this many days after they were deleted
+        #[arg(long, env = "COLLAB_TRASH_RETENTION_DAYS", default_value_t = 30)]
+        trash_retention_days: u64,
+        /// Serve document contents on the health address, at
+        /// /rooms/<room>/docs/<doc>?format=raw|md
+        #[arg(long, env = "COLLAB_ENABLE_HTTP_READ")]
+        enable_http_read: bool,
+        /// Rooms with gauges of their own on /metrics; the rest are summed
+        /// into room="other"
+        #[arg(long, env = "COLLAB_METRICS_ROOM_LIMIT", default_value_t = 50)]
+        metrics_room_limit: usize,
+        /// Refuse edits that would make a document longer than this many
+        /// bytes
+        #[arg(long, env = "COLLAB_MAX_DOC_BYTES")]
+        max_doc_bytes: Option<usize>,
+        /// Refuse messages from clients longer than this many bytes
+        #[arg(long, env = "COLLAB_MAX_MESSAGE_BYTES")]
+        max_message_bytes: Option<usize>,
+        /// Refuse edits past this many per second and connection
+        #[arg(long, env = "COLLAB_MAX_OPS_PER_SECOND")]
+        max_ops_per_second: Option<u32>,
+        /// Close connections silent for this many seconds
+        #[arg(long, env = "COLLAB_IDLE_TIMEOUT_SECS")]
+        idle_timeout_secs: Option<u64>,
+        /// Close connections after this many lines that aren't messages;
+        /// 0 never does
+        #[arg(long, env = "COLLAB_MAX_MALFORMED", default_value_t = 20)]
+        max_malformed: u32,
+        /// Refuse clients that join without a user name
+        #[arg(long, env = "COLLAB_NO_GUESTS")]
+        no_guests: bool,
+        /// Tell a document's room once when it grows past this many bytes
+        #[arg(long, env = "COLLAB_WARN_DOC_BYTES")]
+        warn_doc_bytes: Option<usize>,
+        /// Tell a document's room once when it grows past this many lines
+        #[arg(long, env = "COLLAB_WARN_DOC_LINES")]
+        warn_doc_lines: Option<usize>,
+        /// Instead of only telling the room, move such a document to
+        /// <name>-YYYYMMDD, with its users, and start it over empty
+        #[arg(long, env = "COLLAB_AUTO_ROTATE")]
+        auto_rotate: bool,
+        /// Send this file's text to everyone who connects; it is read again
+        /// on SIGHUP and when it changes
+        #[arg(long, env = "COLLAB_MOTD_FILE")]
+        motd_file: Option<std::path::PathBuf>,
+        /// Also send a changed message of the day to everyone connected
+        #[arg(long, env = "COLLAB_ANNOUNCE_MOTD", requires = "motd_file")]
+        announce_motd: bool,
+        /// Only log startup, shutdown and errors, not every connection and
+        /// join
+        #[arg(long, env = "COLLAB_QUIET")]
+        quiet: bool,
+    },
+    /// List the rooms in the data directory, or the documents of one
+    List {
+        /// Directory the server stores documents in
+        #[arg(long, env = "COLLAB_DATA_DIR", default_value = "data")]
+        data_dir: String,
+        /// Room to list the documents of
+        #[arg(long, env = "COLLAB_ROOM")]
+        room: Option<String>,
+    },
+    /// Move a stored document and its revisions to the room's trash
+    Delete {
+        /// Directory the server stores documents in
+        #[arg(long, env = "COLLAB_DATA_DIR", default_value = "data")]
+        data_dir: String,
+        /// Room name
+        #[arg(long, env = "COLLAB_ROOM")]
+        room: String,
+        /// Document name
+        #[arg(long, env = "COLLAB_DOC")]
+        doc: String,
+    },
+    /// Zip every document of a room, with a manifest.json of their
+    /// metadata
+    ExportRoom {
+        /// Directory the server stores documents in
+        #[arg(long, env = "COLLAB_DATA_DIR", default_value = "data")]
+        data_dir: String,
+        /// Ask the server at this address instead of reading the data
+        /// directory (e.g. 127.0.0.1:4000)
+        #[arg(long, env = "COLLAB_ADDR", conflicts_with = "encryption_key_file")]
+        addr: Option<String>,
+        /// Ask the server as this user; documents they may not read are
+        /// left out, as are, without one, those with readers listed
+        #[arg(long, env = "COLLAB_USER")]
+        user: Option<String>,
+        /// Room name
+        #[arg(long, env = "COLLAB_ROOM")]
+        room: String,
+        /// Zip file to write
+        #[arg(long, env = "COLLAB_OUT")]
+        out: std::path::PathBuf,
+        /// Key file the server encrypts documents with
+        #[arg(long, env = "COLLAB_ENCRYPTION_KEY_FILE")]
+        encryption_key_file: Option<std::path::PathBuf>,
+    },
+    /// List a document's stored revisions, or print one
+    History {
+        /// Directory the server stores documents in
+        #[arg(long, env = "COLLAB_DATA_DIR", default_value = "data")]
+        data_dir: String,
+        /// Room name
+        #[arg(long, env = "COLLAB_ROOM", default_value = "default-room")]
+        room: String,
+        /// Document name
+        #[arg(long, env = "COLLAB_DOC", default_value = "shared.txt")]
+        doc: String,
+        /// Print this revision's text instead of the list
+        #[arg(long, env = "COLLAB_VERSION")]
+        version: Option<u64>,
+        /// Key file the server encrypts documents with
+        #[arg(long, env = "COLLAB_ENCRYPTION_KEY_FILE")]
+        encryption_key_file: Option<std::path::PathBuf>,
+    },
+    /// Check a server's reachability or a data directory's health
+    #[command(group(clap::ArgGroup::new("target").args(["addr", "data_dir"]).required(true).multiple(true)))]
+    Doctor {
+        /// Server to connect to (e.g. 127.0.0.1:4000)
+        #[arg(long, env = "COLLAB_ADDR")]
+        addr: Option<String>,
+        /// Directory the server stores documents in
+        #[arg(long, env = "COLLAB_DATA_DIR")]
+        data_dir: Option<std::path::PathBuf>,
+        /// Key file the server encrypts documents with
+        #[arg(long, env = "COLLAB_ENCRYPTION_KEY_FILE", requires = "data_dir")]
+        encryption_key_file: Option<std::path::PathBuf>,
+    },
+    /// Keep one document the same on two servers, joining it on both as a
+    /// client
+    Bridge {
+        /// Address of the first server
+        #[arg(long, env = "COLLAB_LEFT")]
+        left: String,
+        /// Room on the first server
+        #[arg(long, env = "COLLAB_LEFT_ROOM")]
+        left_room: String,
+        /// Address of the second server
+        #[arg(long, env = "COLLAB_RIGHT")]
+        right: String,
+        /// Room on the second server
+        #[arg(long, env = "COLLAB_RIGHT_ROOM")]
+        right_room: String,
+        /// Document to share, by the same name in both rooms
+        #[arg(long, env = "COLLAB_DOC")]
+        doc: String,
+        /// Name the bridge's edits are made under
+        #[arg(long, env = "COLLAB_USER", default_value = "bridge")]
+        user: String,
+    },
+    /// Fuzz convergence: simulated clients edit concurrently over a
+    /// delaying in-process network
+    Sim {
+        /// Seed of the edits and delays (default: random)
+        #[arg(long, env = "COLLAB_SEED")]
+        seed: Option<u64>,
+        /// Number of clients
+        #[arg(long, env = "COLLAB_CLIENTS", default_value_t = 5)]
+        clients: usize,
+        /// Edits made across all clients
+        #[arg(long, env = "COLLAB_OPS", default_value_t = 2000)]
+        ops: usize,
+        /// Longest delay of a message in milliseconds
+        #[arg(long, env = "COLLAB_MAX_DELAY_MS", default_value_t = 20)]
+        max_delay_ms: u64,
     },
     /// Run an interactive client
     Client {
         /// Server address (e.g. 127.0.0.1:4000)
-        #[arg(long, default_value = "127.0.0.1:4000")]
+        #[arg(long, env = "COLLAB_ADDR", default_value = "127.0.0.1:4000")]
         addr: String,
-        /// User display name
-        #[arg(long)]
-        user: String,
+        /// User display name (default: join as a guest named by the server)
+        #[arg(long, env = "COLLAB_USER")]
+        user: Option<String>,
         /// Room name
-        #[arg(long, default_value = "default-room")]
+        #[arg(long, env = "COLLAB_ROOM", default_value = "default-room")]
         room: String,
         /// Document name
-        #[arg(long, default_value = "shared.txt")]
+        #[arg(long, env = "COLLAB_DOC", default_value = "shared.txt")]
         doc: String,
+        /// `/load` sends files longer than this many KiB in chunks
+        #[arg(long, env = "COLLAB_INSERT_CHUNK_KIB", default_value_t = 64)]
+        insert_chunk_kib: usize,
+        /// `rich` shows room/doc, version, size and users before each input
+        #[arg(long, env = "COLLAB_PROMPT", value_enum, default_value = "plain")]
+        prompt: client::PromptStyle,
+        /// The rich prompt suggests a /sync this many minutes after the last sync
+        #[arg(long, env = "COLLAB_DRIFT_MINUTES", default_value_t = 10)]
+        drift_minutes: u64,
+        /// The rich prompt also suggests one once this many versions were applied since the last sync
+        #[arg(long, env = "COLLAB_DRIFT_VERSIONS", default_value_t = 500)]
+        drift_versions: u64,
+        /// Show big deletes and wait for a `y` before sending them (default: when stdin is a terminal)
+        #[arg(long, env = "COLLAB_CONFIRM_DESTRUCTIVE")]
+        confirm_destructive: Option<bool>,
+        /// Config file (default: ~/.config/carnelia-collab/config.toml)
+        #[arg(long, env = "COLLAB_CONFIG")]
+        config: Option<std::path::PathBuf>,
+        /// Sync again by itself every this many seconds, after 30 s without local edits (default: never)
+        #[arg(long, env = "COLLAB_AUTO_SYNC")]
+        auto_sync: Option<u64>,
+        /// Simulate a slow network: delay each line by this many milliseconds
+        #[arg(long, env = "COLLAB_SIMULATE_LATENCY", default_value_t = 0)]
+        simulate_latency: u64,
+        /// Simulate an uneven network: delay each line by up to this many milliseconds more
+        #[arg(long, env = "COLLAB_SIMULATE_JITTER", default_value_t = 0)]
+        simulate_jitter: u64,
+        /// Simulate a lossy network: lose this percentage of lines, resent a retransmission timeout later
+        #[arg(long, env = "COLLAB_SIMULATE_LOSS", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+        simulate_loss: u8,
+        /// Drop lost lines for good instead of resending them
+        #[arg(long, env = "COLLAB_SIMULATE_UNRELIABLE")]
+        simulate_unreliable: bool,
+        /// Seed of the simulated delays and losses
+        #[arg(long, env = "COLLAB_SIMULATE_SEED", default_value_t = 0)]
+        simulate_seed: u64,
+        /// Append every message sent and received to this file, for `playback`
+        #[arg(long, env = "COLLAB_RECORD")]
+        record: Option<std::path::PathBuf>,
     },
     /// Run a minimal TUI frontend
     Tui {
         /// Server address (e.g. 127.0.0.1:4000 or ngrok host:port)
-        #[arg(long, default_value = "127.0.0.1:4000")]
+        #[arg(long, env = "COLLAB_ADDR", default_value = "127.0.0.1:4000")]
+        addr: String,
+        /// User display name (default: join as a guest named by the server)
+        #[arg(long, env = "COLLAB_USER")]
+        user: Option<String>,
+        /// Room name (default: pick from the server's rooms)
+        #[arg(long, env = "COLLAB_ROOM")]
+        room: Option<String>,
+        /// Document name (default: pick from the room's documents)
+        #[arg(long, env = "COLLAB_DOC")]
+        doc: Option<String>,
+        /// Disable mouse capture (keeps terminal-native text selection)
+        #[arg(long, env = "COLLAB_NO_MOUSE")]
+        no_mouse: bool,
+        /// What to do with keystrokes typed while disconnected
+        #[arg(long, env = "COLLAB_OUTAGE_INPUT", value_enum, default_value = "queue")]
+        outage_input: tui::OutageInput,
+        /// Color theme (overrides `name` in the config's `[theme]` section)
+        #[arg(long, env = "COLLAB_THEME", value_enum)]
+        theme: Option<tui::ThemeName>,
+        /// Config file (default: ~/.config/carnelia-collab/config.toml)
+        #[arg(long, env = "COLLAB_CONFIG")]
+        config: Option<std::path::PathBuf>,
+        /// Append the debug overlay's counters to this file once per second
+        #[arg(long, env = "COLLAB_DEBUG_LOG")]
+        debug_log: Option<std::path::PathBuf>,
+        /// Don't keep local backups under ~/.local/state/collab/backup
+        #[arg(long, env = "COLLAB_NO_BACKUP")]
+        no_backup: bool,
+        /// Pastes and imports longer than this many KiB go out in chunks
+        #[arg(long, env = "COLLAB_INSERT_CHUNK_KIB", default_value_t = 64)]
+        insert_chunk_kib: usize,
+        /// Sync again by itself every this many seconds, after 30 s without local edits (default: never)
+        #[arg(long, env = "COLLAB_AUTO_SYNC")]
+        auto_sync: Option<u64>,
+        /// Simulate a slow network: delay each line by this many milliseconds
+        #[arg(long, env = "COLLAB_SIMULATE_LATENCY", default_value_t = 0)]
+        simulate_latency: u64,
+        /// Simulate an uneven network: delay each line by up to this many milliseconds more
+        #[arg(long, env = "COLLAB_SIMULATE_JITTER", default_value_t = 0)]
+        simulate_jitter: u64,
+        /// Simulate a lossy network: lose this percentage of lines, resent a retransmission timeout later
+        #[arg(long, env = "COLLAB_SIMULATE_LOSS", default_value_t = 0, value_parser = clap::value_parser!(u8).range(0..=100))]
+        simulate_loss: u8,
+        /// Drop lost lines for good instead of resending them
+        #[arg(long, env = "COLLAB_SIMULATE_UNRELIABLE")]
+        simulate_unreliable: bool,
+        /// Seed of the simulated delays and losses
+        #[arg(long, env = "COLLAB_SIMULATE_SEED", default_value_t = 0)]
+        simulate_seed: u64,
+        /// Append every message sent and received to this file, for `playback`
+        #[arg(long, env = "COLLAB_RECORD")]
+        record: Option<std::path::PathBuf>,
+    },
+    /// Play back a session recorded with `--record`
+    Playback {
+        /// The recording
+        path: std::path::PathBuf,
+        /// Play this many times as fast as recorded
+        #[arg(long, env = "COLLAB_SPEED", default_value_t = 1.0)]
+        speed: f64,
+        /// Send what the client sent to this server instead of showing the documents
+        #[arg(long, env = "COLLAB_AGAINST")]
+        against: Option<String>,
+    },
+    /// Watch a server's rooms and documents live, full-screen
+    Dashboard {
+        /// The server's health address, where its admin endpoints are
+        #[arg(long, env = "COLLAB_HEALTH_ADDR", default_value = "127.0.0.1:8080")]
         addr: String,
-        /// User display name
-        #[arg(long)]
-        user: String,
-        /// Room name
-        #[arg(long, default_value = "default-room")]
-        room: String,
-        /// Document name
-        #[arg(long, default_value = "shared.txt")]
-        doc: String,
+    },
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        action: ConfigAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+enum ConfigAction {
+    /// Print the config file, or with --resolved the settings a command
+    /// would run with and where each came from
+    Show {
+        /// Resolve the options of the command given after it, e.g.
+        /// `config show --resolved tui --user ada`
+        #[arg(long, requires = "command")]
+        resolved: bool,
+        /// Config file (default: ~/.config/carnelia-collab/config.toml)
+        #[arg(long, env = "COLLAB_CONFIG")]
+        config: Option<std::path::PathBuf>,
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, hide = true)]
+        command: Vec<String>,
     },
 }
 
@@ -72,20 +398,589 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             addr,
             data_dir,
             health_addr,
-        } => server::run(&addr, &data_dir, &health_addr).await?,
+            storage,
+            fsync,
+            history_keep,
+            history_every_versions,
+            history_every_minutes,
+            encryption_key_file,
+            migrate_encrypt,
+            compress_above,
+            watch_data_dir,
+            template_dir,
+            doc_names,
+            trash_retention_days,
+            enable_http_read,
+            metrics_room_limit,
+            max_doc_bytes,
+            max_message_bytes,
+            max_ops_per_second,
+            idle_timeout_secs,
+            max_malformed,
+            no_guests,
+            warn_doc_bytes,
+            warn_doc_lines,
+            auto_rotate,
+            motd_file,
+            announce_motd,
+            quiet,
+        } => {
+            server::set_quiet(quiet);
+            let history = storage::HistoryPolicy {
+                keep: history_keep,
+                every_versions: history_every_versions,
+                every: std::time::Duration::from_secs(history_every_minutes * 60),
+            };
+            let encryption = match encryption_key_file {
+                Some(path) => Some(storage::Encryption {
+                    cipher: storage::Cipher::from_key_file(&path)?,
+                    migrate: migrate_encrypt,
+                }),
+                None => None,
+            };
+            let options = storage::FsOptions {
+                data_dir: data_dir.into(),
+                fsync,
+                encryption,
+                compress_above: (compress_above > 0).then_some(compress_above),
+                watch: watch_data_dir,
+                template_dir,
+                doc_names,
+                trash_retention: std::time::Duration::from_secs(
+                    trash_retention_days * 24 * 60 * 60,
+                ),
+            };
+            server::run(
+                &addr,
+                &health_addr,
+                storage,
+                options,
+                history,
+                protocol::ServerLimits {
+                    max_doc_bytes,
+                    max_message_bytes,
+                    max_ops_per_second,
+                    idle_timeout_secs,
+                    no_guests,
+                    max_malformed: (max_malformed > 0).then_some(max_malformed),
+                },
+                server::GrowthPolicy {
+                    warn_bytes: warn_doc_bytes,
+                    warn_lines: warn_doc_lines,
+                    auto_rotate,
+                },
+                server::MotdOptions {
+                    file: motd_file,
+                    announce: announce_motd,
+                },
+                server::HealthOptions {
+                    http_read: enable_http_read,
+                    metrics_room_limit,
+                },
+            )
+            .await?
+        }
+        Command::List { data_dir, room } => {
+            let storage = storage::Storage::new(data_dir, storage::SyncPolicy::Never);
+            match room {
+                Some(room) => print_docs(&storage.list(&room).await?),
+                None => storage
+                    .list_rooms()
+                    .await?
+                    .iter()
+                    .for_each(|room| println!("{}", room)),
+            }
+        }
+        Command::Delete {
+            data_dir,
+            room,
+            doc,
+        } => {
+            // A running server would keep saving the document it moved.
+            let _lock = storage::DataDirLock::acquire(std::path::Path::new(&data_dir))?;
+            let storage = storage::Storage::new(data_dir, storage::SyncPolicy::OnSave);
+            storage.delete(&room, &doc).await?;
+            println!(
+                "moved {}/{} to the trash; /restore-doc brings it back within the retention",
+                room, doc
+            );
+        }
+        Command::ExportRoom {
+            data_dir,
+            addr,
+            user,
+            room,
+            out,
+            encryption_key_file,
+        } => {
+            let docs = match addr {
+                Some(addr) => client::export_room(&addr, &room, user.as_deref()).await?,
+                None => {
+                    let mut storage = storage::Storage::new(data_dir, storage::SyncPolicy::Never);
+                    if let Some(path) = encryption_key_file {
+                        storage = storage.encrypt_with(storage::Cipher::from_key_file(&path)?);
+                    }
+                    export::collect(&storage, &room).await?
+                }
+            };
+            export::write_zip(std::fs::File::create(&out)?, &room, &docs)?;
+            let failed: Vec<&str> = docs
+                .iter()
+                .filter_map(|doc| doc.content.as_ref().err().map(|_| doc.name.as_str()))
+                .collect();
+            println!(
+                "exported {} documents of {} to {}",
+                docs.len() - failed.len(),
+                room,
+                out.display()
+            );
+            if !failed.is_empty() {
+                println!("failed to load (see manifest.json): {}", failed.join(", "));
+            }
+        }
+        Command::History {
+            data_dir,
+            room,
+            doc,
+            version,
+            encryption_key_file,
+        } => {
+            let mut storage = storage::Storage::new(data_dir, storage::SyncPolicy::Never);
+            if let Some(path) = encryption_key_file {
+                storage = storage.encrypt_with(storage::Cipher::from_key_file(&path)?);
+            }
+            match version {
+                Some(version) => print!("{}", storage.load_revision(&room, &doc, version).await?),
+                None => print_revisions(&storage.list_revisions(&room, &doc).await?),
+            }
+        }
+        Command::Doctor {
+            addr,
+            data_dir,
+            encryption_key_file,
+        } => {
+            let mut checks = Vec::new();
+            if let Some(addr) = addr {
+                checks.extend(doctor::check_server(&addr).await);
+            }
+            if let Some(data_dir) = data_dir {
+                let cipher = match encryption_key_file {
+                    Some(path) => Some(storage::Cipher::from_key_file(&path)?),
+                    None => None,
+                };
+                checks.extend(doctor::check_data_dir(&data_dir, cipher).await);
+            }
+            checks.iter().for_each(|check| println!("{}", check));
+            let failed = checks
+                .iter()
+                .filter(|check| check.status == doctor::Status::Fail)
+                .count();
+            if failed > 0 {
+                return Err(format!("{} checks failed", failed).into());
+            }
+        }
+        Command::Bridge {
+            left,
+            left_room,
+            right,
+            right_room,
+            doc,
+            user,
+        } => {
+            let left = bridge::Endpoint {
+                addr: left,
+                room: left_room,
+            };
+            let right = bridge::Endpoint {
+                addr: right,
+                room: right_room,
+            };
+            bridge::run(&left, &right, &doc, &user)
+                .await
+                .map_err(|err| err as Box<dyn std::error::Error>)?
+        }
+        Command::Sim {
+            seed,
+            clients,
+            ops,
+            max_delay_ms,
+        } => {
+            let seed = seed.unwrap_or_else(|| {
+                let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH);
+                now.map_or(0, |since| since.as_nanos() as u64)
+            });
+            let config = sim::Config {
+                seed,
+                clients: clients.max(1),
+                ops,
+                max_delay_ms,
+            };
+            let outcome = tokio::task::spawn_blocking(move || sim::run(&config))
+                .await?
+                .map_err(|err| err as Box<dyn std::error::Error>)?;
+            println!(
+                "seed {}: {} clients converged on {} bytes at v{}",
+                seed,
+                clients,
+                outcome.text.len(),
+                outcome.version
+            );
+        }
         Command::Client {
             addr,
             user,
             room,
             doc,
-        } => client::run(&addr, &user, &room, &doc).await?,
+            insert_chunk_kib,
+            prompt,
+            drift_minutes,
+            drift_versions,
+            confirm_destructive,
+            config,
+            auto_sync,
+            simulate_latency,
+            simulate_jitter,
+            simulate_loss,
+            simulate_unreliable,
+            simulate_seed,
+            record,
+        } => {
+            let network = client::NetConditions {
+                latency_ms: simulate_latency,
+                jitter_ms: simulate_jitter,
+                loss_pct: simulate_loss,
+                unreliable: simulate_unreliable,
+                seed: simulate_seed,
+            };
+            let config = config::load(config.as_deref())?;
+            let interactive = std::io::stdin().is_terminal();
+            let options = client::Options {
+                chunk_bytes: insert_chunk_kib * 1024,
+                prompt,
+                drift: client::DriftLimits {
+                    max_age: std::time::Duration::from_secs(drift_minutes * 60),
+                    max_versions: drift_versions,
+                },
+                confirm_deletes: config.client.threshold(confirm_destructive, interactive),
+                network,
+                auto_sync: auto_sync.map(std::time::Duration::from_secs),
+                record: record
+                    .as_deref()
+                    .map(record::Recorder::create)
+                    .transpose()?,
+            };
+            client::run(&addr, &user.unwrap_or_default(), &room, &doc, options).await?
+        }
         Command::Tui {
             addr,
             user,
             room,
             doc,
-        } => tui::run(&addr, &user, &room, &doc).await?,
+            no_mouse,
+            outage_input,
+            theme,
+            config,
+            debug_log,
+            no_backup,
+            insert_chunk_kib,
+            auto_sync,
+            simulate_latency,
+            simulate_jitter,
+            simulate_loss,
+            simulate_unreliable,
+            simulate_seed,
+            record,
+        } => {
+            let network = client::NetConditions {
+                latency_ms: simulate_latency,
+                jitter_ms: simulate_jitter,
+                loss_pct: simulate_loss,
+                unreliable: simulate_unreliable,
+                seed: simulate_seed,
+            };
+            let config = config::load(config.as_deref())?;
+            let theme = tui::Theme::resolve(theme, &config.theme)?;
+            let bindings = tui::Bindings::resolve(&config.tui.keys)?;
+            let options = tui::Options {
+                mouse: !no_mouse,
+                outage_input,
+                theme,
+                theme_config: config.theme,
+                cursors: config.cursors,
+                view: config.view,
+                editing: config.editing,
+                spellcheck: config.spellcheck,
+                bindings,
+                debug_log,
+                backup: !no_backup,
+                chunk_bytes: insert_chunk_kib * 1024,
+                network,
+                auto_sync: auto_sync.map(std::time::Duration::from_secs),
+                record: record
+                    .as_deref()
+                    .map(record::Recorder::create)
+                    .transpose()?,
+            };
+            let user = user.unwrap_or_default();
+            tui::run(&addr, &user, room.as_deref(), doc.as_deref(), options).await?
+        }
+        Command::Playback {
+            path,
+            speed,
+            against,
+        } => {
+            let entries = record::load(&path)?;
+            match against {
+                Some(addr) => {
+                    for (doc_id, text) in record::send_against(&entries, &addr, speed).await? {
+                        println!("[playback] {} as {} has it:", doc_id, addr);
+                        println!("{}", text);
+                    }
+                }
+                None => record::play(&entries, speed).await?,
+            }
+        }
+        Command::Dashboard { addr } => dashboard::run(&addr).await?,
+        Command::Config {
+            action:
+                ConfigAction::Show {
+                    resolved,
+                    config,
+                    command,
+                },
+        } => {
+            if resolved {
+                print_settings(&resolve_command(&command, config)?);
+            } else {
+                print_config(config.as_deref())?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// A command-line option's effective value and where it came from.
+#[derive(Debug)]
+struct Setting {
+    name: String,
+    value: Option<String>,
+    source: String,
+}
+
+/// Parses `command` as `collab-cli` would and resolves each of its
+/// options: flag, then environment, then config file, then default. The
+/// config file is the command's own `--config` if it has one, else
+/// `config_path`.
+fn resolve_command(
+    command: &[String],
+    config_path: Option<std::path::PathBuf>,
+) -> Result<Vec<Setting>, Box<dyn std::error::Error>> {
+    let args = std::iter::once("collab-cli".to_string()).chain(command.iter().cloned());
+    let matches = Args::command().try_get_matches_from(args)?;
+    let (name, matches) = matches.subcommand().ok_or("no command to resolve")?;
+    let cli = Args::command();
+    let subcommand = cli.find_subcommand(name).ok_or("no such command")?;
+    let config = if subcommand
+        .get_arguments()
+        .any(|arg| arg.get_id() == "config")
+    {
+        let path = matches
+            .get_one::<std::path::PathBuf>("config")
+            .cloned()
+            .or(config_path);
+        config::load(path.as_deref())?
+    } else {
+        config::Config::default()
+    };
+    Ok(resolve_settings(subcommand, matches, &config))
+}
+
+fn resolve_settings(
+    command: &clap::Command,
+    matches: &clap::ArgMatches,
+    config: &config::Config,
+) -> Vec<Setting> {
+    let mut settings = Vec::new();
+    for arg in command.get_arguments() {
+        let id = arg.get_id().as_str();
+        let Some(long) = arg.get_long() else {
+            continue;
+        };
+        if matches!(id, "help" | "version") {
+            continue;
+        }
+        let raw = matches.get_raw(id).map(|values| {
+            values
+                .map(|value| value.to_string_lossy())
+                .collect::<Vec<_>>()
+                .join(",")
+        });
+        let env = arg.get_env().map(|env| env.to_string_lossy());
+        let (value, source) = match matches.value_source(id) {
+            Some(ValueSource::CommandLine) => (raw, format!("flag --{}", long)),
+            Some(ValueSource::EnvVariable) => (raw, format!("env {}", env.unwrap_or_default())),
+            _ if id == "theme" && config.theme.name.is_some() => {
+                let name = config.theme.name.and_then(|name| name.to_possible_value());
+                let value = name.map(|name| name.get_name().to_string());
+                (value, "config file".to_string())
+            }
+            _ if id == "confirm_destructive" && config.client.confirm_destructive.is_some() => {
+                let value = config.client.confirm_destructive.map(|on| on.to_string());
+                (value, "config file".to_string())
+            }
+            Some(ValueSource::DefaultValue) => (raw, "default".to_string()),
+            _ => (None, "unset".to_string()),
+        };
+        // Values of options marked secret stay out of the output.
+        let value = if arg.is_hide_env_values_set() {
+            value.map(|_| "<hidden>".to_string())
+        } else {
+            value
+        };
+        settings.push(Setting {
+            name: format!("--{}", long),
+            value,
+            source,
+        });
     }
+    settings
+}
 
+fn print_settings(settings: &[Setting]) {
+    let width = settings.iter().map(|s| s.name.len()).max().unwrap_or(0);
+    let values: Vec<&str> = settings
+        .iter()
+        .map(|s| s.value.as_deref().unwrap_or("-"))
+        .collect();
+    let value_width = values.iter().map(|v| v.len()).max().unwrap_or(0);
+    for (setting, value) in settings.iter().zip(values) {
+        println!(
+            "{:<width$}  {:<value_width$}  {}",
+            setting.name, value, setting.source
+        );
+    }
+}
+
+/// The config file in use, or where it would be read from.
+fn print_config(path: Option<&std::path::Path>) -> Result<(), Box<dyn std::error::Error>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => config::default_path().ok_or("no config location (HOME is not set)")?,
+    };
+    if !path.exists() {
+        println!("no config file at {}; defaults apply", path.display());
+        return Ok(());
+    }
+    // Parse it first, so a broken file is reported as such.
+    config::load(Some(&path))?;
+    println!("# {}", path.display());
+    print!("{}", std::fs::read_to_string(&path)?);
     Ok(())
 }
+
+/// One line per document: name, size and when it last changed.
+fn print_docs(docs: &[storage::DocEntry]) {
+    let width = docs.iter().map(|doc| doc.name.len()).max().unwrap_or(0);
+    for doc in docs {
+        println!(
+            "{:<width$}  {:>10} B  {} ago",
+            doc.name,
+            doc.size,
+            age(doc.modified)
+        );
+    }
+}
+
+/// One line per revision, oldest first: version and age.
+fn print_revisions(revisions: &[storage::Revision]) {
+    if revisions.is_empty() {
+        println!("no revisions");
+    }
+    for revision in revisions {
+        println!("{:>8}  {} ago", revision.version, age(revision.saved_at));
+    }
+}
+
+/// Time since `at`, to the minute (or hour, past a day).
+fn age(at: std::time::SystemTime) -> String {
+    let minutes = at.elapsed().unwrap_or_default().as_secs() / 60;
+    match (minutes / (24 * 60), minutes / 60 % 24, minutes % 60) {
+        (0, 0, minutes) => format!("{}m", minutes),
+        (0, hours, minutes) => format!("{}h {}m", hours, minutes),
+        (days, hours, _) => format!("{}d {}h", days, hours),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn resolve(args: &[&str], config: &config::Config) -> Vec<(String, Option<String>, String)> {
+        let matches = Args::command().try_get_matches_from(args).unwrap();
+        let (name, matches) = matches.subcommand().unwrap();
+        let cli = Args::command();
+        resolve_settings(cli.find_subcommand(name).unwrap(), matches, config)
+            .into_iter()
+            .map(|setting| (setting.name, setting.value, setting.source))
+            .collect()
+    }
+
+    fn setting(settings: &[(String, Option<String>, String)], name: &str) -> (String, String) {
+        let (_, value, source) = settings.iter().find(|(n, ..)| n == name).unwrap();
+        (value.clone().unwrap_or_default(), source.clone())
+    }
+
+    /// The only test reading `COLLAB_*` variables, so it may set them.
+    #[test]
+    fn options_resolve_from_flag_then_env_then_config_file_then_default() {
+        // SAFETY: no other thread of this test binary reads the environment.
+        unsafe {
+            std::env::remove_var("COLLAB_THEME");
+            std::env::remove_var("COLLAB_ADDR");
+            std::env::set_var("COLLAB_ROOM", "from-env");
+        }
+        let tui = ["collab-cli", "tui", "--user", "ada"];
+        let mut config = config::Config::default();
+        let settings = resolve(&tui, &config);
+        let default = ("127.0.0.1:4000".to_string(), "default".to_string());
+        assert_eq!(setting(&settings, "--addr"), default);
+        let env = ("from-env".to_string(), "env COLLAB_ROOM".to_string());
+        assert_eq!(setting(&settings, "--room"), env);
+        assert_eq!(setting(&settings, "--theme").1, "unset");
+
+        let settings = resolve(
+            &["collab-cli", "tui", "--user", "ada", "--room", "x"],
+            &config,
+        );
+        let flag = ("x".to_string(), "flag --room".to_string());
+        assert_eq!(setting(&settings, "--room"), flag);
+
+        config.theme.name = Some(tui::ThemeName::HighContrast);
+        let settings = resolve(&tui, &config);
+        let file = ("high-contrast".to_string(), "config file".to_string());
+        assert_eq!(setting(&settings, "--theme"), file);
+        // SAFETY: as above.
+        unsafe { std::env::set_var("COLLAB_THEME", "light") };
+        let settings = resolve(&tui, &config);
+        let env = ("light".to_string(), "env COLLAB_THEME".to_string());
+        assert_eq!(setting(&settings, "--theme"), env);
+        unsafe {
+            std::env::remove_var("COLLAB_THEME");
+            std::env::remove_var("COLLAB_ROOM");
+            std::env::remove_var("COLLAB_CONFIRM_DESTRUCTIVE");
+        }
+
+        let client = ["collab-cli", "client"];
+        assert_eq!(
+            setting(&resolve(&client, &config), "--confirm-destructive").1,
+            "unset"
+        );
+        config.client.confirm_destructive = Some(false);
+        let file = ("false".to_string(), "config file".to_string());
+        assert_eq!(
+            setting(&resolve(&client, &config), "--confirm-destructive"),
+            file
+        );
+    }
+}
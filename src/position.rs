@@ -0,0 +1,432 @@
+//! Byte offsets as the lines and columns people see (`LineCol`), for
+//! cursors on the wire, the cursor lists and search results, and the
+//! copy of a document's text the server finds them in.
+
+use crate::protocol::LineCol;
+use crate::textpos::{byte_to_line_col, char_to_byte_index, clamp_to_boundary};
+
+/// Bytes a `TextIndex` cuts its text into; pieces grow to twice this
+/// before they are cut again.
+const PIECE: usize = 1024;
+
+/// A document's text in pieces of about `PIECE` bytes, with running
+/// totals of their bytes, characters and line breaks. An edit changes one
+/// piece and the totals, and an offset is found by searching the totals,
+/// so neither costs more in a bigger document. The server keeps one next
+/// to each document's CRDT, to turn the byte offsets of ops into the
+/// character offsets the CRDT takes without copying its text out.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TextIndex {
+    pieces: Vec<Piece>,
+    totals: Totals,
+}
+
+#[derive(Debug, Clone)]
+struct Piece {
+    text: String,
+    counts: Counts,
+}
+
+impl Piece {
+    fn new(text: &str) -> Self {
+        Self {
+            text: text.to_string(),
+            counts: Counts::of(text),
+        }
+    }
+}
+
+/// Where a byte offset is: `offset` bytes into piece `piece`, with the
+/// pieces before it counting `before`.
+struct Spot {
+    piece: usize,
+    offset: usize,
+    before: Counts,
+}
+
+impl TextIndex {
+    pub(crate) fn new(text: &str) -> Self {
+        let mut index = Self {
+            pieces: cut(text),
+            totals: Totals::default(),
+        };
+        index.retotal();
+        index
+    }
+
+    /// The length of the text in bytes.
+    pub(crate) fn byte_len(&self) -> usize {
+        self.totals.before(self.pieces.len()).bytes
+    }
+
+    /// The number of lines, counting an empty one after a final newline.
+    pub(crate) fn line_count(&self) -> usize {
+        self.totals.before(self.pieces.len()).lines + 1
+    }
+
+    /// `pos` moved back to a character boundary, or to the end of the
+    /// text if it is past it.
+    pub(crate) fn clamp(&self, pos: usize) -> usize {
+        let spot = self.spot(pos);
+        spot.before.bytes + spot.offset
+    }
+
+    /// The character offset of byte offset `pos`, clamped like `clamp`.
+    pub(crate) fn char_of(&self, pos: usize) -> usize {
+        self.chars_before(&self.spot(pos))
+    }
+
+    /// The byte offset of character offset `idx`, or the length of the
+    /// text if it is past the end.
+    pub(crate) fn byte_of(&self, idx: usize) -> usize {
+        let Some(last) = self.pieces.len().checked_sub(1) else {
+            return 0;
+        };
+        let (mut lo, mut hi) = (0, last);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.totals.before(mid + 1).chars < idx {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let before = self.totals.before(lo);
+        let piece = &self.pieces[lo].text;
+        before.bytes + char_to_byte_index(piece, idx.saturating_sub(before.chars))
+    }
+
+    /// Where byte offset `pos` is; offsets past the end or inside a
+    /// character count as the character boundary before them.
+    pub(crate) fn locate(&self, pos: usize) -> LineCol {
+        let spot = self.spot(pos);
+        let Some(piece) = self.pieces.get(spot.piece) else {
+            return LineCol { line: 1, col: 1 };
+        };
+        let head = &piece.text[..spot.offset];
+        let line = spot.before.lines + head.matches('\n').count() + 1;
+        // The line may have started pieces earlier.
+        let earlier = self.pieces[..spot.piece].iter().rev();
+        let mut col = 1;
+        for text in std::iter::once(head).chain(earlier.map(|piece| piece.text.as_str())) {
+            match text.rfind('\n') {
+                Some(idx) => {
+                    col += text[idx + 1..].chars().count();
+                    break;
+                }
+                None => col += text.chars().count(),
+            }
+        }
+        LineCol { line, col }
+    }
+
+    /// Inserts `text` at byte offset `pos`, clamped like `locate`, and
+    /// returns the character offset it went in at.
+    pub(crate) fn insert(&mut self, pos: usize, text: &str) -> usize {
+        if self.pieces.is_empty() {
+            *self = Self::new(text);
+            return 0;
+        }
+        let spot = self.spot(pos);
+        let at = self.chars_before(&spot);
+        self.edit_piece(spot.piece, |piece| piece.insert_str(spot.offset, text));
+        if self.pieces[spot.piece].text.len() > 2 * PIECE {
+            let pieces = cut(&self.pieces[spot.piece].text);
+            self.pieces.splice(spot.piece..=spot.piece, pieces);
+            self.retotal();
+        }
+        at
+    }
+
+    /// Deletes `len` bytes from byte offset `pos`, both ends clamped like
+    /// `locate`, and returns the character offset and number of
+    /// characters deleted, or `None` if nothing was.
+    pub(crate) fn delete(&mut self, pos: usize, len: usize) -> Option<(usize, usize)> {
+        let start = self.spot(pos);
+        let end = self.spot((start.before.bytes + start.offset).saturating_add(len));
+        let (from, to) = (self.chars_before(&start), self.chars_before(&end));
+        if to <= from {
+            return None;
+        }
+        if start.piece == end.piece {
+            self.edit_piece(start.piece, |piece| {
+                piece.replace_range(start.offset..end.offset, "");
+            });
+        } else {
+            self.edit_piece(start.piece, |piece| piece.truncate(start.offset));
+            self.edit_piece(end.piece, |piece| piece.replace_range(..end.offset, ""));
+        }
+        let emptied = [start.piece, end.piece]
+            .iter()
+            .any(|&idx| self.pieces[idx].text.is_empty());
+        if emptied || end.piece > start.piece + 1 {
+            if end.piece > start.piece {
+                self.pieces.drain(start.piece + 1..end.piece);
+            }
+            self.pieces.retain(|piece| !piece.text.is_empty());
+            self.retotal();
+        }
+        Some((from, to - from))
+    }
+
+    /// The piece holding byte offset `pos`. Offsets where one piece ends
+    /// and the next starts are in the first, and those past the end of the
+    /// text at the end of the last.
+    fn spot(&self, pos: usize) -> Spot {
+        let Some(last) = self.pieces.len().checked_sub(1) else {
+            return Spot {
+                piece: 0,
+                offset: 0,
+                before: Counts::default(),
+            };
+        };
+        let (mut lo, mut hi) = (0, last);
+        while lo < hi {
+            let mid = (lo + hi) / 2;
+            if self.totals.before(mid + 1).bytes < pos {
+                lo = mid + 1;
+            } else {
+                hi = mid;
+            }
+        }
+        let before = self.totals.before(lo);
+        Spot {
+            piece: lo,
+            offset: clamp_to_boundary(&self.pieces[lo].text, pos - before.bytes),
+            before,
+        }
+    }
+
+    fn chars_before(&self, spot: &Spot) -> usize {
+        let head = self
+            .pieces
+            .get(spot.piece)
+            .map_or(0, |piece| piece.text[..spot.offset].chars().count());
+        spot.before.chars + head
+    }
+
+    fn edit_piece(&mut self, idx: usize, edit: impl FnOnce(&mut String)) {
+        let piece = &mut self.pieces[idx];
+        let old = piece.counts;
+        edit(&mut piece.text);
+        piece.counts = Counts::of(&piece.text);
+        self.totals.replace(idx, old, piece.counts);
+    }
+
+    fn retotal(&mut self) {
+        self.totals = Totals::new(self.pieces.iter().map(|piece| piece.counts));
+    }
+}
+
+/// Cuts `text` into pieces of at most `PIECE` bytes.
+fn cut(text: &str) -> Vec<Piece> {
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let (head, tail) = rest.split_at(clamp_to_boundary(rest, PIECE));
+        pieces.push(Piece::new(head));
+        rest = tail;
+    }
+    pieces
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Counts {
+    bytes: usize,
+    chars: usize,
+    lines: usize,
+}
+
+impl Counts {
+    fn of(text: &str) -> Self {
+        Self {
+            bytes: text.len(),
+            chars: text.chars().count(),
+            lines: text.bytes().filter(|&byte| byte == b'\n').count(),
+        }
+    }
+
+    fn add(&mut self, other: Counts) {
+        self.bytes += other.bytes;
+        self.chars += other.chars;
+        self.lines += other.lines;
+    }
+
+    fn sub(&mut self, other: Counts) {
+        self.bytes -= other.bytes;
+        self.chars -= other.chars;
+        self.lines -= other.lines;
+    }
+}
+
+/// The counts of a row of pieces as a Fenwick tree, so the counts of the
+/// pieces before any one are summed, and one's counts changed, in
+/// O(log n) steps.
+#[derive(Debug, Clone, Default)]
+struct Totals {
+    tree: Vec<Counts>,
+}
+
+impl Totals {
+    fn new(counts: impl Iterator<Item = Counts>) -> Self {
+        let counts: Vec<Counts> = counts.collect();
+        let mut totals = Self {
+            tree: vec![Counts::default(); counts.len()],
+        };
+        for (idx, counts) in counts.into_iter().enumerate() {
+            totals.replace(idx, Counts::default(), counts);
+        }
+        totals
+    }
+
+    /// The counts of the first `end` pieces.
+    fn before(&self, end: usize) -> Counts {
+        let mut total = Counts::default();
+        let mut end = end.min(self.tree.len());
+        while end > 0 {
+            total.add(self.tree[end - 1]);
+            end &= end - 1;
+        }
+        total
+    }
+
+    /// Piece `idx` went from counting `old` to counting `new`.
+    fn replace(&mut self, idx: usize, old: Counts, new: Counts) {
+        let mut idx = idx;
+        while idx < self.tree.len() {
+            self.tree[idx].sub(old);
+            self.tree[idx].add(new);
+            idx |= idx + 1;
+        }
+    }
+}
+
+/// Where byte offset `pos` of `text` is, for a single lookup.
+pub(crate) fn line_col(text: &str, pos: usize) -> LineCol {
+    let (line, col) = byte_to_line_col(text, pos);
+    LineCol {
+        line: line + 1,
+        col: col + 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::textpos::byte_to_char_index;
+    use std::time::{Duration, Instant};
+
+    fn text_of(index: &TextIndex) -> String {
+        index
+            .pieces
+            .iter()
+            .map(|piece| piece.text.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn offsets_become_lines_and_character_columns() {
+        let text = "ab\nä中x\n\nend";
+        let index = TextIndex::new(text);
+        let at = |line, col| LineCol { line, col };
+        for (pos, expected) in [
+            (0, at(1, 1)),
+            (2, at(1, 3)),
+            (3, at(2, 1)),
+            // After "ä中": two characters, five bytes.
+            (8, at(2, 3)),
+            // Inside "中": the boundary before it.
+            (6, at(2, 2)),
+            (10, at(3, 1)),
+            (11, at(4, 1)),
+            (100, at(4, 4)),
+        ] {
+            assert_eq!(index.locate(pos), expected, "at byte {}", pos);
+            assert_eq!(line_col(text, pos), expected, "at byte {}", pos);
+        }
+        assert_eq!(at(12, 8).to_string(), "L12:C8");
+        assert_eq!(TextIndex::new("").locate(3), at(1, 1));
+    }
+
+    #[test]
+    fn edits_keep_the_index_in_step_with_the_text() {
+        // Multi-byte characters land on piece boundaries as the pieces
+        // are cut and edited.
+        let mut text = "é中🎉x\n".repeat(300);
+        let mut index = TextIndex::new(&text);
+        let mut seed = 7usize;
+        let mut next = |below: usize| {
+            seed = seed
+                .wrapping_mul(6364136223846793005)
+                .wrapping_add(1442695040888963407);
+            (seed >> 33) % below.max(1)
+        };
+        for round in 0..2000 {
+            let pos = next(text.len() + 8);
+            if round % 3 == 0 {
+                let len = next(200);
+                let start = clamp_to_boundary(&text, pos);
+                let end = clamp_to_boundary(&text, start + len);
+                let expected = (end > start).then(|| {
+                    (
+                        text[..start].chars().count(),
+                        text[start..end].chars().count(),
+                    )
+                });
+                assert_eq!(index.delete(pos, len), expected);
+                text.replace_range(start..end, "");
+            } else {
+                let insert = ["ä", "\n", "中文", "🎉\n", "plain "][next(5)].repeat(next(60) + 1);
+                let at = clamp_to_boundary(&text, pos);
+                assert_eq!(index.insert(pos, &insert), text[..at].chars().count());
+                text.insert_str(at, &insert);
+            }
+            assert_eq!(index.byte_len(), text.len());
+            let probe = next(text.len() + 2);
+            assert_eq!(index.clamp(probe), clamp_to_boundary(&text, probe));
+            let chars = byte_to_char_index(&text, probe);
+            assert_eq!(index.char_of(probe), chars);
+            assert_eq!(index.byte_of(chars), clamp_to_boundary(&text, probe));
+            assert_eq!(
+                index.locate(probe),
+                line_col(&text, probe),
+                "at byte {}",
+                probe
+            );
+        }
+        assert_eq!(text_of(&index), text);
+        assert!(
+            index
+                .pieces
+                .iter()
+                .all(|piece| piece.text.len() <= 2 * PIECE)
+        );
+    }
+
+    #[test]
+    fn edits_cost_about_the_same_in_big_documents() {
+        fn time_edits(len: usize) -> Duration {
+            let mut index = TextIndex::new(&"line of text\n".repeat(len / 13));
+            let middle = index.byte_len() / 2;
+            let started = Instant::now();
+            for _ in 0..2000 {
+                index.insert(middle, "ab");
+                index.locate(middle);
+                index.delete(middle, 2);
+            }
+            started.elapsed()
+        }
+
+        let small = time_edits(16 * 1024);
+        let big = time_edits(4 * 1024 * 1024);
+        // Copying or scanning the text per edit would make the big
+        // document hundreds of times slower; a tree deeper by a few steps
+        // shouldn't.
+        assert!(
+            big < small * 20 + Duration::from_millis(50),
+            "{:?} for 4 MiB against {:?} for 16 KiB",
+            big,
+            small
+        );
+    }
+}
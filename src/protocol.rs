@@ -1,11 +1,54 @@
+use crate::storage::{Access, Acl, DocMeta, RoomMeta, UserStats, WhitespacePolicy};
 use mdcs_sdk::Message;
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// An edit or a move of one user. Text positions count bytes of the
+/// UTF-8 text (`pos`, `len`); edits may give them in characters, that is
+/// Unicode scalar values, as `pos_chars` and `len_chars` instead, which win
+/// when both are given. Clients may send either or both. The server
+/// passes every edit on with both, counted in the text it was applied to.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Op {
-    Insert { pos: usize, text: String },
-    Delete { pos: usize, len: usize },
-    Cursor { pos: usize },
+    Insert {
+        #[serde(default)]
+        pos: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pos_chars: Option<usize>,
+        text: String,
+    },
+    Delete {
+        #[serde(default)]
+        pos: usize,
+        #[serde(default)]
+        len: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        pos_chars: Option<usize>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        len_chars: Option<usize>,
+    },
+    /// A user's cursor. The server adds where that is in lines and
+    /// columns when it passes the move on.
+    Cursor {
+        pos: usize,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        at: Option<LineCol>,
+    },
+    /// A user's selection from `anchor` to `head`; `anchor == head` clears it.
+    Selection { anchor: usize, head: usize },
+}
+
+/// A position as editors show it: 1-based line, and 1-based column
+/// counted in characters.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineCol {
+    pub line: usize,
+    pub col: usize,
+}
+
+impl std::fmt::Display for LineCol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "L{}:C{}", self.line, self.col)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -19,12 +62,23 @@ pub struct WireUpdate {
 pub struct WireSync {
     pub text: String,
     pub users: Vec<WireUser>,
+    /// The `presence_seq` of the last `Members` notice of the document;
+    /// `users` is at least as new, so notices up to it that arrive later
+    /// are stale.
+    #[serde(default)]
+    pub presence_seq: u64,
+    /// Set when the server refused the join; `text` and `users` are empty.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct WireUser {
     pub id: String,
     pub name: String,
+    /// Joined without a name and goes by one the server made up.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub guest: bool,
 }
 
 pub fn encode_update(
@@ -72,11 +126,14 @@ pub fn encode_sync_response(
     document_id: &str,
     text: &str,
     users: Vec<WireUser>,
+    presence_seq: u64,
     version: u64,
 ) -> Result<Message, serde_json::Error> {
     let payload = WireSync {
         text: text.to_string(),
         users,
+        presence_seq,
+        error: None,
     };
     let delta = serde_json::to_vec(&payload)?;
     Ok(Message::SyncResponse {
@@ -86,6 +143,22 @@ pub fn encode_sync_response(
     })
 }
 
+/// A SyncResponse refusing the join of `document_id`.
+pub fn encode_sync_error(document_id: &str, error: &str) -> Result<Message, serde_json::Error> {
+    let payload = WireSync {
+        text: String::new(),
+        users: Vec::new(),
+        presence_seq: 0,
+        error: Some(error.to_string()),
+    };
+    let delta = serde_json::to_vec(&payload)?;
+    Ok(Message::SyncResponse {
+        document_id: document_id.to_string(),
+        deltas: vec![delta],
+        version: 0,
+    })
+}
+
 pub fn decode_sync_response(msg: &Message) -> Option<(String, WireSync, u64)> {
     match msg {
         Message::SyncResponse {
@@ -101,6 +174,471 @@ pub fn decode_sync_response(msg: &Message) -> Option<(String, WireSync, u64)> {
     }
 }
 
+/// Requests outside the sync protocol, each sent as its own JSON line on
+/// a connection of its own.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ClientMessage {
+    /// Every document of `room`, answered with `ServerMessage`s.
+    ExportRoom { room: String },
+    /// The server's version, answered with `ServerMessage::Version`.
+    Version,
+    /// Save `room`/`doc` with a revision right away, answered with
+    /// `ServerMessage::SnapshotDone` or `SnapshotFailed`. `user` is who
+    /// asked, for the activity feed.
+    Snapshot {
+        room: String,
+        doc: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user: Option<String>,
+    },
+    /// Who wrote how much of `room`/`doc`, answered with
+    /// `ServerMessage::DocStats`.
+    StatsRequest { room: String, doc: String },
+    /// Sets how `room`/`doc`'s whitespace is tidied when it is saved.
+    /// Allowed on connections that never joined a document, and to the
+    /// user of the document who joined first. Answered with
+    /// `ServerMessage::DocInfo`, or `Rejected`.
+    SetPolicy {
+        room: String,
+        doc: String,
+        policy: WhitespacePolicy,
+    },
+    /// Sets who may edit and who may read `room`/`doc`. Allowed on
+    /// connections that never joined a document, to the document's
+    /// writers if it names any, and else to its user who joined first.
+    /// Answered with `ServerMessage::DocInfo`, or `Rejected`.
+    SetAcl { room: String, doc: String, acl: Acl },
+    /// Sets `room`'s default doc and aliases, which joins naming no doc or
+    /// an alias follow. Allowed on connections that never joined a
+    /// document, and to the user in the room who joined first. Answered
+    /// with `ServerMessage::RoomInfo`, or `Rejected`.
+    SetRoomMeta { room: String, meta: RoomMeta },
+    /// The rooms with documents, answered with `ServerMessage::Rooms`.
+    ListRooms,
+    /// The documents of `room`, answered with `ServerMessage::Docs`.
+    ListDocs { room: String },
+    /// The stored revisions of `room`/`doc`, answered with
+    /// `ServerMessage::Revisions`.
+    ListRevisions { room: String, doc: String },
+    /// The text of revision `version` of `room`/`doc`, answered with
+    /// `ServerMessage::RevisionText`, or `Rejected`.
+    HistoryRequest {
+        room: String,
+        doc: String,
+        version: u64,
+    },
+    /// Turns `room`/`doc` back into revision `version`, as edits its users
+    /// receive like any other. Only for a connection that said hello with
+    /// a name allowed to edit the doc, which the activity feed credits.
+    /// Answered with `ServerMessage::RestoreDone`, or `Rejected`.
+    Restore {
+        room: String,
+        doc: String,
+        version: u64,
+    },
+    /// The documents deleted from `room` that are still in its trash,
+    /// answered with `ServerMessage::Trash`, or `Rejected`.
+    ListTrash { room: String },
+    /// Brings `room`/`doc` back from the trash, as `to` if given. A
+    /// document by that name is refused unless `force`, which moves it to
+    /// the trash in its place; its users get the restored text as edits.
+    /// Only for a connection that said hello with a name allowed to edit
+    /// the restored doc. Answered with `ServerMessage::DocRestored`, or
+    /// `Rejected`.
+    RestoreDoc {
+        room: String,
+        doc: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        to: Option<String>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        force: bool,
+    },
+    /// What the sync connection it is sent on gets of what others do in
+    /// its document, from now on; a connection gets everything until it
+    /// says otherwise. Replies to its own requests come regardless. Not
+    /// answered.
+    SetSubscriptions { subscriptions: Vec<Subscription> },
+}
+
+/// Kinds of traffic a sync connection can leave out with
+/// `ClientMessage::SetSubscriptions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Subscription {
+    /// Inserts and deletes.
+    Edits,
+    /// Cursor moves and selections.
+    Cursors,
+    /// Joins, leaves and renames, and the user lists they change.
+    Presence,
+    /// Entries of the activity feed.
+    Activity,
+}
+
+impl Subscription {
+    pub const ALL: [Subscription; 4] = [
+        Subscription::Edits,
+        Subscription::Cursors,
+        Subscription::Presence,
+        Subscription::Activity,
+    ];
+}
+
+/// Replies to a `ClientMessage`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ServerMessage {
+    /// Starts document `name`; its text follows in `DocChunk`s.
+    DocStart {
+        name: String,
+        meta: DocMeta,
+    },
+    DocChunk {
+        name: String,
+        text: String,
+    },
+    /// Document `name` couldn't be loaded; nothing else is sent for it.
+    DocFailed {
+        name: String,
+        error: String,
+    },
+    /// Every document was sent, or `error` says why the export stopped.
+    ExportDone {
+        error: Option<String>,
+    },
+    /// The version of the server's crate, e.g. `0.3.1`.
+    Version {
+        version: String,
+    },
+    /// The document was saved at `version`, `bytes` long.
+    SnapshotDone {
+        version: u64,
+        bytes: usize,
+    },
+    SnapshotFailed {
+        error: String,
+    },
+    /// Edits per user, busiest first; empty if the document can't be
+    /// loaded.
+    DocStats {
+        per_user: Vec<UserStats>,
+    },
+    /// Sent on the sync connection in answer to `Hello`: what the server
+    /// will refuse. Clients older than it skip the line.
+    Welcome {
+        #[serde(default)]
+        limits: ServerLimits,
+        /// The name given to a client that said hello without one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        name: Option<String>,
+        /// The doc of the room joined instead of `redirected_from`, the one
+        /// the hello's id named: the room's default for no name, or what
+        /// an alias stands for. The client takes it for the rest of the
+        /// session, ids and sync requests included.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        doc: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        redirected_from: Option<String>,
+        /// What the user may do with the doc they join, by its ACL, so a
+        /// client can leave editing off for those who may only read.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        access: Option<Access>,
+        /// The server's message of the day, which clients show once.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        motd: Option<String>,
+    },
+    /// The client's last message broke one of the `limits`, or wasn't
+    /// allowed, and was dropped; an edit in it never happened on the
+    /// server.
+    Rejected {
+        error: String,
+    },
+    /// The client sent something the server couldn't take as a message at
+    /// all; `error` says where the parsing failed. Sent for the first
+    /// such line and then only for some, and, with `ProtocolViolation`,
+    /// just before the server closes a connection that sent too many.
+    Error {
+        code: ErrorCode,
+        error: String,
+    },
+    /// Sent on the sync connection to everyone in `room`/`doc` when a user
+    /// joins, leaves, is disconnected by the server or changes their name.
+    /// The user list itself follows in a `Members` notice.
+    UserEvent {
+        room: String,
+        doc: String,
+        kind: UserEventKind,
+        /// The user, under their new name for `Renamed`.
+        user: WireUser,
+        /// Why a user was kicked, or the old name of a renamed one.
+        detail: Option<String>,
+    },
+    /// Everyone in `room`/`doc`, sent to them at most once per 100 ms when
+    /// people joined, left or were renamed, if that changed the list.
+    /// `presence_seq` goes up by one with each; a client keeps the list
+    /// with the highest, counting the one its last sync had.
+    Members {
+        room: String,
+        doc: String,
+        users: Vec<WireUser>,
+        presence_seq: u64,
+    },
+    /// The whitespace policy and ACL of `room`/`doc`, sent to those joining
+    /// it if there is either and to everyone in it when one changes. Also
+    /// sent to the user whose join created the document from a template,
+    /// which is after their `Welcome`.
+    DocInfo {
+        room: String,
+        doc: String,
+        policy: WhitespacePolicy,
+        #[serde(default, skip_serializing_if = "Acl::is_open")]
+        acl: Acl,
+        /// The document was just created from the server's template.
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        seeded_from_template: bool,
+    },
+    /// An entry of `room`/`doc`'s activity feed, sent to everyone in it as
+    /// it happens. Those joining get the recent entries first.
+    Activity {
+        room: String,
+        doc: String,
+        entry: ActivityEntry,
+    },
+    /// Sent on the sync connection to everyone in `room` when something
+    /// happened to `doc` they should know about, e.g. that it grew past a
+    /// size the server warns about. `room` and `doc` are empty for one to
+    /// everyone on the server, like a new message of the day.
+    Notice {
+        room: String,
+        doc: String,
+        text: String,
+    },
+    /// `room`/`from` is now called `to`, e.g. rotated by the server. Its
+    /// users were moved along with it, under ids scoped to `to`, and take
+    /// it as their doc; `from` is a document of its own now.
+    DocRenamed {
+        room: String,
+        from: String,
+        to: String,
+    },
+    /// `room`'s settings as they are now saved.
+    RoomInfo {
+        room: String,
+        meta: RoomMeta,
+    },
+    /// Rooms with stored documents or users, sorted.
+    Rooms {
+        rooms: Vec<String>,
+    },
+    /// Documents of `room` that are stored or have users, sorted.
+    Docs {
+        room: String,
+        docs: Vec<String>,
+    },
+    /// A document's stored revisions, oldest first; empty if it has none
+    /// or can't be stored.
+    Revisions {
+        revisions: Vec<WireRevision>,
+    },
+    RevisionText {
+        version: u64,
+        text: String,
+    },
+    /// The document was restored from revision `from`; it is at `version`
+    /// now.
+    RestoreDone {
+        from: u64,
+        version: u64,
+    },
+    /// Documents in `room`'s trash, last deleted first.
+    Trash {
+        room: String,
+        docs: Vec<WireTrashed>,
+    },
+    /// A document came back from the trash as `room`/`doc`.
+    DocRestored {
+        room: String,
+        doc: String,
+    },
+}
+
+/// A document in a room's trash.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireTrashed {
+    pub name: String,
+    pub bytes: u64,
+    /// Unix time in milliseconds.
+    pub deleted_at_ms: u64,
+}
+
+/// A stored revision of a document.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WireRevision {
+    pub version: u64,
+    /// Unix time in milliseconds.
+    pub saved_at_ms: u64,
+    /// Who took the snapshot that saved it, if the server still knows;
+    /// revisions saved as the document was edited have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub by: Option<String>,
+}
+
+/// What a `ServerMessage::Error` is about.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorCode {
+    /// A line that isn't JSON, or not a message the server knows.
+    MalformedMessage,
+    /// Too many malformed lines; the connection is closed.
+    ProtocolViolation,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UserEventKind {
+    Joined,
+    Left,
+    Kicked,
+    Renamed,
+}
+
+impl UserEventKind {
+    /// The event as a line for people, e.g. "Bob was kicked (idle)".
+    pub fn describe(self, name: &str, detail: Option<&str>) -> String {
+        match (self, detail) {
+            (Self::Joined, _) => format!("{} joined", name),
+            (Self::Left, _) => format!("{} left", name),
+            (Self::Kicked, Some(why)) => format!("{} was kicked ({})", name, why),
+            (Self::Kicked, None) => format!("{} was kicked", name),
+            (Self::Renamed, Some(old)) => format!("{} is now {}", old, name),
+            (Self::Renamed, None) => format!("someone is now {}", name),
+        }
+    }
+}
+
+/// Something that happened to a document, as kept in its activity feed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ActivityEntry {
+    /// Counts the document's entries from 1, since the server loaded it.
+    pub seq: u64,
+    /// Unix time in milliseconds.
+    pub at_ms: u64,
+    /// Name of the user who did it; `server` for the server itself and
+    /// `admin` for requests on the health listener.
+    pub user: String,
+    pub kind: ActivityKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ActivityKind {
+    /// The document was saved with a revision at `version`.
+    Snapshot { version: u64 },
+    /// The stored document was corrupt and was restored from a revision
+    /// when it was loaded.
+    Restored,
+    /// A user turned the document back into revision `version`.
+    Reverted { version: u64 },
+    /// The whitespace policy was set.
+    Policy { policy: WhitespacePolicy },
+    /// A single edit deleted this many bytes.
+    Deleted { bytes: usize },
+    /// A `ServerMessage::UserEvent` about `user`.
+    User {
+        kind: UserEventKind,
+        detail: Option<String>,
+    },
+}
+
+impl ActivityEntry {
+    /// The entry as a line for people, e.g. "bob deleted 812 bytes".
+    pub fn describe(&self) -> String {
+        let user = &self.user;
+        match &self.kind {
+            ActivityKind::Snapshot { version } => {
+                format!("{} took a snapshot at v{}", user, version)
+            }
+            ActivityKind::Restored => {
+                format!("{} restored the document from its last good revision", user)
+            }
+            ActivityKind::Reverted { version } => {
+                format!("{} restored the document to v{}", user, version)
+            }
+            ActivityKind::Policy { policy } => {
+                format!("{} set the whitespace policy to {}", user, policy)
+            }
+            ActivityKind::Deleted { bytes } => format!("{} deleted {} bytes", user, bytes),
+            ActivityKind::User { kind, detail } => kind.describe(user, detail.as_deref()),
+        }
+    }
+}
+
+/// Operational limits of a server, announced in `ServerMessage::Welcome`.
+/// `None` is no limit. Every field is optional on the wire so that limits
+/// added later don't break older clients.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ServerLimits {
+    /// Longest document, in bytes of text.
+    pub max_doc_bytes: Option<usize>,
+    /// Longest line a client may send, in bytes without the newline.
+    pub max_message_bytes: Option<usize>,
+    /// Text edits a connection may send per second, in bursts of as many.
+    pub max_ops_per_second: Option<u32>,
+    /// Seconds a connection may stay silent before the server closes it.
+    pub idle_timeout_secs: Option<u64>,
+    /// Clients saying hello without a name aren't let into documents.
+    pub no_guests: bool,
+    /// Lines that aren't messages a connection may send before the server
+    /// closes it.
+    pub max_malformed: Option<u32>,
+}
+
+impl ServerLimits {
+    /// Why the server would reject `update` to a document `doc_bytes`
+    /// long, if it would.
+    pub fn check_update(&self, doc_bytes: usize, update: &Message) -> Result<(), String> {
+        if let Some(max) = self.max_message_bytes {
+            let len = serde_json::to_string(update).map_or(0, |line| line.len());
+            if len > max {
+                return Err(format!(
+                    "edit too large: {} bytes, the server takes at most {}",
+                    len, max
+                ));
+            }
+        }
+        match decode_update(update) {
+            Some((
+                _,
+                WireUpdate {
+                    op: Op::Insert { text, .. },
+                    ..
+                },
+                _,
+            )) => self.check_doc_size(doc_bytes, text.len()),
+            _ => Ok(()),
+        }
+    }
+
+    /// Why the server would refuse adding `added` bytes to a document
+    /// `doc_bytes` long, if it would.
+    pub fn check_doc_size(&self, doc_bytes: usize, added: usize) -> Result<(), String> {
+        let after = doc_bytes.saturating_add(added);
+        match self.max_doc_bytes {
+            Some(max) if added > 0 && after > max => Err(format!(
+                "document too large: {} bytes after this edit, the server takes at most {}",
+                after, max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Whether a document `doc_bytes` long is within 5% of `max_doc_bytes`.
+    pub fn near_doc_limit(&self, doc_bytes: usize) -> bool {
+        self.max_doc_bytes
+            .is_some_and(|max| doc_bytes.saturating_mul(20) >= max.saturating_mul(19))
+    }
+}
+
+/// Most bytes of text in one `DocChunk`.
+pub const EXPORT_CHUNK_LEN: usize = 64 * 1024;
+
 pub fn make_scoped_user_id(document_id: &str, user_id: &str) -> String {
     format!("{}|{}", document_id, user_id)
 }
@@ -121,6 +659,7 @@ mod tests {
             Op::Insert {
                 pos: 1,
                 text: "hi".to_string(),
+                pos_chars: None,
             },
             vec![1, 2, 3],
             5,
@@ -132,7 +671,7 @@ mod tests {
         assert_eq!(payload.user_id, "room/doc.txt|user-1");
         assert_eq!(payload.delta, vec![1, 2, 3]);
         match payload.op {
-            Op::Insert { pos, text } => {
+            Op::Insert { pos, text, .. } => {
                 assert_eq!(pos, 1);
                 assert_eq!(text, "hi");
             }
@@ -145,13 +684,97 @@ mod tests {
         let users = vec![WireUser {
             id: "room/doc.txt|user-1".to_string(),
             name: "Alice".to_string(),
+            guest: false,
         }];
-        let msg = encode_sync_response("room/doc.txt", "hello", users, 2).expect("encode");
+        let msg = encode_sync_response("room/doc.txt", "hello", users, 5, 2).expect("encode");
         let (doc_id, payload, version) = decode_sync_response(&msg).expect("decode");
         assert_eq!(doc_id, "room/doc.txt");
         assert_eq!(version, 2);
+        assert_eq!(payload.presence_seq, 5);
         assert_eq!(payload.text, "hello");
         assert_eq!(payload.users.len(), 1);
         assert_eq!(payload.users[0].name, "Alice");
+        assert_eq!(payload.error, None);
+
+        let msg = encode_sync_error("room/..", "'..' is not a valid name").expect("encode");
+        let (_, payload, _) = decode_sync_response(&msg).expect("decode");
+        assert_eq!(payload.error.as_deref(), Some("'..' is not a valid name"));
+        assert!(payload.text.is_empty() && payload.users.is_empty());
+    }
+
+    #[test]
+    fn welcome_limits_tolerate_missing_and_unknown_fields() {
+        let welcome: ServerMessage = serde_json::from_str(r#"{"Welcome":{}}"#).expect("decode");
+        assert_eq!(
+            welcome,
+            ServerMessage::Welcome {
+                limits: ServerLimits::default(),
+                name: None,
+                doc: None,
+                redirected_from: None,
+                access: None,
+                motd: None,
+            }
+        );
+        let newer = r#"{"Welcome":{"limits":{"max_doc_bytes":100,"max_cursors":3}}}"#;
+        let Ok(ServerMessage::Welcome { limits, .. }) = serde_json::from_str(newer) else {
+            panic!("newer welcome not decoded");
+        };
+        assert_eq!(limits.max_doc_bytes, Some(100));
+        assert_eq!(limits.idle_timeout_secs, None);
+        // Clients that predate it skip the line like any non-sync message.
+        let welcome = ServerMessage::Welcome {
+            limits,
+            name: None,
+            doc: None,
+            redirected_from: None,
+            access: Some(Access::Read),
+            motd: Some("maintenance Friday".into()),
+        };
+        let line = serde_json::to_string(&welcome).expect("encode");
+        assert!(serde_json::from_str::<Message>(&line).is_err());
+    }
+
+    #[test]
+    fn limits_check_inserts_against_doc_and_message_size() {
+        let insert = |text: &str| {
+            let op = Op::Insert {
+                pos: 0,
+                text: text.to_string(),
+                pos_chars: None,
+            };
+            encode_update("room/doc.txt", "room/doc.txt|ada", op, Vec::new(), 1).expect("encode")
+        };
+        let limits = ServerLimits {
+            max_doc_bytes: Some(100),
+            ..ServerLimits::default()
+        };
+        assert!(limits.check_update(95, &insert("hello")).is_ok());
+        assert!(limits.check_update(96, &insert("hello")).is_err());
+        let delete = encode_update(
+            "room/doc.txt",
+            "room/doc.txt|ada",
+            Op::Delete {
+                pos: 0,
+                len: 5,
+                pos_chars: None,
+                len_chars: None,
+            },
+            Vec::new(),
+            1,
+        )
+        .expect("encode");
+        assert!(limits.check_update(500, &delete).is_ok());
+        assert!(!limits.near_doc_limit(94));
+        assert!(limits.near_doc_limit(95));
+        assert!(!ServerLimits::default().near_doc_limit(usize::MAX));
+
+        let line_len = serde_json::to_string(&insert("hi")).expect("encode").len();
+        let limits = ServerLimits {
+            max_message_bytes: Some(line_len),
+            ..ServerLimits::default()
+        };
+        assert!(limits.check_update(0, &insert("hi")).is_ok());
+        assert!(limits.check_update(0, &insert("hi!")).is_err());
     }
 }
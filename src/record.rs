@@ -0,0 +1,535 @@
+//! Session recordings for bug reports and demos. `--record <path>` puts a
+//! tap between a client and its socket, like the relay of
+//! `--simulate-latency`, that appends every line sent or received to a
+//! JSONL file with the milliseconds since the client started. Values of
+//! fields named like secrets are replaced before anything is written.
+//!
+//! `playback` reads such a file back: either it shows the documents change
+//! as the server told the client, or it sends what the client sent to
+//! another server, one connection for each recorded one, to reproduce a
+//! problem there.
+
+use crate::client::netsim::{Reader, Writer};
+use crate::protocol::{Op, decode_sync_response, decode_update, encode_sync_request};
+use crate::snapshot;
+use crossterm::cursor::MoveTo;
+use crossterm::queue;
+use crossterm::terminal::{self, Clear, ClearType};
+use mdcs_sdk::Message;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::{BTreeMap, HashMap, hash_map};
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc;
+use tokio::time::Instant;
+
+/// Buffer of the in-memory pipes between the client and the tap.
+const PIPE_LEN: usize = 64 * 1024;
+/// Fields whose name contains one of these have their value replaced.
+const SECRET_FIELDS: &[&str] = &["token", "password", "secret", "credential", "authorization"];
+pub const REDACTED: &str = "<redacted>";
+/// How long playback against a server waits for its answer to a sync.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Which way a recorded line went.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// A line of a recording.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    /// When the client started recording, in milliseconds since the Unix
+    /// epoch: several runs can append to the same file.
+    pub session: u64,
+    /// Milliseconds since then.
+    pub ms: u64,
+    /// The connection, counted from 0 in each session; the TUI opens one
+    /// for each tab and each reconnect.
+    pub conn: u32,
+    pub dir: Direction,
+    /// The message, or the line as a string if it wasn't JSON.
+    pub msg: Value,
+}
+
+/// Appends the lines of a client's connections to a recording.
+#[derive(Debug)]
+pub struct Recorder {
+    file: Mutex<File>,
+    session: u64,
+    started: Instant,
+    conns: AtomicU32,
+}
+
+impl Recorder {
+    /// Records into `path`, after anything it holds already.
+    pub fn create(path: &Path) -> io::Result<Arc<Self>> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let session = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        Ok(Arc::new(Self {
+            file: Mutex::new(file),
+            session,
+            started: Instant::now(),
+            conns: AtomicU32::new(0),
+        }))
+    }
+
+    /// Puts a tap recording each line between the client and `reader` and
+    /// `writer`, a connection's halves.
+    pub(crate) fn tap(self: &Arc<Self>, reader: Reader, writer: Writer) -> (Reader, Writer) {
+        let conn = self.conns.fetch_add(1, Ordering::Relaxed);
+        let (incoming, tap_in) = tokio::io::duplex(PIPE_LEN);
+        let (outgoing, tap_out) = tokio::io::duplex(PIPE_LEN);
+        let recorder = Arc::clone(self);
+        tokio::spawn(async move { recorder.copy(tap_out, writer, conn, Direction::Sent).await });
+        let recorder = Arc::clone(self);
+        tokio::spawn(async move {
+            recorder
+                .copy(reader, tap_in, conn, Direction::Received)
+                .await
+        });
+        (Box::new(incoming), Box::new(outgoing))
+    }
+
+    /// Copies lines from `from` to `to`, recording each, until either side
+    /// is closed.
+    async fn copy(
+        &self,
+        from: impl AsyncRead + Unpin,
+        mut to: impl AsyncWrite + Unpin,
+        conn: u32,
+        dir: Direction,
+    ) {
+        let mut from = BufReader::new(from);
+        loop {
+            let mut line = Vec::new();
+            match from.read_until(b'\n', &mut line).await {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {}
+            }
+            self.record(conn, dir, &line);
+            if to.write_all(&line).await.is_err() || to.flush().await.is_err() {
+                return;
+            }
+        }
+        let _ = to.shutdown().await;
+    }
+
+    /// Appends `line` to the file. A recording that can't be written only
+    /// misses lines; the session goes on.
+    fn record(&self, conn: u32, dir: Direction, line: &[u8]) {
+        let line = String::from_utf8_lossy(line);
+        let line = line.trim_end();
+        let mut msg =
+            serde_json::from_str(line).unwrap_or_else(|_| Value::String(line.to_string()));
+        redact(&mut msg);
+        let entry = Entry {
+            session: self.session,
+            ms: self.started.elapsed().as_millis() as u64,
+            conn,
+            dir,
+            msg,
+        };
+        let Ok(mut json) = serde_json::to_string(&entry) else {
+            return;
+        };
+        json.push('\n');
+        let mut file = self.file.lock().unwrap_or_else(PoisonError::into_inner);
+        let _ = file.write_all(json.as_bytes());
+    }
+}
+
+/// Replaces the values of fields named like secrets, at any depth.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Object(fields) => {
+            for (name, value) in fields.iter_mut() {
+                let name = name.to_lowercase();
+                if SECRET_FIELDS.iter().any(|secret| name.contains(secret)) {
+                    *value = Value::String(REDACTED.to_string());
+                } else {
+                    redact(value);
+                }
+            }
+        }
+        Value::Array(values) => values.iter_mut().for_each(redact),
+        _ => {}
+    }
+}
+
+/// Reads the recording at `path`.
+pub fn load(path: &Path) -> io::Result<Vec<Entry>> {
+    let text = std::fs::read_to_string(path)?;
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(index, line)| {
+            serde_json::from_str(line).map_err(|err| {
+                let at = format!("{}:{}: {}", path.display(), index + 1, err);
+                io::Error::new(io::ErrorKind::InvalidData, at)
+            })
+        })
+        .collect()
+}
+
+/// A document as it was after a sync or update the client received.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    pub session: u64,
+    pub ms: u64,
+    pub doc_id: String,
+    pub version: u64,
+    /// Who made the edit; `None` for a sync.
+    pub user_id: Option<String>,
+    pub text: String,
+}
+
+/// The documents of a recording as they changed, from the syncs and
+/// updates the server sent, which include the client's own edits.
+pub fn changes(entries: &[Entry]) -> impl Iterator<Item = Change> + '_ {
+    let mut texts: HashMap<String, String> = HashMap::new();
+    let received = entries
+        .iter()
+        .filter(|entry| entry.dir == Direction::Received);
+    received.filter_map(move |entry| {
+        let msg = serde_json::from_value::<Message>(entry.msg.clone()).ok()?;
+        let (doc_id, user_id, version) =
+            if let Some((doc_id, sync, version)) = decode_sync_response(&msg) {
+                if sync.error.is_some() {
+                    return None;
+                }
+                texts.insert(doc_id.clone(), sync.text);
+                (doc_id, None, version)
+            } else {
+                let (doc_id, update, version) = decode_update(&msg)?;
+                if matches!(update.op, Op::Cursor { .. } | Op::Selection { .. }) {
+                    return None;
+                }
+                let text = texts.entry(doc_id.clone()).or_default();
+                snapshot::apply_to_text(text, &update.op);
+                (doc_id, Some(update.user_id), version)
+            };
+        Some(Change {
+            session: entry.session,
+            ms: entry.ms,
+            text: texts[&doc_id].clone(),
+            doc_id,
+            version,
+            user_id,
+        })
+    })
+}
+
+/// The text each document of the recording ended with.
+pub fn final_texts(entries: &[Entry]) -> BTreeMap<String, String> {
+    changes(entries)
+        .map(|change| (change.doc_id, change.text))
+        .collect()
+}
+
+/// Waits until `ms` into `session`, `speed` times as fast as recorded.
+/// Each session starts right away.
+struct Pace {
+    speed: f64,
+    session: Option<(u64, u64, Instant)>,
+}
+
+impl Pace {
+    fn new(speed: f64) -> Self {
+        Self {
+            speed: if speed > 0.0 { speed } else { 1.0 },
+            session: None,
+        }
+    }
+
+    async fn wait(&mut self, session: u64, ms: u64) {
+        let (_, first_ms, started) = match self.session {
+            Some(current @ (id, ..)) if id == session => current,
+            _ => *self.session.insert((session, ms, Instant::now())),
+        };
+        let offset = ms.saturating_sub(first_ms) as f64 / self.speed;
+        tokio::time::sleep_until(started + Duration::from_secs_f64(offset / 1000.0)).await;
+    }
+}
+
+/// Shows the documents of the recording change at the pace they did,
+/// `speed` times as fast: each change is drawn over the last on a terminal,
+/// and only the final texts are printed otherwise.
+pub async fn play(entries: &[Entry], speed: f64) -> io::Result<()> {
+    let mut stdout = io::stdout();
+    if !stdout.is_terminal() {
+        for (doc_id, text) in final_texts(entries) {
+            writeln!(stdout, "[playback] {} at the end:", doc_id)?;
+            writeln!(stdout, "{}", text)?;
+        }
+        return Ok(());
+    }
+    let mut pace = Pace::new(speed);
+    let mut count = 0;
+    for change in changes(entries) {
+        pace.wait(change.session, change.ms).await;
+        count += 1;
+        let (cols, rows) = terminal::size().unwrap_or((80, 24));
+        queue!(stdout, Clear(ClearType::All), MoveTo(0, 0))?;
+        let by = change.user_id.as_deref().unwrap_or("sync");
+        let header = format!(
+            "[playback] {:.1}s {} v{} by {} (change {})",
+            change.ms as f64 / 1000.0,
+            change.doc_id,
+            change.version,
+            by,
+            count
+        );
+        writeln!(
+            stdout,
+            "{}",
+            header.chars().take(cols as usize).collect::<String>()
+        )?;
+        for line in change.text.lines().take(rows.saturating_sub(2) as usize) {
+            writeln!(
+                stdout,
+                "{}",
+                line.chars().take(cols as usize).collect::<String>()
+            )?;
+        }
+        stdout.flush()?;
+    }
+    writeln!(stdout, "[playback] {} changes", count)?;
+    Ok(())
+}
+
+/// A recorded connection opened again against another server.
+struct Replayed {
+    writer: tokio::net::tcp::OwnedWriteHalf,
+    lines: mpsc::UnboundedReceiver<String>,
+    /// The document it last synced or edited.
+    doc_id: Option<String>,
+    /// Syncs asked for, so the answer to the last can be told apart.
+    syncs: usize,
+}
+
+/// Sends what the client sent to the server at `addr`, at the pace it did
+/// (`speed` times as fast), on a connection for each recorded one. Returns
+/// the text of the document each connection was on, as the server has it
+/// after the connection's last message.
+pub async fn send_against(
+    entries: &[Entry],
+    addr: &str,
+    speed: f64,
+) -> io::Result<BTreeMap<String, String>> {
+    let mut conns: HashMap<(u64, u32), Replayed> = HashMap::new();
+    let mut order = Vec::new();
+    let mut pace = Pace::new(speed);
+    for entry in entries.iter().filter(|entry| entry.dir == Direction::Sent) {
+        pace.wait(entry.session, entry.ms).await;
+        let key = (entry.session, entry.conn);
+        let conn = match conns.entry(key) {
+            hash_map::Entry::Occupied(conn) => conn.into_mut(),
+            hash_map::Entry::Vacant(slot) => {
+                order.push(key);
+                slot.insert(open(addr).await?)
+            }
+        };
+        if let Ok(msg) = serde_json::from_value::<Message>(entry.msg.clone()) {
+            match msg {
+                Message::SyncRequest { document_id, .. } => {
+                    conn.doc_id = Some(document_id);
+                    conn.syncs += 1;
+                }
+                Message::Update { document_id, .. } => conn.doc_id = Some(document_id),
+                _ => {}
+            }
+        }
+        let mut line = match &entry.msg {
+            Value::String(line) => line.clone(),
+            msg => msg.to_string(),
+        };
+        line.push('\n');
+        conn.writer.write_all(line.as_bytes()).await?;
+    }
+
+    let mut texts = BTreeMap::new();
+    for key in order {
+        let mut conn = conns.remove(&key).expect("in order");
+        let Some(doc_id) = conn.doc_id.take() else {
+            continue;
+        };
+        let mut line = serde_json::to_string(&encode_sync_request(&doc_id, 0))?;
+        line.push('\n');
+        conn.writer.write_all(line.as_bytes()).await?;
+        conn.syncs += 1;
+        let mut text = None;
+        while conn.syncs > 0 {
+            let line = tokio::time::timeout(SYNC_TIMEOUT, conn.lines.recv())
+                .await
+                .ok()
+                .flatten()
+                .ok_or_else(|| {
+                    let err = format!("{} didn't answer the sync of {}", addr, doc_id);
+                    io::Error::new(io::ErrorKind::TimedOut, err)
+                })?;
+            let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                continue;
+            };
+            if let Some((synced, sync, _)) = decode_sync_response(&msg) {
+                conn.syncs -= 1;
+                if synced == doc_id {
+                    text = Some(sync.text);
+                }
+            }
+        }
+        if let Some(text) = text {
+            texts.insert(doc_id, text);
+        }
+    }
+    Ok(texts)
+}
+
+/// Connects to `addr`, reading what the server sends into a channel so it
+/// never waits on us.
+async fn open(addr: &str) -> io::Result<Replayed> {
+    let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+    let (tx, lines) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        let mut reader = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = reader.next_line().await {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Replayed {
+        writer,
+        lines,
+        doc_id: None,
+        syncs: 0,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::netsim::{self, NetConditions};
+    use crate::protocol::{encode_update, make_scoped_user_id};
+    use crate::testing::TestServer;
+    use serde_json::json;
+    use tokio::io::Lines;
+
+    /// A client talking through a recorder's tap, as `--record` sets up.
+    struct Recorded {
+        lines: Lines<BufReader<Reader>>,
+        writer: Writer,
+        user_id: String,
+        version: u64,
+    }
+
+    impl Recorded {
+        async fn send(&mut self, msg: &Message) {
+            let mut line = serde_json::to_string(msg).unwrap();
+            line.push('\n');
+            self.writer.write_all(line.as_bytes()).await.unwrap();
+            self.writer.flush().await.unwrap();
+        }
+
+        /// Sends `op` and reads until the server sends it back.
+        async fn edit(&mut self, op: Op) {
+            let update = encode_update("team/notes", &self.user_id, op, Vec::new(), self.version);
+            self.send(&update.unwrap()).await;
+            loop {
+                let line = self.lines.next_line().await.unwrap().unwrap();
+                // Server messages like `Welcome` aren't sync traffic.
+                let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                    continue;
+                };
+                if let Some((_, update, version)) = decode_update(&msg) {
+                    self.version = version;
+                    if update.user_id == self.user_id {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn a_recorded_session_plays_back_to_the_same_text() {
+        let server = TestServer::spawn().await.unwrap();
+        let mut watcher = server.connect("bob", "team", "notes").await.unwrap();
+        let path = std::env::temp_dir().join(format!("carnelia-record-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let recorder = Recorder::create(&path).unwrap();
+
+        let addr = server.addr.to_string();
+        let (reader, writer) = netsim::connect(&addr, &NetConditions::default())
+            .await
+            .unwrap();
+        let (reader, writer) = recorder.tap(reader, writer);
+        let mut client = Recorded {
+            lines: BufReader::new(reader).lines(),
+            writer,
+            user_id: make_scoped_user_id("team/notes", "ada"),
+            version: 0,
+        };
+        let hello = Message::Hello {
+            replica_id: client.user_id.clone(),
+            user_name: "ada".to_string(),
+        };
+        client.send(&hello).await;
+        client.send(&encode_sync_request("team/notes", 0)).await;
+        let insert = |pos, text: &str| Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        client.edit(insert(0, "hello wörld")).await;
+        let delete = Op::Delete {
+            pos: 0,
+            len: 1,
+            pos_chars: None,
+            len_chars: None,
+        };
+        client.edit(delete).await;
+        client.edit(insert(0, "H")).await;
+        client.edit(insert(12, "!")).await;
+        watcher.wait_for_text("Hello wörld!").await.unwrap();
+
+        let entries = load(&path).unwrap();
+        assert!(entries.iter().all(|entry| entry.conn == 0));
+        assert!(entries.iter().any(|entry| entry.dir == Direction::Sent));
+        let expected = BTreeMap::from([("team/notes".to_string(), watcher.text())]);
+        assert_eq!(final_texts(&entries), expected);
+
+        let fresh = TestServer::spawn().await.unwrap();
+        let replayed = send_against(&entries, &fresh.addr.to_string(), 100.0).await;
+        assert_eq!(replayed.unwrap(), expected);
+
+        for server in [server, fresh] {
+            let _ = std::fs::remove_dir_all(server.shutdown().await.unwrap());
+        }
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn secrets_are_redacted_at_any_depth() {
+        let mut msg = json!({
+            "Login": {"user": "ada", "Token": "hunter2", "auth": [{"password": "x"}]}
+        });
+        redact(&mut msg);
+        assert_eq!(
+            msg,
+            json!({"Login": {"user": "ada", "Token": REDACTED, "auth": [{"password": REDACTED}]}})
+        );
+    }
+}
@@ -0,0 +1,208 @@
+//! Search and replace over the local text, shared by the simple client's
+//! `/sed` and the TUI's `:s`. All matches are found first and then turned
+//! into edits applied last first, so the positions of the matches before
+//! each one are still right when it is replaced.
+
+mod regex;
+
+use crate::protocol::Op;
+pub(crate) use regex::Regex;
+
+/// What is searched for: text as it is, or a regex with `--regex`.
+#[derive(Debug)]
+pub(crate) enum Pattern {
+    Literal(String),
+    Regex(Regex),
+}
+
+impl Pattern {
+    pub(crate) fn new(pattern: &str, regex: bool) -> Result<Self, String> {
+        if pattern.is_empty() {
+            return Err("empty pattern".to_string());
+        }
+        if regex {
+            Regex::new(pattern).map(Pattern::Regex)
+        } else {
+            Ok(Pattern::Literal(pattern.to_string()))
+        }
+    }
+}
+
+/// The match at `start..end` and what it becomes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Replacement {
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+    pub(crate) with: String,
+}
+
+/// The matches of `pattern` in `text`, left to right and not overlapping,
+/// each with `replacement` (with `$1` and `${name}` filled in for a regex).
+/// Unless `every`, only the first match of each line is kept.
+pub(crate) fn find(
+    text: &str,
+    pattern: &Pattern,
+    replacement: &str,
+    every: bool,
+) -> Vec<Replacement> {
+    let mut found: Vec<Replacement> = Vec::new();
+    let mut from = 0;
+    while from <= text.len() {
+        let (start, end, with) = match pattern {
+            Pattern::Literal(needle) => match text[from..].find(needle.as_str()) {
+                Some(at) => (from + at, from + at + needle.len(), replacement.to_string()),
+                None => break,
+            },
+            Pattern::Regex(regex) => match regex.find_at(text, from) {
+                Some(groups) => {
+                    let (start, end) = groups[0].unwrap_or_default();
+                    (start, end, regex.expand(text, &groups, replacement))
+                }
+                None => break,
+            },
+        };
+        let past = end + text[end..].chars().next().map_or(1, char::len_utf8);
+        // An empty match right where the last one ended doesn't count.
+        if start == end && found.last().is_some_and(|last| last.end == start) {
+            from = past;
+            continue;
+        }
+        found.push(Replacement { start, end, with });
+        from = if !every {
+            next_line(text, start, end)
+        } else if start == end {
+            past
+        } else {
+            end
+        };
+    }
+    found
+}
+
+/// Where the line after the one of the match at `start..end` starts.
+fn next_line(text: &str, start: usize, end: usize) -> usize {
+    if end > start && text[..end].ends_with('\n') {
+        return end;
+    }
+    match text[end..].find('\n') {
+        Some(at) => end + at + 1,
+        None => text.len() + 1,
+    }
+}
+
+/// The ops making `replacements`, last first: a delete of each match and
+/// an insert of what replaces it at the same place.
+pub(crate) fn to_ops(replacements: &[Replacement]) -> Vec<Op> {
+    let mut ops = Vec::new();
+    for replacement in replacements.iter().rev() {
+        if replacement.end > replacement.start {
+            ops.push(Op::Delete {
+                pos: replacement.start,
+                len: replacement.end - replacement.start,
+                pos_chars: None,
+                len_chars: None,
+            });
+        }
+        if !replacement.with.is_empty() {
+            ops.push(Op::Insert {
+                pos: replacement.start,
+                text: replacement.with.clone(),
+                pos_chars: None,
+            });
+        }
+    }
+    ops
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot;
+
+    fn replace(
+        text: &str,
+        pattern: &str,
+        regex: bool,
+        with: &str,
+    ) -> (Vec<(usize, usize)>, String) {
+        let pattern = Pattern::new(pattern, regex).unwrap();
+        let found = find(text, &pattern, with, true);
+        let mut after = text.to_string();
+        for op in to_ops(&found) {
+            snapshot::apply_to_text(&mut after, &op);
+        }
+        let ranges = found.iter().map(|found| (found.start, found.end)).collect();
+        (ranges, after)
+    }
+
+    #[test]
+    fn overlapping_matches_are_taken_left_to_right() {
+        assert_eq!(
+            replace("aaaa", "aa", false, "b"),
+            (vec![(0, 2), (2, 4)], "bb".into())
+        );
+        assert_eq!(
+            replace("aaa", "aa", false, "b"),
+            (vec![(0, 2)], "ba".into())
+        );
+        assert_eq!(
+            replace("abab", "aba", false, "x"),
+            (vec![(0, 3)], "xb".into())
+        );
+        // A replacement containing the pattern isn't searched again.
+        assert_eq!(replace("a a", "a", false, "aa").1, "aa aa");
+        assert!(Pattern::new("", false).is_err());
+    }
+
+    #[test]
+    fn multi_byte_text_is_matched_by_bytes_on_char_boundaries() {
+        let (ranges, after) = replace("héllo wörld héllo", "héllo", false, "hi");
+        assert_eq!(ranges, [(0, 6), (14, 20)]);
+        assert_eq!(after, "hi wörld hi");
+
+        let (ranges, after) = replace("naïve café", "é|ï", true, "e");
+        assert_eq!(ranges, [(2, 4), (10, 12)]);
+        assert_eq!(after, "naeve cafe");
+        // Empty matches fall between characters, never inside one.
+        assert_eq!(replace("éa", "x*", true, "-").1, "-é-a-");
+    }
+
+    #[test]
+    fn ops_go_last_first_so_earlier_positions_hold() {
+        let pattern = Pattern::new("cat", false).unwrap();
+        let found = find("cat dog cat", &pattern, "tiger", true);
+        let ops = to_ops(&found);
+        let positions: Vec<usize> = ops
+            .iter()
+            .map(|op| match op {
+                Op::Insert { pos, .. } | Op::Delete { pos, .. } => *pos,
+                _ => unreachable!(),
+            })
+            .collect();
+        assert_eq!(positions, [8, 8, 0, 0]);
+        assert!(matches!(ops[0], Op::Delete { len: 3, .. }));
+        assert_eq!(
+            replace("cat dog cat", "cat", false, "tiger").1,
+            "tiger dog tiger"
+        );
+        // Growing, shrinking and deleting all land where they should.
+        assert_eq!(replace("a-b-c", "-", false, "").1, "abc");
+        assert_eq!(replace("xyxyx", "y", false, "***").1, "x***x***x");
+    }
+
+    #[test]
+    fn regexes_fill_in_groups_and_can_stop_at_one_per_line() {
+        let (_, after) = replace("ada=1, bob=22", r"(\w+)=(?P<n>\d+)", true, "${n}:$1 $$");
+        assert_eq!(after, "1:ada $, 22:bob $");
+        assert_eq!(replace("one two", r"\b", true, "|").1, "|one| |two|");
+        assert_eq!(replace("a1b22", r"\d+?", true, "#").1, "a#b##");
+
+        let pattern = Pattern::new("o", false).unwrap();
+        let found = find("foo\nboo\n", &pattern, "0", false);
+        assert_eq!(
+            found.iter().map(|found| found.start).collect::<Vec<_>>(),
+            [1, 5]
+        );
+        assert!(Pattern::new("(", true).is_err());
+    }
+}
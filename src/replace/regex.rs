@@ -0,0 +1,113 @@
+//! `--regex` patterns, matched by the `regex` crate: leftmost-first, in
+//! time linear in the text, so no pattern can hang the client.
+
+/// Where each group matched, the whole match first.
+pub(crate) type Groups = Vec<Option<(usize, usize)>>;
+
+#[derive(Debug)]
+pub(crate) struct Regex {
+    regex: ::regex::Regex,
+}
+
+impl Regex {
+    pub(crate) fn new(pattern: &str) -> Result<Self, String> {
+        let regex = ::regex::Regex::new(pattern).map_err(|err| match &err {
+            // The message ends with what is wrong, after the pattern and a
+            // caret under the spot.
+            ::regex::Error::Syntax(message) => {
+                let mut why = message
+                    .lines()
+                    .filter_map(|line| line.strip_prefix("error: "));
+                format!("bad regex: {}", why.next_back().unwrap_or(message))
+            }
+            err => format!("bad regex: {}", err),
+        })?;
+        Ok(Self { regex })
+    }
+
+    /// The leftmost match starting at or after `from`.
+    pub(crate) fn find_at(&self, text: &str, from: usize) -> Option<Groups> {
+        let captures = self.regex.captures_at(text, from)?;
+        let groups = captures
+            .iter()
+            .map(|group| group.map(|group| (group.start(), group.end())))
+            .collect();
+        Some(groups)
+    }
+
+    /// `replacement` with `$1`, `${1}` and `${name}` replaced by what those
+    /// groups matched (nothing if they didn't) and `$$` by `$`.
+    pub(crate) fn expand(&self, text: &str, groups: &Groups, replacement: &str) -> String {
+        let mut out = String::new();
+        let mut rest = replacement;
+        while let Some(at) = rest.find('$') {
+            out.push_str(&rest[..at]);
+            rest = &rest[at + 1..];
+            let (group, after) = if let Some(braced) = rest.strip_prefix('{')
+                && let Some((group, after)) = braced.split_once('}')
+            {
+                (group, after)
+            } else if rest.starts_with('$') {
+                out.push('$');
+                rest = &rest[1..];
+                continue;
+            } else {
+                let digits = rest.len()
+                    - rest
+                        .trim_start_matches(|ch: char| ch.is_ascii_digit())
+                        .len();
+                if digits == 0 {
+                    out.push('$');
+                    continue;
+                }
+                rest.split_at(digits)
+            };
+            let index = match group.parse::<usize>() {
+                Ok(index) => Some(index),
+                Err(_) => self
+                    .regex
+                    .capture_names()
+                    .position(|name| name == Some(group)),
+            };
+            if let Some(Some((start, end))) = index.and_then(|index| groups.get(index)) {
+                out.push_str(&text[*start..*end]);
+            }
+            rest = after;
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(pattern: &str, text: &str) -> Option<(usize, usize)> {
+        let groups = Regex::new(pattern).unwrap().find_at(text, 0)?;
+        groups[0]
+    }
+
+    #[test]
+    fn matches_are_leftmost_first() {
+        assert_eq!(find("b+", "abbbc"), Some((1, 4)));
+        assert_eq!(find("b+?", "abbbc"), Some((1, 2)));
+        assert_eq!(find("a|ab", "ab"), Some((0, 1)));
+        assert_eq!(find("x{2,3}", "xxxxx"), Some((0, 3)));
+        assert_eq!(find(r"(?m)^b$", "a\nb\nc"), Some((2, 3)));
+        assert_eq!(find(r"(?i)ÉTÉ", "un été"), Some((3, 8)));
+        assert_eq!(find(r"\bbar\b", "foobar bar"), Some((7, 10)));
+        assert_eq!(find("(a*)*b", "aaab"), Some((0, 4)));
+        assert_eq!(find("[0-9]", "abc"), None);
+        assert_eq!(Regex::new("(a").unwrap_err(), "bad regex: unclosed group");
+    }
+
+    #[test]
+    fn long_matches_and_nested_repetitions_finish() {
+        let long = "x".repeat(100_000);
+        assert_eq!(find("x*", &long), Some((0, long.len())));
+        assert_eq!(find(".*", &long), Some((0, long.len())));
+        let text = "a".repeat(40);
+        assert_eq!(find("(a*)*(a*)*(a*)*c", &text), None);
+    }
+}
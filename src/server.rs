@@ -1,19 +1,246 @@
+/// `println!` for what `--quiet` leaves out: anything but startup,
+/// shutdown and errors.
+macro_rules! info {
+    ($($arg:tt)*) => {
+        if !$crate::server::logging::quiet() {
+            println!($($arg)*);
+        }
+    };
+}
+
+mod growth;
+mod guests;
+mod http;
+mod limits;
+mod logging;
+mod motd;
+mod names;
+mod outbound;
+mod overview;
+mod persistence;
+mod presence;
+pub(crate) mod templates;
+
+use crate::export::ExportedDoc;
+use crate::position::TextIndex;
 use crate::protocol::{
-    Op, WireUser, decode_update, doc_id_from_scoped_user_id, encode_sync_response, encode_update,
+    ActivityEntry, ActivityKind, ClientMessage, ErrorCode, Op, ServerLimits, ServerMessage,
+    Subscription, UserEventKind, WireRevision, WireTrashed, WireUser, decode_update,
+    doc_id_from_scoped_user_id, encode_sync_error, encode_sync_response, encode_update,
+    make_scoped_user_id,
+};
+use crate::snapshot;
+use crate::storage::{
+    Access, Acl, BackendKind, DataDirLock, DocMeta, DocNames, Encryption, ExternalChange,
+    FsOptions, HistoryPolicy, MemoryStorage, RoomMeta, Storage, StorageBackend, StorageStats,
+    SyncPolicy, Threshold, UserStats, WhitespacePolicy, is_corrupt,
 };
-use crate::storage::Storage;
+use crate::textpos::build_doc;
+use limits::{ConnectionLimits, Malformed};
 use mdcs_sdk::{Message, TextDoc};
-use std::collections::HashMap;
+use notify::Watcher as _;
+use outbound::OutboundStats;
+use persistence::{PersistenceManager, SnapshotError};
+use presence::Presence;
+use serde::Serialize;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
 use std::error::Error;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream};
-use tokio::sync::{Mutex, broadcast, mpsc};
+use tokio::sync::{Mutex, MutexGuard, Notify, broadcast, mpsc};
+
+pub use growth::GrowthPolicy;
+pub use logging::set_quiet;
+pub use motd::MotdOptions;
+
+/// Edits read from document files changed on disk are made as this user.
+const DISK_USER: &str = "server";
+/// Who requests on the health listener are in the activity feed.
+const ADMIN_USER: &str = "admin";
+/// Entries of a document's activity feed kept for those joining it.
+const ACTIVITY_LEN: usize = 50;
+/// A single edit deleting more than this is in the activity feed.
+const BIG_DELETE_BYTES: usize = 500;
+/// File events are handled once they pause this long, so a burst of
+/// writes is reloaded once.
+const WATCH_DEBOUNCE: Duration = Duration::from_millis(200);
+/// How often deleted documents past `--trash-retention-days` are purged.
+const TRASH_SWEEP_EVERY: Duration = Duration::from_secs(60 * 60);
 
 struct DocState {
     doc: TextDoc,
     version: u64,
     cursors: HashMap<String, usize>,
+    /// Version and time of the last stored revision.
+    last_revision: (u64, Instant),
+    /// Saved alongside the text; `version` is mirrored into it on save.
+    meta: DocMeta,
+    /// When the edits of the last `overview::OPS_WINDOW` were applied,
+    /// oldest first.
+    recent_ops: VecDeque<Instant>,
+    /// The last `ACTIVITY_LEN` entries of the activity feed, oldest first.
+    activity: VecDeque<ActivityEntry>,
+    /// Entries recorded since the document was loaded.
+    activity_seq: u64,
+    /// `doc`'s text, edited along with it, to place ops and cursors
+    /// without copying the text out of the CRDT.
+    text: TextIndex,
+    /// Started from a template; the user whose join created it is told.
+    seeded: bool,
+    /// Its users as last announced to them.
+    presence: Presence,
+    /// Users moved along when the document it replaces was rotated, by
+    /// id, and where to. Their edits still naming this one are refused
+    /// until they join it.
+    rotated_users: HashMap<String, String>,
+}
+
+impl DocState {
+    /// Loads the document from storage, resuming its version counter. A
+    /// corrupt document is restored from its newest revision if possible;
+    /// otherwise it isn't served at all, rather than as empty text that
+    /// the next edit would save over it. One that was never saved starts
+    /// from its template in `template_dir`, if there is one, and is saved
+    /// right away.
+    async fn load(
+        storage: &dyn StorageBackend,
+        room: &str,
+        doc: &str,
+        template_dir: Option<&Path>,
+    ) -> io::Result<Self> {
+        let doc_key = doc_key(room, doc);
+        let (stored, restored) = match storage.load(room, doc).await {
+            Err(err) if is_corrupt(&err) => {
+                println!("[storage] {}; restoring {}", err, doc_key);
+                (storage.recover(room, doc).await?, true)
+            }
+            result => (result?, false),
+        };
+        if let Some(warning) = &stored.warning {
+            println!("[storage] {} ({})", warning, doc_key);
+        }
+        let seed = match template_dir {
+            Some(dir) if stored.is_new() => {
+                templates::seed(dir, room, doc, SystemTime::now()).await
+            }
+            _ => None,
+        };
+        if let Some(text) = &seed {
+            info!("[templates] {} starts from a template", doc_key);
+            let saved = storage.save(room, doc, text.clone(), stored.meta.clone());
+            if let Err(err) = saved.await {
+                println!("[storage] saving {} failed: {}", doc_key, err);
+            }
+        }
+        let text = seed.as_deref().unwrap_or(&stored.text);
+        let mut doc_state = Self::new(build_doc(&doc_key, "server", text), stored.meta);
+        doc_state.seeded = seed.is_some();
+        if restored {
+            // Nobody has it open yet; those joining get it with the rest.
+            doc_state.record_activity(DISK_USER, ActivityKind::Restored);
+        }
+        Ok(doc_state)
+    }
+
+    fn new(doc: TextDoc, meta: DocMeta) -> Self {
+        Self {
+            text: TextIndex::new(&doc.get_text()),
+            doc,
+            version: meta.version,
+            cursors: HashMap::new(),
+            last_revision: (meta.version, Instant::now()),
+            meta,
+            recent_ops: VecDeque::new(),
+            activity: VecDeque::new(),
+            activity_seq: 0,
+            seeded: false,
+            presence: Presence::default(),
+            rotated_users: HashMap::new(),
+        }
+    }
+
+    /// Notes `user_id`'s cursor at `pos` and returns the move for everyone
+    /// in the document, with its line and column.
+    fn move_cursor(&mut self, doc_id: &str, user_id: &str, pos: usize) -> Option<Message> {
+        let pos = self.text.clamp(pos);
+        self.cursors.insert(user_id.to_string(), pos);
+        let op = Op::Cursor {
+            pos,
+            at: Some(self.text.locate(pos)),
+        };
+        encode_update(doc_id, user_id, op, Vec::new(), self.version).ok()
+    }
+
+    /// Adds an entry to the activity feed and returns it.
+    fn record_activity(&mut self, user: &str, kind: ActivityKind) -> ActivityEntry {
+        self.activity_seq += 1;
+        let at_ms = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64);
+        let entry = ActivityEntry {
+            seq: self.activity_seq,
+            at_ms,
+            user: user.to_string(),
+            kind,
+        };
+        if self.activity.len() >= ACTIVITY_LEN {
+            self.activity.pop_front();
+        }
+        self.activity.push_back(entry.clone());
+        entry
+    }
+
+    /// Notes an edit applied just now.
+    fn note_op(&mut self) {
+        let now = Instant::now();
+        self.recent_ops.push_back(now);
+        while self
+            .recent_ops
+            .front()
+            .is_some_and(|at| now.duration_since(*at) >= overview::OPS_WINDOW)
+        {
+            self.recent_ops.pop_front();
+        }
+    }
+
+    /// Edits applied in the `overview::OPS_WINDOW` before `now`.
+    fn recent_op_count(&self, now: Instant) -> usize {
+        let expired = self
+            .recent_ops
+            .partition_point(|at| now.duration_since(*at) >= overview::OPS_WINDOW);
+        self.recent_ops.len() - expired
+    }
+
+    /// Whether enough versions or time passed for another revision.
+    fn revision_due(&self, history: &HistoryPolicy) -> bool {
+        let (version, at) = self.last_revision;
+        self.version - version >= history.every_versions || at.elapsed() >= history.every
+    }
+}
+
+/// What a connection's writer sends: sync traffic, or replies to
+/// `ClientMessage`s.
+#[derive(Serialize)]
+#[serde(untagged)]
+enum Outgoing {
+    Sync(Message),
+    Admin(ServerMessage),
+}
+
+impl From<Message> for Outgoing {
+    fn from(msg: Message) -> Self {
+        Self::Sync(msg)
+    }
+}
+
+impl From<ServerMessage> for Outgoing {
+    fn from(msg: ServerMessage) -> Self {
+        Self::Admin(msg)
+    }
 }
 
 struct UserState {
@@ -21,452 +248,4420 @@ struct UserState {
     name: String,
     room: String,
     doc: String,
+    joined: Instant,
+    guest: bool,
+    /// Woken to close the user's connection, e.g. by `/admin/kick`.
+    kick: Arc<Notify>,
+}
+
+impl UserState {
+    /// What happened to this user, for everyone in their document.
+    fn event(&self, kind: UserEventKind, detail: Option<String>) -> ServerMessage {
+        ServerMessage::UserEvent {
+            room: self.room.clone(),
+            doc: self.doc.clone(),
+            kind,
+            user: WireUser {
+                id: self.id.clone(),
+                name: self.name.clone(),
+                guest: self.guest,
+            },
+            detail,
+        }
+    }
 }
 
 struct SharedState {
     users: HashMap<String, UserState>,
     docs: HashMap<String, DocState>,
-    storage: Storage,
+    storage: Arc<dyn StorageBackend>,
+    history: HistoryPolicy,
+    /// Announced to every client and enforced on its connection.
+    limits: ServerLimits,
+    /// Sync traffic every connection forwards to its client if it is about
+    /// its document.
+    updates: broadcast::Sender<Message>,
+    /// `ServerMessage::UserEvent`s, `Members` and `DocInfo`s, forwarded
+    /// likewise.
+    notices: broadcast::Sender<ServerMessage>,
+    persistence: Arc<PersistenceManager>,
+    outbound: Arc<OutboundStats>,
+    /// Where documents created by a join find their template.
+    template_dir: Option<PathBuf>,
+    growth: GrowthPolicy,
+    /// Sent to every client in its `Welcome`.
+    motd: Option<String>,
+    doc_names: DocNames,
+    /// Our pid, while we hold the data dir's `collab.lock`.
+    lock_pid: Option<u32>,
 }
 
-pub async fn run(addr: &str, data_dir: &str, health_addr: &str) -> Result<(), Box<dyn Error>> {
-    let health_listener = TcpListener::bind(health_addr).await?;
-    println!("[health] listening on {}", health_addr);
-    tokio::spawn(async move {
-        if let Err(err) = run_health_loop(health_listener).await {
-            println!("[health] error: {}", err);
+impl SharedState {
+    /// Records what `user` did to `room`/`doc` in its activity feed and
+    /// sends the entry to its users. Documents that aren't loaded have no
+    /// feed to add to.
+    fn record_activity(&mut self, room: &str, doc: &str, user: &str, kind: ActivityKind) {
+        let Some(doc_state) = self.docs.get_mut(&doc_key(room, doc)) else {
+            return;
+        };
+        let entry = doc_state.record_activity(user, kind);
+        let _ = self.notices.send(ServerMessage::Activity {
+            room: room.to_string(),
+            doc: doc.to_string(),
+            entry,
+        });
+    }
+
+    /// Sends the users of `room`/`doc` to them, if they changed since they
+    /// were last sent, ending the window `presence_changed` opened.
+    fn flush_presence(&mut self, room: &str, doc: &str) {
+        let users = users_in_doc(&self.users, room, doc);
+        let Some(doc_state) = self.docs.get_mut(&doc_key(room, doc)) else {
+            return;
+        };
+        if let Some((presence_seq, users)) = doc_state.presence.flush(users) {
+            let _ = self.notices.send(ServerMessage::Members {
+                room: room.to_string(),
+                doc: doc.to_string(),
+                users,
+                presence_seq,
+            });
         }
-    });
+    }
 
-    let listener = TcpListener::bind(addr).await?;
-    println!("[server] listening on {}", addr);
+    fn new(storage: Arc<dyn StorageBackend>, history: HistoryPolicy) -> Self {
+        let persistence = Arc::new(PersistenceManager::new(Arc::clone(&storage)));
+        Self {
+            users: HashMap::new(),
+            docs: HashMap::new(),
+            storage,
+            history,
+            limits: ServerLimits::default(),
+            updates: broadcast::channel(256).0,
+            notices: broadcast::channel(256).0,
+            persistence,
+            outbound: Arc::default(),
+            template_dir: None,
+            growth: GrowthPolicy::default(),
+            motd: None,
+            doc_names: DocNames::default(),
+            lock_pid: None,
+        }
+    }
+}
+
+/// What the health listener serves besides health checks and metrics.
+#[derive(Debug, Clone, Copy)]
+pub struct HealthOptions {
+    /// Serve document contents, at `/rooms/{room}/docs/{doc}`.
+    pub http_read: bool,
+    /// Rooms given gauges of their own on `/metrics`; the rest are summed
+    /// into `room="other"`.
+    pub metrics_room_limit: usize,
+}
+
+impl Default for HealthOptions {
+    fn default() -> Self {
+        Self {
+            http_read: false,
+            metrics_room_limit: 50,
+        }
+    }
+}
 
-    let state = Arc::new(Mutex::new(SharedState {
-        users: HashMap::new(),
-        docs: HashMap::new(),
-        storage: Storage::new(data_dir),
-    }));
+/// Serves on `addr`, with health checks and metrics on `health_addr`, until
+/// Ctrl-C or SIGTERM.
+#[allow(clippy::too_many_arguments)]
+pub async fn run(
+    addr: &str,
+    health_addr: &str,
+    backend: BackendKind,
+    options: FsOptions,
+    history: HistoryPolicy,
+    limits: ServerLimits,
+    growth: GrowthPolicy,
+    motd: MotdOptions,
+    health: HealthOptions,
+) -> Result<(), Box<dyn Error>> {
+    let listener = TcpListener::bind(addr).await?;
+    let health_listener = TcpListener::bind(health_addr).await?;
+    serve(
+        listener,
+        health_listener,
+        backend,
+        options,
+        history,
+        limits,
+        growth,
+        motd,
+        health,
+        shutdown_signal(),
+    )
+    .await
+}
 
-    let (broadcast_tx, _) = broadcast::channel::<Message>(256);
+/// Serves clients on `listener` and health checks on `health_listener`
+/// until `shutdown` resolves, then saves every dirty document and returns.
+#[allow(clippy::too_many_arguments)]
+pub async fn serve(
+    listener: TcpListener,
+    health_listener: TcpListener,
+    backend: BackendKind,
+    options: FsOptions,
+    history: HistoryPolicy,
+    limits: ServerLimits,
+    growth: GrowthPolicy,
+    motd: MotdOptions,
+    health: HealthOptions,
+    shutdown: impl Future<Output = ()>,
+) -> Result<(), Box<dyn Error>> {
+    let FsOptions {
+        data_dir,
+        fsync,
+        encryption,
+        compress_above,
+        watch,
+        template_dir,
+        doc_names,
+        trash_retention,
+    } = options;
+    let mut watched = None;
+    let mut lock = None;
+    let (storage, stats): (Arc<dyn StorageBackend>, _) = match backend {
+        BackendKind::Fs => {
+            let held = DataDirLock::acquire(&data_dir)?;
+            if let Some(pid) = held.stale_pid {
+                println!(
+                    "[storage] took over {} from pid {}, which is gone",
+                    held.path().display(),
+                    pid
+                );
+            }
+            lock = Some(held);
+            let mut storage = Storage::new(&data_dir, fsync)
+                .keep_revisions(history.keep)
+                .compress_above(compress_above);
+            for (from, to) in storage.migrate_names().await? {
+                println!("[storage] renamed {} to {}", from.display(), to.display());
+            }
+            if let Some(Encryption { cipher, migrate }) = encryption {
+                storage = storage.encrypt_with(cipher);
+                match storage.check_encryption(migrate).await {
+                    Ok(migrated) => migrated
+                        .iter()
+                        .for_each(|path| println!("[storage] encrypted {}", path.display())),
+                    Err(err) => {
+                        println!("[storage] {}", err);
+                        return Err("refusing to start with unreadable documents".into());
+                    }
+                }
+                println!("[storage] documents are encrypted at rest");
+            }
+            println!("[storage] fsync policy {}", fsync);
+            if let SyncPolicy::Interval(every) = fsync {
+                tokio::spawn(run_sync_loop(storage.clone(), every));
+            }
+            if watch {
+                watched = Some((storage.clone(), data_dir));
+            }
+            tokio::spawn(run_purge_loop(storage.clone(), trash_retention));
+            let stats = storage.stats();
+            (Arc::new(storage), stats)
+        }
+        BackendKind::Memory => {
+            println!("[storage] WARNING: in-memory storage, nothing persists across restarts");
+            if encryption.is_some() {
+                println!("[storage] nothing is written, so --encryption-key-file is ignored");
+            }
+            if watch {
+                println!("[storage] there are no files, so --watch-data-dir is ignored");
+            }
+            (Arc::new(MemoryStorage::new()), Arc::default())
+        }
+    };
 
-    loop {
-        let (stream, peer) = listener.accept().await?;
-        println!("[server] connection from {}", peer);
+    if growth.auto_rotate && growth.warn_bytes.is_none() && growth.warn_lines.is_none() {
+        println!(
+            "[server] --auto-rotate needs --warn-doc-bytes or --warn-doc-lines; nothing is rotated"
+        );
+    }
+    let mut state = SharedState::new(storage, history);
+    state.limits = limits;
+    state.template_dir = template_dir;
+    state.doc_names = doc_names;
+    state.growth = growth;
+    state.lock_pid = lock.as_ref().map(|_| std::process::id());
+    if let Some(path) = &motd.file {
+        match motd::load(path).await {
+            Ok(Some(text)) => {
+                println!("[motd] message of the day from {}", path.display());
+                state.motd = Some(text);
+            }
+            Ok(None) => println!("[motd] {} is missing or empty", path.display()),
+            Err(err) => println!("[motd] can't read {}: {}", path.display(), err),
+        }
+    }
+    let state = Arc::new(Mutex::new(state));
+    if let Some(path) = motd.file {
         let state = Arc::clone(&state);
-        let broadcast_tx = broadcast_tx.clone();
-        let broadcast_rx = broadcast_tx.subscribe();
         tokio::spawn(async move {
-            if let Err(err) = handle_connection(stream, state, broadcast_tx, broadcast_rx).await {
-                println!("[server] connection error: {}", err);
+            if let Err(err) = motd::run_reload_loop(path, state, motd.announce).await {
+                println!("[motd] error: {}", err);
             }
         });
     }
-}
+    tokio::spawn(persistence::run_flush_loop(Arc::clone(&state)));
 
-async fn run_health_loop(listener: TcpListener) -> Result<(), Box<dyn Error>> {
-    loop {
-        let (stream, _) = listener.accept().await?;
+    println!("[health] listening on {}", health_listener.local_addr()?);
+    tokio::spawn({
+        let state = Arc::clone(&state);
+        async move {
+            if let Err(err) = run_health_loop(health_listener, stats, state, health).await {
+                println!("[health] error: {}", err);
+            }
+        }
+    });
+
+    println!("[server] listening on {}", listener.local_addr()?);
+    tokio::spawn(logging::run_summary_loop());
+
+    let broadcast_tx = state.lock().await.updates.clone();
+
+    if let Some((storage, data_dir)) = watched {
+        std::fs::create_dir_all(&data_dir)?;
+        let (state, broadcast_tx) = (Arc::clone(&state), broadcast_tx.clone());
         tokio::spawn(async move {
-            if let Err(err) = handle_health_conn(stream).await {
-                println!("[health] request error: {}", err);
+            if let Err(err) = run_watch_loop(storage, data_dir, state, broadcast_tx).await {
+                println!("[watch] error: {}", err);
             }
         });
     }
-}
 
-async fn handle_health_conn(stream: TcpStream) -> Result<(), Box<dyn Error>> {
-    let (reader, mut writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
+    tokio::pin!(shutdown);
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => accepted?,
+            () = &mut shutdown => break,
+        };
+        let peer = peer.to_string();
+        logging::peer_info(
+            "connected",
+            &peer,
+            &format!("[server] connection from {}", peer),
+        );
+        spawn_connection(stream, peer, &state, &broadcast_tx);
+    }
 
-    let request_line = match lines.next_line().await? {
-        Some(line) => line,
-        None => return Ok(()),
+    println!("[server] shutting down, saving open documents");
+    match persistence::flush_all(&state).await {
+        Ok(saved) => println!("[storage] saved {} documents", saved),
+        Err(err) => {
+            println!("[storage] {}", err);
+            return Err("some documents could not be saved".into());
+        }
+    }
+    drop(lock);
+    Ok(())
+}
+
+/// Resolves on Ctrl-C, or SIGTERM on unix.
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(err) = tokio::signal::ctrl_c().await {
+            println!("[server] can't listen for Ctrl-C: {}", err);
+            std::future::pending::<()>().await;
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(err) => {
+                println!("[server] can't listen for SIGTERM: {}", err);
+                std::future::pending::<()>().await;
+            }
+        }
     };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+    tokio::select! {
+        () = ctrl_c => {}
+        () = terminate => {}
+    }
+}
 
-    let ok = request_line.starts_with("GET /health");
-    if ok {
-        writer
-            .write_all(
-                b"HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: 2\r\n\r\nOK",
-            )
-            .await?;
-    } else {
-        writer
-            .write_all(
-                b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nNot Found",
-            )
-            .await?;
+/// Fsyncs the documents saved since the last tick, for
+/// `--fsync interval:<n>`.
+async fn run_sync_loop(storage: Storage, every: Duration) {
+    let mut tick = tokio::time::interval(every);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tick.tick().await;
+        if let Err(err) = storage.sync_pending().await {
+            println!("[storage] fsync failed: {}", err);
+        }
     }
+}
 
-    Ok(())
+/// Purges documents deleted longer than `retention` ago from the trash,
+/// at startup and then every `TRASH_SWEEP_EVERY`.
+async fn run_purge_loop(storage: Storage, retention: Duration) {
+    let mut tick = tokio::time::interval(TRASH_SWEEP_EVERY);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tick.tick().await;
+        match storage.purge_trash(retention).await {
+            Ok(0) => {}
+            Ok(purged) => println!(
+                "[storage] purged {} documents deleted over {} days ago",
+                purged,
+                retention.as_secs() / (24 * 60 * 60)
+            ),
+            Err(err) => println!("[storage] purging the trash failed: {}", err),
+        }
+    }
 }
 
-async fn handle_connection(
-    stream: TcpStream,
+/// Applies edits made to document files outside the server, for
+/// `--watch-data-dir`.
+async fn run_watch_loop(
+    storage: Storage,
+    data_dir: PathBuf,
     state: Arc<Mutex<SharedState>>,
     broadcast_tx: broadcast::Sender<Message>,
-    mut broadcast_rx: broadcast::Receiver<Message>,
-) -> Result<(), Box<dyn Error>> {
-    let (reader, mut writer) = stream.into_split();
-    let mut lines = BufReader::new(reader).lines();
+) -> notify::Result<()> {
+    let (paths_tx, mut paths) = mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && (event.kind.is_create() || event.kind.is_modify())
+        {
+            event.paths.into_iter().for_each(|path| {
+                let _ = paths_tx.send(path);
+            });
+        }
+    })?;
+    watcher.watch(&data_dir, notify::RecursiveMode::Recursive)?;
+    println!(
+        "[watch] reloading documents edited in {}",
+        data_dir.display()
+    );
 
-    let (out_tx, mut out_rx) = mpsc::channel::<Message>(64);
+    while let Some(path) = paths.recv().await {
+        let mut changed = HashSet::from([path]);
+        while let Ok(Some(path)) = tokio::time::timeout(WATCH_DEBOUNCE, paths.recv()).await {
+            changed.insert(path);
+        }
+        for path in changed {
+            match storage.external_change(path).await {
+                Ok(Some(change)) => apply_external_change(&state, &broadcast_tx, change).await,
+                Ok(None) => {}
+                Err(err) => println!("[watch] {}", err),
+            }
+        }
+    }
+    Ok(())
+}
 
-    let mut current_user_id: Option<String> = None;
-    let mut current_user_name: Option<String> = None;
-    let mut current_room: Option<String> = None;
-    let mut current_doc: Option<String> = None;
+/// Turns an open document into the text found on disk, as edits by
+/// `DISK_USER` that connected clients receive like any other. A document
+/// nobody has open is only saved again right away, so its metadata
+/// matches.
+async fn apply_external_change(
+    state: &Mutex<SharedState>,
+    broadcast_tx: &broadcast::Sender<Message>,
+    change: ExternalChange,
+) {
+    let ExternalChange {
+        room,
+        doc,
+        text,
+        meta,
+    } = change;
+    let doc_key = doc_key(&room, &doc);
+    let mut guard = state.lock().await;
+    let SharedState {
+        docs,
+        storage,
+        persistence,
+        ..
+    } = &mut *guard;
+    let Some(doc_state) = docs.get_mut(&doc_key) else {
+        info!("[watch] {} changed on disk", doc_key);
+        let storage = Arc::clone(storage);
+        drop(guard);
+        if let Err(err) = storage.save(&room, &doc, text, meta).await {
+            println!("[storage] saving {} failed: {}", doc_key, err);
+        }
+        return;
+    };
 
-    let writer_task = tokio::spawn(async move {
-        while let Some(msg) = out_rx.recv().await {
-            let json = match serde_json::to_string(&msg) {
-                Ok(json) => json,
-                Err(_) => continue,
-            };
-            if writer.write_all(json.as_bytes()).await.is_err() {
-                break;
-            }
-            if writer.write_all(b"\n").await.is_err() {
-                break;
-            }
+    let updates = edit_to(doc_state, &doc_key, &text);
+    if updates.is_empty() {
+        return;
+    }
+    info!("[watch] reloaded {} from disk", doc_key);
+    persistence.mark_dirty(&room, &doc, doc_state.version, false);
+    drop(guard);
+    for update in updates {
+        let _ = broadcast_tx.send(update);
+    }
+}
+
+/// Turns an open document into `text` with edits by `DISK_USER`, and
+/// returns the updates for its clients.
+fn edit_to(doc_state: &mut DocState, doc_key: &str, text: &str) -> Vec<Message> {
+    let mut updates = Vec::new();
+    for mut op in snapshot::diff(&doc_state.doc.get_text(), text) {
+        resolve_positions(&doc_state.text, &mut op);
+        apply_op_to_doc(doc_state, DISK_USER, &op);
+        doc_state.version += 1;
+        doc_state.note_op();
+        match encode_update(doc_key, DISK_USER, op, Vec::new(), doc_state.version) {
+            Ok(update) => updates.push(update),
+            Err(err) => println!("[server] failed to encode update: {}", err),
         }
-    });
+    }
+    doc_state.meta.version = doc_state.version;
+    updates
+}
 
-    loop {
-        tokio::select! {
-            line = lines.next_line() => {
-                let line = match line {
-                    Ok(Some(line)) => line,
-                    Ok(None) => break,
-                    Err(err) => {
-                        println!("[server] read error: {}", err);
-                        break;
-                    }
-                };
+/// Tidies an open document under a strict whitespace policy, sparing the
+/// lines its users' cursors are on, and returns the updates for its
+/// clients.
+fn apply_whitespace_policy(doc_state: &mut DocState, doc_key: &str) -> Vec<Message> {
+    let text = doc_state.doc.get_text();
+    let busy: Vec<usize> = doc_state.cursors.values().copied().collect();
+    let tidy = doc_state.meta.whitespace.normalize(&text, &busy);
+    edit_to(doc_state, doc_key, &tidy)
+}
 
-                let msg: Message = match serde_json::from_str(&line) {
-                    Ok(msg) => msg,
-                    Err(_) => continue,
-                };
+/// Sets `room`'s default doc and aliases for `user_id`, or for an admin if
+/// `None`. Returns the `RoomInfo` to answer with, or why they weren't set.
+async fn set_room_meta(
+    state: &Mutex<SharedState>,
+    user_id: Option<&str>,
+    room: &str,
+    meta: RoomMeta,
+) -> Result<ServerMessage, String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    let named = meta.aliases.iter().flat_map(|(alias, doc)| [alias, doc]);
+    for doc in meta.default_doc.iter().chain(named) {
+        storage.validate(room, doc).map_err(|err| err.to_string())?;
+    }
+    if let Some(user_id) = user_id {
+        let guard = state.lock().await;
+        let first = guard
+            .users
+            .values()
+            .filter(|user| user.room == room)
+            .min_by_key(|user| user.joined);
+        match first {
+            Some(first) if first.id == user_id => {}
+            Some(first) => {
+                return Err(format!(
+                    "only {}, who joined first, may change the room's settings",
+                    first.name
+                ));
+            }
+            None => return Err("join a document of the room to change its settings".to_string()),
+        }
+    }
+    storage
+        .save_room_meta(room, meta.clone())
+        .await
+        .map_err(|err| format!("saving the settings of {} failed: {}", room, err))?;
+    info!(
+        "[storage] settings of room {}: default doc {:?}, {} aliases",
+        room,
+        meta.default_doc,
+        meta.aliases.len()
+    );
+    Ok(ServerMessage::RoomInfo {
+        room: room.to_string(),
+        meta,
+    })
+}
 
-                match msg {
-                    Message::Hello {
-                        replica_id,
-                        user_name,
-                    } => {
-                        current_user_id = Some(replica_id);
-                        current_user_name = Some(user_name);
-                    }
-                    Message::SyncRequest { document_id, .. } => {
-                        if current_user_id.is_none() || current_user_name.is_none() {
-                            continue;
-                        }
+/// The doc a hello naming `room`/`doc` joins instead, if any: the room's
+/// default doc for an empty name, or the doc an alias stands for while no
+/// document goes by the alias itself.
+async fn redirect_target(state: &Mutex<SharedState>, room: &str, doc: &str) -> Option<String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    let meta = match storage.load_room_meta(room).await {
+        Ok(meta) => meta,
+        Err(err) => {
+            println!(
+                "[storage] can't read the settings of room {}: {}",
+                room, err
+            );
+            return None;
+        }
+    };
+    if doc.is_empty() {
+        return meta.default_doc;
+    }
+    let target = meta.aliases.get(doc)?;
+    if state.lock().await.docs.contains_key(&doc_key(room, doc)) {
+        return None;
+    }
+    let unused = storage
+        .load(room, doc)
+        .await
+        .is_ok_and(|stored| stored.is_new());
+    unused.then(|| target.clone())
+}
 
-                        if doc_id_from_scoped_user_id(current_user_id.as_deref().unwrap())
-                            != Some(document_id.as_str())
-                        {
-                            println!(
-                                "[server] replica_id not scoped to document: {}",
-                                document_id
-                            );
-                            continue;
-                        }
+/// Sets `room`/`doc`'s whitespace policy for `user_id`, or for an admin if
+/// `None`, and tells the document's users. Returns the `DocInfo` sent, or
+/// why the policy wasn't set.
+async fn set_policy(
+    state: &Mutex<SharedState>,
+    user_id: Option<&str>,
+    room: &str,
+    doc: &str,
+    policy: WhitespacePolicy,
+) -> Result<ServerMessage, String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    storage.validate(room, doc).map_err(|err| err.to_string())?;
+    let mut guard = lock_loaded(state, room, doc)
+        .await
+        .map_err(|err| format!("document unavailable: {}", err))?;
+    let by = match user_id {
+        Some(user_id) => guard
+            .users
+            .get(user_id)
+            .map_or_else(|| user_id.to_string(), |user| user.name.clone()),
+        None => ADMIN_USER.to_string(),
+    };
+    if let Some(user_id) = user_id {
+        let first = guard
+            .users
+            .values()
+            .filter(|user| user.room == room && user.doc == doc)
+            .min_by_key(|user| user.joined);
+        match first {
+            Some(first) if first.id == user_id => {}
+            Some(first) => {
+                return Err(format!(
+                    "only {}, who joined first, may set the policy",
+                    first.name
+                ));
+            }
+            None => return Err("join the document to set its policy".to_string()),
+        }
+    }
+    let key = doc_key(room, doc);
+    let doc_state = guard.docs.get_mut(&key).expect("doc is loaded");
+    doc_state.meta.whitespace = policy;
+    let (version, acl) = (doc_state.version, doc_state.meta.acl.clone());
+    guard.persistence.mark_meta_dirty(room, doc, version);
+    info!("[storage] whitespace policy of {}: {}", key, policy);
+    let info = ServerMessage::DocInfo {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        policy,
+        acl,
+        seeded_from_template: false,
+    };
+    let _ = guard.notices.send(info.clone());
+    guard.record_activity(room, doc, &by, ActivityKind::Policy { policy });
+    Ok(info)
+}
 
-                        let (room, doc) = split_doc_id(&document_id);
-                        current_room = Some(room.clone());
-                        current_doc = Some(doc.clone());
+/// Sets who may edit and read `room`/`doc` for `user_id`, or for an admin
+/// if `None`, and tells the document's users. Returns the `DocInfo` sent,
+/// or why the ACL wasn't set. A user must be one of the document's
+/// writers, or the first of its users to join while it names none, and
+/// may not leave themselves out.
+async fn set_acl(
+    state: &Mutex<SharedState>,
+    user_id: Option<&str>,
+    room: &str,
+    doc: &str,
+    acl: Acl,
+) -> Result<ServerMessage, String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    storage.validate(room, doc).map_err(|err| err.to_string())?;
+    let mut guard = lock_loaded(state, room, doc)
+        .await
+        .map_err(|err| format!("document unavailable: {}", err))?;
+    let key = doc_key(room, doc);
+    if let Some(user_id) = user_id {
+        let in_doc = |user: &&UserState| user.room == room && user.doc == doc;
+        let Some(user) = guard.users.get(user_id).filter(in_doc) else {
+            return Err("join the document to change who may edit it".to_string());
+        };
+        let current = &guard.docs[&key].meta.acl;
+        if current.writers.is_empty() {
+            let first = guard
+                .users
+                .values()
+                .filter(in_doc)
+                .min_by_key(|user| user.joined)
+                .expect("the user is in the document");
+            if first.id != user_id {
+                return Err(format!(
+                    "only {}, who joined first, may change who may edit",
+                    first.name
+                ));
+            }
+        } else if current.access(&user.name) != Access::Write {
+            return Err(format!(
+                "only the writers of {} may change who may edit it",
+                key
+            ));
+        }
+        if acl.access(&user.name) != Access::Write {
+            return Err("you can't take yourself off the writers".to_string());
+        }
+    }
+    let doc_state = guard.docs.get_mut(&key).expect("doc is loaded");
+    doc_state.meta.acl = acl.clone();
+    let (version, policy) = (doc_state.version, doc_state.meta.whitespace);
+    guard.persistence.mark_meta_dirty(room, doc, version);
+    info!("[storage] ACL of {}: {}", key, acl);
+    let info = ServerMessage::DocInfo {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        policy,
+        acl,
+        seeded_from_template: false,
+    };
+    let _ = guard.notices.send(info.clone());
+    Ok(info)
+}
 
-                        let mut guard = state.lock().await;
-                        let doc_key = doc_key(&room, &doc);
-                        let (doc_text, doc_version) = if let Some(doc_state) = guard.docs.get(&doc_key) {
-                            (doc_state.doc.get_text(), doc_state.version)
-                        } else {
-                            let text = guard.storage.load_text(&room, &doc).unwrap_or_default();
-                            let mut new_doc = TextDoc::new(doc_key.clone(), "server");
-                            if !text.is_empty() {
-                                new_doc.insert(0, &text);
-                            }
-                            guard.docs.insert(
-                                doc_key.clone(),
-                                DocState {
-                                    doc: new_doc,
-                                    version: 0,
-                                    cursors: HashMap::new(),
-                                },
-                            );
-                            (text, 0)
-                        };
+/// What the user called `name` may do with `room`/`doc`; `None` if it
+/// can't be read. A document nobody has open is read from storage rather
+/// than loaded, so a join still starts it from its template.
+async fn access_of(
+    state: &Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+    name: &str,
+) -> Option<Access> {
+    let storage = {
+        let guard = state.lock().await;
+        if let Some(doc_state) = guard.docs.get(&doc_key(room, doc)) {
+            return Some(doc_state.meta.acl.access(name));
+        }
+        Arc::clone(&guard.storage)
+    };
+    storage.validate(room, doc).ok()?;
+    let stored = storage.load(room, doc).await.ok()?;
+    Some(stored.meta.acl.access(name))
+}
 
-                        let user_id = current_user_id.clone().unwrap();
-                        let user_name = current_user_name.clone().unwrap();
-                        let user_state = UserState {
-                            id: user_id.clone(),
-                            name: user_name.clone(),
-                            room: room.clone(),
-                            doc: doc.clone(),
-                        };
-                        guard.users.insert(user_id.clone(), user_state);
+/// Whether a request may see what is in `room`/`doc`, by the name its
+/// connection said hello with. Connections without one are kept out of
+/// documents that list their readers.
+async fn check_read(
+    state: &Mutex<SharedState>,
+    name: Option<&str>,
+    room: &str,
+    doc: &str,
+) -> Result<(), String> {
+    match access_of(state, room, doc, name.unwrap_or("")).await {
+        Some(Access::Denied) => Err(format!("forbidden: you may not read {}/{}", room, doc)),
+        _ => Ok(()),
+    }
+}
 
-                        let users = users_in_doc(&guard.users, &room, &doc);
-                        match encode_sync_response(&document_id, &doc_text, users, doc_version) {
-                            Ok(sync) => {
-                                let _ = out_tx.send(sync).await;
-                            }
-                            Err(err) => {
-                                println!("[server] failed to encode sync response: {}", err);
-                            }
+/// Who a request to change `room`/`doc` acts as: the name the connection
+/// said hello with, if that may edit it. Connections without one may not.
+async fn editor_of(
+    state: &Mutex<SharedState>,
+    name: Option<&str>,
+    room: &str,
+    doc: &str,
+) -> Result<String, String> {
+    let Some(name) = name else {
+        return Err("forbidden: say hello with a name first".to_string());
+    };
+    let access = access_of(state, room, doc, name).await;
+    if access.is_some_and(|access| access != Access::Write) {
+        return Err(format!("forbidden: you may not edit {}/{}", room, doc));
+    }
+    Ok(name.to_string())
+}
+
+/// Saves `room`/`doc` with a revision now, for `user`, and records it in
+/// the document's activity feed.
+async fn take_snapshot(
+    state: &Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> Result<(u64, usize), SnapshotError> {
+    let persistence = Arc::clone(&state.lock().await.persistence);
+    let (version, bytes) = persistence.snapshot(state, room, doc).await?;
+    info!("[storage] snapshot of {}/{} at v{}", room, doc, version);
+    let mut guard = state.lock().await;
+    guard.record_activity(room, doc, user, ActivityKind::Snapshot { version });
+    Ok((version, bytes))
+}
+
+/// The stored revisions of `room`/`doc` for `ClientMessage::ListRevisions`,
+/// with who took the snapshots its activity feed still remembers.
+async fn list_revisions(state: &Mutex<SharedState>, room: &str, doc: &str) -> Vec<WireRevision> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    if storage.validate(room, doc).is_err() {
+        return Vec::new();
+    }
+    let revisions = match storage.list_revisions(room, doc).await {
+        Ok(revisions) => revisions,
+        Err(err) => {
+            println!(
+                "[storage] listing revisions of {}/{} failed: {}",
+                room, doc, err
+            );
+            return Vec::new();
+        }
+    };
+    let guard = state.lock().await;
+    let feed = guard
+        .docs
+        .get(&doc_key(room, doc))
+        .map(|doc_state| &doc_state.activity);
+    let taken_by = |version: u64| {
+        feed?.iter().rev().find_map(|entry| match entry.kind {
+            ActivityKind::Snapshot { version: taken } if taken == version => {
+                Some(entry.user.clone())
+            }
+            _ => None,
+        })
+    };
+    revisions
+        .into_iter()
+        .map(|revision| WireRevision {
+            version: revision.version,
+            saved_at_ms: revision
+                .saved_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+            by: taken_by(revision.version),
+        })
+        .collect()
+}
+
+/// Turns `room`/`doc` back into its revision `version` for `user`, with
+/// edits by `DISK_USER` its clients receive like any other. Returns the
+/// document's version after them.
+async fn restore_revision(
+    state: &Mutex<SharedState>,
+    broadcast_tx: &broadcast::Sender<Message>,
+    room: &str,
+    doc: &str,
+    version: u64,
+    user: &str,
+) -> Result<u64, String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    storage.validate(room, doc).map_err(|err| err.to_string())?;
+    let text = storage
+        .load_revision(room, doc, version)
+        .await
+        .map_err(|err| err.to_string())?;
+    let mut guard = lock_loaded(state, room, doc)
+        .await
+        .map_err(|err| format!("document unavailable: {}", err))?;
+    let key = doc_key(room, doc);
+    let doc_state = guard.docs.get_mut(&key).expect("doc is loaded");
+    let updates = edit_to(doc_state, &key, &text);
+    let now = doc_state.version;
+    guard.persistence.mark_dirty(room, doc, now, false);
+    info!("[storage] {} restored {} to v{}", user, key, version);
+    guard.record_activity(room, doc, user, ActivityKind::Reverted { version });
+    drop(guard);
+    for update in updates {
+        let _ = broadcast_tx.send(update);
+    }
+    Ok(now)
+}
+
+/// The documents in `room`'s trash for `ClientMessage::ListTrash`.
+async fn list_trash(state: &Mutex<SharedState>, room: &str) -> io::Result<Vec<WireTrashed>> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    let trashed = storage.list_trash(room).await?;
+    Ok(trashed
+        .into_iter()
+        .map(|entry| WireTrashed {
+            name: entry.name,
+            bytes: entry.size,
+            deleted_at_ms: entry
+                .deleted_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64),
+        })
+        .collect())
+}
+
+/// Brings `room`/`doc` back from the trash as `to` for `user`. A document
+/// open as `to` takes the restored text as edits by `DISK_USER`, which its
+/// clients receive like any other.
+async fn restore_trashed(
+    state: &Mutex<SharedState>,
+    broadcast_tx: &broadcast::Sender<Message>,
+    room: &str,
+    doc: &str,
+    to: &str,
+    force: bool,
+    user: &str,
+) -> io::Result<()> {
+    let (storage, persistence) = {
+        let guard = state.lock().await;
+        (Arc::clone(&guard.storage), Arc::clone(&guard.persistence))
+    };
+    storage.validate(room, doc)?;
+    storage.validate(room, to)?;
+    let key = doc_key(room, to);
+    // No flush may write an open document's old text over the restored
+    // file before its users have the restored text.
+    let _paused = persistence.pause().await;
+    let open = state.lock().await.docs.contains_key(&key);
+    if open && !force {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!(
+                "{}/{} is open; restore under another name, or with --force to trash it",
+                room, to
+            ),
+        ));
+    }
+    storage.restore_from_trash(room, doc, to, force).await?;
+    info!(
+        "[storage] {} restored {}/{} from the trash as {}",
+        user, room, doc, to
+    );
+    // Open now if a join read the old file meanwhile.
+    if !state.lock().await.docs.contains_key(&key) {
+        return Ok(());
+    }
+    let text = storage.load(room, to).await?.text;
+    let mut guard = lock_loaded(state, room, to).await?;
+    let doc_state = guard.docs.get_mut(&key).expect("doc is loaded");
+    let updates = edit_to(doc_state, &key, &text);
+    let version = doc_state.version;
+    guard.persistence.mark_dirty(room, to, version, false);
+    drop(guard);
+    for update in updates {
+        let _ = broadcast_tx.send(update);
+    }
+    Ok(())
+}
+
+/// Locks the state with the document loaded. Loading happens without the
+/// lock held, so a slow disk only holds up this document's users.
+async fn lock_loaded<'a>(
+    state: &'a Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+) -> io::Result<MutexGuard<'a, SharedState>> {
+    let (guard, _) = get_or_load_doc(state, room, doc, false).await?;
+    Ok(guard)
+}
+
+/// Like `lock_loaded`, for a join: a document that was never saved starts
+/// from its template, and `--doc-names` decides about names differing
+/// only in case. Nothing else creates documents that way.
+async fn lock_joined<'a>(
+    state: &'a Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+) -> io::Result<(MutexGuard<'a, SharedState>, Joined)> {
+    get_or_load_doc(state, room, doc, true).await
+}
+
+/// The doc a join got: the one asked for, or under `DocNames::Merge` the
+/// one differing from it only in case. Under `DocNames::Warn` that one is
+/// the `twin` kept apart from it.
+struct Joined {
+    doc: String,
+    twin: Option<String>,
+}
+
+/// Where every document is loaded, for `lock_loaded` and `lock_joined`.
+async fn get_or_load_doc<'a>(
+    state: &'a Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+    join: bool,
+) -> io::Result<(MutexGuard<'a, SharedState>, Joined)> {
+    let mut doc = doc.to_string();
+    let mut guard = state.lock().await;
+    let names = match join {
+        true => guard.doc_names,
+        false => DocNames::Distinct,
+    };
+    let mut twin = None;
+    if names != DocNames::Distinct && !guard.docs.contains_key(&doc_key(room, &doc)) {
+        twin = names::open_twin(&guard, room, &doc);
+        if twin.is_none() {
+            let storage = Arc::clone(&guard.storage);
+            drop(guard);
+            twin = names::stored_twin(&*storage, room, &doc).await;
+            guard = state.lock().await;
+        }
+        if names == DocNames::Merge
+            && let Some(twin) = twin.take()
+        {
+            doc = twin;
+        }
+    }
+    let key = doc_key(room, &doc);
+    if guard.docs.contains_key(&key) {
+        return Ok((guard, Joined { doc, twin }));
+    }
+    let storage = Arc::clone(&guard.storage);
+    let template_dir = guard.template_dir.clone().filter(|_| join);
+    drop(guard);
+    let loaded = DocState::load(&*storage, room, &doc, template_dir.as_deref()).await?;
+    let mut guard = state.lock().await;
+    // Another join may have created it, or its twin, meanwhile; that copy
+    // may already have edits, so it wins.
+    if !guard.docs.contains_key(&key)
+        && names != DocNames::Distinct
+        && let Some(raced) = names::open_twin(&guard, room, &doc)
+    {
+        if names == DocNames::Merge {
+            return Ok((guard, Joined { doc: raced, twin }));
+        }
+        twin = Some(raced);
+    }
+    guard.docs.entry(key).or_insert(loaded);
+    Ok((guard, Joined { doc, twin }))
+}
+
+async fn run_health_loop(
+    listener: TcpListener,
+    stats: Arc<StorageStats>,
+    state: Arc<Mutex<SharedState>>,
+    options: HealthOptions,
+) -> Result<(), Box<dyn Error>> {
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let (stats, state) = (Arc::clone(&stats), Arc::clone(&state));
+        tokio::spawn(async move {
+            if let Err(err) = handle_health_conn(stream, &stats, &state, options).await {
+                println!("[health] request error: {}", err);
+            }
+        });
+    }
+}
+
+/// The server's resident memory, where the OS tells it.
+fn process_metrics() -> String {
+    #[cfg(target_os = "linux")]
+    if let Ok(statm) = std::fs::read_to_string("/proc/self/statm")
+        && let Some(pages) = statm.split_whitespace().nth(1)
+        && let Ok(pages) = pages.parse::<u64>()
+    {
+        // SAFETY: sysconf has no memory safety preconditions.
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        return format!(
+            "process_resident_memory_bytes {}\n",
+            pages * page_size.max(0) as u64
+        );
+    }
+    String::new()
+}
+
+/// Closes the connections of the users named `user` in `room`/`doc`, who
+/// are announced as kicked. Returns how many there were.
+async fn kick_user(
+    state: &Mutex<SharedState>,
+    room: &str,
+    doc: &str,
+    user: &str,
+) -> io::Result<usize> {
+    let guard = state.lock().await;
+    let kicked: Vec<&UserState> = guard
+        .users
+        .values()
+        .filter(|state| state.name == user && state.room == room && state.doc == doc)
+        .collect();
+    if kicked.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} is not in {}/{}", user, room, doc),
+        ));
+    }
+    kicked.iter().for_each(|state| state.kick.notify_one());
+    Ok(kicked.len())
+}
+
+async fn handle_health_conn(
+    stream: TcpStream,
+    stats: &StorageStats,
+    state: &Mutex<SharedState>,
+    options: HealthOptions,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    let request_line = match lines.next_line().await? {
+        Some(line) => line,
+        None => return Ok(()),
+    };
+
+    if request_line.starts_with("GET /metrics") {
+        let (persistence, outbound) = {
+            let guard = state.lock().await;
+            (Arc::clone(&guard.persistence), Arc::clone(&guard.outbound))
+        };
+        let rooms = overview::snapshot(state).await;
+        let body = stats.render()
+            + &persistence.render()
+            + &outbound.render()
+            + &process_metrics()
+            + &overview::render_metrics(&rooms, options.metrics_room_limit);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    if request_line.starts_with("GET /admin/overview") {
+        let body = overview::render_json(&overview::snapshot(state).await);
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Saves one document with a revision now, e.g. before a bulk edit.
+    if let Some(path) = request_line
+        .strip_prefix("POST /admin/snapshot/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (status, body) = match http::parse_doc_path(path) {
+            None => (
+                "400 Bad Request",
+                "expected /admin/snapshot/<room>/<doc>\n".to_string(),
+            ),
+            Some((room, doc)) => match take_snapshot(state, &room, &doc, ADMIN_USER).await {
+                Ok((version, bytes)) => {
+                    let body = serde_json::json!({ "version": version, "bytes": bytes });
+                    ("200 OK", format!("{}\n", body))
+                }
+                Err(err) => {
+                    let status = match err {
+                        SnapshotError::TooSoon(_) => "429 Too Many Requests",
+                        SnapshotError::Empty => "404 Not Found",
+                        SnapshotError::Io(ref err) if err.kind() == io::ErrorKind::InvalidInput => {
+                            "400 Bad Request"
                         }
-                        drop(guard);
+                        SnapshotError::Io(_) => "500 Internal Server Error",
+                    };
+                    (status, format!("{}\n", err))
+                }
+            },
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
 
-                        let _ = broadcast_tx.send(Message::Hello {
-                            replica_id: user_id,
-                            user_name,
-                        });
-                    }
-                    Message::Update { .. } => {
-                        handle_update(
-                            &state,
-                            &broadcast_tx,
-                            current_user_id.as_deref(),
-                            current_room.as_deref(),
-                            current_doc.as_deref(),
-                            &msg,
-                        )
-                        .await;
+    // Sets how a document's whitespace is tidied when it's saved.
+    if let Some(target) = request_line
+        .strip_prefix("POST /admin/policy/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (status, body) = match (http::parse_doc_path(path), http::parse_policy(query)) {
+            (None, _) => (
+                "400 Bad Request",
+                "expected /admin/policy/<room>/<doc>?final_newline=..&trim_trailing=..&strict=..\n"
+                    .to_string(),
+            ),
+            (_, Err(err)) => ("400 Bad Request", err),
+            (Some((room, doc)), Ok(policy)) => {
+                match set_policy(state, None, &room, &doc, policy).await {
+                    Ok(_) => ("200 OK", format!("{}\n", serde_json::json!(policy))),
+                    Err(err) => ("400 Bad Request", format!("{}\n", err)),
+                }
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Sets who may edit and who may read a document.
+    if let Some(target) = request_line
+        .strip_prefix("POST /admin/acl/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (status, body) = match (http::parse_doc_path(path), http::parse_acl(query)) {
+            (None, _) => (
+                "400 Bad Request",
+                "expected /admin/acl/<room>/<doc>?writers=..&readers=..\n".to_string(),
+            ),
+            (_, Err(err)) => ("400 Bad Request", err),
+            (Some((room, doc)), Ok(acl)) => {
+                let body = format!("{}\n", serde_json::json!(acl));
+                match set_acl(state, None, &room, &doc, acl).await {
+                    Ok(_) => ("200 OK", body),
+                    Err(err) => ("400 Bad Request", format!("{}\n", err)),
+                }
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Lists what was deleted from a room and can still be restored.
+    if let Some(path) = request_line
+        .strip_prefix("GET /admin/trash/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (status, body) = match http::parse_room_path(path) {
+            None => (
+                "400 Bad Request",
+                "expected /admin/trash/<room>\n".to_string(),
+            ),
+            Some(room) => match list_trash(state, &room).await {
+                Ok(docs) => ("200 OK", format!("{}\n", serde_json::json!(docs))),
+                Err(err) => (http::error_status(&err), format!("{}\n", err)),
+            },
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Brings a deleted document back, under another name with `as=`.
+    if let Some(target) = request_line
+        .strip_prefix("POST /admin/restore/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (status, body) = match (http::parse_doc_path(path), http::parse_restore(query)) {
+            (None, _) => (
+                "400 Bad Request",
+                "expected /admin/restore/<room>/<doc>?as=..&force=..\n".to_string(),
+            ),
+            (_, Err(err)) => ("400 Bad Request", err),
+            (Some((room, doc)), Ok((to, force))) => {
+                let to = to.unwrap_or_else(|| doc.clone());
+                let broadcast_tx = state.lock().await.updates.clone();
+                match restore_trashed(state, &broadcast_tx, &room, &doc, &to, force, ADMIN_USER)
+                    .await
+                {
+                    Ok(()) => ("200 OK", format!("{}\n", serde_json::json!({ "doc": to }))),
+                    Err(err) => (http::error_status(&err), format!("{}\n", err)),
+                }
+            }
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Closes a user's connections to a document.
+    if let Some(target) = request_line
+        .strip_prefix("POST /admin/kick/")
+        .map(|rest| rest.split(' ').next().unwrap_or_default())
+    {
+        let (path, query) = target.split_once('?').unwrap_or((target, ""));
+        let (status, body) = match (http::parse_doc_path(path), http::parse_kick(query)) {
+            (None, _) => (
+                "400 Bad Request",
+                "expected /admin/kick/<room>/<doc>?user=..\n".to_string(),
+            ),
+            (_, Err(err)) => ("400 Bad Request", err),
+            (Some((room, doc)), Ok(user)) => match kick_user(state, &room, &doc, &user).await {
+                Ok(kicked) => (
+                    "200 OK",
+                    format!("{}\n", serde_json::json!({ "kicked": kicked })),
+                ),
+                Err(err) => (http::error_status(&err), format!("{}\n", err)),
+            },
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    // Saves every dirty document now, e.g. before taking a backup.
+    if request_line.starts_with("POST /flush") {
+        let (status, body) = match persistence::flush_all(state).await {
+            Ok(saved) => ("200 OK", format!("saved {} documents\n", saved)),
+            Err(err) => ("500 Internal Server Error", format!("{}\n", err)),
+        };
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: text/plain\r\nContent-Length: {}\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        writer.write_all(response.as_bytes()).await?;
+        return Ok(());
+    }
+
+    if options.http_read
+        && let Some(target) = request_line.strip_prefix("GET ")
+    {
+        let target = target.split(' ').next().unwrap_or_default();
+        // Only document requests read the headers, for If-None-Match.
+        if target.starts_with("/rooms/") {
+            let mut if_none_match = None;
+            while let Some(line) = lines.next_line().await? {
+                if line.is_empty() {
+                    break;
+                }
+                if let Some((name, value)) = line.split_once(':')
+                    && name.eq_ignore_ascii_case("if-none-match")
+                {
+                    if_none_match = Some(value.trim().to_string());
+                }
+            }
+            if let Some(response) =
+                http::doc_response(state, target, if_none_match.as_deref()).await
+            {
+                writer.write_all(&response).await?;
+                return Ok(());
+            }
+        }
+    }
+
+    let ok = request_line.starts_with("GET /health");
+    if ok {
+        let lock_header = match state.lock().await.lock_pid {
+            Some(pid) => format!("X-Lock-Pid: {}\r\n", pid),
+            None => String::new(),
+        };
+        let response = format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\n{}Content-Length: 2\r\n\r\nOK",
+            lock_header
+        );
+        writer.write_all(response.as_bytes()).await?;
+    } else {
+        writer
+            .write_all(
+                b"HTTP/1.1 404 Not Found\r\nContent-Type: text/plain\r\nContent-Length: 9\r\n\r\nNot Found",
+            )
+            .await?;
+    }
+
+    Ok(())
+}
+
+/// Serves one client over `stream`, from `peer`, until it disconnects.
+fn spawn_connection(
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+    peer: String,
+    state: &Arc<Mutex<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Message>,
+) {
+    let state = Arc::clone(state);
+    let broadcast_tx = broadcast_tx.clone();
+    let broadcast_rx = broadcast_tx.subscribe();
+    tokio::spawn(async move {
+        let handled = handle_connection(stream, &peer, state, broadcast_tx, broadcast_rx).await;
+        if let Err(err) = handled {
+            let line = format!("[server] connection error from {}: {}", peer, err);
+            logging::peer_error("connection error", &peer, &line);
+        }
+    });
+}
+
+/// A server without listeners, whose clients connect over in-process
+/// streams such as `tokio::io::duplex`; used by `sim`.
+pub(crate) struct LocalServer {
+    state: Arc<Mutex<SharedState>>,
+    broadcast_tx: broadcast::Sender<Message>,
+}
+
+impl LocalServer {
+    pub(crate) fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        let state = SharedState::new(storage, HistoryPolicy::default());
+        Self {
+            broadcast_tx: state.updates.clone(),
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    pub(crate) fn connect(&self, stream: impl AsyncRead + AsyncWrite + Send + 'static) {
+        spawn_connection(stream, "local".to_string(), &self.state, &self.broadcast_tx);
+    }
+
+    /// The open document's text and version.
+    pub(crate) async fn doc(&self, room: &str, doc: &str) -> Option<(String, u64)> {
+        let guard = self.state.lock().await;
+        let doc_state = guard.docs.get(&doc_key(room, doc))?;
+        Some((doc_state.doc.get_text(), doc_state.version))
+    }
+}
+
+async fn handle_connection(
+    stream: impl AsyncRead + AsyncWrite + Send + 'static,
+    peer: &str,
+    state: Arc<Mutex<SharedState>>,
+    broadcast_tx: broadcast::Sender<Message>,
+    mut broadcast_rx: broadcast::Receiver<Message>,
+) -> Result<(), Box<dyn Error>> {
+    let (reader, writer) = tokio::io::split(stream);
+    let mut lines = BufReader::new(reader).lines();
+
+    let mut current_user_id: Option<String> = None;
+    let mut current_user_name: Option<String> = None;
+    // Whether the user goes by a name the server gave them, and whether
+    // that was refused because the server takes no guests.
+    let mut guest = false;
+    let mut guest_refused = false;
+    let mut current_room: Option<String> = None;
+    let mut current_doc: Option<String> = None;
+    // The doc id the hello named and the one joined instead.
+    let mut redirect: Option<(String, String)> = None;
+    let (limits, events_tx, outbound) = {
+        let guard = state.lock().await;
+        (
+            guard.limits,
+            guard.notices.clone(),
+            Arc::clone(&guard.outbound),
+        )
+    };
+    let _open = outbound.opened();
+    let (out_tx, low_lane, mut writer_task) = outbound::spawn_writer(writer, Arc::clone(&outbound));
+    let mut limits = ConnectionLimits::new(limits, Instant::now());
+    let mut events_rx = events_tx.subscribe();
+    // How the user's leaving is announced.
+    let mut leaving = (UserEventKind::Left, None);
+    // The last activity entry of the joined document sent to the client.
+    let mut activity_seen = 0;
+    // What of others' doings the client wants sent.
+    let mut subscriptions = Subscription::ALL.to_vec();
+    let kick = Arc::new(Notify::new());
+    // Closed for sending too many malformed lines, which it is told.
+    let mut violated = false;
+
+    loop {
+        tokio::select! {
+            line = lines.next_line() => {
+                let line = match line {
+                    Ok(Some(line)) => line,
+                    Ok(None) => break,
+                    Err(err) => {
+                        let user = current_user_id.as_deref().unwrap_or("no user yet");
+                        let line = format!("[server] read error from {} ({}): {}", peer, user, err);
+                        logging::peer_error("read error", peer, &line);
+                        break;
                     }
-                    Message::Presence {
-                        user_id,
-                        document_id,
-                        cursor_pos,
-                    } => {
-                        if let (Some(current_id), Some(room), Some(doc)) = (
-                            current_user_id.as_deref(),
-                            current_room.as_deref(),
-                            current_doc.as_deref(),
-                        ) {
-                            if user_id != current_id {
-                                println!("[server] ignoring spoofed presence for {}", user_id);
-                                continue;
+                };
+                if let Err(error) = limits.heard(&line, Instant::now()) {
+                    let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                    continue;
+                }
+
+                let msg: Message = match serde_json::from_str(&line) {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        match serde_json::from_str(&line) {
+                            Ok(ClientMessage::ExportRoom { room }) => {
+                                export_room(&state, &room, current_user_name.as_deref(), &out_tx).await;
                             }
-                            if document_id != doc_key(room, doc) {
-                                continue;
+                            Ok(ClientMessage::Version) => {
+                                let version = env!("CARGO_PKG_VERSION").to_string();
+                                let _ = out_tx.send(ServerMessage::Version { version }.into()).await;
                             }
-                            let mut guard = state.lock().await;
-                            if let Some(doc_state) = guard.docs.get_mut(&document_id) {
-                                match cursor_pos {
-                                    Some(pos) => {
-                                        doc_state.cursors.insert(user_id.clone(), pos);
+                            Ok(ClientMessage::Snapshot { room, doc, user }) => {
+                                let user = current_user_name
+                                    .clone()
+                                    .or(user)
+                                    .unwrap_or_else(|| ADMIN_USER.to_string());
+                                let reply = match take_snapshot(&state, &room, &doc, &user).await {
+                                    Ok((version, bytes)) => ServerMessage::SnapshotDone { version, bytes },
+                                    Err(err) => ServerMessage::SnapshotFailed {
+                                        error: err.to_string(),
+                                    },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::StatsRequest { room, doc }) => {
+                                if let Err(error) = check_read(&state, current_user_name.as_deref(), &room, &doc).await {
+                                    let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                    continue;
+                                }
+                                let per_user = doc_stats(&state, &room, &doc).await;
+                                let _ = out_tx.send(ServerMessage::DocStats { per_user }.into()).await;
+                            }
+                            Ok(ClientMessage::ListRooms) => {
+                                let rooms = list_rooms(&state).await;
+                                let _ = out_tx.send(ServerMessage::Rooms { rooms }.into()).await;
+                            }
+                            Ok(ClientMessage::ListDocs { room }) => {
+                                let docs = list_docs(&state, &room).await;
+                                let _ = out_tx.send(ServerMessage::Docs { room, docs }.into()).await;
+                            }
+                            Ok(ClientMessage::ListRevisions { room, doc }) => {
+                                if let Err(error) = check_read(&state, current_user_name.as_deref(), &room, &doc).await {
+                                    let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                    continue;
+                                }
+                                let revisions = list_revisions(&state, &room, &doc).await;
+                                let _ = out_tx.send(ServerMessage::Revisions { revisions }.into()).await;
+                            }
+                            Ok(ClientMessage::HistoryRequest { room, doc, version }) => {
+                                if let Err(error) = check_read(&state, current_user_name.as_deref(), &room, &doc).await {
+                                    let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                    continue;
+                                }
+                                let storage = Arc::clone(&state.lock().await.storage);
+                                let reply = match storage.load_revision(&room, &doc, version).await {
+                                    Ok(text) => ServerMessage::RevisionText { version, text },
+                                    Err(err) => ServerMessage::Rejected {
+                                        error: err.to_string(),
+                                    },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::Restore { room, doc, version }) => {
+                                let user = match editor_of(&state, current_user_name.as_deref(), &room, &doc).await {
+                                    Ok(user) => user,
+                                    Err(error) => {
+                                        let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                        continue;
                                     }
-                                    None => {
-                                        doc_state.cursors.remove(&user_id);
+                                };
+                                let restored =
+                                    restore_revision(&state, &broadcast_tx, &room, &doc, version, &user).await;
+                                let reply = match restored {
+                                    Ok(now) => ServerMessage::RestoreDone {
+                                        from: version,
+                                        version: now,
+                                    },
+                                    Err(error) => ServerMessage::Rejected { error },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::ListTrash { room }) => {
+                                let reply = match list_trash(&state, &room).await {
+                                    Ok(docs) => ServerMessage::Trash { room, docs },
+                                    Err(err) => ServerMessage::Rejected {
+                                        error: err.to_string(),
+                                    },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::RestoreDoc { room, doc, to, force }) => {
+                                let to = to.unwrap_or_else(|| doc.clone());
+                                let user = match editor_of(&state, current_user_name.as_deref(), &room, &to).await {
+                                    Ok(user) => user,
+                                    Err(error) => {
+                                        let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                        continue;
+                                    }
+                                };
+                                let restored =
+                                    restore_trashed(&state, &broadcast_tx, &room, &doc, &to, force, &user).await;
+                                let reply = match restored {
+                                    Ok(()) => ServerMessage::DocRestored { room, doc: to },
+                                    Err(err) => ServerMessage::Rejected {
+                                        error: err.to_string(),
+                                    },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::SetRoomMeta { room, meta }) => {
+                                let user_id = current_user_id.as_deref();
+                                let reply = match set_room_meta(&state, user_id, &room, meta).await {
+                                    Ok(info) => info,
+                                    Err(error) => ServerMessage::Rejected { error },
+                                };
+                                let _ = out_tx.send(reply.into()).await;
+                            }
+                            Ok(ClientMessage::SetPolicy { room, doc, policy }) => {
+                                let user_id = current_user_id.as_deref();
+                                let joined = current_room.as_deref() == Some(room.as_str())
+                                    && current_doc.as_deref() == Some(doc.as_str());
+                                match set_policy(&state, user_id, &room, &doc, policy).await {
+                                    // Users of the document get it as a notice.
+                                    Ok(_) if joined => {}
+                                    Ok(info) => {
+                                        let _ = out_tx.send(info.into()).await;
+                                    }
+                                    Err(error) => {
+                                        let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
                                     }
                                 }
                             }
-                            drop(guard);
-                            let _ = broadcast_tx.send(Message::Presence {
-                                user_id,
-                                document_id,
-                                cursor_pos,
-                            });
+                            Ok(ClientMessage::SetAcl { room, doc, acl }) => {
+                                let user_id = current_user_id.as_deref();
+                                let joined = current_room.as_deref() == Some(room.as_str())
+                                    && current_doc.as_deref() == Some(doc.as_str());
+                                match set_acl(&state, user_id, &room, &doc, acl).await {
+                                    // Users of the document get it as a notice.
+                                    Ok(_) if joined => {}
+                                    Ok(info) => {
+                                        let _ = out_tx.send(info.into()).await;
+                                    }
+                                    Err(error) => {
+                                        let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                                    }
+                                }
+                            }
+                            Ok(ClientMessage::SetSubscriptions { subscriptions: wanted }) => {
+                                subscriptions = wanted;
+                            }
+                            Err(err) => {
+                                outbound.malformed();
+                                // Where the JSON breaks says more than which
+                                // messages there are.
+                                let err = serde_json::from_str::<serde::de::IgnoredAny>(&line).err().unwrap_or(err);
+                                let user = current_user_id.as_deref().unwrap_or("no user yet");
+                                let line = format!("[server] malformed message from {} ({}): {}", peer, user, err);
+                                logging::peer_info("malformed message", peer, &line);
+                                let (code, error) = match limits.malformed() {
+                                    Malformed::Reply => (ErrorCode::MalformedMessage, format!("malformed message: {}", err)),
+                                    Malformed::Drop => continue,
+                                    Malformed::Disconnect => (
+                                        ErrorCode::ProtocolViolation,
+                                        format!("too many malformed messages, the last: {}", err),
+                                    ),
+                                };
+                                let _ = out_tx.send(ServerMessage::Error { code, error }.into()).await;
+                                if code == ErrorCode::ProtocolViolation {
+                                    leaving = (UserEventKind::Kicked, Some("protocol violation".to_string()));
+                                    violated = true;
+                                    break;
+                                }
+                            }
                         }
+                        continue;
                     }
-                    Message::SyncResponse { .. } => {}
-                    Message::Ack { .. } | Message::Ping | Message::Pong => {}
-                }
+                };
+
+                match msg {
+                    Message::Hello {
+                        replica_id,
+                        user_name,
+                    } => {
+                        let requested = doc_id_from_scoped_user_id(&replica_id).map(split_doc_id);
+                        let target = match &requested {
+                            Some((room, doc)) => redirect_target(&state, room, doc).await,
+                            None => None,
+                        };
+                        // The user joins the target under an id scoped to it.
+                        let (replica_id, redirected_from) = match (requested, target.as_ref()) {
+                            (Some((room, from)), Some(to)) => {
+                                let raw_id = replica_id.split_once('|').map_or("", |(_, raw)| raw);
+                                let to_id = doc_key(&room, to);
+                                info!("[server] {} joins {} for {}", raw_id, to_id, from);
+                                redirect = Some((doc_key(&room, &from), to_id.clone()));
+                                (make_scoped_user_id(&to_id, raw_id), Some(from))
+                            }
+                            _ => (replica_id, None),
+                        };
+                        let was_guest = guest;
+                        guest = user_name.trim().is_empty();
+                        guest_refused = guest && limits.limits.no_guests;
+                        let user_name = match current_user_name.clone() {
+                            _ if !guest || guest_refused => user_name,
+                            Some(name) if was_guest => name,
+                            _ => new_guest_name(&state, &replica_id).await,
+                        };
+                        let access = match doc_id_from_scoped_user_id(&replica_id).map(split_doc_id) {
+                            Some((room, doc)) if !guest_refused => access_of(&state, &room, &doc, &user_name).await,
+                            _ => None,
+                        };
+                        let welcome = ServerMessage::Welcome {
+                            limits: limits.limits,
+                            name: (guest && !guest_refused).then(|| user_name.clone()),
+                            doc: target.filter(|_| redirected_from.is_some()),
+                            redirected_from,
+                            access,
+                            motd: state.lock().await.motd.clone(),
+                        };
+                        let _ = out_tx.send(welcome.into()).await;
+                        if guest_refused {
+                            info!("[server] refusing guest {}", replica_id);
+                            current_user_name = None;
+                            continue;
+                        }
+                        // A joined user saying hello again under another
+                        // name renamed themselves.
+                        let renamed_from = current_user_name
+                            .clone()
+                            .filter(|old| *old != user_name)
+                            .filter(|_| {
+                                current_room.is_some()
+                                    && current_user_id.as_deref() == Some(replica_id.as_str())
+                            });
+                        current_user_id = Some(replica_id.clone());
+                        current_user_name = Some(user_name.clone());
+                        if let Some(old) = renamed_from {
+                            let mut guard = state.lock().await;
+                            let Some(user) = guard.users.get_mut(&replica_id) else {
+                                continue;
+                            };
+                            user.name = user_name.clone();
+                            user.guest = guest;
+                            let event = user.event(UserEventKind::Renamed, Some(old.clone()));
+                            let (room, doc) = (user.room.clone(), user.doc.clone());
+                            let kind = ActivityKind::User {
+                                kind: UserEventKind::Renamed,
+                                detail: Some(old.clone()),
+                            };
+                            guard.record_activity(&room, &doc, &user_name, kind);
+                            presence_changed(&state, &mut guard, &room, &doc);
+                            drop(guard);
+                            info!("[server] {} renamed to {}", old, user_name);
+                            let _ = events_tx.send(event);
+                        }
+                    }
+                    Message::SyncRequest { document_id, .. } => {
+                        // Each is a copy of the whole document; a client
+                        // asking over and over waits its turn.
+                        let wait = limits.take_sync(Instant::now());
+                        if !wait.is_zero() {
+                            tokio::time::sleep(wait).await;
+                        }
+                        // Asked for under the name the hello was redirected from.
+                        let mut document_id = match &redirect {
+                            Some((from, to)) if *from == document_id => to.clone(),
+                            _ => document_id,
+                        };
+                        if guest_refused {
+                            let why = "this server takes no guests; pick a name with --user";
+                            if let Ok(refusal) = encode_sync_error(&document_id, why) {
+                                let _ = out_tx.send(refusal.into()).await;
+                            }
+                            continue;
+                        }
+                        if current_user_id.is_none() || current_user_name.is_none() {
+                            continue;
+                        }
+
+                        if doc_id_from_scoped_user_id(current_user_id.as_deref().unwrap())
+                            != Some(document_id.as_str())
+                        {
+                            println!(
+                                "[server] replica_id not scoped to document: {}",
+                                document_id
+                            );
+                            continue;
+                        }
+
+                        let (room, mut doc) = split_doc_id(&document_id);
+                        let valid = state.lock().await.storage.validate(&room, &doc);
+                        if let Err(err) = valid {
+                            info!("[server] refusing join of {}: {}", document_id, err);
+                            if let Ok(refusal) = encode_sync_error(&document_id, &err.to_string()) {
+                                let _ = out_tx.send(refusal.into()).await;
+                            }
+                            continue;
+                        }
+                        let (mut guard, joined) = match lock_joined(&state, &room, &doc).await {
+                            Ok(joined) => joined,
+                            Err(err) => {
+                                println!("[server] can't serve {}: {}", document_id, err);
+                                let why = format!("document unavailable: {}", err);
+                                if let Ok(refusal) = encode_sync_error(&document_id, &why) {
+                                    let _ = out_tx.send(refusal.into()).await;
+                                }
+                                continue;
+                            }
+                        };
+                        let user_name = current_user_name.clone().unwrap();
+                        // Sent after the join's answer.
+                        let mut warning = None;
+                        if joined.doc != doc {
+                            // `--doc-names merge`: the doc by this name in
+                            // another case is joined, as if the hello had
+                            // been redirected to it.
+                            let to_id = doc_key(&room, &joined.doc);
+                            info!("[server] {} joins {} for {}", user_name, to_id, doc);
+                            current_user_id = current_user_id.map(|id| rescoped(&id, &to_id));
+                            let acl = &guard.docs[&to_id].meta.acl;
+                            let welcome = ServerMessage::Welcome {
+                                limits: limits.limits,
+                                name: None,
+                                doc: Some(joined.doc.clone()),
+                                redirected_from: Some(doc.clone()),
+                                access: Some(acl.access(&user_name)),
+                                motd: None,
+                            };
+                            let _ = out_tx.send(welcome.into()).await;
+                            redirect = Some((document_id, to_id.clone()));
+                            (document_id, doc) = (to_id, joined.doc);
+                        } else if let Some(twin) = &joined.twin {
+                            println!("[server] {}/{} and {}/{} differ only in case", room, doc, room, twin);
+                            warning = Some(ServerMessage::Notice {
+                                room: room.clone(),
+                                doc: doc.clone(),
+                                text: names::warning(&doc, twin),
+                            });
+                        }
+                        let doc_state = guard.docs.get_mut(&doc_key(&room, &doc)).expect("doc is loaded");
+                        if doc_state.meta.acl.access(&user_name) == Access::Denied {
+                            drop(guard);
+                            info!("[server] {} may not read {}", user_name, document_id);
+                            let why = format!("{} may not read this document", user_name);
+                            if let Ok(refusal) = encode_sync_error(&document_id, &why) {
+                                let _ = out_tx.send(refusal.into()).await;
+                            }
+                            continue;
+                        }
+                        let (doc_text, doc_version) = (doc_state.doc.get_text(), doc_state.version);
+                        let (policy, acl) = (doc_state.meta.whitespace, doc_state.meta.acl.clone());
+                        let seeded_from_template = std::mem::take(&mut doc_state.seeded);
+                        doc_state.rotated_users.remove(current_user_id.as_deref().unwrap());
+                        current_room = Some(room.clone());
+                        current_doc = Some(doc.clone());
+
+                        let user_id = current_user_id.clone().unwrap();
+                        let user_state = UserState {
+                            id: user_id.clone(),
+                            name: user_name.clone(),
+                            room: room.clone(),
+                            doc: doc.clone(),
+                            joined: Instant::now(),
+                            guest,
+                            kick: Arc::clone(&kick),
+                        };
+                        let joined = user_state.event(UserEventKind::Joined, None);
+                        // A `/sync` asks again on the same connection.
+                        let rejoined = guard.users.insert(user_id, user_state).is_some();
+                        if !rejoined {
+                            let kind = ActivityKind::User {
+                                kind: UserEventKind::Joined,
+                                detail: None,
+                            };
+                            guard.record_activity(&room, &doc, &user_name, kind);
+                        }
+                        // The feed so far; entries after it arrive as notices.
+                        let feed = &guard.docs[&doc_key(&room, &doc)].activity;
+                        activity_seen = feed.back().map_or(0, |entry| entry.seq);
+                        let activity: Vec<ActivityEntry> = match subscriptions.contains(&Subscription::Activity) {
+                            true => feed.iter().cloned().collect(),
+                            false => Vec::new(),
+                        };
+
+                        // Announced to the others once the window is over,
+                        // unless it's a rejoin that changed nothing.
+                        presence_changed(&state, &mut guard, &room, &doc);
+                        let presence_seq = guard.docs[&doc_key(&room, &doc)].presence.seq();
+                        let users = users_in_doc(&guard.users, &room, &doc);
+                        let sync = encode_sync_response(
+                            &document_id,
+                            &doc_text,
+                            users,
+                            presence_seq,
+                            doc_version,
+                        );
+                        match sync {
+                            Ok(sync) => {
+                                let _ = out_tx.send(sync.into()).await;
+                            }
+                            Err(err) => {
+                                println!("[server] failed to encode sync response: {}", err);
+                            }
+                        }
+                        if let Some(warning) = warning {
+                            let _ = out_tx.send(warning.into()).await;
+                        }
+                        if !policy.is_off() || !acl.is_open() || seeded_from_template {
+                            let info = ServerMessage::DocInfo {
+                                room: room.clone(),
+                                doc: doc.clone(),
+                                policy,
+                                acl,
+                                seeded_from_template,
+                            };
+                            let _ = out_tx.send(info.into()).await;
+                        }
+                        drop(guard);
+                        for entry in activity {
+                            let msg = ServerMessage::Activity {
+                                room: room.clone(),
+                                doc: doc.clone(),
+                                entry,
+                            };
+                            let _ = out_tx.send(msg.into()).await;
+                        }
+
+                        if !rejoined {
+                            let _ = events_tx.send(joined);
+                        }
+                    }
+                    Message::Update { .. } => {
+                        let handled = handle_update(
+                            &state,
+                            &broadcast_tx,
+                            current_user_id.as_deref(),
+                            current_room.as_deref(),
+                            current_doc.as_deref(),
+                            &msg,
+                            &mut limits,
+                        )
+                        .await;
+                        if let Err(error) = handled {
+                            let _ = out_tx.send(ServerMessage::Rejected { error }.into()).await;
+                        }
+                    }
+                    Message::Presence {
+                        user_id,
+                        document_id,
+                        cursor_pos,
+                    } => {
+                        if let (Some(current_id), Some(room), Some(doc)) = (
+                            current_user_id.as_deref(),
+                            current_room.as_deref(),
+                            current_doc.as_deref(),
+                        ) {
+                            if user_id != current_id {
+                                println!("[server] ignoring spoofed presence for {}", user_id);
+                                continue;
+                            }
+                            if document_id != doc_key(room, doc) {
+                                continue;
+                            }
+                            let mut guard = state.lock().await;
+                            let Some(doc_state) = guard.docs.get_mut(&document_id) else {
+                                continue;
+                            };
+                            // Moves go out as cursor updates, which say
+                            // where the cursor is in lines and columns.
+                            let relayed = match cursor_pos {
+                                Some(pos) => doc_state.move_cursor(&document_id, &user_id, pos),
+                                None => {
+                                    doc_state.cursors.remove(&user_id);
+                                    Some(Message::Presence {
+                                        user_id,
+                                        document_id,
+                                        cursor_pos,
+                                    })
+                                }
+                            };
+                            drop(guard);
+                            if let Some(msg) = relayed {
+                                let _ = broadcast_tx.send(msg);
+                            }
+                        }
+                    }
+                    Message::SyncResponse { .. } => {}
+                    Message::Ping => {
+                        let _ = out_tx.send(Message::Pong.into()).await;
+                    }
+                    Message::Ack { .. } | Message::Pong => {}
+                }
+            }
+            () = limits::idle(limits.idle_at()) => {
+                info!("[server] closing idle connection of {} from {}", current_user_name.as_deref().unwrap_or("unknown user"), peer);
+                let idle_secs = limits.limits.idle_timeout_secs.unwrap_or_default();
+                leaving = (UserEventKind::Kicked, Some(format!("idle for {}s", idle_secs)));
+                break;
+            }
+            () = kick.notified() => {
+                info!("[server] kicking {} from {}", current_user_name.as_deref().unwrap_or("unknown user"), peer);
+                leaving = (UserEventKind::Kicked, Some("kicked by an admin".to_string()));
+                break;
+            }
+            event = broadcast_rx.recv() => {
+                if let Ok(event) = event
+                    && should_forward(&event, current_room.as_deref(), current_doc.as_deref())
+                {
+                    let event = Outgoing::from(event);
+                    let key = outbound::low_priority_key(&event);
+                    if sync_subscription(&event, key.is_some()).is_some_and(|kind| !subscriptions.contains(&kind)) {
+                        continue;
+                    }
+                    match key {
+                        Some(key) => low_lane.push(key, event),
+                        None => {
+                            let _ = out_tx.send(event).await;
+                        }
+                    }
+                }
+            }
+            event = events_rx.recv() => {
+                if let Ok(event) = event
+                    && should_forward_notice(&event, current_user_id.as_deref(), current_room.as_deref(), current_doc.as_deref())
+                    && !matches!(&event, ServerMessage::Activity { entry, .. } if entry.seq <= activity_seen)
+                    && notice_subscription(&event).is_none_or(|kind| subscriptions.contains(&kind))
+                {
+                    // Someone taken off the readers leaves.
+                    if let ServerMessage::DocInfo { room, doc, acl, .. } = &event
+                        && let Some(name) = current_user_name.as_deref()
+                        && acl.access(name) == Access::Denied
+                    {
+                        info!("[server] {} may no longer read {}/{}", name, room, doc);
+                        leaving = (UserEventKind::Kicked, Some("no longer allowed to read".to_string()));
+                        break;
+                    }
+                    // The server moved the user along with the document.
+                    if let ServerMessage::DocRenamed { room, to, .. } = &event {
+                        let to_key = doc_key(room, to);
+                        current_user_id = current_user_id.map(|id| rescoped(&id, &to_key));
+                        current_doc = Some(to.clone());
+                    }
+                    let _ = out_tx.send(event.into()).await;
+                }
+            }
+        }
+    }
+
+    if let Some(user_id) = current_user_id {
+        let mut guard = state.lock().await;
+        let user = guard.users.remove(&user_id);
+        if let Some(user) = &user {
+            let (kind, detail) = leaving.clone();
+            let kind = ActivityKind::User { kind, detail };
+            guard.record_activity(&user.room, &user.doc, &user.name, kind);
+            presence_changed(&state, &mut guard, &user.room, &user.doc);
+        }
+        drop(guard);
+        if let Some(user) = user {
+            let (kind, detail) = leaving;
+            let _ = events_tx.send(user.event(kind, detail));
+        }
+    }
+
+    if violated {
+        // Let the writer send the error, but not wait on a client that
+        // doesn't read.
+        drop(out_tx);
+        let _ = tokio::time::timeout(Duration::from_secs(1), &mut writer_task).await;
+    }
+    writer_task.abort();
+    let user = current_user_name.as_deref().unwrap_or("no user");
+    let line = format!("[server] {} disconnected ({})", peer, user);
+    logging::peer_info("disconnected", peer, &line);
+    Ok(())
+}
+
+/// Who wrote how much of `room`/`doc`, busiest first. A document nobody
+/// has open is read from storage rather than loaded.
+async fn doc_stats(state: &Mutex<SharedState>, room: &str, doc: &str) -> Vec<UserStats> {
+    let storage = {
+        let guard = state.lock().await;
+        if let Some(doc_state) = guard.docs.get(&doc_key(room, doc)) {
+            return by_activity(doc_state.meta.contributions.clone());
+        }
+        Arc::clone(&guard.storage)
+    };
+    if storage.validate(room, doc).is_err() {
+        return Vec::new();
+    }
+    match storage.load(room, doc).await {
+        Ok(stored) => by_activity(stored.meta.contributions),
+        Err(err) => {
+            println!("[server] no stats for {}/{}: {}", room, doc, err);
+            Vec::new()
+        }
+    }
+}
+
+/// Rooms for `ClientMessage::ListRooms`: those in storage and those with
+/// users in documents not saved yet.
+async fn list_rooms(state: &Mutex<SharedState>) -> Vec<String> {
+    let (storage, mut rooms) = {
+        let guard = state.lock().await;
+        let joined: BTreeSet<String> = guard.users.values().map(|user| user.room.clone()).collect();
+        (Arc::clone(&guard.storage), joined)
+    };
+    match storage.list_rooms().await {
+        Ok(stored) => rooms.extend(stored),
+        Err(err) => println!("[server] listing rooms failed: {}", err),
+    }
+    rooms.into_iter().collect()
+}
+
+/// Documents of `room` for `ClientMessage::ListDocs`, likewise.
+async fn list_docs(state: &Mutex<SharedState>, room: &str) -> Vec<String> {
+    let (storage, mut docs) = {
+        let guard = state.lock().await;
+        let joined: BTreeSet<String> = guard
+            .users
+            .values()
+            .filter(|user| user.room == room)
+            .map(|user| user.doc.clone())
+            .collect();
+        (Arc::clone(&guard.storage), joined)
+    };
+    match storage.list(room).await {
+        Ok(stored) => docs.extend(stored.into_iter().map(|entry| entry.name)),
+        // A name no room could have.
+        Err(err) if err.kind() == io::ErrorKind::InvalidInput => {}
+        Err(err) => println!("[server] listing docs of {} failed: {}", room, err),
+    }
+    docs.into_iter().collect()
+}
+
+fn by_activity(mut stats: Vec<UserStats>) -> Vec<UserStats> {
+    stats.sort_by(|a, b| {
+        let written = |stats: &UserStats| stats.inserted_bytes + stats.deleted_bytes;
+        written(b)
+            .cmp(&written(a))
+            .then_with(|| a.user.cmp(&b.user))
+    });
+    stats
+}
+
+/// Streams every document of `room` for `ClientMessage::ExportRoom`, after
+/// saving pending edits so that the export has them. Documents that fail to
+/// load are reported without stopping the export; those `user` may not
+/// read, by the same rule as `check_read`, are left out.
+async fn export_room(
+    state: &Mutex<SharedState>,
+    room: &str,
+    user: Option<&str>,
+    out_tx: &mpsc::Sender<Outgoing>,
+) {
+    let result: Result<usize, String> = async {
+        persistence::flush_all(state)
+            .await
+            .map_err(|err| format!("saving pending edits failed: {}", err))?;
+        let storage = Arc::clone(&state.lock().await.storage);
+        let entries = storage.list(room).await.map_err(|err| err.to_string())?;
+        let mut count = 0;
+        for entry in &entries {
+            let doc = ExportedDoc::load(&*storage, room, entry.name.clone()).await;
+            if let Ok((_, meta)) = &doc.content
+                && meta.acl.access(user.unwrap_or("")) == Access::Denied
+            {
+                continue;
+            }
+            for msg in doc.into_messages() {
+                let _ = out_tx.send(msg.into()).await;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+    .await;
+    match &result {
+        Ok(count) => info!("[server] exported {} documents of room {}", count, room),
+        Err(err) => println!("[server] export of room {} failed: {}", room, err),
+    }
+    let done = ServerMessage::ExportDone {
+        error: result.err(),
+    };
+    let _ = out_tx.send(done.into()).await;
+}
+
+async fn handle_update(
+    state: &Arc<Mutex<SharedState>>,
+    broadcast_tx: &broadcast::Sender<Message>,
+    current_user_id: Option<&str>,
+    room: Option<&str>,
+    doc: Option<&str>,
+    msg: &Message,
+    limits: &mut ConnectionLimits,
+) -> Result<(), String> {
+    if current_user_id.is_none() {
+        return Ok(());
+    }
+    let Some(room) = room else {
+        return Ok(());
+    };
+    let Some(doc) = doc else {
+        return Ok(());
+    };
+
+    let Some((document_id, payload, _)) = decode_update(msg) else {
+        return Ok(());
+    };
+    match current_user_id {
+        Some(current_id) if payload.user_id != current_id => {
+            println!("[server] ignoring spoofed update for {}", payload.user_id);
+            return Ok(());
+        }
+        _ => {}
+    }
+    if document_id != doc_key(room, doc) {
+        return Ok(());
+    }
+    if matches!(payload.op, Op::Insert { .. } | Op::Delete { .. }) {
+        limits.take_op(Instant::now())?;
+    }
+
+    let mut guard = match lock_loaded(state, room, doc).await {
+        Ok(guard) => guard,
+        Err(err) => {
+            println!("[server] dropping update to {}: {}", document_id, err);
+            return Ok(());
+        }
+    };
+    let doc_key = doc_key(room, doc);
+    if matches!(payload.op, Op::Insert { .. } | Op::Delete { .. }) {
+        let name = guard
+            .users
+            .get(&payload.user_id)
+            .map_or(payload.user_id.as_str(), |user| user.name.as_str());
+        let access = guard
+            .docs
+            .get(&doc_key)
+            .map(|doc_state| doc_state.meta.acl.access(name));
+        if access != Some(Access::Write) {
+            return Err(format!("forbidden: {} may not edit {}", name, doc_key));
+        }
+        let rotated = guard
+            .docs
+            .get(&doc_key)
+            .and_then(|doc_state| doc_state.rotated_users.get(&payload.user_id));
+        if let Some(to) = rotated {
+            return Err(format!("{} was moved to {}; edit dropped", doc_key, to));
+        }
+    }
+    if limits.limits.max_doc_bytes.is_some()
+        && let Op::Insert { .. } = payload.op
+        && let Some(doc_state) = guard.docs.get(&doc_key)
+    {
+        limits.limits.check_update(doc_state.text.byte_len(), msg)?;
+    }
+
+    if let Op::Cursor { pos, .. } = payload.op {
+        let doc_state = guard.docs.get_mut(&doc_key).expect("doc exists");
+        let relayed = doc_state.move_cursor(&doc_key, &payload.user_id, pos);
+        drop(guard);
+        if let Some(update) = relayed {
+            let _ = broadcast_tx.send(update);
+        }
+        return Ok(());
+    }
+    if let Op::Selection { .. } = payload.op {
+        // Selections don't touch the text, so they are only relayed.
+        let version = guard
+            .docs
+            .get(&doc_key)
+            .map_or(0, |doc_state| doc_state.version);
+        drop(guard);
+        if let Ok(update) =
+            encode_update(&doc_key, &payload.user_id, payload.op, Vec::new(), version)
+        {
+            let _ = broadcast_tx.send(update);
+        }
+        return Ok(());
+    }
+
+    let (version, op, delta) = {
+        let doc_state = guard.docs.get_mut(&doc_key).expect("doc exists");
+        let mut op = payload.op;
+        resolve_positions(&doc_state.text, &mut op);
+        apply_op_to_doc(doc_state, &payload.user_id, &op);
+        let delta = Vec::new();
+        doc_state.version += 1;
+        (doc_state.version, op, delta)
+    };
+
+    let editor = guard
+        .users
+        .get(&payload.user_id)
+        .map_or_else(|| payload.user_id.clone(), |user| user.name.clone());
+    let SharedState {
+        docs,
+        history,
+        persistence,
+        growth,
+        ..
+    } = &mut *guard;
+    let doc_state = docs.get_mut(&doc_key).expect("doc exists");
+    doc_state.meta.version = version;
+    doc_state.note_op();
+    let (inserted, deleted) = match &op {
+        Op::Insert { text, .. } => (text.len(), 0),
+        Op::Delete { len, .. } => (0, *len),
+        Op::Cursor { .. } | Op::Selection { .. } => (0, 0),
+    };
+    doc_state.meta.record_edit(&editor, inserted, deleted);
+    doc_state.meta.last_editor = Some(editor.clone());
+    let (bytes, lines) = (doc_state.text.byte_len(), doc_state.text.line_count());
+    let crossed = growth.crossed(bytes, lines, &doc_state.meta.warned);
+    doc_state.meta.warned.extend(&crossed);
+    let revision = doc_state.revision_due(history);
+    if revision {
+        doc_state.last_revision = (version, Instant::now());
+    }
+    persistence.mark_dirty(room, doc, version, revision);
+    if deleted > BIG_DELETE_BYTES {
+        let kind = ActivityKind::Deleted { bytes: deleted };
+        guard.record_activity(room, doc, &editor, kind);
+    }
+    drop(guard);
+
+    match encode_update(&doc_key, &payload.user_id, op, delta, version) {
+        Ok(update) => {
+            let _ = broadcast_tx.send(update);
+        }
+        Err(err) => {
+            println!("[server] failed to encode update: {}", err);
+        }
+    }
+    if !crossed.is_empty() {
+        doc_grew(state, room, doc, &crossed).await;
+    }
+    Ok(())
+}
+
+/// Tells `room` that `doc` grew past the sizes in `crossed`, after
+/// rotating it under `--auto-rotate`.
+async fn doc_grew(state: &Arc<Mutex<SharedState>>, room: &str, doc: &str, crossed: &[Threshold]) {
+    let growth = state.lock().await.growth;
+    let size = growth.describe(crossed);
+    let rotated = match growth.auto_rotate {
+        true => rotate_doc(state, room, doc).await,
+        false => None,
+    };
+    let text = match rotated {
+        Some(to) => format!(
+            "{} is {}, so it was moved to {}; {} starts over empty",
+            doc, size, to, doc
+        ),
+        None => format!("{} is {}; consider moving on to a new document", doc, size),
+    };
+    info!("[server] {}: {}", room, text);
+    let notice = ServerMessage::Notice {
+        room: room.to_string(),
+        doc: doc.to_string(),
+        text,
+    };
+    let _ = state.lock().await.notices.send(notice);
+}
+
+/// Moves `room`/`doc` to its name with today's date, for `--auto-rotate`,
+/// taking its users along, and puts an empty document with the same
+/// whitespace policy and ACL in its place. That one counts on from the old
+/// version, so their revisions don't mix. Returns the new name, or `None`
+/// if no free one was found.
+async fn rotate_doc(state: &Arc<Mutex<SharedState>>, room: &str, doc: &str) -> Option<String> {
+    let storage = Arc::clone(&state.lock().await.storage);
+    let date = templates::utc_date(SystemTime::now());
+    let mut free = None;
+    for attempt in 0..growth::NAME_ATTEMPTS {
+        let name = growth::rotated_name(doc, &date, attempt);
+        if storage.validate(room, &name).is_err() {
+            break;
+        }
+        let open = state.lock().await.docs.contains_key(&doc_key(room, &name));
+        if !open
+            && storage
+                .load(room, &name)
+                .await
+                .is_ok_and(|stored| stored.is_new())
+        {
+            free = Some(name);
+            break;
+        }
+    }
+    let Some(to) = free else {
+        println!("[server] no free name to rotate {}/{} to", room, doc);
+        return None;
+    };
+
+    let (from_key, to_key) = (doc_key(room, doc), doc_key(room, &to));
+    let mut guard = state.lock().await;
+    if guard.docs.contains_key(&to_key) {
+        return None;
+    }
+    let mut moved = guard.docs.remove(&from_key)?;
+    moved.doc = build_doc(&to_key, "server", &moved.doc.get_text());
+    moved.cursors = std::mem::take(&mut moved.cursors)
+        .into_iter()
+        .map(|(id, pos)| (rescoped(&id, &to_key), pos))
+        .collect();
+    let meta = DocMeta {
+        version: moved.version,
+        whitespace: moved.meta.whitespace,
+        acl: moved.meta.acl.clone(),
+        ..DocMeta::default()
+    };
+    let version = moved.version;
+    let mut fresh = DocState::new(build_doc(&from_key, "server", ""), meta);
+    let ids: Vec<String> = guard
+        .users
+        .values()
+        .filter(|user| user.room == room && user.doc == doc)
+        .map(|user| user.id.clone())
+        .collect();
+    for id in ids {
+        let mut user = guard.users.remove(&id).expect("listed just now");
+        user.id = rescoped(&id, &to_key);
+        user.doc = to.clone();
+        fresh.rotated_users.insert(id, to.clone());
+        guard.users.insert(user.id.clone(), user);
+    }
+    guard.docs.insert(to_key, moved);
+    guard.docs.insert(from_key, fresh);
+    guard.persistence.mark_dirty(room, &to, version, true);
+    guard.persistence.mark_meta_dirty(room, doc, version);
+    info!("[storage] rotated {}/{} to {}", room, doc, to);
+    let _ = guard.notices.send(ServerMessage::DocRenamed {
+        room: room.to_string(),
+        from: doc.to_string(),
+        to: to.clone(),
+    });
+    presence_changed(state, &mut guard, room, &to);
+    Some(to)
+}
+
+/// `user_id`, a user id scoped to a document, scoped to `doc_id` instead.
+fn rescoped(user_id: &str, doc_id: &str) -> String {
+    let raw_id = user_id.split_once('|').map_or(user_id, |(_, raw)| raw);
+    make_scoped_user_id(doc_id, raw_id)
+}
+
+fn should_forward(msg: &Message, room: Option<&str>, doc: Option<&str>) -> bool {
+    let Some(room) = room else {
+        return false;
+    };
+    let Some(doc) = doc else {
+        return false;
+    };
+    let doc_id = doc_key(room, doc);
+    match msg {
+        Message::Hello { replica_id, .. } => {
+            doc_id_from_scoped_user_id(replica_id) == Some(doc_id.as_str())
+        }
+        Message::Update { document_id, .. } => document_id == &doc_id,
+        Message::Presence { document_id, .. } => document_id == &doc_id,
+        Message::SyncResponse { document_id, .. } => document_id == &doc_id,
+        Message::Ack { .. } | Message::Ping | Message::Pong | Message::SyncRequest { .. } => false,
+    }
+}
+
+/// The subscription sync traffic for a client belongs to, if any; cursor
+/// moves and selections are what is `low_priority`. Sync responses belong
+/// to none and are always sent.
+fn sync_subscription(event: &Outgoing, low_priority: bool) -> Option<Subscription> {
+    match event {
+        _ if low_priority => Some(Subscription::Cursors),
+        Outgoing::Sync(Message::Update { .. }) => Some(Subscription::Edits),
+        Outgoing::Sync(Message::Hello { .. }) => Some(Subscription::Presence),
+        _ => None,
+    }
+}
+
+/// The subscription a notice belongs to, if any; the rest, like a
+/// `DocInfo` or `DocRenamed`, are always sent.
+fn notice_subscription(event: &ServerMessage) -> Option<Subscription> {
+    match event {
+        ServerMessage::UserEvent { .. } | ServerMessage::Members { .. } => {
+            Some(Subscription::Presence)
+        }
+        ServerMessage::Activity { .. } => Some(Subscription::Activity),
+        _ => None,
+    }
+}
+
+/// Whether a notice is for the user `user_id` of `room`/`doc`: a
+/// `UserEvent` about someone else there, a `DocInfo`, `Members` or
+/// `Activity` of it, its `DocRenamed`, or a `Notice` of its room or for
+/// the whole server.
+fn should_forward_notice(
+    event: &ServerMessage,
+    user_id: Option<&str>,
+    room: Option<&str>,
+    doc: Option<&str>,
+) -> bool {
+    match event {
+        ServerMessage::UserEvent {
+            room: event_room,
+            doc: event_doc,
+            user,
+            ..
+        } => {
+            room == Some(event_room.as_str())
+                && doc == Some(event_doc.as_str())
+                && user_id != Some(user.id.as_str())
+        }
+        ServerMessage::DocInfo {
+            room: info_room,
+            doc: info_doc,
+            ..
+        }
+        | ServerMessage::Members {
+            room: info_room,
+            doc: info_doc,
+            ..
+        }
+        | ServerMessage::Activity {
+            room: info_room,
+            doc: info_doc,
+            ..
+        } => room == Some(info_room.as_str()) && doc == Some(info_doc.as_str()),
+        ServerMessage::DocRenamed {
+            room: renamed_room,
+            from,
+            ..
+        } => room == Some(renamed_room.as_str()) && doc == Some(from.as_str()),
+        ServerMessage::Notice {
+            room: notice_room, ..
+        } => notice_room.is_empty() || room == Some(notice_room.as_str()),
+        _ => false,
+    }
+}
+
+/// Notes that people joined, left or were renamed in `room`/`doc`. Their
+/// list is sent to them `presence::WINDOW` after the first such change,
+/// covering those that come in meanwhile.
+fn presence_changed(
+    state: &Arc<Mutex<SharedState>>,
+    guard: &mut SharedState,
+    room: &str,
+    doc: &str,
+) {
+    let Some(doc_state) = guard.docs.get_mut(&doc_key(room, doc)) else {
+        return;
+    };
+    if !doc_state.presence.changed() {
+        return;
+    }
+    let (state, room, doc) = (Arc::clone(state), room.to_string(), doc.to_string());
+    tokio::spawn(async move {
+        tokio::time::sleep(presence::WINDOW).await;
+        state.lock().await.flush_presence(&room, &doc);
+    });
+}
+
+/// A name for the guest `replica_id` that nobody else in its room goes by.
+async fn new_guest_name(state: &Mutex<SharedState>, replica_id: &str) -> String {
+    let (doc_id, raw_id) = replica_id.split_once('|').unwrap_or(("", replica_id));
+    let (room, _) = split_doc_id(doc_id);
+    let guard = state.lock().await;
+    // The same client in another document of the room may keep its name.
+    let names: HashSet<&str> = guard
+        .users
+        .values()
+        .filter(|u| u.room == room && !u.id.ends_with(&format!("|{}", raw_id)))
+        .map(|u| u.name.as_str())
+        .collect();
+    let name = guests::guest_name(raw_id, |name| names.contains(name));
+    info!("[server] guest {} is {}", replica_id, name);
+    name
+}
+
+fn users_in_doc(users: &HashMap<String, UserState>, room: &str, doc: &str) -> Vec<WireUser> {
+    users
+        .values()
+        .filter(|u| u.room == room && u.doc == doc)
+        .map(|u| WireUser {
+            id: u.id.clone(),
+            name: u.name.clone(),
+            guest: u.guest,
+        })
+        .collect()
+}
+
+fn doc_key(room: &str, doc: &str) -> String {
+    format!("{}/{}", room, doc)
+}
+
+/// Gives a text op about to be applied to `text` both its byte and its
+/// character positions there. Where it has characters, the bytes are
+/// worked out from them; otherwise the bytes are kept as they came, and
+/// clamped like `apply_op_to_doc` clamps them to count the characters.
+fn resolve_positions(text: &TextIndex, op: &mut Op) {
+    match op {
+        Op::Insert { pos, pos_chars, .. } => match *pos_chars {
+            Some(idx) => *pos = text.byte_of(idx),
+            None => *pos_chars = Some(text.char_of(*pos)),
+        },
+        Op::Delete {
+            pos,
+            len,
+            pos_chars,
+            len_chars,
+        } => {
+            if let Some(idx) = *pos_chars {
+                *pos = text.byte_of(idx);
+            }
+            let start = text.char_of(*pos);
+            match *len_chars {
+                Some(chars) => {
+                    *len = text
+                        .byte_of(start.saturating_add(chars))
+                        .saturating_sub(*pos);
+                }
+                None => *len_chars = Some(text.char_of(pos.saturating_add(*len)) - start),
+            }
+            *pos_chars = Some(start);
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => {}
+    }
+}
+
+fn apply_op_to_doc(doc_state: &mut DocState, user_id: &str, op: &Op) {
+    match op {
+        Op::Insert { pos, text, .. } => {
+            let char_pos = doc_state.text.insert(*pos, text);
+            doc_state.doc.insert(char_pos, text);
+        }
+        Op::Delete { pos, len, .. } => {
+            if let Some((char_start, char_len)) = doc_state.text.delete(*pos, *len) {
+                doc_state.doc.delete(char_start, char_len);
+            }
+        }
+        Op::Cursor { pos, .. } => {
+            let clamped = doc_state.text.clamp(*pos);
+            doc_state.cursors.insert(user_id.to_string(), clamped);
+        }
+        Op::Selection { .. } => {}
+    }
+}
+
+fn split_doc_id(document_id: &str) -> (String, String) {
+    match document_id.split_once('/') {
+        Some((room, doc)) => (room.to_string(), doc.to_string()),
+        None => ("default".to_string(), document_id.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::{
+        LineCol, WireSync, decode_sync_response, encode_sync_request, make_scoped_user_id,
+    };
+    use crate::storage::{FileSystem, RealFs};
+    use std::io;
+    use std::path::{Path, PathBuf};
+
+    const DELAY: Duration = Duration::from_millis(500);
+
+    /// Takes `DELAY` over every read and write of the "slow" document.
+    struct SlowFs;
+
+    impl SlowFs {
+        fn wait(path: &Path) {
+            if path.to_string_lossy().contains("slow") {
+                std::thread::sleep(DELAY);
+            }
+        }
+    }
+
+    impl FileSystem for SlowFs {
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            RealFs.create_dir_all(dir)
+        }
+
+        fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+            Self::wait(path);
+            RealFs.write(path, bytes)
+        }
+
+        fn sync_file(&self, path: &Path) -> io::Result<()> {
+            RealFs.sync_file(path)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            RealFs.sync_dir(dir)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            RealFs.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            RealFs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+            RealFs.remove_dir_all(dir)
+        }
+
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            RealFs.read_dir(dir)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<std::fs::Metadata> {
+            RealFs.metadata(path)
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            Self::wait(path);
+            RealFs.read(path)
+        }
+    }
+
+    fn insert(doc: &str, user: &str, pos: usize, text: &str) -> Message {
+        let op = Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        encode_update(&doc_key("room", doc), user, op, Vec::new(), 0).unwrap()
+    }
+
+    /// Appends "hi" to a document holding `len` bytes.
+    async fn update(
+        state: &Arc<Mutex<SharedState>>,
+        tx: &broadcast::Sender<Message>,
+        doc: &str,
+        len: usize,
+    ) {
+        let user = format!("{}-user", doc);
+        let msg = insert(doc, &user, len, "hi");
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let (user, doc) = (Some(user.as_str()), Some(doc));
+        handle_update(state, tx, user, Some("room"), doc, &msg, &mut limits)
+            .await
+            .unwrap();
+    }
+
+    /// State on a fresh `MemoryStorage`, which tests use unless they are
+    /// about the filesystem.
+    fn memory_state(storage: &Arc<MemoryStorage>) -> Arc<Mutex<SharedState>> {
+        let storage: Arc<dyn StorageBackend> = Arc::clone(storage) as _;
+        Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )))
+    }
+
+    #[tokio::test]
+    async fn edits_in_bytes_characters_or_both_land_in_the_same_place() {
+        // Each edit in bytes and in characters, building "aéb👍🏽".
+        let edits = [
+            ("Insert", (0, 0), (0, 0), "a🎉b"),
+            ("Insert", (5, 0), (2, 0), "é"),
+            ("Delete", (1, 4), (1, 1), ""),
+            ("Insert", (4, 0), (3, 0), "👍🏽"),
+        ];
+        let state = memory_state(&Arc::new(MemoryStorage::new()));
+        let (tx, mut rx) = broadcast::channel(16);
+        for (doc, bytes, chars) in [("b", true, false), ("c", false, true), ("bc", true, true)] {
+            for (kind, (pos, len), (pos_chars, len_chars), text) in edits {
+                // JSON by hand, as a client in another language would send.
+                let mut op = serde_json::Map::new();
+                if kind == "Insert" {
+                    op.insert("text".into(), text.into());
+                }
+                if bytes {
+                    op.insert("pos".into(), pos.into());
+                    if kind == "Delete" {
+                        op.insert("len".into(), len.into());
+                    }
+                }
+                if chars {
+                    op.insert("pos_chars".into(), pos_chars.into());
+                    if kind == "Delete" {
+                        op.insert("len_chars".into(), len_chars.into());
+                    }
+                }
+                let payload =
+                    serde_json::json!({ "user_id": "u", "op": { kind: op }, "delta": [] });
+                let msg = Message::Update {
+                    document_id: doc_key("room", doc),
+                    delta: serde_json::to_vec(&payload).unwrap(),
+                    version: 0,
+                };
+                let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+                let (user, doc) = (Some("u"), Some(doc));
+                handle_update(&state, &tx, user, Some("room"), doc, &msg, &mut limits)
+                    .await
+                    .unwrap();
+
+                // Passed on with both.
+                let (_, update, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+                let expected = match kind {
+                    "Insert" => Op::Insert {
+                        pos,
+                        pos_chars: Some(pos_chars),
+                        text: text.to_string(),
+                    },
+                    _ => Op::Delete {
+                        pos,
+                        len,
+                        pos_chars: Some(pos_chars),
+                        len_chars: Some(len_chars),
+                    },
+                };
+                assert_eq!(update.op, expected, "{} of {:?}", kind, doc);
+            }
+            let text = state.lock().await.docs[&doc_key("room", doc)]
+                .doc
+                .get_text();
+            assert_eq!(text, "aéb👍🏽", "{}", doc);
+        }
+
+        // Characters win over bytes that disagree, here inside the 🎉.
+        let op = Op::Insert {
+            pos: 2,
+            pos_chars: Some(2),
+            text: "!".to_string(),
+        };
+        let disagreeing = encode_update("room/both", "u", op, Vec::new(), 0).unwrap();
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        for msg in [insert("both", "u", 0, "a🎉b"), disagreeing] {
+            let (user, doc) = (Some("u"), Some("both"));
+            handle_update(&state, &tx, user, Some("room"), doc, &msg, &mut limits)
+                .await
+                .unwrap();
+        }
+        let text = state.lock().await.docs["room/both"].doc.get_text();
+        assert_eq!(text, "a🎉!b");
+    }
+
+    #[tokio::test]
+    async fn edits_are_saved_and_resumed_after_a_restart() {
+        let storage = Arc::new(MemoryStorage::new());
+        let state = memory_state(&storage);
+        let (tx, _rx) = broadcast::channel(16);
+        update(&state, &tx, "notes", 0).await;
+        update(&state, &tx, "notes", 2).await;
+        assert_eq!(persistence::flush_all(&state).await.unwrap(), 1);
+        // Moving the cursor alone leaves nothing to save.
+        let cursor = encode_update(
+            "room/notes",
+            "notes-user",
+            Op::Cursor { pos: 1, at: None },
+            Vec::new(),
+            0,
+        );
+        let (user, cursor) = (Some("notes-user"), cursor.unwrap());
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        handle_update(
+            &state,
+            &tx,
+            user,
+            Some("room"),
+            Some("notes"),
+            &cursor,
+            &mut limits,
+        )
+        .await
+        .unwrap();
+        assert_eq!(persistence::flush_all(&state).await.unwrap(), 0);
+
+        let stored = storage.load("room", "notes").await.unwrap();
+        assert_eq!(stored.text, "hihi");
+        assert_eq!(stored.meta.version, 2);
+        assert_eq!(stored.meta.last_editor.as_deref(), Some("notes-user"));
+
+        let restarted = memory_state(&storage);
+        let guard = lock_loaded(&restarted, "room", "notes").await.unwrap();
+        let doc_state = &guard.docs["room/notes"];
+        assert_eq!(
+            (doc_state.doc.get_text().as_str(), doc_state.version),
+            ("hihi", 2)
+        );
+    }
+
+    #[tokio::test]
+    async fn edits_past_the_limits_are_rejected_unapplied() {
+        let state = memory_state(&Arc::new(MemoryStorage::new()));
+        let (tx, _rx) = broadcast::channel(16);
+        update(&state, &tx, "notes", 0).await;
+        let limits = ServerLimits {
+            max_doc_bytes: Some(3),
+            max_ops_per_second: Some(1),
+            ..ServerLimits::default()
+        };
+        let mut limits = ConnectionLimits::new(limits, Instant::now());
+        let user = Some("notes-user");
+        let mut send = async |msg: Message| {
+            handle_update(
+                &state,
+                &tx,
+                user,
+                Some("room"),
+                Some("notes"),
+                &msg,
+                &mut limits,
+            )
+            .await
+        };
+
+        let error = send(insert("notes", "notes-user", 2, "!!"))
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("document too large"), "{}", error);
+        // Refused edits still use up the rate.
+        let error = send(insert("notes", "notes-user", 2, "!"))
+            .await
+            .unwrap_err();
+        assert!(error.starts_with("too many edits"), "{}", error);
+
+        let guard = state.lock().await;
+        let doc_state = &guard.docs["room/notes"];
+        assert_eq!(
+            (doc_state.doc.get_text().as_str(), doc_state.version),
+            ("hi", 1)
+        );
+    }
+
+    #[tokio::test]
+    async fn corrupt_documents_are_restored_or_not_served() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-server-corrupt-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Storage::new(&dir, SyncPolicy::Never));
+        for doc in ["notes", "lost"] {
+            let meta = DocMeta {
+                version: 3,
+                ..DocMeta::default()
+            };
+            storage
+                .save("room", doc, "good".into(), meta)
+                .await
+                .unwrap();
+            std::fs::write(dir.join("room").join(doc), "bad!").unwrap();
+        }
+        storage
+            .save_revision("room", "notes", "good".into(), 3)
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )));
+
+        let guard = lock_loaded(&state, "room", "notes").await.unwrap();
+        let doc_state = &guard.docs["room/notes"];
+        assert_eq!(
+            (doc_state.doc.get_text().as_str(), doc_state.version),
+            ("good", 3)
+        );
+        let feed: Vec<&ActivityKind> = doc_state.activity.iter().map(|e| &e.kind).collect();
+        assert_eq!(feed, [&ActivityKind::Restored]);
+        drop(guard);
+        assert!(lock_loaded(&state, "room", "lost").await.is_err());
+        assert!(!state.lock().await.docs.contains_key("room/lost"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn revisions_are_listed_and_restored_as_edits() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-server-history-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Storage::new(&dir, SyncPolicy::Never));
+        storage
+            .save_revision("room", "notes", "first draft".into(), 2)
+            .await
+            .unwrap();
+        let state = Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )));
+        let tx = state.lock().await.updates.clone();
+        let mut rx = tx.subscribe();
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let edit = insert("notes", "ada", 0, "a later text");
+        let (ada, room, doc) = (Some("ada"), Some("room"), Some("notes"));
+        handle_update(&state, &tx, ada, room, doc, &edit, &mut limits)
+            .await
+            .unwrap();
+        let mut client = DocState::new(TextDoc::new("room/notes", "client"), DocMeta::default());
+        let (_, payload, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+        apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+        let (version, _) = take_snapshot(&state, "room", "notes", "bob").await.unwrap();
+        assert_eq!(version, 1);
+
+        let revisions = list_revisions(&state, "room", "notes").await;
+        let mut listed: Vec<(u64, Option<&str>)> = revisions
+            .iter()
+            .map(|revision| (revision.version, revision.by.as_deref()))
+            .collect();
+        // Both were saved within the same second.
+        listed.sort();
+        assert_eq!(listed, [(1, Some("bob")), (2, None)]);
+        assert!(list_revisions(&state, "room", "other").await.is_empty());
+
+        let restored = restore_revision(&state, &tx, "room", "notes", 2, "cy").await;
+        let now = restored.unwrap();
+        assert!(now > version);
+        while let Ok(update) = rx.try_recv() {
+            let (_, payload, _) = decode_update(&update).unwrap();
+            assert_eq!(payload.user_id, DISK_USER);
+            apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+        }
+        assert_eq!(client.doc.get_text(), "first draft");
+        let guard = state.lock().await;
+        let doc_state = &guard.docs["room/notes"];
+        assert_eq!(doc_state.version, now);
+        let last = doc_state.activity.back().unwrap();
+        assert_eq!(
+            (last.user.as_str(), &last.kind),
+            ("cy", &ActivityKind::Reverted { version: 2 })
+        );
+        drop(guard);
+        let missing = restore_revision(&state, &tx, "room", "notes", 99, "cy").await;
+        assert!(missing.unwrap_err().contains("no revision 99"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn deleted_documents_come_back_beside_or_over_open_ones() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-server-trash-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Storage::new(&dir, SyncPolicy::Never));
+        storage
+            .save("room", "notes", "deleted text".into(), DocMeta::default())
+            .await
+            .unwrap();
+        storage.delete("room", "notes").await.unwrap();
+        let state = Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )));
+        let tx = state.lock().await.updates.clone();
+        let mut rx = tx.subscribe();
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let edit = insert("notes", "ada", 0, "a new text");
+        let (ada, room, doc) = (Some("ada"), Some("room"), Some("notes"));
+        handle_update(&state, &tx, ada, room, doc, &edit, &mut limits)
+            .await
+            .unwrap();
+        let mut client = DocState::new(TextDoc::new("room/notes", "client"), DocMeta::default());
+        let (_, payload, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+        apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+
+        let trashed = list_trash(&state, "room").await.unwrap();
+        assert_eq!((trashed[0].name.as_str(), trashed[0].bytes), ("notes", 12));
+        let open = restore_trashed(&state, &tx, "room", "notes", "notes", false, "cy").await;
+        assert_eq!(open.unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(list_trash(&state, "room").await.unwrap().len(), 1);
+
+        restore_trashed(&state, &tx, "room", "notes", "notes", true, "cy")
+            .await
+            .unwrap();
+        while let Ok(update) = rx.try_recv() {
+            let (_, payload, _) = decode_update(&update).unwrap();
+            assert_eq!(payload.user_id, DISK_USER);
+            apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+        }
+        assert_eq!(client.doc.get_text(), "deleted text");
+        assert!(list_trash(&state, "room").await.unwrap().is_empty());
+        let gone = restore_trashed(&state, &tx, "room", "notes", "old", false, "cy").await;
+        assert_eq!(gone.unwrap_err().kind(), io::ErrorKind::NotFound);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn files_edited_on_disk_reach_connected_clients() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-server-watch-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        let state = Arc::new(Mutex::new(SharedState::new(
+            Arc::new(storage.clone()),
+            HistoryPolicy::default(),
+        )));
+        let (tx, mut rx) = broadcast::channel(16);
+        tokio::spawn(run_watch_loop(
+            storage,
+            dir.clone(),
+            Arc::clone(&state),
+            tx.clone(),
+        ));
+
+        // A client edits the document and the server saves it; its own
+        // save isn't taken for an outside change.
+        update(&state, &tx, "notes", 0).await;
+        let mut client = DocState::new(TextDoc::new("room/notes", "client"), DocMeta::default());
+        let (_, payload, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+        apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+        let path = dir.join("room").join("notes");
+        persistence::flush_all(&state).await.unwrap();
+        tokio::time::sleep(WATCH_DEBOUNCE * 3).await;
+        assert!(rx.try_recv().is_err());
+
+        // A script appends to the file.
+        std::fs::write(&path, "hi there\n").unwrap();
+        while client.doc.get_text() != "hi there\n" {
+            let msg = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("the change is broadcast")
+                .unwrap();
+            let (_, payload, version) = decode_update(&msg).unwrap();
+            assert_eq!(payload.user_id, DISK_USER);
+            apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+            client.version = version;
+        }
+        let guard = state.lock().await;
+        let doc_state = &guard.docs["room/notes"];
+        assert_eq!(doc_state.doc.get_text(), "hi there\n");
+        assert_eq!(doc_state.version, client.version);
+        drop(guard);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn room_exports_include_unsaved_edits_and_report_broken_documents() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-server-export-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Storage::new(&dir, SyncPolicy::Never));
+        for doc in ["notes/2024/standup.md", "lost"] {
+            storage
+                .save("room", doc, "saved".into(), DocMeta::default())
+                .await
+                .unwrap();
+        }
+        std::fs::write(dir.join("room").join("lost"), "bad!").unwrap();
+        let state = Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )));
+        let (tx, _rx) = broadcast::channel(16);
+        update(&state, &tx, "unsaved", 0).await;
+
+        let (out_tx, mut out_rx) = mpsc::channel(64);
+        export_room(&state, "room", None, &out_tx).await;
+        drop(out_tx);
+        let mut assembler = crate::export::Assembler::default();
+        let mut docs = None;
+        while let Some(Outgoing::Admin(msg)) = out_rx.recv().await {
+            docs = assembler.push(msg);
+        }
+        let docs = docs.expect("the export finished").unwrap();
+        let exported: Vec<(&str, Result<&str, &str>)> = docs
+            .iter()
+            .map(|doc| {
+                let content = doc.content.as_ref();
+                let text = content
+                    .map(|(text, _)| text.as_str())
+                    .map_err(|err| err.as_str());
+                (doc.name.as_str(), text)
+            })
+            .collect();
+        assert_eq!(exported.len(), 3);
+        assert!(exported[0].0 == "lost" && exported[0].1.unwrap_err().contains("checksum"));
+        assert_eq!(exported[1], ("notes/2024/standup.md", Ok("saved")));
+        assert_eq!(exported[2], ("unsaved", Ok("hi")));
+
+        let (out_tx, mut out_rx) = mpsc::channel(64);
+        export_room(&state, "..", None, &out_tx).await;
+        match out_rx.recv().await {
+            Some(Outgoing::Admin(ServerMessage::ExportDone { error: Some(_) })) => {}
+            _ => panic!("an invalid room fails the export"),
+        }
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn slow_storage_does_not_hold_up_other_documents() {
+        let dir = std::env::temp_dir().join(format!("carnelia-server-io-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let storage = Arc::new(Storage::with_fs(&dir, SyncPolicy::Never, Arc::new(SlowFs)));
+        let state = Arc::new(Mutex::new(SharedState::new(
+            storage,
+            HistoryPolicy::default(),
+        )));
+        let (tx, mut rx) = broadcast::channel(16);
+
+        // The slow document loads in the background...
+        let slow = tokio::spawn({
+            let (state, tx) = (Arc::clone(&state), tx.clone());
+            async move { update(&state, &tx, "slow", 0).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // ...while edits of another document go through right away.
+        let started = Instant::now();
+        update(&state, &tx, "fast", 0).await;
+        assert!(
+            started.elapsed() < DELAY / 2,
+            "took {:?}",
+            started.elapsed()
+        );
+        let (document_id, _, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+        assert_eq!(document_id, "room/fast");
+
+        slow.await.unwrap();
+        assert_eq!(state.lock().await.docs["room/slow"].doc.get_text(), "hi");
+        // The slow document's save is under way now, and still in the way
+        // of nothing.
+        let flush = tokio::spawn({
+            let state = Arc::clone(&state);
+            async move { persistence::flush_all(&state).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let started = Instant::now();
+        update(&state, &tx, "fast", 2).await;
+        assert!(
+            started.elapsed() < DELAY / 2,
+            "took {:?}",
+            started.elapsed()
+        );
+
+        flush.await.unwrap().unwrap();
+        persistence::flush_all(&state).await.unwrap();
+        let saved = |doc: &str| std::fs::read_to_string(dir.join("room").join(doc)).ok();
+        assert_eq!(saved("slow").as_deref(), Some("hi"));
+        assert_eq!(saved("fast").as_deref(), Some("hihi"));
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    type Pipe = (
+        tokio::io::Lines<BufReader<tokio::io::ReadHalf<tokio::io::DuplexStream>>>,
+        tokio::io::WriteHalf<tokio::io::DuplexStream>,
+    );
+
+    async fn send(pipe: &mut Pipe, msg: &Message) {
+        let line = format!("{}\n", serde_json::to_string(msg).unwrap());
+        pipe.1.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    fn hello(id: &str, name: &str) -> Message {
+        Message::Hello {
+            replica_id: id.to_string(),
+            user_name: name.to_string(),
+        }
+    }
+
+    /// `name` joined to room/notes on `server`, and their id.
+    async fn join(server: &LocalServer, name: &str) -> (Pipe, String) {
+        join_over(server, name, 64 * 1024).await
+    }
+
+    /// `join` over a pipe holding at most `pipe_len` unread bytes.
+    async fn join_over(server: &LocalServer, name: &str, pipe_len: usize) -> (Pipe, String) {
+        let id = make_scoped_user_id("room/notes", name);
+        (join_as(server, &id, name, pipe_len).await, id)
+    }
+
+    /// A connection to `server` over a pipe holding at most `pipe_len`
+    /// unread bytes, which hasn't said hello.
+    fn connect(server: &LocalServer, pipe_len: usize) -> Pipe {
+        let (ours, theirs) = tokio::io::duplex(pipe_len);
+        server.connect(theirs);
+        let (reader, writer) = tokio::io::split(ours);
+        (BufReader::new(reader).lines(), writer)
+    }
+
+    /// `id` joined to room/notes on `server` as `name`, empty for a guest.
+    async fn join_as(server: &LocalServer, id: &str, name: &str, pipe_len: usize) -> Pipe {
+        let mut pipe = connect(server, pipe_len);
+        send(&mut pipe, &hello(id, name)).await;
+        send(&mut pipe, &encode_sync_request("room/notes", 0)).await;
+        pipe
+    }
+
+    /// The name the server welcomed `pipe` with, and the answer to its
+    /// join.
+    async fn welcome_and_sync(pipe: &mut Pipe) -> (Option<String>, WireSync) {
+        let mut welcomed = None;
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), pipe.0.next_line())
+                .await
+                .expect("the server answers")
+                .unwrap()
+                .unwrap();
+            if let Ok(ServerMessage::Welcome { name, .. }) = serde_json::from_str(&line) {
+                welcomed = Some(name);
+            } else if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((_, sync, _)) = decode_sync_response(&msg)
+            {
+                return (welcomed.expect("welcomed before the sync"), sync);
             }
-            event = broadcast_rx.recv() => {
-                if let Ok(event) = event
-                    && should_forward(&event, current_room.as_deref(), current_doc.as_deref())
-                {
-                    let _ = out_tx.send(event).await;
+        }
+    }
+
+    #[tokio::test]
+    async fn guests_get_a_name_nobody_else_in_the_room_has() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        // Ada goes by the name the guest would get first.
+        let first = guests::guest_name("visitor", |_| false);
+        let (mut ada, _) = join(&server, &first).await;
+        welcome_and_sync(&mut ada).await;
+
+        let id = make_scoped_user_id("room/notes", "visitor");
+        let mut guest = join_as(&server, &id, "", 64 * 1024).await;
+        let (name, sync) = welcome_and_sync(&mut guest).await;
+        let name = name.expect("guests are told their name");
+        assert_eq!(name, guests::guest_name("visitor", |taken| taken == first));
+        let me = sync.users.iter().find(|user| user.id == id).unwrap();
+        assert_eq!((me.name.as_str(), me.guest), (name.as_str(), true));
+        assert!(
+            sync.users
+                .iter()
+                .any(|user| user.name == first && !user.guest)
+        );
+    }
+
+    #[tokio::test]
+    async fn servers_without_guests_refuse_unnamed_joins() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        server.state.lock().await.limits.no_guests = true;
+        let id = make_scoped_user_id("room/notes", "visitor");
+        let mut guest = join_as(&server, &id, " ", 64 * 1024).await;
+        let (name, sync) = welcome_and_sync(&mut guest).await;
+        assert_eq!(name, None);
+        assert!(sync.error.unwrap().contains("no guests"));
+        assert!(server.state.lock().await.users.is_empty());
+
+        // Named users still get in.
+        let (mut ada, _) = join(&server, "ada").await;
+        let (name, sync) = welcome_and_sync(&mut ada).await;
+        assert_eq!((name, sync.error), (None, None));
+        assert_eq!(sync.users.len(), 1);
+    }
+
+    /// The next `UserEvent` sent on `pipe`, and the names in the user list
+    /// sent after it, which has the change.
+    async fn event_and_members(pipe: &mut Pipe) -> (ServerMessage, Vec<String>) {
+        let mut event = None;
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), pipe.0.next_line())
+                .await
+                .expect("the server answers")
+                .unwrap()
+                .unwrap();
+            match serde_json::from_str(&line) {
+                Ok(msg @ ServerMessage::UserEvent { .. }) => {
+                    assert!(event.replace(msg).is_none(), "{}", line);
+                }
+                Ok(ServerMessage::Members { users, .. }) if event.is_some() => {
+                    let names = users.into_iter().map(|user| user.name).collect();
+                    return (event.unwrap(), names);
                 }
+                _ => {}
             }
         }
     }
 
-    if let Some(user_id) = current_user_id {
-        let mut guard = state.lock().await;
-        guard.users.remove(&user_id);
-        if let (Some(room), Some(doc)) = (current_room.take(), current_doc.take()) {
-            let document_id = doc_key(&room, &doc);
-            let _ = broadcast_tx.send(Message::Presence {
-                user_id,
-                document_id,
-                cursor_pos: None,
-            });
+    /// The user lists sent on `pipe` until it has nothing more to read for
+    /// a while, as their `presence_seq` and names.
+    async fn members_until_quiet(pipe: &mut Pipe) -> Vec<(u64, Vec<String>)> {
+        let mut members = Vec::new();
+        while let Ok(line) =
+            tokio::time::timeout(Duration::from_millis(500), pipe.0.next_line()).await
+        {
+            if let Ok(ServerMessage::Members {
+                users,
+                presence_seq,
+                ..
+            }) = serde_json::from_str(&line.unwrap().unwrap())
+            {
+                let names = users.into_iter().map(|user| user.name).collect();
+                members.push((presence_seq, names));
+            }
         }
+        members
     }
 
-    writer_task.abort();
-    Ok(())
-}
+    #[tokio::test]
+    async fn notices_agree_with_the_user_list() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (mut ada, _) = join(&server, "ada").await;
+        assert_eq!(
+            members_until_quiet(&mut ada).await,
+            [(1, vec!["ada".into()])]
+        );
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        let event = |kind, name: &str, detail: Option<&str>| ServerMessage::UserEvent {
+            room: "room".into(),
+            doc: "notes".into(),
+            kind,
+            user: WireUser {
+                id: bob_id.clone(),
+                name: name.into(),
+                guest: false,
+            },
+            detail: detail.map(str::to_string),
+        };
+        let names =
+            |names: &[&str]| -> Vec<String> { names.iter().map(|name| name.to_string()).collect() };
 
-async fn handle_update(
-    state: &Arc<Mutex<SharedState>>,
-    broadcast_tx: &broadcast::Sender<Message>,
-    current_user_id: Option<&str>,
-    room: Option<&str>,
-    doc: Option<&str>,
-    msg: &Message,
-) {
-    if current_user_id.is_none() {
-        return;
+        let joined = event_and_members(&mut ada).await;
+        let expected = event(UserEventKind::Joined, "bob", None);
+        assert_eq!(joined, (expected, names(&["ada", "bob"])));
+
+        // Saying hello again under another name is a rename.
+        send(&mut bob, &hello(&bob_id, "rob")).await;
+        let renamed = event_and_members(&mut ada).await;
+        let expected = event(UserEventKind::Renamed, "rob", Some("bob"));
+        assert_eq!(renamed, (expected, names(&["ada", "rob"])));
+        let users = users_in_doc(&server.state.lock().await.users, "room", "notes");
+        assert!(
+            users
+                .iter()
+                .any(|user| user.id == bob_id && user.name == "rob")
+        );
+
+        drop(bob);
+        let left = event_and_members(&mut ada).await;
+        let expected = event(UserEventKind::Left, "rob", None);
+        assert_eq!(left, (expected, names(&["ada"])));
+        assert_eq!(server.state.lock().await.users.len(), 1);
+
+        // Connections the server closes are announced as kicks.
+        let (_bob, _) = join(&server, "bob").await;
+        let _ = event_and_members(&mut ada).await;
+        let kicked = kick_user(&server.state, "room", "notes", "bob").await;
+        assert_eq!(kicked.unwrap(), 1);
+        let kicked = event_and_members(&mut ada).await;
+        let expected = event(UserEventKind::Kicked, "bob", Some("kicked by an admin"));
+        assert_eq!(kicked, (expected, names(&["ada"])));
+        let gone = kick_user(&server.state, "room", "notes", "bob").await;
+        assert_eq!(gone.unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        server.state.lock().await.limits.idle_timeout_secs = Some(1);
+        let (_bob, _) = join(&server, "bob").await;
+        let _ = event_and_members(&mut ada).await;
+        let kicked = event_and_members(&mut ada).await;
+        let expected = event(UserEventKind::Kicked, "bob", Some("idle for 1s"));
+        assert_eq!(kicked, (expected, names(&["ada"])));
     }
-    let Some(room) = room else {
-        return;
-    };
-    let Some(doc) = doc else {
-        return;
-    };
 
-    let Some((document_id, payload, _)) = decode_update(msg) else {
-        return;
-    };
-    match current_user_id {
-        Some(current_id) if payload.user_id != current_id => {
-            println!("[server] ignoring spoofed update for {}", payload.user_id);
-            return;
+    #[tokio::test]
+    async fn membership_changes_are_sent_once_per_window_and_only_if_any() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (mut ada, _) = join(&server, "ada").await;
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        let (_cy, _) = join(&server, "cy").await;
+        // Joins over local pipes take far less than a window.
+        let (_, sync) = welcome_and_sync(&mut bob).await;
+        assert_eq!(sync.presence_seq, 0);
+        let everyone = vec!["ada".to_string(), "bob".into(), "cy".into()];
+        assert_eq!(members_until_quiet(&mut ada).await, [(1, everyone)]);
+
+        // A `/sync` joins again, changing nothing.
+        send(&mut bob, &encode_sync_request("room/notes", 0)).await;
+        let sync = loop {
+            let line = bob.0.next_line().await.unwrap().unwrap();
+            if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((_, sync, _)) = decode_sync_response(&msg)
+            {
+                break sync;
+            }
+        };
+        assert_eq!((sync.presence_seq, sync.users.len()), (1, 3));
+        assert!(members_until_quiet(&mut ada).await.is_empty());
+
+        send(&mut bob, &hello(&bob_id, "rob")).await;
+        drop(bob);
+        let seqs: Vec<u64> = members_until_quiet(&mut ada)
+            .await
+            .into_iter()
+            .map(|(seq, names)| {
+                assert!(!names.contains(&"rob".to_string()), "{:?}", names);
+                seq
+            })
+            .collect();
+        assert_eq!(seqs, [2], "the rename and leave are one change");
+    }
+
+    /// The next activity entry sent on `pipe`.
+    async fn next_activity(pipe: &mut Pipe) -> ActivityEntry {
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), pipe.0.next_line())
+                .await
+                .expect("the server answers")
+                .unwrap()
+                .unwrap();
+            if let Ok(ServerMessage::Activity { entry, .. }) = serde_json::from_str(&line) {
+                return entry;
+            }
         }
-        _ => {}
     }
-    if document_id != doc_key(room, doc) {
-        return;
+
+    #[tokio::test]
+    async fn each_event_adds_one_activity_entry() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        let mut seen = Vec::new();
+        let mut expect = async |pipe: &mut Pipe, user: &str, kind: ActivityKind| {
+            let entry = next_activity(pipe).await;
+            assert_eq!((entry.user.as_str(), &entry.kind), (user, &kind));
+            seen.push(entry);
+        };
+        let user = |kind| ActivityKind::User { kind, detail: None };
+        expect(&mut ada, "ada", user(UserEventKind::Joined)).await;
+
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        expect(&mut ada, "bob", user(UserEventKind::Joined)).await;
+        send(&mut bob, &hello(&bob_id, "rob")).await;
+        let renamed = ActivityKind::User {
+            kind: UserEventKind::Renamed,
+            detail: Some("bob".into()),
+        };
+        expect(&mut ada, "rob", renamed).await;
+
+        let update = |op| encode_update("room/notes", &ada_id, op, Vec::new(), 0).unwrap();
+        let text = "x".repeat(600);
+        send(
+            &mut ada,
+            &update(Op::Insert {
+                pos: 0,
+                text,
+                pos_chars: None,
+            }),
+        )
+        .await;
+        let snapshot = ClientMessage::Snapshot {
+            room: "room".into(),
+            doc: "notes".into(),
+            user: None,
+        };
+        let line = format!("{}\n", serde_json::to_string(&snapshot).unwrap());
+        ada.1.write_all(line.as_bytes()).await.unwrap();
+        expect(&mut ada, "ada", ActivityKind::Snapshot { version: 1 }).await;
+        // Only deletions past `BIG_DELETE_BYTES` are worth an entry.
+        send(
+            &mut ada,
+            &update(Op::Delete {
+                pos: 0,
+                len: 10,
+                pos_chars: None,
+                len_chars: None,
+            }),
+        )
+        .await;
+        send(
+            &mut ada,
+            &update(Op::Delete {
+                pos: 0,
+                len: 590,
+                pos_chars: None,
+                len_chars: None,
+            }),
+        )
+        .await;
+        expect(&mut ada, "ada", ActivityKind::Deleted { bytes: 590 }).await;
+        let policy = WhitespacePolicy {
+            final_newline: true,
+            ..WhitespacePolicy::default()
+        };
+        set_policy(&server.state, None, "room", "notes", policy)
+            .await
+            .unwrap();
+        expect(&mut ada, ADMIN_USER, ActivityKind::Policy { policy }).await;
+        drop(bob);
+        expect(&mut ada, "rob", user(UserEventKind::Left)).await;
+
+        // Those joining get the feed so far, their own arrival included,
+        // and each entry once.
+        let (mut cy, _) = join(&server, "cy").await;
+        expect(&mut ada, "cy", user(UserEventKind::Joined)).await;
+        let kept = server.state.lock().await.docs["room/notes"]
+            .activity
+            .clone();
+        assert_eq!(Vec::from(kept), seen);
+        for expected in &seen {
+            assert_eq!(&next_activity(&mut cy).await, expected);
+        }
+        send(&mut cy, &Message::Ping).await;
+        loop {
+            let line = cy.0.next_line().await.unwrap().unwrap();
+            assert!(!line.contains("Activity"), "{}", line);
+            if matches!(serde_json::from_str(&line), Ok(Message::Pong)) {
+                break;
+            }
+        }
+        let seqs: Vec<u64> = seen.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, (1..=8).collect::<Vec<_>>());
     }
 
-    let mut guard = state.lock().await;
-    let doc_key = doc_key(room, doc);
-    if !guard.docs.contains_key(&doc_key) {
-        let text = guard.storage.load_text(room, doc).unwrap_or_default();
-        let mut new_doc = TextDoc::new(doc_key.clone(), "server");
-        if !text.is_empty() {
-            new_doc.insert(0, &text);
-        }
-        guard.docs.insert(
-            doc_key.clone(),
-            DocState {
-                doc: new_doc,
-                version: 0,
-                cursors: HashMap::new(),
+    #[tokio::test]
+    async fn whitespace_policies_tidy_the_saved_file_or_everyones_text() {
+        let storage = Arc::new(MemoryStorage::new());
+        let state = memory_state(&storage);
+        let tx = state.lock().await.updates.clone();
+        let mut rx = tx.subscribe();
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let text = "one  \ntwo \n\n\n";
+        let edit = insert("notes", "ada", 0, text);
+        let (ada, room, doc) = (Some("ada"), Some("room"), Some("notes"));
+        handle_update(&state, &tx, ada, room, doc, &edit, &mut limits)
+            .await
+            .unwrap();
+        let mut client = DocState::new(TextDoc::new("room/notes", "client"), DocMeta::default());
+        let (_, payload, _) = decode_update(&rx.recv().await.unwrap()).unwrap();
+        apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+
+        // Only the first of the users may set the policy.
+        let mut guard = state.lock().await;
+        for (id, joined) in [("ada", Instant::now()), ("bob", Instant::now() + DELAY)] {
+            let user = UserState {
+                id: id.into(),
+                name: id.into(),
+                room: "room".into(),
+                doc: "notes".into(),
+                joined,
+                guest: false,
+                kick: Arc::default(),
+            };
+            guard.users.insert(id.into(), user);
+        }
+        drop(guard);
+        let lenient = WhitespacePolicy {
+            final_newline: true,
+            trim_trailing: true,
+            strict: false,
+        };
+        let refused = set_policy(&state, Some("bob"), "room", "notes", lenient).await;
+        assert!(refused.unwrap_err().contains("only ada"));
+        set_policy(&state, Some("ada"), "room", "notes", lenient)
+            .await
+            .unwrap();
+
+        persistence::flush_all(&state).await.unwrap();
+        let stored = storage.load("room", "notes").await.unwrap();
+        assert_eq!(stored.text, "one\ntwo\n");
+        assert_eq!(stored.meta.whitespace, lenient);
+        // The open document is left as it is.
+        let live = || async { state.lock().await.docs["room/notes"].doc.get_text() };
+        assert_eq!(live().await, text);
+        assert!(rx.try_recv().is_err());
+
+        // A strict policy tidies it for everyone, except the line bob is
+        // typing on.
+        let strict = WhitespacePolicy {
+            strict: true,
+            ..lenient
+        };
+        set_policy(&state, None, "room", "notes", strict)
+            .await
+            .unwrap();
+        let bob = "bob".to_string();
+        state
+            .lock()
+            .await
+            .docs
+            .get_mut("room/notes")
+            .unwrap()
+            .cursors
+            .insert(bob, 2);
+        persistence::flush_all(&state).await.unwrap();
+        assert_eq!(live().await, "one  \ntwo\n");
+        assert_eq!(
+            storage.load("room", "notes").await.unwrap().text,
+            "one\ntwo\n"
+        );
+        while let Ok(update) = rx.try_recv() {
+            let (_, payload, version) = decode_update(&update).unwrap();
+            assert_eq!(payload.user_id, DISK_USER);
+            apply_op_to_doc(&mut client, &payload.user_id, &payload.op);
+            client.version = version;
+        }
+        assert_eq!(client.doc.get_text(), "one  \ntwo\n");
+
+        // Offsets taken from the client's text still hit the same spot.
+        let pos = client.doc.get_text().find("two").unwrap() + 3;
+        let edit = insert("notes", "ada", pos, "!");
+        handle_update(&state, &tx, ada, room, doc, &edit, &mut limits)
+            .await
+            .unwrap();
+        assert_eq!(live().await, "one  \ntwo!\n");
+        let guard = state.lock().await;
+        assert_eq!(guard.docs["room/notes"].version, client.version + 1);
+    }
+
+    /// The next server message on `pipe` that `pick` takes.
+    async fn next_notice<T>(pipe: &mut Pipe, pick: impl Fn(ServerMessage) -> Option<T>) -> T {
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), pipe.0.next_line())
+                .await
+                .expect("the server answers")
+                .unwrap()
+                .expect("the connection is open");
+            if let Some(picked) = serde_json::from_str(&line).ok().and_then(&pick) {
+                return picked;
+            }
+        }
+    }
+
+    async fn request(pipe: &mut Pipe, msg: &ClientMessage) {
+        let line = format!("{}\n", serde_json::to_string(msg).unwrap());
+        pipe.1.write_all(line.as_bytes()).await.unwrap();
+    }
+
+    /// The documents of "room" exported to `pipe`.
+    async fn exported(pipe: &mut Pipe) -> Vec<String> {
+        let export = ClientMessage::ExportRoom {
+            room: "room".into(),
+        };
+        request(pipe, &export).await;
+        let mut docs = Vec::new();
+        loop {
+            match next_notice(pipe, Some).await {
+                ServerMessage::DocStart { name, .. } => docs.push(name),
+                ServerMessage::ExportDone { error } => {
+                    assert_eq!(error, None);
+                    return docs;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn acls_decide_who_edits_and_who_reads() {
+        let storage = Arc::new(MemoryStorage::new());
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        welcome_and_sync(&mut bob).await;
+        let acl = |writers: &[&str], readers: &[&str]| Acl {
+            writers: writers.iter().map(|name| name.to_string()).collect(),
+            readers: readers.iter().map(|name| name.to_string()).collect(),
+        };
+        let set = |user: Option<&str>, acl: Acl| {
+            let user = user.map(str::to_string);
+            let state = Arc::clone(&server.state);
+            async move { set_acl(&state, user.as_deref(), "room", "notes", acl).await }
+        };
+
+        // With none yet, the first to join sets it, and keeps editing.
+        let organizers = acl(&["ada"], &["bob"]);
+        let refused = set(Some(&bob_id), organizers.clone()).await;
+        assert!(refused.unwrap_err().contains("only ada"));
+        assert!(set(Some(&ada_id), acl(&["bob"], &[])).await.is_err());
+        set(Some(&ada_id), organizers.clone()).await.unwrap();
+        let info = next_notice(&mut bob, |msg| match msg {
+            ServerMessage::DocInfo { acl, .. } => Some(acl),
+            _ => None,
+        });
+        assert_eq!(info.await, organizers);
+
+        // Bob reads along; his edits are refused unapplied.
+        send(&mut bob, &insert("notes", &bob_id, 0, "bob was here")).await;
+        let rejected = next_notice(&mut bob, |msg| match msg {
+            ServerMessage::Rejected { error } => Some(error),
+            _ => None,
+        });
+        assert!(rejected.await.starts_with("forbidden: bob may not edit"));
+        send(&mut ada, &insert("notes", &ada_id, 0, "agenda")).await;
+        while server.doc("room", "notes").await.unwrap().0.is_empty() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(server.doc("room", "notes").await.unwrap().0, "agenda");
+        let refused = set(Some(&bob_id), acl(&["bob"], &[])).await;
+        assert!(refused.unwrap_err().contains("only the writers"));
+
+        // Cy may not even read it, and is told so on joining.
+        let (mut cy, _) = join(&server, "cy").await;
+        let access = next_notice(&mut cy, |msg| match msg {
+            ServerMessage::Welcome { access, .. } => Some(access),
+            _ => None,
+        });
+        assert_eq!(access.await, Some(Access::Denied));
+        let sync = loop {
+            let line = cy.0.next_line().await.unwrap().unwrap();
+            if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((_, sync, _)) = decode_sync_response(&msg)
+            {
+                break sync;
+            }
+        };
+        assert_eq!(sync.error.as_deref(), Some("cy may not read this document"));
+        assert!(sync.text.is_empty());
+        // Nor through its history, its stats or the room's export.
+        let (saved, _) = take_snapshot(&server.state, "room", "notes", "ada")
+            .await
+            .unwrap();
+        let (room, doc) = (|| "room".to_string(), || "notes".to_string());
+        let asks = [
+            ClientMessage::ListRevisions {
+                room: room(),
+                doc: doc(),
+            },
+            ClientMessage::HistoryRequest {
+                room: room(),
+                doc: doc(),
+                version: saved,
             },
+            ClientMessage::StatsRequest {
+                room: room(),
+                doc: doc(),
+            },
+        ];
+        for ask in &asks {
+            request(&mut cy, ask).await;
+            let answer = next_notice(&mut cy, |msg| match msg {
+                ServerMessage::Rejected { error } => Some(error),
+                ServerMessage::Revisions { .. }
+                | ServerMessage::RevisionText { .. }
+                | ServerMessage::DocStats { .. } => Some(format!("{:?}", msg)),
+                _ => None,
+            });
+            assert_eq!(answer.await, "forbidden: you may not read room/notes");
+        }
+        assert!(exported(&mut cy).await.is_empty());
+        assert_eq!(exported(&mut ada).await, ["notes"]);
+        // Nor over HTTP, where nobody is named.
+        let page = http::doc_response(&server.state, "/rooms/room/docs/notes", None).await;
+        assert!(page.unwrap().starts_with(b"HTTP/1.1 404 Not Found"));
+        // Neither may a connection that never said hello, which may
+        // restore nothing either.
+        let mut nobody = connect(&server, 64 * 1024);
+        assert!(exported(&mut nobody).await.is_empty());
+        let restore = ClientMessage::Restore {
+            room: room(),
+            doc: doc(),
+            version: saved,
+        };
+        request(&mut nobody, &restore).await;
+        let refused = next_notice(&mut nobody, |msg| match msg {
+            ServerMessage::Rejected { error } => Some(error),
+            _ => None,
+        });
+        assert_eq!(refused.await, "forbidden: say hello with a name first");
+
+        // Ada makes bob a writer, who then takes over; ada may only read.
+        set(Some(&ada_id), acl(&["ada", "bob"], &["bob"]))
+            .await
+            .unwrap();
+        let handed_over = acl(&["bob"], &[]);
+        set(Some(&bob_id), handed_over.clone()).await.unwrap();
+        assert!(set(Some(&ada_id), organizers).await.is_err());
+        let page = http::doc_response(&server.state, "/rooms/room/docs/notes", None).await;
+        assert!(page.unwrap().starts_with(b"HTTP/1.1 200 OK"));
+        let (mut dee, _) = join(&server, "dee").await;
+        let access = next_notice(&mut dee, |msg| match msg {
+            ServerMessage::Welcome { access, .. } => Some(access),
+            _ => None,
+        });
+        assert_eq!(access.await, Some(Access::Read));
+
+        // Someone taken off the readers is disconnected.
+        set(None, acl(&["bob"], &["bob"])).await.unwrap();
+        let left = next_notice(&mut bob, |msg| match msg {
+            ServerMessage::UserEvent {
+                kind: UserEventKind::Kicked,
+                user,
+                detail,
+                ..
+            } if user.name == "ada" => detail,
+            _ => None,
+        });
+        assert_eq!(left.await, "no longer allowed to read");
+
+        // The ACL is saved with the document.
+        persistence::flush_all(&server.state).await.unwrap();
+        assert_eq!(
+            storage.load("room", "notes").await.unwrap().meta.acl,
+            acl(&["bob"], &["bob"])
         );
+        let restarted = LocalServer::new(Arc::clone(&storage) as _);
+        let access = access_of(&restarted.state, "room", "notes", "ada").await;
+        assert_eq!(access, Some(Access::Denied));
+        let (mut ada, _) = join(&restarted, "ada").await;
+        assert!(welcome_and_sync(&mut ada).await.1.error.is_some());
     }
 
-    let (updated_text, version, op, delta) = {
-        let doc_state = guard.docs.get_mut(&doc_key).expect("doc exists");
-        apply_op_to_doc(doc_state, &payload.user_id, &payload.op);
-        let delta = Vec::new();
-        doc_state.version += 1;
-        (
-            doc_state.doc.get_text(),
-            doc_state.version,
-            payload.op,
-            delta,
-        )
-    };
+    #[tokio::test]
+    async fn cursor_moves_are_relayed_with_their_line_and_column() {
+        let state = memory_state(&Arc::new(MemoryStorage::new()));
+        let (tx, mut rx) = broadcast::channel(16);
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let mut send = async |msg: Message| {
+            let (ada, room, doc) = (Some("ada"), Some("room"), Some("notes"));
+            handle_update(&state, &tx, ada, room, doc, &msg, &mut limits)
+                .await
+                .unwrap();
+            decode_update(&rx.recv().await.unwrap()).unwrap().1.op
+        };
+        let cursor = |pos| {
+            let op = Op::Cursor { pos, at: None };
+            encode_update("room/notes", "ada", op, Vec::new(), 0).unwrap()
+        };
+        let at = |pos, line, col| Op::Cursor {
+            pos,
+            at: Some(LineCol { line, col }),
+        };
 
-    let _ = guard.storage.save_text(room, doc, &updated_text);
-    drop(guard);
+        send(insert("notes", "ada", 0, "one\ntwö\n")).await;
+        assert_eq!(send(cursor(6)).await, at(6, 2, 3));
+        assert_eq!(send(cursor(8)).await, at(8, 2, 4));
+        // Past the end: the end of the text.
+        assert_eq!(send(cursor(50)).await, at(9, 3, 1));
+        // The lines are found again after an edit.
+        send(insert("notes", "ada", 0, "zero\n")).await;
+        assert_eq!(send(cursor(6)).await, at(6, 2, 2));
+    }
 
-    match op {
-        Op::Cursor { pos } => {
-            let _ = broadcast_tx.send(Message::Presence {
-                user_id: payload.user_id,
-                document_id: doc_key,
+    #[tokio::test]
+    async fn edits_reach_slow_readers_ahead_of_cursor_moves() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        // Bob reads nothing for now, so his pipe is full after a few lines.
+        let (mut bob, _) = join_over(&server, "bob", 1024).await;
+        let _ = event_and_members(&mut ada).await;
+        let (mut ada_lines, mut ada_out) = ada;
+        tokio::spawn(async move { while let Ok(Some(_)) = ada_lines.next_line().await {} });
+
+        let moves = 500;
+        let mut lines = String::new();
+        for pos in 0..moves {
+            let presence = Message::Presence {
+                user_id: ada_id.clone(),
+                document_id: "room/notes".into(),
                 cursor_pos: Some(pos),
-            });
+            };
+            lines += &format!("{}\n", serde_json::to_string(&presence).unwrap());
+        }
+        let edit = insert("notes", &ada_id, 0, "hello");
+        lines += &format!("{}\n", serde_json::to_string(&edit).unwrap());
+        ada_out.write_all(lines.as_bytes()).await.unwrap();
+        while server.doc("room", "notes").await.map(|(text, _)| text) != Some("hello".into()) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
         }
-        _ => match encode_update(&doc_key, &payload.user_id, op, delta, version) {
-            Ok(update) => {
-                let _ = broadcast_tx.send(update);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        // Bob catches up slowly, and gets the edit after a few moves.
+        let started = Instant::now();
+        let mut cursors = 0;
+        let mut edit_after = None;
+        while let Ok(Ok(Some(line))) =
+            tokio::time::timeout(Duration::from_millis(200), bob.0.next_line()).await
+        {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+            let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                continue;
+            };
+            match decode_update(&msg).map(|(_, payload, _)| payload.op) {
+                Some(Op::Cursor { .. }) => cursors += 1,
+                Some(_) => edit_after = Some((cursors, started.elapsed())),
+                None => {}
             }
-            Err(err) => {
-                println!("[server] failed to encode update: {}", err);
+        }
+        let (cursors_first, took) = edit_after.expect("the edit arrives");
+        assert!(
+            cursors_first < moves / 4,
+            "{} moves came first",
+            cursors_first
+        );
+        assert!(took < Duration::from_secs(1), "took {:?}", took);
+        assert!(
+            cursors < moves / 4,
+            "{} of {} moves arrived",
+            cursors,
+            moves
+        );
+        let metrics = server.state.lock().await.outbound.render();
+        let dropped: usize = metrics.split_whitespace().nth(1).unwrap().parse().unwrap();
+        assert!(dropped >= moves - cursors, "{}", metrics);
+    }
+
+    #[tokio::test]
+    async fn listings_have_stored_docs_and_joined_ones() {
+        let storage = Arc::new(MemoryStorage::new());
+        for (room, doc) in [("archive", "old.txt"), ("room", "saved")] {
+            let meta = DocMeta::default();
+            storage.save(room, doc, "x".into(), meta).await.unwrap();
+        }
+        let server = LocalServer::new(storage);
+        // Joined but never saved.
+        let (mut ada, _) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+        assert_eq!(list_rooms(&server.state).await, ["archive", "room"]);
+        assert_eq!(list_docs(&server.state, "room").await, ["notes", "saved"]);
+        assert!(list_docs(&server.state, "elsewhere").await.is_empty());
+    }
+
+    /// `name` joined to `doc_id` on `server`, where the welcome sent them,
+    /// and the document id and answer of the sync.
+    async fn join_doc(
+        server: &LocalServer,
+        doc_id: &str,
+        name: &str,
+    ) -> (Pipe, Option<(String, String)>, String, WireSync) {
+        let (ours, theirs) = tokio::io::duplex(64 * 1024);
+        server.connect(theirs);
+        let (reader, writer) = tokio::io::split(ours);
+        let mut pipe = (BufReader::new(reader).lines(), writer);
+        send(&mut pipe, &hello(&make_scoped_user_id(doc_id, name), name)).await;
+        send(&mut pipe, &encode_sync_request(doc_id, 0)).await;
+        let mut redirected = None;
+        loop {
+            let line = pipe.0.next_line().await.unwrap().unwrap();
+            if let Ok(ServerMessage::Welcome {
+                doc,
+                redirected_from,
+                ..
+            }) = serde_json::from_str(&line)
+            {
+                redirected = doc.zip(redirected_from);
+            } else if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((doc_id, sync, _)) = decode_sync_response(&msg)
+            {
+                return (pipe, redirected, doc_id, sync);
             }
-        },
+        }
     }
-}
 
-fn should_forward(msg: &Message, room: Option<&str>, doc: Option<&str>) -> bool {
-    let Some(room) = room else {
-        return false;
-    };
-    let Some(doc) = doc else {
-        return false;
-    };
-    let doc_id = doc_key(room, doc);
-    match msg {
-        Message::Hello { replica_id, .. } => {
-            doc_id_from_scoped_user_id(replica_id) == Some(doc_id.as_str())
+    #[tokio::test]
+    async fn joins_follow_the_rooms_default_doc_and_aliases() {
+        let storage = Arc::new(MemoryStorage::new());
+        let meta = DocMeta::default();
+        for doc in ["shared.txt", "notes"] {
+            storage
+                .save("room", doc, doc.into(), meta.clone())
+                .await
+                .unwrap();
         }
-        Message::Update { document_id, .. } => document_id == &doc_id,
-        Message::Presence { document_id, .. } => document_id == &doc_id,
-        Message::SyncResponse { document_id, .. } => document_id == &doc_id,
-        Message::Ack { .. } | Message::Ping | Message::Pong | Message::SyncRequest { .. } => false,
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+
+        // Without settings names are taken as they are.
+        let (_ada, redirected, doc_id, sync) = join_doc(&server, "plain/draft", "ada").await;
+        assert_eq!((redirected, doc_id.as_str()), (None, "plain/draft"));
+        assert!(sync.error.is_none());
+        let (_, redirected, _, sync) = join_doc(&server, "plain/", "ada").await;
+        assert!(redirected.is_none() && sync.error.is_some());
+
+        let meta = RoomMeta {
+            default_doc: Some("shared.txt".into()),
+            aliases: [("shared", "shared.txt"), ("notes", "shared.txt")]
+                .into_iter()
+                .map(|(alias, doc)| (alias.to_string(), doc.to_string()))
+                .collect(),
+        };
+        set_room_meta(&server.state, None, "room", meta.clone())
+            .await
+            .unwrap();
+        assert_eq!(storage.load_room_meta("room").await.unwrap(), meta);
+
+        let (_bob, redirected, doc_id, sync) = join_doc(&server, "room/shared", "bob").await;
+        let expected = ("shared.txt".to_string(), "shared".to_string());
+        assert_eq!(redirected, Some(expected));
+        assert_eq!(doc_id, "room/shared.txt");
+        assert_eq!(sync.text, "shared.txt");
+        let bob = make_scoped_user_id("room/shared.txt", "bob");
+        assert!(sync.users.iter().any(|user| user.id == bob));
+        let (_cy, redirected, doc_id, _) = join_doc(&server, "room/", "cy").await;
+        assert_eq!(
+            redirected.map(|(doc, _)| doc).as_deref(),
+            Some("shared.txt")
+        );
+        assert_eq!(doc_id, "room/shared.txt");
+        // A document going by the alias itself is joined as it is.
+        let (_dee, redirected, doc_id, sync) = join_doc(&server, "room/notes", "dee").await;
+        assert_eq!((redirected, doc_id.as_str()), (None, "room/notes"));
+        assert_eq!(sync.text, "notes");
+
+        // Once people are in, only the first of them changes the settings.
+        let cy = make_scoped_user_id("room/shared.txt", "cy");
+        let refused = set_room_meta(&server.state, Some(&cy), "room", RoomMeta::default()).await;
+        assert!(refused.unwrap_err().contains("only bob"));
+        set_room_meta(&server.state, Some(&bob), "room", RoomMeta::default())
+            .await
+            .unwrap();
+        let (_, redirected, _, _) = join_doc(&server, "room/shared", "eve").await;
+        assert!(redirected.is_none());
+        let bad = RoomMeta {
+            default_doc: Some("../escape".into()),
+            ..RoomMeta::default()
+        };
+        assert!(
+            set_room_meta(&server.state, None, "room", bad)
+                .await
+                .is_err()
+        );
     }
-}
 
-fn users_in_doc(users: &HashMap<String, UserState>, room: &str, doc: &str) -> Vec<WireUser> {
-    users
-        .values()
-        .filter(|u| u.room == room && u.doc == doc)
-        .map(|u| WireUser {
-            id: u.id.clone(),
-            name: u.name.clone(),
-        })
-        .collect()
-}
+    #[tokio::test]
+    async fn joins_create_documents_from_the_template() {
+        let dir =
+            std::env::temp_dir().join(format!("carnelia-join-template-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("template"), "# {{doc}} of {{room}}\n").unwrap();
+        let storage = Arc::new(MemoryStorage::new());
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.template_dir = Some(dir.clone());
+        // Loading it for anything but a join doesn't create it.
+        drop(lock_loaded(&server.state, "room", "other").await.unwrap());
+        assert!(storage.load("room", "other").await.unwrap().is_new());
 
-fn doc_key(room: &str, doc: &str) -> String {
-    format!("{}/{}", room, doc)
-}
+        let (mut ada, _) = join(&server, "ada").await;
+        let (_, sync) = welcome_and_sync(&mut ada).await;
+        assert_eq!(sync.text, "# notes of room\n");
+        let seeded = loop {
+            let line = ada.0.next_line().await.unwrap().unwrap();
+            if let Ok(ServerMessage::DocInfo {
+                seeded_from_template,
+                ..
+            }) = serde_json::from_str(&line)
+            {
+                break seeded_from_template;
+            }
+        };
+        assert!(seeded);
+        let stored = storage.load("room", "notes").await.unwrap();
+        assert_eq!(stored.text, "# notes of room\n");
+        assert!(!server.state.lock().await.docs["room/notes"].seeded);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 
-fn clamp_to_boundary(text: &str, pos: usize) -> usize {
-    let mut pos = pos.min(text.len());
-    while pos > 0 && !text.is_char_boundary(pos) {
-        pos -= 1;
+    /// Reads `pipe` up to the answer to a ping, failing on any `Notice`.
+    async fn no_notice(pipe: &mut Pipe) {
+        send(pipe, &Message::Ping).await;
+        loop {
+            let line = pipe.0.next_line().await.unwrap().unwrap();
+            assert!(!line.contains("Notice"), "{}", line);
+            if matches!(serde_json::from_str(&line), Ok(Message::Pong)) {
+                break;
+            }
+        }
     }
-    pos
-}
 
-fn byte_to_char_index(text: &str, byte_pos: usize) -> usize {
-    let byte_pos = clamp_to_boundary(text, byte_pos);
-    text[..byte_pos].chars().count()
-}
+    #[tokio::test]
+    async fn documents_growing_past_a_threshold_are_announced_once() {
+        let storage = Arc::new(MemoryStorage::new());
+        let growth = GrowthPolicy {
+            warn_lines: Some(2),
+            ..GrowthPolicy::default()
+        };
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.growth = growth;
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+        // The whole room hears of it, not only the document.
+        let (mut bob, _, _, _) = join_doc(&server, "room/other", "bob").await;
 
-fn apply_op_to_doc(doc_state: &mut DocState, user_id: &str, op: &Op) {
-    match op {
-        Op::Insert { pos, text } => {
-            let current = doc_state.doc.get_text();
-            let char_pos = byte_to_char_index(&current, *pos);
-            doc_state.doc.insert(char_pos, text);
+        send(&mut ada, &insert("notes", &ada_id, 0, "one\ntwo")).await;
+        no_notice(&mut ada).await;
+        send(&mut ada, &insert("notes", &ada_id, 7, "\nthree")).await;
+        let notice = |msg| match msg {
+            ServerMessage::Notice { room, doc, text } => Some((room, doc, text)),
+            _ => None,
+        };
+        let expected = (
+            "room".to_string(),
+            "notes".to_string(),
+            "notes is over 2 lines; consider moving on to a new document".to_string(),
+        );
+        assert_eq!(next_notice(&mut ada, notice).await, expected);
+        assert_eq!(next_notice(&mut bob, notice).await, expected);
+        send(&mut ada, &insert("notes", &ada_id, 13, "\nfour")).await;
+        no_notice(&mut ada).await;
+
+        // Nor again after a restart.
+        persistence::flush_all(&server.state).await.unwrap();
+        let stored = storage.load("room", "notes").await.unwrap();
+        assert_eq!(stored.meta.warned, [Threshold::Lines]);
+        drop((ada, bob, server));
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.growth = growth;
+        let (mut cy, cy_id) = join(&server, "cy").await;
+        welcome_and_sync(&mut cy).await;
+        send(&mut cy, &insert("notes", &cy_id, 0, "zero\n")).await;
+        no_notice(&mut cy).await;
+        let (text, _) = server.doc("room", "notes").await.unwrap();
+        assert_eq!(text, "zero\none\ntwo\nthree\nfour");
+    }
+
+    #[tokio::test]
+    async fn auto_rotation_moves_the_document_and_its_users() {
+        let storage = Arc::new(MemoryStorage::new());
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.growth = GrowthPolicy {
+            warn_bytes: Some(10),
+            auto_rotate: true,
+            ..GrowthPolicy::default()
+        };
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        welcome_and_sync(&mut bob).await;
+        let to = growth::rotated_name("notes", &templates::utc_date(SystemTime::now()), 0);
+
+        send(&mut ada, &insert("notes", &ada_id, 0, "a long line")).await;
+        for pipe in [&mut ada, &mut bob] {
+            let renamed = next_notice(pipe, |msg| match msg {
+                ServerMessage::DocRenamed { room, from, to } => Some((room, from, to)),
+                _ => None,
+            })
+            .await;
+            assert_eq!(renamed, ("room".into(), "notes".into(), to.clone()));
+            let text = next_notice(pipe, |msg| match msg {
+                ServerMessage::Notice { text, .. } => Some(text),
+                _ => None,
+            })
+            .await;
+            assert_eq!(
+                text,
+                format!(
+                    "notes is over 10 bytes, so it was moved to {}; notes starts over empty",
+                    to
+                )
+            );
         }
-        Op::Delete { pos, len } => {
-            let current = doc_state.doc.get_text();
-            if current.is_empty() {
-                return;
-            }
-            let start = clamp_to_boundary(&current, *pos);
-            let end = clamp_to_boundary(&current, start.saturating_add(*len));
-            if start >= end {
-                return;
+        assert_eq!(server.doc("room", &to).await.unwrap().0, "a long line");
+        let (text, version) = server.doc("room", "notes").await.unwrap();
+        assert_eq!((text.as_str(), version), ("", 1), "counts on from the old");
+
+        // Bob edits the moved document under his new id; an edit still
+        // sent for the old name under his old one is dropped.
+        let moved_id = make_scoped_user_id(&doc_key("room", &to), "bob");
+        send(&mut bob, &insert(&to, &moved_id, 0, "> ")).await;
+        send(&mut bob, &encode_sync_request(&doc_key("room", &to), 0)).await;
+        let sync = loop {
+            let line = bob.0.next_line().await.unwrap().unwrap();
+            if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((_, sync, _)) = decode_sync_response(&msg)
+            {
+                break sync;
             }
-            let char_start = current[..start].chars().count();
-            let char_len = current[start..end].chars().count();
-            if char_len > 0 {
-                doc_state.doc.delete(char_start, char_len);
+        };
+        assert_eq!(sync.text, "> a long line");
+        let state = Arc::clone(&server.state);
+        let (tx, _rx) = broadcast::channel(16);
+        let late = insert("notes", &bob_id, 0, "late");
+        let mut limits = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        let (user, doc) = (Some(bob_id.as_str()), Some("notes"));
+        let rejected =
+            handle_update(&state, &tx, user, Some("room"), doc, &late, &mut limits).await;
+        assert!(rejected.unwrap_err().contains("was moved to"));
+        assert_eq!(server.doc("room", "notes").await.unwrap().0, "");
+
+        // Both are saved, and whoever joins the old name finds it empty.
+        persistence::flush_all(&state).await.unwrap();
+        assert_eq!(
+            storage.load("room", &to).await.unwrap().text,
+            "> a long line"
+        );
+        assert_eq!(storage.load("room", "notes").await.unwrap().meta.version, 1);
+        let (mut cy, _) = join(&server, "cy").await;
+        let (_, sync) = welcome_and_sync(&mut cy).await;
+        assert_eq!(sync.text, "");
+    }
+
+    #[tokio::test]
+    async fn the_message_of_the_day_is_welcomed_with_and_reloaded() {
+        let path = std::env::temp_dir().join(format!("carnelia-motd-{}", std::process::id()));
+        std::fs::write(&path, "maintenance Friday 18:00 UTC\x07\n").unwrap();
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        assert!(motd::reload(&server.state, &path, true).await);
+        assert!(!motd::reload(&server.state, &path, true).await, "unchanged");
+        let welcomed_motd = |msg| match msg {
+            ServerMessage::Welcome { motd, .. } => Some(motd),
+            _ => None,
+        };
+        let (mut ada, _) = join(&server, "ada").await;
+        let motd = next_notice(&mut ada, welcomed_motd).await;
+        assert_eq!(motd.as_deref(), Some("maintenance Friday 18:00 UTC"));
+
+        // A new one reaches everyone connected, whatever their room.
+        let (mut bob, _, _, _) = join_doc(&server, "elsewhere/notes", "bob").await;
+        std::fs::write(&path, "maintenance moved to Monday").unwrap();
+        assert!(motd::reload(&server.state, &path, true).await);
+        for pipe in [&mut ada, &mut bob] {
+            let notice = next_notice(pipe, |msg| match msg {
+                ServerMessage::Notice { room, doc, text } => Some((room, doc, text)),
+                _ => None,
+            })
+            .await;
+            let text = "message of the day: maintenance moved to Monday";
+            assert_eq!(notice, (String::new(), String::new(), text.to_string()));
+        }
+        let (mut cy, _) = join(&server, "cy").await;
+        let motd = next_notice(&mut cy, welcomed_motd).await;
+        assert_eq!(motd.as_deref(), Some("maintenance moved to Monday"));
+
+        // Without the file there is none, and nothing to announce.
+        std::fs::remove_file(&path).unwrap();
+        assert!(motd::reload(&server.state, &path, true).await);
+        no_notice(&mut ada).await;
+        let (mut dee, _) = join(&server, "dee").await;
+        assert_eq!(next_notice(&mut dee, welcomed_motd).await, None);
+    }
+
+    #[tokio::test]
+    async fn connections_get_only_what_they_subscribed_to() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (mut ada, _) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+        let subscribe = |subscriptions: &[Subscription]| ClientMessage::SetSubscriptions {
+            subscriptions: subscriptions.to_vec(),
+        };
+        let line = serde_json::to_string(&subscribe(&[Subscription::Edits])).unwrap();
+        ada.1
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        no_notice(&mut ada).await;
+
+        let (mut bob, bob_id) = join(&server, "bob").await;
+        welcome_and_sync(&mut bob).await;
+        let cursor = |pos| {
+            let op = Op::Cursor { pos, at: None };
+            encode_update("room/notes", &bob_id, op, Vec::new(), 0).unwrap()
+        };
+        send(&mut bob, &insert("notes", &bob_id, 0, "hi")).await;
+        send(&mut bob, &cursor(1)).await;
+        tokio::time::sleep(presence::WINDOW * 2).await;
+        send(&mut bob, &insert("notes", &bob_id, 2, "!")).await;
+        let mut inserts = 0;
+        while inserts < 2 {
+            let line = ada.0.next_line().await.unwrap().unwrap();
+            assert!(
+                !line.contains("UserEvent") && !line.contains("Members"),
+                "{}",
+                line
+            );
+            assert!(!line.contains("Activity"), "{}", line);
+            if let Ok(msg) = serde_json::from_str::<Message>(&line) {
+                match decode_update(&msg).map(|(_, payload, _)| payload.op) {
+                    Some(Op::Insert { .. }) => inserts += 1,
+                    Some(op) => panic!("unsubscribed {:?}", op),
+                    None => {}
+                }
             }
         }
-        Op::Cursor { pos } => {
-            let current = doc_state.doc.get_text();
-            let clamped = clamp_to_boundary(&current, *pos);
-            doc_state.cursors.insert(user_id.to_string(), clamped);
+
+        // Subscribing again mid-session brings the rest back.
+        let line = serde_json::to_string(&subscribe(&Subscription::ALL)).unwrap();
+        ada.1
+            .write_all(format!("{}\n", line).as_bytes())
+            .await
+            .unwrap();
+        no_notice(&mut ada).await;
+        send(&mut bob, &cursor(2)).await;
+        loop {
+            let line = ada.0.next_line().await.unwrap().unwrap();
+            if let Ok(msg) = serde_json::from_str::<Message>(&line)
+                && let Some((_, payload, _)) = decode_update(&msg)
+            {
+                assert!(matches!(payload.op, Op::Cursor { pos: 2, .. }));
+                break;
+            }
         }
     }
-}
 
-fn split_doc_id(document_id: &str) -> (String, String) {
-    match document_id.split_once('/') {
-        Some((room, doc)) => (room.to_string(), doc.to_string()),
-        None => ("default".to_string(), document_id.to_string()),
+    #[tokio::test]
+    async fn names_differing_only_in_case_are_merged_or_warned_about() {
+        let storage = Arc::new(MemoryStorage::new());
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.doc_names = DocNames::Merge;
+        // Both join at once, neither name existing yet.
+        let ((mut ada, ada_redirect, ada_doc, _), (bob, bob_redirect, bob_doc, _)) = tokio::join!(
+            join_doc(&server, "room/Notes.txt", "ada"),
+            join_doc(&server, "room/notes.txt", "bob"),
+        );
+        assert_eq!(ada_doc, bob_doc, "one document");
+        let redirects = [&ada_redirect, &bob_redirect];
+        assert_eq!(
+            redirects
+                .iter()
+                .filter(|redirect| redirect.is_some())
+                .count(),
+            1
+        );
+        let docs: Vec<String> = server.state.lock().await.docs.keys().cloned().collect();
+        assert_eq!(docs, [ada_doc.as_str()]);
+        // Edits are made under the id scoped to the doc joined.
+        let ada_id = make_scoped_user_id(&ada_doc, "ada");
+        let (_, doc) = split_doc_id(&ada_doc);
+        send(&mut ada, &insert(&doc, &ada_id, 0, "shared")).await;
+        no_notice(&mut ada).await;
+        drop((ada, bob));
+
+        // A stored one is found too, after a restart.
+        persistence::flush_all(&server.state).await.unwrap();
+        let server = LocalServer::new(Arc::clone(&storage) as _);
+        server.state.lock().await.doc_names = DocNames::Merge;
+        let (_, redirect, cy_doc, sync) = join_doc(&server, "room/NOTES.TXT", "cy").await;
+        assert_eq!(redirect, Some((doc.clone(), "NOTES.TXT".to_string())));
+        assert_eq!((cy_doc, sync.text.as_str()), (ada_doc, "shared"));
+
+        // By default the name is joined as asked, with a warning.
+        server.state.lock().await.doc_names = DocNames::Warn;
+        let (mut dee, redirect, dee_doc, sync) = join_doc(&server, "room/notes.TXT", "dee").await;
+        assert_eq!(
+            (redirect, dee_doc.as_str(), sync.text.as_str()),
+            (None, "room/notes.TXT", "")
+        );
+        let warning = next_notice(&mut dee, |msg| match msg {
+            ServerMessage::Notice { text, .. } => Some(text),
+            _ => None,
+        })
+        .await;
+        assert_eq!(warning, names::warning("notes.TXT", &doc));
+        server.state.lock().await.doc_names = DocNames::Distinct;
+        let (mut eve, redirect, _, _) = join_doc(&server, "room/Notes.TXT", "eve").await;
+        assert_eq!(redirect, None);
+        no_notice(&mut eve).await;
+    }
+
+    #[tokio::test]
+    async fn malformed_lines_are_answered_and_too_many_close_the_connection() {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        server.state.lock().await.limits.max_malformed = Some(20);
+        let (mut ada, ada_id) = join(&server, "ada").await;
+        welcome_and_sync(&mut ada).await;
+
+        // Edits between the garbage still apply.
+        let mut lines = String::new();
+        for pos in 0..19 {
+            lines += "{\"Update\":\n";
+            let edit = insert("notes", &ada_id, pos, "x");
+            lines += &format!("{}\n", serde_json::to_string(&edit).unwrap());
+        }
+        ada.1.write_all(lines.as_bytes()).await.unwrap();
+        while server.doc("room", "notes").await.map(|(text, _)| text) != Some("x".repeat(19)) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.state.lock().await.users.len(), 1);
+
+        ada.1.write_all(b"not json\n").await.unwrap();
+        let mut errors = Vec::new();
+        loop {
+            let line = tokio::time::timeout(Duration::from_secs(5), ada.0.next_line())
+                .await
+                .expect("the server closes the connection");
+            let Some(line) = line.unwrap() else {
+                break;
+            };
+            if let Ok(ServerMessage::Error { code, error }) = serde_json::from_str(&line) {
+                errors.push((code, error));
+            }
+        }
+        // The first of every five is answered, then the last.
+        let codes: Vec<ErrorCode> = errors.iter().map(|(code, _)| *code).collect();
+        let mut expected = vec![ErrorCode::MalformedMessage; 4];
+        expected.push(ErrorCode::ProtocolViolation);
+        assert_eq!(codes, expected);
+        assert!(errors[0].1.contains("line 1 column 10"), "{}", errors[0].1);
+        assert!(errors[4].1.contains("line 1 column 2"), "{}", errors[4].1);
+
+        while !server.state.lock().await.users.is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let metrics = server.state.lock().await.outbound.render();
+        assert!(
+            metrics.contains("malformed_messages_total 20\n"),
+            "{}",
+            metrics
+        );
     }
 }
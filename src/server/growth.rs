@@ -0,0 +1,110 @@
+//! `--warn-doc-bytes` and `--warn-doc-lines`: a document growing past
+//! either is announced to its room once, as a nudge to rotate it, e.g. a
+//! log that gets longer every day. With `--auto-rotate` the server does it
+//! itself: the document moves to `<name>-YYYYMMDD`, its users with it, and
+//! an empty one takes its name. Which sizes were announced is kept in the
+//! document's metadata, so a restart doesn't announce them again.
+
+use crate::storage::Threshold;
+
+/// Rotated names tried for a document before giving up, when it was
+/// rotated that many times in a day.
+pub(super) const NAME_ATTEMPTS: usize = 100;
+
+/// When documents are announced, or rotated; the default does neither.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GrowthPolicy {
+    pub warn_bytes: Option<usize>,
+    pub warn_lines: Option<usize>,
+    /// Rotate a document instead of only announcing it.
+    pub auto_rotate: bool,
+}
+
+impl GrowthPolicy {
+    /// The sizes a document of `bytes` and `lines` is past that aren't in
+    /// `warned` yet.
+    pub(super) fn crossed(
+        &self,
+        bytes: usize,
+        lines: usize,
+        warned: &[Threshold],
+    ) -> Vec<Threshold> {
+        [
+            (Threshold::Bytes, self.warn_bytes, bytes),
+            (Threshold::Lines, self.warn_lines, lines),
+        ]
+        .into_iter()
+        .filter(|(threshold, limit, size)| {
+            limit.is_some_and(|limit| *size > limit) && !warned.contains(threshold)
+        })
+        .map(|(threshold, _, _)| threshold)
+        .collect()
+    }
+
+    /// What `crossed` means, e.g. "over 5000 lines".
+    pub(super) fn describe(&self, crossed: &[Threshold]) -> String {
+        let parts: Vec<String> = crossed
+            .iter()
+            .map(|threshold| match threshold {
+                Threshold::Bytes => format!("{} bytes", self.warn_bytes.unwrap_or_default()),
+                Threshold::Lines => format!("{} lines", self.warn_lines.unwrap_or_default()),
+            })
+            .collect();
+        format!("over {}", parts.join(" and "))
+    }
+}
+
+/// The `attempt`th name tried for `doc` rotated on `date`, `YYYY-MM-DD`:
+/// the date goes before the extension, so `log.md` becomes
+/// `log-20261016.md`, and later attempts count on from `-2`.
+pub(super) fn rotated_name(doc: &str, date: &str, attempt: usize) -> String {
+    let date = date.replace('-', "");
+    let suffix = match attempt {
+        0 => date,
+        n => format!("{}-{}", date, n + 1),
+    };
+    // Only the last part of a nested name has an extension, and a leading
+    // dot is a hidden file, not one.
+    let base = doc.rfind('/').map_or(0, |slash| slash + 1);
+    match doc[base..].rfind('.').filter(|dot| *dot > 0) {
+        Some(dot) => {
+            let dot = base + dot;
+            format!("{}-{}{}", &doc[..dot], suffix, &doc[dot..])
+        }
+        None => format!("{}-{}", doc, suffix),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes_are_crossed_once_and_rotated_names_keep_the_extension() {
+        let policy = GrowthPolicy {
+            warn_bytes: Some(100),
+            warn_lines: Some(10),
+            auto_rotate: false,
+        };
+        assert!(policy.crossed(100, 10, &[]).is_empty(), "at, not over");
+        assert_eq!(policy.crossed(101, 3, &[]), [Threshold::Bytes]);
+        assert!(policy.crossed(500, 3, &[Threshold::Bytes]).is_empty());
+        let both = policy.crossed(500, 11, &[]);
+        assert_eq!(both, [Threshold::Bytes, Threshold::Lines]);
+        assert_eq!(policy.describe(&both), "over 100 bytes and 10 lines");
+        assert!(
+            GrowthPolicy::default()
+                .crossed(usize::MAX, 1, &[])
+                .is_empty()
+        );
+
+        assert_eq!(rotated_name("log", "2026-10-16", 0), "log-20261016");
+        assert_eq!(rotated_name("log.md", "2026-10-16", 0), "log-20261016.md");
+        assert_eq!(rotated_name("log.md", "2026-10-16", 1), "log-20261016-2.md");
+        assert_eq!(rotated_name(".notes", "2026-10-16", 0), ".notes-20261016");
+        assert_eq!(
+            rotated_name("v1.2/log", "2026-10-16", 0),
+            "v1.2/log-20261016"
+        );
+    }
+}
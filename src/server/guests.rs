@@ -0,0 +1,59 @@
+//! Names for guests, users who said hello without one: `guest-tiger-42`.
+//! A name comes from the client's id, so a guest reconnecting or joining
+//! another document of the room gets the same one, unless somebody else
+//! in the room has taken it meanwhile.
+
+use std::hash::{DefaultHasher, Hash, Hasher};
+
+const ANIMALS: &[&str] = &[
+    "badger", "beaver", "bison", "crane", "eagle", "falcon", "ferret", "fox", "gecko", "heron",
+    "ibis", "jackal", "koala", "lemur", "lynx", "marten", "moose", "newt", "otter", "owl", "panda",
+    "puffin", "quail", "raven", "seal", "stoat", "swan", "tapir", "tiger", "walrus", "wombat",
+    "yak",
+];
+
+/// Tries with a number below 100 before going to longer ones.
+const SHORT_ATTEMPTS: u64 = 32;
+
+/// A guest name for the client `raw_id` (its id without the document)
+/// that `taken` says nobody else in the room has.
+pub(super) fn guest_name(raw_id: &str, taken: impl Fn(&str) -> bool) -> String {
+    (0..)
+        .map(|attempt| candidate(raw_id, attempt))
+        .find(|name| !taken(name))
+        .expect("guest names never run out")
+}
+
+/// The `attempt`th name tried for `raw_id`.
+fn candidate(raw_id: &str, attempt: u64) -> String {
+    let mut hasher = DefaultHasher::new();
+    (raw_id, attempt).hash(&mut hasher);
+    let hash = hasher.finish();
+    let animal = ANIMALS[(hash % ANIMALS.len() as u64) as usize];
+    let numbers = if attempt < SHORT_ATTEMPTS {
+        100
+    } else {
+        100_000
+    };
+    let number = (hash / ANIMALS.len() as u64) % numbers;
+    format!("guest-{}-{}", animal, number)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guests_get_the_same_name_until_someone_has_it() {
+        let first = guest_name("-1700000000000", |_| false);
+        assert!(first.starts_with("guest-"), "{}", first);
+        assert_eq!(guest_name("-1700000000000", |_| false), first);
+
+        // Taken by someone else in the room: the next one that isn't.
+        let second = guest_name("-1700000000000", |name| name == first);
+        assert_ne!(second, first);
+        assert_eq!(second, candidate("-1700000000000", 1));
+        let crowded = |name: &str| (0..SHORT_ATTEMPTS + 3).any(|n| candidate("x", n) == name);
+        assert_eq!(guest_name("x", crowded), candidate("x", SHORT_ATTEMPTS + 3));
+    }
+}
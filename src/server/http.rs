@@ -0,0 +1,413 @@
+//! Read-only document pages on the health listener, with
+//! `--enable-http-read`. `GET /rooms/{room}/docs/{doc}` serves the text as
+//! is (`?format=raw`, the default) or rendered from Markdown to HTML
+//! (`?format=md`). Names are percent-encoded in the path, except for the
+//! `/`s of nested doc names. Documents are collaborative, so nothing in
+//! them is trusted: HTML in the text is shown escaped, links can't run
+//! script, and the page forbids scripts altogether. Documents whose ACL
+//! lists readers are answered as if they didn't exist.
+
+use super::{SharedState, doc_key};
+use crate::storage::{Access, Acl, WhitespacePolicy, percent_decode};
+use std::fmt::Write as _;
+use tokio::sync::Mutex;
+
+const STYLE: &str = "body{max-width:46em;margin:2em auto;padding:0 1em;\
+    font:16px/1.5 system-ui,sans-serif;color:#222}\
+    pre,code{background:#f4f4f4;border-radius:3px}pre{padding:.6em;overflow:auto}\
+    blockquote{margin-left:0;padding-left:1em;border-left:3px solid #ccc;color:#555}\
+    table{border-collapse:collapse}td,th{border:1px solid #ccc;padding:.2em .5em}\
+    img{max-width:100%}";
+/// Styles only: no scripts, frames, forms or plugins.
+const CSP: &str = "default-src 'none'; style-src 'unsafe-inline'; img-src * data:";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Raw,
+    Markdown,
+}
+
+/// The response to `GET target` if `target` is a document URL.
+pub(super) async fn doc_response(
+    state: &Mutex<SharedState>,
+    target: &str,
+    if_none_match: Option<&str>,
+) -> Option<Vec<u8>> {
+    let (room, doc, format) = match parse_target(target)? {
+        Ok(parsed) => parsed,
+        Err(why) => return Some(response("400 Bad Request", "text/plain", &[], why)),
+    };
+    let guard = state.lock().await;
+    if let Err(err) = guard.storage.validate(&room, &doc) {
+        return Some(response(
+            "400 Bad Request",
+            "text/plain",
+            &[],
+            &err.to_string(),
+        ));
+    }
+    let not_found = || response("404 Not Found", "text/plain", &[], "no such document\n");
+    // An open document may have edits that aren't saved yet.
+    let (text, version, acl) = match guard.docs.get(&doc_key(&room, &doc)) {
+        Some(doc_state) => (
+            doc_state.doc.get_text(),
+            doc_state.version,
+            doc_state.meta.acl.clone(),
+        ),
+        None => {
+            let storage = std::sync::Arc::clone(&guard.storage);
+            drop(guard);
+            match storage.load(&room, &doc).await {
+                // Never saved: there is no such document.
+                Ok(stored) if stored.text.is_empty() && stored.meta.version == 0 => {
+                    return Some(not_found());
+                }
+                Ok(stored) => (stored.text, stored.meta.version, stored.meta.acl),
+                Err(err) => {
+                    let body = format!("{}\n", err);
+                    return Some(response(
+                        "500 Internal Server Error",
+                        "text/plain",
+                        &[],
+                        &body,
+                    ));
+                }
+            }
+        }
+    };
+
+    // Nobody says who they are here, so only documents everyone may read
+    // are served, and the others aren't told apart from missing ones.
+    if acl.access("") == Access::Denied {
+        return Some(not_found());
+    }
+
+    let etag = format!("\"v{}-{}-{:?}\"", version, text.len(), format);
+    if if_none_match.is_some_and(|tags| {
+        tags.split(',')
+            .any(|tag| matches!(tag.trim(), "*") || tag.trim() == etag)
+    }) {
+        return Some(response(
+            "304 Not Modified",
+            "text/plain",
+            &[("ETag", &etag)],
+            "",
+        ));
+    }
+    let headers = [("ETag", etag.as_str()), ("Cache-Control", "no-cache")];
+    Some(match format {
+        Format::Raw => response("200 OK", "text/plain; charset=utf-8", &headers, &text),
+        Format::Markdown => {
+            let page = render_page(&format!("{}/{}", room, doc), &text);
+            let headers = [headers[0], headers[1], ("Content-Security-Policy", CSP)];
+            response("200 OK", "text/html; charset=utf-8", &headers, &page)
+        }
+    })
+}
+
+/// The room, doc and format of a document URL; `None` for other paths.
+fn parse_target(target: &str) -> Option<Result<(String, String, Format), &'static str>> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+    let (room, doc) = path.strip_prefix("/rooms/")?.split_once("/docs/")?;
+    let parsed = (|| {
+        let room = percent_decode(room).ok_or("room name is not percent-encoded UTF-8\n")?;
+        let doc = decode_doc(doc).ok_or("doc name is not percent-encoded UTF-8\n")?;
+        let format = match query
+            .split('&')
+            .find_map(|pair| pair.strip_prefix("format="))
+        {
+            None | Some("raw") => Format::Raw,
+            Some("md") => Format::Markdown,
+            Some(_) => return Err("format must be raw or md\n"),
+        };
+        Ok((room, doc, format))
+    })();
+    Some(parsed)
+}
+
+/// The room and doc of `<room>/<doc>`, e.g. the end of
+/// `/admin/snapshot/<room>/<doc>`.
+pub(super) fn parse_doc_path(path: &str) -> Option<(String, String)> {
+    let (room, doc) = path.split_once('/')?;
+    Some((percent_decode(room)?, decode_doc(doc)?))
+}
+
+/// The room of `/admin/trash/<room>`.
+pub(super) fn parse_room_path(path: &str) -> Option<String> {
+    percent_decode(path).filter(|room| !room.is_empty())
+}
+
+/// The new name and whether to force it, from the query of
+/// `/admin/restore/<room>/<doc>`, e.g. `as=notes%20v2&force=true`.
+pub(super) fn parse_restore(query: &str) -> Result<(Option<String>, bool), String> {
+    let (mut to, mut force) = (None, false);
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, "true"));
+        match name {
+            "as" => {
+                to = Some(decode_doc(value).ok_or("as is not percent-encoded UTF-8\n")?);
+            }
+            "force" => {
+                force = match value {
+                    "true" | "1" => true,
+                    "false" | "0" => false,
+                    _ => return Err("force must be true or false\n".to_string()),
+                };
+            }
+            _ => {
+                return Err(format!(
+                    "unknown parameter {}; expected as or force\n",
+                    name
+                ));
+            }
+        }
+    }
+    Ok((to, force))
+}
+
+/// The user in the query of `/admin/kick/<room>/<doc>`, e.g. `user=ada`.
+pub(super) fn parse_kick(query: &str) -> Result<String, String> {
+    let user = query
+        .split('&')
+        .find_map(|pair| pair.strip_prefix("user="))
+        .ok_or("expected user=<name>\n")?;
+    match percent_decode(user) {
+        Some(user) if !user.is_empty() => Ok(user),
+        _ => Err("user is not a percent-encoded name\n".to_string()),
+    }
+}
+
+/// The status of an admin request that failed with `err`.
+pub(super) fn error_status(err: &std::io::Error) -> &'static str {
+    match err.kind() {
+        std::io::ErrorKind::NotFound => "404 Not Found",
+        std::io::ErrorKind::AlreadyExists => "409 Conflict",
+        std::io::ErrorKind::InvalidInput => "400 Bad Request",
+        _ => "500 Internal Server Error",
+    }
+}
+
+/// The policy in the query of `/admin/policy/<room>/<doc>`, e.g.
+/// `final_newline=true&trim_trailing=true`; rules left out are off.
+pub(super) fn parse_policy(query: &str) -> Result<WhitespacePolicy, String> {
+    let mut policy = WhitespacePolicy::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, "true"));
+        let on = match value {
+            "true" | "1" => true,
+            "false" | "0" => false,
+            _ => return Err(format!("{} must be true or false\n", name)),
+        };
+        match name {
+            "final_newline" => policy.final_newline = on,
+            "trim_trailing" => policy.trim_trailing = on,
+            "strict" => policy.strict = on,
+            _ => {
+                return Err(format!(
+                    "unknown rule {}; expected final_newline, trim_trailing or strict\n",
+                    name
+                ));
+            }
+        }
+    }
+    Ok(policy)
+}
+
+/// The ACL in the query of `/admin/acl/<room>/<doc>`, e.g.
+/// `writers=ada,bob&readers=cy`; a list left out lets everyone.
+pub(super) fn parse_acl(query: &str) -> Result<Acl, String> {
+    let mut acl = Acl::default();
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let names = value
+            .split(',')
+            .filter(|user| !user.is_empty())
+            .map(|user| {
+                percent_decode(user).ok_or(format!("{} is not percent-encoded UTF-8\n", name))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        match name {
+            "writers" => acl.writers = names,
+            "readers" => acl.readers = names,
+            _ => {
+                return Err(format!(
+                    "unknown list {}; expected writers or readers\n",
+                    name
+                ));
+            }
+        }
+    }
+    Ok(acl)
+}
+
+/// A doc name with each `/`-separated part percent-decoded.
+fn decode_doc(doc: &str) -> Option<String> {
+    let parts: Option<Vec<String>> = doc.split('/').map(percent_decode).collect();
+    parts.map(|parts| parts.join("/"))
+}
+
+fn response(status: &str, content_type: &str, headers: &[(&str, &str)], body: &str) -> Vec<u8> {
+    let mut head = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n",
+        status,
+        content_type,
+        body.len()
+    );
+    for (name, value) in headers {
+        let _ = write!(head, "{}: {}\r\n", name, value);
+    }
+    head.push_str("\r\n");
+    let mut bytes = head.into_bytes();
+    bytes.extend_from_slice(body.as_bytes());
+    bytes
+}
+
+fn render_page(title: &str, text: &str) -> String {
+    format!(
+        "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\">\
+         <title>{}</title><style>{}</style></head>\n<body>\n{}</body></html>\n",
+        escape_html(title),
+        STYLE,
+        markdown_html(text)
+    )
+}
+
+#[cfg(feature = "markdown")]
+fn markdown_html(text: &str) -> String {
+    use pulldown_cmark::{Event, Options, Parser, Tag};
+    let options =
+        Options::ENABLE_TABLES | Options::ENABLE_STRIKETHROUGH | Options::ENABLE_TASKLISTS;
+    let events = Parser::new_ext(text, options).map(|event| match event {
+        // Shown as written rather than interpreted.
+        Event::Html(html) | Event::InlineHtml(html) => Event::Text(html),
+        Event::Start(Tag::Link {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Link {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        Event::Start(Tag::Image {
+            link_type,
+            dest_url,
+            title,
+            id,
+        }) => Event::Start(Tag::Image {
+            link_type,
+            dest_url: safe_url(dest_url),
+            title,
+            id,
+        }),
+        event => event,
+    });
+    let mut html = String::new();
+    pulldown_cmark::html::push_html(&mut html, events);
+    html
+}
+
+/// Without the `markdown` feature the text is shown preformatted.
+#[cfg(not(feature = "markdown"))]
+fn markdown_html(text: &str) -> String {
+    format!("<pre>{}</pre>\n", escape_html(text))
+}
+
+/// `url`, unless its scheme can run script when followed.
+#[cfg(feature = "markdown")]
+fn safe_url(url: pulldown_cmark::CowStr<'_>) -> pulldown_cmark::CowStr<'_> {
+    // Browsers ignore whitespace and control characters in schemes.
+    let scheme: String = url
+        .split(':')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|ch| !ch.is_whitespace() && !ch.is_control())
+        .collect();
+    let unsafe_scheme = url.contains(':')
+        && ["javascript", "vbscript", "data"]
+            .iter()
+            .any(|bad| scheme.eq_ignore_ascii_case(bad));
+    if unsafe_scheme { "#".into() } else { url }
+}
+
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for ch in text.chars() {
+        match ch {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            ch => escaped.push(ch),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn document_urls_decode_nested_names_and_formats() {
+        let parsed = parse_target("/rooms/team%20a/docs/notes/2024/stand%2Dup.md?format=md");
+        let expected = (
+            "team a".into(),
+            "notes/2024/stand-up.md".into(),
+            Format::Markdown,
+        );
+        assert_eq!(parsed, Some(Ok(expected)));
+        let raw = parse_target("/rooms/r/docs/d").unwrap().unwrap();
+        assert_eq!(raw.2, Format::Raw);
+        assert!(parse_target("/rooms/r/docs/d?format=pdf").unwrap().is_err());
+        assert!(parse_target("/rooms/r/docs/%ff").unwrap().is_err());
+        assert_eq!(parse_target("/metrics"), None);
+    }
+
+    #[test]
+    fn pages_escape_script_from_the_document() {
+        let text = "# Notes\n\n<script>alert(1)</script>\n\nhi <img src=x onerror=alert(2)> \
+                    [click](javascript:alert(3)) [also](JaVa\tScRiPt:alert(4)) [ok](https://example.com)\n";
+        let page = render_page("team/<b>notes</b>", text);
+        assert!(!page.contains("<script"), "{}", page);
+        assert!(!page.contains("<img"), "{}", page);
+        assert!(
+            page.contains("&lt;script&gt;alert(1)&lt;/script&gt;"),
+            "{}",
+            page
+        );
+        assert!(page.contains("<title>team/&lt;b&gt;notes&lt;/b&gt;</title>"));
+        // Without the feature, links stay escaped text.
+        #[cfg(feature = "markdown")]
+        assert!(
+            !page.to_ascii_lowercase().contains("javascript:"),
+            "{}",
+            page
+        );
+        #[cfg(feature = "markdown")]
+        assert!(page.contains("<h1>Notes</h1>") && page.contains("href=\"https://example.com\""));
+    }
+
+    #[test]
+    fn policy_queries_name_the_rules_that_are_on() {
+        let policy = parse_policy("final_newline=true&strict=1").unwrap();
+        assert!(policy.final_newline && policy.strict && !policy.trim_trailing);
+        assert_eq!(parse_policy("").unwrap(), WhitespacePolicy::default());
+        assert!(parse_policy("trim_trailing=yes").is_err());
+        assert!(parse_policy("tabs=true").is_err());
+
+        let acl = parse_acl("writers=ada,b%C3%B6b&readers=").unwrap();
+        assert_eq!(acl.writers, ["ada", "böb"]);
+        assert!(acl.readers.is_empty());
+        assert!(parse_acl("").unwrap().is_open());
+        assert!(parse_acl("owners=ada").is_err());
+
+        assert_eq!(parse_kick("user=b%C3%B6b").unwrap(), "böb");
+        assert!(parse_kick("user=").is_err());
+        assert!(parse_kick("").is_err());
+    }
+}
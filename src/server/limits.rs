@@ -0,0 +1,206 @@
+//! Enforcing the `ServerLimits` a connection was welcomed with. Document
+//! size is checked where edits are applied; this holds what is tracked per
+//! connection.
+
+use crate::protocol::ServerLimits;
+use std::time::{Duration, Instant};
+
+/// Sync requests a connection may make back to back.
+const SYNC_BURST: f64 = 10.0;
+/// Past the burst, one sync request is answered this often; the others
+/// wait their turn.
+const SYNC_EVERY: Duration = Duration::from_secs(1);
+/// Of the malformed lines from a connection, the first and then every this
+/// many are answered with an error.
+const MALFORMED_REPLY_EVERY: u32 = 5;
+
+/// What to do about a line from the client that isn't a message.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Malformed {
+    /// Tell the client where it went wrong.
+    Reply,
+    /// Drop it without a word; the client was told recently.
+    Drop,
+    /// That was one too many: close the connection.
+    Disconnect,
+}
+
+/// Per-connection state for the rate and idle limits.
+pub(super) struct ConnectionLimits {
+    pub(super) limits: ServerLimits,
+    /// Text edits that may be sent right now, refilled continuously up to
+    /// `max_ops_per_second`.
+    tokens: f64,
+    refilled: Instant,
+    /// Sync requests that may be answered right now, refilled one per
+    /// `SYNC_EVERY` up to `SYNC_BURST`.
+    sync_tokens: f64,
+    /// Ahead of now while a request waits its turn.
+    sync_refilled: Instant,
+    last_heard: Instant,
+    /// Lines so far that weren't messages.
+    malformed: u32,
+}
+
+impl ConnectionLimits {
+    pub(super) fn new(limits: ServerLimits, now: Instant) -> Self {
+        Self {
+            limits,
+            tokens: limits.max_ops_per_second.map_or(0.0, f64::from),
+            refilled: now,
+            sync_tokens: SYNC_BURST,
+            sync_refilled: now,
+            last_heard: now,
+            malformed: 0,
+        }
+    }
+
+    /// Notes a line from the client; an error if it is too long.
+    pub(super) fn heard(&mut self, line: &str, now: Instant) -> Result<(), String> {
+        self.last_heard = now;
+        match self.limits.max_message_bytes {
+            Some(max) if line.len() > max => Err(format!(
+                "message too large: {} bytes, the server takes at most {}",
+                line.len(),
+                max
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Takes one text edit from the budget; an error if there is none left.
+    pub(super) fn take_op(&mut self, now: Instant) -> Result<(), String> {
+        let Some(rate) = self.limits.max_ops_per_second else {
+            return Ok(());
+        };
+        let rate = f64::from(rate);
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return Err(format!(
+                "too many edits: the server takes at most {} per second",
+                rate
+            ));
+        }
+        self.tokens -= 1.0;
+        Ok(())
+    }
+
+    /// Takes one sync request from the budget, returning how long it has
+    /// to wait before it is answered. Every request is answered in the
+    /// end, since the client counts on it; a flood is only slowed down.
+    pub(super) fn take_sync(&mut self, now: Instant) -> Duration {
+        let elapsed = now.saturating_duration_since(self.sync_refilled);
+        let refill = elapsed.as_secs_f64() / SYNC_EVERY.as_secs_f64();
+        self.sync_tokens = (self.sync_tokens + refill).min(SYNC_BURST);
+        self.sync_refilled = self.sync_refilled.max(now);
+        if self.sync_tokens >= 1.0 {
+            self.sync_tokens -= 1.0;
+            return Duration::ZERO;
+        }
+        let wait = SYNC_EVERY.mul_f64(1.0 - self.sync_tokens);
+        self.sync_tokens = 0.0;
+        self.sync_refilled = now + wait;
+        wait
+    }
+
+    /// Counts a line that isn't a message.
+    pub(super) fn malformed(&mut self) -> Malformed {
+        self.malformed += 1;
+        if self
+            .limits
+            .max_malformed
+            .is_some_and(|max| self.malformed >= max)
+        {
+            Malformed::Disconnect
+        } else if (self.malformed - 1).is_multiple_of(MALFORMED_REPLY_EVERY) {
+            Malformed::Reply
+        } else {
+            Malformed::Drop
+        }
+    }
+
+    /// When the connection counts as idle, if it ever does.
+    pub(super) fn idle_at(&self) -> Option<Instant> {
+        let timeout = Duration::from_secs(self.limits.idle_timeout_secs?);
+        Some(self.last_heard + timeout)
+    }
+}
+
+/// Completes once a connection is idle, never without an idle timeout.
+pub(super) async fn idle(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_past_the_rate_wait_for_the_budget_to_refill() {
+        let start = Instant::now();
+        let limits = ServerLimits {
+            max_ops_per_second: Some(2),
+            max_message_bytes: Some(5),
+            idle_timeout_secs: Some(30),
+            ..ServerLimits::default()
+        };
+        let mut conn = ConnectionLimits::new(limits, start);
+        assert!(conn.take_op(start).is_ok());
+        assert!(conn.take_op(start).is_ok());
+        assert!(conn.take_op(start).is_err());
+        assert!(conn.take_op(start + Duration::from_millis(500)).is_ok());
+        assert!(conn.take_op(start + Duration::from_millis(500)).is_err());
+        // Idle time doesn't save up more than a second's worth.
+        let later = start + Duration::from_secs(60);
+        assert!((0..2).all(|_| conn.take_op(later).is_ok()));
+        assert!(conn.take_op(later).is_err());
+
+        assert!(conn.heard("12345", later).is_ok());
+        assert!(conn.heard("123456", later).is_err());
+        assert_eq!(conn.idle_at(), Some(later + Duration::from_secs(30)));
+
+        let mut unlimited = ConnectionLimits::new(ServerLimits::default(), start);
+        assert!((0..1000).all(|_| unlimited.take_op(start).is_ok()));
+        assert_eq!(unlimited.idle_at(), None);
+    }
+
+    #[test]
+    fn malformed_lines_are_answered_now_and_then_until_too_many() {
+        let limits = ServerLimits {
+            max_malformed: Some(8),
+            ..ServerLimits::default()
+        };
+        let mut conn = ConnectionLimits::new(limits, Instant::now());
+        let seen: Vec<Malformed> = (0..8).map(|_| conn.malformed()).collect();
+        use Malformed::{Disconnect, Drop, Reply};
+        assert_eq!(
+            seen,
+            [Reply, Drop, Drop, Drop, Drop, Reply, Drop, Disconnect]
+        );
+
+        let mut unlimited = ConnectionLimits::new(ServerLimits::default(), Instant::now());
+        assert!((0..1000).all(|_| unlimited.malformed() != Disconnect));
+    }
+
+    #[test]
+    fn a_flood_of_sync_requests_is_slowed_down() {
+        let start = Instant::now();
+        let mut conn = ConnectionLimits::new(ServerLimits::default(), start);
+        assert!((0..10).all(|_| conn.take_sync(start) == Duration::ZERO));
+        // The rest are answered one per second, each after the one before.
+        assert_eq!(conn.take_sync(start), SYNC_EVERY);
+        let answered = start + SYNC_EVERY;
+        assert_eq!(conn.take_sync(answered), SYNC_EVERY);
+        let answered = answered + SYNC_EVERY;
+        assert_eq!(conn.take_sync(answered + SYNC_EVERY / 2), SYNC_EVERY / 2);
+        // Calm restores the burst.
+        let calm = answered + Duration::from_secs(60);
+        assert!((0..10).all(|_| conn.take_sync(calm) == Duration::ZERO));
+        assert!(conn.take_sync(calm) > Duration::ZERO);
+    }
+}
@@ -0,0 +1,195 @@
+//! Keeping stdout readable: `--quiet` leaves only startup, shutdown and
+//! errors, and what every connection logs (connecting, disconnecting,
+//! failing) is limited per peer, so a port scanner or a client that keeps
+//! reconnecting doesn't flood it. What was left out is summed up once a
+//! minute.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+/// Events of one kind from one peer printed per window; the rest are
+/// counted.
+const PEER_BURST: usize = 5;
+const PEER_WINDOW: Duration = Duration::from_secs(60);
+
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+static PEERS: LazyLock<Mutex<Suppressor>> =
+    LazyLock::new(|| Mutex::new(Suppressor::new(PEER_WINDOW, PEER_BURST)));
+
+/// Leaves out everything but startup, shutdown and errors from now on.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+pub(super) fn quiet() -> bool {
+    QUIET.load(Ordering::Relaxed)
+}
+
+/// Prints `line`, a `kind` of event of the connection from `peer`, unless
+/// `--quiet` is on or the peer has had its share of them.
+pub(super) fn peer_info(kind: &str, peer: &str, line: &str) {
+    if !quiet() {
+        peer_error(kind, peer, line);
+    }
+}
+
+/// Like `peer_info` for errors, which `--quiet` keeps.
+pub(super) fn peer_error(kind: &str, peer: &str, line: &str) {
+    let key = format!("{} {}", kind, host(peer));
+    let allowed = PEERS
+        .lock()
+        .unwrap_or_else(|err| err.into_inner())
+        .allow(&key, Instant::now());
+    if allowed {
+        println!("{}", line);
+    }
+}
+
+/// Prints how many events were left out, for the windows that ended.
+pub(super) async fn run_summary_loop() {
+    let mut tick = tokio::time::interval(PEER_WINDOW / 4);
+    loop {
+        tick.tick().await;
+        let ended = PEERS
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .flush(Instant::now());
+        for (key, suppressed) in ended {
+            println!(
+                "[server] {} similar events suppressed in the last minute ({})",
+                suppressed, key
+            );
+        }
+    }
+}
+
+/// The address without the port, which changes with every connection.
+fn host(peer: &str) -> String {
+    peer.parse::<SocketAddr>()
+        .map_or_else(|_| peer.to_string(), |addr| addr.ip().to_string())
+}
+
+/// Lets the first `burst` events of each key through per `window`, which
+/// starts with the first of them, and counts the rest.
+pub(super) struct Suppressor {
+    window: Duration,
+    burst: usize,
+    windows: HashMap<String, Window>,
+    /// Keys whose window ended with events left out, and how many.
+    ended: Vec<(String, usize)>,
+}
+
+struct Window {
+    started: Instant,
+    shown: usize,
+    suppressed: usize,
+}
+
+impl Suppressor {
+    pub(super) fn new(window: Duration, burst: usize) -> Self {
+        Self {
+            window,
+            burst,
+            windows: HashMap::new(),
+            ended: Vec::new(),
+        }
+    }
+
+    /// Whether the `key` event at `now` is printed.
+    pub(super) fn allow(&mut self, key: &str, now: Instant) -> bool {
+        if let Some(window) = self.windows.get(key)
+            && now.saturating_duration_since(window.started) >= self.window
+        {
+            self.end(key);
+        }
+        let window = self.windows.entry(key.to_string()).or_insert(Window {
+            started: now,
+            shown: 0,
+            suppressed: 0,
+        });
+        if window.shown < self.burst {
+            window.shown += 1;
+            true
+        } else {
+            window.suppressed += 1;
+            false
+        }
+    }
+
+    /// Ends the windows over by `now`, returning the keys that had events
+    /// left out and how many, by key.
+    pub(super) fn flush(&mut self, now: Instant) -> Vec<(String, usize)> {
+        let over: Vec<String> = self
+            .windows
+            .iter()
+            .filter(|(_, window)| now.saturating_duration_since(window.started) >= self.window)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in over {
+            self.end(&key);
+        }
+        let mut ended = std::mem::take(&mut self.ended);
+        ended.sort();
+        ended
+    }
+
+    fn end(&mut self, key: &str) {
+        if let Some(window) = self.windows.remove(key)
+            && window.suppressed > 0
+        {
+            self.ended.push((key.to_string(), window.suppressed));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_past_the_burst_are_counted_until_the_window_ends() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut suppressor = Suppressor::new(Duration::from_secs(60), 2);
+        let shown: Vec<bool> = (0..5)
+            .map(|secs| suppressor.allow("connected 10.0.0.5", at(secs)))
+            .collect();
+        assert_eq!(shown, [true, true, false, false, false]);
+        // Other peers have a share of their own.
+        assert!(suppressor.allow("connected 10.0.0.6", at(5)));
+        assert!(suppressor.flush(at(59)).is_empty());
+
+        let ended = suppressor.flush(at(60));
+        assert_eq!(ended, [("connected 10.0.0.5".to_string(), 3)]);
+        assert!(suppressor.allow("connected 10.0.0.5", at(61)));
+        assert!(suppressor.flush(at(200)).is_empty(), "nothing left out");
+    }
+
+    #[test]
+    fn a_window_ending_between_flushes_keeps_its_count() {
+        let start = Instant::now();
+        let mut suppressor = Suppressor::new(Duration::from_secs(10), 1);
+        for _ in 0..4 {
+            suppressor.allow("read error 10.0.0.5", start);
+        }
+        // The next event starts a new window before the flush saw the old.
+        let later = start + Duration::from_secs(15);
+        assert!(suppressor.allow("read error 10.0.0.5", later));
+        assert!(!suppressor.allow("read error 10.0.0.5", later));
+        assert_eq!(
+            suppressor.flush(later),
+            [("read error 10.0.0.5".to_string(), 3)]
+        );
+        assert_eq!(
+            suppressor.flush(later + Duration::from_secs(10)),
+            [("read error 10.0.0.5".to_string(), 1)]
+        );
+        assert_eq!(host("10.0.0.5:51234"), "10.0.0.5");
+        assert_eq!(host("[::1]:4000"), "::1");
+        assert_eq!(host("local"), "local");
+    }
+}
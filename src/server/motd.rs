@@ -0,0 +1,175 @@
+//! `--motd-file`: a message of the day sent to everyone in their `Welcome`,
+//! e.g. "maintenance Friday 18:00 UTC". The file is read again on SIGHUP
+//! and when it changes; with `--announce-motd` a new message also goes out
+//! to everyone connected as a `Notice`. Control characters are dropped and
+//! a long message is cut short, since clients show it as it is.
+
+use super::{SharedState, WATCH_DEBOUNCE};
+use crate::protocol::ServerMessage;
+use notify::Watcher as _;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::sync::{Mutex, mpsc};
+
+/// Characters of the message kept; the rest is cut off.
+pub(super) const MAX_CHARS: usize = 500;
+/// Lines of the message kept.
+pub(super) const MAX_LINES: usize = 10;
+
+/// Where the message of the day comes from, if anywhere.
+#[derive(Debug, Clone, Default)]
+pub struct MotdOptions {
+    pub file: Option<PathBuf>,
+    /// Send a changed message to everyone connected, not only to those
+    /// joining from then on.
+    pub announce: bool,
+}
+
+/// `text` fit to be shown: without control characters (tabs become
+/// spaces), trailing blanks or blank lines around it, and cut to
+/// `MAX_LINES` and `MAX_CHARS`. `None` if nothing is left.
+pub(super) fn clean(text: &str) -> Option<String> {
+    let lines: Vec<String> = text
+        .lines()
+        .map(|line| {
+            line.chars()
+                .map(|ch| if ch == '\t' { ' ' } else { ch })
+                .filter(|ch| !ch.is_control())
+                .collect::<String>()
+                .trim_end()
+                .to_string()
+        })
+        .collect();
+    let first = lines.iter().position(|line| !line.is_empty())?;
+    let last = lines.iter().rposition(|line| !line.is_empty())?;
+    let kept = &lines[first..=last];
+    let mut motd = kept[..kept.len().min(MAX_LINES)].join("\n");
+    let mut cut = kept.len() > MAX_LINES;
+    if let Some((end, _)) = motd.char_indices().nth(MAX_CHARS) {
+        motd.truncate(end);
+        cut = true;
+    }
+    if cut {
+        motd = format!("{}…", motd.trim_end());
+    }
+    Some(motd)
+}
+
+/// The message in `path`: `None` if the file is missing or holds nothing
+/// to show, and an error if it can't be read.
+pub(super) async fn load(path: &Path) -> io::Result<Option<String>> {
+    match tokio::fs::read_to_string(path).await {
+        Ok(text) => Ok(clean(&text)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads `path` again and takes its message if it changed, sending it to
+/// everyone connected if `announce`. Returns whether it changed; a file
+/// that can't be read leaves the message as it was.
+pub(super) async fn reload(state: &Mutex<SharedState>, path: &Path, announce: bool) -> bool {
+    let motd = match load(path).await {
+        Ok(motd) => motd,
+        Err(err) => {
+            println!("[motd] can't read {}: {}", path.display(), err);
+            return false;
+        }
+    };
+    let mut guard = state.lock().await;
+    if guard.motd == motd {
+        return false;
+    }
+    match &motd {
+        Some(_) => println!("[motd] message of the day updated"),
+        None => println!("[motd] message of the day cleared"),
+    }
+    if announce && let Some(text) = &motd {
+        let _ = guard.notices.send(ServerMessage::Notice {
+            room: String::new(),
+            doc: String::new(),
+            text: format!("message of the day: {}", text),
+        });
+    }
+    guard.motd = motd;
+    true
+}
+
+/// Reloads the message in `path` on SIGHUP and when the file changes.
+pub(super) async fn run_reload_loop(
+    path: PathBuf,
+    state: Arc<Mutex<SharedState>>,
+    announce: bool,
+) -> notify::Result<()> {
+    let (reload_tx, mut reloads) = mpsc::unbounded_channel();
+    // Editors often save by replacing the file, so its directory is
+    // watched rather than the file itself.
+    let watched = path.clone();
+    let changed_tx = reload_tx.clone();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+        if let Ok(event) = event
+            && !event.kind.is_access()
+            && event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == watched.file_name())
+        {
+            let _ = changed_tx.send(());
+        }
+    })?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    watcher.watch(dir, notify::RecursiveMode::NonRecursive)?;
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::hangup()) {
+            Ok(mut hangup) => {
+                tokio::spawn(async move {
+                    while hangup.recv().await.is_some() {
+                        if reload_tx.send(()).is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+            Err(err) => println!("[motd] can't listen for SIGHUP: {}", err),
+        }
+    }
+    #[cfg(not(unix))]
+    drop(reload_tx);
+
+    while reloads.recv().await.is_some() {
+        while let Ok(Some(())) = tokio::time::timeout(WATCH_DEBOUNCE, reloads.recv()).await {}
+        reload(&state, &path, announce).await;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn messages_lose_control_characters_and_are_cut_short() {
+        assert_eq!(clean(""), None);
+        assert_eq!(clean(" \n\t\n"), None);
+        assert_eq!(
+            clean("\n\x1b[31mmaintenance\x07\tFriday  \r\n\nbe nice\n\n").as_deref(),
+            Some("[31mmaintenance Friday\n\nbe nice")
+        );
+
+        let long = "é".repeat(MAX_CHARS + 10);
+        let cut = clean(&long).unwrap();
+        assert_eq!(cut.chars().count(), MAX_CHARS + 1);
+        assert!(cut.ends_with("é…"));
+        let many: String = (1..=20).map(|n| format!("line {}\n", n)).collect();
+        let cut = clean(&many).unwrap();
+        assert_eq!(cut.lines().count(), MAX_LINES);
+        assert!(cut.ends_with("line 10…"));
+    }
+}
@@ -0,0 +1,62 @@
+//! `--doc-names`: documents whose names differ only in case, like
+//! `Notes.txt` and `notes.txt`. Joins find such a twin among the open
+//! documents of the room and those stored there, and look again under the
+//! lock right before adding a document they loaded, so two joins racing
+//! each other with both names are caught too.
+
+use super::SharedState;
+use crate::storage::StorageBackend;
+
+/// Whether `a` and `b` are different names that differ only in case.
+pub(super) fn case_twins(a: &str, b: &str) -> bool {
+    a != b && a.to_lowercase() == b.to_lowercase()
+}
+
+/// An open document of `room` whose name differs from `doc` only in case.
+pub(super) fn open_twin(state: &SharedState, room: &str, doc: &str) -> Option<String> {
+    state
+        .docs
+        .keys()
+        .filter_map(|key| key.strip_prefix(room)?.strip_prefix('/'))
+        .find(|name| case_twins(name, doc))
+        .map(str::to_string)
+}
+
+/// A document stored in `room` whose name differs from `doc` only in case,
+/// unless `doc` is stored itself. A room that can't be listed has none.
+pub(super) async fn stored_twin(
+    storage: &dyn StorageBackend,
+    room: &str,
+    doc: &str,
+) -> Option<String> {
+    let stored = storage.list(room).await.ok()?;
+    if stored.iter().any(|entry| entry.name == doc) {
+        return None;
+    }
+    stored
+        .into_iter()
+        .map(|entry| entry.name)
+        .find(|name| case_twins(name, doc))
+}
+
+/// What whoever joins `doc` is told about its `twin` under `warn`.
+pub(super) fn warning(doc: &str, twin: &str) -> String {
+    format!(
+        "{} and {} differ only in case; they are separate documents here, but one file on a \
+         case-insensitive disk",
+        doc, twin
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_differing_only_in_case_are_twins() {
+        assert!(case_twins("Notes.txt", "notes.txt"));
+        assert!(case_twins("ÉTÉ.md", "été.md"));
+        assert!(!case_twins("notes.txt", "notes.txt"), "the same name");
+        assert!(!case_twins("notes.txt", "notes.md"));
+    }
+}
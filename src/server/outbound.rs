@@ -0,0 +1,160 @@
+//! A connection's way out, in two lanes. Text edits and replies go in
+//! order through a channel; cursor moves and selections, which only the
+//! latest of matters, wait in a small queue where a newer one replaces the
+//! one from the same user. The writer empties the first lane before the
+//! second, so a client reading slowly gets its edits first and fewer
+//! cursor moves instead of falling behind on both. Whatever is waiting in
+//! both lanes goes out in one write, up to a batch.
+
+use super::Outgoing;
+use crate::lines::{LineBatch, WriteStats};
+use crate::protocol::{Op, decode_update};
+use mdcs_sdk::Message;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use tokio::io::AsyncWrite;
+use tokio::sync::{Notify, mpsc};
+use tokio::task::JoinHandle;
+
+/// Most low-priority messages waiting per connection; past it the oldest
+/// is dropped.
+const LOW_LANE_LEN: usize = 64;
+
+/// Low-priority messages dropped, and messages and bytes written, across
+/// connections, the connections open and the lines read from them that
+/// weren't messages, for `/metrics`.
+#[derive(Default)]
+pub(super) struct OutboundStats {
+    dropped: AtomicU64,
+    written: WriteStats,
+    open: AtomicU64,
+    malformed: AtomicU64,
+}
+
+impl OutboundStats {
+    pub(super) fn render(&self) -> String {
+        format!(
+            "outbound_low_priority_dropped_total {}\n\
+             outbound_messages_total {}\n\
+             outbound_bytes_total {}\n\
+             connections_open {}\n\
+             malformed_messages_total {}\n",
+            self.dropped.load(Ordering::Relaxed),
+            self.written.messages(),
+            self.written.bytes(),
+            self.open.load(Ordering::Relaxed),
+            self.malformed.load(Ordering::Relaxed)
+        )
+    }
+
+    pub(super) fn malformed(&self) {
+        self.malformed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Counts a connection as open until the returned guard is dropped.
+    pub(super) fn opened(self: &Arc<Self>) -> OpenConnection {
+        self.open.fetch_add(1, Ordering::Relaxed);
+        OpenConnection(Arc::clone(self))
+    }
+}
+
+pub(super) struct OpenConnection(Arc<OutboundStats>);
+
+impl Drop for OpenConnection {
+    fn drop(&mut self) {
+        self.0.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Cursor moves and selections waiting to be written, by what they
+/// replace.
+pub(super) struct LowLane {
+    pending: std::sync::Mutex<Vec<(String, Outgoing)>>,
+    ready: Notify,
+    stats: Arc<OutboundStats>,
+}
+
+impl LowLane {
+    fn pending(&self) -> std::sync::MutexGuard<'_, Vec<(String, Outgoing)>> {
+        self.pending.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Queues `msg`, replacing an unsent one with the same `key`.
+    pub(super) fn push(&self, key: String, msg: Outgoing) {
+        let mut pending = self.pending();
+        if let Some((_, older)) = pending.iter_mut().find(|(queued, _)| *queued == key) {
+            *older = msg;
+            self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+        } else {
+            if pending.len() >= LOW_LANE_LEN {
+                pending.remove(0);
+                self.stats.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            pending.push((key, msg));
+        }
+        drop(pending);
+        self.ready.notify_one();
+    }
+}
+
+/// What `msg` replaces if it is low priority: the same user's previous
+/// cursor move or selection.
+pub(super) fn low_priority_key(msg: &Outgoing) -> Option<String> {
+    match msg {
+        Outgoing::Sync(Message::Presence { user_id, .. }) => Some(format!("cursor {}", user_id)),
+        Outgoing::Sync(update @ Message::Update { .. }) => match decode_update(update) {
+            Some((_, payload, _)) => match payload.op {
+                Op::Cursor { .. } => Some(format!("cursor {}", payload.user_id)),
+                Op::Selection { .. } => Some(format!("selection {}", payload.user_id)),
+                Op::Insert { .. } | Op::Delete { .. } => None,
+            },
+            None => None,
+        },
+        _ => None,
+    }
+}
+
+/// Writes what is sent on the returned channel and pushed to the returned
+/// lane to `writer`, one JSON line each, until the channel is closed or
+/// writing fails.
+pub(super) fn spawn_writer(
+    mut writer: impl AsyncWrite + Unpin + Send + 'static,
+    stats: Arc<OutboundStats>,
+) -> (mpsc::Sender<Outgoing>, Arc<LowLane>, JoinHandle<()>) {
+    let (out_tx, mut out_rx) = mpsc::channel::<Outgoing>(64);
+    let low = Arc::new(LowLane {
+        pending: std::sync::Mutex::default(),
+        ready: Notify::new(),
+        stats,
+    });
+    let lane = Arc::clone(&low);
+    let task = tokio::spawn(async move {
+        let mut batch = LineBatch::default();
+        loop {
+            tokio::select! {
+                biased;
+                msg = out_rx.recv() => match msg {
+                    Some(msg) => batch.push(&msg),
+                    None => break,
+                },
+                () = lane.ready.notified() => {}
+            }
+            // Edits queued behind it go in too, then the cursor moves.
+            while !batch.full() {
+                let Ok(msg) = out_rx.try_recv() else {
+                    break;
+                };
+                batch.push(&msg);
+            }
+            if !batch.full() {
+                for (_, msg) in std::mem::take(&mut *lane.pending()) {
+                    batch.push(&msg);
+                }
+            }
+            if batch.flush(&mut writer, &lane.stats.written).await.is_err() {
+                return;
+            }
+        }
+    });
+    (out_tx, low, task)
+}
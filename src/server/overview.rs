@@ -0,0 +1,308 @@
+//! Per-room numbers for capacity planning, as JSON on `GET /admin/overview`
+//! and as gauges labelled by room on `/metrics`. Only plain numbers and the
+//! per-user counters are copied under the state lock; grouping and
+//! formatting happen after it is released.
+
+use super::SharedState;
+use crate::storage::UserStats;
+use serde::Serialize;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::sync::Arc;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+/// How far back `ops_last_minute` counts.
+pub(super) const OPS_WINDOW: Duration = Duration::from_secs(60);
+/// The room the rooms past `--metrics-room-limit` are summed into.
+const OTHER: &str = "other";
+
+#[derive(Debug, Serialize)]
+pub(super) struct RoomStats {
+    room: String,
+    users: usize,
+    docs: Vec<DocStats>,
+}
+
+/// A loaded document.
+#[derive(Debug, Serialize)]
+struct DocStats {
+    doc: String,
+    users: usize,
+    /// The names of its users, sorted.
+    user_names: Vec<String>,
+    version: u64,
+    /// Length of the text in characters.
+    chars: usize,
+    bytes: usize,
+    ops_last_minute: usize,
+    /// Unix time of the last save since startup.
+    last_flush: Option<u64>,
+    /// How long its oldest unsaved change has waited, if it has one.
+    unsaved_ms: Option<u64>,
+    /// Who wrote how much, busiest first.
+    contributors: Vec<UserStats>,
+}
+
+/// The loaded documents grouped by room, both sorted by name.
+pub(super) async fn snapshot(state: &Mutex<SharedState>) -> Vec<RoomStats> {
+    let now = Instant::now();
+    let (docs, users, persistence) = {
+        let guard = state.lock().await;
+        let docs: Vec<_> = guard
+            .docs
+            .iter()
+            .map(|(key, doc_state)| {
+                let counts = (
+                    doc_state.version,
+                    doc_state.doc.len(),
+                    doc_state.text.byte_len(),
+                );
+                let contributors = doc_state.meta.contributions.clone();
+                (
+                    key.clone(),
+                    counts,
+                    doc_state.recent_op_count(now),
+                    contributors,
+                )
+            })
+            .collect();
+        let users: Vec<_> = guard
+            .users
+            .values()
+            .map(|user| (super::doc_key(&user.room, &user.doc), user.name.clone()))
+            .collect();
+        (docs, users, Arc::clone(&guard.persistence))
+    };
+
+    let (saved_at, dirty_since) = (persistence.saved_at(), persistence.dirty_since());
+    let mut users_by_doc: HashMap<String, Vec<String>> = HashMap::new();
+    for (key, name) in users {
+        users_by_doc.entry(key).or_default().push(name);
+    }
+    let mut rooms: BTreeMap<String, RoomStats> = BTreeMap::new();
+    for (key, (version, chars, bytes), ops_last_minute, contributors) in docs {
+        let Some((room, doc)) = key.split_once('/') else {
+            continue;
+        };
+        let mut user_names = users_by_doc.remove(&key).unwrap_or_default();
+        user_names.sort();
+        let users = user_names.len();
+        let last_flush = saved_at
+            .get(&key)
+            .and_then(|at| at.duration_since(UNIX_EPOCH).ok())
+            .map(|since| since.as_secs());
+        let unsaved_ms = dirty_since
+            .get(&key)
+            .map(|since| now.saturating_duration_since(*since).as_millis() as u64);
+        let stats = rooms.entry(room.to_string()).or_insert_with(|| RoomStats {
+            room: room.to_string(),
+            users: 0,
+            docs: Vec::new(),
+        });
+        stats.users += users;
+        stats.docs.push(DocStats {
+            doc: doc.to_string(),
+            users,
+            user_names,
+            version,
+            chars,
+            bytes,
+            ops_last_minute,
+            last_flush,
+            unsaved_ms,
+            contributors: super::by_activity(contributors),
+        });
+    }
+    let mut rooms: Vec<RoomStats> = rooms.into_values().collect();
+    for room in &mut rooms {
+        room.docs.sort_by(|a, b| a.doc.cmp(&b.doc));
+    }
+    rooms
+}
+
+pub(super) fn render_json(rooms: &[RoomStats]) -> String {
+    #[derive(Serialize)]
+    struct Overview<'a> {
+        rooms: &'a [RoomStats],
+    }
+    let mut json = serde_json::to_string_pretty(&Overview { rooms }).unwrap_or_default();
+    json.push('\n');
+    json
+}
+
+/// Gauges per room, in the Prometheus text format. The busiest `limit`
+/// rooms (by users, then recent ops) get their own series; the rest are
+/// summed into `room="other"` so the number of series stays bounded.
+pub(super) fn render_metrics(rooms: &[RoomStats], limit: usize) -> String {
+    #[derive(Default)]
+    struct Gauges {
+        users: usize,
+        docs: usize,
+        chars: usize,
+        ops_last_minute: usize,
+        last_flush: Option<u64>,
+    }
+    let gauges = |room: &RoomStats, into: &mut Gauges| {
+        into.users += room.users;
+        into.docs += room.docs.len();
+        for doc in &room.docs {
+            into.chars += doc.chars;
+            into.ops_last_minute += doc.ops_last_minute;
+            into.last_flush = into.last_flush.max(doc.last_flush);
+        }
+    };
+
+    let mut busiest: Vec<(&RoomStats, Gauges)> = rooms
+        .iter()
+        .map(|room| {
+            let mut summed = Gauges::default();
+            gauges(room, &mut summed);
+            (room, summed)
+        })
+        .collect();
+    busiest.sort_by(|(a, a_gauges), (b, b_gauges)| {
+        (b_gauges.users, b_gauges.ops_last_minute)
+            .cmp(&(a_gauges.users, a_gauges.ops_last_minute))
+            .then_with(|| a.room.cmp(&b.room))
+    });
+    let mut series: Vec<(&str, Gauges)> = Vec::new();
+    let mut other: Option<Gauges> = None;
+    for (index, (room, summed)) in busiest.into_iter().enumerate() {
+        if index < limit {
+            series.push((&room.room, summed));
+        } else {
+            gauges(room, other.get_or_insert_with(Gauges::default));
+        }
+    }
+    series.extend(other.map(|summed| (OTHER, summed)));
+
+    let mut out = String::new();
+    let mut gauge = |name: &str, value: &dyn Fn(&Gauges) -> Option<u64>| {
+        for (room, summed) in &series {
+            if let Some(value) = value(summed) {
+                let _ = writeln!(out, "{}{{room=\"{}\"}} {}", name, escape_label(room), value);
+            }
+        }
+    };
+    gauge("room_users", &|g| Some(g.users as u64));
+    gauge("room_docs", &|g| Some(g.docs as u64));
+    gauge("room_chars", &|g| Some(g.chars as u64));
+    gauge("room_ops_last_minute", &|g| Some(g.ops_last_minute as u64));
+    gauge("room_last_flush_timestamp_seconds", &|g| g.last_flush);
+    out
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::server::{DocState, UserState};
+    use crate::storage::{DocMeta, HistoryPolicy, MemoryStorage};
+    use mdcs_sdk::TextDoc;
+
+    fn room(name: &str, users: usize, ops: usize) -> RoomStats {
+        RoomStats {
+            room: name.to_string(),
+            users,
+            docs: vec![DocStats {
+                doc: "notes.md".into(),
+                users,
+                user_names: Vec::new(),
+                version: 7,
+                chars: 10,
+                bytes: 10,
+                ops_last_minute: ops,
+                last_flush: None,
+                unsaved_ms: None,
+                contributors: Vec::new(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn snapshots_group_loaded_docs_by_room() {
+        let mut state =
+            SharedState::new(Arc::new(MemoryStorage::default()), HistoryPolicy::default());
+        let mut doc = TextDoc::new("team/notes.md", "server");
+        doc.insert(0, "héllo");
+        let mut notes = DocState::new(doc, DocMeta::default());
+        notes.version = 2;
+        notes.note_op();
+        notes.note_op();
+        notes.meta.record_edit("ada", 5, 0);
+        state.docs.insert("team/notes.md".into(), notes);
+        let idle = DocState::new(TextDoc::new("team/idle.md", "server"), DocMeta::default());
+        state.docs.insert("team/idle.md".into(), idle);
+        for id in ["bob", "ada"] {
+            let user = UserState {
+                id: id.into(),
+                name: id.into(),
+                room: "team".into(),
+                doc: "notes.md".into(),
+                joined: std::time::Instant::now(),
+                guest: false,
+                kick: Arc::default(),
+            };
+            state.users.insert(id.into(), user);
+        }
+
+        let rooms = snapshot(&Mutex::new(state)).await;
+        assert_eq!(rooms.len(), 1);
+        assert_eq!((rooms[0].room.as_str(), rooms[0].users), ("team", 2));
+        let docs: Vec<_> = rooms[0]
+            .docs
+            .iter()
+            .map(|d| {
+                (
+                    d.doc.as_str(),
+                    d.users,
+                    d.version,
+                    d.chars,
+                    d.bytes,
+                    d.ops_last_minute,
+                )
+            })
+            .collect();
+        assert_eq!(
+            docs,
+            [("idle.md", 0, 0, 0, 0, 0), ("notes.md", 2, 2, 5, 6, 2)]
+        );
+        assert_eq!(rooms[0].docs[1].user_names, ["ada", "bob"]);
+        assert!(render_json(&rooms).contains("\"ops_last_minute\": 2"));
+        assert_eq!(rooms[0].docs[1].contributors[0].inserted_bytes, 5);
+    }
+
+    #[test]
+    fn rooms_past_the_limit_are_summed_into_other() {
+        let rooms = [
+            room("quiet", 0, 1),
+            room("busy", 3, 0),
+            room("a\"b", 1, 9),
+            room("idle", 0, 0),
+        ];
+        let metrics = render_metrics(&rooms, 2);
+        let users: Vec<_> = metrics
+            .lines()
+            .filter(|l| l.starts_with("room_users"))
+            .collect();
+        assert_eq!(
+            users,
+            [
+                "room_users{room=\"busy\"} 3",
+                "room_users{room=\"a\\\"b\"} 1",
+                "room_users{room=\"other\"} 0",
+            ]
+        );
+        assert!(metrics.contains("room_docs{room=\"other\"} 2\n"));
+        assert!(metrics.contains("room_ops_last_minute{room=\"other\"} 1\n"));
+        assert!(!metrics.contains("room_last_flush_timestamp_seconds"));
+        assert!(!render_metrics(&rooms, 4).contains("other"));
+    }
+}
@@ -0,0 +1,561 @@
+//! Write-behind saving. Edits only mark their document dirty; a background
+//! loop saves the documents whose stored version lags, copying their text
+//! under the state lock and writing it after the lock is released.
+
+use super::{SharedState, doc_key};
+use crate::storage::StorageBackend;
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+use tokio::sync::{Mutex, MutexGuard};
+
+/// How often dirty documents are saved.
+pub(super) const FLUSH_EVERY: Duration = Duration::from_secs(1);
+/// Wait before retrying a failed save, doubled per failure up to
+/// `RETRY_MAX`.
+const RETRY_FIRST: Duration = Duration::from_secs(1);
+const RETRY_MAX: Duration = Duration::from_secs(60);
+/// Snapshots on demand are allowed once per document this often, so they
+/// can't keep the disk busy.
+const SNAPSHOT_EVERY: Duration = Duration::from_secs(5);
+
+struct Entry {
+    room: String,
+    doc: String,
+    /// Newest version with changes to save.
+    dirty: u64,
+    /// Version stored last; the document is dirty while `dirty` is ahead.
+    saved: u64,
+    /// Store a revision with the next save.
+    revision: bool,
+    /// The metadata changed without an edit, e.g. the whitespace policy.
+    meta_changed: bool,
+    failures: u32,
+    retry_at: Option<Instant>,
+    /// When the document was last saved.
+    saved_at: Option<SystemTime>,
+    /// Since when it has had changes to save.
+    dirty_since: Option<Instant>,
+}
+
+impl Entry {
+    fn new(room: &str, doc: &str) -> Self {
+        Self {
+            room: room.to_string(),
+            doc: doc.to_string(),
+            dirty: 0,
+            saved: 0,
+            revision: false,
+            meta_changed: false,
+            failures: 0,
+            retry_at: None,
+            saved_at: None,
+            dirty_since: None,
+        }
+    }
+
+    fn is_dirty(&self) -> bool {
+        self.dirty > self.saved || self.meta_changed
+    }
+}
+
+pub(super) struct PersistenceManager {
+    storage: Arc<dyn StorageBackend>,
+    docs: std::sync::Mutex<HashMap<String, Entry>>,
+    /// One flush at a time, so a document is never written twice at once.
+    flushing: Mutex<()>,
+    /// When each document was last snapshotted on demand, for the last
+    /// `SNAPSHOT_EVERY`.
+    snapshots: std::sync::Mutex<HashMap<String, Instant>>,
+    flushes: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+}
+
+impl PersistenceManager {
+    pub(super) fn new(storage: Arc<dyn StorageBackend>) -> Self {
+        Self {
+            storage,
+            docs: std::sync::Mutex::default(),
+            flushing: Mutex::new(()),
+            snapshots: std::sync::Mutex::default(),
+            flushes: AtomicU64::new(0),
+            total_micros: AtomicU64::new(0),
+            max_micros: AtomicU64::new(0),
+        }
+    }
+
+    fn docs(&self) -> std::sync::MutexGuard<'_, HashMap<String, Entry>> {
+        self.docs.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// Notes that the document changed up to `version`, to be saved with
+    /// the next flush (and stored as a revision too, if `revision`).
+    pub(super) fn mark_dirty(&self, room: &str, doc: &str, version: u64, revision: bool) {
+        let mut docs = self.docs();
+        let entry = docs
+            .entry(doc_key(room, doc))
+            .or_insert_with(|| Entry::new(room, doc));
+        entry.dirty = entry.dirty.max(version);
+        entry.revision |= revision;
+        if entry.is_dirty() {
+            entry.dirty_since.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// Notes that the metadata of the document at `version` changed, to be
+    /// saved with the next flush.
+    pub(super) fn mark_meta_dirty(&self, room: &str, doc: &str, version: u64) {
+        let mut docs = self.docs();
+        let entry = docs
+            .entry(doc_key(room, doc))
+            .or_insert_with(|| Entry::new(room, doc));
+        entry.dirty = entry.dirty.max(version);
+        entry.meta_changed = true;
+        entry.dirty_since.get_or_insert_with(Instant::now);
+    }
+
+    pub(super) fn dirty_count(&self) -> usize {
+        self.docs()
+            .values()
+            .filter(|entry| entry.is_dirty())
+            .count()
+    }
+
+    /// When each document saved since startup was saved last, by doc key.
+    pub(super) fn saved_at(&self) -> HashMap<String, SystemTime> {
+        self.docs()
+            .iter()
+            .filter_map(|(key, entry)| Some((key.clone(), entry.saved_at?)))
+            .collect()
+    }
+
+    /// Since when each dirty document has had changes to save, by doc key.
+    pub(super) fn dirty_since(&self) -> HashMap<String, Instant> {
+        self.docs()
+            .iter()
+            .filter(|(_, entry)| entry.is_dirty())
+            .filter_map(|(key, entry)| Some((key.clone(), entry.dirty_since?)))
+            .collect()
+    }
+
+    /// Holds off flushes until the guard is dropped, e.g. while a
+    /// document's file is replaced under its open copy.
+    pub(super) async fn pause(&self) -> MutexGuard<'_, ()> {
+        self.flushing.lock().await
+    }
+
+    /// Saves the dirty documents, skipping ones waiting to retry a failed
+    /// save unless `all` is set. Returns how many were saved; failures are
+    /// logged, and the documents stay dirty.
+    pub(super) async fn flush(&self, state: &Mutex<SharedState>, all: bool) -> io::Result<usize> {
+        let _flushing = self.flushing.lock().await;
+        let now = Instant::now();
+        let due: Vec<(String, String, String, bool)> = self
+            .docs()
+            .iter_mut()
+            .filter(|(_, entry)| {
+                entry.is_dirty() && (all || entry.retry_at.is_none_or(|at| at <= now))
+            })
+            .map(|(key, entry)| {
+                // Metadata changed from here on is saved next time.
+                entry.meta_changed = false;
+                let (room, doc) = (entry.room.clone(), entry.doc.clone());
+                (key.clone(), room, doc, entry.revision)
+            })
+            .collect();
+        if due.is_empty() {
+            return Ok(0);
+        }
+
+        let mut snapshots = Vec::new();
+        {
+            let mut guard = state.lock().await;
+            let SharedState { docs, updates, .. } = &mut *guard;
+            for (key, room, doc, revision) in due {
+                let Some(doc_state) = docs.get_mut(&key) else {
+                    continue;
+                };
+                let policy = doc_state.meta.whitespace;
+                if policy.strict && !policy.is_off() {
+                    for update in super::apply_whitespace_policy(doc_state, &key) {
+                        let _ = updates.send(update);
+                    }
+                }
+                // Lines spared above are tidied in the saved file anyway.
+                let text = policy.normalize(&doc_state.doc.get_text(), &[]);
+                let snapshot = (text, doc_state.meta.clone(), doc_state.version);
+                snapshots.push((key, room, doc, revision, snapshot));
+            }
+        }
+
+        let started = Instant::now();
+        let (mut saved, mut failed) = (0, 0);
+        for (key, room, doc, revision, (text, meta, version)) in snapshots {
+            let mut result = Ok(());
+            if revision {
+                result = self
+                    .storage
+                    .save_revision(&room, &doc, text.clone(), version)
+                    .await;
+            }
+            if result.is_ok() {
+                result = self.storage.save(&room, &doc, text, meta).await;
+            }
+            let mut docs = self.docs();
+            let Some(entry) = docs.get_mut(&key) else {
+                continue;
+            };
+            match result {
+                Ok(()) => {
+                    saved += 1;
+                    entry.saved = entry.saved.max(version);
+                    entry.revision &= !revision;
+                    entry.failures = 0;
+                    entry.retry_at = None;
+                    entry.saved_at = Some(SystemTime::now());
+                    if !entry.is_dirty() {
+                        entry.dirty_since = None;
+                    }
+                }
+                Err(err) => {
+                    failed += 1;
+                    entry.meta_changed = true;
+                    entry.failures += 1;
+                    let backoff = RETRY_FIRST
+                        .saturating_mul(1 << entry.failures.min(16).saturating_sub(1))
+                        .min(RETRY_MAX);
+                    entry.retry_at = Some(Instant::now() + backoff);
+                    println!(
+                        "[storage] saving {} failed ({} in a row, retrying in {:?}): {}",
+                        key, entry.failures, backoff, err
+                    );
+                }
+            }
+        }
+        self.record(started.elapsed());
+        if failed > 0 {
+            return Err(io::Error::other(format!(
+                "{} of {} documents failed to save",
+                failed,
+                saved + failed
+            )));
+        }
+        Ok(saved)
+    }
+
+    /// Saves `room`/`doc` now with a revision, dirty or not, e.g. before a
+    /// risky bulk edit. Returns the version saved and its size in bytes.
+    pub(super) async fn snapshot(
+        &self,
+        state: &Mutex<SharedState>,
+        room: &str,
+        doc: &str,
+    ) -> Result<(u64, usize), SnapshotError> {
+        let key = doc_key(room, doc);
+        {
+            let mut snapshots = self.snapshots.lock().unwrap_or_else(|err| err.into_inner());
+            let now = Instant::now();
+            snapshots.retain(|_, at| now.duration_since(*at) < SNAPSHOT_EVERY);
+            if let Some(at) = snapshots.get(&key) {
+                return Err(SnapshotError::TooSoon(
+                    SNAPSHOT_EVERY - now.duration_since(*at),
+                ));
+            }
+            snapshots.insert(key.clone(), now);
+        }
+        self.storage.validate(room, doc)?;
+
+        let _flushing = self.flushing.lock().await;
+        let (text, meta, version) = {
+            let mut guard = super::lock_loaded(state, room, doc).await?;
+            let doc_state = guard.docs.get_mut(&key).expect("doc is loaded");
+            if doc_state.version == 0 && doc_state.doc.is_empty() {
+                return Err(SnapshotError::Empty);
+            }
+            doc_state.last_revision = (doc_state.version, Instant::now());
+            let text = doc_state
+                .meta
+                .whitespace
+                .normalize(&doc_state.doc.get_text(), &[]);
+            (text, doc_state.meta.clone(), doc_state.version)
+        };
+        let bytes = text.len();
+        let started = Instant::now();
+        let mut result = self
+            .storage
+            .save_revision(room, doc, text.clone(), version)
+            .await;
+        if result.is_ok() {
+            result = self.storage.save(room, doc, text, meta).await;
+        }
+        self.record(started.elapsed());
+        result?;
+
+        let mut docs = self.docs();
+        let entry = docs.entry(key).or_insert_with(|| Entry::new(room, doc));
+        entry.saved = entry.saved.max(version);
+        // The revision just stored covers a pending one, unless there were
+        // edits since.
+        entry.revision &= entry.dirty > version;
+        entry.failures = 0;
+        entry.retry_at = None;
+        entry.saved_at = Some(SystemTime::now());
+        if !entry.is_dirty() {
+            entry.dirty_since = None;
+        }
+        Ok((version, bytes))
+    }
+
+    fn record(&self, took: Duration) {
+        let micros = u64::try_from(took.as_micros()).unwrap_or(u64::MAX);
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    /// Prometheus text format, next to `StorageStats::render`.
+    pub(super) fn render(&self) -> String {
+        let seconds = |micros: &AtomicU64| micros.load(Ordering::Relaxed) as f64 / 1e6;
+        format!(
+            "persistence_dirty_docs {}\n\
+             persistence_flushes_total {}\n\
+             persistence_flush_seconds_sum {:.6}\n\
+             persistence_flush_seconds_max {:.6}\n",
+            self.dirty_count(),
+            self.flushes.load(Ordering::Relaxed),
+            seconds(&self.total_micros),
+            seconds(&self.max_micros),
+        )
+    }
+}
+
+/// Why `PersistenceManager::snapshot` didn't save.
+#[derive(Debug)]
+pub(super) enum SnapshotError {
+    /// The document was snapshotted less than `SNAPSHOT_EVERY` ago; the
+    /// next snapshot is allowed after this long.
+    TooSoon(Duration),
+    /// Nothing was ever written to the document.
+    Empty,
+    Io(io::Error),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::TooSoon(wait) => write!(
+                f,
+                "one snapshot per document every {}s; try again in {:.1}s",
+                SNAPSHOT_EVERY.as_secs(),
+                wait.as_secs_f64()
+            ),
+            SnapshotError::Empty => f.write_str("the document has no edits to snapshot"),
+            SnapshotError::Io(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+impl From<io::Error> for SnapshotError {
+    fn from(err: io::Error) -> Self {
+        SnapshotError::Io(err)
+    }
+}
+
+/// Saves every dirty document now, for shutdown and `POST /flush`.
+pub(super) async fn flush_all(state: &Mutex<SharedState>) -> io::Result<usize> {
+    let persistence = Arc::clone(&state.lock().await.persistence);
+    persistence.flush(state, true).await
+}
+
+pub(super) async fn run_flush_loop(state: Arc<Mutex<SharedState>>) {
+    let persistence = Arc::clone(&state.lock().await.persistence);
+    let mut tick = tokio::time::interval(FLUSH_EVERY);
+    tick.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tick.tick().await;
+        // Failures are logged per document and retried.
+        let _ = persistence.flush(&state, false).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Op;
+    use crate::storage::{DocEntry, DocMeta, HistoryPolicy, MemoryStorage, StoredDoc};
+    use async_trait::async_trait;
+    use std::sync::atomic::AtomicBool;
+
+    /// `MemoryStorage` that counts saves and revisions and can be made to
+    /// fail saves.
+    #[derive(Default)]
+    struct FlakyStorage {
+        inner: MemoryStorage,
+        saves: AtomicU64,
+        revisions: AtomicU64,
+        failing: AtomicBool,
+    }
+
+    #[async_trait]
+    impl StorageBackend for FlakyStorage {
+        async fn load(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+            self.inner.load(room, doc).await
+        }
+
+        async fn save(&self, room: &str, doc: &str, text: String, meta: DocMeta) -> io::Result<()> {
+            self.saves.fetch_add(1, Ordering::Relaxed);
+            if self.failing.load(Ordering::Relaxed) {
+                return Err(io::Error::other("disk full"));
+            }
+            self.inner.save(room, doc, text, meta).await
+        }
+
+        async fn list(&self, room: &str) -> io::Result<Vec<DocEntry>> {
+            self.inner.list(room).await
+        }
+
+        async fn list_rooms(&self) -> io::Result<Vec<String>> {
+            self.inner.list_rooms().await
+        }
+
+        async fn delete(&self, room: &str, doc: &str) -> io::Result<()> {
+            self.inner.delete(room, doc).await
+        }
+
+        async fn save_revision(
+            &self,
+            _room: &str,
+            _doc: &str,
+            _text: String,
+            _version: u64,
+        ) -> io::Result<()> {
+            self.revisions.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+    }
+
+    async fn edit(state: &Mutex<SharedState>, doc: &str, text: &str) {
+        let mut guard = super::super::lock_loaded(state, "room", doc).await.unwrap();
+        let doc_state = guard.docs.get_mut(&doc_key("room", doc)).unwrap();
+        let op = Op::Insert {
+            pos: doc_state.text.byte_len(),
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        super::super::apply_op_to_doc(doc_state, "ada", &op);
+        doc_state.version += 1;
+        doc_state.meta.version = doc_state.version;
+        let version = doc_state.version;
+        guard.persistence.mark_dirty("room", doc, version, false);
+    }
+
+    fn state(storage: &Arc<FlakyStorage>) -> Mutex<SharedState> {
+        let storage: Arc<dyn StorageBackend> = Arc::clone(storage) as _;
+        Mutex::new(SharedState::new(storage, HistoryPolicy::default()))
+    }
+
+    #[tokio::test]
+    async fn only_dirty_documents_are_written() {
+        let storage = Arc::new(FlakyStorage::default());
+        let state = state(&storage);
+        let persistence = Arc::clone(&state.lock().await.persistence);
+        assert_eq!(persistence.flush(&state, false).await.unwrap(), 0);
+
+        edit(&state, "notes", "one").await;
+        let first_edit = persistence.dirty_since()["room/notes"];
+        edit(&state, "notes", " two").await;
+        assert_eq!(persistence.dirty_count(), 1);
+        assert_eq!(persistence.dirty_since()["room/notes"], first_edit);
+        assert_eq!(persistence.flush(&state, false).await.unwrap(), 1);
+        assert!(persistence.dirty_since().is_empty());
+        assert_eq!(storage.saves.load(Ordering::Relaxed), 1);
+        assert_eq!(
+            storage.inner.load("room", "notes").await.unwrap().text,
+            "one two"
+        );
+
+        // Nothing changed since: nothing is written.
+        assert_eq!(persistence.flush(&state, false).await.unwrap(), 0);
+        assert_eq!(storage.saves.load(Ordering::Relaxed), 1);
+        assert!(persistence.render().contains("persistence_dirty_docs 0\n"));
+    }
+
+    #[tokio::test]
+    async fn flush_all_saves_everything_pending() {
+        let storage = Arc::new(FlakyStorage::default());
+        let state = state(&storage);
+        for doc in ["a", "b", "c"] {
+            edit(&state, doc, doc).await;
+        }
+        assert_eq!(flush_all(&state).await.unwrap(), 3);
+        for doc in ["a", "b", "c"] {
+            let stored = storage.inner.load("room", doc).await.unwrap();
+            assert_eq!((stored.text.as_str(), stored.meta.version), (doc, 1));
+        }
+    }
+
+    #[tokio::test]
+    async fn failed_saves_stay_dirty_and_back_off() {
+        let storage = Arc::new(FlakyStorage::default());
+        let state = state(&storage);
+        let persistence = Arc::clone(&state.lock().await.persistence);
+        edit(&state, "notes", "text").await;
+
+        storage.failing.store(true, Ordering::Relaxed);
+        assert!(persistence.flush(&state, false).await.is_err());
+        assert_eq!(persistence.dirty_count(), 1);
+        // Not retried before the backoff passed...
+        assert_eq!(persistence.flush(&state, false).await.unwrap(), 0);
+        assert_eq!(storage.saves.load(Ordering::Relaxed), 1);
+        assert!(persistence.flush(&state, true).await.is_err());
+        let retry_in = |persistence: &PersistenceManager| {
+            let docs = persistence.docs();
+            docs["room/notes"].retry_at.unwrap() - Instant::now()
+        };
+        // ...which doubles per failure.
+        assert!(retry_in(&persistence) > RETRY_FIRST);
+
+        // ...except by a flush of everything, e.g. at shutdown.
+        storage.failing.store(false, Ordering::Relaxed);
+        assert_eq!(flush_all(&state).await.unwrap(), 1);
+        assert_eq!(persistence.dirty_count(), 0);
+        assert_eq!(
+            storage.inner.load("room", "notes").await.unwrap().text,
+            "text"
+        );
+    }
+
+    #[tokio::test]
+    async fn snapshots_save_a_revision_at_most_every_few_seconds() {
+        let storage = Arc::new(FlakyStorage::default());
+        let state = state(&storage);
+        let persistence = Arc::clone(&state.lock().await.persistence);
+        let empty = persistence.snapshot(&state, "room", "blank").await;
+        assert!(matches!(empty, Err(SnapshotError::Empty)));
+        assert!(matches!(
+            persistence.snapshot(&state, "room", "../x").await,
+            Err(SnapshotError::Io(_))
+        ));
+
+        edit(&state, "notes", "text").await;
+        let saved = persistence.snapshot(&state, "room", "notes").await.unwrap();
+        assert_eq!(saved, (1, 4));
+        assert_eq!(storage.revisions.load(Ordering::Relaxed), 1);
+        assert_eq!(persistence.dirty_count(), 0);
+        assert_eq!(
+            storage.inner.load("room", "notes").await.unwrap().text,
+            "text"
+        );
+
+        edit(&state, "notes", "!").await;
+        let again = persistence.snapshot(&state, "room", "notes").await;
+        assert!(matches!(again, Err(SnapshotError::TooSoon(wait)) if wait <= SNAPSHOT_EVERY));
+        assert_eq!(storage.revisions.load(Ordering::Relaxed), 1);
+        // The edit after the snapshot still gets flushed as usual.
+        assert_eq!(persistence.dirty_count(), 1);
+    }
+}
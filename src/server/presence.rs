@@ -0,0 +1,83 @@
+//! Who is in a document, sent to its users as one `Members` notice per
+//! `WINDOW` in which people joined, left or were renamed, and only if the
+//! list really changed: a reconnect storm is one notice, and a `/sync`
+//! that joins again under the same name is none.
+
+use crate::protocol::WireUser;
+use std::time::Duration;
+
+/// How long joins, leaves and renames are gathered into one notice.
+pub(super) const WINDOW: Duration = Duration::from_millis(100);
+
+/// A document's user list as last announced.
+#[derive(Debug, Default)]
+pub(super) struct Presence {
+    /// Sorted by id.
+    members: Vec<WireUser>,
+    /// The `presence_seq` of that announcement; 0 before the first.
+    seq: u64,
+    /// A window is open: a notice may be due at its end.
+    due: bool,
+}
+
+impl Presence {
+    /// Notes that the list may have changed. Returns whether that opens a
+    /// window, at whose end the caller calls `flush`.
+    pub(super) fn changed(&mut self) -> bool {
+        !std::mem::replace(&mut self.due, true)
+    }
+
+    /// Ends the window with the document's `users` as they are now, and
+    /// returns them as an announcement with its `presence_seq` if they
+    /// differ from those announced last.
+    pub(super) fn flush(&mut self, mut users: Vec<WireUser>) -> Option<(u64, Vec<WireUser>)> {
+        self.due = false;
+        users.sort_by(|a, b| a.id.cmp(&b.id));
+        if users == self.members {
+            return None;
+        }
+        self.seq += 1;
+        self.members = users;
+        Some((self.seq, self.members.clone()))
+    }
+
+    /// The `presence_seq` of the last announcement.
+    pub(super) fn seq(&self) -> u64 {
+        self.seq
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user(id: &str, name: &str) -> WireUser {
+        WireUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            guest: false,
+        }
+    }
+
+    #[test]
+    fn changes_in_a_window_are_announced_once_if_the_list_changed() {
+        let mut presence = Presence::default();
+        assert!(presence.changed());
+        assert!(!presence.changed(), "the window is already open");
+        assert!(!presence.changed());
+        let both = vec![user("b", "bob"), user("a", "ada")];
+        let (seq, users) = presence.flush(both.clone()).unwrap();
+        assert_eq!(seq, 1);
+        assert_eq!(users, [user("a", "ada"), user("b", "bob")]);
+
+        // Bob leaving and coming back within a window changes nothing.
+        assert!(presence.changed());
+        assert_eq!(presence.flush(both), None);
+        assert_eq!(presence.seq(), 1);
+
+        assert!(presence.changed());
+        let renamed = vec![user("a", "ada"), user("b", "rob")];
+        assert_eq!(presence.flush(renamed).map(|(seq, _)| seq), Some(2));
+        assert!(presence.changed(), "a flush closes the window");
+    }
+}
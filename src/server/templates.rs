@@ -0,0 +1,119 @@
+//! `--template-dir`: documents that were never saved start from a template
+//! instead of empty. `template.md` is used for `.md` docs, `template.txt`
+//! for `.txt` ones and so on, and `template` for the rest. `{{date}}`,
+//! `{{room}}` and `{{doc}}` in it are filled in.
+
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The text `room`/`doc` starts with, or `None` to start empty: when there
+/// is no template for it or it can't be read, which is logged.
+pub(super) async fn seed(dir: &Path, room: &str, doc: &str, now: SystemTime) -> Option<String> {
+    for path in candidates(dir, doc) {
+        match tokio::fs::read_to_string(&path).await {
+            Ok(template) => return Some(fill(&template, room, doc, &utc_date(now))),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                println!(
+                    "[templates] can't read {}: {}; {}/{} starts empty",
+                    path.display(),
+                    err,
+                    room,
+                    doc
+                );
+                return None;
+            }
+        }
+    }
+    println!(
+        "[templates] no template for {}/{} in {}; it starts empty",
+        room,
+        doc,
+        dir.display()
+    );
+    None
+}
+
+/// Where the template of `doc` may be, most specific first.
+fn candidates(dir: &Path, doc: &str) -> Vec<PathBuf> {
+    let extension = Path::new(doc).extension().and_then(|ext| ext.to_str());
+    extension
+        .map(|ext| dir.join(format!("template.{}", ext)))
+        .into_iter()
+        .chain(std::iter::once(dir.join("template")))
+        .collect()
+}
+
+fn fill(template: &str, room: &str, doc: &str, date: &str) -> String {
+    template
+        .replace("{{date}}", date)
+        .replace("{{room}}", room)
+        .replace("{{doc}}", doc)
+}
+
+/// `YYYY-MM-DD` of `now` in UTC.
+pub(crate) fn utc_date(now: SystemTime) -> String {
+    let days = now
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs() / 86_400) as i64;
+    // Days to civil date, after Howard Hinnant's `civil_from_days`.
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{:04}-{:02}-{:02}", year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::time::Duration;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("carnelia-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let template = "# {{doc}} ({{room}})\n\nDate: {{date}}\n{{date}} {{unknown}}\n";
+        assert_eq!(
+            fill(template, "team", "standup.md", "2026-10-16"),
+            "# standup.md (team)\n\nDate: 2026-10-16\n2026-10-16 {{unknown}}\n"
+        );
+        assert_eq!(utc_date(UNIX_EPOCH), "1970-01-01");
+        let leap_day = UNIX_EPOCH + Duration::from_secs(1_709_164_800 + 3600);
+        assert_eq!(utc_date(leap_day), "2024-02-29");
+        let new_year = UNIX_EPOCH + Duration::from_secs(1_767_225_599);
+        assert_eq!(utc_date(new_year), "2025-12-31");
+    }
+
+    #[tokio::test]
+    async fn templates_are_picked_by_extension_and_fall_back_to_empty() {
+        let dir = temp_dir("templates");
+        fs::write(dir.join("template.md"), "# {{doc}}\n").unwrap();
+        fs::write(dir.join("template"), "{{room}} notes\n").unwrap();
+        let now = SystemTime::now();
+        let md = seed(&dir, "team", "notes/week.md", now).await;
+        assert_eq!(md.as_deref(), Some("# notes/week.md\n"));
+        let other = seed(&dir, "team", "todo.txt", now).await;
+        assert_eq!(other.as_deref(), Some("team notes\n"));
+
+        // An unreadable template isn't replaced by the default one.
+        fs::create_dir(dir.join("template.txt")).unwrap();
+        assert_eq!(seed(&dir, "team", "todo.txt", now).await, None);
+        fs::remove_file(dir.join("template")).unwrap();
+        assert_eq!(seed(&dir, "team", "plain", now).await, None);
+        assert_eq!(seed(&dir.join("missing"), "team", "a.md", now).await, None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,498 @@
+//! Convergence fuzzing: simulated clients wired to an in-process server
+//! over in-memory streams, making random edits while a seeded network
+//! delays their messages. Messages of one connection stay in order, as
+//! over TCP, but those of different clients overtake each other. Once
+//! every message is delivered, each client's text must equal the
+//! server's; if not, the failing run is shrunk to the fewest edits and
+//! reported with its seed and trace.
+
+use crate::client::NetConditions;
+use crate::client::netsim::Rng;
+use crate::protocol::{
+    Op, decode_sync_response, decode_update, encode_sync_request, encode_update,
+    make_scoped_user_id,
+};
+use crate::server::LocalServer;
+use crate::storage::MemoryStorage;
+use crate::textpos::{apply_op_to_doc, build_doc};
+use mdcs_sdk::{Message, TextDoc};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::error::Error;
+use std::fmt;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, WriteHalf};
+use tokio::sync::mpsc;
+
+const ROOM: &str = "sim";
+const DOC: &str = "doc.txt";
+/// Buffer of each in-memory connection, per direction.
+const PIPE_LEN: usize = 1 << 20;
+/// Yields that let the server finish handling a message before the next
+/// is delivered, so runs don't depend on task scheduling.
+const SETTLE_YIELDS: usize = 100;
+/// Characters edits insert, including multi-byte ones.
+const ALPHABET: &[char] = &['a', 'b', 'x', 'y', ' ', '\n', 'é', '→'];
+
+pub struct Config {
+    pub seed: u64,
+    pub clients: usize,
+    /// Edits made across all clients.
+    pub ops: usize,
+    /// Longest delay of a message; edits are made about twice as often.
+    pub max_delay_ms: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            clients: 3,
+            ops: 200,
+            max_delay_ms: 20,
+        }
+    }
+}
+
+/// A run in which every client ended up with the server's text.
+#[derive(Debug)]
+pub struct Outcome {
+    pub text: String,
+    pub version: u64,
+    pub trace: Vec<String>,
+}
+
+/// A run whose clients didn't end up with the server's text.
+#[derive(Debug)]
+pub struct Divergence {
+    pub seed: u64,
+    /// Edits of the shortest failing run found.
+    pub ops: usize,
+    pub server: (String, u64),
+    /// Each client's text and version.
+    pub clients: Vec<(String, u64)>,
+    pub trace: Vec<String>,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "seed {} diverged after {} edits", self.seed, self.ops)?;
+        writeln!(f, "server v{}: {:?}", self.server.1, self.server.0)?;
+        for (idx, (text, version)) in self.clients.iter().enumerate() {
+            writeln!(f, "c{} v{}: {:?}", idx, version, text)?;
+        }
+        writeln!(f, "trace:")?;
+        for line in &self.trace {
+            writeln!(f, "  {}", line)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for Divergence {}
+
+/// Runs the simulation; a divergence is shrunk to the fewest edits of the
+/// same seed that still diverge.
+pub fn run(config: &Config) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let err = match simulate(config) {
+        Err(err) if err.is::<Divergence>() => err,
+        result => return result,
+    };
+    // The edits of a shorter run are a prefix of the longer one's.
+    let (mut lo, mut hi, mut shortest) = (1, config.ops, err);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match simulate(&Config {
+            ops: mid,
+            ..*config
+        }) {
+            Err(err) if err.is::<Divergence>() => (hi, shortest) = (mid, err),
+            _ => lo = mid + 1,
+        }
+    }
+    Err(shortest)
+}
+
+fn simulate(config: &Config) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+    runtime.block_on(Sim::new(config).run())
+}
+
+/// A client as the server sees it: a connection sending JSON lines. It
+/// applies the server's updates in the order they come and replays its
+/// unconfirmed edits on top, so once every edit is confirmed its text is
+/// the server's.
+struct SimClient {
+    doc_id: String,
+    user_id: String,
+    synced: bool,
+    /// The text as of the last update from the server.
+    confirmed: TextDoc,
+    /// Edits sent that the server hasn't sent back yet, oldest first.
+    unconfirmed: VecDeque<Op>,
+    version: u64,
+}
+
+impl SimClient {
+    fn new(idx: usize) -> Self {
+        let doc_id = format!("{}/{}", ROOM, DOC);
+        let user_id = make_scoped_user_id(&doc_id, &format!("c{}", idx));
+        Self {
+            confirmed: TextDoc::new(doc_id.clone(), user_id.clone()),
+            doc_id,
+            user_id,
+            synced: false,
+            unconfirmed: VecDeque::new(),
+            version: 0,
+        }
+    }
+
+    fn join(&self, name: String) -> [Message; 2] {
+        let hello = Message::Hello {
+            replica_id: self.user_id.clone(),
+            user_name: name,
+        };
+        [hello, encode_sync_request(&self.doc_id, 0)]
+    }
+
+    /// What the user sees.
+    fn text(&self) -> String {
+        let mut doc = self.confirmed.clone();
+        for op in &self.unconfirmed {
+            apply_op_to_doc(&mut doc, op);
+        }
+        doc.get_text()
+    }
+
+    /// A random insert or delete at a character boundary, or `None` until
+    /// the client joined.
+    fn edit(&mut self, rng: &mut Rng) -> Option<(Op, Message)> {
+        if !self.synced {
+            return None;
+        }
+        let text = self.text();
+        let bounds: Vec<usize> = text
+            .char_indices()
+            .map(|(idx, _)| idx)
+            .chain([text.len()])
+            .collect();
+        let chars = bounds.len() - 1;
+        let op = if chars > 0 && rng.below(5) < 2 {
+            let start = rng.below(chars);
+            let end = (start + 1 + rng.below(3)).min(chars);
+            Op::Delete {
+                pos: bounds[start],
+                len: bounds[end] - bounds[start],
+                pos_chars: None,
+                len_chars: None,
+            }
+        } else {
+            let len = 1 + rng.below(3);
+            Op::Insert {
+                pos: bounds[rng.below(bounds.len())],
+                text: (0..len)
+                    .map(|_| ALPHABET[rng.below(ALPHABET.len())])
+                    .collect(),
+                pos_chars: None,
+            }
+        };
+        let update = encode_update(
+            &self.doc_id,
+            &self.user_id,
+            op.clone(),
+            Vec::new(),
+            self.version,
+        )
+        .ok()?;
+        self.unconfirmed.push_back(op.clone());
+        Some((op, update))
+    }
+
+    fn receive(&mut self, msg: &Message) -> Result<Option<String>, String> {
+        if let Some((doc_id, sync, version)) = decode_sync_response(msg)
+            && doc_id == self.doc_id
+        {
+            if let Some(error) = sync.error {
+                return Err(format!("join refused: {}", error));
+            }
+            self.confirmed = build_doc(&self.doc_id, &self.user_id, &sync.text);
+            (self.version, self.synced) = (version, true);
+            return Ok(Some(format!("synced at v{}", version)));
+        }
+        let Some((doc_id, update, version)) = decode_update(msg) else {
+            return Ok(None);
+        };
+        if doc_id != self.doc_id || !matches!(update.op, Op::Insert { .. } | Op::Delete { .. }) {
+            return Ok(None);
+        }
+        if update.user_id == self.user_id {
+            let sent = self.unconfirmed.pop_front();
+            if sent.as_ref() != Some(&in_bytes(&update.op)) {
+                return Err(format!(
+                    "v{} echoed {:?} but {:?} was sent",
+                    version, update.op, sent
+                ));
+            }
+        }
+        apply_op_to_doc(&mut self.confirmed, &update.op);
+        self.version = version;
+        Ok(Some(format!("v{} {:?}", version, update.op)))
+    }
+}
+
+/// `op` without the character positions the server adds to the bytes a
+/// client sent.
+fn in_bytes(op: &Op) -> Op {
+    match op.clone() {
+        Op::Insert { pos, text, .. } => Op::Insert {
+            pos,
+            pos_chars: None,
+            text,
+        },
+        Op::Delete { pos, len, .. } => Op::Delete {
+            pos,
+            len,
+            pos_chars: None,
+            len_chars: None,
+        },
+        op => op,
+    }
+}
+
+enum Hop {
+    ToServer(usize),
+    ToClient(usize),
+}
+
+/// Messages in flight, delivered earliest first; a message never
+/// overtakes an earlier one on the same connection.
+#[derive(Default)]
+struct Network {
+    queue: BinaryHeap<Reverse<(u64, u64)>>,
+    messages: HashMap<u64, (Hop, String)>,
+    /// When the last message of each connection and direction arrives.
+    busy_until: HashMap<(usize, bool), u64>,
+    sent: u64,
+}
+
+impl Network {
+    fn send(&mut self, hop: Hop, line: String, now: u64, delay: u64) {
+        let link = match hop {
+            Hop::ToServer(idx) => (idx, true),
+            Hop::ToClient(idx) => (idx, false),
+        };
+        let busy_until = self.busy_until.entry(link).or_default();
+        let at = (now + delay).max(*busy_until);
+        *busy_until = at;
+        self.sent += 1;
+        self.queue.push(Reverse((at, self.sent)));
+        self.messages.insert(self.sent, (hop, line));
+    }
+
+    fn next_at(&self) -> Option<u64> {
+        self.queue.peek().map(|Reverse((at, _))| *at)
+    }
+
+    fn pop(&mut self) -> Option<(u64, Hop, String)> {
+        let Reverse((at, id)) = self.queue.pop()?;
+        let (hop, line) = self.messages.remove(&id)?;
+        Some((at, hop, line))
+    }
+}
+
+struct Sim {
+    seed: u64,
+    rng: Rng,
+    clients: usize,
+    ops: usize,
+    max_delay_ms: u64,
+    /// Draws each message's delay, as `--simulate-jitter` would.
+    conditions: NetConditions,
+    now: u64,
+    network: Network,
+    trace: Vec<String>,
+}
+
+impl Sim {
+    fn new(config: &Config) -> Self {
+        Self {
+            seed: config.seed,
+            rng: Rng::new(config.seed),
+            clients: config.clients,
+            ops: config.ops,
+            max_delay_ms: config.max_delay_ms,
+            conditions: NetConditions {
+                jitter_ms: config.max_delay_ms,
+                ..NetConditions::default()
+            },
+            now: 0,
+            network: Network::default(),
+            trace: Vec::new(),
+        }
+    }
+
+    fn log(&mut self, who: impl fmt::Display, what: impl fmt::Display) {
+        self.trace
+            .push(format!("{:>7}ms {:<6} {}", self.now, who, what));
+    }
+
+    fn delay(&mut self) -> u64 {
+        // Nothing is lost, so there is always a delay.
+        self.conditions.delay_ms(&mut self.rng).unwrap_or_default()
+    }
+
+    fn send(&mut self, hop: Hop, msg: &impl serde::Serialize) -> serde_json::Result<()> {
+        let mut line = serde_json::to_string(msg)?;
+        line.push('\n');
+        let delay = self.delay();
+        self.network.send(hop, line, self.now, delay);
+        Ok(())
+    }
+
+    async fn run(mut self) -> Result<Outcome, Box<dyn Error + Send + Sync>> {
+        let server = LocalServer::new(Arc::new(MemoryStorage::new()));
+        let (from_server_tx, mut from_server) = mpsc::unbounded_channel::<(usize, String)>();
+        let mut clients = Vec::new();
+        let mut pipes: Vec<WriteHalf<tokio::io::DuplexStream>> = Vec::new();
+        for idx in 0..self.clients {
+            let (ours, theirs) = tokio::io::duplex(PIPE_LEN);
+            server.connect(theirs);
+            let (reader, writer) = tokio::io::split(ours);
+            let from_server_tx = from_server_tx.clone();
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(reader).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    let _ = from_server_tx.send((idx, line));
+                }
+            });
+            pipes.push(writer);
+            let client = SimClient::new(idx);
+            for msg in client.join(format!("c{}", idx)) {
+                self.send(Hop::ToServer(idx), &msg)?;
+            }
+            clients.push(client);
+        }
+
+        let (mut edits, mut next_edit) = (0, 0);
+        loop {
+            let delivery_at = self.network.next_at();
+            if edits < self.ops && delivery_at.is_none_or(|at| next_edit <= at) {
+                self.now = next_edit;
+                next_edit += self.rng.below(self.max_delay_ms as usize / 2 + 1) as u64;
+                let idx = self.rng.below(self.clients);
+                if let Some((op, update)) = clients[idx].edit(&mut self.rng) {
+                    let base = clients[idx].version;
+                    self.log(format!("c{}", idx), format!("edit on v{} {:?}", base, op));
+                    self.send(Hop::ToServer(idx), &update)?;
+                    edits += 1;
+                }
+                continue;
+            }
+            let Some((at, hop, line)) = self.network.pop() else {
+                break;
+            };
+            self.now = at;
+            match hop {
+                Hop::ToServer(idx) => {
+                    pipes[idx].write_all(line.as_bytes()).await?;
+                    for _ in 0..SETTLE_YIELDS {
+                        tokio::task::yield_now().await;
+                    }
+                    while let Ok((to, line)) = from_server.try_recv() {
+                        // Notices like `Members` go out on timers of their
+                        // own; letting them draw delays would make runs of
+                        // one seed differ.
+                        if serde_json::from_str::<Message>(&line).is_err() {
+                            continue;
+                        }
+                        let delay = self.delay();
+                        self.network.send(Hop::ToClient(to), line, self.now, delay);
+                    }
+                }
+                Hop::ToClient(idx) => {
+                    let Ok(msg) = serde_json::from_str::<Message>(&line) else {
+                        continue;
+                    };
+                    match clients[idx].receive(&msg) {
+                        Ok(Some(what)) => self.log(format!("c{}", idx), format!("got {}", what)),
+                        Ok(None) => {}
+                        Err(err) => {
+                            self.log(format!("c{}", idx), &err);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let server = server.doc(ROOM, DOC).await.unwrap_or_default();
+        let converged = clients.iter().all(|client| {
+            client.synced
+                && client.unconfirmed.is_empty()
+                && (client.text(), client.version) == server
+        });
+        if !converged {
+            let clients = clients
+                .iter()
+                .map(|client| (client.text(), client.version))
+                .collect();
+            return Err(Box::new(Divergence {
+                seed: self.seed,
+                ops: self.ops,
+                server,
+                clients,
+                trace: self.trace,
+            }));
+        }
+        Ok(Outcome {
+            text: server.0,
+            version: server.1,
+            trace: self.trace,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sim_convergence() {
+        for seed in 0..4 {
+            let config = Config {
+                seed,
+                ..Config::default()
+            };
+            let outcome = run(&config).unwrap_or_else(|err| panic!("{}", err));
+            assert_eq!(outcome.version, config.ops as u64, "seed {}", seed);
+        }
+    }
+
+    #[test]
+    fn a_seed_replays_the_same_run() {
+        let config = Config {
+            seed: 42,
+            clients: 4,
+            ops: 100,
+            max_delay_ms: 50,
+        };
+        let (first, second) = (run(&config).unwrap(), run(&config).unwrap());
+        assert_eq!(first.trace, second.trace);
+        assert_eq!(first.text, second.text);
+        // Edits of different clients crossed on the way: two were made on
+        // the same version.
+        let edits: Vec<Vec<&str>> = first
+            .trace
+            .iter()
+            .map(|line| line.split_whitespace().collect::<Vec<_>>())
+            .filter(|words| words[2..4] == ["edit", "on"])
+            .collect();
+        // Client and base version of each edit.
+        let crossed = edits
+            .windows(2)
+            .any(|pair| pair[0][1] != pair[1][1] && pair[0][4] == pair[1][4]);
+        assert!(crossed);
+    }
+}
@@ -0,0 +1,378 @@
+//! Reconciling server snapshots (`SyncResponse`) with the local replica.
+//!
+//! A snapshot is applied as the difference to the local text, so the local
+//! `TextDoc` keeps its identity and positions held elsewhere (cursor,
+//! selection, undo history) can be mapped through the ops.
+
+use crate::protocol::Op;
+use crate::textpos::clamp_to_boundary;
+use std::collections::VecDeque;
+
+/// Beyond this many inserted plus deleted characters the changed middle is
+/// replaced as a whole instead of searching for a minimal diff.
+const MAX_DIFF_STEPS: usize = 2000;
+
+/// Local text ops sent after each sync request that is still unanswered.
+/// The server answers a request with its text at that point and applies
+/// the ops sent after it later, so they are replayed onto the snapshot.
+#[derive(Debug, Default)]
+pub struct PendingOps {
+    /// One entry per outstanding request, oldest first.
+    requests: VecDeque<Vec<Op>>,
+    /// Text ops sent on this connection whose echo hasn't come back yet.
+    unacked: usize,
+}
+
+impl PendingOps {
+    pub fn request_sent(&mut self) {
+        self.requests.push_back(Vec::new());
+    }
+
+    pub fn op_sent(&mut self, op: &Op) {
+        if matches!(op, Op::Insert { .. } | Op::Delete { .. }) {
+            self.unacked += 1;
+            for ops in &mut self.requests {
+                ops.push(op.clone());
+            }
+        }
+    }
+
+    /// The server echoed one of our text ops back.
+    pub fn op_acked(&mut self) {
+        self.unacked = self.unacked.saturating_sub(1);
+    }
+
+    pub fn unacked(&self) -> usize {
+        self.unacked
+    }
+
+    /// Forgets outstanding requests and ops, e.g. when the connection is
+    /// gone and no answer will come.
+    pub fn clear(&mut self) {
+        self.requests.clear();
+        self.unacked = 0;
+    }
+
+    /// The text the server ends up with once it has applied the local ops
+    /// sent after the request `snapshot` answers.
+    pub fn rebase(&mut self, snapshot: &str) -> String {
+        let mut text = snapshot.to_string();
+        for op in self.requests.pop_front().unwrap_or_default() {
+            apply_to_text(&mut text, &op);
+        }
+        text
+    }
+}
+
+pub fn apply_to_text(text: &mut String, op: &Op) {
+    match op {
+        Op::Insert {
+            pos, text: insert, ..
+        } => {
+            let pos = clamp_to_boundary(text, *pos);
+            text.insert_str(pos, insert);
+        }
+        Op::Delete { pos, len, .. } => {
+            let start = clamp_to_boundary(text, *pos);
+            let end = clamp_to_boundary(text, start.saturating_add(*len));
+            text.drain(start..end);
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => {}
+    }
+}
+
+/// One element of an edit script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change<T> {
+    Keep(T),
+    Delete(T),
+    Insert(T),
+}
+
+/// Insert and Delete ops turning `from` into `to`, to be applied in order.
+pub fn diff(from: &str, to: &str) -> Vec<Op> {
+    let prefix = from
+        .char_indices()
+        .zip(to.chars())
+        .find(|((_, a), b)| a != b)
+        .map_or(from.len().min(to.len()), |((idx, _), _)| idx);
+    let suffix = from[prefix..]
+        .chars()
+        .rev()
+        .zip(to[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum::<usize>();
+    let old: Vec<char> = from[prefix..from.len() - suffix].chars().collect();
+    let new: Vec<char> = to[prefix..to.len() - suffix].chars().collect();
+    let steps = shortest_edit(&old, &new).unwrap_or_else(|| {
+        let deletes = old.iter().map(|ch| Change::Delete(*ch));
+        deletes
+            .chain(new.iter().map(|ch| Change::Insert(*ch)))
+            .collect()
+    });
+
+    let mut ops: Vec<Op> = Vec::new();
+    let mut pos = prefix;
+    for step in steps {
+        match step {
+            Change::Keep(ch) => pos += ch.len_utf8(),
+            Change::Delete(ch) => match ops.last_mut() {
+                Some(Op::Delete {
+                    pos: start, len, ..
+                }) if *start == pos => *len += ch.len_utf8(),
+                _ => ops.push(Op::Delete {
+                    pos,
+                    len: ch.len_utf8(),
+                    pos_chars: None,
+                    len_chars: None,
+                }),
+            },
+            Change::Insert(ch) => {
+                match ops.last_mut() {
+                    Some(Op::Insert {
+                        pos: start, text, ..
+                    }) if *start + text.len() == pos => text.push(ch),
+                    _ => ops.push(Op::Insert {
+                        pos,
+                        text: ch.to_string(),
+                        pos_chars: None,
+                    }),
+                }
+                pos += ch.len_utf8();
+            }
+        }
+    }
+    ops
+}
+
+/// Line by line changes from `from` to `to`, for showing a diff.
+pub fn diff_lines<'a>(from: &'a str, to: &'a str) -> Vec<Change<&'a str>> {
+    let old: Vec<&str> = from.lines().collect();
+    let new: Vec<&str> = to.lines().collect();
+    shortest_edit(&old, &new).unwrap_or_else(|| {
+        let deletes = old.iter().map(|line| Change::Delete(*line));
+        deletes
+            .chain(new.iter().map(|line| Change::Insert(*line)))
+            .collect()
+    })
+}
+
+/// Character-wise three-way merge: `base` with both the changes that made
+/// `ours` and those that made `theirs`. Text either side deleted is gone;
+/// where both inserted at the same place, ours comes first, and identical
+/// inserts are kept once. `None` if either side changed too much to diff.
+pub fn merge(base: &str, ours: &str, theirs: &str) -> Option<String> {
+    let base: Vec<char> = base.chars().collect();
+    // Per side: the text inserted before each base character (and at the
+    // end), and which base characters were deleted.
+    let changes = |to: &str| {
+        let to: Vec<char> = to.chars().collect();
+        let mut inserted = vec![String::new(); base.len() + 1];
+        let mut deleted = vec![false; base.len()];
+        let mut at = 0;
+        for step in shortest_edit(&base, &to)? {
+            match step {
+                Change::Keep(_) => at += 1,
+                Change::Delete(_) => {
+                    deleted[at] = true;
+                    at += 1;
+                }
+                Change::Insert(ch) => inserted[at].push(ch),
+            }
+        }
+        Some((inserted, deleted))
+    };
+    let (our_inserts, our_deletes) = changes(ours)?;
+    let (their_inserts, their_deletes) = changes(theirs)?;
+
+    let mut merged = String::new();
+    for at in 0..=base.len() {
+        merged.push_str(&our_inserts[at]);
+        if their_inserts[at] != our_inserts[at] {
+            merged.push_str(&their_inserts[at]);
+        }
+        if at < base.len() && !our_deletes[at] && !their_deletes[at] {
+            merged.push(base[at]);
+        }
+    }
+    Some(merged)
+}
+
+/// Myers' shortest edit script from `old` to `new`, or `None` if it takes
+/// more than `MAX_DIFF_STEPS` inserts and deletes.
+fn shortest_edit<T: Copy + PartialEq>(old: &[T], new: &[T]) -> Option<Vec<Change<T>>> {
+    let (n, m) = (old.len() as isize, new.len() as isize);
+    let max = (old.len() + new.len()).min(MAX_DIFF_STEPS) as isize;
+    // Furthest x reached on each diagonal k = x - y, at index k + offset.
+    let offset = max + 1;
+    let mut v = vec![0isize; 2 * offset as usize + 1];
+    // `v` before each round d, restricted to the diagonals -d-1..=d+1.
+    let mut trace: Vec<Vec<isize>> = Vec::new();
+    for d in 0..=max {
+        trace.push(v[(offset - d - 1) as usize..=(offset + d + 1) as usize].to_vec());
+        for k in (-d..=d).step_by(2) {
+            let at = |k: isize| v[(k + offset) as usize];
+            let mut x = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+                at(k + 1)
+            } else {
+                at(k - 1) + 1
+            };
+            let mut y = x - k;
+            while x < n && y < m && old[x as usize] == new[y as usize] {
+                x += 1;
+                y += 1;
+            }
+            v[(k + offset) as usize] = x;
+            if x >= n && y >= m {
+                return Some(backtrack(&trace, old, new));
+            }
+        }
+    }
+    None
+}
+
+fn backtrack<T: Copy>(trace: &[Vec<isize>], old: &[T], new: &[T]) -> Vec<Change<T>> {
+    let mut steps = Vec::new();
+    let (mut x, mut y) = (old.len() as isize, new.len() as isize);
+    for (d, v) in trace.iter().enumerate().rev() {
+        let d = d as isize;
+        let at = |k: isize| v[(k + d + 1) as usize];
+        let k = x - y;
+        let prev_k = if k == -d || (k != d && at(k - 1) < at(k + 1)) {
+            k + 1
+        } else {
+            k - 1
+        };
+        let prev_x = at(prev_k);
+        let prev_y = prev_x - prev_k;
+        while x > prev_x && y > prev_y {
+            x -= 1;
+            y -= 1;
+            steps.push(Change::Keep(old[x as usize]));
+        }
+        if d > 0 {
+            if x == prev_x {
+                y -= 1;
+                steps.push(Change::Insert(new[y as usize]));
+            } else {
+                x -= 1;
+                steps.push(Change::Delete(old[x as usize]));
+            }
+        }
+    }
+    steps.reverse();
+    steps
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn apply(from: &str, ops: &[Op]) -> String {
+        let mut text = from.to_string();
+        for op in ops {
+            apply_to_text(&mut text, op);
+        }
+        text
+    }
+
+    #[test]
+    fn diff_touches_only_what_changed() {
+        let ops = diff("the quick fox", "the quick brown fox!");
+        assert_eq!(
+            ops,
+            vec![
+                Op::Insert {
+                    pos: 10,
+                    text: "brown ".into(),
+                    pos_chars: None
+                },
+                Op::Insert {
+                    pos: 19,
+                    text: "!".into(),
+                    pos_chars: None
+                },
+            ]
+        );
+        assert_eq!(
+            diff("a→b→c", "a→c"),
+            vec![Op::Delete {
+                pos: 4,
+                len: 4,
+                pos_chars: None,
+                len_chars: None
+            }]
+        );
+        assert!(diff("same", "same").is_empty());
+        assert_eq!(
+            diff_lines("a\nb\nc", "a\nc\nd\n"),
+            vec![
+                Change::Keep("a"),
+                Change::Delete("b"),
+                Change::Keep("c"),
+                Change::Insert("d"),
+            ]
+        );
+
+        let cases = [
+            ("", "new text"),
+            ("old text", ""),
+            ("kitten sitting", "sitting kitten"),
+            ("αβγ\nδεζ\n", "αγ\nδxεζ\nη"),
+        ];
+        for (from, to) in cases {
+            assert_eq!(apply(from, &diff(from, to)), to, "{:?} -> {:?}", from, to);
+        }
+
+        // Past the step limit the middle is replaced wholesale.
+        let from = "x".repeat(MAX_DIFF_STEPS);
+        let to = "y".repeat(MAX_DIFF_STEPS);
+        let ops = diff(&from, &to);
+        assert_eq!(ops.len(), 2);
+        assert_eq!(apply(&from, &ops), to);
+    }
+
+    #[test]
+    fn merges_keep_the_changes_of_both_sides() {
+        let base = "the quick fox jumps";
+        let merged = merge(base, "the quick brown fox jumps", "the fox jumps high");
+        assert_eq!(merged.as_deref(), Some("the brown fox jumps high"));
+        assert_eq!(merge(base, base, "changed").as_deref(), Some("changed"));
+        assert_eq!(merge("", "ab", "ab").as_deref(), Some("ab"));
+        assert_eq!(merge("x", "ax", "bx").as_deref(), Some("abx"));
+        let long = "y".repeat(MAX_DIFF_STEPS + 1);
+        assert_eq!(merge("", &long, ""), None);
+    }
+
+    #[test]
+    fn ops_sent_after_a_request_are_replayed_onto_its_snapshot() {
+        let mut pending = PendingOps::default();
+        pending.op_sent(&Op::Insert {
+            pos: 0,
+            text: "before".into(),
+            pos_chars: None,
+        });
+        pending.request_sent();
+        pending.op_sent(&Op::Insert {
+            pos: 5,
+            text: "!".into(),
+            pos_chars: None,
+        });
+        pending.op_sent(&Op::Cursor { pos: 6, at: None });
+        pending.request_sent();
+        pending.op_sent(&Op::Delete {
+            pos: 0,
+            len: 1,
+            pos_chars: None,
+            len_chars: None,
+        });
+        assert_eq!(pending.unacked(), 3);
+        pending.op_acked();
+        assert_eq!(pending.unacked(), 2);
+
+        assert_eq!(pending.rebase("hello"), "ello!");
+        assert_eq!(pending.rebase("ello!"), "llo!");
+        assert_eq!(pending.rebase("unrequested"), "unrequested");
+    }
+}
@@ -1,55 +1,2918 @@
+use async_trait::async_trait;
+use flate2::Compression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::OsString;
+use std::fmt;
 use std::fs;
-use std::io;
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
+mod cipher;
+mod lock;
+mod memory;
+
+pub use cipher::Cipher;
+pub(crate) use lock::is_running;
+pub use lock::{DataDirInUse, DataDirLock, LOCK_FILE};
+pub use memory::MemoryStorage;
+
+/// When the directory holding written documents is fsynced, which makes
+/// their rename durable. The documents themselves are always synced before
+/// they are renamed into place.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPolicy {
+    /// Leave it to the OS; a crash can lose recent saves, though never
+    /// leave a document half written.
+    Never,
+    /// Before every save returns.
+    OnSave,
+    /// A background task syncs what was saved since its last run.
+    Interval(Duration),
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    /// `never`, `on-save` or `interval:<n>ms` / `interval:<n>s`.
+    fn from_str(input: &str) -> Result<Self, Self::Err> {
+        match input {
+            "never" => return Ok(SyncPolicy::Never),
+            "on-save" => return Ok(SyncPolicy::OnSave),
+            _ => {}
+        }
+        let invalid = || {
+            format!(
+                "'{}' is not never, on-save or interval:<n>ms / interval:<n>s",
+                input
+            )
+        };
+        let every = input.strip_prefix("interval:").ok_or_else(invalid)?;
+        let every = if let Some(ms) = every.strip_suffix("ms") {
+            ms.parse().map(Duration::from_millis)
+        } else if let Some(secs) = every.strip_suffix('s') {
+            secs.parse().map(Duration::from_secs)
+        } else {
+            return Err(invalid());
+        };
+        match every {
+            Ok(every) if !every.is_zero() => Ok(SyncPolicy::Interval(every)),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl fmt::Display for SyncPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SyncPolicy::Never => write!(f, "never"),
+            SyncPolicy::OnSave => write!(f, "on-save"),
+            SyncPolicy::Interval(every) => write!(f, "interval:{}ms", every.as_millis()),
+        }
+    }
+}
+
+/// Directory under a room holding the revisions of its documents.
+const HISTORY_DIR: &str = ".history";
+/// Texts longer than this many bytes are gzipped by default.
+pub const DEFAULT_COMPRESS_ABOVE: usize = 64 * 1024;
+/// Starts every gzip stream; never the start of UTF-8 text.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Where documents that failed their checksum are moved, per room.
+const QUARANTINE_DIR: &str = ".quarantine";
+/// Where deleted documents are moved, per room, each under the Unix time
+/// in milliseconds it was deleted at.
+const TRASH_DIR: &str = ".trash";
+/// How long deleted documents are kept by default.
+pub const DEFAULT_TRASH_RETENTION: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+/// A room's settings, in its directory.
+const ROOM_META_FILE: &str = ".room.json";
+
+/// Numbers the temporary files of `replace_file`, so concurrent writes of
+/// one path never share one.
+static TMP_FILES: AtomicU64 = AtomicU64::new(0);
+
+/// Marks a data dir as migrated to the current layout (see
+/// `migrate_names`).
+const LAYOUT_FILE: &str = ".layout";
+const LAYOUT_VERSION: &str = "nested doc names\n";
+/// What `LAYOUT_FILE` held while `/` in doc names was encoded as `%2F`.
+const FLAT_LAYOUT_VERSION: &str = "percent-encoded names\n";
+
+/// How many revisions are kept per document and how often the server takes
+/// one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HistoryPolicy {
+    pub keep: usize,
+    /// A revision is taken once this many versions passed since the last...
+    pub every_versions: u64,
+    /// ...or once this much time did, whichever comes first.
+    pub every: Duration,
+}
+
+impl Default for HistoryPolicy {
+    fn default() -> Self {
+        Self {
+            keep: 50,
+            every_versions: 100,
+            every: Duration::from_secs(10 * 60),
+        }
+    }
+}
+
+/// Encryption at rest, from `--encryption-key-file`.
+pub struct Encryption {
+    pub cipher: Cipher,
+    /// Encrypt plaintext files found at startup instead of refusing them.
+    pub migrate: bool,
+}
+
+/// A stored document that can't be trusted: it doesn't match the checksum
+/// in its metadata, or doesn't decode. Carried inside an `io::Error` of
+/// kind `InvalidData`; see `is_corrupt`.
+#[derive(Debug)]
+pub struct Corrupt {
+    pub path: PathBuf,
+    pub why: String,
+}
+
+impl fmt::Display for Corrupt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is corrupt: {}", self.path.display(), self.why)
+    }
+}
+
+impl std::error::Error for Corrupt {}
+
+fn corrupt(path: &Path, why: impl Into<String>) -> io::Error {
+    let why = why.into();
+    let path = path.to_path_buf();
+    io::Error::new(io::ErrorKind::InvalidData, Corrupt { path, why })
+}
+
+/// Whether `err` is a `Corrupt` document, which `StorageBackend::recover`
+/// may be able to restore.
+pub fn is_corrupt(err: &io::Error) -> bool {
+    err.get_ref().is_some_and(|inner| inner.is::<Corrupt>())
+}
+
+/// How the `fs` backend stores documents, from the server flags.
+pub struct FsOptions {
+    pub data_dir: PathBuf,
+    pub fsync: SyncPolicy,
+    pub encryption: Option<Encryption>,
+    /// Texts longer than this are gzipped; `None` never compresses.
+    pub compress_above: Option<usize>,
+    /// Reload documents whose files are edited outside the server.
+    pub watch: bool,
+    /// Documents created by a join start from a template in here, with
+    /// either backend.
+    pub template_dir: Option<PathBuf>,
+    /// What a join does about names differing only in case.
+    pub doc_names: DocNames,
+    /// Deleted documents are purged from the trash once they were deleted
+    /// this long ago.
+    pub trash_retention: Duration,
+}
+
+/// A stored revision of a document.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Revision {
+    pub version: u64,
+    pub saved_at: SystemTime,
+    path: PathBuf,
+}
+
+impl Revision {
+    /// Parses `<version>-<unix seconds>.txt`.
+    fn from_path(path: PathBuf) -> Option<Self> {
+        let stem = path.file_name()?.to_str()?.strip_suffix(".txt")?;
+        let (version, secs) = stem.split_once('-')?;
+        Some(Self {
+            version: version.parse().ok()?,
+            saved_at: UNIX_EPOCH + Duration::from_secs(secs.parse().ok()?),
+            path,
+        })
+    }
+}
+
+/// What is known about a document besides its text, kept next to it in
+/// `.<doc>.meta.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DocMeta {
+    /// Version the server reached; counting resumes here after a restart.
+    pub version: u64,
+    /// Unix seconds of the last save.
+    pub last_modified: Option<u64>,
+    pub last_editor: Option<String>,
+    pub locked: bool,
+    /// Checksum of the text as saved, `crc32c:<hex>`.
+    pub checksum: Option<String>,
+    /// Who wrote how much, by user name, in the order they first edited.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub contributions: Vec<UserStats>,
+    #[serde(skip_serializing_if = "WhitespacePolicy::is_off")]
+    pub whitespace: WhitespacePolicy,
+    #[serde(skip_serializing_if = "Acl::is_open")]
+    pub acl: Acl,
+    /// Sizes the room was told this document grew past, so a restart
+    /// doesn't tell it again.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub warned: Vec<Threshold>,
+}
+
+/// Settings of a room, kept in its directory as `.room.json`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RoomMeta {
+    /// The doc joined by those naming none.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_doc: Option<String>,
+    /// Doc names that join another doc instead, as long as no document
+    /// goes by them, e.g. `shared` for `shared.txt`.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+}
+
+/// How a document's whitespace is tidied when it is saved, like
+/// editorconfig's `insert_final_newline` and `trim_trailing_whitespace`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WhitespacePolicy {
+    /// End the document with exactly one newline.
+    pub final_newline: bool,
+    /// Strip spaces and tabs at the ends of lines.
+    pub trim_trailing: bool,
+    /// Tidy the open document too, with edits every client receives;
+    /// otherwise only the saved file is.
+    pub strict: bool,
+}
+
+impl WhitespacePolicy {
+    pub fn is_off(&self) -> bool {
+        !self.final_newline && !self.trim_trailing
+    }
+
+    /// `text` tidied up, except for the lines holding one of the byte
+    /// offsets in `busy`, such as where someone is typing.
+    pub fn normalize(&self, text: &str, busy: &[usize]) -> String {
+        let is_busy = |from: usize, to: usize| busy.iter().any(|&at| from <= at && at <= to);
+        let mut tidy = String::with_capacity(text.len());
+        let mut start = 0;
+        for line in text.split_inclusive('\n') {
+            let body = line.trim_end_matches(['\r', '\n']);
+            let ending = &line[body.len()..];
+            let end = start + body.len();
+            if self.trim_trailing && !is_busy(start, end) {
+                tidy.push_str(body.trim_end_matches([' ', '\t']));
+            } else {
+                tidy.push_str(body);
+            }
+            tidy.push_str(ending);
+            start += line.len();
+        }
+        let content_end = text.trim_end_matches(['\r', '\n']).len();
+        if self.final_newline && !text.is_empty() && !is_busy(content_end, text.len()) {
+            let ending = if tidy.ends_with("\r\n") { "\r\n" } else { "\n" };
+            tidy.truncate(tidy.trim_end_matches(['\r', '\n']).len());
+            tidy.push_str(ending);
+        }
+        tidy
+    }
+}
+
+impl fmt::Display for WhitespacePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let rules: Vec<&str> = [
+            (self.final_newline, "final newline"),
+            (self.trim_trailing, "trim trailing whitespace"),
+        ]
+        .into_iter()
+        .filter_map(|(on, rule)| on.then_some(rule))
+        .collect();
+        match (rules.is_empty(), self.strict) {
+            (true, _) => f.write_str("off"),
+            (false, false) => f.write_str(&rules.join(", ")),
+            (false, true) => write!(f, "{} (strict)", rules.join(", ")),
+        }
+    }
+}
+
+/// Who may edit and who may read a document, by user name; an empty list
+/// lets everyone. Writers may read too. Names are all there is to tell
+/// users apart, so anyone joining under a writer's name may write.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Acl {
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub writers: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub readers: Vec<String>,
+}
+
+impl Acl {
+    pub fn is_open(&self) -> bool {
+        self.writers.is_empty() && self.readers.is_empty()
+    }
+
+    /// What the user called `name` may do with the document.
+    pub fn access(&self, name: &str) -> Access {
+        let listed = |names: &[String]| names.iter().any(|listed| listed == name);
+        let writer = listed(&self.writers);
+        if !writer && !self.readers.is_empty() && !listed(&self.readers) {
+            Access::Denied
+        } else if writer || self.writers.is_empty() {
+            Access::Write
+        } else {
+            Access::Read
+        }
+    }
+}
+
+impl fmt::Display for Acl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names = |names: &[String]| match names {
+            [] => "everyone".to_string(),
+            names => names.join(", "),
+        };
+        write!(
+            f,
+            "writers: {}; readers: {}",
+            names(&self.writers),
+            names(&self.readers)
+        )
+    }
+}
+
+/// What a user may do with a document under its `Acl`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Write,
+    Read,
+    Denied,
+}
+
+/// A size a document may grow past, from `--warn-doc-bytes` and
+/// `--warn-doc-lines`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Threshold {
+    Bytes,
+    Lines,
+}
+
+/// One user's edits to a document. Users are told apart by name, as there
+/// is no identity that outlives a connection; two people joining under the
+/// same name share a row.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UserStats {
+    pub user: String,
+    pub inserted_bytes: u64,
+    pub deleted_bytes: u64,
+    /// Inserts and deletes.
+    pub ops: u64,
+    /// Unix seconds of the last edit.
+    pub last_active: Option<u64>,
+}
+
+impl DocMeta {
+    /// Counts an insert of `inserted` or a delete of `deleted` bytes by
+    /// `user`.
+    pub fn record_edit(&mut self, user: &str, inserted: usize, deleted: usize) {
+        let index = match self
+            .contributions
+            .iter()
+            .position(|stats| stats.user == user)
+        {
+            Some(index) => index,
+            None => {
+                self.contributions.push(UserStats {
+                    user: user.to_string(),
+                    ..UserStats::default()
+                });
+                self.contributions.len() - 1
+            }
+        };
+        let stats = &mut self.contributions[index];
+        stats.inserted_bytes += inserted as u64;
+        stats.deleted_bytes += deleted as u64;
+        stats.ops += 1;
+        stats.last_active = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs());
+    }
+
+    /// Fills in the checksum of `text` and the current time, before saving.
+    fn stamp(&mut self, text: &str) {
+        self.checksum = Some(checksum(text.as_bytes()));
+        self.last_modified = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|since| since.as_secs());
+    }
+}
+
+/// A document's text and metadata, as `StorageBackend::load` returns them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StoredDoc {
+    pub text: String,
+    pub meta: DocMeta,
+    /// Why the metadata couldn't be used and defaults were taken instead.
+    pub warning: Option<String>,
+}
+
+impl StoredDoc {
+    /// Whether nothing was ever saved under the document's name: saving
+    /// stamps a checksum.
+    pub fn is_new(&self) -> bool {
+        self.text.is_empty() && self.meta.checksum.is_none()
+    }
+}
+
+/// A document file changed by something other than this server, as
+/// `Storage::external_change` finds it. The metadata is the sidecar's as
+/// is; its checksum is the old text's.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalChange {
+    pub room: String,
+    pub doc: String,
+    pub text: String,
+    pub meta: DocMeta,
+}
+
+/// Documents being saved right now and the checksum each was last saved
+/// with, to tell this server's writes from outside ones.
+#[derive(Debug, Default)]
+struct OwnWrites {
+    saving: HashSet<PathBuf>,
+    saved: HashMap<PathBuf, String>,
+}
+
+/// A stored document, as `StorageBackend::list` finds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DocEntry {
+    pub name: String,
+    pub size: u64,
+    pub modified: SystemTime,
+}
+
+/// A deleted document, as `StorageBackend::list_trash` finds it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrashEntry {
+    pub name: String,
+    pub size: u64,
+    pub deleted_at: SystemTime,
+}
+
+/// Which `StorageBackend` the server keeps documents in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum BackendKind {
+    /// Files under the data dir (`Storage`).
+    Fs,
+    /// Process memory (`MemoryStorage`); nothing survives a restart.
+    Memory,
+}
+
+/// What a join of a doc name that differs only in case from a document
+/// open or stored in the room does. On case-insensitive disks (Windows,
+/// macOS) both names are one file, which two documents would overwrite.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum DocNames {
+    /// Join the name as asked, and tell the joiner about the other one.
+    #[default]
+    Warn,
+    /// Join the other document instead.
+    Merge,
+    /// They are different documents; say nothing.
+    Distinct,
+}
+
+/// Where the server keeps documents.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// The document's text and metadata; an empty document if it was never
+    /// saved.
+    async fn load(&self, room: &str, doc: &str) -> io::Result<StoredDoc>;
+    /// Saves the text and its metadata, with the checksum and modification
+    /// time filled in.
+    async fn save(&self, room: &str, doc: &str, text: String, meta: DocMeta) -> io::Result<()>;
+    /// The documents stored in `room`, sorted by name.
+    async fn list(&self, room: &str) -> io::Result<Vec<DocEntry>>;
+    /// The rooms with documents stored, sorted.
+    async fn list_rooms(&self) -> io::Result<Vec<String>>;
+    /// Removes the document, its metadata and its revisions; backends with
+    /// a trash move them there. `NotFound` if it was never saved.
+    async fn delete(&self, room: &str, doc: &str) -> io::Result<()>;
+
+    /// The documents deleted from `room` that are still in its trash, last
+    /// deleted first. Backends without a trash delete for good.
+    async fn list_trash(&self, _room: &str) -> io::Result<Vec<TrashEntry>> {
+        Ok(Vec::new())
+    }
+
+    /// Brings `doc` back from `room`'s trash as `to`, the copy deleted
+    /// last if there are several. A document stored as `to` is refused
+    /// with `AlreadyExists`, or with `force` moved to the trash instead.
+    async fn restore_from_trash(
+        &self,
+        room: &str,
+        doc: &str,
+        _to: &str,
+        _force: bool,
+    ) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{}/{} is not in the trash", room, doc),
+        ))
+    }
+
+    /// Stores `text` as revision `version` of the document. Backends without
+    /// history drop it.
+    async fn save_revision(
+        &self,
+        _room: &str,
+        _doc: &str,
+        _text: String,
+        _version: u64,
+    ) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// The stored revisions of a document, oldest first; none in backends
+    /// without history.
+    async fn list_revisions(&self, _room: &str, _doc: &str) -> io::Result<Vec<Revision>> {
+        Ok(Vec::new())
+    }
+
+    /// The text of the newest revision saved as `version`.
+    async fn load_revision(&self, room: &str, doc: &str, version: u64) -> io::Result<String> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no revision {} of {}/{}", version, room, doc),
+        ))
+    }
+
+    /// The room's settings; defaults if none were saved.
+    async fn load_room_meta(&self, _room: &str) -> io::Result<RoomMeta> {
+        Ok(RoomMeta::default())
+    }
+
+    /// Saves the room's settings. Backends without room settings refuse.
+    async fn save_room_meta(&self, room: &str, _meta: RoomMeta) -> io::Result<()> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("this storage keeps no settings for room {}", room),
+        ))
+    }
+
+    /// Restores a document that failed to load as `Corrupt` from the newest
+    /// revision that still reads, after moving the bad copy aside. Backends
+    /// without history have nothing to restore from.
+    async fn recover(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no revisions of {}/{} to recover from", room, doc),
+        ))
+    }
+
+    /// Checks that `room` and `doc` can be stored, without touching storage.
+    /// The same names are accepted by every backend.
+    fn validate(&self, room: &str, doc: &str) -> io::Result<()> {
+        encode_component(room)?;
+        encode_doc_path(doc)?;
+        Ok(())
+    }
+}
+
+/// The filesystem calls behind saving, so tests can watch or fail them.
+pub trait FileSystem: Send + Sync {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()>;
+    /// Creates or truncates `path` and writes `bytes`, without syncing.
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()>;
+    fn sync_file(&self, path: &Path) -> io::Result<()>;
+    /// Makes renames and new entries in `dir` durable. A no-op on Windows,
+    /// which has no directory handles to sync (NTFS journals renames).
+    fn sync_dir(&self, dir: &Path) -> io::Result<()>;
+    /// Replaces `to`, also when it exists on Windows
+    /// (`MOVEFILE_REPLACE_EXISTING`).
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()>;
+    fn remove_file(&self, path: &Path) -> io::Result<()>;
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()>;
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>>;
+    /// Follows symlinks, like `fs::metadata`.
+    fn metadata(&self, path: &Path) -> io::Result<fs::Metadata>;
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>>;
+}
+
+/// `FileSystem` on `std::fs`.
+pub struct RealFs;
+
+impl FileSystem for RealFs {
+    fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::create_dir_all(dir)
+    }
+
+    fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        fs::File::create(path)?.write_all(bytes)
+    }
+
+    fn sync_file(&self, path: &Path) -> io::Result<()> {
+        fs::File::options().write(true).open(path)?.sync_all()
+    }
+
+    #[cfg(unix)]
+    fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+        fs::File::open(dir)?.sync_all()
+    }
+
+    #[cfg(not(unix))]
+    fn sync_dir(&self, _dir: &Path) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+        fs::rename(from, to)
+    }
+
+    fn remove_file(&self, path: &Path) -> io::Result<()> {
+        fs::remove_file(path)
+    }
+
+    fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+        fs::remove_dir_all(dir)
+    }
+
+    fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        fs::read_dir(dir)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect()
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        fs::metadata(path)
+    }
+
+    fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        fs::read(path)
+    }
+}
+
+/// How long fsyncs take and how many corrupt documents were found, served
+/// on the health address as `GET /metrics`.
+#[derive(Debug, Default)]
+pub struct StorageStats {
+    flushes: AtomicU64,
+    total_micros: AtomicU64,
+    max_micros: AtomicU64,
+    corruptions: AtomicU64,
+}
+
+impl StorageStats {
+    fn record(&self, took: Duration) {
+        let micros = u64::try_from(took.as_micros()).unwrap_or(u64::MAX);
+        self.flushes.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+
+    pub fn flushes(&self) -> u64 {
+        self.flushes.load(Ordering::Relaxed)
+    }
+
+    pub fn corruptions(&self) -> u64 {
+        self.corruptions.load(Ordering::Relaxed)
+    }
+
+    /// Prometheus text format.
+    pub fn render(&self) -> String {
+        let seconds = |micros: &AtomicU64| micros.load(Ordering::Relaxed) as f64 / 1e6;
+        format!(
+            "storage_flushes_total {}\n\
+             storage_flush_seconds_sum {:.6}\n\
+             storage_flush_seconds_max {:.6}\n\
+             storage_corruptions_total {}\n",
+            self.flushes(),
+            seconds(&self.total_micros),
+            seconds(&self.max_micros),
+            self.corruptions(),
+        )
+    }
+}
+
+#[derive(Clone)]
 pub struct Storage {
     data_dir: PathBuf,
+    policy: SyncPolicy,
+    /// Revisions kept per document.
+    keep_revisions: usize,
+    fs: Arc<dyn FileSystem>,
+    /// Documents saved since the last interval sync.
+    unsynced: Arc<Mutex<HashSet<PathBuf>>>,
+    stats: Arc<StorageStats>,
+    /// Seals document text and revisions when set.
+    cipher: Option<Arc<Cipher>>,
+    /// Texts longer than this are written gzipped.
+    compress_above: Option<usize>,
+    own_writes: Arc<Mutex<OwnWrites>>,
 }
 
 impl Storage {
-    pub fn new<P: AsRef<Path>>(data_dir: P) -> Self {
+    pub fn new<P: AsRef<Path>>(data_dir: P, policy: SyncPolicy) -> Self {
+        Self::with_fs(data_dir, policy, Arc::new(RealFs))
+    }
+
+    pub fn with_fs<P: AsRef<Path>>(
+        data_dir: P,
+        policy: SyncPolicy,
+        fs: Arc<dyn FileSystem>,
+    ) -> Self {
         Self {
             data_dir: data_dir.as_ref().to_path_buf(),
+            policy,
+            keep_revisions: HistoryPolicy::default().keep,
+            fs,
+            unsynced: Arc::default(),
+            stats: Arc::default(),
+            cipher: None,
+            compress_above: Some(DEFAULT_COMPRESS_ABOVE),
+            own_writes: Arc::default(),
         }
     }
 
-    pub fn load_text(&self, room: &str, doc: &str) -> io::Result<String> {
-        let path = self.doc_path(room, doc);
-        match fs::read_to_string(&path) {
+    pub fn keep_revisions(mut self, keep: usize) -> Self {
+        self.keep_revisions = keep;
+        self
+    }
+
+    /// Gzips document texts and revisions longer than `threshold` bytes
+    /// when they are written; `None` writes everything as is. Either kind
+    /// of file is read back regardless.
+    pub fn compress_above(mut self, threshold: Option<usize>) -> Self {
+        self.compress_above = threshold;
+        self
+    }
+
+    /// Encrypts document text and revisions written from now on, and
+    /// refuses to read ones that aren't.
+    pub fn encrypt_with(mut self, cipher: Cipher) -> Self {
+        self.cipher = Some(Arc::new(cipher));
+        self
+    }
+
+    pub fn stats(&self) -> Arc<StorageStats> {
+        Arc::clone(&self.stats)
+    }
+
+    /// Runs `op` on the blocking thread pool, so slow disks don't stall the
+    /// tasks serving connections.
+    async fn blocking<T: Send + 'static>(
+        &self,
+        op: impl FnOnce(&Storage) -> io::Result<T> + Send + 'static,
+    ) -> io::Result<T> {
+        let storage = self.clone();
+        tokio::task::spawn_blocking(move || op(&storage))
+            .await
+            .map_err(io::Error::other)?
+    }
+
+    /// Fsyncs the documents saved since the last call, for
+    /// `SyncPolicy::Interval`.
+    pub async fn sync_pending(&self) -> io::Result<()> {
+        self.blocking(Storage::sync_pending_blocking).await
+    }
+
+    /// Renames names stored by older versions to the current encoding.
+    pub async fn migrate_names(&self) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        self.blocking(Storage::migrate_names_blocking).await
+    }
+
+    /// The change to the document at `path` if it was written by something
+    /// else than this storage, for `--watch-data-dir`. Anything that isn't
+    /// a document, is being saved, or holds what was last saved is `None`.
+    pub async fn external_change(&self, path: PathBuf) -> io::Result<Option<ExternalChange>> {
+        self.blocking(move |storage| storage.external_change_blocking(&path))
+            .await
+    }
+
+    /// Purges documents deleted more than `retention` ago from the trash of
+    /// every room. Returns how many there were.
+    pub async fn purge_trash(&self, retention: Duration) -> io::Result<usize> {
+        self.blocking(move |storage| storage.purge_trash_blocking(retention))
+            .await
+    }
+
+    /// Checks that every stored document and revision opens with the key.
+    pub async fn check_encryption(&self, migrate: bool) -> io::Result<Vec<PathBuf>> {
+        self.blocking(move |storage| storage.check_encryption_blocking(migrate))
+            .await
+    }
+
+    /// The document's text and metadata. Missing or unreadable metadata of
+    /// an existing document falls back to defaults with a warning; text
+    /// that doesn't match the metadata's checksum is `Corrupt`.
+    fn load_blocking(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let stored = self.load_verified(room, doc);
+        if let Err(err) = &stored
+            && is_corrupt(err)
+        {
+            self.stats.corruptions.fetch_add(1, Ordering::Relaxed);
+        }
+        stored
+    }
+
+    fn load_verified(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let text = self.load_text(room, doc)?;
+        let meta_path = self.meta_path(room, doc)?;
+        let meta = match self.fs.read(&meta_path) {
+            Ok(json) => serde_json::from_slice(&json)
+                .map_err(|err| format!("corrupt metadata {}: {}", meta_path.display(), err)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound && text.is_empty() => {
+                Ok(DocMeta::default())
+            }
+            Err(err) => Err(format!("no metadata {}: {}", meta_path.display(), err)),
+        };
+        let (meta, warning) = match meta {
+            Ok(meta) => (meta, None),
+            Err(warning) => (DocMeta::default(), Some(warning)),
+        };
+        if let Some(expected) = &meta.checksum
+            && !checksum_matches(expected, text.as_bytes())
+        {
+            let actual = checksum(text.as_bytes());
+            return Err(corrupt(
+                &self.doc_path(room, doc)?,
+                format!("checksum {} instead of {}", actual, expected),
+            ));
+        }
+        Ok(StoredDoc {
+            text,
+            meta,
+            warning,
+        })
+    }
+
+    /// Moves the document aside to the room's quarantine and saves the
+    /// newest revision that reads in its place, keeping the version count.
+    fn recover_blocking(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let path = self.doc_path(room, doc)?;
+        let mut moved_to = self
+            .room_dir(room)?
+            .join(QUARANTINE_DIR)
+            .join(encode_doc_path(doc)?)
+            .into_os_string();
+        moved_to.push(format!(".{}", unix_secs()));
+        let moved_to = PathBuf::from(moved_to);
+        if let Some(dir) = moved_to.parent() {
+            self.fs.create_dir_all(dir)?;
+        }
+        self.fs.rename(&path, &moved_to)?;
+
+        let recovered = self
+            .list_revisions_blocking(room, doc)?
+            .into_iter()
+            .rev()
+            .find_map(|revision| Some((self.read_text(&revision.path).ok()?, revision.version)));
+        let Some((text, version)) = recovered else {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!(
+                    "no readable revision of {}/{} to recover from; the corrupt copy is {}",
+                    room,
+                    doc,
+                    moved_to.display()
+                ),
+            ));
+        };
+        let mut meta = self
+            .fs
+            .read(&self.meta_path(room, doc)?)
+            .ok()
+            .and_then(|json| serde_json::from_slice::<DocMeta>(&json).ok())
+            .unwrap_or_default();
+        self.save_blocking(room, doc, &text, &mut meta)?;
+        Ok(StoredDoc {
+            text,
+            meta,
+            warning: Some(format!(
+                "recovered from revision {}; the corrupt copy is {}",
+                version,
+                moved_to.display()
+            )),
+        })
+    }
+
+    /// Saves the text, then its metadata with the checksum and modification
+    /// time filled in.
+    fn save_blocking(
+        &self,
+        room: &str,
+        doc: &str,
+        text: &str,
+        meta: &mut DocMeta,
+    ) -> io::Result<()> {
+        let path = self.doc_path(room, doc)?;
+        self.own_writes().saving.insert(path.clone());
+        let result = (|| {
+            self.save_text(room, doc, text)?;
+            meta.stamp(text);
+            let json = serde_json::to_vec(meta).map_err(io::Error::other)?;
+            self.write_atomic(&self.meta_path(room, doc)?, &json)
+        })();
+        let mut own_writes = self.own_writes();
+        own_writes.saving.remove(&path);
+        if result.is_ok() {
+            own_writes.saved.insert(path, checksum(text.as_bytes()));
+        }
+        result
+    }
+
+    fn own_writes(&self) -> std::sync::MutexGuard<'_, OwnWrites> {
+        self.own_writes
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+    }
+
+    /// `Path::is_file` through `self.fs`.
+    fn is_file(&self, path: &Path) -> bool {
+        self.fs
+            .metadata(path)
+            .is_ok_and(|metadata| metadata.is_file())
+    }
+
+    fn external_change_blocking(&self, path: &Path) -> io::Result<Option<ExternalChange>> {
+        let (Ok(data_dir), Ok(path)) = (fs::canonicalize(&self.data_dir), fs::canonicalize(path))
+        else {
+            return Ok(None);
+        };
+        let Ok(relative) = path.strip_prefix(&data_dir) else {
+            return Ok(None);
+        };
+        let mut names = relative.components().map(|part| part.as_os_str().to_str());
+        let Some(room) = names.next().flatten().and_then(decode_component) else {
+            return Ok(None);
+        };
+        let Some(doc) = names
+            .collect::<Option<Vec<_>>>()
+            .and_then(|parts| decode_doc_path(&parts))
+        else {
+            return Ok(None);
+        };
+
+        let path = self.doc_path(&room, &doc)?;
+        if self.own_writes().saving.contains(&path) {
+            return Ok(None);
+        }
+        let text = match self.read_text(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let meta: DocMeta = self
+            .fs
+            .read(&self.meta_path(&room, &doc)?)
+            .ok()
+            .and_then(|json| serde_json::from_slice(&json).ok())
+            .unwrap_or_default();
+        let last_saved = self.own_writes().saved.get(&path).cloned();
+        let unchanged = last_saved.is_some_and(|saved| saved == checksum(text.as_bytes()))
+            || meta
+                .checksum
+                .as_deref()
+                .is_some_and(|expected| checksum_matches(expected, text.as_bytes()));
+        if unchanged {
+            return Ok(None);
+        }
+        Ok(Some(ExternalChange {
+            room,
+            doc,
+            text,
+            meta,
+        }))
+    }
+
+    fn load_text(&self, room: &str, doc: &str) -> io::Result<String> {
+        let path = self.doc_path(room, doc)?;
+        match self.read_text(&path) {
             Ok(text) => Ok(text),
             Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(String::new()),
             Err(err) => Err(err),
         }
     }
 
-    pub fn save_text(&self, room: &str, doc: &str, text: &str) -> io::Result<()> {
-        let path = self.doc_path(room, doc);
+    fn save_text(&self, room: &str, doc: &str, text: &str) -> io::Result<()> {
+        let path = self.doc_path(room, doc)?;
         if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
+            self.fs.create_dir_all(parent)?;
+        }
+        self.write_atomic(&path, &self.encode(text)?)
+    }
+
+    /// `text` as written to disk: gzipped if it is long, then sealed if
+    /// encryption is on.
+    fn encode(&self, text: &str) -> io::Result<Vec<u8>> {
+        let bytes = match self.compress_above {
+            Some(threshold) if text.len() > threshold => {
+                let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+                encoder.write_all(text.as_bytes())?;
+                encoder.finish()?
+            }
+            _ => text.as_bytes().to_vec(),
+        };
+        Ok(match &self.cipher {
+            Some(cipher) => cipher.seal(&bytes),
+            None => bytes,
+        })
+    }
+
+    /// A document or revision file's text, decrypted if encryption is on
+    /// and decompressed if it was gzipped.
+    fn read_text(&self, path: &Path) -> io::Result<String> {
+        let bytes = self.fs.read(path)?;
+        let invalid = |why: &str| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{}: {}", path.display(), why),
+            )
+        };
+        let plain = match (&self.cipher, cipher::is_sealed(&bytes)) {
+            (None, false) => bytes,
+            (None, true) => return Err(invalid("encrypted, but no key was given")),
+            (Some(cipher), true) => cipher
+                .open(&bytes)
+                .ok_or_else(|| invalid("can't be decrypted; wrong key or damaged file"))?,
+            (Some(_), false) => return Err(invalid("not encrypted")),
+        };
+        let plain = if plain.starts_with(GZIP_MAGIC) {
+            let mut text = Vec::new();
+            GzDecoder::new(&plain[..])
+                .read_to_end(&mut text)
+                .map_err(|err| corrupt(path, format!("bad gzip data: {}", err)))?;
+            text
+        } else {
+            plain
+        };
+        String::from_utf8(plain).map_err(|_| corrupt(path, "not UTF-8 text"))
+    }
+
+    /// With encryption on, reads every document and revision, including
+    /// trashed and quarantined ones: plaintext ones are encrypted in place
+    /// if `migrate` is set, and anything else that doesn't open is
+    /// reported, one line per file. Quarantined copies may be damaged, so
+    /// they only have to be encrypted. Returns the migrated files.
+    fn check_encryption_blocking(&self, migrate: bool) -> io::Result<Vec<PathBuf>> {
+        let Some(cipher) = &self.cipher else {
+            return Ok(Vec::new());
+        };
+        let mut files = Vec::new();
+        let mut quarantined = Vec::new();
+        for room in self.visible_entries(&self.data_dir)? {
+            if !room.is_dir() {
+                continue;
+            }
+            // Each trash bin is laid out like a room.
+            let mut dirs = vec![room.clone()];
+            dirs.extend(self.visible_entries(&room.join(TRASH_DIR))?);
+            for dir in dirs {
+                files.extend(self.visible_files(&dir)?);
+                files.extend(
+                    self.visible_files(&dir.join(HISTORY_DIR))?
+                        .into_iter()
+                        .filter(|path| Revision::from_path(path.clone()).is_some()),
+                );
+            }
+            quarantined.extend(self.visible_files(&room.join(QUARANTINE_DIR))?);
+        }
+
+        let mut migrated = Vec::new();
+        let mut problems = Vec::new();
+        let files = files.into_iter().map(|path| (path, false));
+        for (path, damaged) in files.chain(quarantined.into_iter().map(|path| (path, true))) {
+            let bytes = self.fs.read(&path)?;
+            if !cipher::is_sealed(&bytes) && migrate {
+                self.write_atomic(&path, &cipher.seal(&bytes))?;
+                migrated.push(path);
+            } else if !cipher::is_sealed(&bytes) {
+                problems.push(format!(
+                    "{}: not encrypted (pass --migrate-encrypt to encrypt it)",
+                    path.display()
+                ));
+            } else if !damaged && cipher.open(&bytes).is_none() {
+                problems.push(format!(
+                    "{}: can't be decrypted; wrong key or damaged file",
+                    path.display()
+                ));
+            }
+        }
+        if !problems.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "{} stored files don't open with the key:\n  {}",
+                    problems.len(),
+                    problems.join("\n  ")
+                ),
+            ));
+        }
+        Ok(migrated)
+    }
+
+    /// Moves the document, its metadata and its revisions into the room's
+    /// trash, where `restore_from_trash` finds them until the retention
+    /// sweep purges them.
+    fn delete_blocking(&self, room: &str, doc: &str) -> io::Result<()> {
+        let doc_path = self.doc_path(room, doc)?;
+        let history = self
+            .room_dir(room)?
+            .join(HISTORY_DIR)
+            .join(encode_doc_path(doc)?);
+        {
+            let mut unsynced = self.unsynced.lock().unwrap_or_else(|err| err.into_inner());
+            let sidecar = sidecar_path(&doc_path);
+            unsynced.retain(|path| {
+                *path != doc_path && *path != sidecar && !path.starts_with(&history)
+            });
+        }
+        if !self.is_file(&doc_path) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{}/{} was never saved", room, doc),
+            ));
+        }
+        // Deleted twice in a millisecond, the second copy goes a bit later.
+        let trash = self.room_dir(room)?.join(TRASH_DIR);
+        let encoded = encode_doc_path(doc)?;
+        let mut deleted_at = unix_millis();
+        while self
+            .fs
+            .metadata(&trash.join(deleted_at.to_string()).join(&encoded))
+            .is_ok()
+        {
+            deleted_at += 1;
+        }
+        let bin = trash.join(deleted_at.to_string());
+        self.move_doc(&self.room_dir(room)?, doc, &bin, doc)?;
+        self.own_writes().saved.remove(&doc_path);
+        if self.policy == SyncPolicy::OnSave {
+            self.fs.sync_dir(&bin)?;
+            if let Some(dir) = doc_path.parent() {
+                self.fs.sync_dir(dir)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Moves `doc` of the room directory `from`, with its metadata and
+    /// revisions, to `to` as `to_doc`. Either can be a room or a trash bin,
+    /// which is laid out like one.
+    fn move_doc(&self, from: &Path, doc: &str, to: &Path, to_doc: &str) -> io::Result<()> {
+        let (encoded, to_encoded) = (encode_doc_path(doc)?, encode_doc_path(to_doc)?);
+        let (doc_path, to_path) = (from.join(&encoded), to.join(&to_encoded));
+        if let Some(dir) = to_path.parent() {
+            self.fs.create_dir_all(dir)?;
+        }
+        self.fs.rename(&doc_path, &to_path)?;
+        match self
+            .fs
+            .rename(&sidecar_path(&doc_path), &sidecar_path(&to_path))
+        {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        let history = from.join(HISTORY_DIR).join(&encoded);
+        let to_history = to.join(HISTORY_DIR).join(&to_encoded);
+        if !history.is_dir() {
+            return Ok(());
+        }
+        if !to_history.exists() {
+            if let Some(dir) = to_history.parent() {
+                self.fs.create_dir_all(dir)?;
+            }
+            return self.fs.rename(&history, &to_history);
+        }
+        for revision in self.fs.read_dir(&history)? {
+            if let Some(name) = revision.file_name() {
+                self.fs.rename(&revision, &to_history.join(name))?;
+            }
+        }
+        self.fs.remove_dir_all(&history)
+    }
+
+    /// The trash bins of `room` and when their documents were deleted,
+    /// oldest first.
+    fn trash_bins(&self, room: &str) -> io::Result<Vec<(SystemTime, PathBuf)>> {
+        let paths = match self.fs.read_dir(&self.room_dir(room)?.join(TRASH_DIR)) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut bins: Vec<(SystemTime, PathBuf)> = paths
+            .into_iter()
+            .filter_map(|path| {
+                let ms = path.file_name()?.to_str()?.parse().ok()?;
+                Some((UNIX_EPOCH + Duration::from_millis(ms), path))
+            })
+            .collect();
+        bins.sort();
+        Ok(bins)
+    }
+
+    fn list_trash_blocking(&self, room: &str) -> io::Result<Vec<TrashEntry>> {
+        let mut trashed = Vec::new();
+        for (deleted_at, bin) in self.trash_bins(room)?.into_iter().rev() {
+            let mut docs = Vec::new();
+            self.list_docs_in(&bin, &[], &mut docs)?;
+            docs.sort_by(|a, b| a.name.cmp(&b.name));
+            trashed.extend(docs.into_iter().map(|doc| TrashEntry {
+                name: doc.name,
+                size: doc.size,
+                deleted_at,
+            }));
+        }
+        Ok(trashed)
+    }
+
+    fn restore_blocking(&self, room: &str, doc: &str, to: &str, force: bool) -> io::Result<()> {
+        let encoded = encode_doc_path(doc)?;
+        let bin = self
+            .trash_bins(room)?
+            .into_iter()
+            .rev()
+            .map(|(_, bin)| bin)
+            .find(|bin| self.is_file(&bin.join(&encoded)))
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}/{} is not in the trash", room, doc),
+                )
+            })?;
+        let to_path = self.doc_path(room, to)?;
+        if self.is_file(&to_path) {
+            if !force {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!(
+                        "{}/{} exists; restore under another name, or with --force to trash it",
+                        room, to
+                    ),
+                ));
+            }
+            self.delete_blocking(room, to)?;
+        }
+        self.move_doc(&bin, doc, &self.room_dir(room)?, to)?;
+        self.own_writes().saved.remove(&to_path);
+        let mut left = Vec::new();
+        self.list_docs_in(&bin, &[], &mut left)?;
+        if left.is_empty() {
+            self.fs.remove_dir_all(&bin)?;
+        }
+        if self.policy == SyncPolicy::OnSave
+            && let Some(dir) = to_path.parent()
+        {
+            self.fs.sync_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    /// Purges what was deleted from any room more than `retention` ago.
+    /// Returns how many documents that was.
+    fn purge_trash_blocking(&self, retention: Duration) -> io::Result<usize> {
+        let Some(before) = SystemTime::now().checked_sub(retention) else {
+            return Ok(0);
+        };
+        let mut purged = 0;
+        for room in self.list_rooms_blocking()? {
+            for (deleted_at, bin) in self.trash_bins(&room)? {
+                if deleted_at >= before {
+                    break;
+                }
+                let mut docs = Vec::new();
+                self.list_docs_in(&bin, &[], &mut docs)?;
+                self.fs.remove_dir_all(&bin)?;
+                purged += docs.len();
+            }
+        }
+        Ok(purged)
+    }
+
+    /// The rooms with a directory under the data dir, sorted. Hidden
+    /// entries, plain files and names this storage didn't encode are
+    /// skipped.
+    fn list_rooms_blocking(&self) -> io::Result<Vec<String>> {
+        let mut rooms = Vec::new();
+        for path in self.visible_entries(&self.data_dir)? {
+            if path.is_dir()
+                && let Some(name) = decoded_file_name(&path)
+            {
+                rooms.push(name);
+            }
+        }
+        rooms.sort();
+        Ok(rooms)
+    }
+
+    /// The documents stored in `room`, sorted by name; those in
+    /// subdirectories are named by their path, like `notes/2024/standup`.
+    /// The history, sidecars, temporary files and anything else hidden or
+    /// not a plain file is skipped.
+    fn list_docs_blocking(&self, room: &str) -> io::Result<Vec<DocEntry>> {
+        let mut docs = Vec::new();
+        self.list_docs_in(&self.room_dir(room)?, &[], &mut docs)?;
+        docs.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(docs)
+    }
+
+    fn list_docs_in(
+        &self,
+        dir: &Path,
+        parents: &[String],
+        docs: &mut Vec<DocEntry>,
+    ) -> io::Result<()> {
+        for path in self.visible_entries(dir)? {
+            let Some(name) = decoded_file_name(&path).filter(|name| !name.contains('/')) else {
+                continue;
+            };
+            let parts = [parents, &[name]].concat();
+            let metadata = self.fs.metadata(&path)?;
+            if metadata.is_dir() && parts.len() < MAX_DOC_DEPTH {
+                self.list_docs_in(&path, &parts, docs)?;
+            } else if metadata.is_file() {
+                docs.push(DocEntry {
+                    name: parts.join("/"),
+                    size: metadata.len(),
+                    modified: metadata.modified()?,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries of `dir` not starting with a dot; none if it doesn't exist.
+    fn visible_entries(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let paths = match self.fs.read_dir(dir) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let hidden = |path: &PathBuf| {
+            path.file_name()
+                .is_none_or(|name| name.as_encoded_bytes().starts_with(b"."))
+        };
+        Ok(paths.into_iter().filter(|path| !hidden(path)).collect())
+    }
+
+    /// Plain files under `dir` and its subdirectories, leaving out hidden
+    /// entries like `visible_entries`.
+    fn visible_files(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut files = Vec::new();
+        for path in self.visible_entries(dir)? {
+            if path.is_dir() {
+                files.extend(self.visible_files(&path)?);
+            } else if path.is_file() {
+                files.push(path);
+            }
+        }
+        Ok(files)
+    }
+
+    /// Stores `text` as revision `version` of the document, then prunes the
+    /// oldest revisions beyond the limit.
+    fn save_revision_blocking(
+        &self,
+        room: &str,
+        doc: &str,
+        text: &str,
+        version: u64,
+    ) -> io::Result<()> {
+        let dir = self.history_path(room, doc)?;
+        self.fs.create_dir_all(&dir)?;
+        self.write_atomic(
+            &dir.join(format!("{}-{}.txt", version, unix_secs())),
+            &self.encode(text)?,
+        )?;
+
+        let revisions = self.list_revisions_blocking(room, doc)?;
+        let excess = revisions.len().saturating_sub(self.keep_revisions);
+        for revision in &revisions[..excess] {
+            self.fs.remove_file(&revision.path)?;
         }
-        fs::write(path, text)
+        Ok(())
     }
 
-    fn doc_path(&self, room: &str, doc: &str) -> PathBuf {
-        let safe_room = sanitize_component(room);
-        let safe_doc = sanitize_component(doc);
-        self.data_dir.join(safe_room).join(safe_doc)
+    /// The stored revisions of a document, oldest first. Versions restart
+    /// with the server, so they are ordered by when they were saved.
+    fn list_revisions_blocking(&self, room: &str, doc: &str) -> io::Result<Vec<Revision>> {
+        let paths = match self.fs.read_dir(&self.history_path(room, doc)?) {
+            Ok(paths) => paths,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(err) => return Err(err),
+        };
+        let mut revisions: Vec<Revision> =
+            paths.into_iter().filter_map(Revision::from_path).collect();
+        revisions.sort_by_key(|revision| (revision.saved_at, revision.version));
+        Ok(revisions)
+    }
+
+    /// The text of the newest revision saved as `version`.
+    fn load_revision_blocking(&self, room: &str, doc: &str, version: u64) -> io::Result<String> {
+        let revision = self
+            .list_revisions_blocking(room, doc)?
+            .into_iter()
+            .rev()
+            .find(|revision| revision.version == version)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("no revision {} of {}/{}", version, room, doc),
+                )
+            })?;
+        self.read_text(&revision.path)
+    }
+
+    /// Fsyncs the directories of the documents saved since the last call,
+    /// for `SyncPolicy::Interval`.
+    fn sync_pending_blocking(&self) -> io::Result<()> {
+        let paths: Vec<PathBuf> = {
+            let mut unsynced = self.unsynced.lock().unwrap_or_else(|err| err.into_inner());
+            unsynced.drain().collect()
+        };
+        if paths.is_empty() {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let dirs: HashSet<&Path> = paths.iter().filter_map(|path| path.parent()).collect();
+        let result = dirs.into_iter().try_for_each(|dir| self.fs.sync_dir(dir));
+        match result {
+            Ok(()) => self.stats.record(started.elapsed()),
+            // Retried on the next call.
+            Err(_) => {
+                let mut unsynced = self.unsynced.lock().unwrap_or_else(|err| err.into_inner());
+                unsynced.extend(paths);
+            }
+        }
+        result
+    }
+
+    /// Writes `bytes` to a temporary file next to `path` and renames it over
+    /// `path`, so a crash or a full disk leaves either the old or the new
+    /// content but never a truncated file. The temporary file is always
+    /// synced; the policy decides whether the directory, which makes the
+    /// rename durable, is synced right away, later or never.
+    fn write_atomic(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+        let synced_in = replace_file(self.fs.as_ref(), path, bytes)?;
+        match self.policy {
+            SyncPolicy::Never => {}
+            SyncPolicy::OnSave => {
+                let started = Instant::now();
+                if let Some(dir) = path.parent() {
+                    self.fs.sync_dir(dir)?;
+                }
+                self.stats.record(synced_in + started.elapsed());
+            }
+            SyncPolicy::Interval(_) => {
+                let mut unsynced = self.unsynced.lock().unwrap_or_else(|err| err.into_inner());
+                unsynced.insert(path.to_path_buf());
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings a data dir written by an older version to the current
+    /// layout, once: names with a leading `.` (which now clash with hidden
+    /// files) are renamed to the current encoding, and documents stored
+    /// flat with `/` encoded as `%2F` move into subdirectories. Returns the
+    /// renames.
+    fn migrate_names_blocking(&self) -> io::Result<Vec<(PathBuf, PathBuf)>> {
+        let marker = self.data_dir.join(LAYOUT_FILE);
+        let layout = match self.fs.read(&marker) {
+            Ok(layout) => Some(layout),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err),
+        };
+        if layout.as_deref() == Some(LAYOUT_VERSION.as_bytes()) {
+            return Ok(Vec::new());
+        }
+        let mut renamed = Vec::new();
+        let rooms = match self.fs.read_dir(&self.data_dir) {
+            Ok(rooms) => rooms,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(err) => return Err(err),
+        };
+        for room in rooms.into_iter().filter(|path| path.is_dir()) {
+            if layout.as_deref() == Some(FLAT_LAYOUT_VERSION.as_bytes()) {
+                self.nest_docs(&room, &mut renamed)?;
+                continue;
+            }
+            let room = match legacy_dot_name(&room) {
+                Some(name) => self.rename_legacy(&room, &name, &mut renamed)?,
+                None => room,
+            };
+            for doc in self.fs.read_dir(&room)? {
+                let Some(name) = legacy_dot_name(&doc).filter(|_| doc.is_file()) else {
+                    continue;
+                };
+                let new = self.rename_legacy(&doc, &name, &mut renamed)?;
+                let encoded = new.file_name().unwrap_or_default().to_string_lossy();
+                let meta = room.join(format!(".{}.meta.json", name));
+                if meta.exists() {
+                    let new_meta = room.join(format!(".{}.meta.json", encoded));
+                    self.fs.rename(&meta, &new_meta)?;
+                    renamed.push((meta, new_meta));
+                }
+                let history = room.join(HISTORY_DIR).join(&name);
+                if history.exists() {
+                    let new_history = room.join(HISTORY_DIR).join(encoded.as_ref());
+                    self.fs.rename(&history, &new_history)?;
+                    renamed.push((history, new_history));
+                }
+            }
+            self.nest_docs(&room, &mut renamed)?;
+        }
+        self.fs.create_dir_all(&self.data_dir)?;
+        self.write_atomic(&marker, LAYOUT_VERSION.as_bytes())?;
+        Ok(renamed)
+    }
+
+    /// Moves the documents of `room` whose name has a `/`, stored flat by
+    /// older versions, into subdirectories along with their metadata and
+    /// history. Names that can't be nested, like `a//b`, stay as they were.
+    fn nest_docs(&self, room: &Path, renamed: &mut Vec<(PathBuf, PathBuf)>) -> io::Result<()> {
+        for doc in self.visible_entries(room)? {
+            let Some(nested) = decoded_file_name(&doc)
+                .filter(|name| name.contains('/') && doc.is_file())
+                .and_then(|name| encode_doc_path(&name).ok())
+            else {
+                continue;
+            };
+            let new = room.join(&nested);
+            let history = room
+                .join(HISTORY_DIR)
+                .join(doc.file_name().unwrap_or_default());
+            let moves = [
+                (sidecar_path(&doc), sidecar_path(&new)),
+                (history, room.join(HISTORY_DIR).join(&nested)),
+                (doc, new),
+            ];
+            for (from, to) in moves {
+                if !from.exists() {
+                    continue;
+                }
+                if let Some(dir) = to.parent() {
+                    self.fs.create_dir_all(dir)?;
+                }
+                self.fs.rename(&from, &to)?;
+                renamed.push((from, to));
+            }
+        }
+        Ok(())
+    }
+
+    fn rename_legacy(
+        &self,
+        path: &Path,
+        name: &str,
+        renamed: &mut Vec<(PathBuf, PathBuf)>,
+    ) -> io::Result<PathBuf> {
+        let new = path.with_file_name(encode_component(name)?);
+        self.fs.rename(path, &new)?;
+        renamed.push((path.to_path_buf(), new.clone()));
+        Ok(new)
+    }
+
+    fn room_dir(&self, room: &str) -> io::Result<PathBuf> {
+        self.contained(self.data_dir.join(encode_component(room)?))
+    }
+
+    fn doc_path(&self, room: &str, doc: &str) -> io::Result<PathBuf> {
+        self.contained(self.room_dir(room)?.join(encode_doc_path(doc)?))
+    }
+
+    fn room_meta_blocking(&self, room: &str) -> io::Result<RoomMeta> {
+        let path = self.room_dir(room)?.join(ROOM_META_FILE);
+        match self.fs.read(&path) {
+            Ok(json) => {
+                serde_json::from_slice(&json).map_err(|err| corrupt(&path, err.to_string()))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(RoomMeta::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn save_room_meta_blocking(&self, room: &str, meta: &RoomMeta) -> io::Result<()> {
+        let dir = self.room_dir(room)?;
+        self.fs.create_dir_all(&dir)?;
+        let json = serde_json::to_vec(meta).map_err(io::Error::other)?;
+        self.write_atomic(&dir.join(ROOM_META_FILE), &json)
+    }
+
+    fn meta_path(&self, room: &str, doc: &str) -> io::Result<PathBuf> {
+        Ok(sidecar_path(&self.doc_path(room, doc)?))
+    }
+
+    fn history_path(&self, room: &str, doc: &str) -> io::Result<PathBuf> {
+        let dir = self.room_dir(room)?.join(HISTORY_DIR);
+        self.contained(dir.join(encode_doc_path(doc)?))
+    }
+
+    /// `path` if, with symlinks resolved as far as it exists, it stays
+    /// inside the data dir.
+    fn contained(&self, path: PathBuf) -> io::Result<PathBuf> {
+        let Ok(root) = self.data_dir.canonicalize() else {
+            // Nothing exists yet that could lead elsewhere.
+            return Ok(path);
+        };
+        let existing = path.ancestors().find_map(|dir| dir.canonicalize().ok());
+        if existing.is_some_and(|existing| !existing.starts_with(&root)) {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} leads outside the data dir", path.display()),
+            ));
+        }
+        Ok(path)
     }
 }
 
-fn sanitize_component(input: &str) -> String {
-    let mut out = String::with_capacity(input.len());
-    for ch in input.chars() {
-        if ch.is_ascii_alphanumeric() || ch == '-' || ch == '_' || ch == '.' {
-            out.push(ch);
+/// Each call runs on the blocking thread pool.
+#[async_trait]
+impl StorageBackend for Storage {
+    /// Missing or unreadable metadata of an existing document falls back to
+    /// defaults with a warning.
+    async fn load(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.load_blocking(&room, &doc))
+            .await
+    }
+
+    async fn save(&self, room: &str, doc: &str, text: String, mut meta: DocMeta) -> io::Result<()> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.save_blocking(&room, &doc, &text, &mut meta))
+            .await
+    }
+
+    async fn list(&self, room: &str) -> io::Result<Vec<DocEntry>> {
+        let room = room.to_string();
+        self.blocking(move |storage| storage.list_docs_blocking(&room))
+            .await
+    }
+
+    /// The rooms with a directory under the data dir.
+    async fn list_rooms(&self) -> io::Result<Vec<String>> {
+        self.blocking(Storage::list_rooms_blocking).await
+    }
+
+    async fn delete(&self, room: &str, doc: &str) -> io::Result<()> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.delete_blocking(&room, &doc))
+            .await
+    }
+
+    async fn list_trash(&self, room: &str) -> io::Result<Vec<TrashEntry>> {
+        let room = room.to_string();
+        self.blocking(move |storage| storage.list_trash_blocking(&room))
+            .await
+    }
+
+    async fn restore_from_trash(
+        &self,
+        room: &str,
+        doc: &str,
+        to: &str,
+        force: bool,
+    ) -> io::Result<()> {
+        let (room, doc, to) = (room.to_string(), doc.to_string(), to.to_string());
+        self.blocking(move |storage| storage.restore_blocking(&room, &doc, &to, force))
+            .await
+    }
+
+    async fn recover(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.recover_blocking(&room, &doc))
+            .await
+    }
+
+    /// Also prunes the oldest revisions beyond the limit.
+    async fn save_revision(
+        &self,
+        room: &str,
+        doc: &str,
+        text: String,
+        version: u64,
+    ) -> io::Result<()> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.save_revision_blocking(&room, &doc, &text, version))
+            .await
+    }
+
+    async fn load_room_meta(&self, room: &str) -> io::Result<RoomMeta> {
+        let room = room.to_string();
+        self.blocking(move |storage| storage.room_meta_blocking(&room))
+            .await
+    }
+
+    async fn save_room_meta(&self, room: &str, meta: RoomMeta) -> io::Result<()> {
+        let room = room.to_string();
+        self.blocking(move |storage| storage.save_room_meta_blocking(&room, &meta))
+            .await
+    }
+
+    async fn list_revisions(&self, room: &str, doc: &str) -> io::Result<Vec<Revision>> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.list_revisions_blocking(&room, &doc))
+            .await
+    }
+
+    async fn load_revision(&self, room: &str, doc: &str, version: u64) -> io::Result<String> {
+        let (room, doc) = (room.to_string(), doc.to_string());
+        self.blocking(move |storage| storage.load_revision_blocking(&room, &doc, version))
+            .await
+    }
+}
+
+/// CRC-32C of `bytes`, as stored in `DocMeta::checksum`.
+fn checksum(bytes: &[u8]) -> String {
+    format!("crc32c:{:08x}", crc32c(bytes))
+}
+
+/// Whether `bytes` match a `DocMeta::checksum`. Older versions stored
+/// 64-bit FNV-1a; checksums of unknown kinds can't be checked and pass.
+fn checksum_matches(expected: &str, bytes: &[u8]) -> bool {
+    match expected.split_once(':') {
+        Some(("crc32c", _)) => expected == checksum(bytes),
+        Some(("fnv1a64", _)) => expected == fnv1a64(bytes),
+        _ => true,
+    }
+}
+
+fn fnv1a64(bytes: &[u8]) -> String {
+    let hash = bytes.iter().fold(0xcbf2_9ce4_8422_2325_u64, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    });
+    format!("fnv1a64:{:016x}", hash)
+}
+
+/// CRC-32C (Castagnoli) lookup table, for the reflected polynomial.
+const CRC32C_TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut idx = 0;
+    while idx < 256 {
+        let mut crc = idx as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[idx] = crc;
+        idx += 1;
+    }
+    table
+};
+
+fn crc32c(bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!0, |crc, byte| {
+        CRC32C_TABLE[((crc ^ u32::from(*byte)) & 0xff) as usize] ^ (crc >> 8)
+    })
+}
+
+fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_secs())
+}
+
+fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64)
+}
+
+/// Longest encoded room or doc name, leaving space in the usual 255 byte
+/// file name limit for the sidecar and temporary file affixes.
+const MAX_NAME_LEN: usize = 200;
+
+/// `name` as a file name: letters, digits, `-`, `_` and `.` are kept,
+/// every other byte (and a leading `.`, which would hide the file) becomes
+/// `%XX`. Distinct names stay distinct. Empty names, `.` and `..` are
+/// refused.
+pub fn encode_component(name: &str) -> io::Result<String> {
+    if matches!(name, "" | "." | "..") {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("'{}' is not a valid name", name),
+        ));
+    }
+    let mut out = String::with_capacity(name.len());
+    for (idx, byte) in name.bytes().enumerate() {
+        let keep = byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.');
+        if keep && !(idx == 0 && byte == b'.') {
+            out.push(char::from(byte));
+        } else {
+            out.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    if out.len() > MAX_NAME_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "name is too long ({} bytes encoded, at most {})",
+                out.len(),
+                MAX_NAME_LEN
+            ),
+        ));
+    }
+    Ok(out)
+}
+
+/// Most `/`-separated parts in a doc name, so nesting stays shallow.
+const MAX_DOC_DEPTH: usize = 8;
+/// Longest encoded doc name with all its parts and separators.
+const MAX_DOC_PATH_LEN: usize = 1024;
+
+/// The path of doc `name` under its room: split on `/`, with each part
+/// encoded by `encode_component`, so that none is empty, `.` or `..`.
+fn encode_doc_path(name: &str) -> io::Result<PathBuf> {
+    let parts: Vec<&str> = name.split('/').collect();
+    if parts.len() > MAX_DOC_DEPTH {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "'{}' is nested too deep ({} parts, at most {})",
+                name,
+                parts.len(),
+                MAX_DOC_DEPTH
+            ),
+        ));
+    }
+    let mut path = PathBuf::new();
+    let mut len = 0;
+    for part in parts {
+        let encoded = encode_component(part).map_err(|err| {
+            io::Error::new(err.kind(), format!("in doc name '{}': {}", name, err))
+        })?;
+        len += encoded.len() + 1;
+        path.push(encoded);
+    }
+    if len - 1 > MAX_DOC_PATH_LEN {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "doc name is too long ({} bytes encoded, at most {})",
+                len - 1,
+                MAX_DOC_PATH_LEN
+            ),
+        ));
+    }
+    Ok(path)
+}
+
+/// The doc name `encode_doc_path` turned into these path parts, if it is
+/// one.
+fn decode_doc_path(parts: &[&str]) -> Option<String> {
+    let names: Vec<String> = parts
+        .iter()
+        .map(|part| decode_component(part).filter(|name| !name.contains('/')))
+        .collect::<Option<_>>()?;
+    let name = names.join("/");
+    encode_doc_path(&name).is_ok().then_some(name)
+}
+
+/// `.<doc>.meta.json` next to the document at `doc_path`.
+fn sidecar_path(doc_path: &Path) -> PathBuf {
+    let mut file = OsString::from(".");
+    file.push(doc_path.file_name().unwrap_or_default());
+    file.push(".meta.json");
+    doc_path.with_file_name(file)
+}
+
+/// `encoded` with each `%XX` turned back into its byte, if that is UTF-8.
+pub(crate) fn percent_decode(encoded: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
         } else {
-            out.push('_');
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(bytes).ok()
+}
+
+/// The name `encode_component` turned into `encoded`, if it is one.
+fn decode_component(encoded: &str) -> Option<String> {
+    let name = percent_decode(encoded)?;
+    // Only the canonical spelling counts, so each file maps to one name.
+    (encode_component(&name).ok()? == encoded).then_some(name)
+}
+
+fn decoded_file_name(path: &Path) -> Option<String> {
+    decode_component(path.file_name()?.to_str()?)
+}
+
+/// Writes `bytes` to a temporary file next to `path`, syncs it and renames
+/// it over `path`, removing the temporary file if any step fails. Returns
+/// how long the sync took; the directory, which makes the rename durable,
+/// is left to the caller.
+pub(crate) fn replace_file(fs: &dyn FileSystem, path: &Path, bytes: &[u8]) -> io::Result<Duration> {
+    let file_name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let mut tmp_name = OsString::from(".");
+    tmp_name.push(file_name);
+    tmp_name.push(format!(
+        ".{}-{}.tmp",
+        std::process::id(),
+        TMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let result = (|| {
+        fs.write(&tmp_path, bytes)?;
+        // The content must be on disk before the rename is, or a crash
+        // could leave an empty file behind.
+        let started = Instant::now();
+        fs.sync_file(&tmp_path)?;
+        let synced_in = started.elapsed();
+        fs.rename(&tmp_path, path)?;
+        Ok(synced_in)
+    })();
+    if result.is_err() {
+        let _ = fs.remove_file(&tmp_path);
+    }
+    result
+}
+
+/// The name a room or doc stored before names were percent-encoded had,
+/// when it started with a `.` and so needs renaming.
+fn legacy_dot_name(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let legacy = name.starts_with('.')
+        && !name.starts_with("..")
+        && name != HISTORY_DIR
+        && !name.ends_with(".meta.json")
+        && !name.ends_with(".tmp");
+    legacy.then(|| name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("carnelia-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        names
+    }
+
+    /// `RealFs` that logs each call by file name and can fail renames.
+    #[derive(Default)]
+    struct RecordingFs {
+        calls: Mutex<Vec<String>>,
+        fail_rename: bool,
+    }
+
+    impl RecordingFs {
+        fn log(&self, call: &str, path: &Path) {
+            let name = path.file_name().unwrap().to_string_lossy();
+            let pid = format!(".{}-", std::process::id());
+            let name = match name.find(&pid) {
+                Some(at) if name.ends_with(".tmp") => format!("{}.pid.tmp", &name[..at]),
+                _ => name.to_string(),
+            };
+            self.calls
+                .lock()
+                .unwrap()
+                .push(format!("{} {}", call, name));
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.calls.lock().unwrap())
         }
     }
-    if out.is_empty() {
-        "untitled".to_string()
-    } else {
-        out
+
+    impl FileSystem for RecordingFs {
+        fn create_dir_all(&self, dir: &Path) -> io::Result<()> {
+            RealFs.create_dir_all(dir)
+        }
+
+        fn write(&self, path: &Path, bytes: &[u8]) -> io::Result<()> {
+            self.log("write", path);
+            RealFs.write(path, bytes)
+        }
+
+        fn sync_file(&self, path: &Path) -> io::Result<()> {
+            self.log("sync", path);
+            RealFs.sync_file(path)
+        }
+
+        fn sync_dir(&self, dir: &Path) -> io::Result<()> {
+            self.log("sync_dir", dir);
+            RealFs.sync_dir(dir)
+        }
+
+        fn rename(&self, from: &Path, to: &Path) -> io::Result<()> {
+            self.log("rename", to);
+            if self.fail_rename {
+                return Err(io::Error::other("crashed before rename"));
+            }
+            RealFs.rename(from, to)
+        }
+
+        fn remove_file(&self, path: &Path) -> io::Result<()> {
+            self.log("remove", path);
+            RealFs.remove_file(path)
+        }
+
+        fn remove_dir_all(&self, dir: &Path) -> io::Result<()> {
+            self.log("remove_dir_all", dir);
+            RealFs.remove_dir_all(dir)
+        }
+
+        fn read_dir(&self, dir: &Path) -> io::Result<Vec<PathBuf>> {
+            RealFs.read_dir(dir)
+        }
+
+        fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+            RealFs.metadata(path)
+        }
+
+        fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+            RealFs.read(path)
+        }
+    }
+
+    #[test]
+    fn each_policy_syncs_when_it_says() {
+        let dir = temp_dir("storage-policy");
+        let recorded = |policy| {
+            let fs = Arc::new(RecordingFs::default());
+            let storage = Storage::with_fs(&dir, policy, fs.clone());
+            storage.save_text("demo", "notes", "text").unwrap();
+            (storage, fs)
+        };
+
+        let (storage, fs) = recorded(SyncPolicy::OnSave);
+        let calls = fs.take();
+        assert_eq!(
+            calls,
+            [
+                "write .notes.pid.tmp",
+                "sync .notes.pid.tmp",
+                "rename notes",
+                "sync_dir demo",
+            ]
+        );
+        assert_eq!(storage.stats().flushes(), 1);
+
+        let (storage, fs) = recorded(SyncPolicy::Never);
+        assert_eq!(
+            fs.take(),
+            [
+                "write .notes.pid.tmp",
+                "sync .notes.pid.tmp",
+                "rename notes"
+            ]
+        );
+        storage.sync_pending_blocking().unwrap();
+        assert!(fs.take().is_empty());
+        assert_eq!(storage.stats().flushes(), 0);
+
+        let (storage, fs) = recorded(SyncPolicy::Interval(Duration::from_secs(1)));
+        storage.save_text("demo", "notes", "more text").unwrap();
+        assert_eq!(fs.take().len(), 6);
+        // Two saves, one sync.
+        storage.sync_pending_blocking().unwrap();
+        assert_eq!(fs.take(), ["sync_dir demo"]);
+        storage.sync_pending_blocking().unwrap();
+        assert!(fs.take().is_empty());
+        assert_eq!(storage.stats().flushes(), 1);
+        assert!(
+            storage
+                .stats()
+                .render()
+                .contains("storage_flushes_total 1\n")
+        );
+        assert_eq!(storage.load_text("demo", "notes").unwrap(), "more text");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn concurrent_writes_of_one_path_use_their_own_temporary_files() {
+        let dir = temp_dir("storage-concurrent");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes");
+        std::thread::scope(|scope| {
+            for writer in 0..8 {
+                let (storage, path) = (&storage, &path);
+                scope.spawn(move || {
+                    for _ in 0..20 {
+                        storage
+                            .write_atomic(path, format!("writer {}", writer).as_bytes())
+                            .unwrap();
+                    }
+                });
+            }
+        });
+        assert!(fs::read_to_string(&path).unwrap().starts_with("writer "));
+        assert_eq!(entries(&dir), ["notes"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn revisions_are_listed_oldest_first_and_pruned() {
+        let dir = temp_dir("storage-history");
+        let storage = Storage::new(&dir, SyncPolicy::Never).keep_revisions(3);
+        assert!(
+            storage
+                .list_revisions_blocking("demo", "notes")
+                .unwrap()
+                .is_empty()
+        );
+        // A server restart starts counting versions again; earlier runs'
+        // revisions still sort first.
+        let history = dir.join("demo").join(HISTORY_DIR).join("notes");
+        fs::create_dir_all(&history).unwrap();
+        fs::write(history.join("900-1000.txt"), "yesterday").unwrap();
+        fs::write(history.join("notes.txt"), "not a revision").unwrap();
+        for version in [100, 200, 300] {
+            let text = format!("at {}", version);
+            storage
+                .save_revision_blocking("demo", "notes", &text, version)
+                .unwrap();
+        }
+
+        let revisions = storage.list_revisions_blocking("demo", "notes").unwrap();
+        let versions: Vec<u64> = revisions.iter().map(|revision| revision.version).collect();
+        assert_eq!(versions, [100, 200, 300]);
+        assert!(revisions[0].saved_at > UNIX_EPOCH + Duration::from_secs(1000));
+        assert_eq!(
+            storage
+                .load_revision_blocking("demo", "notes", 200)
+                .unwrap(),
+            "at 200"
+        );
+        let err = storage
+            .load_revision_blocking("demo", "notes", 900)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        // The current text lives apart from its history.
+        assert_eq!(storage.load_text("demo", "notes").unwrap(), "");
+        assert!(
+            storage
+                .list_revisions_blocking("demo", "other")
+                .unwrap()
+                .is_empty()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rooms_and_docs_are_listed_without_hidden_entries() {
+        let dir = temp_dir("storage-list");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        assert!(storage.list_rooms_blocking().unwrap().is_empty());
+        assert!(storage.list_docs_blocking("demo").unwrap().is_empty());
+
+        storage
+            .save_text("demo", "notes.txt", "twelve bytes")
+            .unwrap();
+        storage.save_text("demo", "a b/c", "").unwrap();
+        storage.save_text("team room", "todo", "x").unwrap();
+        storage
+            .save_revision_blocking("demo", "notes.txt", "old", 7)
+            .unwrap();
+        fs::create_dir_all(dir.join("demo").join("nested")).unwrap();
+        fs::write(dir.join("demo").join(".notes.txt.1.tmp"), "").unwrap();
+        fs::write(dir.join("stray.txt"), "").unwrap();
+        fs::create_dir_all(dir.join(".trash")).unwrap();
+        // Not written by this storage: `%zz` decodes to nothing.
+        fs::create_dir_all(dir.join("odd%zz")).unwrap();
+        let meta = RoomMeta {
+            default_doc: Some("notes.txt".into()),
+            aliases: BTreeMap::from([("notes".into(), "notes.txt".into())]),
+        };
+        storage.save_room_meta_blocking("demo", &meta).unwrap();
+
+        assert_eq!(
+            storage.list_rooms_blocking().unwrap(),
+            ["demo", "team room"]
+        );
+        let docs = storage.list_docs_blocking("demo").unwrap();
+        let names: Vec<&str> = docs.iter().map(|doc| doc.name.as_str()).collect();
+        assert_eq!(names, ["a b/c", "notes.txt"]);
+        assert_eq!(docs[1].size, 12);
+        assert!(docs[1].modified.elapsed().unwrap() < Duration::from_secs(60));
+        // Room names map to the same directory as when saving.
+        assert_eq!(
+            storage.list_docs_blocking("team room").unwrap()[0].name,
+            "todo"
+        );
+        assert!(storage.list_docs_blocking("stray.txt").is_err());
+        assert_eq!(storage.room_meta_blocking("demo").unwrap(), meta);
+        assert_eq!(
+            storage.room_meta_blocking("team room").unwrap(),
+            RoomMeta::default()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn deleted_documents_go_to_the_trash_and_come_back() {
+        let dir = temp_dir("storage-delete");
+        let storage = Storage::new(&dir, SyncPolicy::Interval(Duration::from_secs(60)));
+        let mut meta = DocMeta::default();
+        meta.record_edit("ada", 4, 0);
+        storage
+            .save_blocking("demo", "notes", "text", &mut meta)
+            .unwrap();
+        storage
+            .save_revision_blocking("demo", "notes", "text", 1)
+            .unwrap();
+
+        storage.delete_blocking("demo", "notes").unwrap();
+        assert_eq!(entries(&dir.join("demo")), [HISTORY_DIR, TRASH_DIR]);
+        assert!(storage.list_docs_blocking("demo").unwrap().is_empty());
+        assert!(
+            storage
+                .list_revisions_blocking("demo", "notes")
+                .unwrap()
+                .is_empty()
+        );
+        // The moved files aren't synced (and failing) later.
+        storage.sync_pending_blocking().unwrap();
+        let trashed = storage.list_trash_blocking("demo").unwrap();
+        assert_eq!((trashed[0].name.as_str(), trashed[0].size), ("notes", 4));
+        let err = storage.delete_blocking("demo", "notes").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        // A new document took the name: restore beside it, or over it.
+        storage
+            .save_blocking("demo", "notes", "new", &mut DocMeta::default())
+            .unwrap();
+        let err = storage
+            .restore_blocking("demo", "notes", "notes", false)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        storage
+            .restore_blocking("demo", "notes", "old notes", false)
+            .unwrap();
+        let restored = storage.load_blocking("demo", "old notes").unwrap();
+        assert_eq!(restored.text, "text");
+        assert_eq!(restored.meta.contributions, meta.contributions);
+        assert_eq!(
+            storage
+                .list_revisions_blocking("demo", "old notes")
+                .unwrap()
+                .len(),
+            1
+        );
+        assert!(storage.list_trash_blocking("demo").unwrap().is_empty());
+        assert_eq!(entries(&dir.join("demo").join(TRASH_DIR)), [] as [&str; 0]);
+
+        storage.delete_blocking("demo", "old notes").unwrap();
+        storage
+            .restore_blocking("demo", "old notes", "notes", true)
+            .unwrap();
+        assert_eq!(storage.load_blocking("demo", "notes").unwrap().text, "text");
+        let trashed = storage.list_trash_blocking("demo").unwrap();
+        let names: Vec<&str> = trashed.iter().map(|doc| doc.name.as_str()).collect();
+        assert_eq!(names, ["notes"]);
+        storage
+            .restore_blocking("demo", "notes", "newer notes", false)
+            .unwrap();
+        let newer = storage.load_blocking("demo", "newer notes").unwrap();
+        assert_eq!(newer.text, "new");
+        let err = storage
+            .restore_blocking("demo", "notes", "notes", true)
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn the_trash_is_purged_after_the_retention() {
+        let dir = temp_dir("storage-purge");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        for (room, doc) in [("demo", "a"), ("demo", "b/c"), ("team", "d")] {
+            storage.save_text(room, doc, "text").unwrap();
+            storage.delete_blocking(room, doc).unwrap();
+        }
+        assert_eq!(
+            storage
+                .purge_trash_blocking(Duration::from_secs(3600))
+                .unwrap(),
+            0
+        );
+        assert_eq!(storage.list_trash_blocking("demo").unwrap().len(), 2);
+
+        std::thread::sleep(Duration::from_millis(5));
+        assert_eq!(storage.purge_trash_blocking(Duration::ZERO).unwrap(), 3);
+        assert!(storage.list_trash_blocking("demo").unwrap().is_empty());
+        assert!(storage.list_trash_blocking("team").unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn migrating_to_encryption_covers_the_trash_and_quarantine() {
+        let dir = temp_dir("storage-encrypt-trash");
+        let plain = Storage::new(&dir, SyncPolicy::Never);
+        plain
+            .save_blocking("demo", "notes", "secret plans", &mut DocMeta::default())
+            .unwrap();
+        plain
+            .save_revision_blocking("demo", "notes", "secret draft", 4)
+            .unwrap();
+        plain.delete_blocking("demo", "notes").unwrap();
+        let quarantine = dir.join("demo").join(QUARANTINE_DIR);
+        fs::create_dir_all(&quarantine).unwrap();
+        fs::write(quarantine.join("old.1"), "damaged secret").unwrap();
+
+        fs::write(dir.join(".key"), "0123456789abcdef").unwrap();
+        let storage = Storage::new(&dir, SyncPolicy::Never)
+            .encrypt_with(Cipher::from_key_file(&dir.join(".key")).unwrap());
+        let err = storage.check_encryption_blocking(false).unwrap_err();
+        assert!(err.to_string().starts_with("3 stored files"), "{}", err);
+        assert_eq!(storage.check_encryption_blocking(true).unwrap().len(), 3);
+        assert!(cipher::is_sealed(
+            &fs::read(quarantine.join("old.1")).unwrap()
+        ));
+        assert!(storage.check_encryption_blocking(false).unwrap().is_empty());
+
+        storage
+            .restore_blocking("demo", "notes", "notes", false)
+            .unwrap();
+        assert_eq!(
+            storage.load_blocking("demo", "notes").unwrap().text,
+            "secret plans"
+        );
+        assert_eq!(
+            storage.load_revision_blocking("demo", "notes", 4).unwrap(),
+            "secret draft"
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn encrypted_documents_round_trip_and_refuse_the_wrong_key() {
+        let dir = temp_dir("storage-encrypt");
+        let key = |name: &str, secret: &str| {
+            let path = dir.join(format!(".{}.key", name));
+            fs::write(&path, secret).unwrap();
+            Cipher::from_key_file(&path).unwrap()
+        };
+        let plain = Storage::new(&dir, SyncPolicy::Never);
+        plain
+            .save_blocking("demo", "notes", "secret plans", &mut DocMeta::default())
+            .unwrap();
+        plain
+            .save_revision_blocking("demo", "notes", "secret draft", 4)
+            .unwrap();
+        let on_disk = || fs::read(dir.join("demo").join("notes")).unwrap();
+        assert!(Cipher::from_key_file(&dir.join("missing.key")).is_err());
+        fs::write(dir.join(".short.key"), "short").unwrap();
+        assert!(Cipher::from_key_file(&dir.join(".short.key")).is_err());
+
+        // Plaintext is refused until it is migrated.
+        let storage =
+            Storage::new(&dir, SyncPolicy::Never).encrypt_with(key("right", "0123456789abcdef"));
+        let err = storage.check_encryption_blocking(false).unwrap_err();
+        assert!(err.to_string().starts_with("2 stored files"), "{}", err);
+        assert!(storage.load_blocking("demo", "notes").is_err());
+        assert_eq!(storage.check_encryption_blocking(true).unwrap().len(), 2);
+        assert!(cipher::is_sealed(&on_disk()));
+        assert!(storage.check_encryption_blocking(false).unwrap().is_empty());
+        assert_eq!(
+            storage.load_blocking("demo", "notes").unwrap().text,
+            "secret plans"
+        );
+        assert_eq!(
+            storage.load_revision_blocking("demo", "notes", 4).unwrap(),
+            "secret draft"
+        );
+
+        storage
+            .save_blocking("demo", "notes", "new plans", &mut DocMeta::default())
+            .unwrap();
+        assert!(!on_disk().windows(5).any(|window| window == b"plans"));
+        let stored = storage.load_blocking("demo", "notes").unwrap();
+        assert_eq!((stored.text.as_str(), stored.warning), ("new plans", None));
+
+        // A wrong key names each file instead of returning garbage.
+        let wrong =
+            Storage::new(&dir, SyncPolicy::Never).encrypt_with(key("wrong", "fedcba9876543210"));
+        let err = wrong.check_encryption_blocking(true).unwrap_err();
+        assert!(
+            err.to_string().contains(&format!(
+                "{}: can't be decrypted",
+                dir.join("demo").join("notes").display()
+            )),
+            "{}",
+            err
+        );
+        assert_eq!(
+            wrong.load_blocking("demo", "notes").unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let err = plain.load_blocking("demo", "notes").unwrap_err();
+        assert!(
+            err.to_string().ends_with("encrypted, but no key was given"),
+            "{}",
+            err
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn long_documents_are_gzipped_and_either_format_loads() {
+        let dir = temp_dir("storage-gzip");
+        let storage = Storage::new(&dir, SyncPolicy::Never).compress_above(Some(100));
+        let long = "a meeting log line\n".repeat(50);
+        let on_disk = |doc: &str| fs::read(dir.join("demo").join(doc)).unwrap();
+
+        storage.save_text("demo", "log", &long).unwrap();
+        storage.save_text("demo", "short", "a few words").unwrap();
+        storage
+            .save_revision_blocking("demo", "log", &long, 3)
+            .unwrap();
+        assert!(on_disk("log").starts_with(GZIP_MAGIC) && on_disk("log").len() < long.len());
+        assert_eq!(on_disk("short"), b"a few words");
+        let revision = &storage.list_revisions_blocking("demo", "log").unwrap()[0];
+        assert!(fs::read(&revision.path).unwrap().starts_with(GZIP_MAGIC));
+
+        // A long file written before compression existed, next to new ones.
+        fs::write(dir.join("demo").join("legacy"), &long).unwrap();
+        for doc in ["log", "short", "legacy"] {
+            let expected = if doc == "short" { "a few words" } else { &long };
+            assert_eq!(storage.load_text("demo", doc).unwrap(), expected);
+        }
+        assert_eq!(
+            storage.load_revision_blocking("demo", "log", 3).unwrap(),
+            long
+        );
+
+        // Compression off still reads gzipped files.
+        let uncompressed = Storage::new(&dir, SyncPolicy::Never).compress_above(None);
+        assert_eq!(uncompressed.load_text("demo", "log").unwrap(), long);
+        uncompressed.save_text("demo", "log", &long).unwrap();
+        assert_eq!(on_disk("log"), long.as_bytes());
+
+        // Compressed, then encrypted.
+        let key = dir.join(".key");
+        fs::write(&key, "0123456789abcdef").unwrap();
+        let sealed = Storage::new(&dir, SyncPolicy::Never)
+            .compress_above(Some(100))
+            .encrypt_with(Cipher::from_key_file(&key).unwrap());
+        sealed.save_text("demo", "log", &long).unwrap();
+        assert!(on_disk("log").len() < long.len());
+        assert_eq!(sealed.load_text("demo", "log").unwrap(), long);
+
+        fs::write(dir.join("demo").join("broken"), [0x1f, 0x8b, 0, 1]).unwrap();
+        let err = storage.load_text("demo", "broken").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn a_flipped_byte_is_quarantined_and_restored_from_a_revision() {
+        let dir = temp_dir("storage-corrupt");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        let mut meta = DocMeta {
+            version: 7,
+            ..DocMeta::default()
+        };
+        storage
+            .save_revision_blocking("demo", "notes", "older text", 5)
+            .unwrap();
+        storage
+            .save_blocking("demo", "notes", "latest text", &mut meta)
+            .unwrap();
+        let path = dir.join("demo").join("notes");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[2] ^= 0x20;
+        fs::write(&path, &bytes).unwrap();
+
+        let err = storage.load_blocking("demo", "notes").unwrap_err();
+        assert!(is_corrupt(&err), "{}", err);
+        assert_eq!(storage.stats().corruptions(), 1);
+        assert!(
+            storage
+                .stats()
+                .render()
+                .contains("storage_corruptions_total 1\n")
+        );
+
+        let recovered = storage.recover_blocking("demo", "notes").unwrap();
+        assert_eq!(recovered.text, "older text");
+        assert_eq!(recovered.meta.version, 7);
+        assert!(
+            recovered
+                .warning
+                .unwrap()
+                .starts_with("recovered from revision 5")
+        );
+        let quarantined = entries(&dir.join("demo").join(QUARANTINE_DIR));
+        assert!(quarantined.len() == 1 && quarantined[0].starts_with("notes."));
+        assert_eq!(
+            storage.load_blocking("demo", "notes").unwrap().text,
+            "older text"
+        );
+
+        // Without a revision there is nothing to serve.
+        storage
+            .save_blocking("demo", "lost", "text", &mut DocMeta::default())
+            .unwrap();
+        fs::write(dir.join("demo").join("lost"), "test").unwrap();
+        assert!(is_corrupt(
+            &storage.load_blocking("demo", "lost").unwrap_err()
+        ));
+        let err = storage.recover_blocking("demo", "lost").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(!dir.join("demo").join("lost").exists());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn metadata_survives_a_restart_and_corruption_falls_back() {
+        let dir = temp_dir("storage-meta");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        let fresh = storage.load_blocking("demo", "notes").unwrap();
+        assert_eq!((fresh.meta, fresh.warning), (DocMeta::default(), None));
+
+        let mut meta = DocMeta {
+            version: 41,
+            last_editor: Some("alice".into()),
+            ..DocMeta::default()
+        };
+        storage
+            .save_blocking("demo", "notes", "hello", &mut meta)
+            .unwrap();
+        meta.version = 42;
+        storage
+            .save_blocking("demo", "notes", "hello!", &mut meta)
+            .unwrap();
+        assert_eq!(checksum(b"123456789"), "crc32c:e3069283");
+        assert!(checksum_matches("fnv1a64:cbf29ce484222325", b""));
+        assert!(!checksum_matches("fnv1a64:cbf29ce484222325", b"x"));
+        assert_eq!(meta.checksum.as_deref(), Some(checksum(b"hello!").as_str()));
+
+        // A new server picks up where the last one stopped.
+        let restarted = Storage::new(&dir, SyncPolicy::Never).load_blocking("demo", "notes");
+        let restarted = restarted.unwrap();
+        assert_eq!(restarted.text, "hello!");
+        assert_eq!(restarted.meta, meta);
+        assert_eq!(restarted.warning, None);
+        // The sidecar isn't listed as a document.
+        assert_eq!(storage.list_docs_blocking("demo").unwrap().len(), 1);
+
+        fs::write(dir.join("demo").join(".notes.meta.json"), "{\"version\": ").unwrap();
+        let corrupt = storage.load_blocking("demo", "notes").unwrap();
+        assert_eq!((corrupt.text.as_str(), corrupt.meta.version), ("hello!", 0));
+        assert!(corrupt.warning.unwrap().starts_with("corrupt metadata"));
+        fs::remove_file(dir.join("demo").join(".notes.meta.json")).unwrap();
+        let missing = storage.load_blocking("demo", "notes").unwrap();
+        assert!(missing.warning.unwrap().starts_with("no metadata"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn names_encode_reversibly_and_unsafe_ones_are_refused() {
+        for name in ["", ".", ".."] {
+            let err = encode_component(name).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+        assert_eq!(encode_component("notes.txt").unwrap(), "notes.txt");
+        // Names the old scheme merged stay apart.
+        assert_eq!(encode_component("a/b").unwrap(), "a%2Fb");
+        assert_eq!(encode_component("a_b").unwrap(), "a_b");
+        assert_eq!(encode_component("100%").unwrap(), "100%25");
+        // A leading dot would hide the file among the sidecars.
+        assert_eq!(encode_component(".history").unwrap(), "%2Ehistory");
+        assert_eq!(encode_component("..x").unwrap(), "%2E.x");
+        assert_eq!(encode_component("naïve").unwrap(), "na%C3%AFve");
+        for name in ["a/b", "a_b", "naïve 文書", "..x", "%2F", "tab\there"] {
+            let encoded = encode_component(name).unwrap();
+            assert_eq!(decode_component(&encoded).as_deref(), Some(name));
+        }
+        assert_eq!(decode_component("%61"), None);
+        assert_eq!(decode_component("a%2"), None);
+
+        assert!(encode_component(&"a".repeat(MAX_NAME_LEN)).is_ok());
+        assert!(encode_component(&"a".repeat(MAX_NAME_LEN + 1)).is_err());
+        // Each of these takes nine bytes once encoded.
+        assert!(encode_component(&"文".repeat(22)).is_ok());
+        assert!(encode_component(&"文".repeat(23)).is_err());
+    }
+
+    #[test]
+    fn names_cannot_leave_the_data_dir() {
+        let dir = temp_dir("storage-traversal");
+        let storage = Storage::new(dir.join("data"), SyncPolicy::Never);
+        assert!(storage.save_text("..", "notes", "x").is_err());
+        assert!(storage.save_text("demo", "..", "x").is_err());
+        assert!(storage.validate("demo", ".").is_err());
+        // Each part of a nested name is checked on its own.
+        for doc in ["../../escaped", "a/../b", "a/./b", "a//b", "/a", "a/"] {
+            assert!(storage.save_text("demo", doc, "x").is_err(), "{}", doc);
+            assert!(storage.validate("demo", doc).is_err(), "{}", doc);
+        }
+        storage.save_text("demo", "a_b", "underscore").unwrap();
+        storage.save_text("demo", "a/b", "slash").unwrap();
+        storage.save_text("demo", "a/.b", "dot").unwrap();
+        assert_eq!(storage.load_text("demo", "a_b").unwrap(), "underscore");
+        assert_eq!(storage.load_text("demo", "a/b").unwrap(), "slash");
+        assert_eq!(entries(&dir), ["data"]);
+        assert_eq!(entries(&dir.join("data").join("demo")), ["a", "a_b"]);
+        assert_eq!(
+            entries(&dir.join("data").join("demo").join("a")),
+            ["%2Eb", "b"]
+        );
+
+        #[cfg(unix)]
+        {
+            fs::create_dir_all(dir.join("outside")).unwrap();
+            std::os::unix::fs::symlink(dir.join("outside"), dir.join("data").join("linked"))
+                .unwrap();
+            let err = storage.save_text("linked", "notes", "x").unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert!(entries(&dir.join("outside")).is_empty());
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn nested_doc_names_are_stored_and_listed_as_subdirectories() {
+        let dir = temp_dir("storage-nested");
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        let mut meta = DocMeta {
+            version: 4,
+            ..DocMeta::default()
+        };
+        storage
+            .save_blocking("demo", "notes/2024/standup.md", "agenda", &mut meta)
+            .unwrap();
+        storage
+            .save_blocking("demo", "notes/todo", "x", &mut DocMeta::default())
+            .unwrap();
+        storage
+            .save_revision_blocking("demo", "notes/todo", "old", 1)
+            .unwrap();
+        let room = dir.join("demo");
+        assert_eq!(entries(&room), [".history", "notes"]);
+        assert_eq!(
+            entries(&room.join("notes")),
+            [".todo.meta.json", "2024", "todo"]
+        );
+        assert_eq!(
+            fs::read_to_string(room.join("notes").join("2024").join("standup.md")).unwrap(),
+            "agenda"
+        );
+        let stored = storage
+            .load_blocking("demo", "notes/2024/standup.md")
+            .unwrap();
+        assert_eq!((stored.text.as_str(), stored.meta.version), ("agenda", 4));
+        assert_eq!(
+            storage
+                .load_revision_blocking("demo", "notes/todo", 1)
+                .unwrap(),
+            "old"
+        );
+
+        let names: Vec<String> = storage
+            .list_docs_blocking("demo")
+            .unwrap()
+            .into_iter()
+            .map(|doc| doc.name)
+            .collect();
+        assert_eq!(names, ["notes/2024/standup.md", "notes/todo"]);
+
+        // A name can't be a document and a folder at once.
+        assert!(storage.save_text("demo", "notes/todo/more", "x").is_err());
+        let deep = ["d"; MAX_DOC_DEPTH].join("/");
+        assert!(storage.validate("demo", &deep).is_ok());
+        assert!(storage.validate("demo", &format!("{}/d", deep)).is_err());
+        let long = vec!["a".repeat(MAX_NAME_LEN); 6].join("/");
+        assert!(storage.validate("demo", &long).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn flat_nested_names_of_older_versions_move_into_subdirectories() {
+        let dir = temp_dir("storage-migrate-nested");
+        let room = dir.join("demo");
+        fs::create_dir_all(room.join(HISTORY_DIR).join("notes%2Ftodo")).unwrap();
+        fs::write(dir.join(LAYOUT_FILE), FLAT_LAYOUT_VERSION).unwrap();
+        fs::write(room.join("notes%2Ftodo"), "x").unwrap();
+        fs::write(room.join(".notes%2Ftodo.meta.json"), "{\"version\": 2}").unwrap();
+        fs::write(
+            room.join(HISTORY_DIR)
+                .join("notes%2Ftodo")
+                .join("1-1000.txt"),
+            "old",
+        )
+        .unwrap();
+        // Can't be nested, so it stays.
+        fs::write(room.join("a%2F%2Fb"), "").unwrap();
+
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        assert_eq!(storage.migrate_names_blocking().unwrap().len(), 3);
+        let todo = storage.load_blocking("demo", "notes/todo").unwrap();
+        assert_eq!((todo.text.as_str(), todo.meta.version), ("x", 2));
+        assert_eq!(
+            storage
+                .load_revision_blocking("demo", "notes/todo", 1)
+                .unwrap(),
+            "old"
+        );
+        assert_eq!(entries(&room), [".history", "a%2F%2Fb", "notes"]);
+        assert_eq!(storage.list_docs_blocking("demo").unwrap().len(), 1);
+        assert!(storage.migrate_names_blocking().unwrap().is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn dot_names_of_older_versions_are_migrated_once() {
+        let dir = temp_dir("storage-migrate");
+        let room = dir.join(".hidden");
+        fs::create_dir_all(room.join(HISTORY_DIR).join(".env")).unwrap();
+        fs::write(room.join(".env"), "SECRET=1").unwrap();
+        fs::write(room.join("..env.meta.json"), "{\"version\": 9}").unwrap();
+        fs::write(
+            room.join(HISTORY_DIR).join(".env").join("5-1000.txt"),
+            "old",
+        )
+        .unwrap();
+        fs::write(room.join("plain"), "untouched").unwrap();
+        fs::write(room.join(".plain.meta.json"), "{}").unwrap();
+
+        let storage = Storage::new(&dir, SyncPolicy::Never);
+        let renamed = storage.migrate_names_blocking().unwrap();
+        assert_eq!(renamed.len(), 4);
+        let env = storage.load_blocking(".hidden", ".env").unwrap();
+        assert_eq!((env.text.as_str(), env.meta.version), ("SECRET=1", 9));
+        assert_eq!(env.warning, None);
+        assert_eq!(
+            storage
+                .load_revision_blocking(".hidden", ".env", 5)
+                .unwrap(),
+            "old"
+        );
+        assert_eq!(storage.load_text(".hidden", "plain").unwrap(), "untouched");
+        assert_eq!(storage.list_rooms_blocking().unwrap(), [".hidden"]);
+
+        // The data dir is marked, so later starts leave it alone.
+        let late = dir.join("%2Ehidden").join(".late");
+        fs::write(&late, "").unwrap();
+        assert!(storage.migrate_names_blocking().unwrap().is_empty());
+        assert!(late.exists());
+        let fresh = temp_dir("storage-migrate-fresh");
+        let storage = Storage::new(&fresh, SyncPolicy::Never);
+        assert!(storage.migrate_names_blocking().unwrap().is_empty());
+        assert!(fresh.join(LAYOUT_FILE).exists());
+        fs::remove_dir_all(&dir).unwrap();
+        fs::remove_dir_all(&fresh).unwrap();
+    }
+
+    #[test]
+    fn sync_policies_parse_from_the_flag() {
+        let parse = |input: &str| input.parse::<SyncPolicy>();
+        assert_eq!(parse("never"), Ok(SyncPolicy::Never));
+        assert_eq!(parse("on-save"), Ok(SyncPolicy::OnSave));
+        let every = |ms| Ok(SyncPolicy::Interval(Duration::from_millis(ms)));
+        assert_eq!(parse("interval:250ms"), every(250));
+        assert_eq!(parse("interval:5s"), every(5000));
+        assert!(parse("interval:0s").is_err());
+        assert!(parse("interval:5m").is_err());
+        assert!(parse("always").is_err());
+        assert_eq!(
+            SyncPolicy::Interval(Duration::from_secs(5)).to_string(),
+            "interval:5000ms"
+        );
+    }
+
+    #[test]
+    fn a_failed_rename_keeps_the_old_document() {
+        let dir = temp_dir("storage-rename");
+        let storage = Storage::new(&dir, SyncPolicy::OnSave);
+        storage.save_text("demo", "notes", "first").unwrap();
+        storage.save_text("demo", "notes", "second").unwrap();
+        assert_eq!(storage.load_text("demo", "notes").unwrap(), "second");
+
+        let fs = Arc::new(RecordingFs {
+            fail_rename: true,
+            ..RecordingFs::default()
+        });
+        let crashing = Storage::with_fs(&dir, SyncPolicy::OnSave, fs.clone());
+        let err = crashing.save_text("demo", "notes", "third").unwrap_err();
+        assert_eq!(err.to_string(), "crashed before rename");
+        assert_eq!(storage.load_text("demo", "notes").unwrap(), "second");
+        // The temporary file is cleaned up.
+        assert_eq!(fs.take().last().unwrap(), "remove .notes.pid.tmp");
+        assert_eq!(entries(&dir.join("demo")), ["notes"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn replaced_files_leave_no_temporary_files() {
+        let dir = temp_dir("replace-file");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notes.txt");
+        replace_file(&RealFs, &path, b"first").unwrap();
+        replace_file(&RealFs, &path, b"second").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "second");
+        assert_eq!(entries(&dir), ["notes.txt"]);
+
+        let missing = dir.join("missing").join("x.txt");
+        assert!(replace_file(&RealFs, &missing, b"y").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn saving_into_a_read_only_directory_fails_cleanly() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = temp_dir("storage-readonly");
+        let storage = Storage::new(&dir, SyncPolicy::OnSave);
+        storage.save_text("demo", "notes", "kept").unwrap();
+        let room = dir.join("demo");
+        fs::set_permissions(&room, fs::Permissions::from_mode(0o555)).unwrap();
+        let result = storage.save_text("demo", "notes", "lost");
+        fs::set_permissions(&room, fs::Permissions::from_mode(0o755)).unwrap();
+        // Root ignores directory permissions.
+        if unsafe { libc::geteuid() } != 0 {
+            let err = result.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+            assert_eq!(storage.load_text("demo", "notes").unwrap(), "kept");
+            assert_eq!(entries(&room), ["notes"]);
+        }
+
+        // A room path taken by a plain file can't be created either.
+        fs::write(dir.join("taken"), "").unwrap();
+        assert!(storage.save_text("taken", "notes", "text").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn whitespace_policies_tidy_all_but_busy_lines() {
+        let both = WhitespacePolicy {
+            final_newline: true,
+            trim_trailing: true,
+            strict: false,
+        };
+        assert_eq!(both.normalize("a  \nb\t\n\n\n", &[]), "a\nb\n");
+        assert_eq!(both.normalize("a \r\nb", &[]), "a\r\nb\n");
+        assert_eq!(both.normalize("a \r\nb\r\n\r\n", &[]), "a\r\nb\r\n");
+        assert_eq!(both.normalize("", &[]), "");
+        assert_eq!(both.normalize("  \n\n", &[]), "\n");
+        // Someone typing at the end of "a " keeps their space, and one in
+        // the blank lines at the end keeps those.
+        assert_eq!(both.normalize("a \nb \n", &[2]), "a \nb\n");
+        assert_eq!(both.normalize("a \n\n\n", &[4]), "a\n\n\n");
+
+        let trim_only = WhitespacePolicy {
+            trim_trailing: true,
+            ..WhitespacePolicy::default()
+        };
+        assert_eq!(trim_only.normalize("a \nb ", &[]), "a\nb");
+        assert_eq!(trim_only.to_string(), "trim trailing whitespace");
+        assert_eq!(
+            WhitespacePolicy::default().normalize("a \n\n", &[]),
+            "a \n\n"
+        );
+        assert!(WhitespacePolicy::default().is_off());
+        let strict = WhitespacePolicy {
+            strict: true,
+            ..both
+        };
+        assert_eq!(
+            strict.to_string(),
+            "final newline, trim trailing whitespace (strict)"
+        );
     }
 }
@@ -0,0 +1,104 @@
+//! Encryption at rest (`--encryption-key-file`). Sealed files are
+//! `MAGIC`, a random 24-byte nonce and the XChaCha20-Poly1305 ciphertext of
+//! the text.
+
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use hkdf::Hkdf;
+use sha2::Sha256;
+use std::io;
+use std::path::Path;
+
+/// Starts every sealed file; also authenticated, so it can't be swapped.
+const MAGIC: &[u8] = b"CCENC\x01";
+const NONCE_LEN: usize = 24;
+/// Shorter key files are more likely a mistake than a key.
+const MIN_KEY_LEN: usize = 16;
+const KEY_INFO: &[u8] = b"carnelia-collab storage v1";
+
+pub struct Cipher {
+    aead: XChaCha20Poly1305,
+}
+
+impl Cipher {
+    /// Derives the key from the bytes of `path` with HKDF-SHA256.
+    pub fn from_key_file(path: &Path) -> io::Result<Self> {
+        let secret = std::fs::read(path)
+            .map_err(|err| io::Error::new(err.kind(), format!("{}: {}", path.display(), err)))?;
+        if secret.len() < MIN_KEY_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "{}: key file has {} bytes, at least {} are needed",
+                    path.display(),
+                    secret.len(),
+                    MIN_KEY_LEN
+                ),
+            ));
+        }
+        Ok(Self::from_secret(&secret))
+    }
+
+    fn from_secret(secret: &[u8]) -> Self {
+        let mut key = [0; 32];
+        Hkdf::<Sha256>::new(None, secret)
+            .expand(KEY_INFO, &mut key)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            aead: XChaCha20Poly1305::new(&key.into()),
+        }
+    }
+
+    pub fn seal(&self, plain: &[u8]) -> Vec<u8> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: plain,
+            aad: MAGIC,
+        };
+        let sealed = self
+            .aead
+            .encrypt(&nonce, payload)
+            .expect("encrypting into a Vec can't fail");
+        [MAGIC, nonce.as_slice(), &sealed].concat()
+    }
+
+    /// The plaintext of a sealed file, or `None` if it was sealed with
+    /// another key or altered.
+    pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+        let rest = sealed.strip_prefix(MAGIC)?;
+        if rest.len() < NONCE_LEN {
+            return None;
+        }
+        let (nonce, msg) = rest.split_at(NONCE_LEN);
+        let payload = Payload { msg, aad: MAGIC };
+        self.aead.decrypt(XNonce::from_slice(nonce), payload).ok()
+    }
+}
+
+pub fn is_sealed(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sealed_text_opens_only_with_the_same_key() {
+        let cipher = Cipher::from_secret(b"correct horse battery staple");
+        let sealed = cipher.seal(b"meeting notes");
+        assert!(is_sealed(&sealed));
+        assert!(!sealed.windows(7).any(|window| window == b"meeting"));
+        assert_eq!(cipher.open(&sealed).as_deref(), Some(&b"meeting notes"[..]));
+        // Each write gets its own nonce.
+        assert_ne!(cipher.seal(b"meeting notes"), sealed);
+
+        let other = Cipher::from_secret(b"a different key file");
+        assert_eq!(other.open(&sealed), None);
+        let mut tampered = sealed.clone();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert_eq!(cipher.open(&tampered), None);
+        assert_eq!(cipher.open(&sealed[..MAGIC.len() + 3]), None);
+        assert!(!is_sealed(b"plain text"));
+    }
+}
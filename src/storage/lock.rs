@@ -0,0 +1,153 @@
+//! `collab.lock` in the data dir, held while a server runs on it: two
+//! servers saving the same documents would interleave their writes. The
+//! file is locked with the OS (`flock` on unix, `LockFileEx` on Windows),
+//! which lets go of it when the process dies, and holds the pid of the
+//! server that has it. Where the OS can't lock files, a lock whose pid is
+//! no longer running is taken over instead.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{self, Read, Seek, Write};
+use std::path::{Path, PathBuf};
+
+pub const LOCK_FILE: &str = "collab.lock";
+
+/// Another server holds the lock of `dir`. Carried inside an `io::Error`
+/// of kind `ResourceBusy`.
+#[derive(Debug)]
+pub struct DataDirInUse {
+    pub dir: PathBuf,
+    /// What the lock file says, if it names a pid.
+    pub pid: Option<u32>,
+}
+
+impl fmt::Display for DataDirInUse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} is in use by another server", self.dir.display())?;
+        match self.pid {
+            Some(pid) => write!(f, " (pid {})", pid),
+            None => Ok(()),
+        }
+    }
+}
+
+impl std::error::Error for DataDirInUse {}
+
+/// The lock of a data dir, released when dropped.
+#[derive(Debug)]
+pub struct DataDirLock {
+    file: File,
+    path: PathBuf,
+    /// A lock that was left behind by a process that's gone.
+    pub stale_pid: Option<u32>,
+}
+
+impl DataDirLock {
+    /// Locks `dir`, creating it if needed, and writes our pid into the lock
+    /// file.
+    pub fn acquire(dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(LOCK_FILE);
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let held = read_pid(&mut file);
+        let in_use = |pid| {
+            let in_use = DataDirInUse {
+                dir: dir.to_path_buf(),
+                pid,
+            };
+            io::Error::new(io::ErrorKind::ResourceBusy, in_use)
+        };
+        match file.try_lock() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Err(in_use(held)),
+            Err(TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => {
+                if let Some(pid) = held.filter(|pid| *pid != std::process::id() && is_running(*pid))
+                {
+                    return Err(in_use(Some(pid)));
+                }
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+        file.set_len(0)?;
+        file.rewind()?;
+        writeln!(file, "{}", std::process::id())?;
+        file.sync_all()?;
+        Ok(Self {
+            file,
+            path,
+            stale_pid: held.filter(|pid| *pid != std::process::id()),
+        })
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for DataDirLock {
+    fn drop(&mut self) {
+        // Emptied rather than removed: another server may have the file
+        // open already, waiting to lock it.
+        let _ = self.file.set_len(0);
+        let _ = self.file.unlock();
+    }
+}
+
+fn read_pid(file: &mut File) -> Option<u32> {
+    let mut text = String::new();
+    file.read_to_string(&mut text).ok()?;
+    text.trim().parse().ok()
+}
+
+/// Whether a process with `pid` exists.
+#[cfg(unix)]
+pub(crate) fn is_running(pid: u32) -> bool {
+    // Signalling pid 0 would check our own process group.
+    let Ok(pid @ 1..) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: signal 0 only checks that the process exists.
+    (unsafe { libc::kill(pid, 0) } == 0)
+        || io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+#[cfg(not(unix))]
+pub(crate) fn is_running(pid: u32) -> bool {
+    pid == std::process::id()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn a_locked_dir_is_refused_until_released() {
+        let dir = std::env::temp_dir().join(format!("carnelia-lock-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // A lock left by a process that's gone.
+        fs::write(dir.join(LOCK_FILE), format!("{}\n", u32::MAX)).unwrap();
+
+        let lock = DataDirLock::acquire(&dir).unwrap();
+        assert_eq!(lock.stale_pid, Some(u32::MAX));
+        let ours = format!("{}\n", std::process::id());
+        assert_eq!(fs::read_to_string(lock.path()).unwrap(), ours);
+
+        let err = DataDirLock::acquire(&dir).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        let in_use = err.get_ref().unwrap().downcast_ref::<DataDirInUse>();
+        assert_eq!(in_use.unwrap().pid, Some(std::process::id()));
+
+        drop(lock);
+        assert_eq!(fs::read_to_string(dir.join(LOCK_FILE)).unwrap(), "");
+        let lock = DataDirLock::acquire(&dir).unwrap();
+        assert_eq!(lock.stale_pid, None);
+        drop(lock);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
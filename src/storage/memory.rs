@@ -0,0 +1,145 @@
+//! `StorageBackend` in process memory, for tests and throwaway demo
+//! servers.
+
+use super::{DocEntry, DocMeta, RoomMeta, StorageBackend, StoredDoc};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+struct MemoryDoc {
+    text: String,
+    meta: DocMeta,
+    modified: SystemTime,
+}
+
+/// Documents by room and name, gone when the process exits. Revisions are
+/// not kept.
+#[derive(Default)]
+pub struct MemoryStorage {
+    docs: Mutex<BTreeMap<(String, String), MemoryDoc>>,
+    rooms: Mutex<BTreeMap<String, RoomMeta>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn docs(&self) -> std::sync::MutexGuard<'_, BTreeMap<(String, String), MemoryDoc>> {
+        self.docs.lock().unwrap_or_else(|err| err.into_inner())
+    }
+
+    fn rooms(&self) -> std::sync::MutexGuard<'_, BTreeMap<String, RoomMeta>> {
+        self.rooms.lock().unwrap_or_else(|err| err.into_inner())
+    }
+}
+
+#[async_trait]
+impl StorageBackend for MemoryStorage {
+    async fn load(&self, room: &str, doc: &str) -> io::Result<StoredDoc> {
+        let key = (room.to_string(), doc.to_string());
+        let (text, meta) = self
+            .docs()
+            .get(&key)
+            .map(|stored| (stored.text.clone(), stored.meta.clone()))
+            .unwrap_or_default();
+        Ok(StoredDoc {
+            text,
+            meta,
+            warning: None,
+        })
+    }
+
+    async fn save(&self, room: &str, doc: &str, text: String, mut meta: DocMeta) -> io::Result<()> {
+        meta.stamp(&text);
+        let stored = MemoryDoc {
+            text,
+            meta,
+            modified: SystemTime::now(),
+        };
+        self.docs()
+            .insert((room.to_string(), doc.to_string()), stored);
+        Ok(())
+    }
+
+    async fn list(&self, room: &str) -> io::Result<Vec<DocEntry>> {
+        Ok(self
+            .docs()
+            .iter()
+            .filter(|((stored_room, _), _)| stored_room == room)
+            .map(|((_, name), stored)| DocEntry {
+                name: name.clone(),
+                size: stored.text.len() as u64,
+                modified: stored.modified,
+            })
+            .collect())
+    }
+
+    async fn list_rooms(&self) -> io::Result<Vec<String>> {
+        let mut rooms: Vec<String> = self.docs().keys().map(|(room, _)| room.clone()).collect();
+        rooms.dedup();
+        Ok(rooms)
+    }
+
+    async fn load_room_meta(&self, room: &str) -> io::Result<RoomMeta> {
+        Ok(self.rooms().get(room).cloned().unwrap_or_default())
+    }
+
+    async fn save_room_meta(&self, room: &str, meta: RoomMeta) -> io::Result<()> {
+        self.rooms().insert(room.to_string(), meta);
+        Ok(())
+    }
+
+    async fn delete(&self, room: &str, doc: &str) -> io::Result<()> {
+        self.docs()
+            .remove(&(room.to_string(), doc.to_string()))
+            .map(drop)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("{}/{} was never saved", room, doc),
+                )
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn documents_round_trip_until_deleted() {
+        let storage = MemoryStorage::new();
+        assert_eq!(storage.load("demo", "notes").await.unwrap().text, "");
+
+        let meta = DocMeta {
+            version: 3,
+            ..DocMeta::default()
+        };
+        storage
+            .save("demo", "notes", "hello".into(), meta)
+            .await
+            .unwrap();
+        storage
+            .save("other", "notes", "elsewhere".into(), DocMeta::default())
+            .await
+            .unwrap();
+        let stored = storage.load("demo", "notes").await.unwrap();
+        assert_eq!(stored.text, "hello");
+        assert_eq!(stored.meta.version, 3);
+        assert!(stored.meta.checksum.is_some() && stored.meta.last_modified.is_some());
+
+        let listed = storage.list("demo").await.unwrap();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(storage.list_rooms().await.unwrap(), ["demo", "other"]);
+        assert_eq!((listed[0].name.as_str(), listed[0].size), ("notes", 5));
+
+        storage.delete("demo", "notes").await.unwrap();
+        assert!(storage.list("demo").await.unwrap().is_empty());
+        let err = storage.delete("demo", "notes").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.list("other").await.unwrap().len(), 1);
+    }
+}
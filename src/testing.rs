@@ -0,0 +1,290 @@
+//! Servers and clients in-process, for end-to-end tests: `TestServer`
+//! listens on ports the OS picks and stores documents in a fresh temporary
+//! directory, and `TestClient` joins a document and follows its text.
+
+use crate::chunked::ChunkedInsert;
+use crate::protocol::{
+    Op, ServerLimits, ServerMessage, decode_sync_response, decode_update, encode_sync_request,
+    encode_update, make_scoped_user_id,
+};
+use crate::server::{self, GrowthPolicy, HealthOptions, MotdOptions};
+use crate::storage::{
+    BackendKind, DEFAULT_TRASH_RETENTION, DocNames, FsOptions, HistoryPolicy, SyncPolicy,
+};
+use crate::textpos::{apply_op_to_doc, build_doc};
+use mdcs_sdk::{Message, TextDoc};
+use std::io;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
+
+/// How long `TestClient` waits for the server before failing.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    pub health_addr: SocketAddr,
+    data_dir: PathBuf,
+    shutdown: oneshot::Sender<()>,
+    task: JoinHandle<Result<(), String>>,
+}
+
+impl TestServer {
+    /// A server with an empty data dir of its own.
+    pub async fn spawn() -> io::Result<Self> {
+        static SPAWNED: AtomicUsize = AtomicUsize::new(0);
+        let data_dir = std::env::temp_dir().join(format!(
+            "carnelia-test-server-{}-{}",
+            std::process::id(),
+            SPAWNED.fetch_add(1, Ordering::Relaxed)
+        ));
+        match std::fs::remove_dir_all(&data_dir) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        Self::spawn_in(data_dir).await
+    }
+
+    /// A server on the documents in `data_dir`, e.g. those a stopped server
+    /// left behind. Documents can be read over HTTP on `health_addr`.
+    pub async fn spawn_in(data_dir: PathBuf) -> io::Result<Self> {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        let health_listener = TcpListener::bind("127.0.0.1:0").await?;
+        let (addr, health_addr) = (listener.local_addr()?, health_listener.local_addr()?);
+        let options = FsOptions {
+            data_dir: data_dir.clone(),
+            fsync: SyncPolicy::Never,
+            encryption: None,
+            compress_above: None,
+            watch: false,
+            template_dir: None,
+            doc_names: DocNames::default(),
+            trash_retention: DEFAULT_TRASH_RETENTION,
+        };
+        let (shutdown, stop) = oneshot::channel::<()>();
+        let task = tokio::spawn(async move {
+            let stop = async {
+                let _ = stop.await;
+            };
+            server::serve(
+                listener,
+                health_listener,
+                BackendKind::Fs,
+                options,
+                HistoryPolicy::default(),
+                ServerLimits::default(),
+                GrowthPolicy::default(),
+                MotdOptions::default(),
+                HealthOptions {
+                    http_read: true,
+                    ..HealthOptions::default()
+                },
+                stop,
+            )
+            .await
+            .map_err(|err| err.to_string())
+        });
+        Ok(Self {
+            addr,
+            health_addr,
+            data_dir,
+            shutdown,
+            task,
+        })
+    }
+
+    pub fn data_dir(&self) -> &Path {
+        &self.data_dir
+    }
+
+    pub async fn connect(&self, user: &str, room: &str, doc: &str) -> io::Result<TestClient> {
+        TestClient::connect(self.addr, user, room, doc).await
+    }
+
+    /// Stops the server as Ctrl-C would, once open documents are saved.
+    /// Returns the data dir, to start another server on.
+    pub async fn shutdown(self) -> io::Result<PathBuf> {
+        let _ = self.shutdown.send(());
+        self.task
+            .await
+            .map_err(io::Error::other)?
+            .map_err(io::Error::other)?;
+        Ok(self.data_dir)
+    }
+}
+
+/// A client joined to one document, keeping its text up to date with the
+/// updates it reads.
+pub struct TestClient {
+    doc_id: String,
+    user_id: String,
+    doc: TextDoc,
+    version: u64,
+    /// Edits sent that the server hasn't sent back yet.
+    unconfirmed: usize,
+    lines: Lines<BufReader<OwnedReadHalf>>,
+    writer: OwnedWriteHalf,
+}
+
+impl TestClient {
+    /// Joins `room`/`doc` as `user`; fails if the server refuses the join.
+    pub async fn connect(addr: SocketAddr, user: &str, room: &str, doc: &str) -> io::Result<Self> {
+        let (reader, writer) = TcpStream::connect(addr).await?.into_split();
+        let doc_id = format!("{}/{}", room, doc);
+        let user_id = make_scoped_user_id(&doc_id, user);
+        let mut client = Self {
+            doc: TextDoc::new(doc_id.clone(), user_id.clone()),
+            doc_id,
+            user_id,
+            version: 0,
+            unconfirmed: 0,
+            lines: BufReader::new(reader).lines(),
+            writer,
+        };
+        let hello = Message::Hello {
+            replica_id: client.user_id.clone(),
+            user_name: user.to_string(),
+        };
+        client.send(&hello).await?;
+        client.send(&encode_sync_request(&client.doc_id, 0)).await?;
+        loop {
+            let msg = client.next_message().await?;
+            let Some((doc_id, sync, version)) = decode_sync_response(&msg) else {
+                continue;
+            };
+            if doc_id != client.doc_id {
+                continue;
+            }
+            if let Some(error) = sync.error {
+                return Err(io::Error::other(format!("join refused: {}", error)));
+            }
+            client.doc = build_doc(&client.doc_id, &client.user_id, &sync.text);
+            client.version = version;
+            return Ok(client);
+        }
+    }
+
+    /// The joined document as `room/doc`, after any redirect by the room.
+    pub fn doc_id(&self) -> &str {
+        &self.doc_id
+    }
+
+    pub fn text(&self) -> String {
+        self.doc.get_text()
+    }
+
+    /// The version of the last update read.
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    /// Inserts `text` at byte `pos`, locally and on the server.
+    pub async fn insert(&mut self, pos: usize, text: &str) -> io::Result<()> {
+        self.edit(Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        })
+        .await
+    }
+
+    /// Inserts `text` at byte `pos` as Inserts of at most `chunk_bytes`
+    /// each, like a long paste. Returns how many were sent.
+    pub async fn insert_chunked(
+        &mut self,
+        pos: usize,
+        text: &str,
+        chunk_bytes: usize,
+    ) -> io::Result<usize> {
+        let mut upload = ChunkedInsert::new(pos, text, chunk_bytes, |_| true);
+        while let Some(op) = upload.next_op() {
+            self.edit(op).await?;
+            upload.chunk_sent(Instant::now());
+        }
+        Ok(upload.chunks())
+    }
+
+    pub async fn delete(&mut self, pos: usize, len: usize) -> io::Result<()> {
+        self.edit(Op::Delete {
+            pos,
+            len,
+            pos_chars: None,
+            len_chars: None,
+        })
+        .await
+    }
+
+    async fn edit(&mut self, op: Op) -> io::Result<()> {
+        apply_op_to_doc(&mut self.doc, &op);
+        self.unconfirmed += 1;
+        let update = encode_update(&self.doc_id, &self.user_id, op, Vec::new(), self.version)
+            .map_err(io::Error::other)?;
+        self.send(&update).await
+    }
+
+    /// Reads updates until the text is `expected` and the server applied
+    /// every edit this client sent.
+    pub async fn wait_for_text(&mut self, expected: &str) -> io::Result<()> {
+        while self.text() != expected || self.unconfirmed > 0 {
+            let msg = self.next_message().await.map_err(|err| {
+                io::Error::new(
+                    err.kind(),
+                    format!("{} while the text was {:?}", err, self.text()),
+                )
+            })?;
+            if let Some((doc_id, update, version)) = decode_update(&msg)
+                && doc_id == self.doc_id
+            {
+                // Our own edits come back too; they are applied already.
+                if update.user_id == self.user_id {
+                    self.unconfirmed = self.unconfirmed.saturating_sub(1);
+                } else {
+                    apply_op_to_doc(&mut self.doc, &update.op);
+                }
+                self.version = version;
+            }
+        }
+        Ok(())
+    }
+
+    async fn send(&mut self, msg: &Message) -> io::Result<()> {
+        let mut line = serde_json::to_string(msg).map_err(io::Error::other)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes()).await
+    }
+
+    async fn next_message(&mut self) -> io::Result<Message> {
+        loop {
+            let line = tokio::time::timeout(TIMEOUT, self.lines.next_line())
+                .await
+                .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no message from the server"))?
+                .and_then(|line| {
+                    line.ok_or_else(|| {
+                        io::Error::new(io::ErrorKind::UnexpectedEof, "the server hung up")
+                    })
+                })?;
+            if let Ok(msg) = serde_json::from_str(&line) {
+                return Ok(msg);
+            }
+            // The room may call the doc something else.
+            if let Ok(ServerMessage::Welcome {
+                doc: Some(doc),
+                redirected_from: Some(_),
+                ..
+            }) = serde_json::from_str(&line)
+            {
+                let (room, _) = self.doc_id.split_once('/').unwrap_or_default();
+                let raw_id = self.user_id.split_once('|').map_or("", |(_, raw)| raw);
+                self.doc_id = format!("{}/{}", room, doc);
+                self.user_id = make_scoped_user_id(&self.doc_id, raw_id);
+                self.doc = TextDoc::new(self.doc_id.clone(), self.user_id.clone());
+            }
+        }
+    }
+}
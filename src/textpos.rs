@@ -0,0 +1,361 @@
+//! Byte offsets into UTF-8 text and what they point at: the character
+//! boundary at or before them, the character index a `TextDoc` counts in,
+//! the line and column, and where they move when an edit happens around
+//! them. Ops carry byte offsets from clients that may disagree with the
+//! text, so an offset past the end or inside a character is clamped to the
+//! boundary at or before it, never a panic.
+
+use crate::protocol::Op;
+use mdcs_sdk::TextDoc;
+
+/// The character boundary at or before `pos`, at most the text's length.
+pub(crate) fn clamp_to_boundary(text: &str, pos: usize) -> usize {
+    let mut pos = pos.min(text.len());
+    while !text.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    pos
+}
+
+/// Start of the character before `pos`, or 0 at the start.
+pub(crate) fn prev_char_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    text[..pos]
+        .char_indices()
+        .next_back()
+        .map_or(0, |(idx, _)| idx)
+}
+
+/// End of the character at `pos`, or the text's length at its end.
+pub(crate) fn next_char_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    text[pos..]
+        .chars()
+        .next()
+        .map_or(pos, |ch| pos + ch.len_utf8())
+}
+
+/// Characters before byte `pos`.
+pub(crate) fn byte_to_char_index(text: &str, pos: usize) -> usize {
+    text[..clamp_to_boundary(text, pos)].chars().count()
+}
+
+/// Byte offset of character `idx`, or the text's length past the last.
+pub(crate) fn char_to_byte_index(text: &str, idx: usize) -> usize {
+    text.char_indices()
+        .nth(idx)
+        .map_or(text.len(), |(pos, _)| pos)
+}
+
+/// Byte offset of each line's start, the first at 0. A text ending in a
+/// newline has an empty last line after it.
+pub(crate) fn line_starts(text: &str) -> Vec<usize> {
+    std::iter::once(0)
+        .chain(text.match_indices('\n').map(|(idx, _)| idx + 1))
+        .collect()
+}
+
+/// Line and column, both from 0 and the column in characters, of byte
+/// `pos`.
+pub(crate) fn byte_to_line_col(text: &str, pos: usize) -> (usize, usize) {
+    let before = &text[..clamp_to_boundary(text, pos)];
+    let start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (
+        before.matches('\n').count(),
+        before[start..].chars().count(),
+    )
+}
+
+/// A replica of `doc_id` holding `text`.
+pub(crate) fn build_doc(doc_id: &str, replica_id: &str, text: &str) -> TextDoc {
+    let mut doc = TextDoc::new(doc_id.to_string(), replica_id.to_string());
+    if !text.is_empty() {
+        doc.insert(0, text);
+    }
+    doc
+}
+
+/// Applies a text op, in bytes, to `doc`, which counts characters.
+/// Cursors and selections change nothing.
+pub(crate) fn apply_op_to_doc(doc: &mut TextDoc, op: &Op) {
+    match op {
+        Op::Insert { pos, text, .. } => {
+            let current = doc.get_text();
+            doc.insert(byte_to_char_index(&current, *pos), text);
+        }
+        Op::Delete { pos, len, .. } => {
+            let current = doc.get_text();
+            let start = clamp_to_boundary(&current, *pos);
+            let end = clamp_to_boundary(&current, start.saturating_add(*len));
+            let char_len = current[start..end].chars().count();
+            if char_len > 0 {
+                doc.delete(byte_to_char_index(&current, start), char_len);
+            }
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => {}
+    }
+}
+
+/// Moves `pos` with the text around it when `op` is applied: an insert at
+/// or before it pushes it right, a delete before it pulls it left, to the
+/// start of the deleted text if it was inside.
+pub(crate) fn shift_for_op(op: &Op, pos: &mut usize) {
+    match op {
+        Op::Insert { pos: at, text, .. } => {
+            if *at <= *pos {
+                *pos = pos.saturating_add(text.len());
+            }
+        }
+        Op::Delete { pos: at, len, .. } => {
+            if *at < *pos {
+                let removed = (*pos - *at).min(*len);
+                *pos = pos.saturating_sub(removed);
+            }
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => {}
+    }
+}
+
+/// Range counterpart of `shift_for_op`: moves `start..end` and returns
+/// false if `op` changed text inside the range.
+pub(crate) fn shift_range_for_op(op: &Op, start: &mut usize, end: &mut usize) -> bool {
+    match op {
+        Op::Insert { pos, text, .. } => {
+            if *pos <= *start {
+                *start += text.len();
+                *end += text.len();
+                true
+            } else if *pos < *end {
+                *end += text.len();
+                false
+            } else {
+                true
+            }
+        }
+        Op::Delete { pos, len, .. } => {
+            if pos.saturating_add(*len) <= *start {
+                *start -= len;
+                *end -= len;
+                true
+            } else if *pos >= *end {
+                true
+            } else {
+                shift_for_op(op, start);
+                shift_for_op(op, end);
+                false
+            }
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => true,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::snapshot::apply_to_text;
+    use proptest::collection::vec;
+    use proptest::prelude::*;
+
+    /// Text heavy in characters of every UTF-8 length, and newlines.
+    fn text() -> impl Strategy<Value = String> {
+        vec(
+            prop_oneof![
+                Just('a'),
+                Just(' '),
+                Just('\n'),
+                Just('é'),
+                Just('→'),
+                Just('😀'),
+                any::<char>(),
+            ],
+            0..40,
+        )
+        .prop_map(|chars| chars.into_iter().collect())
+    }
+
+    fn op(len: usize) -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..=len + 2, "[a-z😀]{0,3}").prop_map(|(pos, text)| Op::Insert {
+                pos,
+                text,
+                pos_chars: None
+            }),
+            (0..=len + 2, 0..=len + 2).prop_map(|(pos, len)| Op::Delete {
+                pos,
+                len,
+                pos_chars: None,
+                len_chars: None
+            }),
+        ]
+    }
+
+    /// Ops on ASCII text that insert characters it doesn't have.
+    fn ascii_op(len: usize) -> impl Strategy<Value = Op> {
+        prop_oneof![
+            (0..=len, "[*#]{0,3}").prop_map(|(pos, text)| Op::Insert {
+                pos,
+                text,
+                pos_chars: None
+            }),
+            (0..=len, 0..=len).prop_map(|(pos, len)| Op::Delete {
+                pos,
+                len,
+                pos_chars: None,
+                len_chars: None
+            }),
+        ]
+    }
+
+    #[test]
+    fn multi_byte_and_emoji_boundaries() {
+        // 'é' is 2 bytes, '→' 3, '😀' 4.
+        let text = "aé→😀\nb";
+        assert_eq!(
+            (0..=text.len() + 1)
+                .map(|pos| clamp_to_boundary(text, pos))
+                .collect::<Vec<_>>(),
+            [0, 1, 1, 3, 3, 3, 6, 6, 6, 6, 10, 11, 12, 12]
+        );
+        assert_eq!(prev_char_boundary(text, 6), 3);
+        assert_eq!(prev_char_boundary(text, 8), 3, "inside the emoji");
+        assert_eq!(prev_char_boundary(text, 0), 0);
+        assert_eq!(next_char_boundary(text, 6), 10);
+        assert_eq!(next_char_boundary(text, 8), 10);
+        assert_eq!(next_char_boundary(text, 12), 12);
+        assert_eq!(byte_to_char_index(text, 8), 3);
+        assert_eq!(char_to_byte_index(text, 3), 6);
+        assert_eq!(char_to_byte_index(text, 99), text.len());
+        assert_eq!(line_starts(text), [0, 11]);
+        assert_eq!(line_starts("a\n"), [0, 2]);
+        assert_eq!(byte_to_line_col(text, 10), (0, 4));
+        assert_eq!(byte_to_line_col(text, 12), (1, 1));
+
+        let mut doc = build_doc("r/d", "r/d|me", text);
+        apply_op_to_doc(
+            &mut doc,
+            &Op::Delete {
+                pos: 7,
+                len: 2,
+                pos_chars: None,
+                len_chars: None,
+            },
+        );
+        assert_eq!(doc.get_text(), text, "inside one character: nothing");
+        apply_op_to_doc(
+            &mut doc,
+            &Op::Delete {
+                pos: 2,
+                len: 7,
+                pos_chars: None,
+                len_chars: None,
+            },
+        );
+        assert_eq!(doc.get_text(), "a😀\nb", "from inside é to inside 😀");
+        apply_op_to_doc(
+            &mut doc,
+            &Op::Insert {
+                pos: 3,
+                text: "!".into(),
+                pos_chars: None,
+            },
+        );
+        assert_eq!(doc.get_text(), "a!😀\nb");
+        assert_eq!(build_doc("r/d", "r/d|me", "").get_text(), "");
+    }
+
+    #[test]
+    fn shifting_follows_inserts_and_deletes() {
+        let insert = Op::Insert {
+            pos: 2,
+            text: "xyz".into(),
+            pos_chars: None,
+        };
+        let (mut before, mut at, mut after) = (1, 2, 5);
+        for pos in [&mut before, &mut at, &mut after] {
+            shift_for_op(&insert, pos);
+        }
+        assert_eq!((before, at, after), (1, 5, 8));
+        let delete = Op::Delete {
+            pos: 2,
+            len: 3,
+            pos_chars: None,
+            len_chars: None,
+        };
+        let (mut inside, mut past) = (3, 9);
+        shift_for_op(&delete, &mut inside);
+        shift_for_op(&delete, &mut past);
+        assert_eq!((inside, past), (2, 6));
+
+        let (mut start, mut end) = (4, 8);
+        assert!(!shift_range_for_op(&delete, &mut start, &mut end));
+        assert_eq!((start, end), (2, 5));
+        assert!(shift_range_for_op(&insert, &mut start, &mut end));
+        assert_eq!((start, end), (5, 8));
+    }
+
+    proptest! {
+        #[test]
+        fn clamping_is_idempotent(text in text(), pos in 0..200usize) {
+            let clamped = clamp_to_boundary(&text, pos);
+            prop_assert!(clamped <= pos && text.is_char_boundary(clamped));
+            prop_assert_eq!(clamp_to_boundary(&text, clamped), clamped);
+            let (prev, next) = (prev_char_boundary(&text, pos), next_char_boundary(&text, pos));
+            prop_assert!(prev <= clamped && clamped <= next);
+            if clamped < text.len() {
+                prop_assert_eq!(prev_char_boundary(&text, next), clamped);
+                prop_assert_eq!(text[clamped..next].chars().count(), 1);
+            }
+        }
+
+        #[test]
+        fn bytes_chars_and_line_cols_round_trip(text in text(), pos in 0..200usize) {
+            let pos = clamp_to_boundary(&text, pos);
+            prop_assert_eq!(char_to_byte_index(&text, byte_to_char_index(&text, pos)), pos);
+            let (line, col) = byte_to_line_col(&text, pos);
+            let start = line_starts(&text)[line];
+            prop_assert!(!text[start..pos].contains('\n'));
+            prop_assert_eq!(text[start..pos].chars().count(), col);
+        }
+
+        #[test]
+        fn docs_and_strings_agree_on_ops(text in text(), ops in vec(op(40), 0..8)) {
+            let mut doc = build_doc("r/d", "r/d|me", &text);
+            let mut plain = text.clone();
+            for op in &ops {
+                apply_op_to_doc(&mut doc, op);
+                apply_to_text(&mut plain, op);
+            }
+            prop_assert_eq!(doc.get_text(), plain);
+        }
+
+        #[test]
+        fn shifts_compose_over_a_series_of_ops(
+            len in 1..30usize,
+            pos in 0..30usize,
+            range in (0..30usize, 0..30usize),
+            ops in vec(ascii_op(34), 1..8),
+        ) {
+            // Every character differs, so where one went can be checked.
+            let mut text: String = ('A'..='Z').chain('a'..='z').take(len).collect();
+            let mut at = pos % len;
+            let tracked = text[at..].chars().next().unwrap();
+            let (a, b) = (range.0 % (len + 1), range.1 % (len + 1));
+            let (mut start, mut end) = (a.min(b), a.max(b));
+            let mut intact = text[start..end].to_string();
+            let mut range_intact = true;
+            for op in &ops {
+                apply_to_text(&mut text, op);
+                shift_for_op(op, &mut at);
+                if text.contains(tracked) {
+                    prop_assert_eq!(text[at..].chars().next(), Some(tracked));
+                }
+                range_intact &= shift_range_for_op(op, &mut start, &mut end);
+                if range_intact {
+                    prop_assert_eq!(&text[start..end], intact.as_str());
+                } else {
+                    intact.clear();
+                }
+            }
+        }
+    }
+}
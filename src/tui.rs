@@ -1,821 +1,4446 @@
+use crate::chunked::ChunkedInsert;
+use crate::client::{self, resync::SyncSchedule};
+use crate::position;
 use crate::protocol::{
-    Op, decode_sync_response, decode_update, doc_id_from_scoped_user_id, encode_sync_request,
-    encode_update, make_scoped_user_id,
+    ActivityEntry, LineCol, Op, ServerLimits, encode_sync_request, encode_update,
+    make_scoped_user_id,
 };
-use crossterm::cursor::{MoveTo, Show};
-use crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
-use crossterm::style::{Attribute, Color, SetAttribute, SetBackgroundColor, SetForegroundColor};
-use crossterm::terminal::{self, Clear, ClearType, EnterAlternateScreen, LeaveAlternateScreen};
-use crossterm::{execute, queue};
+use crate::record::Recorder;
+use crate::replace::{self, Pattern, Replacement};
+use crate::snapshot::{self, Change, PendingOps};
+use crate::storage::{RealFs, UserStats, replace_file};
+use crate::textpos::{apply_op_to_doc, clamp_to_boundary, line_starts, shift_for_op};
+use crossterm::event::{
+    self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEvent,
+    MouseEventKind,
+};
+use crossterm::style::Color;
+use crossterm::terminal;
 use mdcs_sdk::{Awareness, Message, TextDoc};
-use std::collections::HashMap;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::error::Error;
-use std::io::{Write, stdout};
-use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
-use tokio::net::TcpStream;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use tokio::sync::mpsc;
+use unicode_segmentation::{GraphemeCursor, UnicodeSegmentation};
+
+mod backup;
+mod buffer;
+mod clipboard;
+mod coalesce;
+mod commands;
+mod connection;
+mod history;
+mod invisibles;
+mod keys;
+mod link;
+#[cfg(feature = "markdown")]
+mod markdown;
+mod metrics;
+mod picker;
+mod prompt;
+mod search;
+#[cfg(feature = "spellcheck")]
+mod spell;
+mod status;
+mod theme;
+mod undo;
+pub(crate) mod widgets;
+
+use backup::Backup;
+use buffer::{Buffer, NetEvent, TabLabel};
+use clipboard::Clipboard;
+use coalesce::{Coalescer, Outbox};
+use commands::Command;
+use connection::JoinInfo;
+pub use connection::OutageInput;
+use history::{History, Reply};
+use keys::Action;
+pub use keys::{Bindings, KeyConfig};
+use link::LinkState;
+use metrics::{DebugStats, Metrics};
+use picker::{Picked, Picker};
+use prompt::{CommandPrompt, PromptAction};
+use search::SearchState;
+use status::{Severity, StatusLog};
+pub use theme::{Theme, ThemeConfig, ThemeName};
+use undo::{Edit, UndoStack};
+use widgets::{
+    PromptEvent, Screen, Style, TerminalGuard, clip_line, grapheme_width, show, text_width, tty,
+};
+
+/// Lines scrolled per mouse wheel notch.
+const WHEEL_SCROLL_LINES: usize = 3;
+/// Columns kept visible around the cursor when scrolling horizontally.
+const HSCROLL_MARGIN: usize = 4;
+/// How long quitting waits for sent edits to be confirmed by the server.
+const QUIT_WAIT: Duration = Duration::from_secs(2);
+/// How often `--debug-log` gets a line.
+const DEBUG_LOG_INTERVAL: Duration = Duration::from_secs(1);
+/// Lines moved by PageUp/PageDown in the message log.
+const LOG_PAGE_LINES: usize = 10;
+/// Width of the presence sidebar, including its separator column.
+const SIDEBAR_WIDTH: usize = 24;
+/// Width of the presence gutter: this many dots, or a count beyond them.
+const GUTTER_WIDTH: usize = 3;
+/// Height of the activity feed panel, including its header row.
+const FEED_ROWS: usize = 6;
+/// Name labels next to remote cursors are hidden after this much inactivity.
+const CURSOR_LABEL_TTL: Duration = Duration::from_secs(3);
+const CURSOR_LABEL_MAX_CHARS: usize = 8;
+/// Interval of the periodic render tick driving time-based effects.
+const RENDER_TICK: Duration = Duration::from_millis(250);
+/// How long the target line stays highlighted after a goto.
+const FLASH_DURATION: Duration = Duration::from_millis(600);
+/// A remote edit this soon after the previous one by the same user extends
+/// its highlight instead of starting a new one.
+const EDIT_FLASH_MERGE: Duration = Duration::from_secs(1);
+/// Without bracketed paste, more than this many text keys arriving within
+/// `PASTE_BURST_WINDOW` are treated as a single paste.
+const PASTE_BURST_MIN_CHARS: usize = 20;
+const PASTE_BURST_WINDOW: Duration = Duration::from_millis(50);
+
+/// Settings for `run` beyond which document to join.
+pub struct Options {
+    /// Capture the mouse (click, drag and wheel).
+    pub mouse: bool,
+    pub outage_input: OutageInput,
+    pub theme: Theme,
+    /// The config's `[theme]` section, applied again when `:theme`
+    /// switches themes.
+    pub theme_config: ThemeConfig,
+    pub cursors: CursorConfig,
+    pub view: ViewConfig,
+    pub editing: EditingConfig,
+    pub spellcheck: SpellConfig,
+    /// The config's `[tui.keys]` over the default key bindings.
+    pub bindings: Bindings,
+    /// File receiving the debug counters once per second.
+    pub debug_log: Option<PathBuf>,
+    /// Keep local backups of the open documents.
+    pub backup: bool,
+    /// Pastes longer than this go out in chunks of this size.
+    pub chunk_bytes: usize,
+    /// Delays and losses to put on server connections.
+    pub network: client::NetConditions,
+    /// Sync each document by itself this often, once the user stopped
+    /// typing.
+    pub auto_sync: Option<Duration>,
+    /// Where `--record` appends what goes over the connections.
+    pub record: Option<Arc<Recorder>>,
+}
+
+/// How remote activity is highlighted, the config's `[cursors]` section.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct CursorConfig {
+    /// Remote cursors are drawn dimmed after this much inactivity.
+    pub fade_after_ms: u64,
+    /// How long text inserted or deleted by others stays highlighted.
+    pub edit_flash_ms: u64,
+}
+
+impl Default for CursorConfig {
+    fn default() -> Self {
+        Self {
+            fade_after_ms: 10_000,
+            edit_flash_ms: 500,
+        }
+    }
+}
+
+/// Optional parts of the editor view, the config's `[view]` section.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ViewConfig {
+    /// Scrollbar with remote cursor ticks on the right edge of the text.
+    pub scrollbar: bool,
+    /// Margin left of the scrollbar with a dot for each remote user on a
+    /// visible line.
+    pub gutter: bool,
+    /// Markdown highlighting for `.md` docs (needs the `markdown` feature).
+    pub markdown: bool,
+}
+
+impl Default for ViewConfig {
+    fn default() -> Self {
+        Self {
+            scrollbar: true,
+            gutter: true,
+            markdown: true,
+        }
+    }
+}
+
+/// Editing behavior, the config's `[editing]` section.
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct EditingConfig {
+    pub line_endings: LineEndings,
+    /// A second End moves back before trailing whitespace.
+    pub smart_end: bool,
+}
+
+/// Spellchecking, the config's `[spellcheck]` section (needs the
+/// `spellcheck` feature).
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SpellConfig {
+    pub enabled: bool,
+    /// Looked up as `/usr/share/hunspell/<language>.dic` (or `myspell`)
+    /// when no dictionary is given.
+    pub language: String,
+    /// A hunspell `.dic` file or a word list, one word per line.
+    pub dictionary: Option<PathBuf>,
+}
+
+impl Default for SpellConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            language: "en_US".to_string(),
+            dictionary: None,
+        }
+    }
+}
+
+/// The config's `[tui]` section.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TuiConfig {
+    /// Action names to key descriptors, see `keys`.
+    pub keys: KeyConfig,
+}
+
+/// What happens to `\r\n` and lone `\r` in pasted or imported text.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LineEndings {
+    /// Both become `\n`.
+    #[default]
+    Normalize,
+    /// Text is inserted as it is.
+    Keep,
+}
+
+impl LineEndings {
+    fn apply(self, text: &str) -> String {
+        match self {
+            LineEndings::Normalize => normalize_line_endings(text),
+            LineEndings::Keep => text.to_string(),
+        }
+    }
+}
+
+impl CursorConfig {
+    fn fade_after(&self) -> Duration {
+        Duration::from_millis(self.fade_after_ms)
+    }
+
+    fn edit_flash(&self) -> Duration {
+        Duration::from_millis(self.edit_flash_ms)
+    }
+}
+
+enum KeyAction {
+    Ignored,
+    Redraw,
+    /// Quit once sent edits are confirmed, asking if they don't get
+    /// confirmed in time.
+    Quit,
+    /// Quit without waiting for the server.
+    ForceQuit,
+    SetTheme(ThemeName),
+    /// Open the command list (`:help`).
+    Help,
+    /// Switch to the named doc of the room, joining it if it isn't open.
+    OpenDoc(String),
+    /// Snapshot the active doc on the server once its edits are confirmed.
+    Snapshot,
+    /// Fetch who wrote how much of the active doc (`:stats`).
+    DocStats,
+    /// List the docs of the room to pick one to open (`:docs`).
+    PickDoc,
+    /// Fetch the saved revisions of the active doc to step through them.
+    History,
+    /// Restore this revision of the active doc.
+    Restore(u64),
+    NextBuffer,
+    PrevBuffer,
+    CloseBuffer,
+    /// Stop the process until the shell continues it (Ctrl+Z on Unix).
+    Suspend,
+}
 
 enum UiEvent {
     Key(KeyEvent),
+    Mouse(MouseEvent),
+    Paste(String),
     Resize,
+    /// Background work finished something worth showing.
+    #[cfg_attr(not(feature = "spellcheck"), allow(dead_code))]
+    Redraw,
+    /// The server's answer to `:snapshot` of a doc: version and bytes.
+    Snapshot(String, Result<(u64, usize), String>),
+    /// The server's answer to `:stats` of a doc.
+    DocStats(String, Result<Vec<UserStats>, String>),
+    /// The server's list of the room's docs, for `:docs`.
+    Docs(Result<Vec<String>, String>),
+    /// The server's answer to a history request of a doc.
+    History(String, history::Reply),
 }
 
-struct TerminalGuard;
+/// Forwards terminal events to the UI loop until the receiver is gone.
+fn read_input(ui_tx: &mpsc::UnboundedSender<UiEvent>) {
+    let mut pending = None;
+    loop {
+        let event = match pending.take() {
+            Some(event) => event,
+            None => match event::read() {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+        };
+        let ui_event = match event {
+            Event::Key(key) if is_text_key(&key) => {
+                let (keys, next) = read_key_burst(key);
+                pending = next;
+                let events = match burst_text(&keys) {
+                    Some(text) => vec![UiEvent::Paste(text)],
+                    None => keys.into_iter().map(UiEvent::Key).collect(),
+                };
+                if events.into_iter().any(|event| ui_tx.send(event).is_err()) {
+                    break;
+                }
+                continue;
+            }
+            Event::Key(key) => UiEvent::Key(key),
+            Event::Mouse(mouse) => UiEvent::Mouse(mouse),
+            Event::Paste(text) => UiEvent::Paste(text),
+            Event::Resize(_, _) => UiEvent::Resize,
+            _ => continue,
+        };
+        if ui_tx.send(ui_event).is_err() {
+            break;
+        }
+    }
+}
 
-impl TerminalGuard {
-    fn new() -> Result<Self, Box<dyn Error>> {
-        terminal::enable_raw_mode()?;
-        execute!(stdout(), EnterAlternateScreen)?;
-        Ok(Self)
+/// Collects text keys that arrive right after `first`, as happens when a
+/// terminal without bracketed paste types out pasted text. Returns the keys
+/// and the first non-text event that ended the burst, if any.
+fn read_key_burst(first: KeyEvent) -> (Vec<KeyEvent>, Option<Event>) {
+    let started = Instant::now();
+    let mut keys = vec![first];
+    while started.elapsed() < PASTE_BURST_WINDOW
+        && event::poll(Duration::from_millis(2)).unwrap_or(false)
+    {
+        match event::read() {
+            Ok(Event::Key(key)) if key.kind == KeyEventKind::Release => {}
+            Ok(Event::Key(key)) if is_text_key(&key) => keys.push(key),
+            Ok(event) => return (keys, Some(event)),
+            Err(_) => break,
+        }
     }
+    (keys, None)
 }
 
-impl Drop for TerminalGuard {
-    fn drop(&mut self) {
-        let _ = execute!(stdout(), Show, LeaveAlternateScreen);
-        let _ = terminal::disable_raw_mode();
+fn is_text_key(key: &KeyEvent) -> bool {
+    if key.kind == KeyEventKind::Release
+        || key
+            .modifiers
+            .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    {
+        return false;
     }
+    matches!(key.code, KeyCode::Char(_) | KeyCode::Enter | KeyCode::Tab)
 }
 
-pub async fn run(addr: &str, user: &str, room: &str, doc: &str) -> Result<(), Box<dyn Error>> {
-    let stream = TcpStream::connect(addr).await?;
-    let (reader, writer) = stream.into_split();
+/// Whether input other than typing arrived, which sends batched
+/// characters right away.
+fn ends_typing(event: &UiEvent) -> bool {
+    match event {
+        UiEvent::Key(key) => key.kind != KeyEventKind::Release && !is_text_key(key),
+        UiEvent::Mouse(_) | UiEvent::Paste(_) => true,
+        UiEvent::Resize
+        | UiEvent::Redraw
+        | UiEvent::Snapshot(..)
+        | UiEvent::DocStats(..)
+        | UiEvent::Docs(..)
+        | UiEvent::History(..) => false,
+    }
+}
 
-    let (out_tx, mut out_rx) = mpsc::channel::<Message>(64);
+/// Completes once `at` has passed; never without a deadline.
+async fn sleep_until(at: Option<Instant>) {
+    match at {
+        Some(at) => tokio::time::sleep_until(at.into()).await,
+        None => std::future::pending().await,
+    }
+}
 
-    let writer_task = tokio::spawn(async move {
-        let mut writer = writer;
-        while let Some(msg) = out_rx.recv().await {
-            let json = match serde_json::to_string(&msg) {
-                Ok(json) => json,
-                Err(_) => continue,
-            };
-            if writer.write_all(json.as_bytes()).await.is_err() {
-                break;
-            }
-            if writer.write_all(b"\n").await.is_err() {
-                break;
+/// Asks for the room and doc that weren't given, from the ones the server
+/// lists; Esc on the docs goes back to the rooms. `None` if the user
+/// cancelled instead.
+async fn pick_doc(
+    addr: &str,
+    room: Option<&str>,
+    doc: Option<&str>,
+    ui_rx: &mut mpsc::UnboundedReceiver<UiEvent>,
+    theme: &Theme,
+) -> Result<Option<(String, String)>, Box<dyn Error>> {
+    let mut last_frame = None;
+    loop {
+        let picked_room = match room {
+            Some(room) => room.to_string(),
+            None => {
+                let rooms = client::list_rooms(addr).await?;
+                let picker = Picker::new("room", rooms);
+                match pick(picker, ui_rx, theme, &mut last_frame).await? {
+                    Some(room) => room,
+                    None => return Ok(None),
+                }
             }
+        };
+        if let Some(doc) = doc {
+            return Ok(Some((picked_room, doc.to_string())));
         }
-    });
+        let docs = client::list_docs(addr, &picked_room).await?;
+        let picker = Picker::new("doc", docs);
+        match pick(picker, ui_rx, theme, &mut last_frame).await? {
+            Some(doc) => return Ok(Some((picked_room, doc))),
+            None if room.is_none() => {}
+            None => return Ok(None),
+        }
+    }
+}
 
-    let doc_id = format!("{}/{}", room, doc);
-    let raw_user_id = format!("{}-{}", user, unique_suffix());
-    let scoped_user_id = make_scoped_user_id(&doc_id, &raw_user_id);
-    let mut doc_state = TextDoc::new(doc_id.clone(), scoped_user_id.clone());
-    let local_user_id: Option<String> = Some(scoped_user_id.clone());
-    let awareness = Awareness::new(scoped_user_id.clone(), user.to_string());
+/// Shows `picker` on a screen of its own until something is picked.
+async fn pick(
+    mut picker: Picker,
+    ui_rx: &mut mpsc::UnboundedReceiver<UiEvent>,
+    theme: &Theme,
+    last_frame: &mut Option<Screen>,
+) -> Result<Option<String>, Box<dyn Error>> {
+    loop {
+        let (cols, rows) = terminal::size()?;
+        let (cols, rows) = (cols as usize, rows as usize);
+        let mut frame = Screen::new(cols, rows);
+        let status_row = rows.saturating_sub(1);
+        picker.render(&mut frame, (0, status_row, cols), theme.selection);
+        let line = clip_line(&picker.prompt().line(), cols);
+        frame.put(0, status_row, &format!("{:<cols$}", line), theme.status);
+        let col = picker.prompt().cursor_col().min(cols.saturating_sub(1));
+        show(frame, Some((col as u16, status_row as u16)), last_frame)?;
 
-    out_tx
-        .send(Message::Hello {
-            replica_id: scoped_user_id.clone(),
-            user_name: user.to_string(),
+        let Some(event) = ui_rx.recv().await else {
+            return Ok(None);
+        };
+        match event {
+            UiEvent::Key(key) if key.kind != KeyEventKind::Release => {
+                match picker.handle_key(key) {
+                    Picked::Entry(name) | Picked::New(name) => return Ok(Some(name)),
+                    Picked::Cancel => return Ok(None),
+                    Picked::Pending => {}
+                }
+            }
+            UiEvent::Resize => *last_frame = None,
+            _ => {}
+        }
+    }
+}
+
+/// Text of a key burst long enough to count as a paste.
+fn burst_text(keys: &[KeyEvent]) -> Option<String> {
+    if keys.len() <= PASTE_BURST_MIN_CHARS {
+        return None;
+    }
+    let text = keys
+        .iter()
+        .filter_map(|key| match key.code {
+            KeyCode::Char(ch) => Some(ch),
+            KeyCode::Enter => Some('\n'),
+            KeyCode::Tab => Some('\t'),
+            _ => None,
         })
-        .await?;
-    out_tx.send(encode_sync_request(&doc_id, 0)).await?;
+        .collect();
+    Some(text)
+}
+
+/// Runs the editor on `room`/`doc`, first asking for whichever is `None`
+/// from what the server has.
+pub async fn run(
+    addr: &str,
+    user: &str,
+    room: Option<&str>,
+    doc: Option<&str>,
+    options: Options,
+) -> Result<(), Box<dyn Error>> {
+    tty::install_panic_hook();
+    match tty::catch_unwind(run_session(addr, user, room, doc, options)).await {
+        Ok(result) => result,
+        // The hook has restored the terminal and printed the panic.
+        Err(_) => Err("the TUI crashed unexpectedly".into()),
+    }
+}
+
+async fn run_session(
+    addr: &str,
+    user: &str,
+    room: Option<&str>,
+    doc: Option<&str>,
+    options: Options,
+) -> Result<(), Box<dyn Error>> {
+    let Options {
+        mouse,
+        outage_input,
+        mut theme,
+        theme_config,
+        cursors: cursor_config,
+        view: view_config,
+        editing,
+        spellcheck,
+        bindings,
+        debug_log,
+        backup,
+        chunk_bytes,
+        network,
+        auto_sync,
+        record,
+    } = options;
+    let started = Instant::now();
+    let mut debug_log = match debug_log {
+        Some(path) => {
+            let file = File::options().create(true).append(true).open(&path);
+            let file = file.map_err(|err| format!("{}: {}", path.display(), err))?;
+            Some((BufWriter::new(file), started))
+        }
+        None => None,
+    };
 
-    let _term = TerminalGuard::new()?;
+    let _term = TerminalGuard::new(mouse)?;
 
     let (ui_tx, mut ui_rx) = mpsc::unbounded_channel::<UiEvent>();
-    tokio::task::spawn_blocking(move || {
-        loop {
-            match event::read() {
-                Ok(Event::Key(key)) => {
-                    if ui_tx.send(UiEvent::Key(key)).is_err() {
-                        break;
-                    }
-                }
-                Ok(Event::Resize(_, _)) => {
-                    if ui_tx.send(UiEvent::Resize).is_err() {
-                        break;
-                    }
-                }
-                Ok(_) => {}
-                Err(_) => break,
-            }
+    // Used to feed input typed during an outage back in after resyncing.
+    let replay_tx = ui_tx.clone();
+    #[cfg(feature = "spellcheck")]
+    let mut speller = spellcheck
+        .enabled
+        .then(|| spell::Speller::start(&spellcheck, replay_tx.clone()));
+    #[cfg(not(feature = "spellcheck"))]
+    let _ = spellcheck;
+    tokio::task::spawn_blocking(move || read_input(&ui_tx));
+
+    let (room, doc) = match (room, doc) {
+        (Some(room), Some(doc)) => (room.to_string(), doc.to_string()),
+        _ => match pick_doc(addr, room, doc, &mut ui_rx, &theme).await? {
+            Some(picked) => picked,
+            None => return Ok(()),
+        },
+    };
+    let (room, doc) = (room.as_str(), doc.as_str());
+    // Without a name the server makes one up, announced in its welcome.
+    let prefix = if user.is_empty() { "guest" } else { user };
+    let raw_user_id = format!("{}-{}", prefix, unique_suffix());
+    let join_doc = |doc: &str| {
+        let doc_id = format!("{}/{}", room, doc);
+        JoinInfo {
+            addr: addr.to_string(),
+            user_id: make_scoped_user_id(&doc_id, &raw_user_id),
+            user_name: user.to_string(),
+            doc_id,
+            network,
+            record: record.clone(),
         }
-    });
+    };
+    let backup_dir = if backup { backup::default_dir() } else { None };
+    let new_backup = |doc: &str| {
+        let dir = backup_dir.as_deref()?;
+        Some(Backup::new(dir, room, doc, Instant::now()))
+    };
+    let mut first = Buffer::connect(join_doc(doc), doc, outage_input).await?;
+    first.backup = new_backup(doc);
+    first.sync = SyncSchedule::new(auto_sync, Instant::now());
+    let mut buffers = vec![first];
+    let mut active = 0usize;
 
-    let mut server_lines = BufReader::new(reader).lines();
-
-    let mut version = 0u64;
-    let mut users_count = 0usize;
-    let mut cursor_byte = 0usize;
-    let mut scroll = 0usize;
-    let mut status_msg = String::new();
-    let mut users: HashMap<String, String> = HashMap::new();
-    let mut cursors: HashMap<String, usize> = HashMap::new();
-
-    let mut render_ctx = RenderContext {
-        addr,
-        room,
-        doc,
-        text: &doc_state.get_text(),
-        cursor_byte,
-        users_count,
-        version,
-        status_msg: &status_msg,
-        scroll: &mut scroll,
-        cursors: &cursors,
-        users: &users,
-        local_user_id: local_user_id.as_deref(),
-    };
-    render(&mut render_ctx)?;
+    let mut clipboard = Clipboard::new();
+    let mut search: Option<SearchState> = None;
+    let mut command_prompt: Option<CommandPrompt> = None;
+    let mut sidebar_open = false;
+    let mut activity_open = false;
+    let mut show_invisibles = false;
+    let mut overwrite = false;
+    let mut last_query = String::new();
+    let mut status = StatusLog::default();
+    // Scroll offset of the message log overlay (F10) while it is open.
+    let mut log_scroll: Option<usize> = None;
+    let mut debug_overlay = false;
+    let mut help_open = false;
+    // The `:stats` table of a doc, shown until a key is pressed.
+    let mut stats_open: Option<(String, Vec<String>)> = None;
+    // The `:docs` list while it is open.
+    let mut picker: Option<Picker> = None;
+    // The doc to snapshot once its sent edits are confirmed.
+    let mut snapshot_wanted: Option<String> = None;
+    // Set while quitting waits for edits to be confirmed: when it stops
+    // waiting and asks instead.
+    let mut quit_wait: Option<Instant> = None;
+    // When closing the connections gives up on queued messages.
+    let mut drain_until = Instant::now();
+    let mut metrics = Metrics::new(started);
+    let mut render_tick = tokio::time::interval(RENDER_TICK);
+    // Last frame sent to the terminal; `None` forces a full redraw.
+    let mut last_frame: Option<Screen> = None;
+    let mut dirty = true;
+    // Connection state drawn with the last frame.
+    let mut shown_link = None;
 
     loop {
-        let mut dirty = false;
+        #[cfg(feature = "spellcheck")]
+        if let Some(err) = speller.as_mut().and_then(spell::Speller::collect) {
+            status.error(format!("spellcheck off: {}", err));
+            speller = None;
+            dirty = true;
+        }
+        if dirty {
+            let tabs = if buffers.len() > 1 {
+                buffer::tab_labels(&buffers, active)
+            } else {
+                Vec::new()
+            };
+            let buffer = &mut buffers[active];
+            let disconnected_banner = disconnected_banner(buffer);
+            let history_banner = buffer.history.as_ref().map(History::banner);
+            let live_text = buffer.doc_state.get_text();
+            let debug = debug_overlay.then(|| debug_stats(&metrics, buffer, &live_text));
+            shown_link = Some(buffer.link_state(Instant::now()));
+            // A revision shown replaces the text, the view and other
+            // people's cursors.
+            let (text, cursor_byte, selection, scroll, hscroll) = match buffer.history.as_mut() {
+                Some(history) => (
+                    history.text().unwrap_or_default().to_string(),
+                    history.cursor_byte,
+                    None,
+                    &mut history.scroll,
+                    &mut history.hscroll,
+                ),
+                None => (
+                    live_text,
+                    buffer.cursor_byte,
+                    selection_range(buffer.selection_anchor, buffer.cursor_byte),
+                    &mut buffer.scroll,
+                    &mut buffer.hscroll,
+                ),
+            };
+            let viewing_history = history_banner.is_some();
+            let no_cursors = HashMap::new();
+            let no_selections = HashMap::new();
+            let no_flashes = HashMap::new();
+            let mut render_ctx = RenderContext {
+                addr,
+                room,
+                doc: &buffer.doc,
+                text: &text,
+                cursor_byte,
+                selection,
+                users_count: buffer.users.len(),
+                version: buffer.version,
+                limits: buffer.limits,
+                status: &status,
+                log_scroll,
+                scroll,
+                hscroll,
+                free_scroll: buffer.free_scroll && !viewing_history,
+                search: search.as_ref(),
+                command_prompt: command_prompt.as_ref(),
+                sidebar_open,
+                feed: activity_open.then_some(&buffer.feed),
+                scrollbar: view_config.scrollbar,
+                gutter: view_config.gutter,
+                #[cfg(feature = "markdown")]
+                markdown: view_config.markdown && markdown::is_markdown_doc(&buffer.doc),
+                #[cfg(feature = "spellcheck")]
+                speller: speller.as_mut(),
+                show_invisibles,
+                overwrite,
+                following: buffer.following.as_deref(),
+                disconnected: disconnected_banner.as_deref(),
+                history: history_banner.as_deref(),
+                flash_line: buffer
+                    .flash_line
+                    .filter(|(_, at)| !viewing_history && at.elapsed() <= FLASH_DURATION)
+                    .map(|(line, _)| line),
+                cursors: if viewing_history {
+                    &no_cursors
+                } else {
+                    &buffer.cursors
+                },
+                selections: if viewing_history {
+                    &no_selections
+                } else {
+                    &buffer.selections
+                },
+                last_activity: &buffer.last_activity,
+                edit_flashes: if viewing_history {
+                    &no_flashes
+                } else {
+                    &buffer.edit_flashes
+                },
+                cursor_config,
+                users: &buffer.users,
+                guests: &buffer.guests,
+                local_user_id: Some(buffer.join.user_id.as_str()),
+                theme: &theme,
+                tabs: &tabs,
+                link: shown_link,
+                debug,
+                help: help_open,
+                bindings: &bindings,
+                stats: stats_open
+                    .as_ref()
+                    .map(|(doc, rows)| (doc.as_str(), rows.as_slice())),
+                picker: picker.as_ref(),
+            };
+            let render_started = Instant::now();
+            render(&mut render_ctx, &mut last_frame)?;
+            metrics.frame_rendered(render_started.elapsed());
+            dirty = false;
+        }
+
         let mut should_exit = false;
+        let typing_flush = buffers[active].coalescer.deadline();
+        let now = Instant::now();
+        let upload_due = buffers
+            .iter()
+            .filter_map(|buffer| buffer.upload_due(now))
+            .min();
         tokio::select! {
-            line = server_lines.next_line() => {
-                let line = match line {
-                    Ok(Some(line)) => line,
-                    Ok(None) => {
-                        status_msg = "server closed connection".to_string();
+            _ = sleep_until(typing_flush), if typing_flush.is_some() => {
+                buffers[active].flush_typing();
+            }
+            _ = sleep_until(upload_due), if upload_due.is_some() => {
+                let now = Instant::now();
+                for (idx, buffer) in buffers.iter_mut().enumerate() {
+                    let mut background_status = StatusLog::default();
+                    let target = if idx == active {
+                        &mut status
+                    } else {
+                        &mut background_status
+                    };
+                    dirty |= buffer.send_upload(now, target) && idx == active;
+                }
+            }
+            _ = render_tick.tick() => {
+                // Only redraw while some cursor label is still due to
+                // disappear or a cursor to fade, something flashes, a status
+                // message expires, message ages tick in the log, or to count
+                // down to the next reconnect.
+                let now = Instant::now();
+                dirty = buffers[active].animating(
+                    now,
+                    CURSOR_LABEL_TTL.max(cursor_config.fade_after()) + RENDER_TICK,
+                    FLASH_DURATION.max(cursor_config.edit_flash()) + RENDER_TICK,
+                ) | status.expire(now)
+                    | log_scroll.is_some()
+                    | debug_overlay;
+                for buffer in &mut buffers {
+                    buffer.probe(now);
+                    buffer.auto_sync(now);
+                }
+                let buffer = &mut buffers[active];
+                if buffer.history.as_ref().is_some_and(|history| history.idle(now)) {
+                    buffer.leave_history(&mut status);
+                    status.info("history closed after a while without a key");
+                    dirty = true;
+                }
+                #[cfg(feature = "spellcheck")]
+                if let Some(speller) = speller.as_mut() {
+                    speller.send_wanted(now);
+                }
+                dirty |= shown_link != Some(buffers[active].link_state(now));
+                if debug_overlay || debug_log.is_some() {
+                    let buffer = &mut buffers[active];
+                    buffer.ping(now);
+                    metrics.update_rates(now, connection::messages_sent());
+                }
+                if let Some((file, last)) = debug_log.as_mut()
+                    && now.duration_since(*last) >= DEBUG_LOG_INTERVAL
+                {
+                    *last = now;
+                    let buffer = &buffers[active];
+                    let stats = debug_stats(&metrics, buffer, &buffer.doc_state.get_text());
+                    let line = stats.log_line(now.duration_since(started));
+                    if let Err(err) = writeln!(file, "{}", line).and_then(|()| file.flush()) {
+                        status.error(format!("debug log stopped: {}", err));
+                        debug_log = None;
                         dirty = true;
-                        should_exit = true;
-                        String::new()
                     }
-                    Err(err) => {
-                        status_msg = format!("read error: {}", err);
-                        dirty = true;
-                        should_exit = true;
-                        String::new()
+                }
+                for buffer in &mut buffers {
+                    if buffer.backup.as_ref().is_some_and(|backup| backup.due(now)) {
+                        dirty |= save_backup(buffer, now, &mut status);
                     }
-                };
-
-                if should_exit {
-                    // Skip parsing when connection closed or errored.
+                }
+            }
+            (idx, event) = buffer::next_event(&mut buffers) => {
+                // Background buffers report through their tab instead of the
+                // status row.
+                let mut background_status = StatusLog::default();
+                let target = if idx == active {
+                    &mut status
                 } else {
-                    let msg: Message = match serde_json::from_str(&line) {
-                        Ok(msg) => msg,
-                        Err(_) => continue,
+                    &mut background_status
+                };
+                let buffer = &mut buffers[idx];
+                if let NetEvent::Line(Ok(Some(_))) = &event {
+                    metrics.message_received();
+                }
+                dirty = match event {
+                    NetEvent::Line(line) => buffer.handle_line(line, target),
+                    NetEvent::Reconnected(result) => {
+                        buffer.handle_reconnect(result, target);
+                        true
+                    }
+                };
+                for line in std::mem::take(&mut buffer.malformed) {
+                    metrics.malformed_received();
+                    let Some((file, _)) = debug_log.as_mut() else {
+                        continue;
                     };
-
-                    match msg {
-                        Message::Hello { replica_id, user_name } => {
-                            if doc_id_from_scoped_user_id(&replica_id) == Some(doc_id.as_str()) {
-                                users.insert(replica_id, user_name);
-                                users_count = users.len();
-                                dirty = true;
-                            }
+                    let uptime = started.elapsed();
+                    let line = format!("t={:.1}s malformed server line: {}", uptime.as_secs_f64(), line);
+                    if let Err(err) = writeln!(file, "{}", line).and_then(|()| file.flush()) {
+                        status.error(format!("debug log stopped: {}", err));
+                        debug_log = None;
+                        dirty = true;
+                    }
+                }
+            }
+            ui_event = ui_rx.recv() => {
+                let Some(ui_event) = ui_event else { break; };
+                if let UiEvent::Snapshot(doc, result) = ui_event {
+                    match result {
+                        Ok((version, bytes)) => status.info(format!(
+                            "snapshot of {} saved at v{} ({} bytes)",
+                            doc, version, bytes
+                        )),
+                        Err(err) => status.error(format!("snapshot of {} failed: {}", doc, err)),
+                    }
+                    dirty = true;
+                    continue;
+                }
+                if let UiEvent::DocStats(doc, result) = ui_event {
+                    match result {
+                        Ok(stats) if stats.is_empty() => {
+                            status.info(format!("no edits to {} recorded yet", doc))
                         }
-                        Message::Update { .. } => {
-                            if let Some((update_doc_id, payload, server_version)) = decode_update(&msg)
-                                && update_doc_id == doc_id
-                            {
-                                if Some(payload.user_id.clone()) != local_user_id {
-                                    // Treat `op` as the single source of truth for remote edits.
-                                    // Ignore `payload.delta` to avoid double-applying changes.
-                                    apply_op_to_doc(&mut doc_state, &payload.op);
-                                    adjust_cursor_for_remote(&payload.op, &mut cursor_byte);
-                                }
-                                version = server_version;
-                                cursor_byte = cursor_byte.min(doc_state.get_text().len());
-                                dirty = true;
+                        Ok(stats) => {
+                            let mut table = client::stats_table(&stats);
+                            if metrics.malformed() > 0 {
+                                table.push(String::new());
+                                table.push(format!("{} malformed lines from the server", metrics.malformed()));
                             }
+                            stats_open = Some((doc, table));
+                        }
+                        Err(err) => status.error(format!("stats of {} failed: {}", doc, err)),
+                    }
+                    dirty = true;
+                    continue;
+                }
+                if let UiEvent::Docs(result) = ui_event {
+                    match result {
+                        Ok(docs) => picker = Some(Picker::new("doc", docs)),
+                        Err(err) => status.error(format!("listing docs failed: {}", err)),
+                    }
+                    dirty = true;
+                    continue;
+                }
+                if let UiEvent::History(doc, reply) = ui_event {
+                    if let Some(buffer) = buffers.iter_mut().find(|buffer| buffer.doc == doc) {
+                        history_reply(buffer, reply, &mut status);
+                    }
+                    dirty = true;
+                    continue;
+                }
+                let bound = match &ui_event {
+                    UiEvent::Key(key) if key.kind != KeyEventKind::Release => bindings.action(key),
+                    _ => None,
+                };
+                let tab_action = match bound {
+                    Some(action)
+                        if search.is_none() && command_prompt.is_none() && picker.is_none() =>
+                    {
+                        tab_key_action(action)
+                    }
+                    _ => None,
+                };
+                let content_top = usize::from(buffers.len() > 1);
+                let buffer = &mut buffers[active];
+                if ends_typing(&ui_event) {
+                    buffer.flush_typing();
+                }
+                #[cfg(feature = "spellcheck")]
+                if let (Some(speller), UiEvent::Key(_) | UiEvent::Paste(_)) =
+                    (speller.as_mut(), &ui_event)
+                {
+                    speller.input(Instant::now());
+                }
+                let action = if let Some(action) = tab_action {
+                    action
+                } else if bound == Some(Action::Suspend) {
+                    KeyAction::Suspend
+                } else if let UiEvent::Key(key) = &ui_event
+                    && quit_wait.is_some()
+                {
+                    match confirm_quit_key(key, bound == Some(Action::Quit)) {
+                        Some(true) => KeyAction::ForceQuit,
+                        Some(false) => {
+                            quit_wait = None;
+                            command_prompt = None;
+                            status.info("quit cancelled");
+                            KeyAction::Redraw
                         }
-                        Message::Presence { user_id, document_id, cursor_pos } => {
-                            if document_id == doc_id {
-                                match cursor_pos {
-                                    Some(pos) => {
-                                        cursors.insert(user_id, pos);
-                                    }
-                                    None => {
-                                        users.remove(&user_id);
-                                        cursors.remove(&user_id);
-                                    }
-                                }
-                                users_count = users.len();
-                                dirty = true;
+                        None => KeyAction::Ignored,
+                    }
+                } else if let (UiEvent::Key(key), Some(open)) = (&ui_event, picker.as_mut()) {
+                    if key.kind == KeyEventKind::Release {
+                        KeyAction::Ignored
+                    } else {
+                        match open.handle_key(*key) {
+                            Picked::Pending => KeyAction::Redraw,
+                            Picked::Entry(doc) | Picked::New(doc) => {
+                                picker = None;
+                                KeyAction::OpenDoc(doc)
+                            }
+                            Picked::Cancel => {
+                                picker = None;
+                                KeyAction::Redraw
                             }
                         }
-                        Message::SyncResponse { .. } => {
-                            if let Some((sync_doc_id, payload, server_version)) = decode_sync_response(&msg)
-                                && sync_doc_id == doc_id
-                            {
-                                doc_state = build_doc(&doc_id, &scoped_user_id, &payload.text);
-                                version = server_version;
-                                cursor_byte = cursor_byte.min(payload.text.len());
-                                users.clear();
-                                for user in payload.users {
-                                    users.insert(user.id, user.name);
-                                }
-                                users_count = users.len();
-                                status_msg = "sync complete".to_string();
-                                dirty = true;
+                    }
+                } else if let UiEvent::Key(key) = &ui_event
+                    && (help_open || stats_open.is_some())
+                {
+                    // Any key closes the command list or the stats.
+                    if key.kind != KeyEventKind::Release {
+                        help_open = false;
+                        stats_open = None;
+                    }
+                    KeyAction::Redraw
+                } else if let UiEvent::Key(key) = &ui_event
+                    && key.kind != KeyEventKind::Release
+                    && (log_scroll.is_some() || bound == Some(Action::Log))
+                {
+                    handle_log_key(key, bound == Some(Action::Log), &mut log_scroll, status.len())
+                } else if bound == Some(Action::Debug) {
+                    debug_overlay = !debug_overlay;
+                    KeyAction::Redraw
+                } else if buffer.history.is_some() {
+                    // Read-only while a revision is shown.
+                    match ui_event {
+                        UiEvent::Key(key) if key.kind == KeyEventKind::Release => KeyAction::Ignored,
+                        UiEvent::Key(_) if bound == Some(Action::Quit) => KeyAction::Quit,
+                        UiEvent::Key(key) => handle_history_key(key, bound, buffer, &mut status),
+                        UiEvent::Mouse(_) | UiEvent::Paste(_) => KeyAction::Ignored,
+                        UiEvent::Redraw
+                        | UiEvent::Snapshot(..)
+                        | UiEvent::DocStats(..)
+                        | UiEvent::Docs(..)
+                        | UiEvent::History(..) => KeyAction::Redraw,
+                        UiEvent::Resize => {
+                            last_frame = None;
+                            KeyAction::Redraw
+                        }
+                    }
+                } else if bound == Some(Action::History)
+                    && search.is_none()
+                    && command_prompt.is_none()
+                {
+                    KeyAction::History
+                } else if buffer.upload.is_some() {
+                    // Nothing else is edited while a paste goes out: Esc
+                    // cancels the rest, the quit key quits.
+                    match ui_event {
+                        UiEvent::Key(key) if key.kind == KeyEventKind::Release => KeyAction::Ignored,
+                        UiEvent::Key(key) if key.code == KeyCode::Esc => {
+                            buffer.cancel_upload(&mut status);
+                            KeyAction::Redraw
+                        }
+                        UiEvent::Key(_) if bound == Some(Action::Quit) => KeyAction::Quit,
+                        UiEvent::Key(_) | UiEvent::Paste(_) => {
+                            status.info("still pasting; Esc cancels");
+                            KeyAction::Redraw
+                        }
+                        UiEvent::Mouse(_) => KeyAction::Ignored,
+                        UiEvent::Redraw
+                        | UiEvent::Snapshot(..)
+                        | UiEvent::DocStats(..)
+                        | UiEvent::Docs(..)
+                        | UiEvent::History(..) => KeyAction::Redraw,
+                        UiEvent::Resize => {
+                            last_frame = None;
+                            KeyAction::Redraw
+                        }
+                    }
+                } else if buffer.is_offline() {
+                    // Read-only until synced again: Esc / the quit key quit,
+                    // other input is buffered per `--outage-input`.
+                    match ui_event {
+                        UiEvent::Key(key) if key.kind == KeyEventKind::Release => KeyAction::Ignored,
+                        UiEvent::Key(key)
+                            if key.code == KeyCode::Esc || bound == Some(Action::Quit) =>
+                        {
+                            KeyAction::Quit
+                        }
+                        UiEvent::Key(_) | UiEvent::Paste(_) => {
+                            buffer.outage.push(ui_event);
+                            KeyAction::Redraw
+                        }
+                        UiEvent::Mouse(_) => KeyAction::Ignored,
+                        UiEvent::Redraw
+                        | UiEvent::Snapshot(..)
+                        | UiEvent::DocStats(..)
+                        | UiEvent::Docs(..)
+                        | UiEvent::History(..) => KeyAction::Redraw,
+                        UiEvent::Resize => {
+                            last_frame = None;
+                            KeyAction::Redraw
+                        }
+                    }
+                } else {
+                    let mut key_ctx = KeyContext {
+                        doc_state: &mut buffer.doc_state,
+                        cursor_byte: &mut buffer.cursor_byte,
+                        selection_anchor: &mut buffer.selection_anchor,
+                        clipboard: &mut clipboard,
+                        undo: &mut buffer.undo,
+                        scroll: &mut buffer.scroll,
+                        hscroll: buffer.hscroll,
+                        free_scroll: &mut buffer.free_scroll,
+                        search: &mut search,
+                        command_prompt: &mut command_prompt,
+                        last_query: &mut last_query,
+                        sidebar_open: &mut sidebar_open,
+                        activity_open: &mut activity_open,
+                        scrollbar: view_config.scrollbar,
+                        gutter: view_config.gutter,
+                        line_endings: editing.line_endings,
+                        smart_end: editing.smart_end,
+                        show_invisibles: &mut show_invisibles,
+                        overwrite: &mut overwrite,
+                        following: &mut buffer.following,
+                        cursors: &mut buffer.cursors,
+                        selections: &mut buffer.selections,
+                        edit_flashes: &mut buffer.edit_flashes,
+                        users: &buffer.users,
+                        out_tx: &buffer.out_tx,
+                        doc_id: &buffer.join.doc_id,
+                        local_user_id: Some(buffer.join.user_id.as_str()),
+                        version: buffer.version,
+                        limits: buffer.limits,
+                        read_only: buffer.read_only,
+                        pending: &mut buffer.pending,
+                        sync: &mut buffer.sync,
+                        coalescer: &mut buffer.coalescer,
+                        upload: &mut buffer.upload,
+                        chunk_bytes,
+                        awareness: &buffer.awareness,
+                        status: &mut status,
+                        flash_line: &mut buffer.flash_line,
+                        content_top,
+                        bound,
+                        #[cfg(feature = "spellcheck")]
+                        speller: speller.as_mut(),
+                    };
+                    match ui_event {
+                        UiEvent::Key(key) if key.kind == KeyEventKind::Release => KeyAction::Ignored,
+                        UiEvent::Key(key) => handle_key(key, &mut key_ctx),
+                        UiEvent::Mouse(mouse) => {
+                            if handle_mouse(mouse, &mut key_ctx)? {
+                                KeyAction::Redraw
+                            } else {
+                                KeyAction::Ignored
                             }
                         }
-                        Message::Ack { .. } | Message::Ping | Message::Pong | Message::SyncRequest { .. } => {}
+                        UiEvent::Paste(text) => {
+                            handle_paste(&text, &mut key_ctx);
+                            KeyAction::Redraw
+                        }
+                        UiEvent::Redraw
+                        | UiEvent::Snapshot(..)
+                        | UiEvent::DocStats(..)
+                        | UiEvent::Docs(..)
+                        | UiEvent::History(..) => KeyAction::Redraw,
+                        UiEvent::Resize => {
+                            last_frame = None;
+                            KeyAction::Redraw
+                        }
                     }
-                }
-            }
-            ui_event = ui_rx.recv() => {
-                let Some(ui_event) = ui_event else { break; };
-                match ui_event {
-                    UiEvent::Key(key) => {
-                        if key.kind == KeyEventKind::Release {
-                            continue;
+                };
+                match action {
+                    KeyAction::Ignored => {}
+                    KeyAction::Redraw => dirty = true,
+                    KeyAction::Quit => {
+                        for buffer in &mut buffers {
+                            buffer.flush_typing();
                         }
-                        let mut key_ctx = KeyContext {
-                            doc_state: &mut doc_state,
-                            cursor_byte: &mut cursor_byte,
-                            out_tx: &out_tx,
-                            doc_id: &doc_id,
-                            local_user_id: local_user_id.as_deref(),
-                            version,
-                            awareness: &awareness,
-                            status_msg: &mut status_msg,
-                        };
-                        if handle_key(key, &mut key_ctx) {
+                        let unconfirmed = unconfirmed_edits(&buffers);
+                        if unconfirmed == 0 {
+                            should_exit = true;
+                            drain_until = Instant::now() + QUIT_WAIT;
+                        } else {
+                            quit_wait = Some(Instant::now() + QUIT_WAIT);
+                            command_prompt = Some(CommandPrompt::confirm_quit(unconfirmed, true));
                             dirty = true;
-                            if key.code == KeyCode::Esc || (key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('q')) {
-                                should_exit = true;
+                        }
+                    }
+                    KeyAction::ForceQuit => should_exit = true,
+                    KeyAction::SetTheme(name) => {
+                        match Theme::resolve(Some(name), &theme_config) {
+                            Ok(resolved) => {
+                                theme = resolved;
+                                last_frame = None;
                             }
+                            Err(err) => status.error(format!("theme: {}", err)),
                         }
+                        dirty = true;
+                    }
+                    KeyAction::Help => {
+                        help_open = true;
+                        dirty = true;
+                    }
+                    KeyAction::Snapshot => {
+                        let buffer = &buffers[active];
+                        if buffer.unconfirmed_edits() > 0 {
+                            status.info(format!(
+                                "snapshot once {} sent edits are confirmed",
+                                buffer.unconfirmed_edits()
+                            ));
+                        }
+                        snapshot_wanted = Some(buffer.doc.clone());
+                        dirty = true;
+                    }
+                    KeyAction::DocStats => {
+                        let doc = buffers[active].doc.clone();
+                        let user = buffers[active].user_name();
+                        let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+                        tokio::spawn(async move {
+                            let result = client::doc_stats(&addr, &room, &doc, &user).await;
+                            let result = result.map_err(|err| err.to_string());
+                            let _ = tx.send(UiEvent::DocStats(doc, result));
+                        });
+                    }
+                    KeyAction::PickDoc => {
+                        let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+                        tokio::spawn(async move {
+                            let result = client::list_docs(&addr, &room).await;
+                            let _ = tx.send(UiEvent::Docs(result.map_err(|err| err.to_string())));
+                        });
                     }
-                    UiEvent::Resize => {
+                    KeyAction::History => {
+                        let doc = buffers[active].doc.clone();
+                        status.info(format!("fetching the revisions of {}…", doc));
+                        let user = buffers[active].user_name();
+                        let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+                        tokio::spawn(async move {
+                            let result = client::list_revisions(&addr, &room, &doc, &user).await;
+                            let reply = Reply::Revisions(result.map_err(|err| err.to_string()));
+                            let _ = tx.send(UiEvent::History(doc, reply));
+                        });
+                        dirty = true;
+                    }
+                    KeyAction::Restore(version) => {
+                        let buffer = &buffers[active];
+                        let doc = buffer.doc.clone();
+                        let user = buffer.user_name();
+                        let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+                        tokio::spawn(async move {
+                            let result =
+                                client::restore_revision(&addr, &room, &doc, version, &user).await;
+                            let reply = Reply::Restored(version, result.map_err(|err| err.to_string()));
+                            let _ = tx.send(UiEvent::History(doc, reply));
+                        });
+                        dirty = true;
+                    }
+                    KeyAction::Suspend => {
+                        tty::suspend()?;
+                        last_frame = None;
+                        dirty = true;
+                    }
+                    KeyAction::OpenDoc(doc) => {
+                        let idx = match buffers.iter().position(|buffer| buffer.doc == doc) {
+                            Some(idx) => idx,
+                            None => {
+                                let mut buffer = Buffer::new(join_doc(&doc), &doc, outage_input);
+                                buffer.backup = new_backup(&doc);
+                                buffer.sync = SyncSchedule::new(auto_sync, Instant::now());
+                                buffers.push(buffer);
+                                buffers.len() - 1
+                            }
+                        };
+                        switch_buffer(&mut buffers, &mut active, idx, &mut status);
+                        dirty = true;
+                    }
+                    KeyAction::NextBuffer | KeyAction::PrevBuffer => {
+                        let count = buffers.len();
+                        let idx = match action {
+                            KeyAction::NextBuffer => (active + 1) % count,
+                            _ => (active + count - 1) % count,
+                        };
+                        switch_buffer(&mut buffers, &mut active, idx, &mut status);
+                        dirty = true;
+                    }
+                    KeyAction::CloseBuffer => {
+                        // Dropping the buffer closes its connection, which
+                        // leaves the document on the server.
+                        let mut closed = buffers.remove(active);
+                        closed.flush_typing();
+                        save_backup(&mut closed, Instant::now(), &mut status);
+                        if buffers.is_empty() {
+                            should_exit = true;
+                        } else {
+                            let idx = active.min(buffers.len() - 1);
+                            switch_buffer(&mut buffers, &mut active, idx, &mut status);
+                            status.info(format!("closed {}", closed.doc));
+                        }
+                        // The tab bar may have disappeared.
+                        last_frame = None;
                         dirty = true;
                     }
                 }
             }
         }
 
-        if dirty {
-            let mut render_ctx = RenderContext {
-                addr,
-                room,
-                doc,
-                text: &doc_state.get_text(),
-                cursor_byte,
-                users_count,
-                version,
-                status_msg: &status_msg,
-                scroll: &mut scroll,
-                cursors: &cursors,
-                users: &users,
-                local_user_id: local_user_id.as_deref(),
-            };
-            render(&mut render_ctx)?;
+        if let Some(doc) = snapshot_wanted.take() {
+            match buffers.iter().find(|buffer| buffer.doc == doc) {
+                Some(buffer) if buffer.unconfirmed_edits() > 0 => snapshot_wanted = Some(doc),
+                Some(buffer) => {
+                    let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+                    let user = buffer
+                        .guest_name
+                        .clone()
+                        .unwrap_or_else(|| user.to_string());
+                    tokio::spawn(async move {
+                        let result = client::snapshot(&addr, &room, &doc, &user).await;
+                        let result = result.map_err(|err| err.to_string());
+                        let _ = tx.send(UiEvent::Snapshot(doc, result));
+                    });
+                }
+                // Closed meanwhile.
+                None => {}
+            }
+        }
+
+        if let Some(buffer) = buffers.get_mut(active)
+            && let Some(version) = buffer.history.as_mut().and_then(History::wanted)
+        {
+            let (doc, user) = (buffer.doc.clone(), buffer.user_name());
+            let (addr, room, tx) = (addr.to_string(), room.to_string(), replay_tx.clone());
+            tokio::spawn(async move {
+                let result = client::revision_text(&addr, &room, &doc, version, &user).await;
+                let reply = Reply::Text(version, result.map_err(|err| err.to_string()));
+                let _ = tx.send(UiEvent::History(doc, reply));
+            });
+        }
+
+        if let Some(deadline) = quit_wait
+            && !should_exit
+        {
+            let unconfirmed = unconfirmed_edits(&buffers);
+            if unconfirmed == 0 {
+                should_exit = true;
+                drain_until = Instant::now() + QUIT_WAIT;
+            } else {
+                let prompt = CommandPrompt::confirm_quit(unconfirmed, Instant::now() < deadline);
+                if command_prompt.as_ref().map(|shown| shown.prompt.line())
+                    != Some(prompt.prompt.line())
+                {
+                    command_prompt = Some(prompt);
+                    dirty = true;
+                }
+            }
         }
 
         if should_exit {
+            for buffer in &mut buffers {
+                buffer.flush_typing();
+                // Too late to report a failure; the terminal is going away.
+                let _ = buffer.save_backup(Instant::now());
+            }
             break;
         }
+
+        let buffer = &mut buffers[active];
+        if let Some((replay, note)) = buffer.take_replay() {
+            status.info(note);
+            for event in replay {
+                let _ = replay_tx.send(event);
+            }
+            dirty = true;
+        }
+        if search.is_none()
+            && command_prompt.is_none()
+            && !buffer.is_offline()
+            && let Some(contents) = buffer.restore_offer.take()
+        {
+            command_prompt = Some(CommandPrompt::restore_backup(&buffer.doc, contents));
+            dirty = true;
+        }
+        buffer.sync_selection();
     }
 
-    writer_task.abort();
+    for buffer in buffers {
+        buffer.close(drain_until).await;
+    }
     Ok(())
 }
 
-struct KeyContext<'a> {
-    doc_state: &'a mut TextDoc,
-    cursor_byte: &'a mut usize,
-    out_tx: &'a mpsc::Sender<Message>,
-    doc_id: &'a str,
-    local_user_id: Option<&'a str>,
-    version: u64,
-    awareness: &'a Awareness,
-    status_msg: &'a mut String,
+fn unconfirmed_edits(buffers: &[Buffer]) -> usize {
+    buffers.iter().map(Buffer::unconfirmed_edits).sum()
 }
 
-fn handle_key(key: KeyEvent, ctx: &mut KeyContext<'_>) -> bool {
-    if key.code == KeyCode::Esc {
-        return true;
+/// Keys while quitting asks about unconfirmed edits: `Some(true)` quits
+/// anyway, `Some(false)` goes back to editing. `quit` is whether `key` is
+/// bound to quitting.
+fn confirm_quit_key(key: &KeyEvent, quit: bool) -> Option<bool> {
+    if key.kind == KeyEventKind::Release {
+        return None;
     }
-    if key.modifiers.contains(KeyModifiers::CONTROL) && key.code == KeyCode::Char('q') {
-        return true;
+    match key.code {
+        _ if quit => Some(true),
+        KeyCode::Char('y' | 'Y') | KeyCode::Esc => Some(true),
+        KeyCode::Char('n' | 'N') => Some(false),
+        _ => None,
     }
+}
 
-    let text = ctx.doc_state.get_text();
+/// Counters for the debug overlay and log, with the active buffer's
+/// connection and view state.
+fn debug_stats(metrics: &Metrics, buffer: &Buffer, text: &str) -> DebugStats {
+    DebugStats {
+        version: buffer.version,
+        acked_version: buffer.acked_version,
+        queue: (
+            buffer.out_tx.max_capacity() - buffer.out_tx.capacity(),
+            buffer.out_tx.max_capacity(),
+        ),
+        rtt: buffer.rtt,
+        link: Some(buffer.link_state(Instant::now())),
+        last_message: Some(buffer.last_received.elapsed()),
+        scroll: (buffer.scroll, buffer.hscroll),
+        free_scroll: buffer.free_scroll,
+        cursor_byte: buffer.cursor_byte,
+        cursor: cursor_line_col(text, buffer.cursor_byte),
+        anchor: buffer.selection_anchor,
+        sent: (connection::messages_sent(), connection::bytes_sent()),
+        ..DebugStats::from_metrics(metrics)
+    }
+}
+
+/// Writes the buffer's backup. A failure is reported and stops backups of
+/// that buffer; returns true if the status row changed.
+fn save_backup(buffer: &mut Buffer, now: Instant, status: &mut StatusLog) -> bool {
+    let Err(err) = buffer.save_backup(now) else {
+        return false;
+    };
+    if let Some(backup) = buffer.backup.take() {
+        status.error(format!(
+            "backup stopped: {}: {}",
+            backup.path().display(),
+            err
+        ));
+    }
+    true
+}
+
+fn switch_buffer(buffers: &mut [Buffer], active: &mut usize, idx: usize, status: &mut StatusLog) {
+    if let Some(buffer) = buffers.get_mut(*active) {
+        buffer.flush_typing();
+    }
+    *active = idx;
+    let count = buffers.len();
+    let buffer = &mut buffers[idx];
+    buffer.activity = false;
+    status.info(format!("{} ({}/{})", buffer.doc, idx + 1, count));
+}
 
+/// Keys while a revision is shown (Shift+F9): stepping, scrolling,
+/// restoring it and going back to the live text.
+fn handle_history_key(
+    key: KeyEvent,
+    bound: Option<Action>,
+    buffer: &mut Buffer,
+    status: &mut StatusLog,
+) -> KeyAction {
+    let Some(history) = buffer.history.as_mut() else {
+        return KeyAction::Ignored;
+    };
+    history.touch(Instant::now());
+    if history.confirming {
+        history.confirming = false;
+        return match key.code {
+            KeyCode::Char('y' | 'Y') => KeyAction::Restore(history.shown().version),
+            _ => KeyAction::Redraw,
+        };
+    }
     match key.code {
-        KeyCode::Left => {
-            *ctx.cursor_byte = prev_char_boundary(&text, *ctx.cursor_byte);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+        _ if key.code == KeyCode::Esc || bound == Some(Action::History) => {
+            buffer.leave_history(status);
+            status.info("back to the live text");
+        }
+        KeyCode::Left if history.step(false) => {}
+        KeyCode::Right if history.step(true) => {}
+        KeyCode::Up => history.move_lines(-1),
+        KeyCode::Down => history.move_lines(1),
+        KeyCode::PageUp => history.page(false),
+        KeyCode::PageDown => history.page(true),
+        KeyCode::Enter if history.text().is_some() => history.confirming = true,
+        _ => return KeyAction::Ignored,
+    }
+    KeyAction::Redraw
+}
+
+/// Takes the server's answer to a history request of `buffer`'s doc.
+fn history_reply(buffer: &mut Buffer, reply: Reply, status: &mut StatusLog) {
+    let doc = &buffer.doc;
+    match reply {
+        Reply::Revisions(Ok(_)) if buffer.history.is_some() => {}
+        Reply::Revisions(Ok(revisions)) => match History::new(revisions, Instant::now()) {
+            Some(history) => {
+                buffer.flush_typing();
+                buffer.history = Some(history);
+            }
+            None => status.info(format!("no saved revisions of {} yet", doc)),
+        },
+        Reply::Revisions(Err(err)) => {
+            status.error(format!("history of {} failed: {}", doc, err));
+        }
+        Reply::Text(version, Ok(text)) => {
+            if let Some(history) = buffer.history.as_mut() {
+                history.loaded(version, text);
+            }
+        }
+        Reply::Text(version, Err(err)) => {
+            status.error(format!("v{} of {} failed: {}", version, doc, err));
+        }
+        Reply::Restored(from, Ok(version)) => {
+            let note = format!("restored v{} of {}, now v{}", from, doc, version);
+            buffer.leave_history(status);
+            status.info(note);
+        }
+        Reply::Restored(from, Err(err)) => {
+            status.error(format!("restoring v{} of {} failed: {}", from, doc, err));
+        }
+    }
+}
+
+/// Keys while the message log (F10) is open: scrolling and closing it.
+/// Everything else is swallowed so it doesn't reach the document.
+/// `toggle` is whether `key` is bound to the log.
+fn handle_log_key(
+    key: &KeyEvent,
+    toggle: bool,
+    log_scroll: &mut Option<usize>,
+    len: usize,
+) -> KeyAction {
+    let Some(scroll) = log_scroll.as_mut() else {
+        *log_scroll = Some(0);
+        return KeyAction::Redraw;
+    };
+    let last = len.saturating_sub(1);
+    match key.code {
+        _ if toggle => *log_scroll = None,
+        KeyCode::Esc => *log_scroll = None,
+        KeyCode::Up => *scroll = scroll.saturating_sub(1),
+        KeyCode::Down => *scroll = (*scroll + 1).min(last),
+        KeyCode::PageUp => *scroll = scroll.saturating_sub(LOG_PAGE_LINES),
+        KeyCode::PageDown => *scroll = (*scroll + LOG_PAGE_LINES).min(last),
+        KeyCode::Home => *scroll = 0,
+        KeyCode::End => *scroll = last,
+        _ => return KeyAction::Ignored,
+    }
+    KeyAction::Redraw
+}
+
+/// Buffer management actions, available whenever no prompt is open.
+fn tab_key_action(action: Action) -> Option<KeyAction> {
+    match action {
+        Action::NextBuffer => Some(KeyAction::NextBuffer),
+        Action::PrevBuffer => Some(KeyAction::PrevBuffer),
+        Action::CloseBuffer => Some(KeyAction::CloseBuffer),
+        _ => None,
+    }
+}
+
+/// Status row shown while the buffer is joining, disconnected or resyncing.
+fn disconnected_banner(buffer: &Buffer) -> Option<String> {
+    let state = match buffer.retry_at {
+        Some(at) => {
+            let secs = at
+                .saturating_duration_since(Instant::now())
+                .as_secs_f32()
+                .ceil();
+            if secs > 0.0 {
+                format!("DISCONNECTED — retrying in {}s", secs)
+            } else {
+                "DISCONNECTED — reconnecting…".to_string()
+            }
+        }
+        None if !buffer.is_offline() => return None,
+        None if !buffer.synced => "CONNECTING — joining…".to_string(),
+        None => "RECONNECTED — syncing…".to_string(),
+    };
+    let input = buffer.outage.summary();
+    if input.is_empty() {
+        Some(format!("{} | read-only, Esc quit", state))
+    } else {
+        Some(format!("{} | read-only, {} | Esc quit", state, input))
+    }
+}
+
+struct KeyContext<'a> {
+    doc_state: &'a mut TextDoc,
+    cursor_byte: &'a mut usize,
+    selection_anchor: &'a mut Option<usize>,
+    clipboard: &'a mut Clipboard,
+    undo: &'a mut UndoStack,
+    scroll: &'a mut usize,
+    hscroll: usize,
+    free_scroll: &'a mut bool,
+    search: &'a mut Option<SearchState>,
+    command_prompt: &'a mut Option<CommandPrompt>,
+    last_query: &'a mut String,
+    sidebar_open: &'a mut bool,
+    /// Whether the activity feed panel (F9) is open.
+    activity_open: &'a mut bool,
+    scrollbar: bool,
+    gutter: bool,
+    line_endings: LineEndings,
+    smart_end: bool,
+    show_invisibles: &'a mut bool,
+    /// Typed characters replace the one under the cursor (Insert toggles).
+    overwrite: &'a mut bool,
+    following: &'a mut Option<String>,
+    cursors: &'a mut HashMap<String, usize>,
+    selections: &'a mut HashMap<String, RemoteSelection>,
+    edit_flashes: &'a mut HashMap<String, EditFlash>,
+    users: &'a HashMap<String, String>,
+    out_tx: &'a mpsc::Sender<Message>,
+    doc_id: &'a str,
+    local_user_id: Option<&'a str>,
+    version: u64,
+    limits: ServerLimits,
+    /// Edits are refused locally: the document's ACL only lets us read.
+    read_only: bool,
+    pending: &'a mut PendingOps,
+    sync: &'a mut SyncSchedule,
+    coalescer: &'a mut Coalescer,
+    /// Where a paste too long for one chunk goes.
+    upload: &'a mut Option<ChunkedInsert>,
+    chunk_bytes: usize,
+    awareness: &'a Awareness,
+    status: &'a mut StatusLog,
+    flash_line: &'a mut Option<(usize, Instant)>,
+    /// Rows above the content area (the tab bar).
+    content_top: usize,
+    /// What the key being handled is bound to.
+    bound: Option<Action>,
+    #[cfg(feature = "spellcheck")]
+    speller: Option<&'a mut spell::Speller>,
+}
+
+fn handle_key(key: KeyEvent, ctx: &mut KeyContext<'_>) -> KeyAction {
+    if ctx.search.is_some() {
+        return handle_search_key(key, ctx);
+    }
+    if ctx.command_prompt.is_some() {
+        return handle_command_prompt_key(key, ctx);
+    }
+    if key.code == KeyCode::Esc && ctx.status.dismiss() {
+        return KeyAction::Redraw;
+    }
+    if ctx.bound == Some(Action::Follow) {
+        cycle_follow(ctx);
+        return KeyAction::Redraw;
+    }
+    if ctx.following.is_some() {
+        // Esc only leaves follow mode; any other key leaves it and then
+        // does whatever it normally does.
+        stop_following(ctx);
+        if key.code == KeyCode::Esc {
+            return KeyAction::Redraw;
+        }
+    }
+    if key.code == KeyCode::Esc {
+        return KeyAction::Quit;
+    }
+    if let Some(action) = ctx.bound {
+        return match action {
+            Action::Quit => KeyAction::Quit,
+            _ if run_action(action, ctx) => KeyAction::Redraw,
+            _ => KeyAction::Ignored,
+        };
+    }
+    if handle_edit_key(key, ctx) {
+        KeyAction::Redraw
+    } else {
+        KeyAction::Ignored
+    }
+}
+
+fn handle_edit_key(key: KeyEvent, ctx: &mut KeyContext<'_>) -> bool {
+    let text = ctx.doc_state.get_text();
+    let extend = key.modifiers.contains(KeyModifiers::SHIFT);
+    let word = key.modifiers.contains(KeyModifiers::CONTROL);
+    let edits = match key.code {
+        KeyCode::Backspace | KeyCode::Delete | KeyCode::Enter => true,
+        KeyCode::Down => extend && key.modifiers.contains(KeyModifiers::ALT),
+        KeyCode::Char('k' | 'K' | 'u') => true,
+        KeyCode::Char(_) => !word,
+        _ => false,
+    };
+    if ctx.read_only && edits {
+        refuse_edit(ctx);
+        return true;
+    }
+    ctx.undo.begin_action();
+    *ctx.free_scroll = false;
+
+    match key.code {
+        KeyCode::Left if word => {
+            let target = prev_word_boundary(&text, *ctx.cursor_byte);
+            move_cursor(ctx, target, extend);
+            true
+        }
+        KeyCode::Right if word => {
+            let target = next_word_boundary(&text, *ctx.cursor_byte);
+            move_cursor(ctx, target, extend);
+            true
+        }
+        KeyCode::Backspace if word || key.modifiers.contains(KeyModifiers::ALT) => {
+            if !delete_selection(ctx, &text) {
+                let start = prev_word_boundary(&text, *ctx.cursor_byte);
+                if start < *ctx.cursor_byte {
+                    delete_range(ctx, start, *ctx.cursor_byte);
+                }
+            }
+            send_cursor(ctx);
+            true
+        }
+        KeyCode::Delete if word => {
+            if !delete_selection(ctx, &text) {
+                let end = next_word_boundary(&text, *ctx.cursor_byte);
+                if end > *ctx.cursor_byte {
+                    delete_range(ctx, *ctx.cursor_byte, end);
+                }
+            }
+            send_cursor(ctx);
+            true
+        }
+        KeyCode::Left => {
+            let target = prev_grapheme_boundary(&text, *ctx.cursor_byte);
+            move_cursor(ctx, target, extend);
             true
         }
         KeyCode::Right => {
-            *ctx.cursor_byte = next_char_boundary(&text, *ctx.cursor_byte);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            let target = next_grapheme_boundary(&text, *ctx.cursor_byte);
+            move_cursor(ctx, target, extend);
             true
         }
         KeyCode::Up => {
-            *ctx.cursor_byte = move_cursor_vertical(&text, *ctx.cursor_byte, -1);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            let target = move_cursor_vertical(&text, *ctx.cursor_byte, -1);
+            move_cursor(ctx, target, extend);
+            true
+        }
+        KeyCode::Down if extend && key.modifiers.contains(KeyModifiers::ALT) => {
+            duplicate_line(ctx, &text);
             true
         }
         KeyCode::Down => {
-            *ctx.cursor_byte = move_cursor_vertical(&text, *ctx.cursor_byte, 1);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            let target = move_cursor_vertical(&text, *ctx.cursor_byte, 1);
+            move_cursor(ctx, target, extend);
             true
         }
         KeyCode::Home => {
-            *ctx.cursor_byte = line_start(&text, *ctx.cursor_byte);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            let target = smart_home(&text, *ctx.cursor_byte);
+            move_cursor(ctx, target, extend);
             true
         }
         KeyCode::End => {
-            *ctx.cursor_byte = line_end(&text, *ctx.cursor_byte);
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            let target = if ctx.smart_end {
+                smart_end(&text, *ctx.cursor_byte)
+            } else {
+                line_end(&text, *ctx.cursor_byte)
+            };
+            move_cursor(ctx, target, extend);
             true
         }
         KeyCode::Backspace => {
-            if *ctx.cursor_byte > 0 {
-                let start = prev_char_boundary(&text, *ctx.cursor_byte);
-                let len = *ctx.cursor_byte - start;
-                apply_delete(ctx.doc_state, start, len);
-                *ctx.cursor_byte = start;
-                let delta = Vec::new();
-                if let Ok(msg) = encode_update(
-                    ctx.doc_id,
-                    ctx.local_user_id.unwrap_or(""),
-                    Op::Delete { pos: start, len },
-                    delta,
-                    ctx.version,
-                ) {
-                    let _ = ctx.out_tx.try_send(msg);
-                }
-                ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-                let _ = ctx.out_tx.try_send(Message::Presence {
-                    user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                    document_id: ctx.doc_id.to_string(),
-                    cursor_pos: Some(*ctx.cursor_byte),
-                });
+            if delete_selection(ctx, &text) {
+                send_cursor(ctx);
+            } else if *ctx.cursor_byte > 0 {
+                let start = prev_grapheme_boundary(&text, *ctx.cursor_byte);
+                delete_range(ctx, start, *ctx.cursor_byte);
+                send_cursor(ctx);
             }
             true
         }
         KeyCode::Delete => {
-            if *ctx.cursor_byte < text.len() {
-                let end = next_char_boundary(&text, *ctx.cursor_byte);
-                let len = end - *ctx.cursor_byte;
-                if len > 0 {
-                    apply_delete(ctx.doc_state, *ctx.cursor_byte, len);
-                    let delta = Vec::new();
-                    if let Ok(msg) = encode_update(
-                        ctx.doc_id,
-                        ctx.local_user_id.unwrap_or(""),
-                        Op::Delete {
-                            pos: *ctx.cursor_byte,
-                            len,
-                        },
-                        delta,
-                        ctx.version,
-                    ) {
-                        let _ = ctx.out_tx.try_send(msg);
-                    }
-                    ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-                    let _ = ctx.out_tx.try_send(Message::Presence {
-                        user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                        document_id: ctx.doc_id.to_string(),
-                        cursor_pos: Some(*ctx.cursor_byte),
-                    });
+            if delete_selection(ctx, &text) {
+                send_cursor(ctx);
+            } else if *ctx.cursor_byte < text.len() {
+                let end = next_grapheme_boundary(&text, *ctx.cursor_byte);
+                if end > *ctx.cursor_byte {
+                    delete_range(ctx, *ctx.cursor_byte, end);
+                    send_cursor(ctx);
                 }
             }
             true
         }
         KeyCode::Enter => {
-            let insert = "\n".to_string();
-            apply_insert(ctx.doc_state, *ctx.cursor_byte, &insert);
-            let delta = Vec::new();
-            if let Ok(msg) = encode_update(
-                ctx.doc_id,
-                ctx.local_user_id.unwrap_or(""),
-                Op::Insert {
-                    pos: *ctx.cursor_byte,
-                    text: insert,
-                },
-                delta,
-                ctx.version,
-            ) {
-                let _ = ctx.out_tx.try_send(msg);
-            }
-            *ctx.cursor_byte += 1;
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            if !room_for(ctx, &text, 1) {
+                return true;
+            }
+            delete_selection(ctx, &text);
+            insert_text(ctx, "\n");
+            send_cursor(ctx);
+            true
+        }
+        KeyCode::Char('k' | 'K') if word && extend => {
+            let (start, end) = whole_line_range(&text, *ctx.cursor_byte);
+            kill_range(ctx, &text, start, end);
+            *ctx.cursor_byte = line_start(&ctx.doc_state.get_text(), start);
+            send_cursor(ctx);
             true
         }
-        KeyCode::Char('r') if key.modifiers.contains(KeyModifiers::CONTROL) => {
-            let _ = ctx
-                .out_tx
-                .try_send(encode_sync_request(ctx.doc_id, ctx.version));
-            ctx.status_msg.clear();
-            ctx.status_msg.push_str("sync requested");
+        KeyCode::Char('k') if word => {
+            let (start, end) = kill_line_range(&text, *ctx.cursor_byte);
+            kill_range(ctx, &text, start, end);
+            send_cursor(ctx);
+            true
+        }
+        KeyCode::Char('u') if word => {
+            let start = line_start(&text, *ctx.cursor_byte);
+            kill_range(ctx, &text, start, *ctx.cursor_byte);
+            send_cursor(ctx);
             true
         }
         KeyCode::Char(ch) => {
             if key.modifiers.contains(KeyModifiers::CONTROL) {
                 return false;
             }
-            let insert = ch.to_string();
-            let insert_len = insert.len();
-            apply_insert(ctx.doc_state, *ctx.cursor_byte, &insert);
-            let delta = Vec::new();
-            if let Ok(msg) = encode_update(
-                ctx.doc_id,
-                ctx.local_user_id.unwrap_or(""),
-                Op::Insert {
-                    pos: *ctx.cursor_byte,
-                    text: insert,
-                },
-                delta,
-                ctx.version,
-            ) {
-                let _ = ctx.out_tx.try_send(msg);
-            }
-            *ctx.cursor_byte += insert_len;
-            ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
-            let _ = ctx.out_tx.try_send(Message::Presence {
-                user_id: ctx.local_user_id.unwrap_or("").to_string(),
-                document_id: ctx.doc_id.to_string(),
-                cursor_pos: Some(*ctx.cursor_byte),
-            });
+            if !room_for(ctx, &text, ch.len_utf8()) {
+                return true;
+            }
+            if !delete_selection(ctx, &text) && *ctx.overwrite {
+                for edit in overwrite_edits(&text, *ctx.cursor_byte, ch) {
+                    apply_edit(ctx, &edit);
+                    *ctx.cursor_byte = edit.cursor_after();
+                    ctx.undo.record(edit);
+                }
+            } else {
+                insert_text(ctx, ch.encode_utf8(&mut [0u8; 4]));
+            }
+            send_cursor(ctx);
             true
         }
         _ => false,
     }
 }
 
-struct RenderContext<'a> {
-    addr: &'a str,
-    room: &'a str,
-    doc: &'a str,
-    text: &'a str,
-    cursor_byte: usize,
-    users_count: usize,
-    version: u64,
-    status_msg: &'a str,
-    scroll: &'a mut usize,
-    cursors: &'a HashMap<String, usize>,
-    users: &'a HashMap<String, String>,
-    local_user_id: Option<&'a str>,
+/// Runs what a bound key does. `false` if it did nothing, like copying
+/// without a selection.
+fn run_action(action: Action, ctx: &mut KeyContext<'_>) -> bool {
+    if ctx.read_only && action.edits() {
+        refuse_edit(ctx);
+        return true;
+    }
+    let text = ctx.doc_state.get_text();
+    ctx.undo.begin_action();
+    *ctx.free_scroll = false;
+
+    match action {
+        Action::Sync => {
+            request_sync(ctx);
+            true
+        }
+        Action::Search => {
+            *ctx.selection_anchor = None;
+            *ctx.search = Some(SearchState::new(*ctx.cursor_byte, *ctx.scroll));
+            true
+        }
+        Action::Save => {
+            let doc_name = ctx.doc_id.rsplit('/').next().unwrap_or(ctx.doc_id);
+            *ctx.command_prompt = Some(CommandPrompt::export(doc_name));
+            true
+        }
+        Action::Import => {
+            *ctx.command_prompt = Some(CommandPrompt::import());
+            true
+        }
+        Action::Goto => {
+            *ctx.command_prompt = Some(CommandPrompt::goto_line());
+            true
+        }
+        Action::Open => {
+            *ctx.command_prompt = Some(CommandPrompt::open_doc());
+            true
+        }
+        Action::Palette => {
+            *ctx.command_prompt = Some(CommandPrompt::command());
+            true
+        }
+        Action::Sidebar => {
+            *ctx.sidebar_open = !*ctx.sidebar_open;
+            true
+        }
+        Action::Activity => {
+            *ctx.activity_open = !*ctx.activity_open;
+            true
+        }
+        Action::Invisibles => {
+            *ctx.show_invisibles = !*ctx.show_invisibles;
+            ctx.status.info(if *ctx.show_invisibles {
+                "invisibles shown"
+            } else {
+                "invisibles hidden"
+            });
+            true
+        }
+        Action::Normalize => {
+            normalize_document(ctx);
+            true
+        }
+        #[cfg(feature = "spellcheck")]
+        Action::Spelling => {
+            cycle_spelling(ctx);
+            true
+        }
+        Action::Overwrite => {
+            *ctx.overwrite = !*ctx.overwrite;
+            ctx.status.info(if *ctx.overwrite {
+                "overwrite mode"
+            } else {
+                "insert mode"
+            });
+            true
+        }
+        Action::FindNext | Action::FindPrev => {
+            let query = ctx.last_query.clone();
+            jump_to_match(ctx, &query, action == Action::FindNext);
+            true
+        }
+        Action::Undo => {
+            match ctx.undo.undo() {
+                Some(edits) => replay_edits(ctx, &edits, "undo"),
+                None => {
+                    ctx.status.info("nothing to undo");
+                }
+            }
+            true
+        }
+        Action::Redo => {
+            match ctx.undo.redo() {
+                Some(edits) => replay_edits(ctx, &edits, "redo"),
+                None => {
+                    ctx.status.info("nothing to redo");
+                }
+            }
+            true
+        }
+        Action::Copy => {
+            // Ctrl+C only copies when there is something selected.
+            let Some((start, end)) = selected(&text, *ctx.selection_anchor, *ctx.cursor_byte)
+            else {
+                return false;
+            };
+            ctx.clipboard.copy(&text[start..end]);
+            ctx.status
+                .info(clipboard_status(ctx.clipboard, "copied", end - start));
+            true
+        }
+        Action::Cut => {
+            let Some((start, end)) = selected(&text, *ctx.selection_anchor, *ctx.cursor_byte)
+            else {
+                return false;
+            };
+            ctx.clipboard.copy(&text[start..end]);
+            delete_selection(ctx, &text);
+            send_cursor(ctx);
+            ctx.status
+                .info(clipboard_status(ctx.clipboard, "cut", end - start));
+            true
+        }
+        Action::Paste => {
+            let pasted = ctx.clipboard.paste();
+            if pasted.is_empty() {
+                ctx.status.info("clipboard is empty");
+                return true;
+            }
+            if !room_for(ctx, &text, pasted.len()) {
+                return true;
+            }
+            delete_selection(ctx, &text);
+            if paste_text(ctx, &ctx.line_endings.apply(&pasted)) {
+                ctx.status
+                    .info(clipboard_status(ctx.clipboard, "pasted", pasted.len()));
+            }
+            send_cursor(ctx);
+            true
+        }
+        // Handled before the buffer sees the key.
+        Action::Quit
+        | Action::NextBuffer
+        | Action::PrevBuffer
+        | Action::CloseBuffer
+        | Action::History
+        | Action::Log
+        | Action::Debug
+        | Action::Suspend => false,
+        #[cfg(not(feature = "spellcheck"))]
+        Action::Spelling => false,
+        Action::Follow => {
+            cycle_follow(ctx);
+            true
+        }
+    }
 }
 
-fn render(ctx: &mut RenderContext<'_>) -> Result<(), Box<dyn Error>> {
-    let mut out = stdout();
-    let (cols, rows) = terminal::size()?;
-    let content_height = rows.saturating_sub(1) as usize;
+fn handle_search_key(key: KeyEvent, ctx: &mut KeyContext<'_>) -> KeyAction {
+    let Some(search) = ctx.search.as_mut() else {
+        return KeyAction::Ignored;
+    };
+    let query = search.prompt.input().to_string();
+    match key.code {
+        KeyCode::F(3) | KeyCode::Down | KeyCode::Up => {
+            let forward = match key.code {
+                KeyCode::F(3) => !key.modifiers.contains(KeyModifiers::SHIFT),
+                code => code == KeyCode::Down,
+            };
+            jump_to_match(ctx, &query, forward);
+            return KeyAction::Redraw;
+        }
+        _ => {}
+    }
+    match search.prompt.handle_key(key) {
+        PromptEvent::Changed => {
+            let text = ctx.doc_state.get_text();
+            let query = search.prompt.input().to_string();
+            let origin = search.origin_cursor;
+            let matches = search::find_matches(&text, &query);
+            let target = search::match_from(&matches, origin).map_or(origin, |(start, _)| start);
+            ctx.status.info(match_count_status(&text, &matches, target));
+            move_cursor(ctx, target, false);
+        }
+        PromptEvent::Submit => {
+            *ctx.last_query = query;
+            *ctx.search = None;
+        }
+        PromptEvent::Cancel => {
+            let (origin_cursor, origin_scroll) = (search.origin_cursor, search.origin_scroll);
+            *ctx.search = None;
+            *ctx.scroll = origin_scroll;
+            move_cursor(ctx, origin_cursor, false);
+            ctx.status.clear();
+        }
+        PromptEvent::Moved | PromptEvent::Ignored => {}
+    }
+    KeyAction::Redraw
+}
 
-    let (cursor_line, cursor_col) = cursor_line_col(ctx.text, ctx.cursor_byte);
-    if cursor_line < *ctx.scroll {
-        *ctx.scroll = cursor_line;
-    } else if cursor_line >= *ctx.scroll + content_height {
-        *ctx.scroll = cursor_line + 1 - content_height;
+fn jump_to_match(ctx: &mut KeyContext<'_>, query: &str, forward: bool) {
+    if query.is_empty() {
+        ctx.status.info("no search query, press Ctrl+F");
+        return;
+    }
+    let text = ctx.doc_state.get_text();
+    let matches = search::find_matches(&text, query);
+    match search::cycle_match(&matches, *ctx.cursor_byte, forward) {
+        Some((start, _)) => {
+            ctx.status.info(match_count_status(&text, &matches, start));
+            move_cursor(ctx, start, false);
+        }
+        None => ctx.status.info(format!("no matches for '{}'", query)),
+    }
+}
+
+fn handle_command_prompt_key(key: KeyEvent, ctx: &mut KeyContext<'_>) -> KeyAction {
+    let Some(command_prompt) = ctx.command_prompt.as_mut() else {
+        return KeyAction::Ignored;
+    };
+    if matches!(command_prompt.action, PromptAction::ConfirmReplace { .. }) {
+        let replace = match key.code {
+            KeyCode::Char('y' | 'Y') => true,
+            KeyCode::Char('n' | 'N') | KeyCode::Enter => false,
+            KeyCode::Esc => {
+                *ctx.command_prompt = None;
+                ctx.status.info("import cancelled");
+                return KeyAction::Redraw;
+            }
+            _ => return KeyAction::Ignored,
+        };
+        if let Some(CommandPrompt {
+            action: PromptAction::ConfirmReplace { path, contents },
+            ..
+        }) = ctx.command_prompt.take()
+        {
+            import_text(ctx, &path, &contents, replace);
+        }
+        return KeyAction::Redraw;
+    }
+    if matches!(command_prompt.action, PromptAction::ConfirmQuit) {
+        // Handled before key routing reaches the document.
+        return KeyAction::Ignored;
+    }
+    if matches!(command_prompt.action, PromptAction::Command) && key.code == KeyCode::Tab {
+        let (input, candidates) = commands::complete(command_prompt.prompt.input());
+        command_prompt.prompt.set_input(&input);
+        if !candidates.is_empty() {
+            ctx.status.info(candidates.join("  "));
+        }
+        return KeyAction::Redraw;
+    }
+    if let PromptAction::RestoreBackup { contents, diff } = &mut command_prompt.action {
+        match (key.code, diff.as_mut()) {
+            (KeyCode::Char('d' | 'D'), _) => {
+                *diff = if diff.is_some() { None } else { Some(0) };
+            }
+            (KeyCode::Char('r' | 'R'), _) => {
+                let contents = std::mem::take(contents);
+                *ctx.command_prompt = None;
+                restore_backup(ctx, &contents);
+            }
+            (KeyCode::Esc, _) => {
+                *ctx.command_prompt = None;
+                ctx.status.info("kept the server's text");
+            }
+            (KeyCode::Up, Some(scroll)) => *scroll = scroll.saturating_sub(1),
+            (KeyCode::Down, Some(scroll)) => *scroll += 1,
+            (KeyCode::PageUp, Some(scroll)) => *scroll = scroll.saturating_sub(LOG_PAGE_LINES),
+            (KeyCode::PageDown, Some(scroll)) => *scroll += LOG_PAGE_LINES,
+            (KeyCode::Home, Some(scroll)) => *scroll = 0,
+            _ => return KeyAction::Ignored,
+        }
+        return KeyAction::Redraw;
     }
 
-    queue!(out, MoveTo(0, 0), Clear(ClearType::All))?;
+    match command_prompt.prompt.handle_key(key) {
+        PromptEvent::Changed | PromptEvent::Moved => KeyAction::Redraw,
+        PromptEvent::Cancel => {
+            *ctx.command_prompt = None;
+            KeyAction::Redraw
+        }
+        PromptEvent::Ignored => KeyAction::Ignored,
+        PromptEvent::Submit => {
+            let input = command_prompt.prompt.input().trim().to_string();
+            let Some(command_prompt) = ctx.command_prompt.take() else {
+                return KeyAction::Ignored;
+            };
+            if matches!(command_prompt.action, PromptAction::GotoLine) {
+                match parse_goto(&input) {
+                    Ok((line, col)) => goto_line(ctx, line, col),
+                    Err(err) => {
+                        ctx.status.error(err);
+                        *ctx.command_prompt = Some(command_prompt);
+                    }
+                }
+                return KeyAction::Redraw;
+            }
+            if matches!(command_prompt.action, PromptAction::Command) {
+                return match commands::parse(&input) {
+                    Ok(command) => run_command(command, ctx),
+                    Err(err) => {
+                        ctx.status.error(err);
+                        *ctx.command_prompt = Some(command_prompt);
+                        KeyAction::Redraw
+                    }
+                };
+            }
+            if matches!(command_prompt.action, PromptAction::OpenDoc) {
+                if input.is_empty() {
+                    ctx.status.info("no doc name given");
+                    return KeyAction::Redraw;
+                }
+                return KeyAction::OpenDoc(input);
+            }
+            let path = input;
+            if path.is_empty() {
+                ctx.status.info("no path given");
+                return KeyAction::Redraw;
+            }
+            let text = ctx.doc_state.get_text();
+            match command_prompt.action {
+                PromptAction::Export => export_to(ctx, &path),
+                PromptAction::Import => match std::fs::read_to_string(&path) {
+                    Ok(contents) if text.is_empty() => import_text(ctx, &path, &contents, true),
+                    Ok(contents) => {
+                        *ctx.command_prompt = Some(CommandPrompt::confirm_replace(path, contents));
+                    }
+                    Err(err) => ctx
+                        .status
+                        .error(format!("import failed: {}: {}", path, err)),
+                },
+                PromptAction::ConfirmReplace { .. }
+                | PromptAction::GotoLine
+                | PromptAction::OpenDoc
+                | PromptAction::Command
+                | PromptAction::RestoreBackup { .. }
+                | PromptAction::ConfirmQuit => {}
+            }
+            KeyAction::Redraw
+        }
+    }
+}
 
-    let lines: Vec<&str> = ctx.text.split('\n').collect();
-    let start = (*ctx.scroll).min(lines.len());
-    let end = (start + content_height).min(lines.len());
+/// Runs a command from the palette (Ctrl+P).
+fn run_command(command: Command, ctx: &mut KeyContext<'_>) -> KeyAction {
+    match command {
+        Command::Sync => request_sync(ctx),
+        Command::Snapshot => {
+            flush_typing(ctx);
+            return KeyAction::Snapshot;
+        }
+        Command::Stats => return KeyAction::DocStats,
+        Command::Users => *ctx.sidebar_open = !*ctx.sidebar_open,
+        Command::Activity => *ctx.activity_open = !*ctx.activity_open,
+        Command::History => {
+            flush_typing(ctx);
+            return KeyAction::History;
+        }
+        Command::Goto(line, col) => goto_line(ctx, line, col),
+        Command::Open(doc) => return KeyAction::OpenDoc(doc),
+        Command::Docs => return KeyAction::PickDoc,
+        Command::Theme(name) => return KeyAction::SetTheme(name),
+        Command::Save(path) => export_to(ctx, &path),
+        Command::Substitute {
+            pattern,
+            replacement,
+            every,
+            regex,
+        } => substitute(ctx, &pattern, &replacement, every, regex),
+        Command::Quit => return KeyAction::Quit,
+        Command::Help => return KeyAction::Help,
+    }
+    KeyAction::Redraw
+}
 
-    for (row, line) in lines[start..end].iter().enumerate() {
-        let clipped = clip_line(line, cols as usize);
-        queue!(out, MoveTo(0, row as u16))?;
-        out.write_all(clipped.as_bytes())?;
+fn request_sync(ctx: &mut KeyContext<'_>) {
+    if let Err(wait) = ctx.sync.manual(Instant::now()) {
+        ctx.status.info(format!(
+            "synced a moment ago; try again in {:.1}s",
+            wait.as_secs_f64()
+        ));
+        return;
+    }
+    flush_typing(ctx);
+    let request = encode_sync_request(ctx.doc_id, ctx.version);
+    if ctx.out_tx.try_send(request).is_ok() {
+        ctx.pending.request_sent();
     }
+    ctx.status.info("sync requested");
+}
 
-    render_local_cursor(
-        &mut out,
-        ctx.text,
-        ctx.scroll,
-        content_height,
-        cols as usize,
-        ctx.cursor_byte,
-    )?;
+fn export_to(ctx: &mut KeyContext<'_>, path: &str) {
+    let text = ctx.doc_state.get_text();
+    match replace_file(&RealFs, Path::new(path), text.as_bytes()) {
+        Ok(_) => ctx
+            .status
+            .info(format!("exported {} bytes to {}", text.len(), path)),
+        Err(err) => ctx
+            .status
+            .error(format!("export failed: {}: {}", path, err)),
+    }
+}
 
-    render_remote_cursors(
-        &mut out,
-        ctx.text,
-        ctx.scroll,
-        content_height,
-        cols as usize,
-        ctx.cursors,
-        ctx.local_user_id,
-    )?;
-
-    let cursor_summary = build_cursor_summary(ctx.cursors, ctx.users, ctx.local_user_id, 3);
-    let status = format!(
-        "{} | room={} doc={} users={} v={} pos={} | {} | Ctrl+Q quit | Ctrl+R sync {}",
-        ctx.addr,
-        ctx.room,
-        ctx.doc,
-        ctx.users_count,
-        ctx.version,
-        ctx.cursor_byte,
-        if cursor_summary.is_empty() {
-            "cursors: -"
-        } else {
-            &cursor_summary
-        },
-        if ctx.status_msg.is_empty() { "" } else { "|" }
-    );
-    let status_line = if ctx.status_msg.is_empty() {
-        status
-    } else {
-        format!("{} {}", status, ctx.status_msg)
+/// Parses `<line>` or `<line>:<col>` (both 1-based).
+fn parse_goto(input: &str) -> Result<(usize, Option<usize>), String> {
+    let invalid = || {
+        format!(
+            "invalid position '{}': expected <line> or <line>:<col>",
+            input
+        )
     };
+    let (line, col) = match input.split_once(':') {
+        Some((line, col)) => (line, Some(col)),
+        None => (input, None),
+    };
+    let line: usize = line.trim().parse().map_err(|_| invalid())?;
+    let col = col
+        .map(|col| col.trim().parse::<usize>())
+        .transpose()
+        .map_err(|_| invalid())?;
+    if line == 0 || col == Some(0) {
+        return Err(invalid());
+    }
+    Ok((line, col))
+}
 
-    queue!(out, MoveTo(0, rows.saturating_sub(1)))?;
-    queue!(out, Clear(ClearType::CurrentLine))?;
-    let clipped_status = clip_line(&status_line, cols as usize);
-    out.write_all(clipped_status.as_bytes())?;
+/// Moves the cursor to a 1-based line and display column (clamped to the
+/// document), centers it in the viewport and flashes the line.
+fn goto_line(ctx: &mut KeyContext<'_>, line: usize, col: Option<usize>) {
+    let text = ctx.doc_state.get_text();
+    let starts = line_starts(&text);
+    let line_idx = (line - 1).min(starts.len() - 1);
+    let (start, end) = line_range(&text, &starts, line_idx);
+    let target = start + byte_at_column(&text[start..end], col.unwrap_or(1) - 1);
+    move_cursor(ctx, target, false);
 
-    let cursor_row = cursor_line.saturating_sub(*ctx.scroll);
-    if cursor_row < content_height {
-        let col = cursor_col.min(cols.saturating_sub(1) as usize);
-        queue!(out, MoveTo(col as u16, cursor_row as u16))?;
+    let content_height = terminal::size()
+        .map_or(24, |(_, rows)| rows as usize)
+        .saturating_sub(1 + ctx.content_top);
+    let content_height = content_height - feed_rows(content_height, *ctx.activity_open);
+    *ctx.scroll = centered_scroll(line_idx, content_height, starts.len());
+    *ctx.free_scroll = true;
+    *ctx.flash_line = Some((line_idx, Instant::now()));
+    ctx.status
+        .info(format!("line {} of {}", line_idx + 1, starts.len()));
+}
+
+/// Inserts imported file contents at the cursor (replacing the selection),
+/// or replaces the whole document, as a single undo step.
+fn import_text(ctx: &mut KeyContext<'_>, path: &str, contents: &str, replace: bool) {
+    let contents = ctx.line_endings.apply(contents);
+    let kept = if replace {
+        *ctx.selection_anchor = None;
+        ""
+    } else {
+        &ctx.doc_state.get_text()
+    };
+    if !room_for(ctx, kept, contents.len()) {
+        return;
+    }
+    ctx.undo.begin_action();
+    ctx.undo.seal();
+    *ctx.free_scroll = false;
+    let text = ctx.doc_state.get_text();
+    if replace {
+        *ctx.selection_anchor = None;
+        if !text.is_empty() {
+            delete_range(ctx, 0, text.len());
+        }
+        if paste_text(ctx, &contents) {
+            *ctx.cursor_byte = 0;
+            ctx.status.info(format!(
+                "replaced document with {} ({} bytes)",
+                path,
+                contents.len()
+            ));
+        }
+    } else {
+        delete_selection(ctx, &text);
+        if paste_text(ctx, &contents) {
+            ctx.status
+                .info(format!("imported {} bytes from {}", contents.len(), path));
+        }
+    }
+    send_cursor(ctx);
+}
+
+/// Turns the document into an earlier session's backup with the fewest
+/// edits, sent like typing and undone as one step.
+fn restore_backup(ctx: &mut KeyContext<'_>, contents: &str) {
+    if let Err(err) = ctx.limits.check_doc_size(0, contents.len()) {
+        ctx.status.error(format!("backup not restored: {}", err));
+        return;
+    }
+    stop_following(ctx);
+    *ctx.selection_anchor = None;
+    *ctx.free_scroll = false;
+    ctx.undo.begin_action();
+    ctx.undo.seal();
+    let text = ctx.doc_state.get_text();
+    let mut current = text.clone();
+    for op in snapshot::diff(&text, contents) {
+        let edit = match &op {
+            Op::Insert { pos, text, .. } => Edit::Insert {
+                pos: *pos,
+                text: text.clone(),
+            },
+            Op::Delete { pos, len, .. } => Edit::Delete {
+                pos: *pos,
+                text: current[*pos..pos + len].to_string(),
+            },
+            Op::Cursor { .. } | Op::Selection { .. } => continue,
+        };
+        apply_edit(ctx, &edit);
+        current = ctx.doc_state.get_text();
+        shift_for_op(&op, ctx.cursor_byte);
+        ctx.undo.record(edit);
+    }
+    send_cursor(ctx);
+    ctx.status.info("re-applied the backup; undo takes it back");
+}
+
+/// Inserts pasted text at the cursor as one undo step, replacing the
+/// selection. While searching, the text goes into the search prompt.
+fn handle_paste(text: &str, ctx: &mut KeyContext<'_>) {
+    if ctx.search.is_some() || ctx.command_prompt.is_some() {
+        let text = normalize_line_endings(text);
+        for ch in text.chars().filter(|ch| *ch != '\n') {
+            handle_key(KeyEvent::from(KeyCode::Char(ch)), ctx);
+        }
+        return;
+    }
+    if text.is_empty() {
+        return;
+    }
+    if ctx.read_only {
+        refuse_edit(ctx);
+        return;
+    }
+    let text = ctx.line_endings.apply(text);
+    stop_following(ctx);
+    ctx.undo.begin_action();
+    ctx.undo.seal();
+    *ctx.free_scroll = false;
+    let doc_text = ctx.doc_state.get_text();
+    if !room_for(ctx, &doc_text, text.len()) {
+        return;
+    }
+    delete_selection(ctx, &doc_text);
+    if paste_text(ctx, &text) {
+        ctx.status.info(format!("pasted {} bytes", text.len()));
+    }
+    send_cursor(ctx);
+}
+
+/// Inserts `text` at the cursor. Text longer than one chunk, or than one
+/// message the server takes, becomes an upload sent a chunk at a time from
+/// the main loop; returns false then, with the progress on the status row.
+fn paste_text(ctx: &mut KeyContext<'_>, text: &str) -> bool {
+    let upload = ChunkedInsert::new(*ctx.cursor_byte, text, ctx.chunk_bytes, |chunk| {
+        fits_message(ctx, chunk)
+    });
+    if upload.chunks() <= 1 {
+        if !text.is_empty() {
+            insert_text(ctx, text);
+        }
+        return true;
+    }
+    flush_typing(ctx);
+    let upload = upload.paced(&ctx.limits);
+    ctx.status.progress(upload.progress());
+    *ctx.upload = Some(upload);
+    false
+}
+
+/// Whether inserting `chunk` at the cursor goes out in a message the
+/// server takes.
+fn fits_message(ctx: &KeyContext<'_>, chunk: &str) -> bool {
+    if ctx.limits.max_message_bytes.is_none() {
+        return true;
+    }
+    let op = Op::Insert {
+        pos: *ctx.cursor_byte,
+        text: chunk.to_string(),
+        pos_chars: None,
+    };
+    let user_id = ctx.local_user_id.unwrap_or("");
+    encode_update(ctx.doc_id, user_id, op, Vec::new(), ctx.version)
+        .is_ok_and(|update| ctx.limits.check_update(0, &update).is_ok())
+}
+
+/// Whether `added` bytes in place of the selection keep `text` within the
+/// document size the server announced. If not, says so in the status row
+/// instead of sending an edit the server would reject.
+fn room_for(ctx: &mut KeyContext<'_>, text: &str, added: usize) -> bool {
+    let replaced = selected(text, *ctx.selection_anchor, *ctx.cursor_byte)
+        .map_or(0, |(start, end)| end - start);
+    match ctx.limits.check_doc_size(text.len() - replaced, added) {
+        Ok(()) => true,
+        Err(err) => {
+            ctx.status.error(err);
+            false
+        }
+    }
+}
+
+fn normalize_line_endings(text: &str) -> String {
+    text.replace("\r\n", "\n").replace('\r', "\n")
+}
+
+/// Edits turning every `\r\n` and lone `\r` of `text` into `\n`, last
+/// first so each position is still valid when its edit is applied.
+fn line_ending_edits(text: &str) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for (pos, _) in text.match_indices('\r').rev() {
+        edits.push(Edit::Delete {
+            pos,
+            text: "\r".to_string(),
+        });
+        if !text[pos + 1..].starts_with('\n') {
+            edits.push(Edit::Insert {
+                pos,
+                text: "\n".to_string(),
+            });
+        }
+    }
+    edits
+}
+
+/// Rewrites the document's line endings to `\n` as a single undo step.
+fn normalize_document(ctx: &mut KeyContext<'_>) {
+    let text = ctx.doc_state.get_text();
+    let edits = line_ending_edits(&text);
+    if edits.is_empty() {
+        ctx.status.info("line endings are already normalized");
+        return;
+    }
+    stop_following(ctx);
+    *ctx.selection_anchor = None;
+    ctx.undo.begin_action();
+    ctx.undo.seal();
+    // Only the `\r` of a `\r\n` goes away; a lone `\r` is replaced in place.
+    let removed = text
+        .match_indices("\r\n")
+        .filter(|(pos, _)| *pos < *ctx.cursor_byte)
+        .count();
+    for edit in edits {
+        apply_edit(ctx, &edit);
+        ctx.undo.record(edit);
+    }
+    *ctx.cursor_byte -= removed;
+    send_cursor(ctx);
+    ctx.status.info(format!(
+        "normalized {} line endings",
+        text.matches('\r').count()
+    ));
+}
+
+/// Edits making `found`, last first like `line_ending_edits`.
+fn replace_edits(text: &str, found: &[Replacement]) -> Vec<Edit> {
+    let mut edits = Vec::new();
+    for found in found.iter().rev() {
+        if found.end > found.start {
+            edits.push(Edit::Delete {
+                pos: found.start,
+                text: text[found.start..found.end].to_string(),
+            });
+        }
+        if !found.with.is_empty() {
+            edits.push(Edit::Insert {
+                pos: found.start,
+                text: found.with.clone(),
+            });
+        }
+    }
+    edits
+}
+
+/// `:s`: replaces the matches of `pattern` as a single undo step.
+fn substitute(
+    ctx: &mut KeyContext<'_>,
+    pattern: &str,
+    replacement: &str,
+    every: bool,
+    regex: bool,
+) {
+    if ctx.read_only {
+        refuse_edit(ctx);
+        return;
+    }
+    let text = ctx.doc_state.get_text();
+    let found = Pattern::new(pattern, regex)
+        .map(|pattern| replace::find(&text, &pattern, replacement, every));
+    let found = match found {
+        Ok(found) if found.is_empty() => {
+            ctx.status.info(format!("no match for '{}'", pattern));
+            return;
+        }
+        Ok(found) => found,
+        Err(err) => {
+            ctx.status.error(err);
+            return;
+        }
+    };
+    let removed: usize = found.iter().map(|found| found.end - found.start).sum();
+    let added: usize = found.iter().map(|found| found.with.len()).sum();
+    if let Err(err) = ctx.limits.check_doc_size(text.len() - removed, added) {
+        ctx.status.error(err);
+        return;
+    }
+    stop_following(ctx);
+    *ctx.selection_anchor = None;
+    ctx.undo.begin_action();
+    ctx.undo.seal();
+    for edit in replace_edits(&text, &found) {
+        apply_edit(ctx, &edit);
+        shift_for_op(&edit.to_op(), ctx.cursor_byte);
+        ctx.undo.record(edit);
+    }
+    send_cursor(ctx);
+    ctx.status.info(match found.len() {
+        1 => "replaced 1 match".to_string(),
+        count => format!("replaced {} matches", count),
+    });
+}
+
+/// F8: ignores the word under the cursor, adds it to the session
+/// dictionary, or checks it again, in turn.
+#[cfg(feature = "spellcheck")]
+fn cycle_spelling(ctx: &mut KeyContext<'_>) {
+    let Some(speller) = ctx.speller.as_deref_mut() else {
+        ctx.status.info("spellcheck is off");
+        return;
+    };
+    let text = ctx.doc_state.get_text();
+    let Some(word) = spell::word_at(&text, *ctx.cursor_byte) else {
+        ctx.status.info("no word under the cursor");
+        return;
+    };
+    let message = match speller.cycle(word) {
+        spell::WordState::Ignored => format!("ignoring '{}'", word),
+        spell::WordState::Added => format!("added '{}' for this session", word),
+        spell::WordState::Checked => format!("checking '{}' again", word),
+    };
+    ctx.status.info(message);
+}
+
+/// Starts following the next remote user in id order; past the last one,
+/// follow mode is switched off again.
+fn cycle_follow(ctx: &mut KeyContext<'_>) {
+    *ctx.following = next_follow_target(ctx.cursors, ctx.local_user_id, ctx.following.as_deref());
+    match ctx.following.as_deref() {
+        Some(user_id) => {
+            let name = ctx.users.get(user_id).map_or(user_id, String::as_str);
+            ctx.status.info(format!("following {}", name));
+        }
+        None if ctx
+            .cursors
+            .keys()
+            .any(|id| Some(id.as_str()) != ctx.local_user_id) =>
+        {
+            ctx.status.info("stopped following");
+        }
+        None => ctx.status.info("no one to follow"),
+    }
+}
+
+fn stop_following(ctx: &mut KeyContext<'_>) {
+    if ctx.following.take().is_some() {
+        ctx.status.info("stopped following");
+    }
+}
+
+fn next_follow_target(
+    cursors: &HashMap<String, usize>,
+    local_user_id: Option<&str>,
+    current: Option<&str>,
+) -> Option<String> {
+    let mut ids: Vec<&String> = cursors
+        .keys()
+        .filter(|id| Some(id.as_str()) != local_user_id)
+        .collect();
+    ids.sort();
+    match current {
+        None => ids.first().map(|id| id.to_string()),
+        Some(current) => ids.into_iter().find(|id| id.as_str() > current).cloned(),
+    }
+}
+
+fn match_count_status(text: &str, matches: &[(usize, usize)], current: usize) -> String {
+    match matches.iter().position(|(start, _)| *start == current) {
+        Some(idx) => format!(
+            "match {}/{} at {}",
+            idx + 1,
+            matches.len(),
+            position::line_col(text, current)
+        ),
+        None if matches.is_empty() => "no matches".to_string(),
+        None => format!("{} matches", matches.len()),
+    }
+}
+
+fn handle_mouse(mouse: MouseEvent, ctx: &mut KeyContext<'_>) -> Result<bool, Box<dyn Error>> {
+    let (cols, rows) = terminal::size()?;
+    let content_height = (rows as usize).saturating_sub(1 + ctx.content_top);
+    let content_height = content_height - feed_rows(content_height, *ctx.activity_open);
+    let content_width = content_width(cols as usize, *ctx.sidebar_open, ctx.scrollbar, ctx.gutter);
+    let text = ctx.doc_state.get_text();
+    let row = (mouse.row as usize).checked_sub(ctx.content_top);
+    let on_content =
+        row.is_some_and(|row| row < content_height) && (mouse.column as usize) < content_width;
+    let row = row.unwrap_or(0);
+    if ctx.following.is_some()
+        && matches!(
+            mouse.kind,
+            MouseEventKind::Down(_) | MouseEventKind::ScrollUp | MouseEventKind::ScrollDown
+        )
+    {
+        stop_following(ctx);
+    }
+
+    match mouse.kind {
+        MouseEventKind::Down(MouseButton::Left) if on_content => {
+            let col = ctx.hscroll + mouse.column as usize;
+            let target = byte_at_screen(&text, *ctx.scroll, row, col);
+            *ctx.free_scroll = false;
+            move_cursor(ctx, target, false);
+            *ctx.selection_anchor = Some(target);
+            Ok(true)
+        }
+        MouseEventKind::Drag(MouseButton::Left) if on_content => {
+            let col = ctx.hscroll + mouse.column as usize;
+            let target = byte_at_screen(&text, *ctx.scroll, row, col);
+            *ctx.free_scroll = false;
+            move_cursor(ctx, target, true);
+            Ok(true)
+        }
+        MouseEventKind::ScrollUp => {
+            *ctx.scroll = ctx.scroll.saturating_sub(WHEEL_SCROLL_LINES);
+            *ctx.free_scroll = true;
+            Ok(true)
+        }
+        MouseEventKind::ScrollDown => {
+            let max_scroll = line_starts(&text).len().saturating_sub(1);
+            *ctx.scroll = (*ctx.scroll + WHEEL_SCROLL_LINES).min(max_scroll);
+            *ctx.free_scroll = true;
+            Ok(true)
+        }
+        _ => Ok(false),
+    }
+}
+
+/// Maps a content-area cell to a byte offset; cells past the end of a line
+/// clamp to the line end and rows past the last line clamp to the document end.
+fn byte_at_screen(text: &str, scroll: usize, row: usize, col: usize) -> usize {
+    let starts = line_starts(text);
+    let line_idx = scroll + row;
+    if line_idx >= starts.len() {
+        return text.len();
+    }
+    let (start, end) = line_range(text, &starts, line_idx);
+    start + byte_at_column(&text[start..end], col)
+}
+
+fn send_cursor(ctx: &mut KeyContext<'_>) {
+    ctx.awareness.set_cursor(ctx.doc_id, *ctx.cursor_byte);
+    let cursor = *ctx.cursor_byte;
+    let (outbox, coalescer) = outbox(ctx);
+    // While typed characters are batched the cursor goes out with them.
+    if !coalescer.defer_cursor() {
+        outbox.send_cursor(cursor);
+    }
+}
+
+fn send_op(ctx: &mut KeyContext<'_>, op: Op) {
+    let cursor = *ctx.cursor_byte;
+    let (mut outbox, coalescer) = outbox(ctx);
+    coalescer.send(op, &mut outbox, cursor, Instant::now());
+}
+
+/// Sends typed characters that are still batched.
+fn flush_typing(ctx: &mut KeyContext<'_>) {
+    let cursor = *ctx.cursor_byte;
+    let (mut outbox, coalescer) = outbox(ctx);
+    coalescer.flush(&mut outbox, cursor);
+}
+
+/// Where the buffer's edits go, and the typed characters batched in front.
+fn outbox<'b>(ctx: &'b mut KeyContext<'_>) -> (Outbox<'b>, &'b mut Coalescer) {
+    let outbox = Outbox {
+        out_tx: ctx.out_tx,
+        pending: ctx.pending,
+        sync: ctx.sync,
+        doc_id: ctx.doc_id,
+        user_id: ctx.local_user_id.unwrap_or(""),
+        version: ctx.version,
+    };
+    (outbox, ctx.coalescer)
+}
+
+fn move_cursor(ctx: &mut KeyContext<'_>, target: usize, extend: bool) {
+    if extend {
+        if ctx.selection_anchor.is_none() {
+            *ctx.selection_anchor = Some(*ctx.cursor_byte);
+        }
+    } else {
+        *ctx.selection_anchor = None;
+    }
+    *ctx.cursor_byte = target;
+    ctx.undo.seal();
+    send_cursor(ctx);
+}
+
+/// Inserts `insert` at the cursor as a single Insert op and moves the cursor
+/// to the end of the inserted text.
+fn insert_text(ctx: &mut KeyContext<'_>, insert: &str) {
+    let edit = Edit::Insert {
+        pos: *ctx.cursor_byte,
+        text: insert.to_string(),
+    };
+    apply_edit(ctx, &edit);
+    *ctx.cursor_byte = edit.cursor_after();
+    ctx.undo.record(edit);
+}
+
+/// Edits for typing `ch` in overwrite mode: the character under the cursor
+/// is deleted and `ch` inserted in its place. At a line end or the end of
+/// the text `ch` is only inserted.
+fn overwrite_edits(text: &str, cursor_byte: usize, ch: char) -> Vec<Edit> {
+    let insert = Edit::Insert {
+        pos: cursor_byte,
+        text: ch.to_string(),
+    };
+    let end = next_grapheme_boundary(text, cursor_byte);
+    let under = &text[cursor_byte..end];
+    if under.is_empty() || under.starts_with(['\n', '\r']) {
+        return vec![insert];
+    }
+    let delete = Edit::Delete {
+        pos: cursor_byte,
+        text: under.to_string(),
+    };
+    vec![delete, insert]
+}
+
+/// Deletes the byte range `start..end` as a single Delete op and leaves the
+/// cursor at `start`.
+fn delete_range(ctx: &mut KeyContext<'_>, start: usize, end: usize) {
+    let edit = Edit::Delete {
+        pos: start,
+        text: ctx.doc_state.get_text()[start..end].to_string(),
+    };
+    apply_edit(ctx, &edit);
+    *ctx.cursor_byte = start;
+    ctx.undo.record(edit);
+}
+
+/// Deletes `start..end` as one op and puts the removed text on the
+/// clipboard so Ctrl+V brings it back.
+fn kill_range(ctx: &mut KeyContext<'_>, text: &str, start: usize, end: usize) {
+    *ctx.selection_anchor = None;
+    let Some((start, end)) = clamped_range(text, start, end) else {
+        return;
+    };
+    ctx.clipboard.copy(&text[start..end]);
+    delete_range(ctx, start, end);
+}
+
+/// Copies the cursor line below itself as a single Insert and moves the
+/// cursor into the copy, keeping its column.
+fn duplicate_line(ctx: &mut KeyContext<'_>, text: &str) {
+    *ctx.selection_anchor = None;
+    let start = line_start(text, *ctx.cursor_byte);
+    let end = line_end(text, *ctx.cursor_byte);
+    if !room_for(ctx, text, end - start + 1) {
+        return;
+    }
+    let target = end + 1 + (*ctx.cursor_byte - start);
+    *ctx.cursor_byte = end;
+    insert_text(ctx, &format!("\n{}", &text[start..end]));
+    *ctx.cursor_byte = target;
+    send_cursor(ctx);
+}
+
+/// Says why an edit of a read-only document did nothing.
+fn refuse_edit(ctx: &mut KeyContext<'_>) {
+    ctx.status
+        .error("read-only: the document's ACL doesn't let you edit it");
+}
+
+/// Applies an edit locally and sends it as a regular op.
+fn apply_edit(ctx: &mut KeyContext<'_>, edit: &Edit) {
+    let op = edit.to_op();
+    apply_op_to_doc(ctx.doc_state, &op);
+    shift_remote_positions(&op, ctx.cursors, ctx.selections, ctx.edit_flashes);
+    send_op(ctx, op);
+}
+
+fn replay_edits(ctx: &mut KeyContext<'_>, edits: &[Edit], action: &str) {
+    *ctx.selection_anchor = None;
+    for edit in edits {
+        let text = ctx.doc_state.get_text();
+        if !edit.applies_to(&text) {
+            ctx.status
+                .error(format!("{} stopped: document changed underneath", action));
+            send_cursor(ctx);
+            return;
+        }
+        if let Edit::Insert { text: insert, .. } = edit
+            && let Err(err) = ctx.limits.check_doc_size(text.len(), insert.len())
+        {
+            ctx.status.error(format!("{} stopped: {}", action, err));
+            send_cursor(ctx);
+            return;
+        }
+        apply_edit(ctx, edit);
+        *ctx.cursor_byte = edit.cursor_after();
+    }
+    send_cursor(ctx);
+    ctx.status.clear();
+}
+
+fn delete_selection(ctx: &mut KeyContext<'_>, text: &str) -> bool {
+    let selection = selected(text, ctx.selection_anchor.take(), *ctx.cursor_byte);
+    let Some((start, end)) = selection else {
+        return false;
+    };
+    delete_range(ctx, start, end);
+    true
+}
+
+/// The selection as a range of `text`. A remote edit may have left the
+/// anchor past the end or inside a character, so both ends are clamped.
+fn selected(text: &str, anchor: Option<usize>, cursor_byte: usize) -> Option<(usize, usize)> {
+    let (start, end) = selection_range(anchor, cursor_byte)?;
+    clamped_range(text, start, end)
+}
+
+/// `start..end` clamped to character boundaries of `text`; `None` if that
+/// leaves nothing.
+fn clamped_range(text: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    let (start, end) = (clamp_to_boundary(text, start), clamp_to_boundary(text, end));
+    (start < end).then_some((start, end))
+}
+
+fn selection_range(anchor: Option<usize>, cursor_byte: usize) -> Option<(usize, usize)> {
+    let anchor = anchor?;
+    if anchor == cursor_byte {
+        return None;
+    }
+    Some((anchor.min(cursor_byte), anchor.max(cursor_byte)))
+}
+
+fn clipboard_status(clipboard: &Clipboard, action: &str, bytes: usize) -> String {
+    if clipboard.is_system() {
+        format!("{} {} bytes", action, bytes)
+    } else {
+        format!("{} {} bytes (internal register)", action, bytes)
+    }
+}
+
+struct RenderContext<'a> {
+    addr: &'a str,
+    room: &'a str,
+    doc: &'a str,
+    text: &'a str,
+    cursor_byte: usize,
+    selection: Option<(usize, usize)>,
+    users_count: usize,
+    version: u64,
+    /// For the warning once the document nears the server's size limit.
+    limits: ServerLimits,
+    status: &'a StatusLog,
+    /// Scroll offset of the message log overlay, when open.
+    log_scroll: Option<usize>,
+    scroll: &'a mut usize,
+    hscroll: &'a mut usize,
+    free_scroll: bool,
+    search: Option<&'a SearchState>,
+    command_prompt: Option<&'a CommandPrompt>,
+    sidebar_open: bool,
+    /// The activity feed, while its panel is open.
+    feed: Option<&'a VecDeque<ActivityEntry>>,
+    scrollbar: bool,
+    gutter: bool,
+    /// Highlight Markdown syntax.
+    #[cfg(feature = "markdown")]
+    markdown: bool,
+    /// Underline misspelled words; `None` without a dictionary.
+    #[cfg(feature = "spellcheck")]
+    speller: Option<&'a mut spell::Speller>,
+    /// Draw tabs, trailing spaces and control characters as visible glyphs.
+    show_invisibles: bool,
+    overwrite: bool,
+    following: Option<&'a str>,
+    /// Status text replacing the status row while offline.
+    disconnected: Option<&'a str>,
+    /// Status text replacing the status row while a revision is shown.
+    history: Option<&'a str>,
+    flash_line: Option<usize>,
+    cursors: &'a HashMap<String, usize>,
+    selections: &'a HashMap<String, RemoteSelection>,
+    last_activity: &'a HashMap<String, Instant>,
+    edit_flashes: &'a HashMap<String, EditFlash>,
+    cursor_config: CursorConfig,
+    users: &'a HashMap<String, String>,
+    guests: &'a HashSet<String>,
+    local_user_id: Option<&'a str>,
+    theme: &'a Theme,
+    /// Open buffers; empty while there is only one, hiding the tab bar.
+    tabs: &'a [TabLabel],
+    /// Connection health for the dot at the left of the status row.
+    link: Option<LinkState>,
+    /// Counters for the debug overlay (F12) while it is open.
+    debug: Option<DebugStats>,
+    /// Whether the command list (`:help`) is open.
+    help: bool,
+    /// For the key hints in the status row and the help.
+    bindings: &'a Bindings,
+    /// The doc and table rows of an open `:stats`.
+    stats: Option<(&'a str, &'a [String])>,
+    /// The `:docs` list while it is open.
+    picker: Option<&'a Picker>,
+}
+
+/// The part of the document visible in the content area, in lines and
+/// columns.
+#[derive(Debug, Clone, Copy)]
+struct Viewport {
+    top: usize,
+    left: usize,
+    /// Screen row of the first content line.
+    y: usize,
+    rows: usize,
+    cols: usize,
+    /// Whether invisible characters are drawn as glyphs.
+    invisibles: bool,
+}
+
+impl Viewport {
+    /// Screen cell (column, row) of a text position, if it is visible.
+    fn cell(&self, line: usize, col: usize) -> Option<(u16, u16)> {
+        if line < self.top || line >= self.top + self.rows {
+            return None;
+        }
+        if col < self.left || col >= self.left + self.cols {
+            return None;
+        }
+        Some(((col - self.left) as u16, (line - self.top + self.y) as u16))
+    }
+}
+
+/// Draws the frame for `ctx`, sending only what changed since `last_frame`
+/// to the terminal.
+fn render(
+    ctx: &mut RenderContext<'_>,
+    last_frame: &mut Option<Screen>,
+) -> Result<(), Box<dyn Error>> {
+    let (cols, rows) = terminal::size()?;
+    let (frame, cursor) = compose(ctx, cols as usize, rows as usize);
+    show(frame, cursor, last_frame)
+}
+
+/// Lays out a full frame and returns it along with where the terminal
+/// cursor belongs.
+fn compose(ctx: &mut RenderContext<'_>, cols: usize, rows: usize) -> (Screen, Option<(u16, u16)>) {
+    let mut screen = Screen::new(cols, rows);
+    let tab_rows = usize::from(!ctx.tabs.is_empty());
+    let content_height = rows.saturating_sub(1 + tab_rows);
+    let feed_height = feed_rows(content_height, ctx.feed.is_some());
+    let content_height = content_height - feed_height;
+
+    let (cursor_line, cursor_col) = cursor_line_col(ctx.text, ctx.cursor_byte);
+    let text_cols = content_width(cols, ctx.sidebar_open, ctx.scrollbar, ctx.gutter);
+    // Gutter, scrollbar and sidebar start here, left to right.
+    let bar_left = content_width(cols, ctx.sidebar_open, ctx.scrollbar, false);
+    let panel_left = content_width(cols, ctx.sidebar_open, false, false);
+    let followed_pos = ctx
+        .following
+        .and_then(|user_id| ctx.cursors.get(user_id))
+        // The followed cursor may point past the end after a large delete.
+        .map(|pos| clamp_to_boundary(ctx.text, *pos));
+    if let Some(pos) = followed_pos {
+        let (line, col) = cursor_line_col(ctx.text, pos);
+        let line_count = ctx.text.split('\n').count();
+        *ctx.scroll = centered_scroll(line, content_height, line_count);
+        *ctx.hscroll = follow_cursor_column(*ctx.hscroll, col, text_cols);
+    } else if ctx.free_scroll {
+        // Keep the wheel-scrolled viewport, only clamped to the document.
+        let line_count = ctx.text.split('\n').count();
+        *ctx.scroll = (*ctx.scroll).min(line_count.saturating_sub(1));
+    } else if cursor_line < *ctx.scroll {
+        *ctx.scroll = cursor_line;
+    } else if cursor_line >= *ctx.scroll + content_height {
+        *ctx.scroll = cursor_line + 1 - content_height;
+    }
+    if followed_pos.is_none() {
+        *ctx.hscroll = follow_cursor_column(*ctx.hscroll, cursor_col, text_cols);
+    }
+
+    let view = Viewport {
+        top: *ctx.scroll,
+        left: *ctx.hscroll,
+        y: tab_rows,
+        rows: content_height,
+        cols: text_cols,
+        invisibles: ctx.show_invisibles,
+    };
+
+    let lines: Vec<&str> = ctx.text.split('\n').collect();
+    let start = view.top.min(lines.len());
+    let end = (start + view.rows).min(lines.len());
+
+    // Fenced code blocks depend on the lines above the viewport.
+    #[cfg(feature = "markdown")]
+    let mut markdown_state = ctx
+        .markdown
+        .then(|| markdown::LineState::after(lines[..start].iter().copied()));
+    for (row, line) in lines[start..end].iter().enumerate() {
+        let clipped = clip_line_window(line, view.left, view.cols, view.invisibles);
+        screen.put(0, view.y + row, &clipped, Style::default());
+        #[cfg(feature = "markdown")]
+        if let Some(state) = markdown_state.as_mut() {
+            render_markdown(&mut screen, line, view, view.y + row, state);
+        }
+        #[cfg(feature = "spellcheck")]
+        if let Some(speller) = ctx.speller.as_deref_mut() {
+            render_spelling(&mut screen, line, view, view.y + row, speller);
+        }
+        if view.invisibles {
+            render_invisible_marks(&mut screen, line, view, view.y + row);
+        }
+    }
+
+    if let Some(line) = ctx.flash_line
+        && let Some((_, row)) = view.cell(line, view.left)
+    {
+        let starts = line_starts(ctx.text);
+        let (start, end) = line_range(ctx.text, &starts, line);
+        let visible =
+            clip_line_window(&ctx.text[start..end], view.left, view.cols, view.invisibles);
+        let padding = view.cols.saturating_sub(text_width(&visible));
+        let padded = format!("{}{}", visible, " ".repeat(padding));
+        let style = Style::colored(Color::DarkBlue, Color::White);
+        screen.put(0, row as usize, &padded, style);
+    }
+
+    if let Some(search) = ctx.search {
+        let starts = line_starts(ctx.text);
+        let visible_start = starts.get(view.top).copied().unwrap_or(ctx.text.len());
+        let visible_end = starts
+            .get(view.top + view.rows)
+            .copied()
+            .unwrap_or(ctx.text.len());
+        for (start, end) in search::find_matches(ctx.text, search.prompt.input()) {
+            if end < visible_start {
+                continue;
+            }
+            if start > visible_end {
+                break;
+            }
+            let bg = if start == ctx.cursor_byte {
+                Color::DarkYellow
+            } else {
+                Color::Yellow
+            };
+            let style = Style::colored(bg, Color::Black);
+            render_range(&mut screen, ctx.text, view, (start, end), style);
+        }
+    }
+
+    // Most recently changed selection last, so it wins where they overlap.
+    let mut remote_selections: Vec<(&String, &RemoteSelection)> = ctx
+        .selections
+        .iter()
+        .filter(|(user_id, _)| Some(user_id.as_str()) != ctx.local_user_id)
+        .collect();
+    remote_selections.sort_by_key(|(_, selection)| selection.seq);
+    for (user_id, selection) in remote_selections {
+        let style = ctx.theme.selection_tint(user_id);
+        render_range(
+            &mut screen,
+            ctx.text,
+            view,
+            selection.range(ctx.text),
+            style,
+        );
+    }
+
+    let now = Instant::now();
+    for (user_id, flash) in ctx.edit_flashes {
+        if now.duration_since(flash.at) > ctx.cursor_config.edit_flash() {
+            continue;
+        }
+        let style = Style::colored(ctx.theme.user_color(user_id), Color::Black);
+        render_range(&mut screen, ctx.text, view, flash.range(ctx.text), style);
+    }
+
+    if let Some(selection) = ctx.selection {
+        render_range(&mut screen, ctx.text, view, selection, ctx.theme.selection);
+    }
+
+    render_local_cursor(&mut screen, ctx, view);
+
+    let local_cell = view.cell(cursor_line, cursor_col);
+    render_remote_cursors(&mut screen, ctx, view, local_cell);
+
+    if text_cols < bar_left {
+        let users = gutter_users(
+            ctx.text,
+            ctx.cursors,
+            ctx.selections,
+            ctx.local_user_id,
+            (view.top, view.rows),
+        );
+        render_gutter(&mut screen, ctx.theme, (text_cols, view.y), &users);
+    }
+
+    if bar_left < panel_left {
+        let line_count = ctx.text.split('\n').count();
+        let ticks: Vec<(usize, Color)> = ctx
+            .cursors
+            .iter()
+            .filter(|(user_id, _)| Some(user_id.as_str()) != ctx.local_user_id)
+            .map(|(user_id, pos)| {
+                let (line, _) = cursor_line_col(ctx.text, *pos);
+                (line, ctx.theme.user_color(user_id))
+            })
+            .collect();
+        let area = (bar_left, view.y, content_height);
+        render_scrollbar(&mut screen, area, (view.top, line_count), &ticks);
+    }
+
+    if panel_left < cols {
+        let entries = sidebar_entries(
+            ctx.text,
+            ctx.users,
+            ctx.guests,
+            ctx.cursors,
+            ctx.selections,
+            ctx.local_user_id,
+            ctx.cursor_byte,
+        );
+        let area = (panel_left, view.y, content_height);
+        render_sidebar(&mut screen, ctx.theme, area, &entries);
+    }
+
+    if tab_rows > 0 {
+        render_tab_bar(&mut screen, ctx.tabs, ctx.theme);
+    }
+
+    if let Some(feed) = ctx.feed
+        && feed_height > 0
+    {
+        let hint = ctx.bindings.hint(Action::Activity);
+        let area = (view.y + content_height, feed_height, cols);
+        render_feed(&mut screen, feed, &hint, area);
+    }
+
+    if let Some(stats) = ctx.debug {
+        render_debug_overlay(&mut screen, &stats, (text_cols, view.y, content_height));
+    }
+
+    if let Some(CommandPrompt {
+        action:
+            PromptAction::RestoreBackup {
+                contents,
+                diff: Some(scroll),
+            },
+        ..
+    }) = ctx.command_prompt
+    {
+        render_backup_diff(
+            &mut screen,
+            ctx.text,
+            contents,
+            *scroll,
+            (view.y, content_height, cols),
+        );
+    }
+
+    if ctx.help {
+        render_help(&mut screen, ctx.bindings, (view.y, content_height, cols));
+    }
+
+    if let Some((doc, rows)) = ctx.stats {
+        render_stats(&mut screen, doc, rows, (view.y, content_height, cols));
+    }
+
+    if let Some(picker) = ctx.picker {
+        let area = (view.y, content_height, cols);
+        picker.render(&mut screen, area, ctx.theme.selection);
+    }
+
+    if let Some(scroll) = ctx.log_scroll {
+        render_status_log(
+            &mut screen,
+            ctx.status,
+            scroll,
+            (view.y, content_height, cols),
+        );
+    }
+
+    let current = ctx.status.current();
+    let status_msg = current.map_or("", |entry| entry.text.as_str());
+    let cursor_summary =
+        build_cursor_summary(ctx.text, ctx.cursors, ctx.users, ctx.local_user_id, 3);
+    let status = format!(
+        "{} | room={} doc={} users={} v={} pos={} | {} | {} quit | {} sync {}",
+        ctx.addr,
+        ctx.room,
+        ctx.doc,
+        ctx.users_count,
+        ctx.version,
+        ctx.cursor_byte,
+        if cursor_summary.is_empty() {
+            "cursors: -"
+        } else {
+            &cursor_summary
+        },
+        ctx.bindings.hint(Action::Quit),
+        ctx.bindings.hint(Action::Sync),
+        if status_msg.is_empty() { "" } else { "|" }
+    );
+    let invisibles = invisibles::badge(ctx.text).map(|badge| {
+        if ctx.text.contains('\r') {
+            format!(
+                "{} ({} normalize)",
+                badge,
+                ctx.bindings.hint(Action::Normalize)
+            )
+        } else {
+            badge.to_string()
+        }
+    });
+    let badge = [size_badge(ctx.limits, ctx.text.len()), invisibles]
+        .into_iter()
+        .flatten()
+        .reduce(|a, b| format!("{} {}", a, b));
+    let status = match &badge {
+        Some(badge) => format!("{} | {}", badge, status),
+        None => status,
+    };
+    let status = if ctx.overwrite {
+        format!("OVR | {}", status)
+    } else {
+        status
+    };
+    let status = match ctx.following {
+        Some(user_id) => {
+            let name = ctx.users.get(user_id).map_or(user_id, String::as_str);
+            format!(
+                "following {} ({} next, Esc stop) | {}",
+                name,
+                ctx.bindings.hint(Action::Follow),
+                status
+            )
+        }
+        None => status,
+    };
+    let status_line = if let Some(search) = ctx.search {
+        format!(
+            "{}  | {} | Enter accept, F3/Up/Down cycle, Esc cancel",
+            search.prompt.line(),
+            status_msg
+        )
+    } else if let Some(picker) = ctx.picker {
+        picker.prompt().line()
+    } else if let Some(command_prompt) = ctx.command_prompt {
+        format!(
+            "{}  | {}",
+            command_prompt.prompt.line(),
+            command_prompt.hint()
+        )
+    } else if status_msg.is_empty() {
+        status
+    } else {
+        format!("{} {}", status, status_msg)
+    };
+
+    let indicator = if ctx.link.is_some() { "● " } else { "" };
+    let indent = text_width(indicator);
+    let status_line = format!("{}{}", indicator, status_line);
+
+    let status_row = rows.saturating_sub(1);
+    let banner = match (ctx.disconnected, ctx.history) {
+        (Some(banner), _) => Some((banner, Style::colored(Color::Red, Color::White))),
+        (None, Some(banner)) => Some((banner, Style::colored(Color::Yellow, Color::Black))),
+        (None, None) => None,
+    };
+    match banner {
+        Some((banner, style)) => {
+            screen.put(0, status_row, &format!("{:<cols$}", banner), style);
+        }
+        None => {
+            let padding = cols.saturating_sub(text_width(&status_line));
+            let padded = format!("{}{}", status_line, " ".repeat(padding));
+            screen.put(0, status_row, &padded, ctx.theme.status);
+            if let Some(state) = ctx.link {
+                let style = Style {
+                    fg: Some(state.color()),
+                    ..ctx.theme.status
+                };
+                screen.restyle(0, status_row, 1, |_| style);
+            }
+            if let Some(badge) = badge
+                && ctx.following.is_none()
+                && status_line[indicator.len()..].starts_with(&badge)
+            {
+                let style = Style::colored(Color::Yellow, Color::Black);
+                screen.put(indent, status_row, &badge, style);
+            }
+            let highlight = match current.map(|entry| entry.severity) {
+                Some(Severity::Error) => Some(Style::colored(Color::DarkRed, Color::White)),
+                Some(Severity::Notice) => Some(Style::colored(Color::DarkBlue, Color::White)),
+                _ => None,
+            };
+            if let Some(style) = highlight
+                && ctx.command_prompt.is_none()
+            {
+                let len = text_width(status_msg);
+                let col = text_width(&status_line) - len;
+                screen.restyle(col, status_row, len, |_| style);
+            }
+        }
+    }
+
+    let status_prompt = ctx
+        .search
+        .map(|search| &search.prompt)
+        .or(ctx.picker.map(Picker::prompt))
+        .or(ctx
+            .command_prompt
+            .map(|command_prompt| &command_prompt.prompt));
+    let cursor = match status_prompt {
+        Some(prompt) => {
+            let col = (indent + prompt.cursor_col()).min(cols.saturating_sub(1));
+            Some((col as u16, status_row as u16))
+        }
+        None if ctx.log_scroll.is_some() => None,
+        None => local_cell,
+    };
+    (screen, cursor)
+}
+
+/// Scroll offset that puts `line` in the middle of the content area, clamped
+/// so the document end does not scroll past the top.
+/// `⚠ SIZE 97%` once the document is within 5% of the size the server
+/// takes.
+fn size_badge(limits: ServerLimits, doc_bytes: usize) -> Option<String> {
+    let max = limits
+        .max_doc_bytes
+        .filter(|_| limits.near_doc_limit(doc_bytes))?;
+    let percent = doc_bytes.saturating_mul(100) / max.max(1);
+    Some(format!("⚠ SIZE {}%", percent))
+}
+
+fn centered_scroll(line: usize, height: usize, line_count: usize) -> usize {
+    line.saturating_sub(height / 2)
+        .min(line_count.saturating_sub(1))
+}
+
+/// Returns the horizontal scroll offset that keeps `cursor_col` at least
+/// `HSCROLL_MARGIN` columns away from either edge of a `width`-wide window.
+fn follow_cursor_column(hscroll: usize, cursor_col: usize, width: usize) -> usize {
+    if width == 0 {
+        return 0;
+    }
+    let margin = HSCROLL_MARGIN.min(width / 4);
+    if cursor_col < hscroll + margin {
+        cursor_col.saturating_sub(margin)
+    } else if cursor_col + margin >= hscroll + width {
+        cursor_col + margin + 1 - width
+    } else {
+        hscroll
+    }
+}
+
+/// Splits `line` into terminal cells: `Some(glyph)` where a grapheme
+/// starts and `None` for the extra cells of wide graphemes. Glyphs take as
+/// many cells as their grapheme, so columns map to the same bytes whether
+/// or not `show_invisibles` substitutes them.
+fn line_cells(line: &str, show_invisibles: bool) -> Vec<Option<&str>> {
+    let trailing = invisibles::trailing_whitespace_start(line);
+    let mut cells = Vec::new();
+    for (idx, grapheme) in line.grapheme_indices(true) {
+        let (glyph, _) = invisibles::glyph(grapheme, idx >= trailing, show_invisibles);
+        cells.push(Some(glyph));
+        cells.extend(std::iter::repeat_n(None, grapheme_width(grapheme) - 1));
+    }
+    cells
+}
+
+/// Joins cells back into text, blanking wide graphemes that were cut in
+/// half by the edges of the window the cells were taken from.
+fn cells_to_string(cells: &[Option<&str>]) -> String {
+    let mut out = String::new();
+    let mut covered = 0;
+    for (idx, cell) in cells.iter().enumerate() {
+        match cell {
+            Some(grapheme) => {
+                covered = 0;
+                let width = grapheme_width(grapheme);
+                let tail = cells.get(idx + 1..idx + width);
+                if tail.is_some_and(|tail| tail.iter().all(Option::is_none)) {
+                    out.push_str(grapheme);
+                    covered = width - 1;
+                } else {
+                    out.push(' ');
+                }
+            }
+            None if covered > 0 => covered -= 1,
+            None => out.push(' '),
+        }
+    }
+    out
+}
+
+/// Replaces one cell, blanking the rest of a wide grapheme it overwrites.
+fn set_cell<'a>(cells: &mut [Option<&'a str>], idx: usize, value: &'a str) {
+    cells[idx] = Some(value);
+    for cell in cells[idx + 1..].iter_mut() {
+        if cell.is_some() {
+            break;
+        }
+        *cell = Some(" ");
+    }
+}
+
+/// Like `clip_line` but starting `offset` cells into the line, with `…`
+/// replacing the edge cells when content is hidden on that side.
+fn clip_line_window(line: &str, offset: usize, width: usize, show_invisibles: bool) -> String {
+    if width == 0 {
+        return String::new();
+    }
+    let all = line_cells(line, show_invisibles);
+    let mut cells: Vec<Option<&str>> = all.iter().skip(offset).take(width + 1).copied().collect();
+    let clipped_right = cells.len() > width;
+    cells.truncate(width);
+    if offset > 0 && !line.is_empty() {
+        if cells.is_empty() {
+            cells.push(Some("…"));
+        } else {
+            set_cell(&mut cells, 0, "…");
+        }
+    }
+    if clipped_right {
+        set_cell(&mut cells, width - 1, "…");
+    }
+    cells_to_string(&cells)
+}
+
+/// Line index and display column (in terminal cells) of a byte offset.
+pub(crate) fn cursor_line_col(text: &str, cursor_byte: usize) -> (usize, usize) {
+    let cursor_byte = clamp_to_boundary(text, cursor_byte);
+    let before = &text[..cursor_byte];
+    let line = before.matches('\n').count();
+    let line_start = before.rfind('\n').map_or(0, |idx| idx + 1);
+    (line, text_width(&before[line_start..]))
+}
+
+/// Byte offset within `line` of the grapheme covering display column `col`,
+/// or the line end past the last grapheme.
+fn byte_at_column(line: &str, col: usize) -> usize {
+    let mut used = 0;
+    for (idx, grapheme) in line.grapheme_indices(true) {
+        used += grapheme_width(grapheme);
+        if used > col {
+            return idx;
+        }
+    }
+    line.len()
+}
+
+fn line_range(text: &str, starts: &[usize], line_idx: usize) -> (usize, usize) {
+    let start = starts.get(line_idx).copied().unwrap_or(0);
+    let mut end = if line_idx + 1 < starts.len() {
+        starts[line_idx + 1]
+    } else {
+        text.len()
+    };
+    if end > start && text.as_bytes()[end - 1] == b'\n' {
+        end -= 1;
+    }
+    (start, end)
+}
+
+fn line_start(text: &str, cursor_byte: usize) -> usize {
+    let starts = line_starts(text);
+    let (line_idx, _) = cursor_line_col(text, cursor_byte);
+    starts.get(line_idx).copied().unwrap_or(0)
+}
+
+fn line_end(text: &str, cursor_byte: usize) -> usize {
+    let starts = line_starts(text);
+    let (line_idx, _) = cursor_line_col(text, cursor_byte);
+    let (start, end) = line_range(text, &starts, line_idx);
+    if end < start { start } else { end }
+}
+
+/// Home: the first non-blank character of the line, or column 0 when the
+/// cursor is already there or the line is blank.
+fn smart_home(text: &str, cursor_byte: usize) -> usize {
+    let start = line_start(text, cursor_byte);
+    let end = line_end(text, cursor_byte);
+    let indent = text[start..end]
+        .bytes()
+        .take_while(|byte| matches!(byte, b' ' | b'\t'))
+        .count();
+    let first = start + indent;
+    if first == end || first == cursor_byte {
+        start
+    } else {
+        first
+    }
+}
+
+/// End with `smart_end`: the line end, or the end of its last non-blank
+/// character when the cursor is already at the line end.
+fn smart_end(text: &str, cursor_byte: usize) -> usize {
+    let start = line_start(text, cursor_byte);
+    let end = line_end(text, cursor_byte);
+    let last = start + text[start..end].trim_end_matches([' ', '\t']).len();
+    if cursor_byte == end && last > start {
+        last
+    } else {
+        end
+    }
+}
+
+/// Range removed by Ctrl+K: up to the end of the line, or just the newline
+/// when the cursor already sits at the line end.
+fn kill_line_range(text: &str, cursor_byte: usize) -> (usize, usize) {
+    let end = line_end(text, cursor_byte);
+    if cursor_byte == end && end < text.len() {
+        (cursor_byte, end + 1)
+    } else {
+        (cursor_byte, end)
+    }
+}
+
+/// Range of the whole cursor line including one adjacent newline, so that
+/// deleting it removes the line entirely.
+fn whole_line_range(text: &str, cursor_byte: usize) -> (usize, usize) {
+    let start = line_start(text, cursor_byte);
+    let end = line_end(text, cursor_byte);
+    if end < text.len() {
+        (start, end + 1)
+    } else {
+        (start.saturating_sub(1), end)
+    }
+}
+
+fn move_cursor_vertical(text: &str, cursor_byte: usize, direction: i32) -> usize {
+    let starts = line_starts(text);
+    let (line_idx, col) = cursor_line_col(text, cursor_byte);
+    let target_line = if direction < 0 {
+        if line_idx == 0 {
+            return cursor_byte;
+        }
+        line_idx - 1
+    } else {
+        if line_idx + 1 >= starts.len() {
+            return cursor_byte;
+        }
+        line_idx + 1
+    };
+    let (start, end) = line_range(text, &starts, target_line);
+    start + byte_at_column(&text[start..end], col)
+}
+
+/// Start of the grapheme cluster before `pos`, so that e.g. an emoji with a
+/// modifier or a letter with a combining accent is a single cursor step.
+fn prev_grapheme_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    GraphemeCursor::new(pos, text.len(), true)
+        .prev_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(0)
+}
+
+fn next_grapheme_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    GraphemeCursor::new(pos, text.len(), true)
+        .next_boundary(text, 0)
+        .ok()
+        .flatten()
+        .unwrap_or(text.len())
+}
+
+fn is_blank_segment(segment: &str) -> bool {
+    segment.chars().all(char::is_whitespace)
+}
+
+/// Start of the word at or before `pos`, skipping any whitespace in between.
+/// Words follow Unicode word boundaries, so punctuation runs and each CJK
+/// ideograph count as their own word.
+fn prev_word_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    let mut boundary = 0;
+    for (start, segment) in text.split_word_bound_indices() {
+        if start >= pos {
+            break;
+        }
+        if !is_blank_segment(segment) {
+            boundary = start;
+        }
+    }
+    boundary
+}
+
+/// End of the word at or after `pos`, skipping any whitespace in between.
+fn next_word_boundary(text: &str, pos: usize) -> usize {
+    let pos = clamp_to_boundary(text, pos);
+    text.split_word_bound_indices()
+        .map(|(start, segment)| (start + segment.len(), segment))
+        .find(|(end, segment)| *end > pos && !is_blank_segment(segment))
+        .map(|(end, _)| end)
+        .unwrap_or(text.len())
+}
+
+fn unique_suffix() -> u128 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis()
+}
+
+/// Another user's selection, `seq` ordering selections by when they were
+/// last changed.
+struct RemoteSelection {
+    anchor: usize,
+    head: usize,
+    seq: u64,
+}
+
+impl RemoteSelection {
+    fn range(&self, text: &str) -> (usize, usize) {
+        let anchor = clamp_to_boundary(text, self.anchor);
+        let head = clamp_to_boundary(text, self.head);
+        (anchor.min(head), anchor.max(head))
+    }
+}
+
+/// Text another user just inserted, or where they deleted some (an empty
+/// range), highlighted for a moment.
+struct EditFlash {
+    start: usize,
+    end: usize,
+    at: Instant,
+}
+
+impl EditFlash {
+    /// Records an edit by `user_id`, already applied to the document and to
+    /// the existing flashes. Typing or deleting at the edge of a recent flash
+    /// extends it instead of starting over.
+    fn record(flashes: &mut HashMap<String, EditFlash>, user_id: &str, op: &Op, now: Instant) {
+        let (start, end) = match op {
+            Op::Insert { pos, text, .. } => (*pos, pos + text.len()),
+            Op::Delete { pos, .. } => (*pos, *pos),
+            Op::Cursor { .. } | Op::Selection { .. } => return,
+        };
+        if let Some(flash) = flashes.get_mut(user_id)
+            && now.duration_since(flash.at) <= EDIT_FLASH_MERGE
+            && flash.start <= start
+            && end <= flash.end
+        {
+            flash.at = now;
+            return;
+        }
+        flashes.insert(
+            user_id.to_string(),
+            EditFlash {
+                start,
+                end,
+                at: now,
+            },
+        );
+    }
+
+    /// Range to paint: at least the grapheme at `start`, so deletions show.
+    fn range(&self, text: &str) -> (usize, usize) {
+        let start = clamp_to_boundary(text, self.start);
+        let end = clamp_to_boundary(text, self.end);
+        if end > start {
+            return (start, end);
+        }
+        let next = text[start..].graphemes(true).next().map_or(0, str::len);
+        (start, start + next)
+    }
+}
+
+/// Keeps other users' cursors, selections and edit flashes on the same text
+/// when an edit shifts offsets.
+fn shift_remote_positions(
+    op: &Op,
+    cursors: &mut HashMap<String, usize>,
+    selections: &mut HashMap<String, RemoteSelection>,
+    edit_flashes: &mut HashMap<String, EditFlash>,
+) {
+    for flash in edit_flashes.values_mut() {
+        shift_for_op(op, &mut flash.start);
+        shift_for_op(op, &mut flash.end);
+    }
+    for pos in cursors.values_mut() {
+        shift_for_op(op, pos);
+    }
+    for selection in selections.values_mut() {
+        shift_for_op(op, &mut selection.anchor);
+        shift_for_op(op, &mut selection.head);
+    }
+}
+
+fn render_remote_cursors(
+    screen: &mut Screen,
+    ctx: &RenderContext<'_>,
+    view: Viewport,
+    local_cell: Option<(u16, u16)>,
+) {
+    let now = Instant::now();
+    for (user_id, pos) in ctx.cursors {
+        if Some(user_id.as_str()) == ctx.local_user_id {
+            continue;
+        }
+        let (line, col) = cursor_line_col(ctx.text, *pos);
+        let Some((col, row)) = view.cell(line, col) else {
+            continue;
+        };
+        let cell = cursor_cell(ctx.text, *pos, view.cols - col as usize, view.invisibles);
+        let last_activity = ctx.last_activity.get(user_id);
+        let stale = last_activity
+            .is_some_and(|at| now.duration_since(*at) > ctx.cursor_config.fade_after());
+        let style = ctx.theme.remote_cursor(user_id, stale);
+        screen.put(col as usize, row as usize, cell, style);
+
+        let recently_moved =
+            last_activity.is_some_and(|at| now.duration_since(*at) <= CURSOR_LABEL_TTL);
+        if !recently_moved {
+            continue;
+        }
+        let name = ctx.users.get(user_id).unwrap_or(user_id);
+        let label = clip_line(name, CURSOR_LABEL_MAX_CHARS);
+        let Some((label_col, label_row, label)) =
+            place_cursor_label(&label, (col, row), text_width(cell), local_cell, view.cols)
+        else {
+            continue;
+        };
+        screen.put(label_col as usize, label_row as usize, &label, style);
+    }
+}
+
+/// Picks where to draw a remote cursor's name label: right after the cursor
+/// cell (`cursor_width` cells wide), or on the row above if that would cover the local cursor. Labels
+/// are clipped at the right edge and never placed over the local cursor.
+fn place_cursor_label(
+    label: &str,
+    (col, row): (u16, u16),
+    cursor_width: usize,
+    local_cell: Option<(u16, u16)>,
+    width: usize,
+) -> Option<(u16, u16, String)> {
+    let len = text_width(label);
+    let mut candidates = vec![(col as usize + cursor_width, row)];
+    if row > 0 {
+        candidates.push((col as usize, row - 1));
+    }
+    for (start, row) in candidates {
+        if start >= width {
+            continue;
+        }
+        let visible = len.min(width - start);
+        let covers_local = local_cell.is_some_and(|(local_col, local_row)| {
+            local_row == row && (start..start + visible).contains(&(local_col as usize))
+        });
+        if !covers_local {
+            return Some((start as u16, row, clip_line(label, visible)));
+        }
+    }
+    None
+}
+
+/// Paints the byte range `sel_start..sel_end` with the given colors, clipped
+/// to the viewport.
+fn render_range(
+    screen: &mut Screen,
+    text: &str,
+    view: Viewport,
+    (sel_start, sel_end): (usize, usize),
+    style: Style,
+) {
+    let starts = line_starts(text);
+    let last = (view.top + view.rows).min(starts.len());
+    for line_idx in view.top..last {
+        let (start, end) = line_range(text, &starts, line_idx);
+        let from = sel_start.max(start);
+        let to = sel_end.min(end);
+        // A selection that continues past the end of the line also covers its newline.
+        let covers_newline = sel_start <= end && sel_end > end && end < text.len();
+        if from >= to && !covers_newline {
+            continue;
+        }
+        let col = text_width(&text[start..from.min(end)]);
+        let mut cells = if from < to {
+            // Cells of the whole line, so trailing whitespace is recognised.
+            let line = line_cells(&text[start..end], view.invisibles);
+            line[col..col + text_width(&text[from..to])].to_vec()
+        } else {
+            Vec::new()
+        };
+        if covers_newline {
+            cells.push(Some(" "));
+        }
+        // Drop the part of the selection left of the window, then clip right.
+        let hidden = view.left.saturating_sub(col);
+        let Some((screen_col, row)) = view.cell(line_idx, col + hidden) else {
+            continue;
+        };
+        let window: Vec<Option<&str>> = cells
+            .into_iter()
+            .skip(hidden)
+            .take(view.cols - screen_col as usize)
+            .collect();
+        let visible = cells_to_string(&window);
+        screen.put(screen_col as usize, row as usize, &visible, style);
     }
+}
 
-    out.flush()?;
-    Ok(())
+fn render_local_cursor(screen: &mut Screen, ctx: &RenderContext<'_>, view: Viewport) {
+    let (line, col) = cursor_line_col(ctx.text, ctx.cursor_byte);
+    let Some((col, row)) = view.cell(line, col) else {
+        return;
+    };
+    let cell = cursor_cell(
+        ctx.text,
+        ctx.cursor_byte,
+        view.cols - col as usize,
+        view.invisibles,
+    );
+    screen.put(col as usize, row as usize, cell, ctx.theme.cursor);
 }
 
-fn clip_line(line: &str, max_width: usize) -> String {
-    if max_width == 0 {
-        return String::new();
-    }
-    let mut out = String::new();
-    for ch in line.chars().take(max_width) {
-        out.push(ch);
+/// Recolors the invisible-character glyphs of `line` drawn on screen row
+/// `row`: faint for whitespace, a warning color for control characters.
+/// Edge cells showing `…` are left alone.
+fn render_invisible_marks(screen: &mut Screen, line: &str, view: Viewport, row: usize) {
+    let visible = visible_columns(line, view);
+    for (col, glyph, mark) in invisibles::line_marks(line) {
+        if !visible.contains(&col) {
+            continue;
+        }
+        let col = col - view.left;
+        let fg = match mark {
+            invisibles::Mark::Whitespace => Color::DarkGrey,
+            invisibles::Mark::Control => Color::Red,
+        };
+        let style = Style {
+            fg: Some(fg),
+            ..Style::default()
+        };
+        screen.put(col, row, glyph, style);
     }
-    out
 }
 
-fn cursor_line_col(text: &str, cursor_byte: usize) -> (usize, usize) {
-    let cursor_byte = clamp_to_boundary(text, cursor_byte);
-    let mut line = 0usize;
-    let mut col = 0usize;
-    for ch in text[..cursor_byte].chars() {
-        if ch == '\n' {
-            line += 1;
-            col = 0;
-        } else {
-            col += 1;
+/// Line columns that `clip_line_window` shows as text rather than as the
+/// `…` edge markers.
+fn visible_columns(line: &str, view: Viewport) -> std::ops::Range<usize> {
+    let start = view.left + usize::from(view.left > 0 && !line.is_empty());
+    let clipped_right = text_width(line) > view.left + view.cols;
+    let end = view.left + view.cols - usize::from(clipped_right);
+    start..end
+}
+
+/// Restyles the cells of `line` covered by Markdown spans. Spans are byte
+/// ranges of the line, so they are mapped to columns like the cursor is.
+#[cfg(feature = "markdown")]
+fn render_markdown(
+    screen: &mut Screen,
+    line: &str,
+    view: Viewport,
+    row: usize,
+    state: &mut markdown::LineState,
+) {
+    let visible = visible_columns(line, view);
+    for span in markdown::tokenize_line(line, state) {
+        let from = text_width(&line[..span.range.start]).max(visible.start);
+        let to = text_width(&line[..span.range.end]).min(visible.end);
+        if from < to {
+            screen.restyle(from - view.left, row, to - from, |style| {
+                span.token.apply(style)
+            });
         }
     }
-    (line, col)
 }
 
-fn line_start_positions(text: &str) -> Vec<usize> {
-    let mut starts = vec![0usize];
-    for (idx, ch) in text.char_indices() {
-        if ch == '\n' {
-            starts.push(idx + ch.len_utf8());
+/// Underlines the words of `line` the dictionary doesn't know in red.
+/// Words not checked yet are queued and drawn plain until their verdict
+/// arrives.
+#[cfg(feature = "spellcheck")]
+fn render_spelling(
+    screen: &mut Screen,
+    line: &str,
+    view: Viewport,
+    row: usize,
+    speller: &mut spell::Speller,
+) {
+    let visible = visible_columns(line, view);
+    for range in spell::words(line) {
+        let from = text_width(&line[..range.start]).max(visible.start);
+        let to = text_width(&line[..range.end]).min(visible.end);
+        if from >= to || speller.check(&line[range]) != Some(false) {
+            continue;
         }
+        screen.restyle(from - view.left, row, to - from, |style| Style {
+            underline: true,
+            underline_color: Some(Color::Red),
+            ..style
+        });
     }
-    if starts.is_empty() {
-        starts.push(0);
+}
+
+/// Rows the activity feed panel takes from the bottom of `content_height`
+/// while it is `open`; none if too little would be left for the text.
+fn feed_rows(content_height: usize, open: bool) -> usize {
+    if open && content_height >= FEED_ROWS * 2 {
+        FEED_ROWS
+    } else {
+        0
     }
-    starts
 }
 
-fn line_range(text: &str, starts: &[usize], line_idx: usize) -> (usize, usize) {
-    let start = starts.get(line_idx).copied().unwrap_or(0);
-    let mut end = if line_idx + 1 < starts.len() {
-        starts[line_idx + 1]
+/// Width of the text area once the sidebar (if open and if it fits), the
+/// scrollbar and the presence gutter (if it fits) are taken out.
+fn content_width(cols: usize, sidebar_open: bool, scrollbar: bool, gutter: bool) -> usize {
+    let cols = if sidebar_open && cols >= SIDEBAR_WIDTH * 2 {
+        cols - SIDEBAR_WIDTH
     } else {
-        text.len()
+        cols
     };
-    if end > start && text.as_bytes()[end - 1] == b'\n' {
-        end -= 1;
+    let cols = if scrollbar && cols > 1 {
+        cols - 1
+    } else {
+        cols
+    };
+    if gutter && cols >= GUTTER_WIDTH * 2 {
+        cols - GUTTER_WIDTH
+    } else {
+        cols
     }
-    (start, end)
 }
 
-fn line_start(text: &str, cursor_byte: usize) -> usize {
-    let starts = line_start_positions(text);
-    let (line_idx, _) = cursor_line_col(text, cursor_byte);
-    starts.get(line_idx).copied().unwrap_or(0)
+/// First row and length of the scrollbar thumb on a `rows` high track, for
+/// a viewport starting at line `top` of `line_count`.
+fn scrollbar_thumb(top: usize, rows: usize, line_count: usize) -> (usize, usize) {
+    // Scrolling past the end makes the document effectively longer.
+    let total = line_count.max(top + rows).max(1);
+    let len = (rows * rows).div_ceil(total).clamp(1, rows.max(1));
+    let start = (top * rows / total).min(rows.saturating_sub(len));
+    (start, len)
 }
 
-fn line_end(text: &str, cursor_byte: usize) -> usize {
-    let starts = line_start_positions(text);
-    let (line_idx, _) = cursor_line_col(text, cursor_byte);
-    let (start, end) = line_range(text, &starts, line_idx);
-    if end < start { start } else { end }
+/// Draws the scrollbar in column `col` from row `top` down, with a tick in
+/// each remote user's color at their cursor line.
+fn render_scrollbar(
+    screen: &mut Screen,
+    (col, top, rows): (usize, usize, usize),
+    (scroll, line_count): (usize, usize),
+    ticks: &[(usize, Color)],
+) {
+    let (thumb_start, thumb_len) = scrollbar_thumb(scroll, rows, line_count);
+    let total = line_count.max(scroll + rows).max(1);
+    for row in 0..rows {
+        let (glyph, fg) = if (thumb_start..thumb_start + thumb_len).contains(&row) {
+            ("┃", Color::Grey)
+        } else {
+            ("│", Color::DarkGrey)
+        };
+        let style = Style {
+            fg: Some(fg),
+            ..Style::default()
+        };
+        screen.put(col, top + row, glyph, style);
+    }
+    for (line, color) in ticks {
+        let row = (line * rows / total).min(rows.saturating_sub(1));
+        let style = Style {
+            fg: Some(*color),
+            ..Style::default()
+        };
+        screen.put(col, top + row, "━", style);
+    }
 }
 
-fn move_cursor_vertical(text: &str, cursor_byte: usize, direction: i32) -> usize {
-    let starts = line_start_positions(text);
-    let (line_idx, col) = cursor_line_col(text, cursor_byte);
-    let target_line = if direction < 0 {
-        if line_idx == 0 {
-            return cursor_byte;
+/// Remote users whose cursor or selection is on each of the `rows` lines
+/// from `top`, for the presence gutter: one list per row, sorted by user id.
+fn gutter_users<'a>(
+    text: &str,
+    cursors: &'a HashMap<String, usize>,
+    selections: &'a HashMap<String, RemoteSelection>,
+    local_user_id: Option<&str>,
+    (top, rows): (usize, usize),
+) -> Vec<Vec<&'a str>> {
+    let line_of = |pos: usize| cursor_line_col(text, pos).0;
+    let cursor_lines = cursors.iter().map(|(user_id, pos)| {
+        let line = line_of(*pos);
+        (user_id, line, line)
+    });
+    let selection_lines = selections.iter().map(|(user_id, selection)| {
+        let (start, end) = selection.range(text);
+        // A selection ending at a line start takes nothing of that line.
+        let last = if end > start && text[..end].ends_with('\n') {
+            line_of(end) - 1
+        } else {
+            line_of(end)
+        };
+        (user_id, line_of(start), last)
+    });
+    let mut users = vec![Vec::new(); rows];
+    for (user_id, first, last) in cursor_lines.chain(selection_lines) {
+        if Some(user_id.as_str()) == local_user_id {
+            continue;
         }
-        line_idx - 1
-    } else {
-        if line_idx + 1 >= starts.len() {
-            return cursor_byte;
+        for line in first.max(top)..(last + 1).min(top + rows) {
+            users[line - top].push(user_id.as_str());
         }
-        line_idx + 1
-    };
-    let (start, end) = line_range(text, &starts, target_line);
-    let line_text = &text[start..end];
-    let mut byte_offset = 0usize;
-    for (count, ch) in line_text.chars().enumerate() {
-        if count >= col {
-            break;
+    }
+    for row in &mut users {
+        row.sort_unstable();
+        row.dedup();
+    }
+    users
+}
+
+/// Draws a dot in each user's color on their rows of the gutter at `col`,
+/// or how many there are when they don't fit.
+fn render_gutter(
+    screen: &mut Screen,
+    theme: &Theme,
+    (col, top): (usize, usize),
+    users: &[Vec<&str>],
+) {
+    for (row, users) in users.iter().enumerate() {
+        if users.len() > GUTTER_WIDTH {
+            let count = format!("{:>width$}", users.len(), width = GUTTER_WIDTH);
+            let style = Style {
+                fg: Some(Color::Grey),
+                ..Style::default()
+            };
+            screen.put(col, top + row, &count, style);
+            continue;
+        }
+        for (i, user_id) in users.iter().enumerate() {
+            let style = Style {
+                fg: Some(theme.user_color(user_id)),
+                ..Style::default()
+            };
+            screen.put(col + i, top + row, "●", style);
         }
-        byte_offset += ch.len_utf8();
     }
-    start + byte_offset
 }
 
-fn apply_insert(doc: &mut TextDoc, pos: usize, text: &str) {
-    let current = doc.get_text();
-    let char_pos = byte_to_char_index(&current, pos);
-    doc.insert(char_pos, text);
+struct SidebarEntry {
+    user_id: String,
+    name: String,
+    /// Where the user's cursor is, if known.
+    at: Option<LineCol>,
+    is_local: bool,
+    selecting: bool,
+    guest: bool,
 }
 
-fn apply_delete(doc: &mut TextDoc, pos: usize, len: usize) {
-    let current = doc.get_text();
-    if current.is_empty() {
-        return;
-    }
-    let start = clamp_to_boundary(&current, pos);
-    let end = clamp_to_boundary(&current, start.saturating_add(len));
-    if start >= end {
+fn sidebar_entries(
+    text: &str,
+    users: &HashMap<String, String>,
+    guests: &HashSet<String>,
+    cursors: &HashMap<String, usize>,
+    selections: &HashMap<String, RemoteSelection>,
+    local_user_id: Option<&str>,
+    local_cursor: usize,
+) -> Vec<SidebarEntry> {
+    let mut entries: Vec<SidebarEntry> = users
+        .iter()
+        .map(|(id, name)| {
+            let is_local = Some(id.as_str()) == local_user_id;
+            let pos = if is_local {
+                Some(local_cursor)
+            } else {
+                cursors.get(id).copied()
+            };
+            SidebarEntry {
+                user_id: id.clone(),
+                name: name.clone(),
+                at: pos.map(|pos| position::line_col(text, pos)),
+                is_local,
+                selecting: selections.contains_key(id),
+                guest: guests.contains(id),
+            }
+        })
+        .collect();
+    // Local user first, then by name so the list doesn't jump around.
+    entries.sort_by(|a, b| {
+        b.is_local
+            .cmp(&a.is_local)
+            .then_with(|| a.name.cmp(&b.name))
+            .then_with(|| a.user_id.cmp(&b.user_id))
+    });
+    entries
+}
+
+/// Draws the presence sidebar into `(left, top, rows)`.
+/// Draws the message log over the content area (`top`, `rows` rows, `cols`
+/// wide), newest first, starting `scroll` entries down.
+fn render_status_log(
+    screen: &mut Screen,
+    log: &StatusLog,
+    scroll: usize,
+    (top, rows, cols): (usize, usize, usize),
+) {
+    if rows == 0 {
         return;
     }
-    let char_start = current[..start].chars().count();
-    let char_len = current[start..end].chars().count();
-    if char_len > 0 {
-        doc.delete(char_start, char_len);
+    let header = format!("Messages ({}) | Up/Down scroll, F10/Esc close", log.len());
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(
+        0,
+        top,
+        &format!("{:<cols$}", clip_line(&header, cols)),
+        bold,
+    );
+    let now = Instant::now();
+    let mut entries = log.entries().skip(scroll);
+    for row in top + 1..top + rows {
+        let Some(entry) = entries.next() else {
+            screen.put(0, row, &" ".repeat(cols), Style::default());
+            continue;
+        };
+        let age = status::format_age(now.duration_since(entry.at));
+        let line = clip_line(&format!("{:>4}  {}", age, entry.text), cols);
+        let style = match entry.severity {
+            Severity::Info => Style::default(),
+            Severity::Error => Style {
+                fg: Some(Color::Red),
+                ..Style::default()
+            },
+            Severity::Notice => Style {
+                fg: Some(Color::Blue),
+                ..Style::default()
+            },
+        };
+        let padding = cols.saturating_sub(text_width(&line));
+        screen.put(0, row, &format!("{}{}", line, " ".repeat(padding)), style);
     }
 }
 
-fn apply_op_to_doc(doc: &mut TextDoc, op: &Op) {
-    match op {
-        Op::Insert { pos, text } => apply_insert(doc, *pos, text),
-        Op::Delete { pos, len } => apply_delete(doc, *pos, *len),
-        Op::Cursor { .. } => {}
+/// Draws the newest entries of the activity feed into `rows` rows from
+/// `top`, oldest first like a chat.
+fn render_feed(
+    screen: &mut Screen,
+    feed: &VecDeque<ActivityEntry>,
+    hint: &str,
+    (top, rows, cols): (usize, usize, usize),
+) {
+    let header = format!("Activity ({}) | {} closes", feed.len(), hint);
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(
+        0,
+        top,
+        &format!("{:<cols$}", clip_line(&header, cols)),
+        bold,
+    );
+    let now_ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |since| since.as_millis() as u64);
+    let shown = feed.len().min(rows - 1);
+    let mut entries = feed.iter().skip(feed.len() - shown);
+    for row in top + 1..top + rows {
+        let line = match entries.next() {
+            Some(entry) => {
+                let age = Duration::from_millis(now_ms.saturating_sub(entry.at_ms));
+                format!("{:>4}  {}", status::format_age(age), entry.describe())
+            }
+            None => String::new(),
+        };
+        let line = clip_line(&line, cols);
+        let padding = cols.saturating_sub(text_width(&line));
+        screen.put(
+            0,
+            row,
+            &format!("{}{}", line, " ".repeat(padding)),
+            Style::default(),
+        );
     }
 }
 
-fn clamp_to_boundary(text: &str, pos: usize) -> usize {
-    let mut pos = pos.min(text.len());
-    while pos > 0 && !text.is_char_boundary(pos) {
-        pos -= 1;
+/// Draws the palette's commands over the content area (`top`, `rows` rows,
+/// `cols` wide).
+fn render_help(screen: &mut Screen, bindings: &Bindings, (top, rows, cols): (usize, usize, usize)) {
+    if rows == 0 {
+        return;
+    }
+    let header = format!(
+        "Commands ({}, Tab completes) | any key closes",
+        bindings.hint(Action::Palette)
+    );
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(
+        0,
+        top,
+        &format!("{:<cols$}", clip_line(&header, cols)),
+        bold,
+    );
+    let width = commands::COMMANDS
+        .iter()
+        .map(|spec| usage(spec).len())
+        .max()
+        .unwrap_or(0);
+    let mut lines: Vec<String> = commands::COMMANDS
+        .iter()
+        .map(|spec| format!("{:<width$}  {}", usage(spec), spec.help))
+        .collect();
+    // The effective key bindings, packed into as few rows as fit.
+    lines.push(String::new());
+    lines.push("Keys ([tui.keys] in the config):".to_string());
+    let mut packed = String::new();
+    for entry in bindings.help_entries() {
+        if !packed.is_empty() && text_width(&packed) + 3 + text_width(&entry) > cols {
+            lines.push(std::mem::take(&mut packed));
+        }
+        if !packed.is_empty() {
+            packed.push_str("   ");
+        }
+        packed.push_str(&entry);
+    }
+    lines.push(packed);
+    let mut lines = lines.into_iter();
+    for row in top + 1..top + rows {
+        let line = lines.next().unwrap_or_default();
+        let line = clip_line(&line, cols);
+        let padding = cols.saturating_sub(text_width(&line));
+        screen.put(
+            0,
+            row,
+            &format!("{}{}", line, " ".repeat(padding)),
+            Style::default(),
+        );
     }
-    pos
 }
 
-fn prev_char_boundary(text: &str, pos: usize) -> usize {
-    let mut pos = clamp_to_boundary(text, pos);
-    if pos == 0 {
-        return 0;
+fn render_stats(
+    screen: &mut Screen,
+    doc: &str,
+    rows: &[String],
+    (top, height, cols): (usize, usize, usize),
+) {
+    if height == 0 {
+        return;
     }
-    pos -= 1;
-    while pos > 0 && !text.is_char_boundary(pos) {
-        pos -= 1;
+    let header = format!("Who wrote {} | any key closes", doc);
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(
+        0,
+        top,
+        &format!("{:<cols$}", clip_line(&header, cols)),
+        bold,
+    );
+    let mut lines = rows.iter();
+    for row in top + 1..top + height {
+        let line = clip_line(lines.next().map_or("", String::as_str), cols);
+        let padding = cols.saturating_sub(text_width(&line));
+        screen.put(
+            0,
+            row,
+            &format!("{}{}", line, " ".repeat(padding)),
+            Style::default(),
+        );
     }
-    pos
 }
 
-fn next_char_boundary(text: &str, pos: usize) -> usize {
-    let mut pos = clamp_to_boundary(text, pos);
-    if pos >= text.len() {
-        return text.len();
+/// `:name args (:alias)` for the command list.
+fn usage(spec: &commands::CommandSpec) -> String {
+    let mut usage = format!(":{}", spec.name);
+    if !spec.args.is_empty() {
+        usage = format!("{} {}", usage, spec.args);
     }
-    pos += 1;
-    while pos < text.len() && !text.is_char_boundary(pos) {
-        pos += 1;
+    for alias in spec.aliases {
+        usage = format!("{} (:{})", usage, alias);
     }
-    pos.min(text.len())
+    usage
 }
 
-fn byte_to_char_index(text: &str, byte_pos: usize) -> usize {
-    let byte_pos = clamp_to_boundary(text, byte_pos);
-    text[..byte_pos].chars().count()
+/// Draws the line diff from the document to a backup over the content
+/// area (`top`, `rows` rows, `cols` wide), starting `scroll` lines down.
+fn render_backup_diff(
+    screen: &mut Screen,
+    text: &str,
+    backup: &str,
+    scroll: usize,
+    (top, rows, cols): (usize, usize, usize),
+) {
+    if rows == 0 {
+        return;
+    }
+    let changes = snapshot::diff_lines(text, backup);
+    let header = format!(
+        "Backup diff (- server, + backup) {}/{} | Up/Down scroll, d close",
+        scroll.min(changes.len()),
+        changes.len()
+    );
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(
+        0,
+        top,
+        &format!("{:<cols$}", clip_line(&header, cols)),
+        bold,
+    );
+    let colored = |color| Style {
+        fg: Some(color),
+        ..Style::default()
+    };
+    let mut changes = changes.into_iter().skip(scroll);
+    for row in top + 1..top + rows {
+        let (line, style) = match changes.next() {
+            Some(Change::Keep(line)) => (format!("  {}", line), Style::default()),
+            Some(Change::Delete(line)) => (format!("- {}", line), colored(Color::Red)),
+            Some(Change::Insert(line)) => (format!("+ {}", line), colored(Color::Green)),
+            None => (String::new(), Style::default()),
+        };
+        let line = clip_line(&line.replace('\t', "    "), cols);
+        let padding = cols.saturating_sub(text_width(&line));
+        screen.put(0, row, &format!("{}{}", line, " ".repeat(padding)), style);
+    }
 }
 
-fn build_doc(doc_id: &str, replica_id: &str, text: &str) -> TextDoc {
-    let mut doc = TextDoc::new(doc_id, replica_id);
-    if !text.is_empty() {
-        doc.insert(0, text);
+/// The debug counters in a box at the top right of the text area.
+fn render_debug_overlay(
+    screen: &mut Screen,
+    stats: &DebugStats,
+    (cols, top, rows): (usize, usize, usize),
+) {
+    let header = "Debug | F12 close".to_string();
+    let lines = stats.overlay_lines();
+    let width = lines
+        .iter()
+        .chain([&header])
+        .map(|line| text_width(line))
+        .max()
+        .unwrap_or(0)
+        + 2;
+    let width = width.min(cols);
+    let left = cols - width;
+    let style = Style::colored(Color::DarkGrey, Color::White);
+    let bold = Style {
+        bold: true,
+        ..style
+    };
+    let rows_used = (lines.len() + 1).min(rows);
+    for (idx, line) in [header].iter().chain(&lines).take(rows_used).enumerate() {
+        let line = clip_line(&format!(" {}", line), width);
+        let padding = width.saturating_sub(text_width(&line));
+        let padded = format!("{}{}", line, " ".repeat(padding));
+        screen.put(
+            left,
+            top + idx,
+            &padded,
+            if idx == 0 { bold } else { style },
+        );
     }
-    doc
 }
 
-fn unique_suffix() -> u128 {
-    use std::time::{SystemTime, UNIX_EPOCH};
-    SystemTime::now()
-        .duration_since(UNIX_EPOCH)
-        .unwrap_or_default()
-        .as_millis()
-}
+fn render_sidebar(
+    screen: &mut Screen,
+    theme: &Theme,
+    (left, top, rows): (usize, usize, usize),
+    entries: &[SidebarEntry],
+) {
+    let inner = SIDEBAR_WIDTH - 2;
+    for row in top..top + rows {
+        screen.put(left, row, "│ ", Style::default());
+    }
+    if rows == 0 {
+        return;
+    }
+    let header = format!("Users ({})", entries.len());
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(left + 2, top, &clip_line(&header, inner), bold);
 
-fn adjust_cursor_for_remote(op: &Op, cursor_byte: &mut usize) {
-    match op {
-        Op::Insert { pos, text } => {
-            if *pos <= *cursor_byte {
-                *cursor_byte = cursor_byte.saturating_add(text.len());
-            }
-        }
-        Op::Delete { pos, len } => {
-            if *pos < *cursor_byte {
-                let removed = (*cursor_byte - *pos).min(*len);
-                *cursor_byte = cursor_byte.saturating_sub(removed);
-            }
+    let slots = rows - 1;
+    let shown = if entries.len() > slots {
+        slots.saturating_sub(1)
+    } else {
+        entries.len()
+    };
+    for (idx, entry) in entries.iter().take(shown).enumerate() {
+        let row = top + idx + 1;
+        let color = if entry.is_local {
+            theme.cursor.bg.unwrap_or(Color::White)
+        } else {
+            theme.user_color(&entry.user_id)
+        };
+        // Filled glyph when we know where the user's cursor is.
+        let glyph = if entry.at.is_some() { '●' } else { '○' };
+        let mut line = entry.at.map(|at| at.to_string()).unwrap_or_default();
+        if entry.selecting {
+            line.push_str(" sel");
         }
-        Op::Cursor { .. } => {}
+        let marker = if entry.is_local { "*" } else { "" };
+        let name_width = inner.saturating_sub(4 + line.len() + 1 + marker.len());
+        let label = format!(
+            "{:<width$}{} {}",
+            clip_line(&entry.name, name_width),
+            marker,
+            line,
+            width = name_width
+        );
+        screen.put(left + 2, row, " ", Style::colored(color, color));
+        let glyph_style = Style {
+            fg: Some(color),
+            ..Style::default()
+        };
+        screen.put(left + 3, row, &format!(" {} ", glyph), glyph_style);
+        // Guests go by made-up names and are shown dimmer.
+        let label_style = Style {
+            fg: entry.guest.then_some(Color::DarkGrey),
+            ..Style::default()
+        };
+        screen.put(left + 6, row, &clip_line(&label, inner - 4), label_style);
+    }
+    if shown < entries.len() {
+        let more = format!("+{} more", entries.len() - shown);
+        screen.put(
+            left + 2,
+            top + shown + 1,
+            &clip_line(&more, inner),
+            Style::default(),
+        );
     }
 }
 
-fn render_remote_cursors(
-    out: &mut std::io::Stdout,
-    text: &str,
-    scroll: &usize,
-    content_height: usize,
-    cols: usize,
-    cursors: &HashMap<String, usize>,
-    local_user_id: Option<&str>,
-) -> Result<(), Box<dyn Error>> {
-    for (user_id, pos) in cursors {
-        if Some(user_id.as_str()) == local_user_id {
-            continue;
+/// Top row listing the open buffers; the active one is highlighted and
+/// background buffers with unseen remote edits are marked with `•`.
+fn render_tab_bar(screen: &mut Screen, tabs: &[TabLabel], theme: &Theme) {
+    let mut col = 0;
+    for tab in tabs {
+        let mut label = format!(" {}", tab.name);
+        if tab.activity {
+            label.push_str(" •");
         }
-        let (line, col) = cursor_line_col(text, *pos);
-        if line < *scroll || line >= *scroll + content_height {
-            continue;
+        if let Some(state) = tab.state {
+            label.push_str(&format!(" ({})", state));
         }
-        let row = (line - *scroll) as u16;
-        let col = col.min(cols.saturating_sub(1)) as u16;
-        let cell = cursor_cell_char(text, *pos);
-        let color = color_for_user(user_id);
-        queue!(
-            out,
-            MoveTo(col, row),
-            SetBackgroundColor(color),
-            SetForegroundColor(Color::Black)
-        )?;
-        out.write_all(cell.to_string().as_bytes())?;
-        queue!(out, SetAttribute(Attribute::Reset))?;
+        label.push(' ');
+        let style = if tab.active {
+            Style {
+                bold: true,
+                ..theme.selection
+            }
+        } else {
+            Style::default()
+        };
+        screen.put(col, 0, &label, style);
+        col += text_width(&label);
+        screen.put(col, 0, "│", Style::default());
+        col += 1;
     }
-    Ok(())
-}
-
-fn render_local_cursor(
-    out: &mut std::io::Stdout,
-    text: &str,
-    scroll: &usize,
-    content_height: usize,
-    cols: usize,
-    cursor_byte: usize,
-) -> Result<(), Box<dyn Error>> {
-    let (line, col) = cursor_line_col(text, cursor_byte);
-    if line < *scroll || line >= *scroll + content_height {
-        return Ok(());
-    }
-    let row = (line - *scroll) as u16;
-    let col = col.min(cols.saturating_sub(1)) as u16;
-    let cell = cursor_cell_char(text, cursor_byte);
-    queue!(
-        out,
-        MoveTo(col, row),
-        SetBackgroundColor(Color::White),
-        SetForegroundColor(Color::Black)
-    )?;
-    out.write_all(cell.to_string().as_bytes())?;
-    queue!(out, SetAttribute(Attribute::Reset))?;
-    Ok(())
 }
 
 fn build_cursor_summary(
+    text: &str,
     cursors: &HashMap<String, usize>,
     users: &HashMap<String, String>,
     local_user_id: Option<&str>,
@@ -833,7 +4458,7 @@ fn build_cursor_summary(
 
     let mut parts = Vec::new();
     for (_, pos, name) in entries.into_iter().take(limit) {
-        parts.push(format!("{}@{}", name, pos));
+        parts.push(format!("{} {}", name, position::line_col(text, pos)));
     }
 
     if parts.is_empty() {
@@ -843,34 +4468,803 @@ fn build_cursor_summary(
     format!("cursors: {}", parts.join(", "))
 }
 
-fn color_for_user(user_id: &str) -> Color {
-    const PALETTE: [Color; 6] = [
-        Color::Cyan,
-        Color::Magenta,
-        Color::Yellow,
-        Color::Green,
-        Color::Blue,
-        Color::Red,
-    ];
-    let mut hash = 0u64;
-    for byte in user_id.as_bytes() {
-        hash = hash.wrapping_mul(31).wrapping_add(*byte as u64);
+/// Text drawn in a cursor cell: the grapheme under the cursor (two cells
+/// wide for wide characters), or a blank at line ends or when the grapheme
+/// would not fit into the `room` cells left on the row. Invisible
+/// characters are substituted like in `line_cells`.
+fn cursor_cell(text: &str, pos: usize, room: usize, show_invisibles: bool) -> &str {
+    let pos = clamp_to_boundary(text, pos);
+    let line_start = text[..pos].rfind('\n').map_or(0, |idx| idx + 1);
+    let line_end = text[pos..].find('\n').map_or(text.len(), |idx| pos + idx);
+    let trailing = line_start + invisibles::trailing_whitespace_start(&text[line_start..line_end]);
+    // `\r\n` is a single grapheme; the cell shows its `\r`.
+    let next = text[pos..]
+        .graphemes(true)
+        .next()
+        .map(|grapheme| match grapheme {
+            "\r\n" => "\r",
+            _ => grapheme,
+        });
+    match next {
+        Some(grapheme) if grapheme.starts_with('\n') => " ",
+        Some(grapheme) if grapheme_width(grapheme) <= room => {
+            invisibles::glyph(grapheme, pos >= trailing, show_invisibles).0
+        }
+        _ => " ",
     }
-    let idx = (hash as usize) % PALETTE.len();
-    PALETTE[idx]
 }
 
-fn char_at(text: &str, pos: usize) -> Option<char> {
-    let pos = clamp_to_boundary(text, pos);
-    if pos >= text.len() {
-        return None;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clip_line_window_marks_hidden_edges() {
+        assert_eq!(clip_line_window("abcdef", 0, 10, false), "abcdef");
+        assert_eq!(clip_line_window("abcdef", 0, 4, false), "abc…");
+        assert_eq!(clip_line_window("abcdef", 2, 3, false), "…d…");
+        assert_eq!(clip_line_window("abcdef", 2, 10, false), "…def");
+        assert_eq!(clip_line_window("ab", 5, 10, false), "…");
+        assert_eq!(clip_line_window("", 5, 10, false), "");
+        assert_eq!(clip_line_window("äöüßx", 1, 3, false), "…ü…");
+    }
+
+    #[test]
+    fn invisibles_keep_one_cell_per_character() {
+        assert_eq!(clip_line_window("a\tb \r", 0, 10, true), "a→b·␍");
+        assert_eq!(clip_line_window("a\tb \r", 0, 10, false), "a b  ");
+        assert_eq!(clip_line_window("\t\0x", 1, 3, true), "…x");
+        assert_eq!(clip_line_window("x \t", 0, 10, true), "x·→");
+
+        let text = "ab\t\r\nc";
+        assert_eq!(cursor_cell(text, 2, 1, true), "→");
+        assert_eq!(cursor_cell(text, 3, 1, true), "␍");
+        assert_eq!(cursor_cell(text, 3, 1, false), " ");
+        assert_eq!(cursor_line_col(text, 4), (0, 4));
+
+        let screen = frame("ab\r\nc", 0, &HashMap::new());
+        assert!(screen.row_text(9).starts_with("⚠ CR (F7 normalize) | "));
+    }
+
+    #[test]
+    fn documents_near_the_size_limit_get_a_badge() {
+        let limits = ServerLimits {
+            max_doc_bytes: Some(1000),
+            ..ServerLimits::default()
+        };
+        assert_eq!(size_badge(limits, 949), None);
+        assert_eq!(size_badge(limits, 975).as_deref(), Some("⚠ SIZE 97%"));
+        assert_eq!(size_badge(limits, 1000).as_deref(), Some("⚠ SIZE 100%"));
+        assert_eq!(size_badge(ServerLimits::default(), usize::MAX), None);
+    }
+
+    #[cfg(feature = "markdown")]
+    #[test]
+    fn markdown_styles_skip_clipped_edges() {
+        let view = Viewport {
+            top: 0,
+            left: 2,
+            y: 0,
+            rows: 1,
+            cols: 4,
+            invisibles: false,
+        };
+        let line = "# Title";
+        let mut screen = Screen::new(6, 1);
+        screen.put(0, 0, &clip_line_window(line, 2, 4, false), Style::default());
+        render_markdown(&mut screen, line, view, 0, &mut Default::default());
+        assert_eq!(screen.row_text(0), "…it…  ");
+        let bold: Vec<bool> = (0..5).map(|col| screen.style_at(col, 0).bold).collect();
+        assert_eq!(bold, [false, true, true, false, false]);
+    }
+
+    #[test]
+    fn scrollbar_thumb_tracks_the_viewport() {
+        // Everything visible: the thumb fills the track.
+        assert_eq!(scrollbar_thumb(0, 10, 4), (0, 10));
+        assert_eq!(scrollbar_thumb(0, 10, 100), (0, 1));
+        assert_eq!(scrollbar_thumb(45, 10, 100), (4, 1));
+        assert_eq!(scrollbar_thumb(90, 10, 100), (9, 1));
+        assert_eq!(scrollbar_thumb(0, 10, 20), (0, 5));
+        assert_eq!(scrollbar_thumb(10, 10, 20), (5, 5));
+        // Scrolled past the end.
+        assert_eq!(scrollbar_thumb(19, 10, 20), (6, 4));
+        assert_eq!(scrollbar_thumb(0, 0, 20), (0, 1));
+
+        assert_eq!(content_width(80, false, true, false), 79);
+        assert_eq!(content_width(80, true, true, false), 80 - SIDEBAR_WIDTH - 1);
+        assert_eq!(content_width(1, false, true, false), 1);
+        assert_eq!(content_width(80, false, true, true), 79 - GUTTER_WIDTH);
+        assert_eq!(content_width(4, false, true, true), 3);
+    }
+
+    #[test]
+    fn horizontal_scroll_follows_cursor_with_margin() {
+        assert_eq!(follow_cursor_column(0, 10, 80), 0);
+        assert_eq!(follow_cursor_column(0, 79, 80), 4);
+        assert_eq!(follow_cursor_column(20, 30, 80), 20);
+        assert_eq!(follow_cursor_column(20, 22, 80), 18);
+        assert_eq!(follow_cursor_column(20, 0, 80), 0);
+    }
+
+    /// Composes a 40x10 frame of `text` with the local cursor at `cursor`.
+    fn frame(text: &str, cursor: usize, cursors: &HashMap<String, usize>) -> Screen {
+        frame_with_selections(text, cursor, cursors, &HashMap::new(), &[])
+    }
+
+    fn frame_with_selections(
+        text: &str,
+        cursor: usize,
+        cursors: &HashMap<String, usize>,
+        selections: &HashMap<String, RemoteSelection>,
+        tabs: &[TabLabel],
+    ) -> Screen {
+        let users = HashMap::new();
+        let moved = HashMap::new();
+        let (mut scroll, mut hscroll) = (0, 0);
+        let mut ctx = RenderContext {
+            addr: "127.0.0.1:4000",
+            room: "demo",
+            doc: "notes",
+            text,
+            cursor_byte: cursor,
+            selection: None,
+            users_count: 1,
+            version: 1,
+            limits: ServerLimits::default(),
+            status: &StatusLog::default(),
+            log_scroll: None,
+            scroll: &mut scroll,
+            hscroll: &mut hscroll,
+            free_scroll: false,
+            search: None,
+            command_prompt: None,
+            sidebar_open: false,
+            feed: None,
+            scrollbar: false,
+            gutter: false,
+            #[cfg(feature = "markdown")]
+            markdown: false,
+            #[cfg(feature = "spellcheck")]
+            speller: None,
+            show_invisibles: false,
+            overwrite: false,
+            following: None,
+            disconnected: None,
+            history: None,
+            flash_line: None,
+            cursors,
+            selections,
+            last_activity: &moved,
+            edit_flashes: &HashMap::new(),
+            cursor_config: CursorConfig::default(),
+            users: &users,
+            guests: &HashSet::new(),
+            local_user_id: Some("demo/notes|me"),
+            theme: &Theme::default(),
+            tabs,
+            link: None,
+            debug: None,
+            help: false,
+            bindings: &Bindings::default(),
+            stats: None,
+            picker: None,
+        };
+        compose(&mut ctx, 40, 10).0
+    }
+
+    #[test]
+    fn typing_a_character_redraws_at_most_two_rows() {
+        let cursors = HashMap::new();
+        let before = frame("hello\nworld\n", 5, &cursors);
+        let after = frame("hellox\nworld\n", 6, &cursors);
+        let changed = widgets::screen::draw(&mut Vec::new(), Some(&before), &after).unwrap();
+        assert!(changed.len() <= 2, "{:?}", changed);
+        assert_eq!(changed[0], (0, 2));
+    }
+
+    #[test]
+    fn remote_cursor_moves_redraw_two_content_cells() {
+        let text = "hello\nworld\n";
+        let mut cursors = HashMap::from([("demo/notes|bob".to_string(), 1)]);
+        let before = frame(text, 0, &cursors);
+        cursors.insert("demo/notes|bob".to_string(), 8);
+        let after = frame(text, 0, &cursors);
+        let changed = widgets::screen::draw(&mut Vec::new(), Some(&before), &after).unwrap();
+        let content_cells: usize = changed
+            .iter()
+            .filter(|(row, _)| *row < 9)
+            .map(|(_, cells)| cells)
+            .sum();
+        assert_eq!(content_cells, 2);
+    }
+
+    #[test]
+    fn remote_selections_tint_cells_and_latest_wins() {
+        let (bob, carol) = ("demo/notes|bob", "demo/notes|carol-1");
+        let theme = Theme::default();
+        assert_ne!(theme.user_color(bob), theme.user_color(carol));
+        let text = "hello world\nsecond line\n";
+        let cursors = HashMap::from([(bob.to_string(), 9), (carol.to_string(), 1)]);
+        let selections = HashMap::from([
+            (
+                bob.to_string(),
+                RemoteSelection {
+                    anchor: 2,
+                    head: 9,
+                    seq: 2,
+                },
+            ),
+            (
+                carol.to_string(),
+                RemoteSelection {
+                    anchor: 6,
+                    head: 1,
+                    seq: 1,
+                },
+            ),
+        ]);
+        let screen = frame_with_selections(text, text.len(), &cursors, &selections, &[]);
+        let bg = |col| screen.style_at(col, 0).bg;
+        let bob_tint = theme.selection_tint(bob).bg;
+        let carol_tint = theme.selection_tint(carol).bg;
+
+        assert_eq!(bg(0), None);
+        // Carol's head cell shows her cursor, the rest of her range her tint.
+        assert_eq!(bg(1), Some(theme.user_color(carol)));
+        // Bob changed his selection last, so the overlap is his.
+        assert_eq!((bg(2), bg(5), bg(8)), (bob_tint, bob_tint, bob_tint));
+        assert_ne!(bg(2), carol_tint);
+        assert_eq!(bg(9), Some(theme.user_color(bob)));
+        assert_eq!(bg(10), None);
+        assert_eq!(screen.style_at(0, 1).bg, None);
+    }
+
+    #[test]
+    fn gutter_lists_remote_users_on_each_visible_line() {
+        let text = "zero\none\ntwo\nthree\nfour\n";
+        let selection = |anchor, head| RemoteSelection {
+            anchor,
+            head,
+            seq: 0,
+        };
+        // Lines start at 0, 5, 9, 13 and 19.
+        let cursors = HashMap::from([
+            ("me".to_string(), 9),
+            ("bob".to_string(), 6),
+            ("carol".to_string(), 20),
+            ("dave".to_string(), 500),
+        ]);
+        let selections = HashMap::from([
+            // Ends at the start of line 3, so takes lines 1 and 2 only.
+            ("bob".to_string(), selection(13, 6)),
+            ("erin".to_string(), selection(0, 2)),
+            ("me".to_string(), selection(0, 20)),
+        ]);
+        let users = gutter_users(text, &cursors, &selections, Some("me"), (1, 3));
+        assert_eq!(users, [vec!["bob"], vec!["bob"], vec![]]);
+        let users = gutter_users(text, &cursors, &selections, Some("me"), (0, 10));
+        assert_eq!(users[0], ["erin"]);
+        assert_eq!(users[4], ["carol"]);
+        // Past the end: on the last, empty line.
+        assert_eq!(users[5], ["dave"]);
+        assert!(users[6..].iter().all(Vec::is_empty));
+        assert!(gutter_users(text, &cursors, &selections, None, (0, 0)).is_empty());
+
+        let crowd: HashMap<String, usize> =
+            ["a", "b", "c", "d"].map(|id| (id.to_string(), 0)).into();
+        let (theme, none) = (Theme::default(), HashMap::new());
+        let mut screen = Screen::new(6, 2);
+        let rows = gutter_users(text, &crowd, &none, None, (0, 2));
+        render_gutter(&mut screen, &theme, (2, 0), &rows);
+        assert_eq!(screen.row_text(0), "    4 ");
+        let rows = gutter_users(text, &crowd, &none, Some("d"), (0, 2));
+        render_gutter(&mut screen, &theme, (2, 1), &rows);
+        assert_eq!(screen.row_text(1), "  ●●● ");
+        assert_eq!(screen.style_at(3, 1).fg, Some(theme.user_color("b")));
+    }
+
+    #[test]
+    fn tab_bar_pushes_content_down_and_marks_activity() {
+        let tab = |name: &str, active, activity| TabLabel {
+            name: name.to_string(),
+            active,
+            activity,
+            state: None,
+        };
+        let tabs = [tab("notes", true, false), tab("todo", false, true)];
+        let cursors = HashMap::new();
+        let screen = frame_with_selections("hello", 0, &cursors, &HashMap::new(), &tabs);
+        assert_eq!(screen.row_text(0).trim_end(), " notes │ todo • │");
+        assert!(screen.style_at(1, 0).bold);
+        assert!(!screen.style_at(8, 0).bold);
+        assert_eq!(screen.row_text(1).trim_end(), "hello");
+        // The local cursor sits on the first content row.
+        assert_eq!(screen.style_at(0, 1), Theme::default().cursor);
+
+        let screen = frame("hello", 0, &cursors);
+        assert_eq!(screen.row_text(0).trim_end(), "hello");
+    }
+
+    #[test]
+    fn remote_selections_follow_edits() {
+        let mut cursors = HashMap::from([("u".to_string(), 5)]);
+        let mut selections = HashMap::from([(
+            "u".to_string(),
+            RemoteSelection {
+                anchor: 2,
+                head: 5,
+                seq: 1,
+            },
+        )]);
+        let insert = Op::Insert {
+            pos: 0,
+            text: "ab".to_string(),
+            pos_chars: None,
+        };
+        let mut flashes = HashMap::new();
+        shift_remote_positions(&insert, &mut cursors, &mut selections, &mut flashes);
+        assert_eq!(cursors["u"], 7);
+        assert_eq!((selections["u"].anchor, selections["u"].head), (4, 7));
+
+        shift_remote_positions(
+            &Op::Delete {
+                pos: 3,
+                len: 2,
+                pos_chars: None,
+                len_chars: None,
+            },
+            &mut cursors,
+            &mut selections,
+            &mut flashes,
+        );
+        assert_eq!(selections["u"].range("0123456789"), (3, 5));
+    }
+
+    #[test]
+    fn edit_flashes_grow_while_typing() {
+        let now = Instant::now();
+        let mut cursors = HashMap::new();
+        let mut selections = HashMap::new();
+        let mut flashes = HashMap::new();
+        let mut edit = |op: Op, at: Instant, flashes: &mut HashMap<String, EditFlash>| {
+            shift_remote_positions(&op, &mut cursors, &mut selections, flashes);
+            EditFlash::record(flashes, "u", &op, at);
+        };
+        let insert = |pos: usize, text: &str| Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+
+        edit(insert(2, "ab"), now, &mut flashes);
+        edit(insert(4, "c"), now, &mut flashes);
+        assert_eq!(flashes["u"].range("01abc56"), (2, 5));
+        edit(
+            Op::Delete {
+                pos: 4,
+                len: 1,
+                pos_chars: None,
+                len_chars: None,
+            },
+            now,
+            &mut flashes,
+        );
+        assert_eq!(flashes["u"].range("01ab56"), (2, 4));
+
+        // A later edit elsewhere starts a new flash; a deletion marks one cell.
+        let later = now + EDIT_FLASH_MERGE * 2;
+        edit(
+            Op::Delete {
+                pos: 0,
+                len: 1,
+                pos_chars: None,
+                len_chars: None,
+            },
+            later,
+            &mut flashes,
+        );
+        assert_eq!(flashes["u"].range("1ab56"), (0, 1));
+    }
+
+    #[test]
+    fn idle_remote_cursors_fade() {
+        let cursors = HashMap::from([
+            ("demo/notes|a".to_string(), 1),
+            ("demo/notes|b".to_string(), 3),
+        ]);
+        let idle_since = Instant::now() - CursorConfig::default().fade_after() * 2;
+        let moved = HashMap::from([
+            ("demo/notes|a".to_string(), Instant::now()),
+            ("demo/notes|b".to_string(), idle_since),
+        ]);
+        let users = HashMap::new();
+        let (mut scroll, mut hscroll) = (0, 0);
+        let flashes = HashMap::from([(
+            "demo/notes|a".to_string(),
+            EditFlash {
+                start: 0,
+                end: 1,
+                at: Instant::now(),
+            },
+        )]);
+        let mut ctx = RenderContext {
+            addr: "127.0.0.1:4000",
+            room: "demo",
+            doc: "notes",
+            text: "hello",
+            cursor_byte: 5,
+            selection: None,
+            users_count: 1,
+            version: 1,
+            limits: ServerLimits::default(),
+            status: &StatusLog::default(),
+            log_scroll: None,
+            scroll: &mut scroll,
+            hscroll: &mut hscroll,
+            free_scroll: false,
+            search: None,
+            command_prompt: None,
+            sidebar_open: false,
+            feed: None,
+            scrollbar: false,
+            gutter: false,
+            #[cfg(feature = "markdown")]
+            markdown: false,
+            #[cfg(feature = "spellcheck")]
+            speller: None,
+            show_invisibles: false,
+            overwrite: false,
+            following: None,
+            disconnected: None,
+            history: None,
+            flash_line: None,
+            cursors: &cursors,
+            selections: &HashMap::new(),
+            last_activity: &moved,
+            edit_flashes: &flashes,
+            cursor_config: CursorConfig::default(),
+            users: &users,
+            guests: &HashSet::new(),
+            local_user_id: Some("demo/notes|me"),
+            theme: &Theme::default(),
+            tabs: &[],
+            link: None,
+            debug: None,
+            help: false,
+            bindings: &Bindings::default(),
+            stats: None,
+            picker: None,
+        };
+        let screen = compose(&mut ctx, 40, 10).0;
+        let theme = Theme::default();
+        assert_eq!(
+            screen.style_at(0, 0),
+            theme.remote_cursor("demo/notes|a", false)
+        );
+        assert_eq!(
+            screen.style_at(1, 0),
+            theme.remote_cursor("demo/notes|a", false)
+        );
+        assert_eq!(
+            screen.style_at(3, 0),
+            theme.remote_cursor("demo/notes|b", true)
+        );
+    }
+
+    #[test]
+    fn key_bursts_become_pastes_only_when_long() {
+        let keys: Vec<KeyEvent> = "ab\tc"
+            .chars()
+            .map(|ch| KeyEvent::from(KeyCode::Char(ch)))
+            .collect();
+        assert_eq!(burst_text(&keys), None);
+
+        let mut keys: Vec<KeyEvent> = "x"
+            .repeat(PASTE_BURST_MIN_CHARS)
+            .chars()
+            .map(|ch| KeyEvent::from(KeyCode::Char(ch)))
+            .collect();
+        keys.push(KeyEvent::from(KeyCode::Enter));
+        keys.push(KeyEvent::from(KeyCode::Tab));
+        let expected = format!("{}\n\t", "x".repeat(PASTE_BURST_MIN_CHARS));
+        assert_eq!(burst_text(&keys), Some(expected));
+    }
+
+    #[test]
+    fn pasted_line_endings_are_normalized() {
+        assert_eq!(normalize_line_endings("a\r\nb\rc\n"), "a\nb\nc\n");
+        let mixed = "one\r\ntwo\rthree\n\r\n";
+        assert_eq!(LineEndings::Normalize.apply(mixed), "one\ntwo\nthree\n\n");
+        assert_eq!(LineEndings::Keep.apply(mixed), mixed);
+    }
+
+    #[test]
+    fn normalizing_the_document_rewrites_each_carriage_return() {
+        let text = "a\r\nb\rc\r";
+        let edits = line_ending_edits(text);
+        let ops: Vec<Op> = edits.iter().map(Edit::to_op).collect();
+        assert_eq!(
+            ops,
+            vec![
+                Op::Delete {
+                    pos: 6,
+                    len: 1,
+                    pos_chars: None,
+                    len_chars: None
+                },
+                Op::Insert {
+                    pos: 6,
+                    text: "\n".into(),
+                    pos_chars: None
+                },
+                Op::Delete {
+                    pos: 4,
+                    len: 1,
+                    pos_chars: None,
+                    len_chars: None
+                },
+                Op::Insert {
+                    pos: 4,
+                    text: "\n".into(),
+                    pos_chars: None
+                },
+                Op::Delete {
+                    pos: 1,
+                    len: 1,
+                    pos_chars: None,
+                    len_chars: None
+                },
+            ]
+        );
+        let mut doc = text.to_string();
+        for edit in &edits {
+            assert!(edit.applies_to(&doc));
+            match edit {
+                Edit::Insert { pos, text } => doc.insert_str(*pos, text),
+                Edit::Delete { pos, text } => drop(doc.drain(*pos..pos + text.len())),
+            }
+        }
+        assert_eq!(doc, "a\nb\nc\n");
+        assert!(line_ending_edits("no\ncr\n").is_empty());
+    }
+
+    #[test]
+    fn substitution_edits_apply_last_first_and_undo() {
+        let text = "naïve naïve\nnaïf";
+        let pattern = Pattern::new("naï", false).unwrap();
+        let found = replace::find(text, &pattern, "NAI", false);
+        assert_eq!(found.len(), 2, "one per line without g");
+        let edits = replace_edits(text, &found);
+        let mut doc = text.to_string();
+        for edit in &edits {
+            assert!(edit.applies_to(&doc));
+            snapshot::apply_to_text(&mut doc, &edit.to_op());
+        }
+        assert_eq!(doc, "NAIve naïve\nNAIf");
+        for edit in edits.iter().rev() {
+            let undo = edit.inverse();
+            assert!(undo.applies_to(&doc));
+            snapshot::apply_to_text(&mut doc, &undo.to_op());
+        }
+        assert_eq!(doc, text);
+    }
+
+    #[test]
+    fn home_toggles_between_indentation_and_column_zero() {
+        let text = "  \tfn main() {  \n\n   \nx";
+        // Anywhere in the line, then at the first non-blank, then at 0.
+        assert_eq!(smart_home(text, 8), 3);
+        assert_eq!(smart_home(text, 3), 0);
+        assert_eq!(smart_home(text, 0), 3);
+        assert_eq!(smart_home(text, 1), 3);
+        // Empty and whitespace-only lines only have column 0.
+        assert_eq!(smart_home(text, 17), 17);
+        assert_eq!(smart_home(text, 20), 18);
+        assert_eq!(smart_home(text, 18), 18);
+        assert_eq!(smart_home(text, 23), 22);
+
+        assert_eq!(smart_end(text, 3), 16);
+        assert_eq!(smart_end(text, 16), 14);
+        assert_eq!(smart_end(text, 14), 16);
+        assert_eq!(smart_end(text, 17), 17);
+        assert_eq!(smart_end(text, 21), 21);
+        assert_eq!(smart_end(text, 23), 23);
+    }
+
+    #[test]
+    fn overwrite_replaces_the_character_under_the_cursor() {
+        let ops = |text, cursor, ch| -> Vec<Op> {
+            overwrite_edits(text, cursor, ch)
+                .iter()
+                .map(Edit::to_op)
+                .collect()
+        };
+        let insert = |pos, text: &str| Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        assert_eq!(
+            ops("abc\n", 1, 'X'),
+            [
+                Op::Delete {
+                    pos: 1,
+                    len: 1,
+                    pos_chars: None,
+                    len_chars: None
+                },
+                insert(1, "X")
+            ]
+        );
+        // Multi-byte characters and whole graphemes go in one Delete.
+        assert_eq!(
+            ops("añb", 1, 'é'),
+            [
+                Op::Delete {
+                    pos: 1,
+                    len: 2,
+                    pos_chars: None,
+                    len_chars: None
+                },
+                insert(1, "é")
+            ]
+        );
+        assert_eq!(
+            ops("e\u{301}x", 0, 'a'),
+            [
+                Op::Delete {
+                    pos: 0,
+                    len: 3,
+                    pos_chars: None,
+                    len_chars: None
+                },
+                insert(0, "a")
+            ]
+        );
+        // Line ends and the end of the text are inserted into.
+        assert_eq!(ops("abc\n", 3, 'X'), [insert(3, "X")]);
+        assert_eq!(ops("ab\r\n", 2, 'X'), [insert(2, "X")]);
+        assert_eq!(ops("abc", 3, 'X'), [insert(3, "X")]);
+    }
+
+    #[test]
+    fn selections_left_inside_characters_are_clamped() {
+        assert_eq!(selected("añb", Some(2), 4), Some((1, 4)));
+        assert_eq!(selected("ab", Some(9), 0), Some((0, 2)));
+        assert_eq!(selected("añb", Some(2), 1), None);
+        assert_eq!(selected("ab", None, 1), None);
+    }
+
+    #[test]
+    fn follow_cycles_through_remote_users_then_stops() {
+        let cursors: HashMap<String, usize> = [("d|bob", 4), ("d|alice", 0), ("d|me", 2)]
+            .into_iter()
+            .map(|(id, pos)| (id.to_string(), pos))
+            .collect();
+        let local = Some("d|me");
+        let first = next_follow_target(&cursors, local, None);
+        assert_eq!(first.as_deref(), Some("d|alice"));
+        let second = next_follow_target(&cursors, local, first.as_deref());
+        assert_eq!(second.as_deref(), Some("d|bob"));
+        assert_eq!(next_follow_target(&cursors, local, second.as_deref()), None);
+        assert_eq!(next_follow_target(&HashMap::new(), local, None), None);
+    }
+
+    #[test]
+    fn follow_scroll_centers_the_line() {
+        assert_eq!(centered_scroll(50, 20, 100), 40);
+        assert_eq!(centered_scroll(3, 20, 100), 0);
+        assert_eq!(centered_scroll(9, 20, 10), 0);
+    }
+
+    #[test]
+    fn cursor_labels_avoid_local_cursor_and_clip() {
+        assert_eq!(
+            place_cursor_label("alice", (3, 2), 1, None, 80),
+            Some((4, 2, "alice".to_string()))
+        );
+        assert_eq!(
+            place_cursor_label("alice", (3, 2), 1, Some((6, 2)), 80),
+            Some((3, 1, "alice".to_string()))
+        );
+        assert_eq!(
+            place_cursor_label("alice", (3, 0), 1, Some((6, 0)), 80),
+            None
+        );
+        assert_eq!(
+            place_cursor_label("alice", (77, 5), 1, None, 80),
+            Some((78, 5, "al".to_string()))
+        );
+    }
+
+    #[test]
+    fn columns_count_wide_characters_as_two_cells() {
+        let line = "a中😀b";
+        let cols: Vec<usize> = [0, 1, 4, 8]
+            .into_iter()
+            .map(|pos| cursor_line_col(line, pos).1)
+            .collect();
+        assert_eq!(cols, vec![0, 1, 3, 5]);
+        assert_eq!(cursor_line_col("x\n中文", 7), (1, 2));
+        assert_eq!(cursor_line_col("e\u{301}x", 3), (0, 1));
+
+        assert_eq!(byte_at_column(line, 2), 1);
+        assert_eq!(byte_at_column(line, 3), 4);
+        assert_eq!(byte_at_column(line, 9), line.len());
+    }
+
+    #[test]
+    fn clipping_never_splits_wide_characters() {
+        assert_eq!(clip_line("中文字", 5), "中文");
+        assert_eq!(clip_line("ab😀", 3), "ab");
+        assert_eq!(clip_line_window("中文字", 0, 6, false), "中文字");
+        assert_eq!(clip_line_window("中文字", 1, 4, false), "…文…");
+        assert_eq!(clip_line_window("a中文", 0, 4, false), "a中…");
+        assert_eq!(clip_line_window("ab中文", 1, 3, false), "… …");
+        assert_eq!(text_width(&clip_line_window("😀a😀b😀", 1, 5, false)), 5);
+    }
+
+    #[test]
+    fn cursor_moves_by_grapheme_cluster() {
+        let text = "e\u{301}👍🏽x";
+        let after_accent = next_grapheme_boundary(text, 0);
+        assert_eq!(after_accent, 3);
+        let after_emoji = next_grapheme_boundary(text, after_accent);
+        assert_eq!(&text[after_accent..after_emoji], "👍🏽");
+        assert_eq!(prev_grapheme_boundary(text, after_emoji), after_accent);
+        assert_eq!(prev_grapheme_boundary(text, after_accent), 0);
+        assert_eq!(cursor_cell(text, after_accent, 2, false), "👍🏽");
+        assert_eq!(cursor_cell(text, after_accent, 1, false), " ");
+    }
+
+    #[test]
+    fn goto_input_parsing() {
+        assert_eq!(parse_goto("42"), Ok((42, None)));
+        assert_eq!(parse_goto("7:3"), Ok((7, Some(3))));
+        assert_eq!(parse_goto(" 7 : 3 "), Ok((7, Some(3))));
+        assert!(parse_goto("").is_err());
+        assert!(parse_goto("0").is_err());
+        assert!(parse_goto("3:x").is_err());
+        assert!(parse_goto("-1").is_err());
+    }
+
+    #[test]
+    fn line_kill_ranges() {
+        let text = "one\ntwo\nthree";
+        assert_eq!(kill_line_range(text, 1), (1, 3));
+        assert_eq!(kill_line_range(text, 3), (3, 4));
+        assert_eq!(kill_line_range(text, text.len()), (text.len(), text.len()));
+        assert_eq!(whole_line_range(text, 5), (4, 8));
+        assert_eq!(whole_line_range(text, 10), (7, 13));
+        assert_eq!(whole_line_range("solo", 2), (0, 4));
+    }
+
+    #[test]
+    fn word_boundaries_skip_whitespace_runs() {
+        let text = "let  x = compute(a, b);";
+        assert_eq!(next_word_boundary(text, 0), 3);
+        assert_eq!(next_word_boundary(text, 3), 6);
+        assert_eq!(prev_word_boundary(text, 5), 0);
+        assert_eq!(prev_word_boundary(text, 9), 7);
+    }
+
+    #[test]
+    fn word_boundaries_at_document_edges() {
+        assert_eq!(prev_word_boundary("", 0), 0);
+        assert_eq!(next_word_boundary("", 0), 0);
+        assert_eq!(prev_word_boundary("abc", 0), 0);
+        assert_eq!(next_word_boundary("abc", 3), 3);
+        assert_eq!(next_word_boundary("abc   ", 3), 6);
+        assert_eq!(prev_word_boundary("   abc", 3), 0);
     }
-    text[pos..].chars().next()
-}
 
-fn cursor_cell_char(text: &str, pos: usize) -> char {
-    match char_at(text, pos) {
-        Some('\n') | None => ' ',
-        Some(ch) => ch,
+    #[test]
+    fn word_boundaries_split_cjk_without_spaces() {
+        let text = "日本語テキスト";
+        let first = next_word_boundary(text, 0);
+        assert!(first > 0 && first < text.len());
+        assert!(text.is_char_boundary(first));
+        assert_eq!(prev_word_boundary(text, first), 0);
     }
 }
@@ -0,0 +1,163 @@
+//! Local copies of open documents, so text typed while the server or the
+//! TUI goes down can be recovered on the next start.
+
+use crate::storage::{RealFs, replace_file};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// How often a changed document is written to its backup.
+pub(super) const BACKUP_INTERVAL: Duration = Duration::from_secs(30);
+/// Backups kept per document: the current one plus earlier sessions'.
+const BACKUPS_KEPT: usize = 5;
+
+/// `$XDG_STATE_HOME/collab/backup`, falling back to
+/// `~/.local/state/collab/backup` (`%LOCALAPPDATA%\collab\backup` on
+/// Windows).
+pub(super) fn default_dir() -> Option<PathBuf> {
+    let state = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| {
+            if cfg!(windows) {
+                std::env::var_os("LOCALAPPDATA").map(PathBuf::from)
+            } else {
+                std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state"))
+            }
+        })?;
+    Some(state.join("collab").join("backup"))
+}
+
+/// The backup file of one document.
+#[derive(Debug)]
+pub(super) struct Backup {
+    path: PathBuf,
+    /// What the file held when the buffer was opened, until the first sync
+    /// takes it.
+    previous: Option<String>,
+    /// Earlier sessions' files are shifted along before the first write.
+    rotated: bool,
+    saved_at: Instant,
+    /// Text of the last write, so an unchanged document isn't rewritten.
+    saved: Option<String>,
+}
+
+impl Backup {
+    /// `<dir>/<room>__<doc>.txt`, reading what an earlier session left there.
+    pub(super) fn new(dir: &Path, room: &str, doc: &str, now: Instant) -> Self {
+        let path = dir.join(format!("{}__{}.txt", file_safe(room), file_safe(doc)));
+        Self {
+            previous: fs::read_to_string(&path).ok(),
+            path,
+            rotated: false,
+            saved_at: now,
+            saved: None,
+        }
+    }
+
+    pub(super) fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The earlier session's backup, once.
+    pub(super) fn take_previous(&mut self) -> Option<String> {
+        self.previous.take()
+    }
+
+    pub(super) fn due(&self, now: Instant) -> bool {
+        now.duration_since(self.saved_at) >= BACKUP_INTERVAL
+    }
+
+    /// Writes `text` unless it is what the file already holds.
+    pub(super) fn save(&mut self, text: &str, now: Instant) -> io::Result<()> {
+        self.saved_at = now;
+        if self.saved.as_deref() == Some(text) {
+            return Ok(());
+        }
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        if !self.rotated {
+            self.rotate()?;
+            self.rotated = true;
+        }
+        replace_file(&RealFs, &self.path, text.as_bytes())?;
+        self.saved = Some(text.to_string());
+        Ok(())
+    }
+
+    /// Moves `name.txt` to `name.1.txt`, `name.1.txt` to `name.2.txt` and
+    /// so on, dropping the oldest.
+    fn rotate(&self) -> io::Result<()> {
+        let numbered = |idx: usize| self.path.with_extension(format!("{}.txt", idx));
+        ignore_missing(fs::remove_file(numbered(BACKUPS_KEPT - 1)))?;
+        for idx in (1..BACKUPS_KEPT - 1).rev() {
+            ignore_missing(fs::rename(numbered(idx), numbered(idx + 1)))?;
+        }
+        ignore_missing(fs::rename(&self.path, numbered(1)))
+    }
+}
+
+fn ignore_missing(result: io::Result<()>) -> io::Result<()> {
+    match result {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// `name` with anything but letters, digits, `-`, `_` and `.` replaced, so
+/// doc names like `notes/today` stay one file.
+fn file_safe(name: &str) -> String {
+    name.chars()
+        .map(|ch| {
+            if ch.is_alphanumeric() || matches!(ch, '-' | '_' | '.') {
+                ch
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sessions_rotate_and_only_the_newest_are_kept() {
+        let dir = std::env::temp_dir().join(format!("carnelia-backup-{}", std::process::id()));
+        let now = Instant::now();
+        let mut backup = Backup::new(&dir, "demo", "notes/today", now);
+        assert_eq!(backup.path(), dir.join("demo__notes_today.txt"));
+        assert_eq!(backup.take_previous(), None);
+        assert!(!backup.due(now) && backup.due(now + BACKUP_INTERVAL));
+        backup.save("session 0", now).unwrap();
+        backup.save("session 0, later", now).unwrap();
+
+        for session in 1..8 {
+            let mut backup = Backup::new(&dir, "demo", "notes/today", now);
+            let previous = backup.take_previous().unwrap();
+            assert!(previous.starts_with(&format!("session {}", session - 1)));
+            backup.save(&format!("session {}", session), now).unwrap();
+        }
+        let mut names: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                "demo__notes_today.1.txt",
+                "demo__notes_today.2.txt",
+                "demo__notes_today.3.txt",
+                "demo__notes_today.4.txt",
+                "demo__notes_today.txt",
+            ]
+        );
+        let oldest = fs::read_to_string(dir.join("demo__notes_today.4.txt")).unwrap();
+        assert_eq!(oldest, "session 3");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
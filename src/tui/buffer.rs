@@ -0,0 +1,1251 @@
+use super::backup::Backup;
+use super::coalesce::{Coalescer, Outbox};
+use super::connection::{self, Backoff, Connection, JoinInfo, OutageBuffer, Reconnect};
+use super::history::History;
+use super::link::{self, LinkState};
+use super::status::StatusLog;
+use super::undo::{Edit, UndoStack};
+use super::{EditFlash, OutageInput, RemoteSelection, UiEvent, shift_remote_positions};
+use crate::chunked::ChunkedInsert;
+use crate::client;
+use crate::client::resync::SyncSchedule;
+use crate::protocol::{
+    ActivityEntry, Op, ServerLimits, ServerMessage, UserEventKind, WireUser, decode_sync_response,
+    decode_update, doc_id_from_scoped_user_id, encode_sync_request, encode_update,
+    make_scoped_user_id,
+};
+use crate::snapshot::{self, PendingOps};
+use crate::storage::Access;
+use crate::textpos::{apply_op_to_doc, build_doc, shift_for_op};
+use mdcs_sdk::{Awareness, Message, TextDoc};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::future::Future;
+use std::io;
+use std::task::Poll;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Time between Pings while round trips are measured.
+const PING_INTERVAL: Duration = Duration::from_secs(1);
+/// Activity feed entries kept per buffer.
+const FEED_LEN: usize = 100;
+/// How soon to try again to send an upload chunk while the writer's queue
+/// is full.
+const UPLOAD_RETRY: Duration = Duration::from_millis(10);
+
+/// One open document: its connection, text and the view state kept per
+/// document (cursor, scroll, remote cursors, undo history).
+pub(super) struct Buffer {
+    pub(super) doc: String,
+    pub(super) join: JoinInfo,
+    pub(super) awareness: Awareness,
+    pub(super) doc_state: TextDoc,
+    pub(super) version: u64,
+    /// Server version of our own last edit echoed back.
+    pub(super) acked_version: u64,
+    /// Local edits the next snapshot won't contain yet.
+    pub(super) pending: PendingOps,
+    /// When to sync again, by hand or by itself.
+    pub(super) sync: SyncSchedule,
+    /// The next snapshot answers an automatic sync: no status message.
+    quiet_sync: bool,
+    /// Typed characters not sent yet.
+    pub(super) coalescer: Coalescer,
+    /// A long paste going out chunk by chunk; input waits meanwhile.
+    pub(super) upload: Option<ChunkedInsert>,
+    pub(super) cursor_byte: usize,
+    pub(super) selection_anchor: Option<usize>,
+    pub(super) undo: UndoStack,
+    pub(super) scroll: usize,
+    pub(super) hscroll: usize,
+    /// Set when the viewport was scrolled independently of the cursor (mouse
+    /// wheel); cleared again as soon as the cursor moves.
+    pub(super) free_scroll: bool,
+    /// Remote user whose cursor the viewport is glued to (F5).
+    pub(super) following: Option<String>,
+    pub(super) users: HashMap<String, String>,
+    /// Users going by a name the server made up, shown dimmer.
+    pub(super) guests: HashSet<String>,
+    /// The `presence_seq` of the user list we have; older lists are stale.
+    presence_seq: u64,
+    pub(super) cursors: HashMap<String, usize>,
+    /// When each remote user last moved their cursor or edited.
+    pub(super) last_activity: HashMap<String, Instant>,
+    pub(super) edit_flashes: HashMap<String, EditFlash>,
+    pub(super) selections: HashMap<String, RemoteSelection>,
+    selection_seq: u64,
+    /// Local selection as last sent to the server.
+    sent_selection: Option<(usize, usize)>,
+    /// Line briefly highlighted after a jump (Ctrl+G).
+    pub(super) flash_line: Option<(usize, Instant)>,
+    pub(super) connection: Option<Connection>,
+    pub(super) out_tx: mpsc::Sender<Message>,
+    reconnect: Option<Reconnect>,
+    backoff: Backoff,
+    /// Next reconnect attempt while disconnected.
+    pub(super) retry_at: Option<Instant>,
+    /// Set after (re)connecting until the fresh snapshot has arrived; input
+    /// stays buffered until then.
+    pub(super) awaiting_sync: bool,
+    /// Whether a snapshot has ever arrived on this buffer.
+    pub(super) synced: bool,
+    /// Set when a sync finished with input waiting to be replayed: the note
+    /// for the status row ("joined" or "reconnected").
+    resynced: Option<&'static str>,
+    pub(super) outage: OutageBuffer,
+    /// Remote edits arrived while the buffer was in the background.
+    pub(super) activity: bool,
+    /// The document's activity feed (F9), oldest first.
+    pub(super) feed: VecDeque<ActivityEntry>,
+    /// When the last Ping went out, and the round trip it measured.
+    ping_sent: Option<Instant>,
+    pub(super) rtt: Option<Duration>,
+    /// When the server was last heard from, or the connection opened.
+    pub(super) last_received: Instant,
+    /// Local copy of the text, unless `--no-backup`.
+    pub(super) backup: Option<Backup>,
+    /// An earlier session's backup that differs from the synced text,
+    /// waiting to be offered for restoring.
+    pub(super) restore_offer: Option<String>,
+    /// What the server announced it refuses; none until it says.
+    pub(super) limits: ServerLimits,
+    /// The name the server gave us for joining without one.
+    pub(super) guest_name: Option<String>,
+    /// The server's message of the day last shown.
+    pub(super) motd: Option<String>,
+    /// The document's ACL lets us read it but not edit it.
+    pub(super) read_only: bool,
+    /// Set while a saved revision is shown instead of the text (Shift+F9).
+    pub(super) history: Option<History>,
+    /// Server lines that were no message, cut short, until the main loop
+    /// counts and logs them.
+    pub(super) malformed: Vec<String>,
+}
+
+/// Something that happened on a buffer's connection.
+pub(super) enum NetEvent {
+    Line(io::Result<Option<String>>),
+    Reconnected(io::Result<(Connection, mpsc::Sender<Message>)>),
+}
+
+/// What the tab bar shows for a buffer.
+pub(super) struct TabLabel {
+    pub(super) name: String,
+    pub(super) active: bool,
+    pub(super) activity: bool,
+    /// `connecting` or `offline` while the buffer can't be edited.
+    pub(super) state: Option<&'static str>,
+}
+
+impl Buffer {
+    /// A buffer that starts joining its document in the background.
+    pub(super) fn new(join: JoinInfo, doc: &str, outage_input: OutageInput) -> Self {
+        let reconnect = connection::reconnect_after(Duration::ZERO, join.clone());
+        // Nothing reads this sender; edits are blocked until the join is done.
+        let (out_tx, _) = mpsc::channel(1);
+        Self {
+            doc: doc.to_string(),
+            awareness: Awareness::new(join.user_id.clone(), join.user_name.clone()),
+            doc_state: TextDoc::new(join.doc_id.clone(), join.user_id.clone()),
+            join,
+            version: 0,
+            acked_version: 0,
+            pending: PendingOps::default(),
+            sync: SyncSchedule::default(),
+            quiet_sync: false,
+            coalescer: Coalescer::default(),
+            upload: None,
+            cursor_byte: 0,
+            selection_anchor: None,
+            undo: UndoStack::default(),
+            scroll: 0,
+            hscroll: 0,
+            free_scroll: false,
+            following: None,
+            users: HashMap::new(),
+            guests: HashSet::new(),
+            presence_seq: 0,
+            cursors: HashMap::new(),
+            last_activity: HashMap::new(),
+            edit_flashes: HashMap::new(),
+            selections: HashMap::new(),
+            selection_seq: 0,
+            sent_selection: None,
+            flash_line: None,
+            connection: None,
+            out_tx,
+            reconnect: Some(reconnect),
+            backoff: Backoff::default(),
+            retry_at: None,
+            awaiting_sync: true,
+            synced: false,
+            resynced: None,
+            outage: OutageBuffer::new(outage_input),
+            activity: false,
+            feed: VecDeque::new(),
+            ping_sent: None,
+            rtt: None,
+            last_received: Instant::now(),
+            backup: None,
+            restore_offer: None,
+            limits: ServerLimits::default(),
+            guest_name: None,
+            motd: None,
+            read_only: false,
+            history: None,
+            malformed: Vec::new(),
+        }
+    }
+
+    /// Joins the document right away, failing if the server is unreachable.
+    pub(super) async fn connect(
+        join: JoinInfo,
+        doc: &str,
+        outage_input: OutageInput,
+    ) -> io::Result<Self> {
+        let (connection, out_tx) = Connection::open(&join).await?;
+        let mut buffer = Self::new(join, doc, outage_input);
+        buffer.connection = Some(connection);
+        buffer.out_tx = out_tx;
+        buffer.reconnect = None;
+        buffer.pending.request_sent();
+        Ok(buffer)
+    }
+
+    pub(super) fn is_offline(&self) -> bool {
+        self.connection.is_none() || self.awaiting_sync
+    }
+
+    /// Whether time-based effects (cursor labels and fading, goto and edit
+    /// flashes, reconnect countdown) still need redraws.
+    pub(super) fn animating(&self, now: Instant, activity_ttl: Duration, flash: Duration) -> bool {
+        self.retry_at.is_some()
+            || self
+                .flash_line
+                .is_some_and(|(_, at)| now.duration_since(at) <= flash)
+            || self
+                .edit_flashes
+                .values()
+                .any(|edit| now.duration_since(edit.at) <= flash)
+            || self
+                .last_activity
+                .values()
+                .any(|at| now.duration_since(*at) <= activity_ttl)
+    }
+
+    pub(super) fn handle_reconnect(
+        &mut self,
+        result: io::Result<(Connection, mpsc::Sender<Message>)>,
+        status: &mut StatusLog,
+    ) {
+        match result {
+            Ok((connection, out_tx)) => {
+                self.connection = Some(connection);
+                self.out_tx = out_tx;
+                self.retry_at = None;
+                self.awaiting_sync = true;
+                self.last_received = Instant::now();
+                // Joining sent a sync request.
+                self.pending.request_sent();
+                self.sync.sent(Instant::now());
+                self.backoff.reset();
+            }
+            Err(err) => {
+                self.schedule_reconnect();
+                status.error(format!("reconnect failed: {}", err));
+            }
+        }
+    }
+
+    fn schedule_reconnect(&mut self) {
+        let delay = self.backoff.next_delay();
+        self.retry_at = Some(Instant::now() + delay);
+        self.reconnect = Some(connection::reconnect_after(delay, self.join.clone()));
+    }
+
+    /// Applies a line read from the server. Returns whether the view changed.
+    pub(super) fn handle_line(
+        &mut self,
+        line: io::Result<Option<String>>,
+        status: &mut StatusLog,
+    ) -> bool {
+        let line = match line {
+            Ok(Some(line)) => {
+                self.last_received = Instant::now();
+                line
+            }
+            Ok(None) => {
+                status.error("server closed connection");
+                self.stop_upload("disconnected", status);
+                self.disconnect();
+                return true;
+            }
+            Err(err) => {
+                status.error(format!("read error: {}", err));
+                self.stop_upload("disconnected", status);
+                self.disconnect();
+                return true;
+            }
+        };
+        let msg: Message = match serde_json::from_str(&line) {
+            Ok(msg) => msg,
+            Err(_) => {
+                return match serde_json::from_str(&line) {
+                    Ok(ServerMessage::Welcome {
+                        limits,
+                        name,
+                        doc,
+                        redirected_from,
+                        access,
+                        motd,
+                    }) => {
+                        self.limits = limits;
+                        if let Some(name) = name
+                            && self.guest_name.as_ref() != Some(&name)
+                        {
+                            status.info(format!("joined as guest {}", name));
+                            self.guest_name = Some(name);
+                        }
+                        if let (Some(doc), Some(from)) = (doc, redirected_from) {
+                            status.info(format!("{} is {} in this room", from, doc));
+                            self.redirected(doc);
+                        }
+                        self.read_only = access == Some(Access::Read);
+                        if self.read_only {
+                            status.info("you may only read this document");
+                        }
+                        if let Some(motd) = motd
+                            && self.motd.as_ref() != Some(&motd)
+                        {
+                            // One row, so its lines are run together.
+                            status.notice(motd.lines().collect::<Vec<_>>().join(" | "));
+                            self.motd = Some(motd);
+                        }
+                        true
+                    }
+                    Ok(ServerMessage::Rejected { error }) => {
+                        self.rejected(&error, status);
+                        true
+                    }
+                    Ok(ServerMessage::UserEvent {
+                        room,
+                        doc,
+                        kind,
+                        user,
+                        detail,
+                    }) if format!("{}/{}", room, doc) == self.join.doc_id => {
+                        status.info(kind.describe(&user.name, detail.as_deref()));
+                        let here = matches!(kind, UserEventKind::Joined | UserEventKind::Renamed);
+                        if here && user.guest {
+                            self.guests.insert(user.id);
+                        } else {
+                            self.guests.remove(&user.id);
+                        }
+                        true
+                    }
+                    Ok(ServerMessage::Members {
+                        room,
+                        doc,
+                        users,
+                        presence_seq,
+                    }) if format!("{}/{}", room, doc) == self.join.doc_id => {
+                        self.set_members(users, presence_seq, status)
+                    }
+                    Ok(ServerMessage::DocInfo {
+                        room,
+                        doc,
+                        policy,
+                        acl,
+                        seeded_from_template,
+                    }) if format!("{}/{}", room, doc) == self.join.doc_id => {
+                        let name = self.guest_name.as_ref().unwrap_or(&self.join.user_name);
+                        let read_only = acl.access(name) == Access::Read;
+                        if read_only != self.read_only {
+                            self.read_only = read_only;
+                            status.info(if read_only {
+                                "you may only read this document now"
+                            } else {
+                                "you may edit this document now"
+                            });
+                        }
+                        if seeded_from_template {
+                            status.info("new document, started from the server's template");
+                        }
+                        if !seeded_from_template || !policy.is_off() {
+                            status.info(format!("whitespace policy: {}", policy));
+                        }
+                        true
+                    }
+                    Ok(ServerMessage::Activity { room, doc, entry })
+                        if format!("{}/{}", room, doc) == self.join.doc_id =>
+                    {
+                        self.add_to_feed(entry);
+                        true
+                    }
+                    Ok(ServerMessage::Notice { text, .. }) => {
+                        status.info(text);
+                        true
+                    }
+                    Ok(ServerMessage::DocRenamed { room, from, to })
+                        if format!("{}/{}", room, from) == self.join.doc_id =>
+                    {
+                        status.info(format!("{} was moved to {}; following it", from, to));
+                        self.renamed(to);
+                        true
+                    }
+                    Ok(ServerMessage::Error { error, .. }) => {
+                        status.error(format!("server error: {}", error));
+                        true
+                    }
+                    Err(err) => {
+                        self.malformed.push(client::malformed_line(&line, &err));
+                        false
+                    }
+                    _ => false,
+                };
+            }
+        };
+        self.handle_message(msg, status)
+    }
+
+    /// The name we go by in this buffer's document.
+    pub(super) fn user_name(&self) -> String {
+        self.guest_name
+            .clone()
+            .unwrap_or_else(|| self.join.user_name.clone())
+    }
+
+    /// Takes `doc`, which the server joined instead of the one asked for,
+    /// as this buffer's doc, here and on reconnects. Nothing was synced
+    /// or typed yet: the welcome comes first.
+    fn redirected(&mut self, doc: String) {
+        let room = self
+            .join
+            .doc_id
+            .split_once('/')
+            .map_or("", |(room, _)| room);
+        let doc_id = format!("{}/{}", room, doc);
+        let raw_id = self.join.user_id.split_once('|').map_or("", |(_, raw)| raw);
+        self.join.user_id = make_scoped_user_id(&doc_id, raw_id);
+        self.join.doc_id = doc_id;
+        self.doc_state = TextDoc::new(self.join.doc_id.clone(), self.join.user_id.clone());
+        self.awareness = Awareness::new(self.join.user_id.clone(), self.join.user_name.clone());
+        self.doc = doc;
+    }
+
+    /// Takes `doc` as this buffer's doc after the server renamed ours, with
+    /// its text, and syncs again: edits in flight went to the old name.
+    fn renamed(&mut self, doc: String) {
+        self.flush_typing();
+        let text = self.doc_state.get_text();
+        self.redirected(doc);
+        self.doc_state = build_doc(&self.join.doc_id, &self.join.user_id, &text);
+        let request = encode_sync_request(&self.join.doc_id, self.version);
+        if self.out_tx.try_send(request).is_ok() {
+            self.pending.request_sent();
+            self.sync.sent(Instant::now());
+        }
+    }
+
+    /// The server dropped one of our edits: it won't be echoed, and the
+    /// local text has it while the server's doesn't, so sync again.
+    fn rejected(&mut self, error: &str, status: &mut StatusLog) {
+        status.error(format!("server rejected an edit: {}", error));
+        self.stop_upload("an edit was rejected", status);
+        self.pending.op_acked();
+        self.flush_typing();
+        let request = encode_sync_request(&self.join.doc_id, self.version);
+        if self.out_tx.try_send(request).is_ok() {
+            self.pending.request_sent();
+            self.sync.sent(Instant::now());
+        }
+    }
+
+    /// Closes the history view: applies the remote edits held meanwhile
+    /// and syncs again.
+    pub(super) fn leave_history(&mut self, status: &mut StatusLog) {
+        let Some(history) = self.history.take() else {
+            return;
+        };
+        // Held edits carry older versions than those seen since.
+        let version = self.version;
+        for msg in history.held {
+            self.handle_message(msg, status);
+        }
+        self.version = self.version.max(version);
+        let request = encode_sync_request(&self.join.doc_id, self.version);
+        if self.out_tx.try_send(request).is_ok() {
+            self.pending.request_sent();
+            self.sync.sent(Instant::now());
+        }
+    }
+
+    /// Keeps the last known document on screen (read-only) and tries to get
+    /// back in.
+    fn disconnect(&mut self) {
+        self.connection = None;
+        self.awaiting_sync = false;
+        self.pending.clear();
+        self.quiet_sync = false;
+        self.coalescer.clear();
+        self.ping_sent = None;
+        self.rtt = None;
+        self.following = None;
+        self.cursors.clear();
+        self.selections.clear();
+        self.sent_selection = None;
+        self.last_activity.clear();
+        self.edit_flashes.clear();
+        self.schedule_reconnect();
+    }
+
+    fn handle_message(&mut self, msg: Message, status: &mut StatusLog) -> bool {
+        let doc_id = self.join.doc_id.clone();
+        match msg {
+            Message::Hello {
+                replica_id,
+                user_name,
+            } => {
+                if doc_id_from_scoped_user_id(&replica_id) != Some(doc_id.as_str()) {
+                    return false;
+                }
+                self.users.insert(replica_id, user_name);
+                true
+            }
+            Message::Update { .. } => {
+                let Some((update_doc_id, payload, server_version)) = decode_update(&msg) else {
+                    return false;
+                };
+                if update_doc_id != doc_id {
+                    return false;
+                }
+                let remote = payload.user_id != self.join.user_id;
+                if let Some(history) = self.history.as_mut()
+                    && remote
+                    && matches!(payload.op, Op::Insert { .. } | Op::Delete { .. })
+                {
+                    history.held.push(msg);
+                    return true;
+                }
+                if let Op::Cursor { pos, .. } = payload.op {
+                    if remote {
+                        self.last_activity
+                            .insert(payload.user_id.clone(), Instant::now());
+                        self.cursors.insert(payload.user_id.clone(), pos);
+                    }
+                } else if let Op::Selection { anchor, head } = payload.op {
+                    if remote {
+                        if anchor == head {
+                            self.selections.remove(&payload.user_id);
+                        } else {
+                            self.selection_seq += 1;
+                            let selection = RemoteSelection {
+                                anchor,
+                                head,
+                                seq: self.selection_seq,
+                            };
+                            self.selections.insert(payload.user_id.clone(), selection);
+                        }
+                        self.cursors.insert(payload.user_id.clone(), head);
+                        self.last_activity
+                            .insert(payload.user_id.clone(), Instant::now());
+                    }
+                } else if remote {
+                    // Treat `op` as the single source of truth for remote edits.
+                    // Ignore `payload.delta` to avoid double-applying changes.
+                    apply_op_to_doc(&mut self.doc_state, &payload.op);
+                    shift_for_op(&payload.op, &mut self.cursor_byte);
+                    if let Some(anchor) = self.selection_anchor.as_mut() {
+                        shift_for_op(&payload.op, anchor);
+                    }
+                    self.undo.adjust_for_remote(&payload.op);
+                    self.coalescer.adjust_for_remote(&payload.op);
+                    if let Some(upload) = self.upload.as_mut() {
+                        upload.adjust_for_remote(&payload.op);
+                    }
+                    shift_remote_positions(
+                        &payload.op,
+                        &mut self.cursors,
+                        &mut self.selections,
+                        &mut self.edit_flashes,
+                    );
+                    let now = Instant::now();
+                    EditFlash::record(&mut self.edit_flashes, &payload.user_id, &payload.op, now);
+                    self.last_activity.insert(payload.user_id.clone(), now);
+                    self.activity = true;
+                }
+                if !remote {
+                    self.acked_version = server_version;
+                    if matches!(payload.op, Op::Insert { .. } | Op::Delete { .. }) {
+                        self.pending.op_acked();
+                    }
+                }
+                self.version = server_version;
+                let text_len = self.doc_state.get_text().len();
+                self.cursor_byte = self.cursor_byte.min(text_len);
+                self.selection_anchor = self.selection_anchor.map(|anchor| anchor.min(text_len));
+                true
+            }
+            Message::Presence {
+                user_id,
+                document_id,
+                cursor_pos,
+            } => {
+                if document_id != doc_id {
+                    return false;
+                }
+                match cursor_pos {
+                    Some(pos) => {
+                        self.last_activity.insert(user_id.clone(), Instant::now());
+                        self.cursors.insert(user_id, pos);
+                    }
+                    None => self.user_left(&user_id, status),
+                }
+                true
+            }
+            Message::SyncResponse { .. } => {
+                let Some((sync_doc_id, payload, server_version)) = decode_sync_response(&msg)
+                else {
+                    return false;
+                };
+                if sync_doc_id != doc_id {
+                    return false;
+                }
+                if let Some(err) = payload.error {
+                    status.error(format!("join refused: {}", err));
+                    return true;
+                }
+                // The snapshot has the edits held for after the history view.
+                if let Some(history) = self.history.as_mut() {
+                    history.held.clear();
+                }
+                // Batched characters count as sent after the request.
+                self.flush_typing();
+                // Apply only the difference, so local edits the snapshot
+                // doesn't have yet survive and positions follow the text.
+                let target = self.pending.rebase(&payload.text);
+                for op in snapshot::diff(&self.doc_state.get_text(), &target) {
+                    apply_op_to_doc(&mut self.doc_state, &op);
+                    shift_for_op(&op, &mut self.cursor_byte);
+                    if let Some(anchor) = self.selection_anchor.as_mut() {
+                        shift_for_op(&op, anchor);
+                    }
+                    self.undo.adjust_for_remote(&op);
+                    if let Some(upload) = self.upload.as_mut() {
+                        upload.adjust_for_remote(&op);
+                    }
+                    shift_remote_positions(
+                        &op,
+                        &mut self.cursors,
+                        &mut self.selections,
+                        &mut self.edit_flashes,
+                    );
+                }
+                self.version = server_version;
+                self.sent_selection = None;
+                self.selections.clear();
+                self.edit_flashes.clear();
+                self.users.clear();
+                self.guests.clear();
+                self.presence_seq = payload.presence_seq;
+                for user in payload.users {
+                    if user.guest {
+                        self.guests.insert(user.id.clone());
+                    }
+                    self.users.insert(user.id, user.name);
+                }
+                if !std::mem::take(&mut self.quiet_sync) {
+                    status.info("sync complete");
+                }
+                if self.awaiting_sync {
+                    self.awaiting_sync = false;
+                    if self.synced {
+                        self.resynced = Some("reconnected");
+                    } else if !self.outage.summary().is_empty() {
+                        self.resynced = Some("joined");
+                    }
+                }
+                if !self.synced {
+                    let text = self.doc_state.get_text();
+                    self.restore_offer = self
+                        .backup
+                        .as_mut()
+                        .and_then(Backup::take_previous)
+                        .filter(|previous| !previous.is_empty() && *previous != text);
+                }
+                self.synced = true;
+                true
+            }
+            Message::Pong => {
+                self.rtt = self.ping_sent.map(|at| at.elapsed());
+                false
+            }
+            Message::Ack { .. } | Message::Ping | Message::SyncRequest { .. } => false,
+        }
+    }
+
+    /// Takes `users` as the user list if `presence_seq` is newer than the
+    /// one we have. Returns whether it was.
+    fn set_members(
+        &mut self,
+        users: Vec<WireUser>,
+        presence_seq: u64,
+        status: &mut StatusLog,
+    ) -> bool {
+        if presence_seq <= self.presence_seq {
+            return false;
+        }
+        self.presence_seq = presence_seq;
+        let left: Vec<String> = self
+            .users
+            .keys()
+            .filter(|id| !users.iter().any(|user| &user.id == *id))
+            .cloned()
+            .collect();
+        for user_id in left {
+            self.user_left(&user_id, status);
+        }
+        self.guests = users
+            .iter()
+            .filter(|user| user.guest)
+            .map(|user| user.id.clone())
+            .collect();
+        self.users = users.into_iter().map(|user| (user.id, user.name)).collect();
+        true
+    }
+
+    /// Forgets `user_id`, who left the document.
+    fn user_left(&mut self, user_id: &str, status: &mut StatusLog) {
+        if self.following.as_deref() == Some(user_id) {
+            let name = self.users.get(user_id).map_or(user_id, String::as_str);
+            status.info(format!("stopped following {}: user left", name));
+            self.following = None;
+        }
+        self.users.remove(user_id);
+        self.cursors.remove(user_id);
+        self.selections.remove(user_id);
+        self.last_activity.remove(user_id);
+        self.edit_flashes.remove(user_id);
+    }
+
+    /// Adds an entry to the activity feed. Entries sent again on
+    /// rejoining are already there.
+    fn add_to_feed(&mut self, entry: ActivityEntry) {
+        if self.feed.contains(&entry) {
+            return;
+        }
+        if self.feed.len() >= FEED_LEN {
+            self.feed.pop_front();
+        }
+        self.feed.push_back(entry);
+    }
+
+    /// Hands out the input buffered during an outage once the buffer is
+    /// synced again, with a status note about it.
+    pub(super) fn take_replay(&mut self) -> Option<(Vec<UiEvent>, String)> {
+        let note = self.resynced.take()?;
+        let (replay, discarded) = self.outage.take();
+        let status = match (replay.len(), discarded) {
+            (0, 0) => note.to_string(),
+            (0, discarded) => format!("{}; {} keys discarded", note, discarded),
+            (replayed, 0) => format!("{}; replaying {} keys", note, replayed),
+            (replayed, discarded) => format!(
+                "{}; replaying {} keys, {} discarded",
+                note, replayed, discarded
+            ),
+        };
+        Some((replay, status))
+    }
+
+    /// Writes the text to the backup file. Nothing is written before the
+    /// first sync, which would clobber the last session's backup with an
+    /// empty document.
+    pub(super) fn save_backup(&mut self, now: Instant) -> io::Result<()> {
+        match self.backup.as_mut() {
+            Some(backup) if self.synced => backup.save(&self.doc_state.get_text(), now),
+            _ => Ok(()),
+        }
+    }
+
+    /// Local edits sent but not yet echoed back by the server, counting
+    /// batched characters as one.
+    pub(super) fn unconfirmed_edits(&self) -> usize {
+        self.pending.unacked() + usize::from(self.coalescer.deadline().is_some())
+    }
+
+    /// Leaves the document, first letting the writer task send what is
+    /// still queued; gives up at `deadline`.
+    pub(super) async fn close(mut self, deadline: Instant) {
+        self.flush_typing();
+        let Some(mut connection) = self.connection.take() else {
+            return;
+        };
+        // The writer task finishes once its channel is drained and the
+        // last sender, ours, is gone.
+        drop(self);
+        let _ = tokio::time::timeout_at(deadline.into(), connection.drained()).await;
+    }
+
+    /// Sends typed characters that are still batched.
+    pub(super) fn flush_typing(&mut self) {
+        let mut outbox = Outbox {
+            out_tx: &self.out_tx,
+            pending: &mut self.pending,
+            sync: &mut self.sync,
+            doc_id: &self.join.doc_id,
+            user_id: &self.join.user_id,
+            version: self.version,
+        };
+        self.coalescer.flush(&mut outbox, self.cursor_byte);
+    }
+
+    /// When the next chunk of the upload can go out, later while the
+    /// writer's queue is full.
+    pub(super) fn upload_due(&self, now: Instant) -> Option<Instant> {
+        let upload = self.upload.as_ref()?;
+        if self.out_tx.capacity() == 0 {
+            return Some(now + UPLOAD_RETRY);
+        }
+        Some(now + upload.wait(now))
+    }
+
+    /// Sends the next chunk of the upload if it is due, and applies it to
+    /// the local text with the cursor after it, so the text only ever has
+    /// what was sent. Returns whether the view changed.
+    pub(super) fn send_upload(&mut self, now: Instant, status: &mut StatusLog) -> bool {
+        let Some(upload) = self.upload.as_mut() else {
+            return false;
+        };
+        if upload.wait(now) > Duration::ZERO || self.out_tx.capacity() == 0 {
+            return false;
+        }
+        let Some(op) = upload.next_op() else {
+            return false;
+        };
+        let mut outbox = Outbox {
+            out_tx: &self.out_tx,
+            pending: &mut self.pending,
+            sync: &mut self.sync,
+            doc_id: &self.join.doc_id,
+            user_id: &self.join.user_id,
+            version: self.version,
+        };
+        if !outbox.send_op(op.clone()) {
+            self.stop_upload("the connection is gone", status);
+            return true;
+        }
+        upload.chunk_sent(now);
+        apply_op_to_doc(&mut self.doc_state, &op);
+        shift_remote_positions(
+            &op,
+            &mut self.cursors,
+            &mut self.selections,
+            &mut self.edit_flashes,
+        );
+        if let Op::Insert { pos, text, .. } = op {
+            self.cursor_byte = pos + text.len();
+            self.undo.record(Edit::Insert { pos, text });
+        }
+        if upload.is_done() {
+            status.info(format!(
+                "pasted {} bytes in {} chunks",
+                upload.bytes(),
+                upload.chunks()
+            ));
+            self.upload = None;
+        } else {
+            status.progress(upload.progress());
+        }
+        true
+    }
+
+    /// Drops the rest of the upload (Esc); what was sent stays.
+    pub(super) fn cancel_upload(&mut self, status: &mut StatusLog) {
+        if let Some(upload) = self.upload.take() {
+            status.info(format!("upload cancelled after {}", upload.summary()));
+        }
+    }
+
+    fn stop_upload(&mut self, why: &str, status: &mut StatusLog) {
+        if let Some(upload) = self.upload.take() {
+            status.error(format!(
+                "upload stopped after {}: {}",
+                upload.summary(),
+                why
+            ));
+        }
+    }
+
+    pub(super) fn link_state(&self, now: Instant) -> LinkState {
+        let silence = now.saturating_duration_since(self.last_received);
+        LinkState::assess(!self.is_offline(), silence, self.rtt)
+    }
+
+    /// Pings a link that has gone quiet, so it either shows signs of life
+    /// or counts as down. Quiet for half the server's idle timeout counts
+    /// too, so that an idle editor isn't disconnected.
+    pub(super) fn probe(&mut self, now: Instant) {
+        let silence = now.saturating_duration_since(self.last_received);
+        let idle_timeout = self.limits.idle_timeout_secs.map(Duration::from_secs);
+        if link::needs_probe(silence) || idle_timeout.is_some_and(|timeout| silence >= timeout / 2)
+        {
+            self.ping(now);
+        }
+    }
+
+    /// Sends an automatic sync if `--auto-sync` says one is due, unless a
+    /// snapshot now would land on typing, an upload or the history view.
+    pub(super) fn auto_sync(&mut self, now: Instant) {
+        let busy =
+            self.upload.is_some() || self.history.is_some() || self.coalescer.deadline().is_some();
+        if self.is_offline() || busy || !self.sync.auto_due(now) {
+            return;
+        }
+        let request = encode_sync_request(&self.join.doc_id, self.version);
+        if self.out_tx.try_send(request).is_ok() {
+            self.pending.request_sent();
+            self.quiet_sync = true;
+        }
+    }
+
+    /// Measures the round trip to the server, at most once per
+    /// `PING_INTERVAL`.
+    pub(super) fn ping(&mut self, now: Instant) {
+        let due = self
+            .ping_sent
+            .is_none_or(|at| now.duration_since(at) >= PING_INTERVAL);
+        if self.connection.is_some() && due && self.out_tx.try_send(Message::Ping).is_ok() {
+            self.ping_sent = Some(now);
+        }
+    }
+
+    /// Sends the local selection if it changed since it was last sent.
+    pub(super) fn sync_selection(&mut self) {
+        let local_selection = self
+            .selection_anchor
+            .filter(|anchor| *anchor != self.cursor_byte)
+            .map(|anchor| (anchor, self.cursor_byte));
+        if self.connection.is_none() || local_selection == self.sent_selection {
+            return;
+        }
+        self.flush_typing();
+        let (anchor, head) = local_selection.unwrap_or((self.cursor_byte, self.cursor_byte));
+        let op = Op::Selection { anchor, head };
+        if let Ok(msg) = encode_update(
+            &self.join.doc_id,
+            &self.join.user_id,
+            op,
+            Vec::new(),
+            self.version,
+        ) {
+            let _ = self.out_tx.try_send(msg);
+        }
+        self.sent_selection = local_selection;
+    }
+}
+
+/// Waits for the next line or finished reconnect attempt on any buffer.
+pub(super) fn next_event(buffers: &mut [Buffer]) -> impl Future<Output = (usize, NetEvent)> + '_ {
+    std::future::poll_fn(move |cx| {
+        for (idx, buffer) in buffers.iter_mut().enumerate() {
+            if let Some(connection) = buffer.connection.as_mut()
+                && let Poll::Ready(line) = connection.poll_next_line(cx)
+            {
+                return Poll::Ready((idx, NetEvent::Line(line)));
+            }
+            if let Some(reconnect) = buffer.reconnect.as_mut()
+                && let Poll::Ready(result) = reconnect.as_mut().poll(cx)
+            {
+                buffer.reconnect = None;
+                return Poll::Ready((idx, NetEvent::Reconnected(result)));
+            }
+        }
+        Poll::Pending
+    })
+}
+
+pub(super) fn tab_labels(buffers: &[Buffer], active: usize) -> Vec<TabLabel> {
+    buffers
+        .iter()
+        .enumerate()
+        .map(|(idx, buffer)| TabLabel {
+            name: buffer.doc.clone(),
+            active: idx == active,
+            activity: idx != active && buffer.activity,
+            state: match (buffer.is_offline(), buffer.synced) {
+                (false, _) => None,
+                (true, false) => Some("connecting"),
+                (true, true) => Some("offline"),
+            },
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::NetConditions;
+    use crate::protocol::{LineCol, encode_sync_response};
+    use crossterm::event::{KeyCode, KeyEvent};
+
+    fn line(msg: Message) -> io::Result<Option<String>> {
+        Ok(Some(serde_json::to_string(&msg).unwrap()))
+    }
+
+    #[test]
+    fn syncs_applies_remote_edits_and_replays_after_reconnect() {
+        let join = JoinInfo {
+            addr: "127.0.0.1:1".to_string(),
+            user_id: "demo/notes|me".to_string(),
+            user_name: "me".to_string(),
+            doc_id: "demo/notes".to_string(),
+            network: NetConditions::default(),
+            record: None,
+        };
+        let mut buffer = Buffer::new(join, "notes", OutageInput::Queue);
+        let mut status = StatusLog::default();
+        let sync = || encode_sync_response("demo/notes", "hello", Vec::new(), 0, 3).unwrap();
+        assert!(buffer.is_offline());
+
+        assert!(buffer.handle_line(line(sync()), &mut status));
+        assert!(buffer.synced && !buffer.awaiting_sync);
+        assert!(buffer.take_replay().is_none());
+
+        let insert = Op::Insert {
+            pos: 5,
+            text: "!".to_string(),
+            pos_chars: None,
+        };
+        let update = encode_update("demo/notes", "demo/notes|bob", insert, Vec::new(), 4);
+        assert!(buffer.handle_line(line(update.unwrap()), &mut status));
+        assert_eq!(buffer.doc_state.get_text(), "hello!");
+        assert!(buffer.activity);
+        // Other documents' traffic is ignored.
+        let other = encode_update(
+            "demo/todo",
+            "demo/todo|bob",
+            Op::Cursor { pos: 0, at: None },
+            vec![],
+            1,
+        );
+        assert!(!buffer.handle_line(line(other.unwrap()), &mut status));
+        // Cursor moves arrive as updates too.
+        let moved = Op::Cursor {
+            pos: 3,
+            at: Some(LineCol { line: 1, col: 4 }),
+        };
+        let moved = encode_update("demo/notes", "demo/notes|bob", moved, vec![], 4);
+        assert!(buffer.handle_line(line(moved.unwrap()), &mut status));
+        assert_eq!(buffer.cursors.get("demo/notes|bob"), Some(&3));
+        // Garbage is kept for the debug log, cut short, and changes nothing.
+        let garbage = format!("{{\"Update\": {}", "x".repeat(200));
+        assert!(!buffer.handle_line(Ok(Some(garbage)), &mut status));
+        assert_eq!(buffer.malformed.len(), 1);
+        assert!(
+            buffer.malformed[0].starts_with("expected value at line 1 column 12: {\"Update\": xx"),
+            "{}",
+            buffer.malformed[0]
+        );
+        assert!(buffer.malformed[0].ends_with("x…"));
+        assert_eq!(buffer.doc_state.get_text(), "hello!");
+
+        // Our own edit counts as unconfirmed until the server echoes it.
+        let own = Op::Insert {
+            pos: 6,
+            text: "?".to_string(),
+            pos_chars: None,
+        };
+        apply_op_to_doc(&mut buffer.doc_state, &own);
+        buffer.pending.op_sent(&own);
+        assert_eq!(buffer.unconfirmed_edits(), 1);
+        let echo = encode_update("demo/notes", "demo/notes|me", own, Vec::new(), 5);
+        buffer.handle_line(line(echo.unwrap()), &mut status);
+        assert_eq!((buffer.unconfirmed_edits(), buffer.acked_version), (0, 5));
+        assert_eq!(buffer.doc_state.get_text(), "hello!?");
+
+        buffer.handle_line(Ok(None), &mut status);
+        assert!(buffer.retry_at.is_some());
+        buffer
+            .outage
+            .push(UiEvent::Key(KeyEvent::from(KeyCode::Char('x'))));
+        buffer.awaiting_sync = true;
+        buffer.handle_line(line(sync()), &mut status);
+        let (replay, status) = buffer.take_replay().unwrap();
+        assert_eq!(
+            (replay.len(), status.as_str()),
+            (1, "reconnected; replaying 1 keys")
+        );
+        assert_eq!(buffer.doc_state.get_text(), "hello");
+    }
+
+    #[test]
+    fn resync_keeps_unacked_local_typing() {
+        let join = JoinInfo {
+            addr: "127.0.0.1:1".to_string(),
+            user_id: "demo/notes|me".to_string(),
+            user_name: "me".to_string(),
+            doc_id: "demo/notes".to_string(),
+            network: NetConditions::default(),
+            record: None,
+        };
+        let mut buffer = Buffer::new(join, "notes", OutageInput::Queue);
+        let mut status = StatusLog::default();
+        let sync = |text| line(encode_sync_response("demo/notes", text, Vec::new(), 0, 1).unwrap());
+        buffer.pending.request_sent();
+        buffer.handle_line(sync("hello wor, bye"), &mut status);
+
+        // Ctrl+R, then "ld" is typed before the snapshot arrives.
+        buffer.pending.request_sent();
+        for (pos, ch) in [(9, "l"), (10, "d")] {
+            let op = Op::Insert {
+                pos,
+                text: ch.to_string(),
+                pos_chars: None,
+            };
+            apply_op_to_doc(&mut buffer.doc_state, &op);
+            buffer.pending.op_sent(&op);
+        }
+        buffer.cursor_byte = 11;
+        buffer.selection_anchor = Some(13);
+
+        // Someone else meanwhile added "!" at the end.
+        buffer.handle_line(sync("hello wor, bye!"), &mut status);
+        assert_eq!(buffer.doc_state.get_text(), "hello world, bye!");
+        assert_eq!(
+            (buffer.cursor_byte, buffer.selection_anchor),
+            (11, Some(13))
+        );
+    }
+
+    #[test]
+    fn a_differing_backup_is_offered_after_the_first_sync() {
+        let dir = std::env::temp_dir().join(format!("carnelia-offer-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("demo__notes.txt"), "hello, unsent").unwrap();
+        let join = JoinInfo {
+            addr: "127.0.0.1:1".to_string(),
+            user_id: "demo/notes|me".to_string(),
+            user_name: "me".to_string(),
+            doc_id: "demo/notes".to_string(),
+            network: NetConditions::default(),
+            record: None,
+        };
+        let mut buffer = Buffer::new(join, "notes", OutageInput::Queue);
+        let now = Instant::now();
+        buffer.backup = Some(Backup::new(&dir, "demo", "notes", now));
+        let mut status = StatusLog::default();
+        let sync = || line(encode_sync_response("demo/notes", "hello", Vec::new(), 0, 1).unwrap());
+
+        // Not synced yet: the old backup must stay untouched.
+        buffer.save_backup(now).unwrap();
+        let backup = std::fs::read_to_string(dir.join("demo__notes.txt")).unwrap();
+        assert_eq!(backup, "hello, unsent");
+
+        buffer.handle_line(sync(), &mut status);
+        assert_eq!(
+            buffer.restore_offer.take().as_deref(),
+            Some("hello, unsent")
+        );
+        buffer.save_backup(now).unwrap();
+        let backup = std::fs::read_to_string(dir.join("demo__notes.txt")).unwrap();
+        assert_eq!(backup, "hello");
+        let rotated = std::fs::read_to_string(dir.join("demo__notes.1.txt")).unwrap();
+        assert_eq!(rotated, "hello, unsent");
+
+        // Only the first sync offers it.
+        buffer.awaiting_sync = true;
+        buffer.handle_line(sync(), &mut status);
+        assert!(buffer.restore_offer.is_none());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn user_lists_older_than_the_one_we_have_are_ignored() {
+        let join = JoinInfo {
+            addr: "127.0.0.1:1".to_string(),
+            user_id: "demo/notes|me".to_string(),
+            user_name: "me".to_string(),
+            doc_id: "demo/notes".to_string(),
+            network: NetConditions::default(),
+            record: None,
+        };
+        let mut buffer = Buffer::new(join, "notes", OutageInput::Queue);
+        let mut status = StatusLog::default();
+        let user = |id: &str, name: &str| WireUser {
+            id: id.to_string(),
+            name: name.to_string(),
+            guest: false,
+        };
+        let members = |users: Vec<WireUser>, presence_seq| {
+            let msg = ServerMessage::Members {
+                room: "demo".to_string(),
+                doc: "notes".to_string(),
+                users,
+                presence_seq,
+            };
+            Ok(Some(serde_json::to_string(&msg).unwrap()))
+        };
+        let me = user("demo/notes|me", "me");
+        let bob = user("demo/notes|bob", "bob");
+
+        let users = vec![me.clone(), bob.clone()];
+        let sync = encode_sync_response("demo/notes", "hi", users, 5, 1).unwrap();
+        buffer.handle_line(line(sync), &mut status);
+        buffer.cursors.insert(bob.id.clone(), 1);
+        // Sent before the sync was, but read after it.
+        assert!(!buffer.handle_line(members(vec![me.clone()], 4), &mut status));
+        assert!(!buffer.handle_line(members(vec![me.clone()], 5), &mut status));
+        assert_eq!(buffer.users.len(), 2);
+
+        let cy = WireUser {
+            guest: true,
+            ..user("demo/notes|cy", "guest-cy")
+        };
+        let users = vec![me.clone(), cy.clone()];
+        assert!(buffer.handle_line(members(users, 6), &mut status));
+        assert_eq!(
+            buffer.users.get(&cy.id).map(String::as_str),
+            Some("guest-cy")
+        );
+        assert!(buffer.guests.contains(&cy.id));
+        assert!(!buffer.users.contains_key(&bob.id) && buffer.cursors.is_empty());
+        assert!(!buffer.handle_line(members(vec![me], 3), &mut status));
+        assert_eq!(buffer.users.len(), 2);
+    }
+
+    #[test]
+    fn the_activity_feed_keeps_each_entry_once() {
+        let join = JoinInfo {
+            addr: "127.0.0.1:1".to_string(),
+            user_id: "demo/notes|me".to_string(),
+            user_name: "me".to_string(),
+            doc_id: "demo/notes".to_string(),
+            network: NetConditions::default(),
+            record: None,
+        };
+        let mut buffer = Buffer::new(join, "notes", OutageInput::Queue);
+        let mut status = StatusLog::default();
+        let activity = |doc: &str, seq| {
+            let entry = ActivityEntry {
+                seq,
+                at_ms: 1_000,
+                user: "bob".to_string(),
+                kind: crate::protocol::ActivityKind::Deleted { bytes: 900 },
+            };
+            let msg = ServerMessage::Activity {
+                room: "demo".to_string(),
+                doc: doc.to_string(),
+                entry,
+            };
+            Ok(Some(serde_json::to_string(&msg).unwrap()))
+        };
+        assert!(buffer.handle_line(activity("notes", 1), &mut status));
+        // Joining again sends the feed again.
+        assert!(buffer.handle_line(activity("notes", 1), &mut status));
+        assert!(!buffer.handle_line(activity("todo", 2), &mut status));
+        assert!(buffer.handle_line(activity("notes", 2), &mut status));
+        let seqs: Vec<u64> = buffer.feed.iter().map(|entry| entry.seq).collect();
+        assert_eq!(seqs, [1, 2]);
+        assert_eq!(buffer.feed[0].describe(), "bob deleted 900 bytes");
+    }
+}
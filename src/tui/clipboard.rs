@@ -0,0 +1,37 @@
+/// System clipboard with an internal register used when no system clipboard
+/// is reachable (e.g. SSH sessions without a display).
+pub(super) struct Clipboard {
+    system: Option<arboard::Clipboard>,
+    register: String,
+}
+
+impl Clipboard {
+    pub(super) fn new() -> Self {
+        Self {
+            system: arboard::Clipboard::new().ok(),
+            register: String::new(),
+        }
+    }
+
+    pub(super) fn copy(&mut self, text: &str) {
+        self.register = text.to_string();
+        if let Some(system) = self.system.as_mut()
+            && system.set_text(text.to_string()).is_err()
+        {
+            self.system = None;
+        }
+    }
+
+    pub(super) fn paste(&mut self) -> String {
+        if let Some(system) = self.system.as_mut()
+            && let Ok(text) = system.get_text()
+        {
+            return text;
+        }
+        self.register.clone()
+    }
+
+    pub(super) fn is_system(&self) -> bool {
+        self.system.is_some()
+    }
+}
@@ -0,0 +1,228 @@
+//! Batching of typed characters into combined Insert messages.
+
+use crate::client::resync::SyncSchedule;
+use crate::protocol::{Op, encode_update};
+use crate::snapshot::PendingOps;
+use crate::textpos::shift_for_op;
+use mdcs_sdk::Message;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Typed characters are held back at most this long before being sent.
+const TYPING_BATCH: Duration = Duration::from_millis(30);
+
+/// Where outgoing edits and cursor updates of a buffer go.
+pub(super) struct Outbox<'a> {
+    pub(super) out_tx: &'a mpsc::Sender<Message>,
+    pub(super) pending: &'a mut PendingOps,
+    /// Told about each edit, so automatic syncs wait for a pause.
+    pub(super) sync: &'a mut SyncSchedule,
+    pub(super) doc_id: &'a str,
+    pub(super) user_id: &'a str,
+    pub(super) version: u64,
+}
+
+impl Outbox<'_> {
+    /// Queues `op` for the server. Returns false if it couldn't be.
+    pub(super) fn send_op(&mut self, op: Op) -> bool {
+        let delta = Vec::new();
+        if let Ok(msg) = encode_update(self.doc_id, self.user_id, op.clone(), delta, self.version)
+            && self.out_tx.try_send(msg).is_ok()
+        {
+            self.pending.op_sent(&op);
+            self.sync.edited(Instant::now());
+            return true;
+        }
+        false
+    }
+
+    pub(super) fn send_cursor(&self, pos: usize) {
+        let _ = self.out_tx.try_send(Message::Presence {
+            user_id: self.user_id.to_string(),
+            document_id: self.doc_id.to_string(),
+            cursor_pos: Some(pos),
+        });
+    }
+}
+
+/// Characters typed at an advancing position, waiting to go out as one
+/// Insert. The local document already contains them.
+#[derive(Debug, Default)]
+pub(super) struct Coalescer {
+    batch: Option<Batch>,
+}
+
+#[derive(Debug)]
+struct Batch {
+    pos: usize,
+    text: String,
+    started: Instant,
+    /// A cursor update was held back along with the characters.
+    cursor: bool,
+}
+
+impl Coalescer {
+    /// Sends `op`, batching it if it is a typed character. Anything that
+    /// doesn't continue the batch sends it first, with the held back cursor
+    /// at `cursor`.
+    pub(super) fn send(&mut self, op: Op, outbox: &mut Outbox<'_>, cursor: usize, now: Instant) {
+        let typed = typed_char(&op);
+        let continues = match (&self.batch, typed) {
+            (None, _) => true,
+            (Some(batch), Some((pos, _))) => pos == batch.pos + batch.text.len(),
+            (Some(_), None) => false,
+        };
+        if !continues {
+            self.flush(outbox, cursor);
+        }
+        let Some((pos, ch)) = typed else {
+            outbox.send_op(op);
+            return;
+        };
+        match self.batch.as_mut() {
+            Some(batch) => batch.text.push(ch),
+            None => {
+                self.batch = Some(Batch {
+                    pos,
+                    text: ch.to_string(),
+                    started: now,
+                    cursor: false,
+                });
+            }
+        }
+    }
+
+    /// Holds back a cursor update while characters are batched. Returns
+    /// false if it should be sent now.
+    pub(super) fn defer_cursor(&mut self) -> bool {
+        match self.batch.as_mut() {
+            Some(batch) => {
+                batch.cursor = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// When the batch has to go out.
+    pub(super) fn deadline(&self) -> Option<Instant> {
+        self.batch
+            .as_ref()
+            .map(|batch| batch.started + TYPING_BATCH)
+    }
+
+    /// Keeps the batch position in step with a remote edit applied to the
+    /// local document.
+    pub(super) fn adjust_for_remote(&mut self, op: &Op) {
+        if let Some(batch) = self.batch.as_mut() {
+            shift_for_op(op, &mut batch.pos);
+        }
+    }
+
+    /// Sends the batched Insert, then the cursor at `cursor` if an update
+    /// was held back.
+    pub(super) fn flush(&mut self, outbox: &mut Outbox<'_>, cursor: usize) {
+        let Some(batch) = self.batch.take() else {
+            return;
+        };
+        outbox.send_op(Op::Insert {
+            pos: batch.pos,
+            text: batch.text,
+            pos_chars: None,
+        });
+        if batch.cursor {
+            outbox.send_cursor(cursor);
+        }
+    }
+
+    /// Drops the batch, e.g. when the connection is gone.
+    pub(super) fn clear(&mut self) {
+        self.batch = None;
+    }
+}
+
+fn typed_char(op: &Op) -> Option<(usize, char)> {
+    let Op::Insert { pos, text, .. } = op else {
+        return None;
+    };
+    let mut chars = text.chars();
+    let ch = chars.next()?;
+    chars.next().is_none().then_some((*pos, ch))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::decode_update;
+
+    fn apply(text: &mut String, op: &Op) {
+        match op {
+            Op::Insert {
+                pos, text: insert, ..
+            } => text.insert_str(*pos, insert),
+            Op::Delete { pos, len, .. } => drop(text.drain(*pos..pos + len)),
+            Op::Cursor { .. } | Op::Selection { .. } => {}
+        }
+    }
+
+    #[test]
+    fn batched_ops_give_the_same_text() {
+        let (out_tx, mut out_rx) = mpsc::channel(64);
+        let mut pending = PendingOps::default();
+        let mut sync = SyncSchedule::default();
+        let mut outbox = Outbox {
+            out_tx: &out_tx,
+            pending: &mut pending,
+            sync: &mut sync,
+            doc_id: "demo/notes",
+            user_id: "demo/notes|me",
+            version: 1,
+        };
+        let insert = |pos, text: &str| Op::Insert {
+            pos,
+            text: text.to_string(),
+            pos_chars: None,
+        };
+        // Typing "héllo", a jump back, "X", Backspace and "!\n".
+        let ops = [
+            insert(0, "h"),
+            insert(1, "é"),
+            insert(3, "l"),
+            insert(4, "l"),
+            insert(5, "o"),
+            insert(1, "X"),
+            Op::Delete {
+                pos: 1,
+                len: 1,
+                pos_chars: None,
+                len_chars: None,
+            },
+            insert(6, "!"),
+            insert(7, "\n"),
+            insert(0, "pasted "),
+        ];
+        let mut coalescer = Coalescer::default();
+        let now = Instant::now();
+        let mut expected = String::new();
+        for op in ops {
+            apply(&mut expected, &op);
+            coalescer.send(op, &mut outbox, 0, now);
+            assert!(coalescer.defer_cursor() || coalescer.deadline().is_none());
+        }
+        assert_eq!(coalescer.deadline(), None);
+
+        let mut sent = Vec::new();
+        while let Ok(msg) = out_rx.try_recv() {
+            if let Some((_, payload, _)) = decode_update(&msg) {
+                sent.push(payload.op);
+            }
+        }
+        assert_eq!(sent.len(), 5);
+        assert_eq!(sent[0], insert(0, "héllo"));
+        let mut text = String::new();
+        for op in &sent {
+            apply(&mut text, op);
+        }
+        assert_eq!(text, expected);
+    }
+}
@@ -0,0 +1,398 @@
+//! The command palette (Ctrl+P): `:goto 42`, `:theme light`, ... Every
+//! command is one row of `COMMANDS`, which also feeds completion and the
+//! `:help` overlay.
+
+use super::ThemeName;
+use clap::ValueEnum;
+
+/// A parsed palette command, run by `tui::run_command`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Command {
+    Sync,
+    Snapshot,
+    Stats,
+    Users,
+    Activity,
+    History,
+    /// 1-based line and optional display column.
+    Goto(usize, Option<usize>),
+    Open(String),
+    Docs,
+    Theme(ThemeName),
+    Save(String),
+    /// `:s/<pattern>/<replacement>/[g][r]`: every match of each line with
+    /// `g`, only its first without; a regex with `r`.
+    Substitute {
+        pattern: String,
+        replacement: String,
+        every: bool,
+        regex: bool,
+    },
+    Quit,
+    Help,
+}
+
+pub(super) struct CommandSpec {
+    pub(super) name: &'static str,
+    pub(super) aliases: &'static [&'static str],
+    /// Argument synopsis for `:help`, empty without arguments.
+    pub(super) args: &'static str,
+    pub(super) help: &'static str,
+    parse: fn(&str) -> Result<Command, String>,
+    /// Values the argument can be completed to.
+    complete: fn() -> Vec<String>,
+}
+
+pub(super) const COMMANDS: &[CommandSpec] = &[
+    CommandSpec {
+        name: "sync",
+        aliases: &[],
+        args: "",
+        help: "request a fresh snapshot (Ctrl+R)",
+        parse: |arg| no_arg(arg, Command::Sync),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "snapshot",
+        aliases: &[],
+        args: "",
+        help: "save the document with a revision on the server now",
+        parse: |arg| no_arg(arg, Command::Snapshot),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "stats",
+        aliases: &[],
+        args: "",
+        help: "show who wrote how much of the document",
+        parse: |arg| no_arg(arg, Command::Stats),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "users",
+        aliases: &[],
+        args: "",
+        help: "toggle the presence sidebar (F2)",
+        parse: |arg| no_arg(arg, Command::Users),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "activity",
+        aliases: &[],
+        args: "",
+        help: "toggle the document's activity feed (F9)",
+        parse: |arg| no_arg(arg, Command::Activity),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "history",
+        aliases: &[],
+        args: "",
+        help: "step through the document's saved revisions (Shift+F9)",
+        parse: |arg| no_arg(arg, Command::History),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "goto",
+        aliases: &["g"],
+        args: "<line>[:<col>]",
+        help: "jump to a line (Ctrl+G)",
+        parse: |arg| super::parse_goto(arg).map(|(line, col)| Command::Goto(line, col)),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "open",
+        aliases: &["e"],
+        args: "<doc>",
+        help: "open another doc of the room in a tab (Ctrl+N)",
+        parse: |arg| required(arg, "doc name").map(Command::Open),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "docs",
+        aliases: &[],
+        args: "",
+        help: "pick a doc of the room to open from the server's list",
+        parse: |arg| no_arg(arg, Command::Docs),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "theme",
+        aliases: &[],
+        args: "<dark|light|high-contrast>",
+        help: "switch the color theme",
+        parse: |arg| {
+            ThemeName::from_str(arg, true)
+                .map(Command::Theme)
+                .map_err(|_| format!("unknown theme '{}'", arg))
+        },
+        complete: theme_names,
+    },
+    CommandSpec {
+        name: "save",
+        aliases: &["w"],
+        args: "<path>",
+        help: "export the document to a local file (Ctrl+S)",
+        parse: |arg| required(arg, "path").map(Command::Save),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "s",
+        aliases: &[],
+        args: "/<pattern>/<replacement>/[g][r]",
+        help: "replace in the document; g for every match of a line, r for a regex",
+        parse: parse_substitute,
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "quit",
+        aliases: &["q"],
+        args: "",
+        help: "quit (Ctrl+Q)",
+        parse: |arg| no_arg(arg, Command::Quit),
+        complete: Vec::new,
+    },
+    CommandSpec {
+        name: "help",
+        aliases: &[],
+        args: "",
+        help: "list these commands",
+        parse: |arg| no_arg(arg, Command::Help),
+        complete: Vec::new,
+    },
+];
+
+fn no_arg(arg: &str, command: Command) -> Result<Command, String> {
+    if arg.is_empty() {
+        Ok(command)
+    } else {
+        Err(format!("unexpected argument '{}'", arg))
+    }
+}
+
+fn required(arg: &str, what: &str) -> Result<String, String> {
+    if arg.is_empty() {
+        Err(format!("no {} given", what))
+    } else {
+        Ok(arg.to_string())
+    }
+}
+
+/// Parses the `/foo/bar/g` of `:s/foo/bar/g`. Any punctuation other than
+/// `/` works as the separator too, and a backslash escapes it.
+fn parse_substitute(arg: &str) -> Result<Command, String> {
+    let mut chars = arg.chars();
+    let Some(separator) = chars.next().filter(char::is_ascii_punctuation) else {
+        return Err("expected /<pattern>/<replacement>/".to_string());
+    };
+    let mut parts = vec![String::new()];
+    while let Some(ch) = chars.next() {
+        match ch {
+            '\\' if chars.clone().next() == Some(separator) => {
+                parts.last_mut().unwrap().push(separator);
+                chars.next();
+            }
+            _ if ch == separator && parts.len() < 3 => parts.push(String::new()),
+            _ => parts.last_mut().unwrap().push(ch),
+        }
+    }
+    let [pattern, replacement, flags] = match <[String; 3]>::try_from(parts) {
+        Ok(parts) => parts,
+        Err(parts) if parts.len() == 2 => {
+            let [pattern, replacement] = <[String; 2]>::try_from(parts).unwrap();
+            [pattern, replacement, String::new()]
+        }
+        Err(_) => return Err("no replacement given".to_string()),
+    };
+    if pattern.is_empty() {
+        return Err("no pattern given".to_string());
+    }
+    if let Some(flag) = flags.trim().chars().find(|flag| !matches!(flag, 'g' | 'r')) {
+        return Err(format!("unknown flag '{}': try g or r", flag));
+    }
+    Ok(Command::Substitute {
+        pattern,
+        replacement,
+        every: flags.contains('g'),
+        regex: flags.contains('r'),
+    })
+}
+
+fn theme_names() -> Vec<String> {
+    ThemeName::value_variants()
+        .iter()
+        .filter_map(ThemeName::to_possible_value)
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+/// Splits `:name arg` into the command name and its trimmed argument.
+/// `:s/a/b/` has its argument right after the name, untrimmed.
+fn split(input: &str) -> (&str, &str) {
+    let input = input.trim_start();
+    let input = input.strip_prefix(':').unwrap_or(input);
+    if let Some(arg) = input.strip_prefix('s')
+        && arg.starts_with(|ch: char| ch.is_ascii_punctuation())
+    {
+        return ("s", arg);
+    }
+    match input.split_once(char::is_whitespace) {
+        Some((name, arg)) => (name, arg.trim()),
+        None => (input, ""),
+    }
+}
+
+fn find(name: &str) -> Option<&'static CommandSpec> {
+    COMMANDS
+        .iter()
+        .find(|spec| spec.name == name || spec.aliases.contains(&name))
+}
+
+pub(super) fn parse(input: &str) -> Result<Command, String> {
+    let (name, arg) = split(input);
+    if name.is_empty() {
+        return Err("no command given".to_string());
+    }
+    let spec = find(name).ok_or_else(|| format!("unknown command '{}'", name))?;
+    (spec.parse)(arg).map_err(|err| format!(":{}: {}", spec.name, err))
+}
+
+/// Tab completion of the command name, or of the argument once the name is
+/// complete. Returns the new input and, if that is still ambiguous, the
+/// candidates.
+pub(super) fn complete(input: &str) -> (String, Vec<String>) {
+    let (name, arg) = split(input);
+    let naming =
+        (!input.trim_start().contains(char::is_whitespace) || name.is_empty()) && arg.is_empty();
+    let (prefix, candidates): (String, Vec<String>) = if naming {
+        let names = COMMANDS.iter().map(|spec| spec.name.to_string());
+        let candidates = names.filter(|candidate| candidate.starts_with(name));
+        (String::new(), candidates.collect())
+    } else {
+        let Some(spec) = find(name) else {
+            return (input.to_string(), Vec::new());
+        };
+        let values = (spec.complete)().into_iter();
+        let candidates = values.filter(|value| value.starts_with(arg)).collect();
+        (format!("{} ", name), candidates)
+    };
+    match candidates.as_slice() {
+        [] => (input.to_string(), Vec::new()),
+        [only] if naming && !find(only).is_some_and(|spec| spec.args.is_empty()) => {
+            (format!("{} ", only), Vec::new())
+        }
+        [only] => (format!("{}{}", prefix, only), Vec::new()),
+        _ => {
+            let common = common_prefix(&candidates);
+            (format!("{}{}", prefix, common), candidates)
+        }
+    }
+}
+
+fn common_prefix(words: &[String]) -> &str {
+    let first = words[0].as_str();
+    let len = words[1..].iter().fold(first.len(), |len, word| {
+        first[..len]
+            .char_indices()
+            .zip(word.chars())
+            .find(|((_, a), b)| a != b)
+            .map_or(len.min(word.len()), |((idx, _), _)| idx)
+    });
+    &first[..len]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_parse_with_aliases_and_arguments() {
+        assert_eq!(parse(":sync"), Ok(Command::Sync));
+        assert_eq!(parse(":snapshot"), Ok(Command::Snapshot));
+        assert_eq!(parse(":stats"), Ok(Command::Stats));
+        assert_eq!(parse(":activity"), Ok(Command::Activity));
+        assert_eq!(parse(":history"), Ok(Command::History));
+        assert_eq!(parse("  goto 42:3 "), Ok(Command::Goto(42, Some(3))));
+        assert_eq!(parse(":g 7"), Ok(Command::Goto(7, None)));
+        assert_eq!(parse(":theme Light"), Ok(Command::Theme(ThemeName::Light)));
+        assert_eq!(
+            parse(":w /tmp/out.txt"),
+            Ok(Command::Save("/tmp/out.txt".into()))
+        );
+        assert_eq!(
+            parse(":open other.txt"),
+            Ok(Command::Open("other.txt".into()))
+        );
+        assert_eq!(parse(":docs"), Ok(Command::Docs));
+        assert_eq!(parse("q"), Ok(Command::Quit));
+        assert_eq!(
+            parse(":s/teh /the /g"),
+            Ok(Command::Substitute {
+                pattern: "teh ".into(),
+                replacement: "the ".into(),
+                every: true,
+                regex: false,
+            })
+        );
+        assert_eq!(
+            parse(r":s#a\#b#c"),
+            Ok(Command::Substitute {
+                pattern: "a#b".into(),
+                replacement: "c".into(),
+                every: false,
+                regex: false,
+            })
+        );
+        assert!(matches!(
+            parse(r":s/\d+/n/gr"),
+            Ok(Command::Substitute {
+                regex: true,
+                every: true,
+                ..
+            })
+        ));
+
+        assert_eq!(
+            parse(":frobnicate"),
+            Err("unknown command 'frobnicate'".into())
+        );
+        assert_eq!(parse(":"), Err("no command given".into()));
+        assert_eq!(
+            parse(":quit now"),
+            Err(":quit: unexpected argument 'now'".into())
+        );
+        assert_eq!(
+            parse(":theme neon"),
+            Err(":theme: unknown theme 'neon'".into())
+        );
+        assert_eq!(parse(":save"), Err(":save: no path given".into()));
+        assert!(parse(":goto 0").is_err());
+        assert_eq!(parse(":s/foo"), Err(":s: no replacement given".into()));
+        assert_eq!(
+            parse(":s/a/b/x"),
+            Err(":s: unknown flag 'x': try g or r".into())
+        );
+    }
+
+    #[test]
+    fn completion_extends_names_and_arguments() {
+        let done = |text: &str| (text.to_string(), Vec::<String>::new());
+        assert_eq!(complete("th"), done("theme "));
+        assert_eq!(complete(":sy"), done("sync"));
+        assert_eq!(complete("sn"), done("snapshot"));
+        assert_eq!(complete("theme hi"), done("theme high-contrast"));
+        assert_eq!(complete("zzz"), done("zzz"));
+        assert_eq!(complete("save /tm"), done("save /tm"));
+        assert_eq!(complete(":s/fo"), done(":s/fo"));
+
+        let (text, candidates) = complete("theme ");
+        assert_eq!(text, "theme ");
+        assert_eq!(candidates, ["dark", "light", "high-contrast"]);
+        let (text, candidates) = complete("");
+        assert_eq!(text, "");
+        assert_eq!(candidates.len(), COMMANDS.len());
+        // Names sharing a prefix complete up to where they differ.
+        assert_eq!(common_prefix(&["save".into(), "sync".into()]), "s");
+        assert_eq!(common_prefix(&["users".into(), "use".into()]), "use");
+    }
+}
@@ -0,0 +1,222 @@
+use super::UiEvent;
+use crate::client::NetConditions;
+use crate::client::netsim::{self, Reader};
+use crate::lines::{WriteStats, write_lines};
+use crate::protocol::encode_sync_request;
+use crate::record::Recorder;
+use mdcs_sdk::Message;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader, Lines};
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+/// Input kept at most while disconnected; anything beyond is discarded.
+const MAX_QUEUED_INPUT: usize = 1024;
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Messages and bytes written to any server connection so far.
+static WRITTEN: WriteStats = WriteStats::new();
+
+pub(super) fn messages_sent() -> u64 {
+    WRITTEN.messages()
+}
+
+pub(super) fn bytes_sent() -> u64 {
+    WRITTEN.bytes()
+}
+
+/// What happens to keystrokes typed while the connection is down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum OutageInput {
+    /// Queue them and replay them once the document is synced again.
+    Queue,
+    /// Drop them, only counting how many were lost.
+    Discard,
+}
+
+/// Everything needed to (re-)join the document on a fresh connection.
+#[derive(Clone)]
+pub(super) struct JoinInfo {
+    pub(super) addr: String,
+    pub(super) user_id: String,
+    pub(super) user_name: String,
+    pub(super) doc_id: String,
+    pub(super) network: NetConditions,
+    pub(super) record: Option<Arc<Recorder>>,
+}
+
+pub(super) struct Connection {
+    lines: Lines<BufReader<Reader>>,
+    writer_task: JoinHandle<()>,
+}
+
+impl Connection {
+    /// Connects, announces the user and requests a full snapshot. Returns
+    /// the connection and the sender feeding its writer task.
+    pub(super) async fn open(join: &JoinInfo) -> io::Result<(Self, mpsc::Sender<Message>)> {
+        let (reader, writer) = netsim::connect(&join.addr, &join.network).await?;
+        let (reader, writer) = match &join.record {
+            Some(recorder) => recorder.tap(reader, writer),
+            None => (reader, writer),
+        };
+        let (out_tx, mut out_rx) = mpsc::channel::<Message>(64);
+
+        let writer_task = tokio::spawn(async move {
+            let mut writer = writer;
+            let _ = write_lines(&mut out_rx, &mut writer, &WRITTEN).await;
+        });
+
+        let hello = Message::Hello {
+            replica_id: join.user_id.clone(),
+            user_name: join.user_name.clone(),
+        };
+        let sent = out_tx.send(hello).await.is_ok()
+            && out_tx
+                .send(encode_sync_request(&join.doc_id, 0))
+                .await
+                .is_ok();
+        if !sent {
+            writer_task.abort();
+            return Err(io::Error::new(
+                io::ErrorKind::BrokenPipe,
+                "connection closed during join",
+            ));
+        }
+
+        let connection = Self {
+            lines: BufReader::new(reader).lines(),
+            writer_task,
+        };
+        Ok((connection, out_tx))
+    }
+
+    /// Polls for the next line from the server.
+    pub(super) fn poll_next_line(
+        &mut self,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<Option<String>>> {
+        Pin::new(&mut self.lines).poll_next_line(cx)
+    }
+
+    /// Completes once the writer task has stopped: everything queued is
+    /// written and the sender side is gone, or the socket failed.
+    pub(super) async fn drained(&mut self) {
+        let _ = (&mut self.writer_task).await;
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        self.writer_task.abort();
+    }
+}
+
+pub(super) type Reconnect =
+    Pin<Box<dyn Future<Output = io::Result<(Connection, mpsc::Sender<Message>)>> + Send>>;
+
+pub(super) fn reconnect_after(delay: Duration, join: JoinInfo) -> Reconnect {
+    Box::pin(async move {
+        tokio::time::sleep(delay).await;
+        Connection::open(&join).await
+    })
+}
+
+/// Exponential reconnect delay: 1s, 2s, 4s, ... capped at 30s.
+#[derive(Default)]
+pub(super) struct Backoff {
+    attempts: u32,
+}
+
+impl Backoff {
+    pub(super) fn next_delay(&mut self) -> Duration {
+        let delay = Duration::from_secs(1 << self.attempts.min(5)).min(MAX_BACKOFF);
+        self.attempts += 1;
+        delay
+    }
+
+    pub(super) fn reset(&mut self) {
+        self.attempts = 0;
+    }
+}
+
+/// Keystrokes and pastes received while disconnected.
+pub(super) struct OutageBuffer {
+    mode: OutageInput,
+    queued: VecDeque<UiEvent>,
+    discarded: usize,
+}
+
+impl OutageBuffer {
+    pub(super) fn new(mode: OutageInput) -> Self {
+        Self {
+            mode,
+            queued: VecDeque::new(),
+            discarded: 0,
+        }
+    }
+
+    pub(super) fn push(&mut self, event: UiEvent) {
+        if self.mode == OutageInput::Queue && self.queued.len() < MAX_QUEUED_INPUT {
+            self.queued.push_back(event);
+        } else {
+            self.discarded += 1;
+        }
+    }
+
+    /// Short note for the status row, e.g. `3 keys queued`.
+    pub(super) fn summary(&self) -> String {
+        match (self.queued.len(), self.discarded) {
+            (0, 0) => String::new(),
+            (queued, 0) => format!("{} keys queued", queued),
+            (0, discarded) => format!("{} keys discarded", discarded),
+            (queued, discarded) => format!("{} keys queued, {} discarded", queued, discarded),
+        }
+    }
+
+    /// Hands out the queued input for replay and resets the counters.
+    pub(super) fn take(&mut self) -> (Vec<UiEvent>, usize) {
+        let discarded = std::mem::take(&mut self.discarded);
+        (self.queued.drain(..).collect(), discarded)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crossterm::event::{KeyCode, KeyEvent};
+
+    fn key(ch: char) -> UiEvent {
+        UiEvent::Key(KeyEvent::from(KeyCode::Char(ch)))
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_cap() {
+        let mut backoff = Backoff::default();
+        let delays: Vec<u64> = (0..8).map(|_| backoff.next_delay().as_secs()).collect();
+        assert_eq!(delays, vec![1, 2, 4, 8, 16, 30, 30, 30]);
+        backoff.reset();
+        assert_eq!(backoff.next_delay(), Duration::from_secs(1));
+    }
+
+    #[test]
+    fn outage_input_is_queued_or_counted() {
+        let mut queue = OutageBuffer::new(OutageInput::Queue);
+        queue.push(key('a'));
+        queue.push(key('b'));
+        assert_eq!(queue.summary(), "2 keys queued");
+        let (events, discarded) = queue.take();
+        assert_eq!((events.len(), discarded), (2, 0));
+        assert_eq!(queue.summary(), "");
+
+        let mut discard = OutageBuffer::new(OutageInput::Discard);
+        discard.push(key('a'));
+        assert_eq!(discard.summary(), "1 keys discarded");
+        assert_eq!(discard.take().1, 1);
+    }
+}
@@ -0,0 +1,290 @@
+//! Looking back through a document's saved revisions (Shift+F9): the
+//! buffer shows one revision read-only, Left/Right step through them and
+//! Enter puts the one shown back as an edit. Remote edits arriving
+//! meanwhile are held and applied on the way back to the live text.
+
+use crate::protocol::WireRevision;
+use crate::server::templates::utc_date;
+use mdcs_sdk::Message;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+/// Revision texts kept for stepping back and forth.
+const CACHE_LEN: usize = 4;
+/// History closes by itself after this long without a key.
+const IDLE_LIMIT: Duration = Duration::from_secs(5 * 60);
+/// Up to this many revisions the timeline has a dot each; past it only
+/// the count is shown.
+const TIMELINE_DOTS: usize = 24;
+/// Lines PageUp and PageDown move.
+const PAGE_LINES: usize = 20;
+
+/// The server's answer to a history request of a doc.
+pub(super) enum Reply {
+    Revisions(Result<Vec<WireRevision>, String>),
+    Text(u64, Result<String, String>),
+    /// The version restored, and the document's version after.
+    Restored(u64, Result<u64, String>),
+}
+
+/// An open history view of a buffer.
+pub(super) struct History {
+    /// Oldest first; never empty.
+    revisions: Vec<WireRevision>,
+    /// Index of the revision shown.
+    idx: usize,
+    /// Fetched texts, the most recently shown last.
+    cache: VecDeque<(u64, String)>,
+    /// The version last asked for, so it is asked for once.
+    fetching: Option<u64>,
+    /// Enter was pressed: `y` restores the revision shown.
+    pub(super) confirming: bool,
+    pub(super) cursor_byte: usize,
+    pub(super) scroll: usize,
+    pub(super) hscroll: usize,
+    last_input: Instant,
+    /// Remote edits to the live text, applied on leaving.
+    pub(super) held: Vec<Message>,
+}
+
+impl History {
+    /// Opens at the newest of `revisions`; `None` if there are none.
+    pub(super) fn new(mut revisions: Vec<WireRevision>, now: Instant) -> Option<Self> {
+        if revisions.is_empty() {
+            return None;
+        }
+        revisions.sort_by_key(|revision| revision.version);
+        Some(Self {
+            idx: revisions.len() - 1,
+            revisions,
+            cache: VecDeque::new(),
+            fetching: None,
+            confirming: false,
+            cursor_byte: 0,
+            scroll: 0,
+            hscroll: 0,
+            last_input: now,
+            held: Vec::new(),
+        })
+    }
+
+    /// The revision shown.
+    pub(super) fn shown(&self) -> &WireRevision {
+        &self.revisions[self.idx]
+    }
+
+    /// The text of the revision shown, once fetched.
+    pub(super) fn text(&self) -> Option<&str> {
+        let version = self.shown().version;
+        self.cache
+            .iter()
+            .find(|(cached, _)| *cached == version)
+            .map(|(_, text)| text.as_str())
+    }
+
+    /// Shows the next newer or older revision. Returns whether there was one.
+    pub(super) fn step(&mut self, newer: bool) -> bool {
+        let idx = if newer {
+            self.idx + 1
+        } else {
+            match self.idx.checked_sub(1) {
+                Some(idx) => idx,
+                None => return false,
+            }
+        };
+        if idx >= self.revisions.len() {
+            return false;
+        }
+        self.idx = idx;
+        self.confirming = false;
+        self.cursor_byte = 0;
+        self.scroll = 0;
+        self.hscroll = 0;
+        let version = self.shown().version;
+        if let Some(at) = self.cache.iter().position(|(cached, _)| *cached == version)
+            && let Some(entry) = self.cache.remove(at)
+        {
+            self.cache.push_back(entry);
+        }
+        true
+    }
+
+    /// The version whose text should be fetched now: the one shown, if it
+    /// isn't cached or asked for already.
+    pub(super) fn wanted(&mut self) -> Option<u64> {
+        let version = self.shown().version;
+        if self.text().is_some() || self.fetching == Some(version) {
+            return None;
+        }
+        self.fetching = Some(version);
+        Some(version)
+    }
+
+    /// Caches the fetched text of `version`, dropping the one shown longest
+    /// ago past `CACHE_LEN`.
+    pub(super) fn loaded(&mut self, version: u64, text: String) {
+        self.cache.retain(|(cached, _)| *cached != version);
+        if version == self.shown().version {
+            self.cache.push_back((version, text));
+        } else {
+            self.cache.push_front((version, text));
+        }
+        while self.cache.len() > CACHE_LEN {
+            self.cache.pop_front();
+        }
+    }
+
+    /// Moves the cursor `delta` lines through the revision shown.
+    pub(super) fn move_lines(&mut self, delta: isize) {
+        let Some(text) = self.text() else {
+            return;
+        };
+        let starts: Vec<usize> = std::iter::once(0)
+            .chain(text.match_indices('\n').map(|(at, _)| at + 1))
+            .collect();
+        let line = starts
+            .iter()
+            .rposition(|start| *start <= self.cursor_byte)
+            .unwrap_or(0);
+        let line = line.saturating_add_signed(delta).min(starts.len() - 1);
+        self.cursor_byte = starts[line];
+    }
+
+    /// Moves the cursor a page of lines.
+    pub(super) fn page(&mut self, down: bool) {
+        let lines = PAGE_LINES as isize;
+        self.move_lines(if down { lines } else { -lines });
+    }
+
+    pub(super) fn touch(&mut self, now: Instant) {
+        self.last_input = now;
+    }
+
+    /// Whether nobody pressed a key for `IDLE_LIMIT`.
+    pub(super) fn idle(&self, now: Instant) -> bool {
+        now.duration_since(self.last_input) >= IDLE_LIMIT
+    }
+
+    /// The status row while history is open.
+    pub(super) fn banner(&self) -> String {
+        let shown = self.shown();
+        if self.confirming {
+            return format!(
+                "HISTORY — restore v{} over the live text? y/N",
+                shown.version
+            );
+        }
+        let saved = UNIX_EPOCH + Duration::from_millis(shown.saved_at_ms);
+        let secs = (shown.saved_at_ms / 1000) % 86_400;
+        let mut banner = format!(
+            "HISTORY — v{}, {} {:02}:{:02}",
+            shown.version,
+            utc_date(saved),
+            secs / 3600,
+            secs / 60 % 60
+        );
+        if let Some(by) = &shown.by {
+            banner.push_str(&format!(", by {}", by));
+        }
+        if self.text().is_none() {
+            banner.push_str(" (loading…)");
+        }
+        banner.push_str(&format!(" | {} | ", self.timeline()));
+        if !self.held.is_empty() {
+            banner.push_str(&format!("{} live edits held | ", self.held.len()));
+        }
+        banner.push_str("←/→ step, Enter restore, Esc live");
+        banner
+    }
+
+    /// `··●· 3/4`: where the revision shown is among them.
+    fn timeline(&self) -> String {
+        let count = self.revisions.len();
+        let position = format!("{}/{}", self.idx + 1, count);
+        if count > TIMELINE_DOTS {
+            return position;
+        }
+        let dots: String = (0..count)
+            .map(|idx| if idx == self.idx { '●' } else { '·' })
+            .collect();
+        format!("{} {}", dots, position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn revisions(versions: &[u64]) -> Vec<WireRevision> {
+        versions
+            .iter()
+            .map(|version| WireRevision {
+                version: *version,
+                // 2024-05-02 14:03 UTC.
+                saved_at_ms: 1_714_658_580_000,
+                by: (*version == 7).then(|| "bob".to_string()),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn stepping_fetches_each_revision_once_and_caches_a_few() {
+        let now = Instant::now();
+        assert!(History::new(Vec::new(), now).is_none());
+        let mut history = History::new(revisions(&[9, 3, 7, 1, 5, 2]), now).unwrap();
+        assert_eq!(history.shown().version, 9, "history opens at the newest");
+        assert_eq!(history.wanted(), Some(9));
+        assert_eq!(history.wanted(), None, "asked for already");
+        history.loaded(9, "nine".into());
+        assert_eq!(history.text(), Some("nine"));
+        assert!(!history.step(true), "nothing is newer");
+
+        for (version, text) in [(7, "seven"), (5, "five"), (3, "three"), (2, "two")] {
+            assert!(history.step(false));
+            assert_eq!(history.text(), None);
+            assert_eq!(history.wanted(), Some(version));
+            history.loaded(version, text.into());
+        }
+        assert_eq!(history.cache.len(), CACHE_LEN);
+        // v9 was shown longest ago, so it went; v7 is still there.
+        assert!(history.step(true));
+        assert_eq!(history.text(), Some("three"));
+        for _ in 0..2 {
+            history.step(true);
+        }
+        assert_eq!(history.text(), Some("seven"));
+        history.step(true);
+        assert_eq!(history.text(), None);
+        assert_eq!(history.wanted(), Some(9));
+    }
+
+    #[test]
+    fn the_banner_shows_the_revision_and_where_it_is() {
+        let mut history = History::new(revisions(&[5, 7, 9]), Instant::now()).unwrap();
+        history.step(false);
+        assert_eq!(
+            history.banner(),
+            "HISTORY — v7, 2024-05-02 14:03, by bob (loading…) | ·●· 2/3 | \
+             ←/→ step, Enter restore, Esc live"
+        );
+        history.loaded(7, "seven\nlines\n".into());
+        history.confirming = true;
+        assert_eq!(
+            history.banner(),
+            "HISTORY — restore v7 over the live text? y/N"
+        );
+
+        history.move_lines(1);
+        assert_eq!(history.cursor_byte, 6);
+        history.page(true);
+        assert_eq!(history.cursor_byte, 12, "the empty last line");
+        history.page(false);
+        assert_eq!(history.cursor_byte, 0);
+
+        let long = (1..=30).collect::<Vec<_>>();
+        let history = History::new(revisions(&long), Instant::now()).unwrap();
+        assert!(history.banner().contains(" (loading…) | 30/30 | "));
+        assert!(!history.idle(Instant::now()));
+        assert!(history.idle(Instant::now() + IDLE_LIMIT));
+    }
+}
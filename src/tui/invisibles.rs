@@ -0,0 +1,107 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+/// Why a cell shows a substitute glyph.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Mark {
+    /// Tabs and trailing spaces, drawn faint.
+    Whitespace,
+    /// `\r`, NUL and other control characters, drawn in a warning color.
+    Control,
+}
+
+/// The visible stand-in for `grapheme`, if it is a tab, a carriage return,
+/// a control character or (with `trailing`) a trailing space.
+fn substitute(grapheme: &str, trailing: bool) -> Option<(&'static str, Mark)> {
+    match grapheme {
+        "\t" => Some(("→", Mark::Whitespace)),
+        "\r" => Some(("␍", Mark::Control)),
+        " " if trailing => Some(("·", Mark::Whitespace)),
+        _ if grapheme.starts_with(char::is_control) => Some(("�", Mark::Control)),
+        _ => None,
+    }
+}
+
+/// What to draw for a grapheme of the document. Control characters are
+/// never sent to the terminal as-is: they become a blank, or a visible
+/// glyph with `show`. Every glyph is a single cell, like the control
+/// character itself, so display columns keep mapping to the same bytes.
+pub(super) fn glyph(grapheme: &str, trailing: bool, show: bool) -> (&str, Option<Mark>) {
+    match substitute(grapheme, trailing) {
+        Some((glyph, mark)) if show => (glyph, Some(mark)),
+        Some(_) if grapheme != " " => (" ", None),
+        _ => (grapheme, None),
+    }
+}
+
+/// Byte offset where the trailing spaces and tabs of `line` start, before
+/// a final `\r` of CRLF line endings.
+pub(super) fn trailing_whitespace_start(line: &str) -> usize {
+    let line = line.strip_suffix('\r').unwrap_or(line);
+    line.trim_end_matches([' ', '\t']).len()
+}
+
+/// Display columns and glyphs of the marked cells of `line`.
+pub(super) fn line_marks(line: &str) -> Vec<(usize, &'static str, Mark)> {
+    let trailing = trailing_whitespace_start(line);
+    let mut marks = Vec::new();
+    let mut col = 0;
+    for (idx, grapheme) in line.grapheme_indices(true) {
+        if let Some((glyph, mark)) = substitute(grapheme, idx >= trailing) {
+            marks.push((col, glyph, mark));
+        }
+        col += super::grapheme_width(grapheme);
+    }
+    marks
+}
+
+/// Status badge warning about characters that are easy to miss: carriage
+/// returns and other control characters besides newlines and tabs.
+pub(super) fn badge(text: &str) -> Option<&'static str> {
+    let has_cr = text.contains('\r');
+    let has_control = text
+        .chars()
+        .any(|ch| ch.is_control() && !matches!(ch, '\n' | '\t' | '\r'));
+    match (has_cr, has_control) {
+        (false, false) => None,
+        (true, false) => Some("⚠ CR"),
+        (false, true) => Some("⚠ CTRL"),
+        (true, true) => Some("⚠ CR+CTRL"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use unicode_width::UnicodeWidthStr;
+
+    #[test]
+    fn control_characters_never_reach_the_terminal() {
+        assert_eq!(glyph("\t", false, false), (" ", None));
+        assert_eq!(glyph("\0", false, false), (" ", None));
+        assert_eq!(glyph(" ", true, false), (" ", None));
+        assert_eq!(glyph("\t", false, true), ("→", Some(Mark::Whitespace)));
+        assert_eq!(glyph("\r", false, true), ("␍", Some(Mark::Control)));
+        assert_eq!(glyph("\u{1b}", false, true), ("�", Some(Mark::Control)));
+        assert_eq!(glyph(" ", false, true), (" ", None));
+        assert_eq!(glyph("é", false, true), ("é", None));
+        for glyph in ["→", "␍", "·", "�"] {
+            assert_eq!(glyph.width(), 1, "{}", glyph);
+        }
+    }
+
+    #[test]
+    fn marks_cover_trailing_whitespace_and_controls() {
+        let marks = line_marks("a\tb  中\0 \t\r");
+        let cols: Vec<(usize, &str)> = marks.iter().map(|(col, glyph, _)| (*col, *glyph)).collect();
+        assert_eq!(
+            cols,
+            vec![(1, "→"), (7, "�"), (8, "·"), (9, "→"), (10, "␍")]
+        );
+        assert_eq!(trailing_whitespace_start("ab \t"), 2);
+        assert_eq!(trailing_whitespace_start("ab \r"), 2);
+
+        assert_eq!(badge("plain\ttext\n"), None);
+        assert_eq!(badge("dos\r\n"), Some("⚠ CR"));
+        assert_eq!(badge("nul\0\r\n"), Some("⚠ CR+CTRL"));
+    }
+}
@@ -0,0 +1,602 @@
+//! Key bindings: which chord runs which editor action, from the built-in
+//! defaults and the config's `[tui.keys]` section.
+//!
+//! A chord is written as modifiers and a key joined by `+`, case
+//! insensitive: `ctrl+q`, `f5`, `alt+shift+down`, `ctrl++`. Modifiers are
+//! `ctrl`, `alt` and `shift`; keys are a single character, `f1` to `f24`,
+//! or one of the names in `KEY_NAMES`.
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// The config's `[tui.keys]` section: action name to one chord or a list
+/// of them. An empty list unbinds the action.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(transparent)]
+pub struct KeyConfig(BTreeMap<String, KeyList>);
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+enum KeyList {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl KeyList {
+    fn descriptors(&self) -> &[String] {
+        match self {
+            KeyList::One(one) => std::slice::from_ref(one),
+            KeyList::Many(many) => many,
+        }
+    }
+}
+
+/// Named keys as written in a descriptor and as shown in the help.
+const KEY_NAMES: &[(&str, &str, KeyCode)] = &[
+    ("enter", "Enter", KeyCode::Enter),
+    ("tab", "Tab", KeyCode::Tab),
+    ("backspace", "Backspace", KeyCode::Backspace),
+    ("delete", "Delete", KeyCode::Delete),
+    ("del", "Delete", KeyCode::Delete),
+    ("insert", "Insert", KeyCode::Insert),
+    ("ins", "Insert", KeyCode::Insert),
+    ("home", "Home", KeyCode::Home),
+    ("end", "End", KeyCode::End),
+    ("pageup", "PageUp", KeyCode::PageUp),
+    ("pgup", "PageUp", KeyCode::PageUp),
+    ("pagedown", "PageDown", KeyCode::PageDown),
+    ("pgdn", "PageDown", KeyCode::PageDown),
+    ("up", "Up", KeyCode::Up),
+    ("down", "Down", KeyCode::Down),
+    ("left", "Left", KeyCode::Left),
+    ("right", "Right", KeyCode::Right),
+    ("space", "Space", KeyCode::Char(' ')),
+    ("esc", "Esc", KeyCode::Esc),
+    ("escape", "Esc", KeyCode::Esc),
+];
+
+/// A key with the modifiers held, normalized so that what the terminal
+/// reports matches what the config says: letters are lowercase with
+/// Shift as a modifier, Shift on other characters is dropped (it is part
+/// of the character), and BackTab is Shift+Tab.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct Chord {
+    code: KeyCode,
+    modifiers: KeyModifiers,
+}
+
+impl Chord {
+    fn new(code: KeyCode, modifiers: KeyModifiers) -> Self {
+        let modifiers =
+            modifiers & (KeyModifiers::CONTROL | KeyModifiers::ALT | KeyModifiers::SHIFT);
+        match code {
+            KeyCode::Char(ch) if ch.is_uppercase() => Self {
+                code: KeyCode::Char(ch.to_lowercase().next().unwrap_or(ch)),
+                modifiers: modifiers | KeyModifiers::SHIFT,
+            },
+            KeyCode::Char(ch) if !ch.is_alphabetic() => Self {
+                code,
+                modifiers: modifiers - KeyModifiers::SHIFT,
+            },
+            KeyCode::BackTab => Self {
+                code: KeyCode::Tab,
+                modifiers: modifiers | KeyModifiers::SHIFT,
+            },
+            _ => Self { code, modifiers },
+        }
+    }
+
+    pub(super) fn of(key: &KeyEvent) -> Self {
+        Self::new(key.code, key.modifiers)
+    }
+
+    /// Whether the chord would type a character if it were bound.
+    fn types_text(&self) -> bool {
+        matches!(self.code, KeyCode::Char(_))
+            && !self
+                .modifiers
+                .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT)
+    }
+}
+
+impl fmt::Display for Chord {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (modifier, name) in [
+            (KeyModifiers::CONTROL, "Ctrl+"),
+            (KeyModifiers::ALT, "Alt+"),
+            (KeyModifiers::SHIFT, "Shift+"),
+        ] {
+            if self.modifiers.contains(modifier) {
+                f.write_str(name)?;
+            }
+        }
+        if let Some((_, name, _)) = KEY_NAMES.iter().find(|(_, _, code)| *code == self.code) {
+            return f.write_str(name);
+        }
+        match self.code {
+            KeyCode::F(n) => write!(f, "F{}", n),
+            KeyCode::Char(ch) => write!(f, "{}", ch.to_uppercase()),
+            code => write!(f, "{:?}", code),
+        }
+    }
+}
+
+/// Parses a descriptor such as `ctrl+shift+k` or `f5`.
+pub(super) fn parse_chord(descriptor: &str) -> Result<Chord, String> {
+    let text = descriptor.trim();
+    if text.is_empty() {
+        return Err("empty key".to_string());
+    }
+    // A trailing `+` after a separator is the key itself: `ctrl++`.
+    let (modifiers, key) = if text == "+" {
+        ("", "+")
+    } else if let Some(modifiers) = text.strip_suffix("++") {
+        (modifiers, "+")
+    } else {
+        match text.rsplit_once('+') {
+            Some((modifiers, key)) => (modifiers, key),
+            None => ("", text),
+        }
+    };
+    let key = key.trim();
+    if key.is_empty() {
+        return Err(format!("no key after the modifiers in {:?}", descriptor));
+    }
+    let mut held = KeyModifiers::NONE;
+    if !modifiers.is_empty() {
+        for name in modifiers.split('+') {
+            let modifier = match name.trim().to_lowercase().as_str() {
+                "ctrl" | "control" => KeyModifiers::CONTROL,
+                "alt" | "meta" | "option" => KeyModifiers::ALT,
+                "shift" => KeyModifiers::SHIFT,
+                "" => return Err(format!("empty modifier in {:?}", descriptor)),
+                other => return Err(format!("unknown modifier {:?}", other)),
+            };
+            if held.contains(modifier) {
+                return Err(format!("{:?} repeats a modifier", descriptor));
+            }
+            held |= modifier;
+        }
+    }
+    Ok(Chord::new(parse_key(key)?, held))
+}
+
+fn parse_key(key: &str) -> Result<KeyCode, String> {
+    let mut chars = key.chars();
+    if let (Some(ch), None) = (chars.next(), chars.next()) {
+        return Ok(KeyCode::Char(ch.to_lowercase().next().unwrap_or(ch)));
+    }
+    let lower = key.to_lowercase();
+    if let Some((_, _, code)) = KEY_NAMES.iter().find(|(name, _, _)| *name == lower) {
+        return Ok(*code);
+    }
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok())
+        && (1..=24).contains(&n)
+    {
+        return Ok(KeyCode::F(n));
+    }
+    Err(format!("unknown key {:?}", key))
+}
+
+/// What a bound key does.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Action {
+    Quit,
+    Sync,
+    Save,
+    Import,
+    Search,
+    FindNext,
+    FindPrev,
+    Goto,
+    Open,
+    Palette,
+    Sidebar,
+    Activity,
+    History,
+    Follow,
+    Invisibles,
+    Normalize,
+    Spelling,
+    Overwrite,
+    Undo,
+    Redo,
+    Copy,
+    Cut,
+    Paste,
+    NextBuffer,
+    PrevBuffer,
+    CloseBuffer,
+    Log,
+    Debug,
+    Suspend,
+}
+
+/// Every action, in the order the help lists them.
+const ACTIONS: &[Action] = &[
+    Action::Quit,
+    Action::Sync,
+    Action::Save,
+    Action::Import,
+    Action::Search,
+    Action::FindNext,
+    Action::FindPrev,
+    Action::Goto,
+    Action::Open,
+    Action::Palette,
+    Action::Sidebar,
+    Action::Activity,
+    Action::History,
+    Action::Follow,
+    Action::Invisibles,
+    Action::Normalize,
+    Action::Spelling,
+    Action::Overwrite,
+    Action::Undo,
+    Action::Redo,
+    Action::Copy,
+    Action::Cut,
+    Action::Paste,
+    Action::NextBuffer,
+    Action::PrevBuffer,
+    Action::CloseBuffer,
+    Action::Log,
+    Action::Debug,
+    Action::Suspend,
+];
+
+impl Action {
+    /// Whether the action changes the text, which a read-only document
+    /// doesn't allow.
+    pub(super) fn edits(self) -> bool {
+        matches!(
+            self,
+            Action::Import
+                | Action::Normalize
+                | Action::Undo
+                | Action::Redo
+                | Action::Cut
+                | Action::Paste
+        )
+    }
+
+    /// The name used in `[tui.keys]`.
+    pub(super) fn name(self) -> &'static str {
+        match self {
+            Action::Quit => "quit",
+            Action::Sync => "sync",
+            Action::Save => "save",
+            Action::Import => "import",
+            Action::Search => "search",
+            Action::FindNext => "find_next",
+            Action::FindPrev => "find_prev",
+            Action::Goto => "goto",
+            Action::Open => "open",
+            Action::Palette => "palette",
+            Action::Sidebar => "sidebar",
+            Action::Activity => "activity",
+            Action::History => "history",
+            Action::Follow => "follow",
+            Action::Invisibles => "invisibles",
+            Action::Normalize => "normalize",
+            Action::Spelling => "spelling",
+            Action::Overwrite => "overwrite",
+            Action::Undo => "undo",
+            Action::Redo => "redo",
+            Action::Copy => "copy",
+            Action::Cut => "cut",
+            Action::Paste => "paste",
+            Action::NextBuffer => "next_buffer",
+            Action::PrevBuffer => "prev_buffer",
+            Action::CloseBuffer => "close_buffer",
+            Action::Log => "log",
+            Action::Debug => "debug",
+            Action::Suspend => "suspend",
+        }
+    }
+
+    /// Ctrl+Z suspends on Unix and does nothing elsewhere.
+    fn defaults(self) -> &'static [&'static str] {
+        match self {
+            Action::Quit => &["ctrl+q"],
+            Action::Sync => &["ctrl+r"],
+            Action::Save => &["ctrl+s"],
+            Action::Import => &["ctrl+o"],
+            Action::Search => &["ctrl+f"],
+            Action::FindNext => &["f3"],
+            Action::FindPrev => &["shift+f3"],
+            Action::Goto => &["ctrl+g"],
+            Action::Open => &["ctrl+n"],
+            Action::Palette => &["ctrl+p"],
+            Action::Sidebar => &["f2"],
+            Action::Activity => &["f9"],
+            Action::History => &["shift+f9"],
+            Action::Follow => &["f5"],
+            Action::Invisibles => &["f6"],
+            Action::Normalize => &["f7"],
+            Action::Spelling => &["f8"],
+            Action::Overwrite => &["insert"],
+            Action::Undo => &["alt+z"],
+            Action::Redo => &["ctrl+y"],
+            Action::Copy => &["ctrl+c"],
+            Action::Cut => &["ctrl+x"],
+            Action::Paste => &["ctrl+v"],
+            Action::NextBuffer => &["ctrl+tab", "ctrl+pagedown"],
+            Action::PrevBuffer => &["ctrl+shift+tab", "ctrl+pageup"],
+            Action::CloseBuffer => &["ctrl+w"],
+            Action::Log => &["f10"],
+            Action::Debug => &["f12"],
+            Action::Suspend if cfg!(unix) => &["ctrl+z"],
+            Action::Suspend => &[],
+        }
+    }
+}
+
+/// The effective chords of every action.
+#[derive(Debug, Clone)]
+pub struct Bindings {
+    /// In `ACTIONS` order, each with its chords.
+    table: Vec<(Action, Vec<Chord>)>,
+}
+
+impl Default for Bindings {
+    fn default() -> Self {
+        Self::resolve(&KeyConfig::default()).expect("the default bindings are valid")
+    }
+}
+
+impl Bindings {
+    /// The defaults with the config's entries replacing those of the
+    /// actions they name. Every unknown action, bad descriptor and chord
+    /// bound twice is listed in the error.
+    pub fn resolve(config: &KeyConfig) -> Result<Self, String> {
+        let mut errors = Vec::new();
+        for name in config.0.keys() {
+            if !ACTIONS.iter().any(|action| action.name() == name) {
+                errors.push(format!("tui.keys.{}: unknown action", name));
+            }
+        }
+        let mut table = Vec::new();
+        for &action in ACTIONS {
+            let configured = config.0.get(action.name());
+            let descriptors: Vec<&str> = match configured {
+                Some(list) => list.descriptors().iter().map(String::as_str).collect(),
+                None => action.defaults().to_vec(),
+            };
+            let mut chords = Vec::new();
+            for descriptor in descriptors {
+                match parse_chord(descriptor) {
+                    Ok(chord) if configured.is_some() && chord.code == KeyCode::Esc => {
+                        errors.push(format!(
+                            "tui.keys.{}: Esc is reserved for closing and quitting",
+                            action.name()
+                        ));
+                    }
+                    Ok(chord) if configured.is_some() && chord.types_text() => {
+                        errors.push(format!(
+                            "tui.keys.{}: {} would stop it from being typed; add ctrl or alt",
+                            action.name(),
+                            chord
+                        ));
+                    }
+                    Ok(chord) if !chords.contains(&chord) => chords.push(chord),
+                    Ok(_) => {}
+                    Err(err) => errors.push(format!("tui.keys.{}: {}", action.name(), err)),
+                }
+            }
+            table.push((action, chords));
+        }
+        for (idx, (action, chords)) in table.iter().enumerate() {
+            for chord in chords {
+                if let Some((other, _)) = table[..idx]
+                    .iter()
+                    .find(|(_, others)| others.contains(chord))
+                {
+                    errors.push(format!(
+                        "tui.keys: {} is bound to both {} and {}",
+                        chord,
+                        other.name(),
+                        action.name()
+                    ));
+                }
+            }
+        }
+        if errors.is_empty() {
+            Ok(Self { table })
+        } else {
+            Err(errors.join("\n"))
+        }
+    }
+
+    /// The action bound to `key`, if any.
+    pub(super) fn action(&self, key: &KeyEvent) -> Option<Action> {
+        let chord = Chord::of(key);
+        self.table
+            .iter()
+            .find(|(_, chords)| chords.contains(&chord))
+            .map(|(action, _)| *action)
+    }
+
+    /// The first chord of `action` for hints like "Ctrl+Q quit", or
+    /// "unbound".
+    pub(super) fn hint(&self, action: Action) -> String {
+        self.chords(action)
+            .first()
+            .map_or_else(|| "unbound".to_string(), Chord::to_string)
+    }
+
+    fn chords(&self, action: Action) -> &[Chord] {
+        self.table
+            .iter()
+            .find(|(bound, _)| *bound == action)
+            .map_or(&[], |(_, chords)| chords.as_slice())
+    }
+
+    /// `name  Chord / Chord` for each bound action, for the help overlay.
+    pub(super) fn help_entries(&self) -> Vec<String> {
+        self.table
+            .iter()
+            .filter(|(_, chords)| !chords.is_empty())
+            .map(|(action, chords)| {
+                let chords: Vec<String> = chords.iter().map(Chord::to_string).collect();
+                format!("{} {}", action.name(), chords.join("/"))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chord(code: KeyCode, modifiers: KeyModifiers) -> Chord {
+        Chord::new(code, modifiers)
+    }
+
+    fn config(toml: &str) -> KeyConfig {
+        toml::from_str(toml).unwrap()
+    }
+
+    #[test]
+    fn descriptors_parse_modifiers_and_keys_in_any_case() {
+        let ctrl = KeyModifiers::CONTROL;
+        let cases = [
+            ("ctrl+q", chord(KeyCode::Char('q'), ctrl)),
+            ("Ctrl+Q", chord(KeyCode::Char('q'), ctrl)),
+            ("control+q", chord(KeyCode::Char('q'), ctrl)),
+            ("f5", chord(KeyCode::F(5), KeyModifiers::NONE)),
+            ("F24", chord(KeyCode::F(24), KeyModifiers::NONE)),
+            (
+                "alt+shift+down",
+                chord(KeyCode::Down, KeyModifiers::ALT | KeyModifiers::SHIFT),
+            ),
+            (
+                "shift+alt+down",
+                chord(KeyCode::Down, KeyModifiers::ALT | KeyModifiers::SHIFT),
+            ),
+            (" ctrl + pgdn ", chord(KeyCode::PageDown, ctrl)),
+            ("ctrl+space", chord(KeyCode::Char(' '), ctrl)),
+            ("ctrl++", chord(KeyCode::Char('+'), ctrl)),
+            ("+", chord(KeyCode::Char('+'), KeyModifiers::NONE)),
+            ("alt+/", chord(KeyCode::Char('/'), KeyModifiers::ALT)),
+        ];
+        for (descriptor, expected) in cases {
+            assert_eq!(parse_chord(descriptor), Ok(expected), "{}", descriptor);
+        }
+    }
+
+    #[test]
+    fn bad_descriptors_say_what_is_wrong() {
+        let cases = [
+            ("", "empty key"),
+            ("ctrl+", "no key"),
+            ("hyper+q", "unknown modifier"),
+            ("ctrl++q", "empty modifier"),
+            ("ctrl+ctrl+q", "repeats a modifier"),
+            ("ctrl+enterr", "unknown key"),
+            ("f0", "unknown key"),
+            ("f25", "unknown key"),
+        ];
+        for (descriptor, expected) in cases {
+            let err = parse_chord(descriptor).unwrap_err();
+            assert!(err.contains(expected), "{:?}: {}", descriptor, err);
+        }
+    }
+
+    #[test]
+    fn terminal_keys_match_their_descriptors() {
+        let key = |code, modifiers| KeyEvent::new(code, modifiers);
+        let shift = KeyModifiers::SHIFT;
+        let ctrl = KeyModifiers::CONTROL;
+        // Terminals report Shift+letter as the uppercase letter, with or
+        // without the modifier.
+        let shifted_k = parse_chord("ctrl+shift+k").unwrap();
+        assert_eq!(Chord::of(&key(KeyCode::Char('K'), ctrl | shift)), shifted_k);
+        assert_eq!(Chord::of(&key(KeyCode::Char('K'), ctrl)), shifted_k);
+        assert_ne!(Chord::of(&key(KeyCode::Char('k'), ctrl)), shifted_k);
+        // Symbols carry their Shift in the character.
+        assert_eq!(
+            Chord::of(&key(KeyCode::Char('?'), shift)),
+            parse_chord("?").unwrap()
+        );
+        assert_eq!(
+            Chord::of(&key(KeyCode::BackTab, ctrl | shift)),
+            parse_chord("ctrl+shift+tab").unwrap()
+        );
+        assert_eq!(
+            parse_chord("ctrl+shift+tab").unwrap().to_string(),
+            "Ctrl+Shift+Tab"
+        );
+        assert_eq!(parse_chord("alt+pgup").unwrap().to_string(), "Alt+PageUp");
+        assert_eq!(parse_chord("ctrl+q").unwrap().to_string(), "Ctrl+Q");
+    }
+
+    #[test]
+    fn config_entries_replace_the_defaults_of_their_action() {
+        let bindings = Bindings::resolve(&config(
+            "quit = \"ctrl+x\"\ncut = [\"alt+x\", \"ctrl+k\"]\nsidebar = []",
+        ))
+        .unwrap();
+        let key = |code, modifiers| KeyEvent::new(code, modifiers);
+        let ctrl = KeyModifiers::CONTROL;
+        assert_eq!(
+            bindings.action(&key(KeyCode::Char('x'), ctrl)),
+            Some(Action::Quit)
+        );
+        assert_eq!(bindings.action(&key(KeyCode::Char('q'), ctrl)), None);
+        assert_eq!(
+            bindings.action(&key(KeyCode::Char('k'), ctrl)),
+            Some(Action::Cut)
+        );
+        assert_eq!(
+            bindings.action(&key(KeyCode::F(2), KeyModifiers::NONE)),
+            None
+        );
+        assert_eq!(bindings.hint(Action::Quit), "Ctrl+X");
+        assert_eq!(bindings.hint(Action::Sidebar), "unbound");
+        assert!(
+            bindings
+                .help_entries()
+                .contains(&"cut Alt+X/Ctrl+K".to_string())
+        );
+
+        let defaults = Bindings::default();
+        assert_eq!(
+            defaults.action(&key(KeyCode::F(3), KeyModifiers::SHIFT)),
+            Some(Action::FindPrev)
+        );
+        assert_eq!(
+            defaults.action(&key(KeyCode::Char('r'), ctrl | KeyModifiers::ALT)),
+            None
+        );
+        // Ctrl+Z suspends on Unix and is left alone elsewhere.
+        let suspend = cfg!(unix).then_some(Action::Suspend);
+        assert_eq!(defaults.action(&key(KeyCode::Char('z'), ctrl)), suspend);
+        assert_eq!(
+            defaults.action(&key(KeyCode::Char('z'), KeyModifiers::ALT)),
+            Some(Action::Undo)
+        );
+    }
+
+    #[test]
+    fn every_bad_entry_and_conflict_is_reported() {
+        let err = Bindings::resolve(&config(
+            "quti = \"ctrl+q\"\nsync = \"ctrl+q\"\nsave = [\"f5\", \"hyper+s\"]\nsearch = \"/\"\ngoto = \"esc\"",
+        ))
+        .unwrap_err();
+        let lines: Vec<&str> = err.lines().collect();
+        assert_eq!(
+            lines,
+            [
+                "tui.keys.quti: unknown action",
+                "tui.keys.save: unknown modifier \"hyper\"",
+                "tui.keys.search: / would stop it from being typed; add ctrl or alt",
+                "tui.keys.goto: Esc is reserved for closing and quitting",
+                "tui.keys: Ctrl+Q is bound to both quit and sync",
+                "tui.keys: F5 is bound to both save and follow",
+            ]
+        );
+    }
+}
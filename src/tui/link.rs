@@ -0,0 +1,84 @@
+//! Health of a buffer's connection, shown as a colored dot at the left of
+//! the status row.
+
+use crossterm::style::Color;
+use std::time::Duration;
+
+/// Round trips up to this long count as healthy.
+const HEALTHY_RTT: Duration = Duration::from_millis(150);
+/// Without messages for this long the link is probed with Pings.
+const QUIET: Duration = Duration::from_secs(10);
+/// Without messages for this long the link counts as down.
+const SILENT: Duration = Duration::from_secs(30);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum LinkState {
+    Healthy,
+    /// Slow round trips, or quiet for a while.
+    Degraded,
+    /// Reconnecting, or nothing heard for too long.
+    Down,
+}
+
+impl LinkState {
+    /// State of a link that is `connected` (joined and synced), has been
+    /// quiet for `silence` and last measured `rtt`.
+    pub(super) fn assess(connected: bool, silence: Duration, rtt: Option<Duration>) -> Self {
+        if !connected || silence > SILENT {
+            LinkState::Down
+        } else if silence >= QUIET || rtt.is_some_and(|rtt| rtt > HEALTHY_RTT) {
+            LinkState::Degraded
+        } else {
+            LinkState::Healthy
+        }
+    }
+
+    pub(super) fn color(self) -> Color {
+        match self {
+            LinkState::Healthy => Color::Green,
+            LinkState::Degraded => Color::Yellow,
+            LinkState::Down => Color::Red,
+        }
+    }
+
+    pub(super) fn label(self) -> &'static str {
+        match self {
+            LinkState::Healthy => "healthy",
+            LinkState::Degraded => "degraded",
+            LinkState::Down => "down",
+        }
+    }
+}
+
+/// Whether a link quiet for `silence` should be probed with a Ping, whose
+/// Pong then counts as traffic again.
+pub(super) fn needs_probe(silence: Duration) -> bool {
+    silence >= QUIET
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn state_follows_silence_round_trips_and_reconnects() {
+        let secs = Duration::from_secs;
+        let fast = Some(Duration::from_millis(40));
+        let slow = Some(Duration::from_millis(320));
+        let assess = LinkState::assess;
+
+        assert_eq!(assess(true, secs(0), None), LinkState::Healthy);
+        assert_eq!(assess(true, secs(9), fast), LinkState::Healthy);
+        assert_eq!(assess(true, secs(1), slow), LinkState::Degraded);
+        // Going quiet degrades the link and starts probing it...
+        assert!(!needs_probe(secs(9)));
+        assert_eq!(assess(true, secs(10), fast), LinkState::Degraded);
+        assert!(needs_probe(secs(10)));
+        // ...a Pong brings it back, otherwise it is down after 30s.
+        assert_eq!(assess(true, secs(0), fast), LinkState::Healthy);
+        assert_eq!(assess(true, secs(30), fast), LinkState::Degraded);
+        assert_eq!(assess(true, secs(31), fast), LinkState::Down);
+        // Reconnecting is down regardless of the last measurements.
+        assert_eq!(assess(false, secs(0), fast), LinkState::Down);
+    }
+}
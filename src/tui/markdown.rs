@@ -0,0 +1,377 @@
+//! Line-by-line Markdown highlighting. Not a parser: it recognises the
+//! common constructs well enough to color notes, and only ever reports
+//! byte ranges of the line it was given.
+
+use super::widgets::Style;
+use crossterm::style::Color;
+use std::ops::Range;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Token {
+    Heading,
+    /// `code span` or a line inside a fenced block.
+    Code,
+    /// The ``` / ~~~ lines around a fenced block.
+    Fence,
+    ListMarker,
+    QuoteMarker,
+    Emphasis,
+    Strong,
+    Link,
+}
+
+impl Token {
+    /// Adds this token's look to `style`, so nested spans combine.
+    pub(super) fn apply(self, style: Style) -> Style {
+        let fg = |color| Style {
+            fg: Some(color),
+            ..style
+        };
+        match self {
+            Token::Heading => Style {
+                bold: true,
+                ..fg(Color::Cyan)
+            },
+            Token::Code | Token::Fence => fg(Color::DarkYellow),
+            Token::ListMarker => fg(Color::Yellow),
+            Token::QuoteMarker => fg(Color::DarkCyan),
+            Token::Emphasis => Style {
+                italic: true,
+                ..style
+            },
+            Token::Strong => Style {
+                bold: true,
+                ..style
+            },
+            Token::Link => Style {
+                underline: true,
+                ..fg(Color::Blue)
+            },
+        }
+    }
+}
+
+/// A highlighted byte range of a line. Spans may nest; later ones are
+/// applied on top of earlier ones.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) struct Span {
+    pub(super) range: Range<usize>,
+    pub(super) token: Token,
+}
+
+/// What carries over from one line to the next: the open code fence, if
+/// any, as its character and length.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct LineState {
+    fence: Option<(char, usize)>,
+}
+
+impl LineState {
+    /// State at the start of the line after `lines`.
+    pub(super) fn after<'a>(lines: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut state = Self::default();
+        for line in lines {
+            state.fence_line(line);
+        }
+        state
+    }
+
+    /// Handles a fence opening or closing on `line`. Returns the token for
+    /// the whole line if it is part of a fenced block.
+    fn fence_line(&mut self, line: &str) -> Option<Token> {
+        let fence = fence(line);
+        match (self.fence, fence) {
+            (Some((ch, len)), Some((close_ch, close_len)))
+                if ch == close_ch && close_len >= len =>
+            {
+                self.fence = None;
+                Some(Token::Fence)
+            }
+            (Some(_), _) => Some(Token::Code),
+            (None, Some(fence)) => {
+                self.fence = Some(fence);
+                Some(Token::Fence)
+            }
+            (None, None) => None,
+        }
+    }
+}
+
+/// Whether a doc name looks like a Markdown file.
+pub(super) fn is_markdown_doc(doc: &str) -> bool {
+    let doc = doc.to_ascii_lowercase();
+    doc.ends_with(".md") || doc.ends_with(".markdown")
+}
+
+/// Highlighted spans of `line` (without its newline), updating `state` for
+/// the next line.
+pub(super) fn tokenize_line(line: &str, state: &mut LineState) -> Vec<Span> {
+    let whole = |token| {
+        vec![Span {
+            range: 0..line.len(),
+            token,
+        }]
+    };
+    if let Some(token) = state.fence_line(line) {
+        return whole(token);
+    }
+    let indent = line.len() - line.trim_start_matches(' ').len();
+    if indent <= 3 && is_heading(&line[indent..]) {
+        return whole(Token::Heading);
+    }
+
+    let mut spans = Vec::new();
+    let mut pos = indent;
+    // Blockquote markers, possibly nested (`> > text`).
+    while line[pos..].starts_with('>') {
+        spans.push(Span {
+            range: pos..pos + 1,
+            token: Token::QuoteMarker,
+        });
+        pos += 1;
+        pos += line[pos..].len() - line[pos..].trim_start_matches(' ').len();
+    }
+    if let Some(len) = list_marker(&line[pos..]) {
+        spans.push(Span {
+            range: pos..pos + len,
+            token: Token::ListMarker,
+        });
+        pos += len;
+    }
+    inline_spans(line, pos..line.len(), &mut spans);
+    spans
+}
+
+/// An opening or closing fence: at least three backticks or tildes after
+/// at most three spaces.
+fn fence(line: &str) -> Option<(char, usize)> {
+    let trimmed = line.trim_start_matches(' ');
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let ch = trimmed
+        .chars()
+        .next()
+        .filter(|ch| matches!(ch, '`' | '~'))?;
+    let len = trimmed.len() - trimmed.trim_start_matches(ch).len();
+    (len >= 3).then_some((ch, len))
+}
+
+fn is_heading(line: &str) -> bool {
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    (1..=6).contains(&hashes) && (line.len() == hashes || line[hashes..].starts_with(' '))
+}
+
+/// Length of a `-`, `*`, `+` or `1.` / `1)` list marker including the
+/// space after it.
+fn list_marker(text: &str) -> Option<usize> {
+    let digits = text.len()
+        - text
+            .trim_start_matches(|ch: char| ch.is_ascii_digit())
+            .len();
+    let marker = match text.as_bytes().get(digits) {
+        Some(b'-' | b'*' | b'+') if digits == 0 => 1,
+        Some(b'.' | b')') if (1..=9).contains(&digits) => digits + 1,
+        _ => return None,
+    };
+    text[marker..].starts_with(' ').then_some(marker + 1)
+}
+
+/// Code spans, links and emphasis within `range` of `line`.
+fn inline_spans(line: &str, range: Range<usize>, spans: &mut Vec<Span>) {
+    let bytes = line.as_bytes();
+    let mut pos = range.start;
+    while pos < range.end {
+        let next = match bytes[pos] {
+            b'`' => code_span(line, pos, range.end, spans),
+            b'[' => link(line, pos, range.end, spans),
+            b'*' | b'_' => emphasis(line, pos, range.end, spans),
+            _ => None,
+        };
+        pos = match next {
+            Some(end) => end,
+            None => pos + run_len(bytes, pos, range.end),
+        };
+    }
+}
+
+/// Length of the run of `bytes[pos]` starting at `pos`. Multi-byte
+/// characters count as one run, so `pos` stays on char boundaries.
+fn run_len(bytes: &[u8], pos: usize, end: usize) -> usize {
+    let byte = bytes[pos];
+    if !byte.is_ascii() {
+        let width = match byte {
+            0xf0.. => 4,
+            0xe0.. => 3,
+            _ => 2,
+        };
+        return width.min(end - pos);
+    }
+    bytes[pos..end].iter().take_while(|b| **b == byte).count()
+}
+
+/// A code span opened by the backtick run at `start`; it ends at the next
+/// run of the same length. Returns where scanning continues.
+fn code_span(line: &str, start: usize, end: usize, spans: &mut Vec<Span>) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let len = run_len(bytes, start, end);
+    let mut pos = start + len;
+    while pos < end {
+        let run = run_len(bytes, pos, end);
+        if bytes[pos] == b'`' && run == len {
+            spans.push(Span {
+                range: start..pos + run,
+                token: Token::Code,
+            });
+            return Some(pos + run);
+        }
+        pos += run;
+    }
+    // Unterminated: the backticks are plain text.
+    Some(start + len)
+}
+
+/// `[text](target)`, underlined as a whole.
+fn link(line: &str, start: usize, end: usize, spans: &mut Vec<Span>) -> Option<usize> {
+    let text_end = start + line[start..end].find("](")?;
+    let target_end = text_end + line[text_end..end].find(')')?;
+    spans.push(Span {
+        range: start..target_end + 1,
+        token: Token::Link,
+    });
+    Some(target_end + 1)
+}
+
+/// `*em*`, `**strong**` or `***both***` (or with `_`), closed by the next
+/// run of the same length. The content is scanned again for nested spans.
+fn emphasis(line: &str, start: usize, end: usize, spans: &mut Vec<Span>) -> Option<usize> {
+    let bytes = line.as_bytes();
+    let delim = bytes[start];
+    let len = run_len(bytes, start, end);
+    let open_end = start + len;
+    let flanking = |idx: Option<usize>| idx.is_some_and(|idx| !bytes[idx].is_ascii_whitespace());
+    let word = |idx: Option<usize>| idx.is_some_and(|idx| bytes[idx].is_ascii_alphanumeric());
+    // Openers need text right after them; `_` also not inside a word.
+    if len > 3
+        || !flanking((open_end < end).then_some(open_end))
+        || (delim == b'_' && word(start.checked_sub(1)))
+    {
+        return None;
+    }
+    let mut pos = open_end;
+    while pos < end {
+        let run = run_len(bytes, pos, end);
+        let closes = bytes[pos] == delim
+            && run == len
+            && flanking(Some(pos - 1))
+            && !(delim == b'_' && word((pos + run < end).then_some(pos + run)));
+        if closes {
+            let range = start..pos + run;
+            if len != 2 {
+                spans.push(Span {
+                    range: range.clone(),
+                    token: Token::Emphasis,
+                });
+            }
+            if len >= 2 {
+                spans.push(Span {
+                    range,
+                    token: Token::Strong,
+                });
+            }
+            inline_spans(line, open_end..pos, spans);
+            return Some(pos + run);
+        }
+        pos += run;
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tokens(line: &str) -> Vec<(&str, Token)> {
+        tokenize_line(line, &mut LineState::default())
+            .into_iter()
+            .map(|span| (&line[span.range], span.token))
+            .collect()
+    }
+
+    #[test]
+    fn block_markers_and_headings() {
+        assert_eq!(tokens("## Title *x*"), [("## Title *x*", Token::Heading)]);
+        assert_eq!(tokens("#hashtag"), []);
+        assert_eq!(
+            tokens("> - [docs](http://x) and `a*b*`"),
+            [
+                (">", Token::QuoteMarker),
+                ("- ", Token::ListMarker),
+                ("[docs](http://x)", Token::Link),
+                ("`a*b*`", Token::Code),
+            ]
+        );
+        assert_eq!(tokens("12. item"), [("12. ", Token::ListMarker)]);
+        assert_eq!(tokens("-not a list"), []);
+    }
+
+    #[test]
+    fn nested_emphasis() {
+        assert_eq!(
+            tokens("**bold *both* bold**"),
+            [
+                ("**bold *both* bold**", Token::Strong),
+                ("*both*", Token::Emphasis),
+            ]
+        );
+        assert_eq!(
+            tokens("*it __strong__ it*"),
+            [
+                ("*it __strong__ it*", Token::Emphasis),
+                ("__strong__", Token::Strong),
+            ]
+        );
+        assert_eq!(
+            tokens("***all***"),
+            [("***all***", Token::Emphasis), ("***all***", Token::Strong)]
+        );
+        // Unclosed, spaced or intraword delimiters stay plain.
+        assert_eq!(tokens("2 * 3 * 4"), []);
+        assert_eq!(tokens("*open"), []);
+        assert_eq!(tokens("snake_case_name"), []);
+        assert_eq!(tokens("é*ü*"), [("*ü*", Token::Emphasis)]);
+    }
+
+    #[test]
+    fn unterminated_code_fences_run_to_the_end() {
+        let mut state = LineState::default();
+        let lines = [
+            "text",
+            "```rust",
+            "let *x* = 1;",
+            "~~~",
+            "````",
+            "```",
+            "after *em*",
+        ];
+        let per_line: Vec<Vec<Token>> = lines
+            .iter()
+            .map(|line| {
+                let spans = tokenize_line(line, &mut state);
+                spans.into_iter().map(|span| span.token).collect()
+            })
+            .collect();
+        assert_eq!(per_line[0], []);
+        assert_eq!(per_line[1], [Token::Fence]);
+        assert_eq!(per_line[2], [Token::Code]);
+        // Another fence character doesn't close the block, a longer fence does.
+        assert_eq!(per_line[3], [Token::Code]);
+        assert_eq!(per_line[4], [Token::Fence]);
+        // This one is never closed.
+        assert_eq!(per_line[5], [Token::Fence]);
+        assert_eq!(per_line[6], [Token::Code]);
+        assert!(LineState::after(lines).fence.is_some());
+
+        assert_eq!(tokens("``unterminated `code"), []);
+    }
+}
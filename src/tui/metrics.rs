@@ -0,0 +1,228 @@
+//! Counters behind the debug overlay (F12) and `--debug-log`.
+
+use super::link::LinkState;
+use std::time::{Duration, Instant};
+
+/// Message rates are averaged over windows of this length.
+const RATE_WINDOW: Duration = Duration::from_secs(1);
+
+/// Plain counters updated from the main loop; nothing here allocates.
+#[derive(Debug)]
+pub(super) struct Metrics {
+    frames: u64,
+    last_render: Duration,
+    received: u64,
+    window_start: Instant,
+    /// `received` and the sent total when the current window started.
+    window_received: u64,
+    window_sent: u64,
+    received_per_sec: f64,
+    sent_per_sec: f64,
+    /// Lines from servers that were no message.
+    malformed: u64,
+}
+
+impl Metrics {
+    pub(super) fn new(now: Instant) -> Self {
+        Self {
+            frames: 0,
+            last_render: Duration::ZERO,
+            received: 0,
+            window_start: now,
+            window_received: 0,
+            window_sent: 0,
+            received_per_sec: 0.0,
+            sent_per_sec: 0.0,
+            malformed: 0,
+        }
+    }
+
+    pub(super) fn frame_rendered(&mut self, took: Duration) {
+        self.frames += 1;
+        self.last_render = took;
+    }
+
+    pub(super) fn message_received(&mut self) {
+        self.received += 1;
+    }
+
+    pub(super) fn malformed_received(&mut self) {
+        self.malformed += 1;
+    }
+
+    pub(super) fn malformed(&self) -> u64 {
+        self.malformed
+    }
+
+    /// Starts a new rate window once the current one is over; `sent` is the
+    /// number of messages sent so far. Returns true if the rates changed.
+    pub(super) fn update_rates(&mut self, now: Instant, sent: u64) -> bool {
+        let elapsed = now.duration_since(self.window_start);
+        if elapsed < RATE_WINDOW {
+            return false;
+        }
+        let secs = elapsed.as_secs_f64();
+        self.received_per_sec = (self.received - self.window_received) as f64 / secs;
+        self.sent_per_sec = sent.saturating_sub(self.window_sent) as f64 / secs;
+        self.window_start = now;
+        self.window_received = self.received;
+        self.window_sent = sent;
+        true
+    }
+}
+
+/// What the overlay shows, gathered from the counters and the active
+/// buffer.
+#[derive(Debug, Clone, Copy, Default)]
+pub(super) struct DebugStats {
+    pub(super) frames: u64,
+    pub(super) last_render: Duration,
+    pub(super) received_per_sec: f64,
+    pub(super) sent_per_sec: f64,
+    pub(super) version: u64,
+    /// Server version of our own last edit echoed back.
+    pub(super) acked_version: u64,
+    /// Messages waiting for the writer task, out of its capacity.
+    pub(super) queue: (usize, usize),
+    pub(super) rtt: Option<Duration>,
+    pub(super) link: Option<LinkState>,
+    /// Time since the server was last heard from.
+    pub(super) last_message: Option<Duration>,
+    pub(super) scroll: (usize, usize),
+    pub(super) free_scroll: bool,
+    pub(super) cursor_byte: usize,
+    /// Zero-based line and display column of the cursor.
+    pub(super) cursor: (usize, usize),
+    pub(super) anchor: Option<usize>,
+    /// Messages and bytes written to servers so far.
+    pub(super) sent: (u64, u64),
+    pub(super) malformed: u64,
+}
+
+impl DebugStats {
+    pub(super) fn from_metrics(metrics: &Metrics) -> Self {
+        Self {
+            frames: metrics.frames,
+            last_render: metrics.last_render,
+            received_per_sec: metrics.received_per_sec,
+            sent_per_sec: metrics.sent_per_sec,
+            malformed: metrics.malformed,
+            ..Self::default()
+        }
+    }
+
+    /// Rows of the overlay box.
+    pub(super) fn overlay_lines(&self) -> Vec<String> {
+        vec![
+            format!("frame   {}  #{}", millis(self.last_render), self.frames),
+            format!(
+                "msgs/s  in {:.1}  out {:.1}",
+                self.received_per_sec, self.sent_per_sec
+            ),
+            format!("version v{}  acked v{}", self.version, self.acked_version),
+            format!("queue   {}/{}", self.queue.0, self.queue.1),
+            format!("rtt     {}", self.rtt.map_or("-".to_string(), millis)),
+            format!("link    {}", self.link_details()),
+            format!(
+                "scroll  top {}  left {}{}",
+                self.scroll.0,
+                self.scroll.1,
+                if self.free_scroll { "  free" } else { "" }
+            ),
+            format!(
+                "cursor  {} ({}:{})",
+                self.cursor_byte,
+                self.cursor.0 + 1,
+                self.cursor.1 + 1
+            ),
+            format!(
+                "anchor  {}",
+                self.anchor
+                    .map_or("-".to_string(), |anchor| anchor.to_string())
+            ),
+            format!("sent    {} msgs  {} bytes", self.sent.0, self.sent.1),
+            format!("bad     {} malformed lines", self.malformed),
+        ]
+    }
+
+    /// E.g. `degraded: rtt 320ms, last msg 14s ago`.
+    fn link_details(&self) -> String {
+        let Some(link) = self.link else {
+            return "-".to_string();
+        };
+        let rtt = self
+            .rtt
+            .map_or("-".to_string(), |rtt| format!("{}ms", rtt.as_millis()));
+        match self.last_message {
+            Some(ago) => format!(
+                "{}: rtt {}, last msg {}s ago",
+                link.label(),
+                rtt,
+                ago.as_secs()
+            ),
+            None => format!("{}: rtt {}", link.label(), rtt),
+        }
+    }
+
+    /// One line of `--debug-log`, `uptime` since the TUI started.
+    pub(super) fn log_line(&self, uptime: Duration) -> String {
+        format!(
+            "t={:.1}s frames={} render_us={} in_per_s={:.1} out_per_s={:.1} version={} acked={} queue={} rtt_us={} malformed={}",
+            uptime.as_secs_f64(),
+            self.frames,
+            self.last_render.as_micros(),
+            self.received_per_sec,
+            self.sent_per_sec,
+            self.version,
+            self.acked_version,
+            self.queue.0,
+            self.rtt
+                .map_or("-".to_string(), |rtt| rtt.as_micros().to_string()),
+            self.malformed,
+        )
+    }
+}
+
+fn millis(duration: Duration) -> String {
+    format!("{:.2} ms", duration.as_secs_f64() * 1000.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_cover_whole_windows() {
+        let start = Instant::now();
+        let mut metrics = Metrics::new(start);
+        for _ in 0..6 {
+            metrics.message_received();
+        }
+        metrics.frame_rendered(Duration::from_micros(1500));
+        metrics.malformed_received();
+        assert!(!metrics.update_rates(start + RATE_WINDOW / 2, 2));
+        assert!(metrics.update_rates(start + RATE_WINDOW * 2, 4));
+
+        let stats = DebugStats {
+            version: 7,
+            acked_version: 5,
+            rtt: Some(Duration::from_millis(320)),
+            link: Some(LinkState::Degraded),
+            last_message: Some(Duration::from_secs(14)),
+            ..DebugStats::from_metrics(&metrics)
+        };
+        assert_eq!((stats.received_per_sec, stats.sent_per_sec), (3.0, 2.0));
+        assert_eq!(stats.overlay_lines()[0], "frame   1.50 ms  #1");
+        assert_eq!(
+            stats.overlay_lines()[5],
+            "link    degraded: rtt 320ms, last msg 14s ago"
+        );
+        assert_eq!(
+            stats.log_line(Duration::from_secs(3)),
+            "t=3.0s frames=1 render_us=1500 in_per_s=3.0 out_per_s=2.0 version=7 acked=5 queue=0 rtt_us=320000 malformed=1"
+        );
+
+        assert!(metrics.update_rates(start + RATE_WINDOW * 3, 4));
+        assert_eq!(metrics.received_per_sec, 0.0);
+    }
+}
@@ -0,0 +1,164 @@
+//! A list narrowed down by typing: the room and doc to join when `--room`
+//! or `--doc` is left out, and `:docs` to open another doc of the room.
+
+use super::widgets::{self, Prompt, PromptEvent, Screen, Style};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// What a key did in the picker.
+#[derive(Debug, PartialEq, Eq)]
+pub(super) enum Picked {
+    /// Still picking.
+    Pending,
+    Entry(String),
+    /// A name that isn't listed, typed as the filter and taken with Ctrl+N.
+    New(String),
+    Cancel,
+}
+
+pub(super) struct Picker {
+    /// What is picked, e.g. "room".
+    what: String,
+    entries: Vec<String>,
+    filter: Prompt,
+    /// Index into `matches()`.
+    selected: usize,
+}
+
+impl Picker {
+    pub(super) fn new(what: &str, entries: Vec<String>) -> Self {
+        Self {
+            what: what.to_string(),
+            entries,
+            filter: Prompt::new(what, ""),
+            selected: 0,
+        }
+    }
+
+    /// The entries containing the filter, ignoring case.
+    pub(super) fn matches(&self) -> Vec<&str> {
+        let filter = self.filter.input().to_lowercase();
+        self.entries
+            .iter()
+            .filter(|entry| entry.to_lowercase().contains(&filter))
+            .map(String::as_str)
+            .collect()
+    }
+
+    pub(super) fn handle_key(&mut self, key: KeyEvent) -> Picked {
+        match key.code {
+            KeyCode::Up => {
+                self.selected = self.selected.saturating_sub(1);
+                return Picked::Pending;
+            }
+            KeyCode::Down => {
+                let last = self.matches().len().saturating_sub(1);
+                self.selected = (self.selected + 1).min(last);
+                return Picked::Pending;
+            }
+            KeyCode::Char('n') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                let name = self.filter.input().trim();
+                if name.is_empty() {
+                    return Picked::Pending;
+                }
+                return Picked::New(name.to_string());
+            }
+            _ => {}
+        }
+        match self.filter.handle_key(key) {
+            PromptEvent::Changed => {
+                self.selected = 0;
+                Picked::Pending
+            }
+            PromptEvent::Submit => match self.matches().get(self.selected) {
+                Some(entry) => Picked::Entry(entry.to_string()),
+                None => Picked::Pending,
+            },
+            PromptEvent::Cancel => Picked::Cancel,
+            PromptEvent::Moved | PromptEvent::Ignored => Picked::Pending,
+        }
+    }
+
+    /// The filter, shown on the status row as e.g. `room: tea`.
+    pub(super) fn prompt(&self) -> &Prompt {
+        &self.filter
+    }
+
+    /// Draws a header and the matching entries over `rows` rows from `top`,
+    /// scrolled to keep the selected one, drawn in `selected`, in view.
+    pub(super) fn render(
+        &self,
+        screen: &mut Screen,
+        (top, rows, cols): (usize, usize, usize),
+        selected: Style,
+    ) {
+        let matches = self.matches();
+        let header = if matches.is_empty() && self.filter.input().trim().is_empty() {
+            format!(
+                "No {}s yet | type a name, Ctrl+N creates it, Esc cancels",
+                self.what
+            )
+        } else {
+            format!(
+                "Pick a {} | type to filter, Up/Down, Enter opens, Ctrl+N new, Esc cancels",
+                self.what
+            )
+        };
+        let lines: Vec<String> = matches.iter().map(|entry| format!("  {}", entry)).collect();
+        let area = (top, rows, cols);
+        widgets::draw_list(screen, area, &header, &lines, (self.selected, selected));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode) -> KeyEvent {
+        KeyEvent::new(code, KeyModifiers::NONE)
+    }
+
+    fn type_text(picker: &mut Picker, text: &str) {
+        for ch in text.chars() {
+            assert_eq!(picker.handle_key(key(KeyCode::Char(ch))), Picked::Pending);
+        }
+    }
+
+    #[test]
+    fn typing_filters_and_enter_picks_the_selected_match() {
+        let docs = ["notes.txt", "Todo.md", "standup/notes", "readme"];
+        let mut picker = Picker::new("doc", docs.map(String::from).to_vec());
+        assert_eq!(picker.matches().len(), 4);
+        type_text(&mut picker, "NOT");
+        assert_eq!(picker.matches(), ["notes.txt", "standup/notes"]);
+        picker.handle_key(key(KeyCode::Down));
+        picker.handle_key(key(KeyCode::Down));
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Enter)),
+            Picked::Entry("standup/notes".into())
+        );
+
+        // Changing the filter starts from the top again.
+        for _ in 0..3 {
+            picker.handle_key(key(KeyCode::Backspace));
+        }
+        type_text(&mut picker, "do");
+        assert_eq!(picker.matches(), ["Todo.md"]);
+        assert_eq!(
+            picker.handle_key(key(KeyCode::Enter)),
+            Picked::Entry("Todo.md".into())
+        );
+        assert_eq!(picker.handle_key(key(KeyCode::Esc)), Picked::Cancel);
+    }
+
+    #[test]
+    fn ctrl_n_takes_the_filter_as_a_new_name() {
+        let mut picker = Picker::new("room", vec!["team".into()]);
+        let ctrl_n = KeyEvent::new(KeyCode::Char('n'), KeyModifiers::CONTROL);
+        assert_eq!(picker.handle_key(ctrl_n), Picked::Pending);
+        type_text(&mut picker, " design ");
+        assert!(picker.matches().is_empty());
+        assert_eq!(picker.handle_key(key(KeyCode::Enter)), Picked::Pending);
+        assert_eq!(picker.handle_key(ctrl_n), Picked::New("design".into()));
+        assert_eq!(picker.prompt().line(), "room:  design ");
+    }
+}
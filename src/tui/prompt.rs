@@ -0,0 +1,118 @@
+use super::widgets::Prompt;
+
+/// What a status-row prompt is asking for.
+pub(super) enum PromptAction {
+    Export,
+    Import,
+    /// Asks whether the imported `contents` replace the document or get
+    /// inserted at the cursor.
+    ConfirmReplace {
+        path: String,
+        contents: String,
+    },
+    GotoLine,
+    OpenDoc,
+    /// The command palette (Ctrl+P).
+    Command,
+    /// Offers an earlier session's backup that differs from the document;
+    /// `diff` is the scroll offset while the diff is shown.
+    RestoreBackup {
+        contents: String,
+        diff: Option<usize>,
+    },
+    /// Quitting while sent edits haven't been echoed back by the server.
+    ConfirmQuit,
+}
+
+/// A prompt opened by a command (Ctrl+S, Ctrl+O, Ctrl+G, Ctrl+N, Ctrl+P)
+/// together with the action to run on Enter.
+pub(super) struct CommandPrompt {
+    pub(super) action: PromptAction,
+    pub(super) prompt: Prompt,
+}
+
+impl CommandPrompt {
+    pub(super) fn export(doc: &str) -> Self {
+        Self {
+            action: PromptAction::Export,
+            prompt: Prompt::new("export to", doc),
+        }
+    }
+
+    pub(super) fn import() -> Self {
+        Self {
+            action: PromptAction::Import,
+            prompt: Prompt::new("import from", ""),
+        }
+    }
+
+    pub(super) fn confirm_replace(path: String, contents: String) -> Self {
+        let label = format!(
+            "replace the whole document with {}? (y replace, n insert at cursor)",
+            path
+        );
+        Self {
+            action: PromptAction::ConfirmReplace { path, contents },
+            prompt: Prompt::new(&label, ""),
+        }
+    }
+
+    pub(super) fn goto_line() -> Self {
+        Self {
+            action: PromptAction::GotoLine,
+            prompt: Prompt::new("go to line[:col]", ""),
+        }
+    }
+
+    pub(super) fn open_doc() -> Self {
+        Self {
+            action: PromptAction::OpenDoc,
+            prompt: Prompt::new("open doc", ""),
+        }
+    }
+
+    pub(super) fn command() -> Self {
+        Self {
+            action: PromptAction::Command,
+            prompt: Prompt::new("command", ""),
+        }
+    }
+
+    pub(super) fn restore_backup(doc: &str, contents: String) -> Self {
+        let label = format!(
+            "the backup of {} differs from the server's text (d view diff, r re-apply)",
+            doc
+        );
+        Self {
+            action: PromptAction::RestoreBackup {
+                contents,
+                diff: None,
+            },
+            prompt: Prompt::new(&label, ""),
+        }
+    }
+
+    /// `waiting` while quitting still gives the edits time to be confirmed.
+    pub(super) fn confirm_quit(unconfirmed: usize, waiting: bool) -> Self {
+        let label = if waiting {
+            format!("waiting for {} edits to be confirmed…", unconfirmed)
+        } else {
+            format!("{} edits not yet confirmed — quit anyway?", unconfirmed)
+        };
+        Self {
+            action: PromptAction::ConfirmQuit,
+            prompt: Prompt::new(&label, ""),
+        }
+    }
+
+    /// Hint shown after the prompt on the status row.
+    pub(super) fn hint(&self) -> &'static str {
+        match self.action {
+            PromptAction::ConfirmReplace { .. } => "Esc cancel",
+            PromptAction::RestoreBackup { .. } => "Esc keep server text",
+            PromptAction::ConfirmQuit => "y/Esc quit, n keep editing",
+            PromptAction::Command => "Tab complete, Enter run, Esc cancel",
+            _ => "Enter confirm, Esc cancel",
+        }
+    }
+}
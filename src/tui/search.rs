@@ -0,0 +1,130 @@
+use super::widgets::Prompt;
+
+/// An open incremental search: the query prompt plus where the cursor and
+/// viewport were when it was opened, so Esc can restore them.
+pub(super) struct SearchState {
+    pub(super) prompt: Prompt,
+    pub(super) origin_cursor: usize,
+    pub(super) origin_scroll: usize,
+}
+
+impl SearchState {
+    pub(super) fn new(origin_cursor: usize, origin_scroll: usize) -> Self {
+        Self {
+            prompt: Prompt::new("search", ""),
+            origin_cursor,
+            origin_scroll,
+        }
+    }
+}
+
+/// Finds non-overlapping matches of `query` as byte ranges. Matching is
+/// case-insensitive unless the query contains an uppercase letter.
+pub(super) fn find_matches(text: &str, query: &str) -> Vec<(usize, usize)> {
+    if query.is_empty() {
+        return Vec::new();
+    }
+    if query.chars().any(char::is_uppercase) {
+        return text
+            .match_indices(query)
+            .map(|(start, found)| (start, start + found.len()))
+            .collect();
+    }
+
+    let needle: Vec<char> = query.chars().flat_map(char::to_lowercase).collect();
+    let mut matches = Vec::new();
+    let mut next_allowed = 0;
+    for (start, _) in text.char_indices() {
+        if start < next_allowed {
+            continue;
+        }
+        if let Some(end) = match_at(text, start, &needle) {
+            matches.push((start, end));
+            next_allowed = end;
+        }
+    }
+    matches
+}
+
+/// Returns the end of a case-insensitive match of `needle` starting at `start`.
+fn match_at(text: &str, start: usize, needle: &[char]) -> Option<usize> {
+    let mut matched = 0;
+    let mut end = start;
+    for ch in text[start..].chars() {
+        for lower in ch.to_lowercase() {
+            if needle.get(matched) != Some(&lower) {
+                return None;
+            }
+            matched += 1;
+        }
+        end += ch.len_utf8();
+        if matched == needle.len() {
+            return Some(end);
+        }
+    }
+    None
+}
+
+/// First match starting at or after `pos`, wrapping around to the top.
+pub(super) fn match_from(matches: &[(usize, usize)], pos: usize) -> Option<(usize, usize)> {
+    matches
+        .iter()
+        .find(|(start, _)| *start >= pos)
+        .or_else(|| matches.first())
+        .copied()
+}
+
+/// Next (or previous) match relative to the one at `pos`, with wraparound.
+pub(super) fn cycle_match(
+    matches: &[(usize, usize)],
+    pos: usize,
+    forward: bool,
+) -> Option<(usize, usize)> {
+    if forward {
+        matches
+            .iter()
+            .find(|(start, _)| *start > pos)
+            .or_else(|| matches.first())
+            .copied()
+    } else {
+        matches
+            .iter()
+            .rev()
+            .find(|(start, _)| *start < pos)
+            .or_else(|| matches.last())
+            .copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn smart_case_matching() {
+        let text = "Foo foo FOO";
+        assert_eq!(find_matches(text, "foo"), vec![(0, 3), (4, 7), (8, 11)]);
+        assert_eq!(find_matches(text, "Foo"), vec![(0, 3)]);
+        assert!(find_matches(text, "").is_empty());
+    }
+
+    #[test]
+    fn matches_are_byte_ranges_in_multibyte_text() {
+        let text = "größe GRÖSSE größe";
+        let matches = find_matches(text, "größe");
+        assert_eq!(matches.len(), 2);
+        for (start, end) in matches {
+            assert_eq!(&text[start..end], "größe");
+        }
+    }
+
+    #[test]
+    fn cycling_wraps_around() {
+        let matches = vec![(2, 4), (10, 12)];
+        assert_eq!(match_from(&matches, 5), Some((10, 12)));
+        assert_eq!(match_from(&matches, 11), Some((2, 4)));
+        assert_eq!(cycle_match(&matches, 10, true), Some((2, 4)));
+        assert_eq!(cycle_match(&matches, 2, false), Some((10, 12)));
+        assert_eq!(cycle_match(&[], 0, true), None);
+    }
+}
@@ -0,0 +1,314 @@
+//! Spellchecking of the visible text (the `spellcheck` feature). Words are
+//! looked up on a worker thread and the verdicts cached, so rendering never
+//! waits for the dictionary.
+
+use super::{SpellConfig, UiEvent};
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc as std_mpsc;
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc;
+
+/// Unknown words are only sent for checking after input paused this long.
+const IDLE_BEFORE_CHECK: Duration = Duration::from_millis(300);
+
+/// What the worker sends back.
+enum Reply {
+    Verdicts(Vec<(String, bool)>),
+    Failed(String),
+}
+
+/// What F8 did to the word under the cursor.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum WordState {
+    /// This exact spelling is no longer flagged.
+    Ignored,
+    /// The word is accepted in any capitalization.
+    Added,
+    /// Checked against the dictionary again.
+    Checked,
+}
+
+pub(super) struct Speller {
+    requests: std_mpsc::Sender<Vec<String>>,
+    replies: std_mpsc::Receiver<Reply>,
+    /// Dictionary verdicts so far, by word as written.
+    verdicts: HashMap<String, bool>,
+    /// Words sent to the worker and not answered yet.
+    asked: HashSet<String>,
+    /// Words drawn without a verdict, sent once input is idle.
+    wanted: HashSet<String>,
+    ignored: HashSet<String>,
+    /// Added words, lowercased.
+    session: HashSet<String>,
+    last_input: Instant,
+}
+
+impl Speller {
+    /// Starts the worker, which loads the dictionary and then answers
+    /// lookups, waking the UI loop through `notify` after each reply.
+    pub(super) fn start(config: &SpellConfig, notify: mpsc::UnboundedSender<UiEvent>) -> Self {
+        let (requests, worker_requests) = std_mpsc::channel::<Vec<String>>();
+        let (worker_replies, replies) = std_mpsc::channel();
+        let path = dictionary_path(config);
+        std::thread::spawn(move || {
+            let words = path
+                .ok_or_else(|| "no dictionary found; set spellcheck.dictionary".to_string())
+                .and_then(|path| {
+                    std::fs::read_to_string(&path)
+                        .map_err(|err| format!("{}: {}", path.display(), err))
+                });
+            let dictionary = match words {
+                Ok(words) => Dictionary::parse(&words),
+                Err(err) => {
+                    let _ = worker_replies.send(Reply::Failed(err));
+                    let _ = notify.send(UiEvent::Redraw);
+                    return;
+                }
+            };
+            while let Ok(words) = worker_requests.recv() {
+                let verdicts = words
+                    .into_iter()
+                    .map(|word| {
+                        let known = dictionary.knows(&word);
+                        (word, known)
+                    })
+                    .collect();
+                if worker_replies.send(Reply::Verdicts(verdicts)).is_err()
+                    || notify.send(UiEvent::Redraw).is_err()
+                {
+                    break;
+                }
+            }
+        });
+        Self::new(requests, replies)
+    }
+
+    fn new(requests: std_mpsc::Sender<Vec<String>>, replies: std_mpsc::Receiver<Reply>) -> Self {
+        Self {
+            requests,
+            replies,
+            verdicts: HashMap::new(),
+            asked: HashSet::new(),
+            wanted: HashSet::new(),
+            ignored: HashSet::new(),
+            session: HashSet::new(),
+            last_input: Instant::now(),
+        }
+    }
+
+    /// Whether `word` is spelled correctly, once known. A word without a
+    /// verdict is queued for checking.
+    pub(super) fn check(&mut self, word: &str) -> Option<bool> {
+        if self.ignored.contains(word) || self.session.contains(&word.to_lowercase()) {
+            return Some(true);
+        }
+        let verdict = self.verdicts.get(word).copied();
+        if verdict.is_none() && !self.asked.contains(word) {
+            self.wanted.insert(word.to_string());
+        }
+        verdict
+    }
+
+    /// Notes input, which holds back checking until it pauses.
+    pub(super) fn input(&mut self, now: Instant) {
+        self.last_input = now;
+    }
+
+    /// Sends the queued words to the worker once input has paused.
+    pub(super) fn send_wanted(&mut self, now: Instant) {
+        if self.wanted.is_empty() || now.duration_since(self.last_input) < IDLE_BEFORE_CHECK {
+            return;
+        }
+        let words: Vec<String> = self.wanted.drain().collect();
+        self.asked.extend(words.iter().cloned());
+        let _ = self.requests.send(words);
+    }
+
+    /// Takes in the worker's replies. Returns an error to report if the
+    /// dictionary couldn't be loaded.
+    pub(super) fn collect(&mut self) -> Option<String> {
+        let mut failure = None;
+        while let Ok(reply) = self.replies.try_recv() {
+            match reply {
+                Reply::Verdicts(verdicts) => {
+                    for (word, known) in verdicts {
+                        self.asked.remove(&word);
+                        self.verdicts.insert(word, known);
+                    }
+                }
+                Reply::Failed(err) => failure = Some(err),
+            }
+        }
+        failure
+    }
+
+    /// F8 on a word: ignore this spelling, then accept the word in any
+    /// capitalization, then check it again.
+    pub(super) fn cycle(&mut self, word: &str) -> WordState {
+        let lower = word.to_lowercase();
+        if self.session.remove(&lower) {
+            self.ignored.remove(word);
+            WordState::Checked
+        } else if self.ignored.remove(word) {
+            self.session.insert(lower);
+            WordState::Added
+        } else {
+            self.ignored.insert(word.to_string());
+            WordState::Ignored
+        }
+    }
+}
+
+/// `spellcheck.dictionary`, else the hunspell or myspell dictionary for
+/// `spellcheck.language`, else the system word list.
+fn dictionary_path(config: &SpellConfig) -> Option<PathBuf> {
+    if let Some(path) = &config.dictionary {
+        return Some(path.clone());
+    }
+    let file = format!("{}.dic", config.language);
+    [
+        Path::new("/usr/share/hunspell").join(&file),
+        Path::new("/usr/share/myspell").join(&file),
+        PathBuf::from("/usr/share/dict/words"),
+    ]
+    .into_iter()
+    .find(|path| path.is_file())
+}
+
+/// Known words, lowercased. Affix rules of hunspell dictionaries are not
+/// applied; only a plural or possessive `s` is stripped.
+struct Dictionary {
+    words: HashSet<String>,
+}
+
+impl Dictionary {
+    /// Reads a word list or a hunspell `.dic` file (a count on the first
+    /// line, `word/FLAGS` entries).
+    fn parse(text: &str) -> Self {
+        let words = text
+            .lines()
+            .map(|line| line.split('/').next().unwrap_or(line).trim())
+            .filter(|word| !word.is_empty() && !word.bytes().all(|byte| byte.is_ascii_digit()))
+            .map(str::to_lowercase)
+            .collect();
+        Self { words }
+    }
+
+    fn knows(&self, word: &str) -> bool {
+        let word = word.to_lowercase();
+        let stem = word
+            .strip_suffix("'s")
+            .or_else(|| word.strip_suffix('s'))
+            .filter(|stem| !stem.is_empty());
+        self.words.contains(&word) || stem.is_some_and(|stem| self.words.contains(stem))
+    }
+}
+
+/// Byte ranges of the words of `line` worth checking: letters with inner
+/// apostrophes, at least two long, skipping anything with digits or
+/// capitals after the first letter (acronyms, identifiers).
+pub(super) fn words(line: &str) -> Vec<Range<usize>> {
+    let mut words = Vec::new();
+    let mut start = None;
+    let mut chars = line.char_indices().peekable();
+    while let Some((idx, ch)) = chars.next() {
+        let inner_apostrophe = ch == '\''
+            && start.is_some()
+            && chars.peek().is_some_and(|(_, next)| next.is_alphabetic());
+        if ch.is_alphanumeric() || inner_apostrophe {
+            start.get_or_insert(idx);
+            continue;
+        }
+        if let Some(from) = start.take() {
+            words.push(from..idx);
+        }
+    }
+    if let Some(from) = start {
+        words.push(from..line.len());
+    }
+    words.retain(|range| {
+        let word = &line[range.clone()];
+        word.chars().count() >= 2
+            && !word.chars().any(|ch| ch.is_numeric())
+            && !word.chars().skip(1).any(char::is_uppercase)
+    });
+    words
+}
+
+/// The checkable word touching `pos`, if any.
+pub(super) fn word_at(text: &str, pos: usize) -> Option<&str> {
+    let start = text[..pos].rfind('\n').map_or(0, |idx| idx + 1);
+    let end = text[pos..].find('\n').map_or(text.len(), |idx| pos + idx);
+    let line = &text[start..end];
+    words(line)
+        .into_iter()
+        .find(|range| range.start <= pos - start && pos - start <= range.end)
+        .map(|range| &line[range])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn words_skip_identifiers_and_keep_apostrophes() {
+        let line = "Teh cat's HTTP x2 fooBar a 'quoted' naïve";
+        let found: Vec<&str> = words(line).into_iter().map(|range| &line[range]).collect();
+        assert_eq!(found, ["Teh", "cat's", "quoted", "naïve"]);
+        assert_eq!(word_at("one\ntwo words", 7), Some("two"));
+        assert_eq!(word_at("one\ntwo words", 4), Some("two"));
+        assert_eq!(word_at("a, b", 1), None);
+    }
+
+    #[test]
+    fn lookups_go_through_the_worker_and_f8_cycles() {
+        let dictionary = Dictionary::parse("3\nthe/S\nParis\ncat/SM\n");
+        assert!(dictionary.knows("The") && dictionary.knows("paris"));
+        assert!(dictionary.knows("cats") && dictionary.knows("cat's"));
+        assert!(!dictionary.knows("teh") && !dictionary.knows("3"));
+
+        let (requests, worker_requests) = std_mpsc::channel::<Vec<String>>();
+        let (worker_replies, replies) = std_mpsc::channel();
+        let mut speller = Speller::new(requests, replies);
+        let start = Instant::now();
+        speller.input(start);
+        assert_eq!(speller.check("teh"), None);
+        assert_eq!(speller.check("cat"), None);
+        // Nothing goes out while typing continues.
+        speller.send_wanted(start);
+        assert!(worker_requests.try_recv().is_err());
+        speller.send_wanted(start + IDLE_BEFORE_CHECK);
+        let asked = worker_requests.try_recv().unwrap();
+        assert_eq!(asked.len(), 2);
+        // Asked words aren't queued again while the answer is pending.
+        assert_eq!(speller.check("teh"), None);
+        assert!(speller.wanted.is_empty());
+
+        let verdicts = asked
+            .into_iter()
+            .map(|word| {
+                let known = dictionary.knows(&word);
+                (word, known)
+            })
+            .collect();
+        worker_replies.send(Reply::Verdicts(verdicts)).unwrap();
+        assert_eq!(speller.collect(), None);
+        assert_eq!(speller.check("teh"), Some(false));
+        assert_eq!(speller.check("cat"), Some(true));
+
+        assert_eq!(speller.cycle("teh"), WordState::Ignored);
+        assert_eq!(speller.check("teh"), Some(true));
+        assert_eq!(speller.check("Teh"), None);
+        assert_eq!(speller.cycle("teh"), WordState::Added);
+        assert_eq!(speller.check("Teh"), Some(true));
+        assert_eq!(speller.cycle("teh"), WordState::Checked);
+        assert_eq!(speller.check("teh"), Some(false));
+
+        worker_replies
+            .send(Reply::Failed("no dictionary".into()))
+            .unwrap();
+        assert_eq!(speller.collect().as_deref(), Some("no dictionary"));
+    }
+}
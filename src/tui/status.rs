@@ -0,0 +1,203 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+/// Informational messages leave the status row after this long.
+const INFO_TTL: Duration = Duration::from_secs(5);
+/// Messages kept for the log overlay (F10).
+const LOG_CAPACITY: usize = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Severity {
+    Info,
+    /// Stays on the status row until dismissed with Esc.
+    Error,
+    /// Stays too, but isn't an error: the server's message of the day.
+    Notice,
+}
+
+#[derive(Debug)]
+pub(super) struct StatusEntry {
+    pub(super) text: String,
+    pub(super) severity: Severity,
+    pub(super) at: Instant,
+}
+
+/// The message shown in the status row plus a log of recent ones.
+#[derive(Debug, Default)]
+pub(super) struct StatusLog {
+    /// Oldest first.
+    entries: VecDeque<StatusEntry>,
+    /// Whether the newest entry is still shown in the status row.
+    showing: bool,
+    /// Whether the newest entry is progress the next progress replaces.
+    progress: bool,
+}
+
+impl StatusLog {
+    pub(super) fn info(&mut self, text: impl Into<String>) {
+        self.push(Severity::Info, text.into(), Instant::now());
+    }
+
+    pub(super) fn error(&mut self, text: impl Into<String>) {
+        self.push(Severity::Error, text.into(), Instant::now());
+    }
+
+    pub(super) fn notice(&mut self, text: impl Into<String>) {
+        self.push(Severity::Notice, text.into(), Instant::now());
+    }
+
+    /// Shows how far something got, in place of the previous progress so
+    /// the log keeps only the latest.
+    pub(super) fn progress(&mut self, text: impl Into<String>) {
+        let now = Instant::now();
+        if self.progress
+            && let Some(entry) = self.entries.back_mut()
+        {
+            entry.text = text.into();
+            entry.at = now;
+            self.showing = true;
+            return;
+        }
+        self.push(Severity::Info, text.into(), now);
+        self.progress = !self.entries.is_empty();
+    }
+
+    fn push(&mut self, severity: Severity, text: String, at: Instant) {
+        if text.is_empty() {
+            return;
+        }
+        self.progress = false;
+        if self.entries.len() == LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(StatusEntry { text, severity, at });
+        self.showing = true;
+    }
+
+    /// Takes the message off the status row; it stays in the log.
+    pub(super) fn clear(&mut self) {
+        self.showing = false;
+    }
+
+    /// The message for the status row, if any.
+    pub(super) fn current(&self) -> Option<&StatusEntry> {
+        self.entries.back().filter(|_| self.showing)
+    }
+
+    /// Clears a shown error or notice (Esc). Returns false if there was
+    /// none, so the key can do what it normally does.
+    pub(super) fn dismiss(&mut self) -> bool {
+        let stays = self
+            .current()
+            .is_some_and(|entry| entry.severity != Severity::Info);
+        if stays {
+            self.showing = false;
+        }
+        stays
+    }
+
+    /// Hides an informational message once it is old enough. Returns true
+    /// if the status row changed.
+    pub(super) fn expire(&mut self, now: Instant) -> bool {
+        let expired = self.current().is_some_and(|entry| {
+            entry.severity == Severity::Info && now.duration_since(entry.at) > INFO_TTL
+        });
+        if expired {
+            self.showing = false;
+        }
+        expired
+    }
+
+    /// Logged messages, newest first.
+    pub(super) fn entries(&self) -> impl Iterator<Item = &StatusEntry> {
+        self.entries.iter().rev()
+    }
+
+    pub(super) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Short age of a log entry: `42s`, `5m` or `2h`.
+pub(super) fn format_age(age: Duration) -> String {
+    let secs = age.as_secs();
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        _ => format!("{}h", secs / 3600),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn info_expires_and_errors_wait_for_dismissal() {
+        let start = Instant::now();
+        let mut log = StatusLog::default();
+        log.push(Severity::Info, "sync requested".into(), start);
+        assert!(!log.expire(start + INFO_TTL));
+        assert!(log.expire(start + INFO_TTL * 2));
+        assert!(log.current().is_none());
+
+        log.push(Severity::Error, "export failed".into(), start);
+        assert!(!log.expire(start + INFO_TTL * 2));
+        assert_eq!(log.current().unwrap().text, "export failed");
+        assert!(log.dismiss());
+        assert!(log.current().is_none());
+        assert!(!log.dismiss());
+
+        log.notice("maintenance Friday");
+        assert!(!log.expire(Instant::now() + INFO_TTL * 2));
+        assert!(log.dismiss());
+
+        log.push(Severity::Info, "copied".into(), start);
+        assert!(!log.dismiss());
+        log.clear();
+        assert!(log.current().is_none());
+        let texts: Vec<&str> = log.entries().map(|entry| entry.text.as_str()).collect();
+        let expected = [
+            "copied",
+            "maintenance Friday",
+            "export failed",
+            "sync requested",
+        ];
+        assert_eq!(texts, expected);
+    }
+
+    #[test]
+    fn log_keeps_the_newest_entries() {
+        let now = Instant::now();
+        let mut log = StatusLog::default();
+        log.push(Severity::Info, String::new(), now);
+        assert_eq!(log.len(), 0);
+        for idx in 0..LOG_CAPACITY + 5 {
+            log.push(Severity::Info, format!("msg {}", idx), now);
+        }
+        assert_eq!(log.len(), LOG_CAPACITY);
+        assert_eq!(log.entries().next().unwrap().text, "msg 104");
+        assert_eq!(log.entries().last().unwrap().text, "msg 5");
+        assert_eq!(format_age(Duration::from_secs(75)), "1m");
+    }
+
+    #[test]
+    fn progress_replaces_itself_until_another_message() {
+        let mut log = StatusLog::default();
+        log.info("pasting");
+        log.progress("uploading 1/3 chunks");
+        log.progress("uploading 2/3 chunks");
+        log.info("pasted");
+        log.progress("uploading 1/2 chunks");
+        let texts: Vec<&str> = log.entries().map(|entry| entry.text.as_str()).collect();
+        assert_eq!(
+            texts,
+            [
+                "uploading 1/2 chunks",
+                "pasted",
+                "uploading 2/3 chunks",
+                "pasting"
+            ]
+        );
+    }
+}
@@ -0,0 +1,404 @@
+use super::widgets::Style;
+use crossterm::style::Color;
+use serde::Deserialize;
+
+/// Built-in themes selectable with `--theme` or `name` in the config.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, clap::ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum ThemeName {
+    Dark,
+    Light,
+    HighContrast,
+}
+
+/// The `[theme]` section of the config file. Colors are names (`dark_cyan`,
+/// `grey`, ...) or `#rrggbb`; unset entries keep the built-in theme's value.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThemeConfig {
+    /// Built-in theme the overrides below apply to. `--theme` wins over it.
+    pub name: Option<ThemeName>,
+    pub status_fg: Option<String>,
+    pub status_bg: Option<String>,
+    pub cursor_fg: Option<String>,
+    pub cursor_bg: Option<String>,
+    pub selection_fg: Option<String>,
+    pub selection_bg: Option<String>,
+    /// Colors handed out to remote users; empty keeps the built-in palette.
+    pub palette: Vec<String>,
+}
+
+/// How many colors the terminal can show.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ColorDepth {
+    TrueColor,
+    Ansi256,
+    Ansi16,
+}
+
+impl ColorDepth {
+    /// Uses crossterm's `COLORTERM` / `TERM` detection.
+    fn detect() -> Self {
+        match crossterm::style::available_color_count() {
+            u16::MAX => Self::TrueColor,
+            count if count >= 256 => Self::Ansi256,
+            _ => Self::Ansi16,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Theme {
+    pub(super) status: Style,
+    pub(super) cursor: Style,
+    pub(super) selection: Style,
+    palette: Vec<Color>,
+    /// Whether the theme is meant for a dark terminal background; remote
+    /// selection tints are darkened there and lightened otherwise.
+    dark: bool,
+    depth: ColorDepth,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::builtin(ThemeName::Dark, ColorDepth::TrueColor)
+    }
+}
+
+impl Theme {
+    /// Picks the built-in theme (`name`, else the config's, else `dark`),
+    /// applies the config overrides and adapts RGB colors to the terminal.
+    pub fn resolve(name: Option<ThemeName>, config: &ThemeConfig) -> Result<Self, String> {
+        Self::build(name, config, ColorDepth::detect())
+    }
+
+    fn build(
+        name: Option<ThemeName>,
+        config: &ThemeConfig,
+        depth: ColorDepth,
+    ) -> Result<Self, String> {
+        let name = name.or(config.name).unwrap_or(ThemeName::Dark);
+        let mut theme = Self::builtin(name, depth);
+        override_color(&mut theme.status.fg, "status_fg", &config.status_fg)?;
+        override_color(&mut theme.status.bg, "status_bg", &config.status_bg)?;
+        override_color(&mut theme.cursor.fg, "cursor_fg", &config.cursor_fg)?;
+        override_color(&mut theme.cursor.bg, "cursor_bg", &config.cursor_bg)?;
+        override_color(
+            &mut theme.selection.fg,
+            "selection_fg",
+            &config.selection_fg,
+        )?;
+        override_color(
+            &mut theme.selection.bg,
+            "selection_bg",
+            &config.selection_bg,
+        )?;
+        if !config.palette.is_empty() {
+            theme.palette = config
+                .palette
+                .iter()
+                .enumerate()
+                .map(|(idx, color)| {
+                    parse_color(color).map_err(|err| format!("theme.palette[{}]: {}", idx, err))
+                })
+                .collect::<Result<_, _>>()?;
+        }
+        for style in [&mut theme.status, &mut theme.cursor, &mut theme.selection] {
+            style.fg = style.fg.map(|color| downgrade(color, depth));
+            style.bg = style.bg.map(|color| downgrade(color, depth));
+        }
+        Ok(theme)
+    }
+
+    fn builtin(name: ThemeName, depth: ColorDepth) -> Self {
+        match name {
+            ThemeName::Dark => Self {
+                status: Style::default(),
+                cursor: Style::colored(Color::White, Color::Black),
+                selection: Style::colored(Color::DarkGrey, Color::White),
+                palette: vec![
+                    Color::Cyan,
+                    Color::Magenta,
+                    Color::Yellow,
+                    Color::Green,
+                    Color::Blue,
+                    Color::Red,
+                ],
+                dark: true,
+                depth,
+            },
+            ThemeName::Light => Self {
+                status: Style::colored(Color::DarkGrey, Color::White),
+                cursor: Style::colored(Color::Black, Color::White),
+                selection: Style::colored(Color::Grey, Color::Black),
+                palette: vec![
+                    Color::DarkCyan,
+                    Color::DarkMagenta,
+                    Color::DarkYellow,
+                    Color::DarkGreen,
+                    Color::DarkBlue,
+                    Color::DarkRed,
+                ],
+                dark: false,
+                depth,
+            },
+            // Okabe-Ito palette: distinguishable with common color blindness.
+            ThemeName::HighContrast => Self {
+                status: Style {
+                    bold: true,
+                    ..Style::colored(Color::White, Color::Black)
+                },
+                cursor: Style::colored(Color::Yellow, Color::Black),
+                selection: Style::colored(Color::Blue, Color::White),
+                palette: vec![
+                    Color::from((230, 159, 0)),
+                    Color::from((86, 180, 233)),
+                    Color::from((0, 158, 115)),
+                    Color::from((240, 228, 66)),
+                    Color::from((0, 114, 178)),
+                    Color::from((213, 94, 0)),
+                    Color::from((204, 121, 167)),
+                ],
+                dark: true,
+                depth,
+            },
+        }
+    }
+
+    fn palette_color(&self, user_id: &str) -> Color {
+        let mut hash = 0u64;
+        for byte in user_id.as_bytes() {
+            hash = hash.wrapping_mul(31).wrapping_add(*byte as u64);
+        }
+        let idx = (hash as usize) % self.palette.len();
+        self.palette[idx]
+    }
+
+    /// Stable color of a remote user's cursor.
+    pub(super) fn user_color(&self, user_id: &str) -> Color {
+        downgrade(self.palette_color(user_id), self.depth)
+    }
+
+    /// Style of a remote user's cursor cell: their color, or their
+    /// selection tint once they have been idle for a while (`stale`).
+    pub(super) fn remote_cursor(&self, user_id: &str, stale: bool) -> Style {
+        if stale {
+            self.selection_tint(user_id)
+        } else {
+            Style::colored(self.user_color(user_id), Color::Black)
+        }
+    }
+
+    /// Background and text color of a remote user's selection: a shade of
+    /// their cursor color that stays readable on the theme's background.
+    pub(super) fn selection_tint(&self, user_id: &str) -> Style {
+        let tint = shade(self.palette_color(user_id), self.dark);
+        let fg = if self.dark {
+            Color::White
+        } else {
+            Color::Black
+        };
+        Style::colored(downgrade(tint, self.depth), fg)
+    }
+}
+
+fn override_color(
+    slot: &mut Option<Color>,
+    key: &str,
+    value: &Option<String>,
+) -> Result<(), String> {
+    if let Some(value) = value {
+        *slot = Some(parse_color(value).map_err(|err| format!("theme.{}: {}", key, err))?);
+    }
+    Ok(())
+}
+
+/// Parses `#rrggbb` or a crossterm color name (`-` and `_` are interchangeable).
+fn parse_color(value: &str) -> Result<Color, String> {
+    let value = value.trim();
+    if let Some(hex) = value.strip_prefix('#') {
+        let channel = |idx: usize| {
+            hex.get(idx..idx + 2)
+                .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+        };
+        return match (hex.len(), channel(0), channel(2), channel(4)) {
+            (6, Some(r), Some(g), Some(b)) => Ok(Color::Rgb { r, g, b }),
+            _ => Err(format!("invalid hex color '{}'", value)),
+        };
+    }
+    Color::try_from(value.replace('-', "_").as_str())
+        .map_err(|()| format!("unknown color '{}'", value))
+}
+
+/// Darker (on dark backgrounds) or lighter shade of a palette color.
+fn shade(color: Color, dark: bool) -> Color {
+    match (color, dark) {
+        (Color::Rgb { r, g, b }, true) => Color::Rgb {
+            r: (r as u16 * 3 / 5) as u8,
+            g: (g as u16 * 3 / 5) as u8,
+            b: (b as u16 * 3 / 5) as u8,
+        },
+        (Color::Rgb { r, g, b }, false) => Color::Rgb {
+            r: r + (255 - r) / 2,
+            g: g + (255 - g) / 2,
+            b: b + (255 - b) / 2,
+        },
+        (Color::Cyan, true) => Color::DarkCyan,
+        (Color::Magenta, true) => Color::DarkMagenta,
+        (Color::Yellow, true) => Color::DarkYellow,
+        (Color::Green, true) => Color::DarkGreen,
+        (Color::Blue, true) => Color::DarkBlue,
+        (Color::Red, true) => Color::DarkRed,
+        (Color::DarkCyan, false) => Color::Cyan,
+        (Color::DarkMagenta, false) => Color::Magenta,
+        (Color::DarkYellow, false) => Color::Yellow,
+        (Color::DarkGreen, false) => Color::Green,
+        (Color::DarkBlue, false) => Color::Blue,
+        (Color::DarkRed, false) => Color::Red,
+        (other, _) => other,
+    }
+}
+
+/// Maps RGB colors onto what the terminal can display. Named colors pass
+/// through unchanged.
+fn downgrade(color: Color, depth: ColorDepth) -> Color {
+    let Color::Rgb { r, g, b } = color else {
+        return color;
+    };
+    match depth {
+        ColorDepth::TrueColor => color,
+        ColorDepth::Ansi256 => Color::AnsiValue(nearest_ansi256(r, g, b)),
+        ColorDepth::Ansi16 => nearest_named(r, g, b),
+    }
+}
+
+fn distance(a: (u8, u8, u8), b: (u8, u8, u8)) -> u32 {
+    let d = |x: u8, y: u8| (x as i32 - y as i32).pow(2) as u32;
+    d(a.0, b.0) + d(a.1, b.1) + d(a.2, b.2)
+}
+
+/// Closest entry of the xterm 6x6x6 color cube or grey ramp.
+fn nearest_ansi256(r: u8, g: u8, b: u8) -> u8 {
+    const LEVELS: [u8; 6] = [0, 95, 135, 175, 215, 255];
+    let level = |value: u8| {
+        (0..LEVELS.len())
+            .min_by_key(|&idx| (LEVELS[idx] as i32 - value as i32).abs())
+            .unwrap_or(0)
+    };
+    let (ri, gi, bi) = (level(r), level(g), level(b));
+    let cube = (LEVELS[ri], LEVELS[gi], LEVELS[bi]);
+    let cube_idx = 16 + 36 * ri + 6 * gi + bi;
+
+    let avg = (r as u32 + g as u32 + b as u32) / 3;
+    let grey_idx = ((avg.saturating_sub(8) + 5) / 10).min(23);
+    let grey_level = (8 + grey_idx * 10) as u8;
+    let grey = (grey_level, grey_level, grey_level);
+
+    if distance((r, g, b), grey) < distance((r, g, b), cube) {
+        232 + grey_idx as u8
+    } else {
+        cube_idx as u8
+    }
+}
+
+/// Closest of the 16 basic terminal colors, using the xterm defaults.
+fn nearest_named(r: u8, g: u8, b: u8) -> Color {
+    const NAMED: [(Color, (u8, u8, u8)); 16] = [
+        (Color::Black, (0, 0, 0)),
+        (Color::DarkRed, (128, 0, 0)),
+        (Color::DarkGreen, (0, 128, 0)),
+        (Color::DarkYellow, (128, 128, 0)),
+        (Color::DarkBlue, (0, 0, 128)),
+        (Color::DarkMagenta, (128, 0, 128)),
+        (Color::DarkCyan, (0, 128, 128)),
+        (Color::Grey, (192, 192, 192)),
+        (Color::DarkGrey, (128, 128, 128)),
+        (Color::Red, (255, 0, 0)),
+        (Color::Green, (0, 255, 0)),
+        (Color::Yellow, (255, 255, 0)),
+        (Color::Blue, (0, 0, 255)),
+        (Color::Magenta, (255, 0, 255)),
+        (Color::Cyan, (0, 255, 255)),
+        (Color::White, (255, 255, 255)),
+    ];
+    NAMED
+        .iter()
+        .min_by_key(|(_, rgb)| distance((r, g, b), *rgb))
+        .map(|(color, _)| *color)
+        .unwrap_or(Color::White)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_names_and_hex_colors() {
+        assert_eq!(parse_color("dark-cyan"), Ok(Color::DarkCyan));
+        assert_eq!(parse_color("Dark_Grey"), Ok(Color::DarkGrey));
+        assert_eq!(
+            parse_color("#FF8000"),
+            Ok(Color::Rgb {
+                r: 255,
+                g: 128,
+                b: 0
+            })
+        );
+        assert!(parse_color("#ff80").is_err());
+        assert!(parse_color("chartreuse").is_err());
+    }
+
+    #[test]
+    fn rgb_colors_fall_back_without_truecolor() {
+        let orange = Color::Rgb {
+            r: 230,
+            g: 159,
+            b: 0,
+        };
+        assert_eq!(downgrade(orange, ColorDepth::TrueColor), orange);
+        assert_eq!(
+            downgrade(orange, ColorDepth::Ansi256),
+            Color::AnsiValue(178)
+        );
+        assert_eq!(downgrade(orange, ColorDepth::Ansi16), Color::Yellow);
+        assert_eq!(nearest_ansi256(128, 128, 128), 244);
+        assert_eq!(downgrade(Color::Cyan, ColorDepth::Ansi16), Color::Cyan);
+    }
+
+    #[test]
+    fn config_overrides_apply_on_top_of_the_selected_theme() {
+        let config = ThemeConfig {
+            name: Some(ThemeName::Light),
+            cursor_bg: Some("#102030".to_string()),
+            palette: vec!["green".to_string()],
+            ..ThemeConfig::default()
+        };
+        let theme = Theme::build(None, &config, ColorDepth::TrueColor).unwrap();
+        assert_eq!(
+            theme.status,
+            Theme::builtin(ThemeName::Light, ColorDepth::TrueColor).status
+        );
+        assert_eq!(
+            theme.cursor.bg,
+            Some(Color::Rgb {
+                r: 16,
+                g: 32,
+                b: 48
+            })
+        );
+        assert_eq!(theme.user_color("anyone"), Color::Green);
+        assert_eq!(theme.selection_tint("anyone").bg, Some(Color::Green));
+
+        // `--theme` beats the config's name; bad colors name their key.
+        let theme = Theme::build(Some(ThemeName::Dark), &config, ColorDepth::TrueColor).unwrap();
+        assert!(theme.dark);
+        let bad = ThemeConfig {
+            palette: vec!["cyan".to_string(), "nope".to_string()],
+            ..ThemeConfig::default()
+        };
+        assert_eq!(
+            Theme::build(None, &bad, ColorDepth::Ansi16).unwrap_err(),
+            "theme.palette[1]: unknown color 'nope'"
+        );
+    }
+}
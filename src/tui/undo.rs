@@ -0,0 +1,400 @@
+use crate::protocol::Op;
+use crate::textpos::{shift_for_op, shift_range_for_op};
+use std::time::{Duration, Instant};
+
+/// Typing pauses longer than this start a new undo entry.
+const GROUP_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(super) enum Edit {
+    Insert { pos: usize, text: String },
+    Delete { pos: usize, text: String },
+}
+
+impl Edit {
+    pub(super) fn inverse(&self) -> Edit {
+        match self {
+            Edit::Insert { pos, text } => Edit::Delete {
+                pos: *pos,
+                text: text.clone(),
+            },
+            Edit::Delete { pos, text } => Edit::Insert {
+                pos: *pos,
+                text: text.clone(),
+            },
+        }
+    }
+
+    pub(super) fn to_op(&self) -> Op {
+        match self {
+            Edit::Insert { pos, text } => Op::Insert {
+                pos: *pos,
+                text: text.clone(),
+                pos_chars: None,
+            },
+            Edit::Delete { pos, text } => Op::Delete {
+                pos: *pos,
+                len: text.len(),
+                pos_chars: None,
+                len_chars: None,
+            },
+        }
+    }
+
+    /// Cursor position after this edit has been applied.
+    pub(super) fn cursor_after(&self) -> usize {
+        match self {
+            Edit::Insert { pos, text } => pos + text.len(),
+            Edit::Delete { pos, .. } => *pos,
+        }
+    }
+
+    /// Returns true if the document still looks the way this edit expects
+    /// before it is applied.
+    pub(super) fn applies_to(&self, doc_text: &str) -> bool {
+        match self {
+            Edit::Insert { pos, .. } => *pos <= doc_text.len() && doc_text.is_char_boundary(*pos),
+            Edit::Delete { pos, text } => doc_text.get(*pos..pos + text.len()) == Some(text),
+        }
+    }
+
+    fn single_char(&self) -> Option<char> {
+        let text = match self {
+            Edit::Insert { text, .. } | Edit::Delete { text, .. } => text,
+        };
+        let mut chars = text.chars();
+        let ch = chars.next()?;
+        chars.next().is_none().then_some(ch)
+    }
+
+    /// Adjusts positions for a remote op; returns false if the remote op
+    /// touched the text this edit refers to.
+    fn adjust_for_remote(&mut self, op: &Op) -> bool {
+        match self {
+            Edit::Insert { pos, text } => {
+                let mut start = *pos;
+                let mut end = *pos + text.len();
+                let intact = shift_range_for_op(op, &mut start, &mut end);
+                *pos = start;
+                intact
+            }
+            Edit::Delete { pos, .. } => {
+                let mut end = *pos;
+                shift_range_for_op(op, pos, &mut end)
+            }
+        }
+    }
+}
+
+struct UndoEntry {
+    edits: Vec<Edit>,
+    last_change: Instant,
+}
+
+impl UndoEntry {
+    /// Each edit is positioned in the document its predecessors left, so
+    /// the op is walked back through the later edits before each earlier
+    /// one is adjusted.
+    fn adjust_for_remote(&mut self, op: &Op) -> bool {
+        let mut op = op.clone();
+        for edit in self.edits.iter_mut().rev() {
+            let undone = edit.inverse().to_op();
+            if !edit.adjust_for_remote(&op) {
+                return false;
+            }
+            op = op_before(&op, &undone);
+        }
+        true
+    }
+
+    /// Tries to fold a single-character edit into this entry so that typing
+    /// or repeated Backspace undo word by word.
+    fn try_merge(&mut self, edit: &Edit, now: Instant) -> bool {
+        if self.edits.len() != 1 || now.duration_since(self.last_change) > GROUP_TIMEOUT {
+            return false;
+        }
+        let Some(ch) = edit.single_char() else {
+            return false;
+        };
+        if ch == '\n' {
+            return false;
+        }
+        match (&mut self.edits[0], edit) {
+            (
+                Edit::Insert { pos, text },
+                Edit::Insert {
+                    pos: new_pos,
+                    text: new_text,
+                },
+            ) => {
+                let after_space = text.ends_with(char::is_whitespace);
+                if *new_pos != *pos + text.len() || (after_space && !ch.is_whitespace()) {
+                    return false;
+                }
+                text.push_str(new_text);
+            }
+            (
+                Edit::Delete { pos, text },
+                Edit::Delete {
+                    pos: new_pos,
+                    text: new_text,
+                },
+            ) => {
+                if *new_pos + new_text.len() == *pos {
+                    // Backspace: the deleted char sits in front of the entry.
+                    if text.starts_with(char::is_whitespace) && !ch.is_whitespace() {
+                        return false;
+                    }
+                    text.insert_str(0, new_text);
+                    *pos = *new_pos;
+                } else if *new_pos == *pos {
+                    // Forward delete: the deleted char followed the entry.
+                    if text.ends_with(char::is_whitespace) && !ch.is_whitespace() {
+                        return false;
+                    }
+                    text.push_str(new_text);
+                } else {
+                    return false;
+                }
+            }
+            _ => return false,
+        }
+        self.last_change = now;
+        true
+    }
+}
+
+/// Moves `op` to the document as it was before an edit, given the op that
+/// undoes that edit.
+fn op_before(op: &Op, undone: &Op) -> Op {
+    match op {
+        Op::Insert { pos, text, .. } => {
+            let mut pos = *pos;
+            shift_for_op(undone, &mut pos);
+            Op::Insert {
+                pos,
+                text: text.clone(),
+                pos_chars: None,
+            }
+        }
+        Op::Delete { pos, len, .. } => {
+            let mut start = *pos;
+            let mut end = pos + len;
+            shift_for_op(undone, &mut start);
+            shift_for_op(undone, &mut end);
+            Op::Delete {
+                pos: start,
+                len: end - start,
+                pos_chars: None,
+                len_chars: None,
+            }
+        }
+        Op::Cursor { .. } | Op::Selection { .. } => op.clone(),
+    }
+}
+
+/// Undo/redo history of local edits, stored as the edits themselves so the
+/// inverse can be sent as ordinary ops.
+#[derive(Default)]
+pub(super) struct UndoStack {
+    undo: Vec<UndoEntry>,
+    redo: Vec<UndoEntry>,
+    action_open: bool,
+    sealed: bool,
+}
+
+impl UndoStack {
+    const LIMIT: usize = 500;
+
+    /// Marks the start of a new user action (one key press). Edits recorded
+    /// within the same action are undone together.
+    pub(super) fn begin_action(&mut self) {
+        self.action_open = false;
+    }
+
+    /// Prevents the next edit from being grouped with the previous entry.
+    pub(super) fn seal(&mut self) {
+        self.sealed = true;
+    }
+
+    pub(super) fn record(&mut self, edit: Edit) {
+        let now = Instant::now();
+        self.redo.clear();
+        if self.action_open {
+            if let Some(entry) = self.undo.last_mut() {
+                entry.edits.push(edit);
+                entry.last_change = now;
+                return;
+            }
+        } else if !self.sealed
+            && let Some(entry) = self.undo.last_mut()
+            && entry.try_merge(&edit, now)
+        {
+            self.action_open = true;
+            return;
+        }
+        self.undo.push(UndoEntry {
+            edits: vec![edit],
+            last_change: now,
+        });
+        if self.undo.len() > Self::LIMIT {
+            self.undo.remove(0);
+        }
+        self.action_open = true;
+        self.sealed = false;
+    }
+
+    /// Returns the edits to apply (in order) to undo the latest entry.
+    pub(super) fn undo(&mut self) -> Option<Vec<Edit>> {
+        let entry = self.undo.pop()?;
+        let inverse = entry.edits.iter().rev().map(Edit::inverse).collect();
+        self.redo.push(entry);
+        self.sealed = true;
+        Some(inverse)
+    }
+
+    /// Returns the edits to apply (in order) to redo the latest undone entry.
+    pub(super) fn redo(&mut self) -> Option<Vec<Edit>> {
+        let mut entry = self.redo.pop()?;
+        let edits = entry.edits.clone();
+        entry.last_change = Instant::now();
+        self.undo.push(entry);
+        self.sealed = true;
+        Some(edits)
+    }
+
+    /// Shifts all entries for a remote op, dropping entries whose text was
+    /// modified by it since they can no longer be inverted safely.
+    pub(super) fn adjust_for_remote(&mut self, op: &Op) {
+        self.undo.retain_mut(|entry| entry.adjust_for_remote(op));
+        self.redo.retain_mut(|entry| entry.adjust_for_remote(op));
+        self.sealed = true;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn typed(stack: &mut UndoStack, start: usize, text: &str) {
+        for (offset, ch) in text.char_indices() {
+            stack.begin_action();
+            stack.record(Edit::Insert {
+                pos: start + offset,
+                text: ch.to_string(),
+            });
+        }
+    }
+
+    #[test]
+    fn typing_groups_by_word() {
+        let mut stack = UndoStack::default();
+        typed(&mut stack, 0, "hello world");
+        assert_eq!(
+            stack.undo(),
+            Some(vec![Edit::Delete {
+                pos: 6,
+                text: "world".to_string()
+            }])
+        );
+        assert_eq!(
+            stack.undo(),
+            Some(vec![Edit::Delete {
+                pos: 0,
+                text: "hello ".to_string()
+            }])
+        );
+        assert_eq!(stack.undo(), None);
+    }
+
+    #[test]
+    fn redo_reapplies_and_new_edit_clears_redo() {
+        let mut stack = UndoStack::default();
+        typed(&mut stack, 0, "ab");
+        stack.undo();
+        assert_eq!(
+            stack.redo(),
+            Some(vec![Edit::Insert {
+                pos: 0,
+                text: "ab".to_string()
+            }])
+        );
+        stack.undo();
+        typed(&mut stack, 0, "c");
+        assert_eq!(stack.redo(), None);
+    }
+
+    #[test]
+    fn remote_ops_shift_or_invalidate_entries() {
+        let mut stack = UndoStack::default();
+        typed(&mut stack, 5, "abc");
+        stack.adjust_for_remote(&Op::Insert {
+            pos: 0,
+            text: "xy".to_string(),
+            pos_chars: None,
+        });
+        assert_eq!(
+            stack.undo(),
+            Some(vec![Edit::Delete {
+                pos: 7,
+                text: "abc".to_string()
+            }])
+        );
+
+        let mut stack = UndoStack::default();
+        typed(&mut stack, 5, "abc");
+        stack.adjust_for_remote(&Op::Delete {
+            pos: 6,
+            len: 1,
+            pos_chars: None,
+            len_chars: None,
+        });
+        assert_eq!(stack.undo(), None);
+    }
+
+    /// `:s/a/bb/g` on `a_a_a`, recorded last match first.
+    fn replaced(stack: &mut UndoStack) {
+        stack.begin_action();
+        for pos in [4, 2, 0] {
+            stack.record(Edit::Delete {
+                pos,
+                text: "a".to_string(),
+            });
+            stack.record(Edit::Insert {
+                pos,
+                text: "bb".to_string(),
+            });
+        }
+    }
+
+    #[test]
+    fn remote_ops_map_through_back_to_front_entries() {
+        let mut stack = UndoStack::default();
+        replaced(&mut stack);
+        let mut text = "bb_bb_bb".to_string();
+        text.insert(3, 'X');
+        stack.adjust_for_remote(&Op::Insert {
+            pos: 3,
+            text: "X".to_string(),
+            pos_chars: None,
+        });
+        for edit in stack.undo().unwrap() {
+            assert!(edit.applies_to(&text), "{:?} on {:?}", edit, text);
+            match edit {
+                Edit::Insert { pos, text: ins } => text.insert_str(pos, &ins),
+                Edit::Delete { pos, text: del } => drop(text.drain(pos..pos + del.len())),
+            }
+        }
+        assert_eq!(text, "a_Xa_a");
+
+        // Inside the last `bb` the remote text belongs to an edit of the entry.
+        let mut stack = UndoStack::default();
+        replaced(&mut stack);
+        stack.adjust_for_remote(&Op::Insert {
+            pos: 7,
+            text: "X".to_string(),
+            pos_chars: None,
+        });
+        assert_eq!(stack.undo(), None);
+    }
+}
@@ -0,0 +1,119 @@
+//! What a full-screen terminal program is made of, shared by the editor
+//! and `collab-cli dashboard`: the terminal set up for as long as it runs,
+//! frames sent by their difference to the last one, text measured in
+//! cells, a list with a selected row and a line of input.
+
+mod prompt;
+pub(crate) mod screen;
+pub(crate) mod tty;
+
+use crossterm::cursor::MoveTo;
+use crossterm::queue;
+use crossterm::terminal::{Clear, ClearType};
+pub(crate) use prompt::{Prompt, PromptEvent};
+pub(crate) use screen::{Screen, Style};
+use std::error::Error;
+use std::io::{Write, stdout};
+pub(crate) use tty::TerminalGuard;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Terminal cells taken by a grapheme cluster. Zero-width clusters (a lone
+/// combining mark, control characters) still get a cell of their own.
+pub(crate) fn grapheme_width(grapheme: &str) -> usize {
+    grapheme.width().max(1)
+}
+
+pub(crate) fn text_width(text: &str) -> usize {
+    text.graphemes(true).map(grapheme_width).sum()
+}
+
+/// Cuts `line` to at most `max_width` cells without splitting a wide
+/// grapheme.
+pub(crate) fn clip_line(line: &str, max_width: usize) -> String {
+    let mut out = String::new();
+    let mut used = 0;
+    for grapheme in line.graphemes(true) {
+        used += grapheme_width(grapheme);
+        if used > max_width {
+            break;
+        }
+        out.push_str(grapheme);
+    }
+    out
+}
+
+/// Sends what changed since `last_frame` to the terminal and places the
+/// cursor; redraws everything without a last frame of the same size.
+pub(crate) fn show(
+    frame: Screen,
+    cursor: Option<(u16, u16)>,
+    last_frame: &mut Option<Screen>,
+) -> Result<(), Box<dyn Error>> {
+    let mut out = stdout();
+    let prev = last_frame.take().filter(|prev| prev.size() == frame.size());
+    if prev.is_none() {
+        queue!(out, Clear(ClearType::All))?;
+    }
+    screen::draw(&mut out, prev.as_ref(), &frame)?;
+    if let Some((col, row)) = cursor {
+        queue!(out, MoveTo(col, row))?;
+    }
+    out.flush()?;
+    *last_frame = Some(frame);
+    Ok(())
+}
+
+/// Draws `header` in bold and below it as many of `lines` as fit in
+/// `rows` rows from `top`, scrolled to keep line `selected` in view and
+/// drawn in `selected_style`. Lines are padded to the full width.
+pub(crate) fn draw_list(
+    screen: &mut Screen,
+    (top, rows, cols): (usize, usize, usize),
+    header: &str,
+    lines: &[String],
+    (selected, selected_style): (usize, Style),
+) {
+    if rows == 0 {
+        return;
+    }
+    let bold = Style {
+        bold: true,
+        ..Style::default()
+    };
+    screen.put(0, top, &format!("{:<cols$}", clip_line(header, cols)), bold);
+    let height = rows - 1;
+    let first = (selected + 1).saturating_sub(height);
+    for (idx, line) in lines.iter().enumerate().skip(first).take(height) {
+        let line = clip_line(line, cols);
+        let padding = cols.saturating_sub(text_width(&line));
+        let style = if idx == selected {
+            selected_style
+        } else {
+            Style::default()
+        };
+        let row = top + 1 + idx - first;
+        screen.put(0, row, &format!("{}{}", line, " ".repeat(padding)), style);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lists_scroll_to_keep_the_selected_line_in_view() {
+        let lines: Vec<String> = (0..10).map(|idx| format!("line {}", idx)).collect();
+        let picked = Style {
+            underline: true,
+            ..Style::default()
+        };
+        let mut screen = Screen::new(8, 4);
+        draw_list(&mut screen, (0, 4, 8), "header!!!", &lines, (5, picked));
+        let rows: Vec<String> = (0..4).map(|row| screen.row_text(row)).collect();
+        assert_eq!(rows, ["header!!", "line 3  ", "line 4  ", "line 5  "]);
+        assert!(screen.style_at(0, 0).bold);
+        assert_eq!(screen.style_at(7, 3), picked);
+        assert_eq!(screen.style_at(0, 2), Style::default());
+    }
+}
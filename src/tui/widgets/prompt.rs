@@ -0,0 +1,108 @@
+use crate::textpos::{next_char_boundary, prev_char_boundary};
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+use unicode_width::UnicodeWidthStr;
+
+pub(crate) enum PromptEvent {
+    /// The input text changed.
+    Changed,
+    /// Only the input cursor moved.
+    Moved,
+    Submit,
+    Cancel,
+    /// The key is not handled by the prompt itself.
+    Ignored,
+}
+
+/// Single-line input shown on the status row that takes over key handling
+/// while it is open.
+pub(crate) struct Prompt {
+    label: String,
+    input: String,
+    cursor: usize,
+}
+
+impl Prompt {
+    pub(crate) fn new(label: &str, initial: &str) -> Self {
+        Self {
+            label: label.to_string(),
+            input: initial.to_string(),
+            cursor: initial.len(),
+        }
+    }
+
+    pub(crate) fn input(&self) -> &str {
+        &self.input
+    }
+
+    /// Replaces the input, e.g. with a completion, leaving the cursor at
+    /// its end.
+    pub(crate) fn set_input(&mut self, input: &str) {
+        self.input = input.to_string();
+        self.cursor = input.len();
+    }
+
+    pub(crate) fn handle_key(&mut self, key: KeyEvent) -> PromptEvent {
+        match key.code {
+            KeyCode::Esc => PromptEvent::Cancel,
+            KeyCode::Enter => PromptEvent::Submit,
+            KeyCode::Backspace => {
+                if self.cursor == 0 {
+                    return PromptEvent::Moved;
+                }
+                let start = prev_char_boundary(&self.input, self.cursor);
+                self.input.replace_range(start..self.cursor, "");
+                self.cursor = start;
+                PromptEvent::Changed
+            }
+            KeyCode::Delete => {
+                if self.cursor == self.input.len() {
+                    return PromptEvent::Moved;
+                }
+                let end = next_char_boundary(&self.input, self.cursor);
+                self.input.replace_range(self.cursor..end, "");
+                PromptEvent::Changed
+            }
+            KeyCode::Left => {
+                self.cursor = prev_char_boundary(&self.input, self.cursor);
+                PromptEvent::Moved
+            }
+            KeyCode::Right => {
+                self.cursor = next_char_boundary(&self.input, self.cursor);
+                PromptEvent::Moved
+            }
+            KeyCode::Home => {
+                self.cursor = 0;
+                PromptEvent::Moved
+            }
+            KeyCode::End => {
+                self.cursor = self.input.len();
+                PromptEvent::Moved
+            }
+            KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.input.clear();
+                self.cursor = 0;
+                PromptEvent::Changed
+            }
+            KeyCode::Char(ch)
+                if !key
+                    .modifiers
+                    .intersects(KeyModifiers::CONTROL | KeyModifiers::ALT) =>
+            {
+                self.input.insert(self.cursor, ch);
+                self.cursor += ch.len_utf8();
+                PromptEvent::Changed
+            }
+            _ => PromptEvent::Ignored,
+        }
+    }
+
+    /// Text for the status row, e.g. `search: foo`.
+    pub(crate) fn line(&self) -> String {
+        format!("{}: {}", self.label, self.input)
+    }
+
+    /// Display column of the input cursor within `line()`.
+    pub(crate) fn cursor_col(&self) -> usize {
+        self.label.width() + 2 + self.input[..self.cursor].width()
+    }
+}
@@ -0,0 +1,271 @@
+use super::grapheme_width;
+use crossterm::cursor::MoveTo;
+use crossterm::queue;
+use crossterm::style::{
+    Attribute, Color, SetAttribute, SetBackgroundColor, SetForegroundColor, SetUnderlineColor,
+};
+use std::io::{self, Write};
+use unicode_segmentation::UnicodeSegmentation;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct Style {
+    pub(crate) fg: Option<Color>,
+    pub(crate) bg: Option<Color>,
+    pub(crate) bold: bool,
+    pub(crate) italic: bool,
+    pub(crate) underline: bool,
+    /// Color of the underline where the terminal supports it, else `fg`.
+    pub(crate) underline_color: Option<Color>,
+}
+
+impl Style {
+    pub(crate) fn colored(bg: Color, fg: Color) -> Self {
+        Self {
+            fg: Some(fg),
+            bg: Some(bg),
+            ..Self::default()
+        }
+    }
+}
+
+/// One terminal cell. Wide graphemes occupy their first cell; the cells
+/// they cover after that hold an empty `text`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Cell {
+    text: String,
+    style: Style,
+}
+
+impl Cell {
+    fn blank() -> Self {
+        Self {
+            text: " ".to_string(),
+            style: Style::default(),
+        }
+    }
+
+    fn is_continuation(&self) -> bool {
+        self.text.is_empty()
+    }
+}
+
+/// An in-memory frame. Rendering fills a fresh `Screen` and `draw` sends
+/// only the cells that differ from the previous frame to the terminal.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Screen {
+    cols: usize,
+    rows: usize,
+    cells: Vec<Cell>,
+}
+
+impl Screen {
+    pub(crate) fn new(cols: usize, rows: usize) -> Self {
+        Self {
+            cols,
+            rows,
+            cells: vec![Cell::blank(); cols * rows],
+        }
+    }
+
+    pub(crate) fn size(&self) -> (usize, usize) {
+        (self.cols, self.rows)
+    }
+
+    /// Writes `text` starting at a cell, clipped at the right edge. Wide
+    /// graphemes that do not fit entirely are left out.
+    pub(crate) fn put(&mut self, col: usize, row: usize, text: &str, style: Style) {
+        if row >= self.rows {
+            return;
+        }
+        let mut col = col;
+        for grapheme in text.graphemes(true) {
+            let width = grapheme_width(grapheme);
+            if col + width > self.cols {
+                break;
+            }
+            let idx = row * self.cols + col;
+            self.clear_overlap(idx, width);
+            self.cells[idx] = Cell {
+                text: grapheme.to_string(),
+                style,
+            };
+            for cell in &mut self.cells[idx + 1..idx + width] {
+                *cell = Cell {
+                    text: String::new(),
+                    style,
+                };
+            }
+            col += width;
+        }
+    }
+
+    /// Blanks wide graphemes that would be partly overwritten by a write of
+    /// `width` cells at `idx`.
+    fn clear_overlap(&mut self, idx: usize, width: usize) {
+        let row_start = idx - idx % self.cols;
+        let mut start = idx;
+        while start > row_start && self.cells[start].is_continuation() {
+            start -= 1;
+        }
+        for cell in &mut self.cells[start..idx] {
+            cell.text = " ".to_string();
+        }
+        let row_end = row_start + self.cols;
+        let mut end = idx + width;
+        while end < row_end && self.cells[end].is_continuation() {
+            self.cells[end].text = " ".to_string();
+            end += 1;
+        }
+    }
+
+    /// Changes the style of `len` cells starting at a cell, keeping their
+    /// text.
+    pub(crate) fn restyle(
+        &mut self,
+        col: usize,
+        row: usize,
+        len: usize,
+        restyle: impl Fn(Style) -> Style,
+    ) {
+        if row >= self.rows || col >= self.cols {
+            return;
+        }
+        let start = row * self.cols + col;
+        let end = start + len.min(self.cols - col);
+        for cell in &mut self.cells[start..end] {
+            cell.style = restyle(cell.style);
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn row_text(&self, row: usize) -> String {
+        let cells = &self.cells[row * self.cols..(row + 1) * self.cols];
+        cells.iter().map(|cell| cell.text.as_str()).collect()
+    }
+
+    #[cfg(test)]
+    pub(crate) fn style_at(&self, col: usize, row: usize) -> Style {
+        self.cells[row * self.cols + col].style
+    }
+
+    /// Whether the grapheme starting at `idx` (including the cells it
+    /// covers) differs from `other`.
+    fn grapheme_differs(&self, other: &Screen, idx: usize) -> bool {
+        let row_end = idx - idx % self.cols + self.cols;
+        let mut end = idx + 1;
+        while end < row_end && self.cells[end].is_continuation() {
+            end += 1;
+        }
+        self.cells[idx..end] != other.cells[idx..end]
+    }
+}
+
+/// Emits the cells of `next` that differ from `prev` (everything without a
+/// previous frame). Returns the rows that were touched together with the
+/// number of cells rewritten in each.
+pub(crate) fn draw(
+    out: &mut impl Write,
+    prev: Option<&Screen>,
+    next: &Screen,
+) -> io::Result<Vec<(usize, usize)>> {
+    let prev = prev.filter(|prev| prev.size() == next.size());
+    let mut changed_rows = Vec::new();
+    let mut style = None;
+    for row in 0..next.rows {
+        let mut changed = 0;
+        // Column the terminal cursor sits at after the last write on this row.
+        let mut at = None;
+        for col in 0..next.cols {
+            let idx = row * next.cols + col;
+            let cell = &next.cells[idx];
+            if cell.is_continuation() {
+                continue;
+            }
+            if prev.is_some_and(|prev| !next.grapheme_differs(prev, idx)) {
+                continue;
+            }
+            if at != Some(col) {
+                queue!(out, MoveTo(col as u16, row as u16))?;
+            }
+            if style != Some(cell.style) {
+                apply_style(out, cell.style)?;
+                style = Some(cell.style);
+            }
+            out.write_all(cell.text.as_bytes())?;
+            let width = grapheme_width(&cell.text);
+            changed += width;
+            at = Some(col + width);
+        }
+        if changed > 0 {
+            changed_rows.push((row, changed));
+        }
+    }
+    if style.is_some() {
+        queue!(out, SetAttribute(Attribute::Reset))?;
+    }
+    Ok(changed_rows)
+}
+
+fn apply_style(out: &mut impl Write, style: Style) -> io::Result<()> {
+    queue!(out, SetAttribute(Attribute::Reset))?;
+    if let Some(fg) = style.fg {
+        queue!(out, SetForegroundColor(fg))?;
+    }
+    if let Some(bg) = style.bg {
+        queue!(out, SetBackgroundColor(bg))?;
+    }
+    if style.bold {
+        queue!(out, SetAttribute(Attribute::Bold))?;
+    }
+    if style.italic {
+        queue!(out, SetAttribute(Attribute::Italic))?;
+    }
+    if style.underline {
+        queue!(out, SetAttribute(Attribute::Underlined))?;
+        if let Some(color) = style.underline_color {
+            queue!(out, SetUnderlineColor(color))?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn unchanged_frames_draw_nothing() {
+        let mut screen = Screen::new(10, 3);
+        screen.put(0, 0, "hello", Style::default());
+        let mut out = Vec::new();
+        assert_eq!(draw(&mut out, None, &screen).unwrap().len(), 3);
+        assert!(
+            draw(&mut Vec::new(), Some(&screen), &screen)
+                .unwrap()
+                .is_empty()
+        );
+
+        let mut next = screen.clone();
+        next.put(5, 0, "!", Style::default());
+        assert_eq!(
+            draw(&mut Vec::new(), Some(&screen), &next).unwrap(),
+            vec![(0, 1)]
+        );
+    }
+
+    #[test]
+    fn overwriting_half_of_a_wide_grapheme_blanks_the_rest() {
+        let mut screen = Screen::new(4, 1);
+        screen.put(0, 0, "中文", Style::default());
+        screen.put(1, 0, "x", Style::default());
+        let mut out = Vec::new();
+        draw(&mut out, None, &screen).unwrap();
+        let text = String::from_utf8(out).unwrap();
+        assert!(text.contains(" x文"));
+
+        // A wide grapheme never spills over the right edge.
+        let mut screen = Screen::new(3, 1);
+        screen.put(0, 0, "ab中", Style::default());
+        assert_eq!(screen.cells[2].text, " ");
+    }
+}
@@ -0,0 +1,178 @@
+use crossterm::cursor::Show;
+use crossterm::event::{
+    DisableBracketedPaste, DisableMouseCapture, EnableBracketedPaste, EnableMouseCapture,
+};
+use crossterm::execute;
+use crossterm::terminal::{self, EnterAlternateScreen, LeaveAlternateScreen};
+use std::future::Future;
+use std::io::{self, stdout};
+use std::panic::AssertUnwindSafe;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::task::Poll;
+
+const INACTIVE: u8 = 0;
+const ACTIVE: u8 = 1;
+const ACTIVE_WITH_MOUSE: u8 = 2;
+
+/// What `restore` has to undo. Global because the panic hook has no other
+/// way to find out.
+static STATE: AtomicU8 = AtomicU8::new(INACTIVE);
+
+/// Raw mode, alternate screen, bracketed paste and (optionally) mouse
+/// capture for as long as it lives.
+pub(crate) struct TerminalGuard;
+
+impl TerminalGuard {
+    pub(crate) fn new(mouse: bool) -> io::Result<Self> {
+        enter(mouse)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore();
+    }
+}
+
+fn enter(mouse: bool) -> io::Result<()> {
+    terminal::enable_raw_mode()?;
+    execute!(stdout(), EnterAlternateScreen, EnableBracketedPaste)?;
+    if mouse {
+        execute!(stdout(), EnableMouseCapture)?;
+    }
+    mark_active(mouse);
+    Ok(())
+}
+
+fn mark_active(mouse: bool) {
+    let state = if mouse { ACTIVE_WITH_MOUSE } else { ACTIVE };
+    STATE.store(state, Ordering::SeqCst);
+}
+
+/// Claims the job of restoring the terminal: `Some(mouse)` for exactly one
+/// caller after each `enter`.
+fn take_active() -> Option<bool> {
+    match STATE.swap(INACTIVE, Ordering::SeqCst) {
+        INACTIVE => None,
+        state => Some(state == ACTIVE_WITH_MOUSE),
+    }
+}
+
+fn leave(mouse: bool) {
+    if mouse {
+        let _ = execute!(stdout(), DisableMouseCapture);
+    }
+    let _ = execute!(stdout(), DisableBracketedPaste, Show, LeaveAlternateScreen);
+    let _ = terminal::disable_raw_mode();
+}
+
+/// Puts the terminal back into its normal mode. Only the first call after
+/// entering does anything, so the guard, the panic hook and `suspend` can
+/// all call it.
+pub(crate) fn restore() {
+    if let Some(mouse) = take_active() {
+        leave(mouse);
+    }
+}
+
+/// Hands the terminal back to the shell and stops the process group, like
+/// Ctrl+Z in other full-screen programs. Returns once the shell continues
+/// us (SIGCONT) with the terminal set up again; the caller has to redraw
+/// everything. Tokio tasks simply pause while stopped.
+#[cfg(unix)]
+pub(crate) fn suspend() -> io::Result<()> {
+    let Some(mouse) = take_active() else {
+        return Ok(());
+    };
+    leave(mouse);
+    // SAFETY: kill has no memory safety preconditions; pid 0 is our own
+    // process group.
+    unsafe {
+        libc::kill(0, libc::SIGTSTP);
+    }
+    enter(mouse)
+}
+
+/// Suspending is a Unix job-control feature.
+#[cfg(not(unix))]
+pub(crate) fn suspend() -> io::Result<()> {
+    Ok(())
+}
+
+/// Restores the terminal before the panic message and backtrace are
+/// printed, so they end up on a usable shell. Hooks also run with
+/// `panic = "abort"`, where `TerminalGuard` is never dropped.
+pub(crate) fn install_panic_hook() {
+    chain_panic_hook(restore);
+}
+
+fn chain_panic_hook(before: fn()) {
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        before();
+        previous(info);
+    }));
+}
+
+/// Runs `future`, turning a panic while polling it into an `Err`. The
+/// future is dropped right after, closing whatever connections it owned.
+pub(crate) async fn catch_unwind<F: Future>(future: F) -> std::thread::Result<F::Output> {
+    let mut future = std::pin::pin!(future);
+    std::future::poll_fn(|cx| {
+        match std::panic::catch_unwind(AssertUnwindSafe(|| future.as_mut().poll(cx))) {
+            Ok(Poll::Ready(output)) => Poll::Ready(Ok(output)),
+            Ok(Poll::Pending) => Poll::Pending,
+            Err(payload) => Poll::Ready(Err(payload)),
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+
+    static RESTORED: AtomicUsize = AtomicUsize::new(0);
+
+    fn mark_restored() {
+        RESTORED.fetch_add(1, Ordering::SeqCst);
+    }
+
+    fn faulty_render(rows: &[&str], row: usize) -> usize {
+        rows[row].len()
+    }
+
+    #[test]
+    fn panics_run_the_hook_and_are_caught() {
+        chain_panic_hook(mark_restored);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let ok = runtime.block_on(catch_unwind(async { faulty_render(&["ab"], 0) }));
+        let rows = std::hint::black_box(vec!["ab"]);
+        let panicked = runtime.block_on(catch_unwind(async { faulty_render(&rows, 3) }));
+        // Back to the default hook.
+        let _ = std::panic::take_hook();
+
+        assert_eq!(ok.unwrap(), 2);
+        let payload = panicked.unwrap_err();
+        let message = payload.downcast_ref::<String>().unwrap();
+        assert!(message.contains("index out of bounds"), "{}", message);
+        assert!(RESTORED.load(Ordering::SeqCst) >= 1);
+    }
+
+    #[test]
+    fn suspend_and_resume_leave_one_restore_for_the_guard() {
+        mark_active(true);
+        // Suspending restores the terminal...
+        assert_eq!(take_active(), Some(true));
+        // ...so a panic hook firing while stopped has nothing left to do.
+        assert_eq!(take_active(), None);
+        // Resuming sets it up again, which the guard undoes exactly once.
+        mark_active(true);
+        drop(TerminalGuard);
+        assert_eq!(take_active(), None);
+    }
+}
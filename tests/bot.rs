@@ -0,0 +1,91 @@
+use carnelia_collab::bot::BotRunner;
+use carnelia_collab::testing::TestServer;
+use std::time::Duration;
+use tokio::sync::mpsc;
+
+#[tokio::test]
+async fn bots_answer_commands_and_greet_newcomers() {
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "notes.txt").await.unwrap();
+    let bot = BotRunner::new("clock", "team", "notes.txt")
+        .on_insert(|ctx, pos, text| async move {
+            let end = pos + text.len();
+            if ctx.text().await[..end].ends_with("/now") {
+                ctx.delete(end - 4, 4).await?;
+                ctx.insert(end - 4, "12:00 UTC").await?;
+            }
+            Ok(())
+        })
+        .on_user_joined(|ctx, user| async move {
+            let end = ctx.text().await.len();
+            ctx.insert(end, &format!("\nhi {}", user.name)).await
+        })
+        .connect(server.addr)
+        .await
+        .unwrap();
+    let ctx = bot.context();
+    let running = tokio::spawn(bot.run());
+
+    ada.insert(0, "lunch at /no").await.unwrap();
+    ada.wait_for_text("lunch at /no").await.unwrap();
+    ada.insert(12, "w").await.unwrap();
+    ada.wait_for_text("lunch at 12:00 UTC").await.unwrap();
+
+    let mut bob = server.connect("bob", "team", "notes.txt").await.unwrap();
+    ada.wait_for_text("lunch at 12:00 UTC\nhi bob")
+        .await
+        .unwrap();
+    bob.wait_for_text("lunch at 12:00 UTC\nhi bob")
+        .await
+        .unwrap();
+    assert_eq!(ctx.text().await, "lunch at 12:00 UTC\nhi bob");
+    // The user list follows once the server announces it.
+    let mut names = Vec::new();
+    for _ in 0..50 {
+        names = ctx
+            .users()
+            .await
+            .into_iter()
+            .map(|user| user.name)
+            .collect();
+        names.sort();
+        if names.len() == 3 {
+            break;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    assert_eq!(names, ["ada", "bob", "clock"]);
+
+    running.abort();
+    server.shutdown().await.unwrap();
+}
+
+#[tokio::test]
+async fn deletes_are_reported_with_the_text_they_removed() {
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "notes.txt").await.unwrap();
+    let (deleted_tx, mut deleted) = mpsc::unbounded_channel();
+    let bot = BotRunner::new("watcher", "team", "notes.txt")
+        .on_delete(move |_, pos, text| {
+            let deleted_tx = deleted_tx.clone();
+            async move {
+                let _ = deleted_tx.send((pos, text));
+                Ok(())
+            }
+        })
+        .connect(server.addr)
+        .await
+        .unwrap();
+    let running = tokio::spawn(bot.run());
+
+    ada.insert(0, "héllo world").await.unwrap();
+    ada.delete(6, 6).await.unwrap();
+    // A delete from inside a character starts where the character does.
+    ada.delete(2, 2).await.unwrap();
+    ada.wait_for_text("hllo").await.unwrap();
+    assert_eq!(deleted.recv().await.unwrap(), (6, " world".to_string()));
+    assert_eq!(deleted.recv().await.unwrap(), (1, "é".to_string()));
+
+    running.abort();
+    server.shutdown().await.unwrap();
+}
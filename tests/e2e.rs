@@ -0,0 +1,290 @@
+use carnelia_collab::chunked::DEFAULT_CHUNK_BYTES;
+use carnelia_collab::testing::{TestClient, TestServer};
+
+#[tokio::test]
+async fn edits_reach_other_clients_and_survive_a_restart() {
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "notes.txt").await.unwrap();
+    let mut bob = server.connect("bob", "team", "notes.txt").await.unwrap();
+    let mut elsewhere = server.connect("eve", "team", "other.txt").await.unwrap();
+
+    ada.insert(0, "hello").await.unwrap();
+    bob.wait_for_text("hello").await.unwrap();
+    bob.insert(5, " world").await.unwrap();
+    ada.wait_for_text("hello world").await.unwrap();
+    bob.wait_for_text("hello world").await.unwrap();
+    assert_eq!(ada.version(), 2);
+    assert_eq!(bob.version(), 2);
+    ada.delete(0, 6).await.unwrap();
+    bob.wait_for_text("world").await.unwrap();
+
+    // Edits of one document don't leak into another.
+    elsewhere.insert(0, "private").await.unwrap();
+    elsewhere.wait_for_text("private").await.unwrap();
+    ada.wait_for_text("world").await.unwrap();
+    assert_eq!(ada.text(), "world");
+
+    let data_dir = server.shutdown().await.unwrap();
+    let server = TestServer::spawn_in(data_dir).await.unwrap();
+    let carol = server.connect("carol", "team", "notes.txt").await.unwrap();
+    assert_eq!(carol.text(), "world");
+    assert_eq!(carol.version(), 3);
+    let other = TestClient::connect(server.addr, "dan", "team", "other.txt")
+        .await
+        .unwrap();
+    assert_eq!(other.text(), "private");
+
+    let data_dir = server.shutdown().await.unwrap();
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn a_long_paste_arrives_whole_in_chunks() {
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "big.txt").await.unwrap();
+    let mut bob = server.connect("bob", "team", "big.txt").await.unwrap();
+    ada.insert(0, "before|after").await.unwrap();
+    bob.wait_for_text("before|after").await.unwrap();
+
+    let pasted: String = (0..60_000)
+        .map(|idx| format!("line {} ünïcødé 🎉\n", idx))
+        .collect();
+    let chunks = ada
+        .insert_chunked(7, &pasted, DEFAULT_CHUNK_BYTES)
+        .await
+        .unwrap();
+    assert!(chunks > 10);
+    let expected = format!("before|{}after", pasted);
+    ada.wait_for_text(&expected).await.unwrap();
+    bob.wait_for_text(&expected).await.unwrap();
+
+    let data_dir = server.shutdown().await.unwrap();
+    let server = TestServer::spawn_in(data_dir).await.unwrap();
+    let carol = server.connect("carol", "team", "big.txt").await.unwrap();
+    assert!(carol.text() == expected);
+
+    let data_dir = server.shutdown().await.unwrap();
+    std::fs::remove_dir_all(data_dir).unwrap();
+}
+
+#[tokio::test]
+async fn joins_with_unsafe_names_are_refused() {
+    let server = TestServer::spawn().await.unwrap();
+    let err = server
+        .connect("ada", "team", "../escape")
+        .await
+        .err()
+        .unwrap();
+    assert!(err.to_string().contains("join refused"), "{}", err);
+    let data_dir = server.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(data_dir);
+}
+
+async fn http_get(addr: std::net::SocketAddr, target: &str, headers: &str) -> String {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    let mut stream = tokio::net::TcpStream::connect(addr).await.unwrap();
+    let request = format!("GET {} HTTP/1.1\r\nHost: test\r\n{}\r\n", target, headers);
+    stream.write_all(request.as_bytes()).await.unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    response
+}
+
+#[tokio::test]
+async fn documents_are_served_over_http_with_script_escaped() {
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "notes.md").await.unwrap();
+    let text = "# Plan\n\n<script>alert('x')</script>\n";
+    ada.insert(0, text).await.unwrap();
+    ada.wait_for_text(text).await.unwrap();
+
+    let raw = http_get(server.health_addr, "/rooms/team/docs/notes.md", "").await;
+    assert!(raw.starts_with("HTTP/1.1 200 OK\r\n"), "{}", raw);
+    assert!(raw.contains("Content-Type: text/plain; charset=utf-8\r\n"));
+    assert!(raw.ends_with(&format!("\r\n\r\n{}", text)), "{}", raw);
+
+    let page = http_get(
+        server.health_addr,
+        "/rooms/team/docs/notes.md?format=md",
+        "",
+    )
+    .await;
+    assert!(
+        page.contains("Content-Type: text/html; charset=utf-8\r\n"),
+        "{}",
+        page
+    );
+    assert!(!page.contains("<script"), "{}", page);
+    assert!(page.contains("&lt;script&gt;"), "{}", page);
+
+    let etag = page
+        .lines()
+        .find_map(|line| line.strip_prefix("ETag: "))
+        .unwrap();
+    let header = format!("If-None-Match: {}\r\n", etag);
+    let cached = http_get(
+        server.health_addr,
+        "/rooms/team/docs/notes.md?format=md",
+        &header,
+    )
+    .await;
+    assert!(
+        cached.starts_with("HTTP/1.1 304 Not Modified\r\n"),
+        "{}",
+        cached
+    );
+    ada.insert(0, "x").await.unwrap();
+    let changed = http_get(
+        server.health_addr,
+        "/rooms/team/docs/notes.md?format=md",
+        &header,
+    )
+    .await;
+    assert!(changed.starts_with("HTTP/1.1 200 OK\r\n"), "{}", changed);
+
+    let missing = http_get(server.health_addr, "/rooms/team/docs/nothing.md", "").await;
+    assert!(
+        missing.starts_with("HTTP/1.1 404 Not Found\r\n"),
+        "{}",
+        missing
+    );
+    let unsafe_name = http_get(server.health_addr, "/rooms/team/docs/..%2Fescape", "").await;
+    assert!(
+        unsafe_name.starts_with("HTTP/1.1 400 Bad Request\r\n"),
+        "{}",
+        unsafe_name
+    );
+
+    let data_dir = server.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(data_dir);
+}
+
+#[tokio::test]
+async fn a_bridge_keeps_a_doc_the_same_on_two_servers() {
+    use carnelia_collab::bridge::{self, Endpoint};
+
+    let east = TestServer::spawn().await.unwrap();
+    let west = TestServer::spawn().await.unwrap();
+    let mut ada = east
+        .connect("ada", "east-team", "shared.txt")
+        .await
+        .unwrap();
+    ada.insert(0, "hello world").await.unwrap();
+    ada.wait_for_text("hello world").await.unwrap();
+
+    let left = Endpoint {
+        addr: east.addr.to_string(),
+        room: "east-team".into(),
+    };
+    let right = Endpoint {
+        addr: west.addr.to_string(),
+        room: "west-team".into(),
+    };
+    let bridge = tokio::spawn(async move {
+        bridge::run(&left, &right, "shared.txt", "bridge")
+            .await
+            .map_err(|err| err.to_string())
+    });
+
+    // The longer east side is copied west when the bridge starts.
+    let mut bob = west
+        .connect("bob", "west-team", "shared.txt")
+        .await
+        .unwrap();
+    bob.wait_for_text("hello world").await.unwrap();
+
+    ada.insert(11, "!").await.unwrap();
+    bob.wait_for_text("hello world!").await.unwrap();
+    bob.insert(0, "oh, ").await.unwrap();
+    let expected = bob.text();
+    ada.wait_for_text(&expected).await.unwrap();
+
+    // Edits made on both sides at once are merged.
+    let len = expected.len();
+    ada.insert(len, " bye").await.unwrap();
+    bob.delete(len - 1, 1).await.unwrap();
+    let merged = format!("{} bye", &expected[..len - 1]);
+    ada.wait_for_text(&merged).await.unwrap();
+    bob.wait_for_text(&merged).await.unwrap();
+
+    assert!(!bridge.is_finished());
+    bridge.abort();
+    for server in [east, west] {
+        let data_dir = server.shutdown().await.unwrap();
+        let _ = std::fs::remove_dir_all(data_dir);
+    }
+}
+
+#[tokio::test]
+async fn contribution_stats_are_kept_per_user_across_restarts() {
+    use carnelia_collab::client;
+
+    let server = TestServer::spawn().await.unwrap();
+    let mut ada = server.connect("ada", "team", "notes.txt").await.unwrap();
+    let mut bob = server.connect("bob", "team", "notes.txt").await.unwrap();
+    ada.insert(0, "hello world").await.unwrap();
+    bob.wait_for_text("hello world").await.unwrap();
+    bob.delete(5, 6).await.unwrap();
+    bob.insert(5, "!").await.unwrap();
+    ada.wait_for_text("hello!").await.unwrap();
+
+    let check = |stats: Vec<carnelia_collab::storage::UserStats>| {
+        let ada = stats.iter().find(|stats| stats.user == "ada").unwrap();
+        assert_eq!((ada.inserted_bytes, ada.deleted_bytes, ada.ops), (11, 0, 1));
+        let bob = stats.iter().find(|stats| stats.user == "bob").unwrap();
+        assert_eq!((bob.inserted_bytes, bob.deleted_bytes, bob.ops), (1, 6, 2));
+        assert!(ada.last_active.is_some() && bob.last_active.is_some());
+    };
+    let addr = server.addr.to_string();
+    check(
+        client::doc_stats(&addr, "team", "notes.txt", "ada")
+            .await
+            .unwrap(),
+    );
+
+    drop((ada, bob));
+    let data_dir = server.shutdown().await.unwrap();
+    let server = TestServer::spawn_in(data_dir).await.unwrap();
+    let addr = server.addr.to_string();
+    check(
+        client::doc_stats(&addr, "team", "notes.txt", "ada")
+            .await
+            .unwrap(),
+    );
+    let none = client::doc_stats(&addr, "team", "nothing.txt", "ada")
+        .await
+        .unwrap();
+    assert!(none.is_empty());
+
+    let data_dir = server.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(data_dir);
+}
+
+#[tokio::test]
+async fn a_second_server_on_the_same_data_dir_is_refused() {
+    let server = TestServer::spawn().await.unwrap();
+    // Joined, so the first server holds the lock by now.
+    let ada = server.connect("ada", "team", "notes.txt").await.unwrap();
+    let second = TestServer::spawn_in(server.data_dir().to_path_buf())
+        .await
+        .unwrap();
+    let err = second.shutdown().await.unwrap_err();
+    let expected = format!(
+        "{} is in use by another server (pid {})",
+        server.data_dir().display(),
+        std::process::id()
+    );
+    assert_eq!(err.to_string(), expected);
+
+    let health = http_get(server.health_addr, "/health", "").await;
+    assert!(health.starts_with("HTTP/1.1 200 OK"), "{}", health);
+    let pid = format!("X-Lock-Pid: {}\r\n", std::process::id());
+    assert!(health.contains(&pid), "{}", health);
+
+    drop(ada);
+    let data_dir = server.shutdown().await.unwrap();
+    let server = TestServer::spawn_in(data_dir).await.unwrap();
+    server.connect("bob", "team", "notes.txt").await.unwrap();
+    let data_dir = server.shutdown().await.unwrap();
+    let _ = std::fs::remove_dir_all(data_dir);
+}